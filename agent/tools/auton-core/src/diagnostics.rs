@@ -0,0 +1,260 @@
+//! Structured diagnostics parsed from compiler, assembler and linker output.
+//!
+//! kernel-builder's `--diagnostics-format json` turns gcc/clang/GAS/nasm/ld
+//! messages into one JSON record per line (file, line, column, severity,
+//! message, tool) instead of leaving the agent to scrape raw stderr, and
+//! diff-validator's compile check maps the same records to findings. Failing
+//! tools surface through a [`ToolFailure`] at the root of the error chain;
+//! warnings from tools that succeeded are gathered with [`record`] and
+//! drained with [`take`]. Cache hits do not replay the warnings of the run
+//! that produced them.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub tool: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<u32>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Root cause of a failed tool run: renders as the tool's stderr so `{:#}`
+/// output is unchanged, while keeping the program for [`parse`].
+#[derive(Debug, Clone)]
+pub struct ToolFailure {
+    pub program: String,
+    pub stderr: String,
+}
+
+impl fmt::Display for ToolFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.stderr.trim())
+    }
+}
+
+impl std::error::Error for ToolFailure {}
+
+/// Diagnostics carried by an error chain, if a tool failure caused it.
+pub fn from_error(err: &anyhow::Error) -> Vec<Diagnostic> {
+    err.chain()
+        .find_map(|c| c.downcast_ref::<ToolFailure>())
+        .map(|f| parse(&f.program, &f.stderr))
+        .unwrap_or_default()
+}
+
+static COLLECTED: Mutex<Vec<Diagnostic>> = Mutex::new(Vec::new());
+
+/// Parse and keep `stderr` of a tool that succeeded (warnings, notes).
+pub fn record(program: &str, stderr: &str) {
+    let parsed = parse(program, stderr);
+    if !parsed.is_empty() {
+        COLLECTED.lock().unwrap().extend(parsed);
+    }
+}
+
+/// Drain everything [`record`]ed so far.
+pub fn take() -> Vec<Diagnostic> {
+    std::mem::take(&mut *COLLECTED.lock().unwrap())
+}
+
+fn severity_word(word: &str) -> Option<Severity> {
+    match word.trim() {
+        "error" | "fatal error" | "Error" | "Fatal error" => Some(Severity::Error),
+        "warning" | "Warning" => Some(Severity::Warning),
+        "note" | "Note" | "Info" => Some(Severity::Note),
+        _ => None,
+    }
+}
+
+fn tool_name(program: &str) -> String {
+    program.rsplit('/').next().unwrap_or(program).to_string()
+}
+
+fn is_linker(tool: &str) -> bool {
+    tool == "ld" || tool.ends_with("-ld") || tool.starts_with("ld.")
+}
+
+/// `file:line[:col]: severity: message` (gcc, clang, GAS, nasm).
+fn parse_located(tool: &str, line: &str) -> Option<Diagnostic> {
+    let mut parts = line.splitn(5, ':');
+    let file = parts.next()?.trim();
+    let line_no: u32 = parts.next()?.trim().parse().ok()?;
+    let third = parts.next()?;
+    let (column, sev_part, rest) = match third.trim().parse::<u32>() {
+        Ok(col) => (Some(col), parts.next()?, parts.next()),
+        Err(_) => {
+            let rest = match (parts.next(), parts.next()) {
+                (Some(a), Some(b)) => Some(format!("{a}:{b}")),
+                (Some(a), None) => Some(a.to_string()),
+                _ => None,
+            };
+            return match severity_word(third) {
+                Some(severity) => Some(Diagnostic {
+                    tool: tool.to_string(),
+                    file: Some(file.to_string()),
+                    line: Some(line_no),
+                    column: None,
+                    severity,
+                    message: rest.unwrap_or_default().trim().to_string(),
+                }),
+                // ld: `pmm.c:12: undefined reference to `foo'`
+                None if is_linker(tool) => Some(Diagnostic {
+                    tool: tool.to_string(),
+                    file: Some(file.to_string()),
+                    line: Some(line_no),
+                    column: None,
+                    severity: Severity::Error,
+                    message: match rest {
+                        Some(r) => format!("{}:{r}", third.trim()),
+                        None => third.trim().to_string(),
+                    },
+                }),
+                None => None,
+            };
+        }
+    };
+    let severity = severity_word(sev_part)?;
+    Some(Diagnostic {
+        tool: tool.to_string(),
+        file: Some(file.to_string()),
+        line: Some(line_no),
+        column,
+        severity,
+        message: rest.unwrap_or("").trim().to_string(),
+    })
+}
+
+/// `ld: warning: …`, `ld: cannot find …`, `ld: obj.o:(.text+0x5): …`.
+fn parse_linker(tool: &str, line: &str) -> Option<Diagnostic> {
+    let (prefix, body) = line.split_once(": ")?;
+    if tool_name(prefix) != tool {
+        return None;
+    }
+    if body.ends_with(':') && body.contains(" in function ") {
+        return None; // context header for the next line
+    }
+    let (severity, message) = match body.strip_prefix("warning: ") {
+        Some(w) => (Severity::Warning, w),
+        None => (Severity::Error, body),
+    };
+    let (file, message) = match message.split_once(":(") {
+        Some((f, rest)) if !f.contains(' ') => (Some(f.to_string()), format!("({rest}")),
+        _ => (None, message.to_string()),
+    };
+    Some(Diagnostic {
+        tool: tool.to_string(),
+        file,
+        line: None,
+        column: None,
+        severity,
+        message,
+    })
+}
+
+/// Every diagnostic in `text`, in order. Caret lines, source excerpts and
+/// `In file included from` context are dropped.
+pub fn parse(program: &str, text: &str) -> Vec<Diagnostic> {
+    let tool = tool_name(program);
+    text.lines()
+        .filter(|l| !l.trim().is_empty() && !l.starts_with(' '))
+        .filter_map(|l| {
+            parse_located(&tool, l).or_else(|| {
+                if is_linker(&tool) {
+                    parse_linker(&tool, l)
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_gcc_errors_and_skips_excerpts() {
+        let text = "\
+kernel/mm/pmm.c: In function 'pmm_init':
+kernel/mm/pmm.c:12:5: error: 'x' undeclared (first use in this function)
+   12 |     x = 1;
+      |     ^
+kernel/mm/pmm.c:3:10: warning: unused variable 'y' [-Wunused-variable]
+";
+        let d = parse("/usr/bin/gcc", text);
+        assert_eq!(d.len(), 2);
+        assert_eq!(d[0].tool, "gcc");
+        assert_eq!(d[0].file.as_deref(), Some("kernel/mm/pmm.c"));
+        assert_eq!((d[0].line, d[0].column), (Some(12), Some(5)));
+        assert_eq!(d[0].severity, Severity::Error);
+        assert_eq!(d[0].message, "'x' undeclared (first use in this function)");
+        assert_eq!(d[1].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn parses_nasm_and_gas_without_columns() {
+        let nasm = parse(
+            "nasm",
+            "boot/stage2.asm:40: error: symbol `foo' not defined",
+        );
+        assert_eq!(nasm[0].line, Some(40));
+        assert_eq!(nasm[0].column, None);
+        assert_eq!(nasm[0].message, "symbol `foo' not defined");
+
+        let gas = parse(
+            "as",
+            "boot.s: Assembler messages:\nboot.s:7: Error: no such instruction: `movz x0'",
+        );
+        assert_eq!(gas.len(), 1);
+        assert_eq!(gas[0].severity, Severity::Error);
+        assert_eq!(gas[0].message, "no such instruction: `movz x0'");
+    }
+
+    #[test]
+    fn parses_linker_messages() {
+        let text = "\
+ld: build/obj/kernel/a.c.o: in function `main':
+kernel/a.c:9: undefined reference to `missing'
+ld: warning: build/obj/boot.S.o: missing .note.GNU-stack section
+ld: cannot find -lc: No such file or directory
+";
+        let d = parse(
+            "/usr/bin/ld",
+            text.replace("ld: c", "/usr/bin/ld: c").as_str(),
+        );
+        assert_eq!(d.len(), 3);
+        assert_eq!(d[0].file.as_deref(), Some("kernel/a.c"));
+        assert_eq!(d[0].message, "undefined reference to `missing'");
+        assert_eq!(d[1].severity, Severity::Warning);
+        assert_eq!(d[2].file, None);
+        assert!(d[2].message.starts_with("cannot find -lc"));
+    }
+
+    #[test]
+    fn tool_failure_keeps_rendering_and_yields_diagnostics() {
+        let err = anyhow::Error::new(ToolFailure {
+            program: "gcc".into(),
+            stderr: "a.c:1:1: error: boom\n".into(),
+        })
+        .context("compiling a.c");
+        assert_eq!(format!("{err:#}"), "compiling a.c: a.c:1:1: error: boom");
+        assert_eq!(from_error(&err)[0].message, "boom");
+    }
+}
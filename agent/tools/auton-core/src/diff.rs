@@ -0,0 +1,689 @@
+//! Unified diffs as files and hunks.
+//!
+//! Understands plain `diff -u` output and git's extended headers: new and
+//! deleted files (`/dev/null` or `new file mode`), renames and copies,
+//! mode changes and binary patches. Paths lose their `a/` / `b/` prefix.
+//! [`unified_diff`] goes the other way, writing the diff between two texts,
+//! and [`merge`] merges two texts' changes to a common base.
+
+use std::fmt;
+
+/// A line of a hunk, without its marker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HunkLine {
+    Context(String),
+    Removed(String),
+    Added(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    /// 1-based, as in the header; 0 for an empty old side.
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    /// The header line, `@@ -a,b +c,d @@ section`.
+    pub header: String,
+    /// Line of the header in the diff, 1-based.
+    pub diff_line: usize,
+    pub lines: Vec<HunkLine>,
+}
+
+impl Hunk {
+    /// The lines the hunk expects to find: context and removed.
+    pub fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|l| match l {
+                HunkLine::Context(s) | HunkLine::Removed(s) => Some(s.as_str()),
+                HunkLine::Added(_) => None,
+            })
+            .collect()
+    }
+
+    /// The lines it leaves: context and added.
+    pub fn new_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|l| match l {
+                HunkLine::Context(s) | HunkLine::Added(s) => Some(s.as_str()),
+                HunkLine::Removed(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// One file's part of a diff.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilePatch {
+    /// `None` for a new file.
+    pub old_path: Option<String>,
+    /// `None` for a deleted file.
+    pub new_path: Option<String>,
+    pub old_mode: Option<u32>,
+    pub new_mode: Option<u32>,
+    /// The (possibly abbreviated) blob hashes of git's `index` line; `None`
+    /// for the missing side of an added or deleted file.
+    pub old_blob: Option<String>,
+    pub new_blob: Option<String>,
+    /// `rename from`/`rename to`, as opposed to a copy.
+    pub rename: bool,
+    pub copy: bool,
+    /// Git binary patch or `Binary files ... differ`: no hunks to check.
+    pub binary: bool,
+    pub hunks: Vec<Hunk>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Deleted,
+    Renamed,
+    Copied,
+    Modified,
+}
+
+impl FilePatch {
+    pub fn change(&self) -> Change {
+        match (&self.old_path, &self.new_path) {
+            (None, _) => Change::Added,
+            (_, None) => Change::Deleted,
+            _ if self.copy => Change::Copied,
+            (Some(a), Some(b)) if self.rename || a != b => Change::Renamed,
+            _ => Change::Modified,
+        }
+    }
+
+    /// The path the patch leaves (the old one for a deletion).
+    pub fn path(&self) -> &str {
+        self.new_path
+            .as_deref()
+            .or(self.old_path.as_deref())
+            .unwrap_or("")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// 1-based line of the diff.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// `a/kernel/x.c\t2024-01-01 ...` → `kernel/x.c`; `/dev/null` → `None`.
+fn header_path(raw: &str) -> Option<String> {
+    let raw = raw.split('\t').next().unwrap_or(raw).trim_end();
+    let raw = raw
+        .strip_prefix('"')
+        .and_then(|r| r.strip_suffix('"'))
+        .unwrap_or(raw);
+    if raw == "/dev/null" {
+        return None;
+    }
+    Some(
+        raw.strip_prefix("a/")
+            .or_else(|| raw.strip_prefix("b/"))
+            .unwrap_or(raw)
+            .to_string(),
+    )
+}
+
+/// `diff --git a/x b/y` → (`x`, `y`), for patches without `---`/`+++`.
+fn git_paths(rest: &str) -> Option<(String, String)> {
+    let rest = rest.strip_prefix("a/")?;
+    let (old, new) = rest.split_once(" b/")?;
+    Some((old.to_string(), new.to_string()))
+}
+
+fn parse_mode(s: &str) -> Option<u32> {
+    u32::from_str_radix(s.trim(), 8).ok()
+}
+
+/// `-a,b` / `+c` → (a, b), the length defaulting to 1.
+fn parse_range(s: &str) -> Option<(usize, usize)> {
+    let (start, len) = match s.split_once(',') {
+        Some((start, len)) => (start, len.parse().ok()?),
+        None => (s, 1),
+    };
+    Some((start.parse().ok()?, len))
+}
+
+/// `@@ -a,b +c,d @@ ...` → (a, b, c, d).
+fn parse_header(line: &str) -> Option<(usize, usize, usize, usize)> {
+    let mut parts = line.strip_prefix("@@ ")?.split(' ');
+    let (old_start, old_len) = parse_range(parts.next()?.strip_prefix('-')?)?;
+    let (new_start, new_len) = parse_range(parts.next()?.strip_prefix('+')?)?;
+    (parts.next()? == "@@").then_some((old_start, old_len, new_start, new_len))
+}
+
+/// Parse every file of a unified diff. Text around the files (a commit
+/// message, say) is skipped; a malformed or truncated hunk is an error.
+pub fn parse(diff: &str) -> Result<Vec<FilePatch>, ParseError> {
+    let lines: Vec<&str> = diff.lines().collect();
+    let mut files: Vec<FilePatch> = Vec::new();
+    // Whether the last file's headers are still being read.
+    let mut in_header = false;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let err = |message: String| ParseError {
+            line: i + 1,
+            message,
+        };
+        if let Some(rest) = line.strip_prefix("diff --git ") {
+            let mut file = FilePatch::default();
+            if let Some((old, new)) = git_paths(rest) {
+                file.old_path = Some(old);
+                file.new_path = Some(new);
+            }
+            files.push(file);
+            in_header = true;
+        } else if let Some(rest) = line.strip_prefix("--- ") {
+            let next = lines.get(i + 1).and_then(|l| l.strip_prefix("+++ "));
+            let Some(new) = next else {
+                return Err(err("`---` without a following `+++`".into()));
+            };
+            if !in_header || files.last().is_some_and(|f| !f.hunks.is_empty()) {
+                files.push(FilePatch::default());
+            }
+            let file = files.last_mut().expect("a file is open");
+            file.old_path = header_path(rest);
+            file.new_path = header_path(new);
+            in_header = true;
+            i += 1;
+        } else if line.starts_with("@@") {
+            let Some(file) = files.last_mut() else {
+                return Err(err("hunk before any file header".into()));
+            };
+            let Some((old_start, old_len, new_start, new_len)) = parse_header(line) else {
+                return Err(err(format!("malformed hunk header `{line}`")));
+            };
+            let mut hunk = Hunk {
+                old_start,
+                old_len,
+                new_start,
+                new_len,
+                header: line.to_string(),
+                diff_line: i + 1,
+                lines: Vec::new(),
+            };
+            let (mut old, mut new) = (0, 0);
+            while old < old_len || new < new_len {
+                i += 1;
+                let Some(&body) = lines.get(i) else {
+                    return Err(ParseError {
+                        line: hunk.diff_line,
+                        message: format!(
+                            "hunk `{}` ends early: {old} of {old_len} old and {new} of {new_len} new lines",
+                            hunk.header
+                        ),
+                    });
+                };
+                // The first char, however wide, so a line missing its
+                // marker is an error rather than a split inside a char.
+                let (marker, text) = body.split_at(body.chars().next().map_or(0, char::len_utf8));
+                let text = text.to_string();
+                match marker {
+                    // Editors strip the space of empty context lines.
+                    " " | "" => {
+                        hunk.lines.push(HunkLine::Context(text));
+                        old += 1;
+                        new += 1;
+                    }
+                    "-" => {
+                        hunk.lines.push(HunkLine::Removed(text));
+                        old += 1;
+                    }
+                    "+" => {
+                        hunk.lines.push(HunkLine::Added(text));
+                        new += 1;
+                    }
+                    "\\" => {}
+                    _ => {
+                        return Err(ParseError {
+                            line: i + 1,
+                            message: format!("unexpected line in hunk `{}`", hunk.header),
+                        })
+                    }
+                }
+            }
+            if old > old_len || new > new_len {
+                return Err(ParseError {
+                    line: hunk.diff_line,
+                    message: format!("hunk `{line}` is longer than its header says"),
+                });
+            }
+            // `\ No newline at end of file` after the last line.
+            if lines.get(i + 1).is_some_and(|l| l.starts_with('\\')) {
+                i += 1;
+            }
+            file.hunks.push(hunk);
+            in_header = false;
+        } else if in_header {
+            let file = files.last_mut().expect("in a file header");
+            if let Some(mode) = line.strip_prefix("new file mode ") {
+                file.old_path = None;
+                file.new_mode = parse_mode(mode);
+            } else if let Some(mode) = line.strip_prefix("deleted file mode ") {
+                file.new_path = None;
+                file.old_mode = parse_mode(mode);
+            } else if let Some(mode) = line.strip_prefix("old mode ") {
+                file.old_mode = parse_mode(mode);
+            } else if let Some(mode) = line.strip_prefix("new mode ") {
+                file.new_mode = parse_mode(mode);
+            } else if let Some(rest) = line.strip_prefix("index ") {
+                let hashes = rest.split(' ').next().unwrap_or_default();
+                if let Some((old, new)) = hashes.split_once("..") {
+                    let blob = |h: &str| (!h.bytes().all(|b| b == b'0')).then(|| h.to_string());
+                    file.old_blob = blob(old);
+                    file.new_blob = blob(new);
+                }
+            } else if let Some(path) = line.strip_prefix("rename from ") {
+                file.old_path = Some(path.to_string());
+                file.rename = true;
+            } else if let Some(path) = line.strip_prefix("rename to ") {
+                file.new_path = Some(path.to_string());
+                file.rename = true;
+            } else if let Some(path) = line.strip_prefix("copy from ") {
+                file.old_path = Some(path.to_string());
+                file.copy = true;
+            } else if let Some(path) = line.strip_prefix("copy to ") {
+                file.new_path = Some(path.to_string());
+                file.copy = true;
+            } else if line.starts_with("Binary files ") || line == "GIT binary patch" {
+                file.binary = true;
+            }
+        }
+        i += 1;
+    }
+    Ok(files)
+}
+
+/// Lines of unchanged context around each diff hunk.
+const CONTEXT: usize = 3;
+
+/// Edit tables larger than this many cells give a whole-file diff.
+const MAX_DIFF_CELLS: usize = 1 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Same,
+    Delete,
+    Insert,
+}
+
+/// The edits turning `old` into `new`: a longest common subsequence over
+/// what lies between their common prefix and suffix.
+fn edits(old: &[&str], new: &[&str]) -> Vec<Edit> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+    let mut out = vec![Edit::Same; prefix];
+    if (a.len() + 1) * (b.len() + 1) > MAX_DIFF_CELLS {
+        out.extend(std::iter::repeat_n(Edit::Delete, a.len()));
+        out.extend(std::iter::repeat_n(Edit::Insert, b.len()));
+    } else {
+        // lcs[i][j]: LCS length of a[i..] and b[j..].
+        let w = b.len() + 1;
+        let mut lcs = vec![0u32; (a.len() + 1) * w];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i * w + j] = if a[i] == b[j] {
+                    lcs[(i + 1) * w + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * w + j].max(lcs[i * w + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                out.push(Edit::Same);
+                i += 1;
+                j += 1;
+            } else if j == b.len() || (i < a.len() && lcs[(i + 1) * w + j] >= lcs[i * w + j + 1]) {
+                out.push(Edit::Delete);
+                i += 1;
+            } else {
+                out.push(Edit::Insert);
+                j += 1;
+            }
+        }
+    }
+    out.extend(std::iter::repeat_n(Edit::Same, suffix));
+    out
+}
+
+/// A unified diff from `old` to `new` with [`CONTEXT`] lines of context.
+pub fn unified_diff(old: &[&str], new: &[&str], old_name: &str, new_name: &str) -> String {
+    let edits = edits(old, new);
+    let mut out = format!("--- {old_name}\n+++ {new_name}\n");
+    // Line positions in `old` and `new` before each edit.
+    let mut pos = Vec::with_capacity(edits.len() + 1);
+    let (mut i, mut j) = (0, 0);
+    for e in &edits {
+        pos.push((i, j));
+        match e {
+            Edit::Same => (i, j) = (i + 1, j + 1),
+            Edit::Delete => i += 1,
+            Edit::Insert => j += 1,
+        }
+    }
+    pos.push((i, j));
+    let changed: Vec<usize> = (0..edits.len())
+        .filter(|&k| edits[k] != Edit::Same)
+        .collect();
+    let mut k = 0;
+    while k < changed.len() {
+        let first = changed[k];
+        let mut last = first;
+        while k + 1 < changed.len() && changed[k + 1] - last <= 2 * CONTEXT + 1 {
+            k += 1;
+            last = changed[k];
+        }
+        k += 1;
+        let from = first.saturating_sub(CONTEXT);
+        let to = (last + 1 + CONTEXT).min(edits.len());
+        let ((a0, b0), (a1, b1)) = (pos[from], pos[to]);
+        let start = |s: usize, len: usize| if len == 0 { s } else { s + 1 };
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            start(a0, a1 - a0),
+            a1 - a0,
+            start(b0, b1 - b0),
+            b1 - b0
+        ));
+        for e in from..to {
+            let (a, b) = pos[e];
+            let line = match edits[e] {
+                Edit::Same => format!(" {}", old[a]),
+                Edit::Delete => format!("-{}", old[a]),
+                Edit::Insert => format!("+{}", new[b]),
+            };
+            out.push_str(&line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// A stretch where both sides changed the base differently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    /// 1-based first line of the stretch in `ours` (where it would be if
+    /// empty there), and the lines each side has in its place.
+    pub line: usize,
+    pub ours: Vec<String>,
+    pub theirs: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Merged {
+    /// `ours` with `theirs`' changes, keeping `ours` where they conflict.
+    pub lines: Vec<String>,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// The lines of `base` that `new` replaces: `(start, end, replacement)`,
+/// in order.
+fn changes<'a>(base: &[&str], new: &[&'a str]) -> Vec<(usize, usize, Vec<&'a str>)> {
+    let mut out: Vec<(usize, usize, Vec<&str>)> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let mut open = false;
+    for e in edits(base, new) {
+        match e {
+            Edit::Same => {
+                open = false;
+                i += 1;
+                j += 1;
+                continue;
+            }
+            _ if !open => {
+                out.push((i, i, Vec::new()));
+                open = true;
+            }
+            _ => {}
+        }
+        let last = out.last_mut().expect("a change is open");
+        if e == Edit::Delete {
+            i += 1;
+            last.1 = i;
+        } else {
+            last.2.push(new[j]);
+            j += 1;
+        }
+    }
+    out
+}
+
+/// Merge the changes `ours` and `theirs` each made to `base`. Changes that
+/// overlap or touch conflict unless they are the same, as in git.
+pub fn merge(base: &[&str], ours: &[&str], theirs: &[&str]) -> Merged {
+    let (a, b) = (changes(base, ours), changes(base, theirs));
+    let mut merged = Merged {
+        lines: Vec::new(),
+        conflicts: Vec::new(),
+    };
+    let (mut x, mut y, mut at) = (0, 0, 0);
+    while x < a.len() || y < b.len() {
+        // The next group of changes whose base stretches overlap or touch.
+        let mut start = usize::MAX;
+        if let Some(c) = a.get(x) {
+            start = c.0;
+        }
+        if let Some(c) = b.get(y) {
+            start = start.min(c.0);
+        }
+        let (x0, y0) = (x, y);
+        let mut end = start;
+        loop {
+            if let Some(c) = a.get(x).filter(|c| c.0 <= end) {
+                end = end.max(c.1);
+                x += 1;
+            } else if let Some(c) = b.get(y).filter(|c| c.0 <= end) {
+                end = end.max(c.1);
+                y += 1;
+            } else {
+                break;
+            }
+        }
+        merged
+            .lines
+            .extend(base[at..start].iter().map(|l| l.to_string()));
+        // Each side's text for base[start..end].
+        let side = |cs: &[(usize, usize, Vec<&str>)]| {
+            let mut out = Vec::new();
+            let mut pos = start;
+            for c in cs {
+                out.extend(base[pos..c.0].iter().map(|l| l.to_string()));
+                out.extend(c.2.iter().map(|l| l.to_string()));
+                pos = c.1;
+            }
+            out.extend(base[pos..end].iter().map(|l| l.to_string()));
+            out
+        };
+        let (ours_text, theirs_text) = (side(&a[x0..x]), side(&b[y0..y]));
+        if y == y0 || ours_text == theirs_text {
+            merged.lines.extend(ours_text);
+        } else if x == x0 {
+            merged.lines.extend(theirs_text);
+        } else {
+            // Where the stretch starts in `ours`: the base lines and
+            // `ours`' changes before it.
+            let shift: isize = a[..x0]
+                .iter()
+                .map(|c| c.2.len() as isize - (c.1 - c.0) as isize)
+                .sum();
+            merged.conflicts.push(Conflict {
+                line: (start as isize + shift) as usize + 1,
+                ours: ours_text.clone(),
+                theirs: theirs_text,
+            });
+            merged.lines.extend(ours_text);
+        }
+        at = end;
+    }
+    merged
+        .lines
+        .extend(base[at..].iter().map(|l| l.to_string()));
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hunks_and_their_lines() {
+        let files = parse(
+            "--- a/kernel/mm/pmm.c\t2024-05-01\n+++ b/kernel/mm/pmm.c\n@@ -1,3 +1,3 @@ void pmm(void)\n a\n-b\n+B\n c\n@@ -10 +10,2 @@\n x\n+y\n",
+        )
+        .unwrap();
+        assert_eq!(files.len(), 1);
+        let f = &files[0];
+        assert_eq!(f.change(), Change::Modified);
+        assert_eq!(f.path(), "kernel/mm/pmm.c");
+        assert_eq!(f.hunks.len(), 2);
+        assert_eq!(f.hunks[0].old_lines(), ["a", "b", "c"]);
+        assert_eq!(f.hunks[0].new_lines(), ["a", "B", "c"]);
+        assert_eq!(f.hunks[0].diff_line, 3);
+        assert_eq!((f.hunks[1].old_start, f.hunks[1].old_len), (10, 1));
+    }
+
+    #[test]
+    fn understands_git_headers() {
+        let diff = "\
+diff --git a/kernel/old.c b/kernel/new.c
+similarity index 90%
+rename from kernel/old.c
+rename to kernel/new.c
+diff --git a/tools/run.sh b/tools/run.sh
+old mode 100644
+new mode 100755
+diff --git a/kernel/gone.c b/kernel/gone.c
+deleted file mode 100644
+index 1111111..0000000
+--- a/kernel/gone.c
++++ /dev/null
+@@ -1 +0,0 @@
+-int gone;
+diff --git a/kernel/fresh.c b/kernel/fresh.c
+new file mode 100644
+--- /dev/null
++++ b/kernel/fresh.c
+@@ -0,0 +1 @@
++int fresh;
+\\ No newline at end of file
+diff --git a/logo.bin b/logo.bin
+Binary files a/logo.bin and b/logo.bin differ
+";
+        let files = parse(diff).unwrap();
+        let changes: Vec<Change> = files.iter().map(FilePatch::change).collect();
+        assert_eq!(
+            changes,
+            [
+                Change::Renamed,
+                Change::Modified,
+                Change::Deleted,
+                Change::Added,
+                Change::Modified
+            ]
+        );
+        assert_eq!(files[0].old_path.as_deref(), Some("kernel/old.c"));
+        assert_eq!(
+            (files[1].old_mode, files[1].new_mode),
+            (Some(0o100644), Some(0o100755))
+        );
+        assert_eq!(files[2].path(), "kernel/gone.c");
+        assert_eq!(
+            (files[2].old_blob.as_deref(), files[2].new_blob.as_deref()),
+            (Some("1111111"), None)
+        );
+        assert_eq!(files[3].new_mode, Some(0o100644));
+        assert!(files[4].binary && files[4].hunks.is_empty());
+    }
+
+    #[test]
+    fn rejects_broken_hunks() {
+        let truncated = parse("--- a/x.c\n+++ b/x.c\n@@ -1,3 +1,3 @@\n a\n-b\n").unwrap_err();
+        assert_eq!(truncated.line, 3);
+        assert!(truncated.message.contains("ends early"), "{truncated}");
+        let header = parse("--- a/x.c\n+++ b/x.c\n@@ -x +1 @@\n").unwrap_err();
+        assert!(header.message.contains("malformed"));
+        assert!(parse("@@ -1 +1 @@\n-a\n+b\n").is_err());
+        let unmarked = parse("--- a/x.c\n+++ b/x.c\n@@ -1 +1 @@\nété = 1;\n").unwrap_err();
+        assert_eq!(unmarked.line, 4);
+        assert!(unmarked.message.contains("unexpected line"), "{unmarked}");
+        assert_eq!(parse("just a commit message\n").unwrap(), []);
+    }
+
+    #[test]
+    fn mismatches_show_a_unified_diff() {
+        let old: Vec<String> = (1..=12).map(|i| format!("line {i}")).collect();
+        let old: Vec<&str> = old.iter().map(String::as_str).collect();
+        let mut new = old.clone();
+        new[1] = "line two";
+        new.remove(9);
+        new.push("line 13");
+        assert_eq!(
+            unified_diff(&old, &new, "boot.golden", "serial"),
+            "--- boot.golden\n+++ serial\n\
+             @@ -1,5 +1,5 @@\n line 1\n-line 2\n+line two\n line 3\n line 4\n line 5\n\
+             @@ -7,6 +7,6 @@\n line 7\n line 8\n line 9\n-line 10\n line 11\n line 12\n+line 13\n"
+        );
+        assert_eq!(
+            unified_diff(&[], &["a"], "g", "s"),
+            "--- g\n+++ s\n@@ -0,0 +1,1 @@\n+a\n"
+        );
+    }
+
+    #[test]
+    fn merges_separate_changes_and_finds_conflicts() {
+        let base = ["a", "b", "c", "d", "e", "f", "g"];
+        // Ours inserts at the top; theirs edits further down: no conflict.
+        let merged = merge(
+            &base,
+            &["new", "a", "b", "c", "d", "e", "f", "g"],
+            &["a", "b", "c", "D", "e", "f", "g"],
+        );
+        assert_eq!(merged.lines, ["new", "a", "b", "c", "D", "e", "f", "g"]);
+        assert!(merged.conflicts.is_empty());
+
+        // The same change on both sides is taken once.
+        let same = ["a", "B", "c", "d", "e", "f", "g"];
+        assert_eq!(merge(&base, &same, &same).lines, same);
+
+        // Both change `d`, differently.
+        let merged = merge(
+            &base,
+            &["x", "a", "b", "c", "ours", "e", "f", "g"],
+            &["a", "b", "c", "theirs", "e", "f", "g"],
+        );
+        assert_eq!(
+            merged.conflicts,
+            [Conflict {
+                line: 5,
+                ours: vec!["ours".into()],
+                theirs: vec!["theirs".into()],
+            }]
+        );
+        assert_eq!(merged.lines[4], "ours");
+    }
+}
@@ -0,0 +1,123 @@
+//! Just enough HTTP/1.1 to POST JSON to a local receiver: webhooks and
+//! trace collectors. Only `http://` is supported; there is no TLS here,
+//! so anything remote needs a relay in front of it.
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// `http://host[:port]/path`, split for connecting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpUrl {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl HttpUrl {
+    pub fn parse(url: &str) -> Result<Self> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            if url.starts_with("https://") {
+                bail!("{url}: https is not supported (no TLS); send to an http relay");
+            }
+            bail!("{url}: not an http:// URL");
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(v6) => {
+                let (host, after) = v6
+                    .split_once(']')
+                    .with_context(|| format!("{url}: unclosed `[`"))?;
+                (host, after.strip_prefix(':'))
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .with_context(|| format!("{url}: invalid port `{port}`"))?,
+            None => 80,
+        };
+        if host.is_empty() {
+            bail!("{url}: no host");
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// One HTTP/1.1 request; the response's status code.
+pub async fn post(url: &HttpUrl, headers: &[(&str, String)], body: &[u8]) -> Result<u16> {
+    let mut stream = TcpStream::connect((url.host.as_str(), url.port))
+        .await
+        .with_context(|| format!("connecting to {}:{}", url.host, url.port))?;
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nUser-Agent: auton/{}\r\n\
+         Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.host,
+        url.port,
+        env!("CARGO_PKG_VERSION"),
+        body.len()
+    );
+    for (name, value) in headers {
+        request += &format!("{name}: {value}\r\n");
+    }
+    request += "\r\n";
+    stream.write_all(request.as_bytes()).await?;
+    stream.write_all(body).await?;
+    // The status line is all that matters; read no further than it.
+    let mut response = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !response.contains(&b'\n') && response.len() < 8192 {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..n]);
+    }
+    let line = String::from_utf8_lossy(&response);
+    let status = line
+        .strip_prefix("HTTP/1.")
+        .and_then(|rest| rest.get(2..5))
+        .and_then(|code| code.parse().ok());
+    status.with_context(|| {
+        format!(
+            "not an HTTP response: {:?}",
+            line.lines().next().unwrap_or("")
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_http_urls() {
+        assert_eq!(
+            HttpUrl::parse("http://example.com").unwrap(),
+            HttpUrl {
+                host: "example.com".into(),
+                port: 80,
+                path: "/".into()
+            }
+        );
+        let url = HttpUrl::parse("http://[::1]:9000/a/b?c=d").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("::1", 9000));
+        assert_eq!(url.path, "/a/b?c=d");
+        let https = HttpUrl::parse("https://example.com/").unwrap_err();
+        assert!(https.to_string().contains("no TLS"));
+        assert_eq!(HttpUrl::parse("http://[::1]/").unwrap().port, 80);
+        assert!(HttpUrl::parse("http://host:port/").is_err());
+    }
+}
@@ -0,0 +1,24 @@
+//! Pieces shared by kernel-builder, test-runner and diff-validator, and
+//! available to anything else that embeds them.
+//!
+//! [`diff`] models unified diffs, [`manifest`] is the build manifest
+//! kernel-builder writes and the others read, [`diagnostics`] parses
+//! compiler, assembler and linker messages, [`process`] runs a tool under
+//! an optional timeout, [`store`] keeps artifacts by content hash,
+//! [`lock`] keeps concurrent runs off each other's files and ports, and
+//! [`logging`] sets up tracing the same way in every binary, exporting
+//! spans to OpenTelemetry when asked ([`telemetry`], over [`http`]), and
+//! [`plan`] is what each tool's `--dry-run` prints. Every
+//! result is a plain serde struct, so it can be reported as JSON unchanged.
+
+pub mod diagnostics;
+pub mod diff;
+pub mod hash;
+pub mod http;
+pub mod lock;
+pub mod logging;
+pub mod manifest;
+pub mod plan;
+pub mod process;
+pub mod store;
+pub mod telemetry;
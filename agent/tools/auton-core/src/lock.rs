@@ -0,0 +1,115 @@
+//! Advisory locks between tool processes, so concurrent agent sessions do
+//! not race on what they share.
+//!
+//! [`Lock`] is an exclusive `flock(2)` on a file, released when it is
+//! dropped or its process dies, however it dies. [`lease_port`] hands out
+//! a localhost port no other process holding a lease has: finding a free
+//! port by binding port 0 and closing it again leaves a gap in which a
+//! second run is handed the same one before QEMU has bound it, so each
+//! leased port is also locked in [`PORTS_DIR`] under the temp dir, and
+//! stays so until the process exits.
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Where [`lease_port`] keeps its locks, under the temp dir.
+pub const PORTS_DIR: &str = "auton-ports";
+
+/// Ports tried before giving up on finding one nobody has leased.
+const PORT_ATTEMPTS: usize = 64;
+
+/// The locks behind this process's leased ports.
+static LEASES: Mutex<Vec<Lock>> = Mutex::new(Vec::new());
+
+/// An exclusive lock on a file.
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Wait for the lock on `path`, creating the file if need be.
+    pub fn acquire(path: &Path) -> Result<Self> {
+        Self::flock(path, libc::LOCK_EX)?
+            .with_context(|| format!("locking {}: would block", path.display()))
+    }
+
+    /// The lock on `path` if nobody else holds it.
+    pub fn try_acquire(path: &Path) -> Result<Option<Self>> {
+        Self::flock(path, libc::LOCK_EX | libc::LOCK_NB)
+    }
+
+    fn flock(path: &Path, operation: libc::c_int) -> Result<Option<Self>> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("opening {}", path.display()))?;
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                return Ok(Some(Self {
+                    _file: file,
+                    path: path.to_path_buf(),
+                }));
+            }
+            let e = std::io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EWOULDBLOCK) => return Ok(None),
+                _ => return Err(e).with_context(|| format!("locking {}", path.display())),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// A free localhost port that no other process has leased, held for the
+/// rest of this process.
+pub fn lease_port() -> Result<u16> {
+    let dir = std::env::temp_dir().join(PORTS_DIR);
+    for _ in 0..PORT_ATTEMPTS {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").context("finding a free port")?;
+        let port = listener.local_addr()?.port();
+        if let Some(lock) = Lock::try_acquire(&dir.join(port.to_string()))? {
+            LEASES.lock().unwrap().push(lock);
+            return Ok(port);
+        }
+    }
+    anyhow::bail!("no free port after {PORT_ATTEMPTS} tries: every one was leased")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_held_lock_is_not_acquired_again() {
+        let path = std::env::temp_dir().join(format!("auton-lock-{}", std::process::id()));
+        let lock = Lock::acquire(&path).unwrap();
+        // flock locks belong to the open file, so a second open conflicts
+        // even within one process.
+        assert!(Lock::try_acquire(&path).unwrap().is_none());
+        drop(lock);
+        assert!(Lock::try_acquire(&path).unwrap().is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn leased_ports_are_distinct_and_locked() {
+        let (a, b) = (lease_port().unwrap(), lease_port().unwrap());
+        assert_ne!(a, b);
+        let held = std::env::temp_dir().join(PORTS_DIR).join(a.to_string());
+        assert!(Lock::try_acquire(&held).unwrap().is_none());
+    }
+}
@@ -0,0 +1,327 @@
+//! Tracing setup shared by the tool binaries, and the run ID that ties
+//! their logs together.
+//!
+//! Every invocation has a run ID ([`run_id`]): the one in [`RUN_ID`] when
+//! the process was started with it, else a fresh one.
+//! [`process::run`](crate::process::run) hands it and [`LOG_DIR`] on to
+//! every tool it starts, so one auton iteration, the kernel-builder builds
+//! and the test-runner VMs it runs all share it.
+//!
+//! With `AUTON_LOG_DIR` set, every event at debug level and above is also
+//! appended as a JSON Line to `<dir>/<run id>.jsonl`, one file per run that
+//! all of its tools write to:
+//!
+//! ```json
+//! {"fields":{"entry":"0x101000"},"level":"INFO","message":"linked","pid":4242,
+//!  "run_id":"4f1c9a0b2d3e5f67","spans":[{"arch":"x86_64","name":"build"}],
+//!  "target":"kernel_builder::pipeline","tool":"kernel-builder","ts":"2026-10-14T15:38:31.680Z"}
+//! ```
+//!
+//! Each line is one `write` to a file opened for appending, so lines from
+//! concurrent tools do not interleave.
+
+use crate::telemetry;
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// The environment variable a run ID travels in.
+pub const RUN_ID: &str = "AUTON_RUN_ID";
+
+/// The environment variable naming the JSON Lines log directory.
+pub const LOG_DIR: &str = "AUTON_LOG_DIR";
+
+/// Install the `tracing` subscriber. Logs go to stderr so `--json` and
+/// JSON Lines output on stdout stay parseable; spans also go to an OTLP
+/// collector if one is configured ([`telemetry`]), and events to the run's
+/// log file if [`LOG_DIR`] is set.
+pub fn init() {
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_filter(LevelFilter::INFO),
+        )
+        .with(telemetry::layer().with_filter(LevelFilter::INFO))
+        .with(file_layer().with_filter(LevelFilter::DEBUG))
+        .init();
+}
+
+/// This invocation's run ID.
+pub fn run_id() -> &'static str {
+    static ID: OnceLock<String> = OnceLock::new();
+    ID.get_or_init(|| {
+        std::env::var(RUN_ID)
+            .ok()
+            .filter(|id| valid_run_id(id))
+            .unwrap_or_else(|| crate::hash::hex(&telemetry::random::<8>()))
+    })
+}
+
+/// Run IDs name files, so they are short and plain.
+fn valid_run_id(id: &str) -> bool {
+    (1..=64).contains(&id.len())
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// The JSON Lines directory, from [`LOG_DIR`].
+pub fn log_dir() -> Option<PathBuf> {
+    std::env::var_os(LOG_DIR)
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+}
+
+/// The run's log file in `dir`.
+pub fn log_path(dir: &Path) -> PathBuf {
+    dir.join(format!("{}.jsonl", run_id()))
+}
+
+/// The environment a child tool needs to join this run.
+pub fn child_env() -> Vec<(&'static str, String)> {
+    let mut env = vec![(RUN_ID, run_id().to_string())];
+    if let Some(dir) = log_dir() {
+        env.push((LOG_DIR, dir.display().to_string()));
+    }
+    env
+}
+
+/// The layer that writes JSON Lines to the run's log file; `None` without
+/// [`LOG_DIR`] or when the file cannot be opened (which only warns).
+pub fn file_layer() -> Option<JsonLayer> {
+    let dir = log_dir()?;
+    let path = log_path(&dir);
+    let opened = std::fs::create_dir_all(&dir)
+        .and_then(|()| File::options().create(true).append(true).open(&path));
+    match opened {
+        Ok(file) => Some(JsonLayer::new(file)),
+        Err(e) => {
+            eprintln!("not logging to {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// Writes each event as one JSON Line.
+pub struct JsonLayer {
+    sink: Arc<Mutex<dyn Write + Send>>,
+    tool: String,
+}
+
+impl JsonLayer {
+    pub fn new(sink: impl Write + Send + 'static) -> Self {
+        let tool = std::env::current_exe()
+            .ok()
+            .and_then(|exe| Some(exe.file_stem()?.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+        Self {
+            sink: Arc::new(Mutex::new(sink)),
+            tool,
+        }
+    }
+}
+
+/// A span's fields, kept in its extensions for the events inside it.
+struct SpanFields(Map<String, Value>);
+
+/// Collects fields into a JSON object; `message` goes to its own slot.
+struct Fields<'a> {
+    fields: &'a mut Map<String, Value>,
+    message: Option<&'a mut Option<String>>,
+}
+
+impl Fields<'_> {
+    fn add(&mut self, field: &Field, value: Value) {
+        if field.name() == "message" {
+            if let Some(message) = self.message.as_deref_mut() {
+                *message = Some(match value {
+                    Value::String(s) => s,
+                    other => other.to_string(),
+                });
+                return;
+            }
+        }
+        self.fields.insert(field.name().to_string(), value);
+    }
+}
+
+impl Visit for Fields<'_> {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.add(field, json!(value));
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.add(field, json!(value));
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.add(field, json!(value));
+    }
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.add(field, json!(value));
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.add(field, json!(value));
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.add(field, json!(format!("{value:?}")));
+    }
+}
+
+impl<S> Layer<S> for JsonLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Map::new();
+        attrs.record(&mut Fields {
+            fields: &mut fields,
+            message: None,
+        });
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            values.record(&mut Fields {
+                fields,
+                message: None,
+            });
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let (mut fields, mut message) = (Map::new(), None);
+        event.record(&mut Fields {
+            fields: &mut fields,
+            message: Some(&mut message),
+        });
+        let spans: Vec<Value> = ctx
+            .event_scope(event)
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .map(|span| {
+                let mut entry = Map::new();
+                entry.insert("name".into(), json!(span.name()));
+                if let Some(SpanFields(f)) = span.extensions().get::<SpanFields>() {
+                    entry.extend(f.clone());
+                }
+                Value::Object(entry)
+            })
+            .collect();
+        let meta = event.metadata();
+        let line = json!({
+            "ts": rfc3339(SystemTime::now()),
+            "run_id": run_id(),
+            "tool": self.tool,
+            "pid": std::process::id(),
+            "level": meta.level().as_str(),
+            "target": meta.target(),
+            "message": message.unwrap_or_default(),
+            "fields": fields,
+            "spans": spans,
+        });
+        let mut bytes = line.to_string().into_bytes();
+        bytes.push(b'\n');
+        let mut sink = self.sink.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = sink.write_all(&bytes);
+    }
+}
+
+/// `YYYY-MM-DDTHH:MM:SS.mmmZ`.
+fn rfc3339(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs();
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    // Days since 1970-01-01 to a Gregorian date (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let (era, doe) = (z.div_euclid(146_097), z.rem_euclid(146_097));
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        d.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// A sink the test can read back.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn formats_utc_timestamps() {
+        let t = UNIX_EPOCH + Duration::from_millis(1_792_000_711_680);
+        assert_eq!(rfc3339(t), "2026-10-14T17:58:31.680Z");
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn run_ids_are_plain_names() {
+        assert!(valid_run_id(run_id()));
+        assert!(valid_run_id("ci-1234_a"));
+        assert!(!valid_run_id("../etc"));
+        assert!(!valid_run_id(""));
+    }
+
+    #[test]
+    fn events_become_json_lines_with_their_spans() {
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::registry().with(JsonLayer::new(buffer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("build", arch = "x86_64", stage = tracing::field::Empty);
+            let _entered = span.enter();
+            span.record("stage", "link");
+            tracing::info!(entry = "0x101000", objects = 29u64, "linked");
+        });
+        let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: Value = serde_json::from_str(text.trim_end()).unwrap();
+        assert_eq!(text.lines().count(), 1);
+        assert_eq!(line["run_id"], run_id());
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "linked");
+        assert_eq!(line["fields"], json!({"entry": "0x101000", "objects": 29}));
+        assert_eq!(
+            line["spans"],
+            json!([{"name": "build", "arch": "x86_64", "stage": "link"}])
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! `<output>/manifest.json`: what a build produced and how to boot it.
+//!
+//! kernel-builder writes it; test-runner, diff-validator and the
+//! orchestrator read it instead of re-deriving artifact paths, flags and
+//! QEMU settings by convention. Every artifact carries a SHA-256 so
+//! consumers can tell whether it changed. Reading is lenient: every field
+//! may be missing, so a partial or older manifest still yields what it has.
+
+use crate::hash::{hex, Sha256};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Bumped when a field changes meaning or disappears.
+pub const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArtifactKind {
+    /// Relocatable object fed to the linker.
+    Object,
+    /// Flat binary from the assembly stage (e.g. a stage2 loader).
+    Binary,
+    /// Static library from the Rust stage.
+    StaticLib,
+    /// The linked kernel.
+    Kernel,
+    /// A bootable ISO or disk image.
+    Image,
+    /// `symbols.json` for the kernel.
+    Symbols,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Artifact {
+    pub path: String,
+    pub kind: ArtifactKind,
+    /// Source file this artifact was built from (objects and binaries).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Exact tool and arguments that produced it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub program: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Artifact {
+    /// Describe the file at `path`, hashing its current contents.
+    pub fn from_file(path: &Path, kind: ArtifactKind) -> Result<Self> {
+        let (size, sha256) = hash_file(path)?;
+        Ok(Self {
+            path: path.display().to_string(),
+            kind,
+            source: None,
+            program: None,
+            args: Vec::new(),
+            size,
+            sha256,
+        })
+    }
+
+    /// Attach the command that built this artifact.
+    pub fn built_by(mut self, source: &Path, program: &str, args: &[String]) -> Self {
+        self.source = Some(source.display().to_string());
+        self.program = Some(program.to_string());
+        self.args = args.to_vec();
+        self
+    }
+}
+
+/// One tool that took part in the build.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ToolInfo {
+    pub program: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// First line of `--version`, or the parsed version for the compiler.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Toolchain {
+    pub cc: ToolInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub linker: Option<ToolInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub assemblers: Vec<ToolInfo>,
+}
+
+/// Wall-clock duration of one pipeline stage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StageTiming {
+    pub stage: String,
+    pub ms: u64,
+}
+
+/// Collects [`StageTiming`]s in the order stages finish.
+#[derive(Debug)]
+pub struct Timings {
+    stages: Vec<StageTiming>,
+    mark: Instant,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self {
+            stages: Vec::new(),
+            mark: Instant::now(),
+        }
+    }
+}
+
+impl Timings {
+    /// Close the current stage as `stage` and start timing the next one.
+    pub fn lap(&mut self, stage: &str) {
+        let now = Instant::now();
+        self.stages.push(StageTiming {
+            stage: stage.to_string(),
+            ms: now.duration_since(self.mark).as_millis() as u64,
+        });
+        self.mark = now;
+    }
+
+    pub fn into_stages(self) -> Vec<StageTiming> {
+        self.stages
+    }
+}
+
+/// Firmware an image boots under.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum BootMode {
+    /// Legacy BIOS (QEMU's default SeaBIOS, or OpenSBI via `-bios default`).
+    #[default]
+    Bios,
+    /// UEFI (OVMF).
+    Uefi,
+}
+
+/// QEMU launch defaults for the build's architecture.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QemuDefaults {
+    pub binary: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub machine: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BuildManifest {
+    pub version: u32,
+    pub arch: String,
+    /// kernel-builder's `--profile` (`release`, `debug`, …).
+    pub profile: String,
+    pub workspace: String,
+    /// `git rev-parse HEAD` of the workspace, when it is a checkout.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    pub kernel: String,
+    /// `symbols.json` for the kernel, for backtrace symbolization.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symbols: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+    /// Firmware the images need (OVMF for `uefi`); absent without images.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot: Option<BootMode>,
+    pub qemu: QemuDefaults,
+    pub toolchain: Toolchain,
+    pub artifacts: Vec<Artifact>,
+    pub timings: Vec<StageTiming>,
+    /// The artifact store the artifacts were added to, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store: Option<String>,
+}
+
+impl BuildManifest {
+    /// Write pretty-printed JSON to `<output>/manifest.json`.
+    pub fn write(&self, output: &Path) -> Result<PathBuf> {
+        let path = output.join(MANIFEST_NAME);
+        let text = serde_json::to_string_pretty(self)?;
+        std::fs::write(&path, text + "\n")
+            .with_context(|| format!("writing {}", path.display()))?;
+        Ok(path)
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// The manifest in `image`'s directory, if a build left one there.
+    pub fn beside(image: &Path) -> Option<Self> {
+        let path = image.parent()?.join(MANIFEST_NAME);
+        if !path.is_file() {
+            return None;
+        }
+        match Self::read(&path) {
+            Ok(m) => Some(m),
+            Err(e) => {
+                tracing::warn!("ignoring unreadable manifest: {e:#}");
+                None
+            }
+        }
+    }
+
+    /// The target architecture, if the manifest names one.
+    pub fn arch(&self) -> Option<&str> {
+        Some(self.arch.as_str()).filter(|a| !a.is_empty())
+    }
+
+    /// What to boot: an ISO if one was built, else the first image, else
+    /// the linked kernel.
+    pub fn boot_image(&self) -> Option<PathBuf> {
+        self.images
+            .iter()
+            .find(|p| p.ends_with(".iso"))
+            .or(self.images.first())
+            .map(String::as_str)
+            .or(Some(self.kernel.as_str()).filter(|k| !k.is_empty()))
+            .map(PathBuf::from)
+    }
+}
+
+/// Size and SHA-256 (hex) of a file, streamed so large images stay cheap.
+pub fn hash_file(path: &Path) -> Result<(u64, String)> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("hashing {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut size = 0u64;
+    loop {
+        let n = file
+            .read(&mut buf)
+            .with_context(|| format!("hashing {}", path.display()))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((size, hex(&hasher.finish())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> BuildManifest {
+        BuildManifest {
+            version: MANIFEST_VERSION,
+            arch: "aarch64".into(),
+            profile: "release".into(),
+            workspace: "kernels/aarch64".into(),
+            kernel: "build/kernel.elf".into(),
+            qemu: QemuDefaults {
+                binary: "qemu-system-aarch64".into(),
+                machine: Some("virt".into()),
+                cpu: Some("cortex-a53".into()),
+                extra: Vec::new(),
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn round_trips_and_omits_empty_fields() {
+        let v = serde_json::to_value(manifest()).unwrap();
+        assert_eq!(v["qemu"]["machine"], "virt");
+        assert!(v.get("images").is_none());
+        assert!(v.get("git_commit").is_none());
+        let back: BuildManifest = serde_json::from_value(v).unwrap();
+        assert_eq!(back, manifest());
+    }
+
+    #[test]
+    fn partial_manifests_read_with_defaults() {
+        let m: BuildManifest = serde_json::from_str(r#"{"arch": "riscv64"}"#).unwrap();
+        assert_eq!(m.arch(), Some("riscv64"));
+        assert_eq!(m.qemu, QemuDefaults::default());
+        assert_eq!(BuildManifest::default().arch(), None);
+    }
+
+    #[test]
+    fn boot_image_prefers_iso_then_image_then_kernel() {
+        let mut m = manifest();
+        assert_eq!(m.boot_image(), Some(PathBuf::from("build/kernel.elf")));
+        m.images = vec!["b/auton.img".into(), "b/auton.iso".into()];
+        assert_eq!(m.boot_image(), Some(PathBuf::from("b/auton.iso")));
+        m.images.pop();
+        assert_eq!(m.boot_image(), Some(PathBuf::from("b/auton.img")));
+        assert_eq!(BuildManifest::default().boot_image(), None);
+    }
+
+    #[test]
+    fn artifacts_carry_size_hash_and_command() {
+        let path = std::env::temp_dir().join(format!("auton-core-manifest-{}", std::process::id()));
+        std::fs::write(&path, b"abc").unwrap();
+        let art = Artifact::from_file(&path, ArtifactKind::Object)
+            .unwrap()
+            .built_by(Path::new("boot.S"), "gcc", &["-c".to_string()]);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(art.size, 3);
+        assert_eq!(
+            art.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let v = serde_json::to_value(&art).unwrap();
+        assert_eq!(v["kind"], "object");
+        assert_eq!(v["source"], "boot.S");
+        assert_eq!(v["args"][0], "-c");
+    }
+
+    #[test]
+    fn timings_keep_stage_order() {
+        let mut t = Timings::default();
+        t.lap("assemble");
+        t.lap("compile");
+        let names: Vec<_> = t.into_stages().into_iter().map(|s| s.stage).collect();
+        assert_eq!(names, vec!["assemble", "compile"]);
+    }
+}
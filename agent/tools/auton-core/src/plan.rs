@@ -0,0 +1,229 @@
+//! What a `--dry-run` would do, without doing it.
+//!
+//! A [`Plan`] lists the external commands a tool would run, in order and
+//! with their full argument vectors, the environment they are given on top
+//! of the tool's own, and their working directory; the files the tool would
+//! write itself; and, for builds, how many compile jobs the object cache
+//! would answer. Each tool builds it from the same planning its real run
+//! uses, so the two cannot drift apart, and runs nothing to build it beyond
+//! read-only probes (which compiler is on PATH, the git commit).
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Plan {
+    pub commands: Vec<Planned>,
+    pub writes: Vec<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheEstimate>,
+}
+
+/// One command a tool would run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Planned {
+    /// `compiling kernel/mm/pmm.c`.
+    pub what: String,
+    pub program: String,
+    pub args: Vec<String>,
+    /// Variables set for this command only.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// Whether the object cache would restore the output instead of running
+    /// the command; `None` for commands the cache does not cover.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cached: Option<bool>,
+    /// `program` is not on PATH: the name is the one a real run would look
+    /// for first, and the run would fail here.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub unresolved: bool,
+}
+
+/// Compile jobs the object cache would and would not answer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEstimate {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl Planned {
+    pub fn new(what: impl Into<String>, program: &str, args: &[String]) -> Self {
+        Self {
+            what: what.into(),
+            program: program.to_string(),
+            args: args.to_vec(),
+            ..Self::default()
+        }
+    }
+
+    /// What `cmd` would run, as built for the real run (a container
+    /// backend's wrapping included).
+    pub fn of(what: impl Into<String>, cmd: &std::process::Command) -> Self {
+        let env = cmd
+            .get_envs()
+            .filter_map(|(k, v)| Some((k.to_string_lossy().into(), v?.to_string_lossy().into())))
+            .collect();
+        Self {
+            what: what.into(),
+            program: cmd.get_program().to_string_lossy().into(),
+            args: cmd.get_args().map(|a| a.to_string_lossy().into()).collect(),
+            env,
+            cwd: cmd.get_current_dir().map(PathBuf::from),
+            ..Self::default()
+        }
+    }
+
+    pub fn env(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.env.insert(key.to_string(), value.into());
+        self
+    }
+
+    pub fn cached(mut self, hit: bool) -> Self {
+        self.cached = Some(hit);
+        self
+    }
+
+    pub fn unresolved(mut self, missing: bool) -> Self {
+        self.unresolved = missing;
+        self
+    }
+
+    /// The command as one shell line: `(cd dir && K=V program args…)`.
+    pub fn command_line(&self) -> String {
+        let words: Vec<String> = self
+            .env
+            .iter()
+            .map(|(k, v)| format!("{k}={}", shell_quote(v)))
+            .chain(std::iter::once(shell_quote(&self.program)))
+            .chain(self.args.iter().map(|a| shell_quote(a)))
+            .collect();
+        match &self.cwd {
+            Some(dir) => format!(
+                "(cd {} && {})",
+                shell_quote(&dir.display().to_string()),
+                words.join(" ")
+            ),
+            None => words.join(" "),
+        }
+    }
+}
+
+impl Plan {
+    pub fn run(&mut self, command: Planned) {
+        self.commands.push(command);
+    }
+
+    pub fn write(&mut self, path: impl Into<PathBuf>) {
+        self.writes.push(path.into());
+    }
+
+    /// Count a cache-covered job; `hit` is whether the cache would answer.
+    pub fn count(&mut self, hit: bool) {
+        let cache = self.cache.get_or_insert_with(CacheEstimate::default);
+        if hit {
+            cache.hits += 1;
+        } else {
+            cache.misses += 1;
+        }
+    }
+
+    /// `other`'s commands and files after this plan's.
+    pub fn extend(&mut self, other: Plan) {
+        self.commands.extend(other.commands);
+        self.writes.extend(other.writes);
+        if let Some(theirs) = other.cache {
+            let ours = self.cache.get_or_insert_with(CacheEstimate::default);
+            ours.hits += theirs.hits;
+            ours.misses += theirs.misses;
+        }
+    }
+
+    /// The plan for a terminal: each command as a `#` comment saying what it
+    /// is and a shell line, then the files and the cache estimate.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for c in &self.commands {
+            let note = match (c.unresolved, c.cached) {
+                (true, _) => " (unresolved)",
+                (false, Some(true)) => " (cached)",
+                _ => "",
+            };
+            let _ = writeln!(out, "# {}{note}", c.what);
+            let _ = writeln!(out, "{}", c.command_line());
+        }
+        if !self.writes.is_empty() {
+            let _ = writeln!(out, "# would write");
+            for path in &self.writes {
+                let _ = writeln!(out, "#   {}", path.display());
+            }
+        }
+        if let Some(cache) = self.cache {
+            let _ = writeln!(
+                out,
+                "# cache: {} of {} jobs would hit",
+                cache.hits,
+                cache.hits + cache.misses
+            );
+        }
+        out
+    }
+}
+
+/// `arg` quoted for a POSIX shell, unquoted when nothing needs it.
+pub fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-./:=,@+%".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_commands_as_shell_lines() {
+        let mut cmd = std::process::Command::new("cargo");
+        cmd.args(["build", "--message-format=json"])
+            .current_dir("kernel/rust")
+            .env("SOURCE_DATE_EPOCH", "1792000000");
+        let mut plan = Plan::default();
+        plan.run(Planned::of("building kernel/rust", &cmd));
+        let cc = Planned::new(
+            "compiling kernel/mm/pmm.c",
+            "gcc",
+            &["-DNAME=\"a b\"".to_string()],
+        );
+        plan.run(cc.cached(true));
+        let ld = Planned::new("linking build/kernel.elf", "x86_64-elf-ld", &[]);
+        plan.run(ld.unresolved(true));
+        plan.count(true);
+        plan.count(false);
+        plan.write("build/kernel.elf");
+        assert_eq!(
+            plan.render(),
+            "# building kernel/rust\n\
+             (cd kernel/rust && SOURCE_DATE_EPOCH=1792000000 cargo build --message-format=json)\n\
+             # compiling kernel/mm/pmm.c (cached)\n\
+             gcc '-DNAME=\"a b\"'\n\
+             # linking build/kernel.elf (unresolved)\n\
+             x86_64-elf-ld\n\
+             # would write\n\
+             #   build/kernel.elf\n\
+             # cache: 1 of 2 jobs would hit\n"
+        );
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+}
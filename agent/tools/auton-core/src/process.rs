@@ -0,0 +1,244 @@
+//! Running a tool to completion, optionally under a deadline and with
+//! input on its stdin.
+//!
+//! [`run`] captures stdout and stderr as they arrive, so a process killed at
+//! its timeout still reports what it printed, and returns a [`ProcessOutput`]
+//! rather than a bare exit status. [`ProcessOutput::check`] turns a failure
+//! into an error whose root cause is a [`ToolFailure`], so
+//! [`diagnostics::from_error`](crate::diagnostics::from_error) can parse it.
+//! A tool run under an exported span is told so in its environment
+//! ([`telemetry`](crate::telemetry)), to put its own spans in the trace,
+//! and every tool inherits the run ID and log directory
+//! ([`logging`](crate::logging)), to log as part of the same run.
+
+use crate::diagnostics::ToolFailure;
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+
+/// How long output is still drained after a timeout, in case a grandchild
+/// holds the pipes open.
+const DRAIN_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ProcessOutput {
+    pub program: String,
+    /// Exit code; `None` if a signal (or the timeout) ended the process.
+    pub code: Option<i32>,
+    pub timed_out: bool,
+    pub stdout: String,
+    pub stderr: String,
+    pub elapsed_ms: u64,
+}
+
+impl ProcessOutput {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    /// How the process ended, for messages: `exit code 2`, `timed out after
+    /// 30000 ms`, `a signal`.
+    pub fn status(&self) -> String {
+        match (self.timed_out, self.code) {
+            (true, _) => format!("timed out after {} ms", self.elapsed_ms),
+            (false, Some(code)) => format!("exit code {code}"),
+            (false, None) => "a signal".to_string(),
+        }
+    }
+
+    /// The output if the process succeeded, else an error with `what` as
+    /// context and the tool's stderr (or its status) as root cause.
+    pub fn check(self, what: &str) -> Result<Self> {
+        if self.success() {
+            return Ok(self);
+        }
+        let cause = match self.stderr.trim() {
+            _ if self.timed_out => anyhow!("{} {}", self.program, self.status()),
+            "" => anyhow!("{} exited with {}", self.program, self.status()),
+            _ => anyhow::Error::new(ToolFailure {
+                program: self.program.clone(),
+                stderr: self.stderr.clone(),
+            }),
+        };
+        Err(cause.context(what.to_string()))
+    }
+
+    /// The last `n` lines of stderr.
+    pub fn stderr_tail(&self, n: usize) -> String {
+        let lines: Vec<&str> = self.stderr.lines().collect();
+        lines[lines.len().saturating_sub(n)..].join("\n")
+    }
+}
+
+/// The AUTON tool `name` installed next to the running executable, else
+/// `name` itself, for a `PATH` lookup.
+pub fn sibling(name: &str) -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join(name)))
+        .filter(|p| p.is_file())
+        .unwrap_or_else(|| PathBuf::from(name))
+}
+
+/// Run `cmd` with null stdin until it exits or `timeout` passes, when it is
+/// killed. Only failing to spawn it is an error.
+pub async fn run(cmd: Command, timeout: Option<Duration>) -> Result<ProcessOutput> {
+    execute(cmd, None, timeout).await
+}
+
+/// [`run`] with `input` written to the process's stdin, which is then
+/// closed.
+pub async fn run_with_input(
+    cmd: Command,
+    input: String,
+    timeout: Option<Duration>,
+) -> Result<ProcessOutput> {
+    execute(cmd, Some(input), timeout).await
+}
+
+async fn execute(
+    mut cmd: Command,
+    input: Option<String>,
+    timeout: Option<Duration>,
+) -> Result<ProcessOutput> {
+    let program = cmd.as_std().get_program().to_string_lossy().into_owned();
+    let started = Instant::now();
+    if let Some(parent) = crate::telemetry::current() {
+        cmd.env(crate::telemetry::TRACEPARENT, parent.to_string());
+    }
+    cmd.envs(crate::logging::child_env());
+    let stdin = if input.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    };
+    let mut child = cmd
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("failed to spawn `{program}` (is it installed?)"))?;
+    let stdout = tokio::spawn(read_all(child.stdout.take()));
+    let stderr = tokio::spawn(read_all(child.stderr.take()));
+    if let (Some(mut pipe), Some(input)) = (child.stdin.take(), input) {
+        // A process that exits without reading it all breaks the pipe.
+        tokio::spawn(async move {
+            let _ = pipe.write_all(input.as_bytes()).await;
+        });
+    }
+
+    let (status, timed_out) = match timeout {
+        None => (Some(child.wait().await?), false),
+        Some(limit) => match tokio::time::timeout(limit, child.wait()).await {
+            Ok(status) => (Some(status?), false),
+            Err(_) => {
+                let _ = child.kill().await;
+                (None, true)
+            }
+        },
+    };
+    Ok(ProcessOutput {
+        program,
+        code: status.and_then(|s| s.code()),
+        timed_out,
+        stdout: drain(stdout, timed_out).await,
+        stderr: drain(stderr, timed_out).await,
+        elapsed_ms: started.elapsed().as_millis() as u64,
+    })
+}
+
+async fn drain(reader: JoinHandle<String>, timed_out: bool) -> String {
+    if !timed_out {
+        return reader.await.unwrap_or_default();
+    }
+    let abort = reader.abort_handle();
+    match tokio::time::timeout(DRAIN_GRACE, reader).await {
+        Ok(text) => text.unwrap_or_default(),
+        Err(_) => {
+            abort.abort();
+            String::new()
+        }
+    }
+}
+
+async fn read_all(pipe: Option<impl AsyncRead + Unpin>) -> String {
+    let mut buf = Vec::new();
+    if let Some(mut pipe) = pipe {
+        let _ = pipe.read_to_end(&mut buf).await;
+    }
+    String::from_utf8_lossy(&buf).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics;
+
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", script]);
+        cmd
+    }
+
+    #[tokio::test]
+    async fn captures_output_and_exit_code() {
+        let out = run(sh("echo out; echo err >&2; exit 3"), None)
+            .await
+            .unwrap();
+        assert_eq!((out.code, out.timed_out), (Some(3), false));
+        assert_eq!(
+            (out.stdout.as_str(), out.stderr.as_str()),
+            ("out\n", "err\n")
+        );
+        assert_eq!(out.status(), "exit code 3");
+
+        let err = run(sh("echo 'a.c:1:2: error: nope' >&2; exit 1"), None)
+            .await
+            .unwrap()
+            .check("compiling a.c")
+            .unwrap_err();
+        assert_eq!(format!("{err:#}"), "compiling a.c: a.c:1:2: error: nope");
+        assert_eq!(diagnostics::from_error(&err)[0].message, "nope");
+    }
+
+    #[tokio::test]
+    async fn children_join_the_run() {
+        let out = run(sh("echo $AUTON_RUN_ID"), None).await.unwrap();
+        assert_eq!(out.stdout.trim(), crate::logging::run_id());
+    }
+
+    #[tokio::test]
+    async fn feeds_input_to_stdin() {
+        let out = run_with_input(sh("tr a-z A-Z"), "kernel\n".into(), None)
+            .await
+            .unwrap();
+        assert_eq!(out.stdout, "KERNEL\n");
+    }
+
+    #[tokio::test]
+    async fn kills_at_the_timeout_keeping_partial_output() {
+        let timeout = Some(Duration::from_millis(200));
+        let out = run(sh("echo started; exec sleep 10"), timeout)
+            .await
+            .unwrap();
+        assert!(out.timed_out && !out.success());
+        assert_eq!(out.stdout, "started\n");
+        assert!(out.elapsed_ms < 5000);
+        let msg = format!("{:#}", out.check("waiting").unwrap_err());
+        assert!(msg.starts_with("waiting: sh timed out after"), "{msg}");
+    }
+
+    #[tokio::test]
+    async fn missing_programs_fail_to_spawn() {
+        let err = run(Command::new("auton-core-no-such-tool"), None)
+            .await
+            .unwrap_err();
+        assert!(format!("{err}").contains("is it installed?"));
+    }
+}
@@ -0,0 +1,366 @@
+//! Content-addressed artifact store, shared by the tools.
+//!
+//! Files are kept once each under their SHA-256, read-only, in
+//! `<root>/objects/<first two hex digits>/<the rest>`. What uses them is
+//! recorded as refs, one JSON document each in `<root>/refs/<tool>/`:
+//! kernel-builder's build manifests ([`ArtifactStore::add_build`]) and
+//! test-runner's run records. Any SHA-256 (64 lowercase hex digits) in a
+//! ref references that object, so a ref can be any document that names
+//! artifacts by hash.
+//!
+//! [`ArtifactStore::gc`] removes refs older than a retention window, then
+//! objects that no remaining ref mentions and that were last stored before
+//! the window: storing a file again refreshes it, so an object is never
+//! removed between being stored and being referenced.
+//!
+//! Only a local directory is supported; an S3 backend would need TLS and
+//! signed HTTPS requests, which the tools do not have. Sync the directory
+//! to a bucket if it has to be shared.
+
+use crate::manifest::{hash_file, Artifact, ArtifactKind, BuildManifest, MANIFEST_NAME};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+pub const DEFAULT_DIR: &str = "build/artifacts";
+
+const OBJECTS: &str = "objects";
+const REFS: &str = "refs";
+/// Prefix of a copy on its way into `objects/`.
+const TMP_PREFIX: &str = ".tmp-";
+
+/// A stored file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stored {
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// What [`ArtifactStore::gc`] removed, or would with `dry_run`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct GcReport {
+    pub refs_removed: usize,
+    pub objects_removed: usize,
+    pub bytes_removed: u64,
+    pub objects_kept: usize,
+    pub bytes_kept: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArtifactStore {
+    root: PathBuf,
+}
+
+impl ArtifactStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where the object with `sha256` is (or would be) kept.
+    pub fn object(&self, sha256: &str) -> PathBuf {
+        let (fan, rest) = sha256.split_at(2.min(sha256.len()));
+        self.root.join(OBJECTS).join(fan).join(rest)
+    }
+
+    pub fn contains(&self, sha256: &str) -> bool {
+        is_sha256(sha256) && self.object(sha256).is_file()
+    }
+
+    /// Store the file at `path`; a file already stored is only refreshed.
+    pub fn put(&self, path: &Path) -> Result<Stored> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let (size, sha256) = hash_file(path)?;
+        let dest = self.object(&sha256);
+        if dest.is_file() {
+            std::fs::File::open(&dest)
+                .and_then(|f| f.set_modified(SystemTime::now()))
+                .with_context(|| format!("refreshing {}", dest.display()))?;
+            return Ok(Stored { sha256, size });
+        }
+        let dir = dest.parent().expect("objects have a fan-out directory");
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        let tmp = self.root.join(OBJECTS).join(format!(
+            "{TMP_PREFIX}{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::copy(path, &tmp)
+            .with_context(|| format!("copying {} to {}", path.display(), tmp.display()))?;
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o444))?;
+        std::fs::rename(&tmp, &dest).with_context(|| format!("storing {}", dest.display()))?;
+        Ok(Stored { sha256, size })
+    }
+
+    /// Record `doc` as `refs/<tool>/<name>.json`, replacing a ref of that
+    /// name.
+    pub fn add_ref(&self, tool: &str, name: &str, doc: &impl Serialize) -> Result<PathBuf> {
+        let dir = self.root.join(REFS).join(tool);
+        std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        let path = dir.join(format!("{name}.json"));
+        let tmp = dir.join(format!("{TMP_PREFIX}{name}.json"));
+        std::fs::write(&tmp, serde_json::to_string_pretty(doc)? + "\n")
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, &path).with_context(|| format!("writing {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Store the kernel, images and symbols the build in `output` produced,
+    /// and record its manifest as a `builds` ref, named for the kernel. The
+    /// manifest is rewritten naming the store, with any of those it did not
+    /// list as artifacts added, so each is referenced by hash.
+    pub fn add_build(&self, output: &Path) -> Result<BuildManifest> {
+        let path = output.join(MANIFEST_NAME);
+        let mut manifest = BuildManifest::read(&path)?;
+        let outputs = std::iter::once((manifest.kernel.clone(), ArtifactKind::Kernel))
+            .chain(
+                manifest
+                    .images
+                    .iter()
+                    .map(|i| (i.clone(), ArtifactKind::Image)),
+            )
+            .chain(
+                manifest
+                    .symbols
+                    .iter()
+                    .map(|s| (s.clone(), ArtifactKind::Symbols)),
+            )
+            .collect::<Vec<_>>();
+        for (file, kind) in outputs {
+            let listed = manifest.artifacts.iter().any(|a| a.path == file);
+            if !listed && !file.is_empty() && Path::new(&file).is_file() {
+                manifest
+                    .artifacts
+                    .push(Artifact::from_file(Path::new(&file), kind)?);
+            }
+        }
+        for artifact in &manifest.artifacts {
+            if matches!(
+                artifact.kind,
+                ArtifactKind::Kernel | ArtifactKind::Image | ArtifactKind::Symbols
+            ) {
+                self.put(Path::new(&artifact.path))?;
+            }
+        }
+        manifest.store = Some(self.root.display().to_string());
+        manifest.write(output)?;
+        let name = manifest
+            .artifacts
+            .iter()
+            .find(|a| a.kind == ArtifactKind::Kernel)
+            .or(manifest.artifacts.first())
+            .map(|a| a.sha256.clone())
+            .with_context(|| format!("{} lists no artifacts", path.display()))?;
+        self.add_ref("builds", &name, &manifest)?;
+        Ok(manifest)
+    }
+
+    /// Remove refs older than `retention`, then the objects none of the
+    /// rest reference that are older too; with `dry_run`, only count them.
+    pub fn gc(&self, retention: Duration, dry_run: bool) -> Result<GcReport> {
+        let cutoff = SystemTime::now()
+            .checked_sub(retention)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let old = |meta: &std::fs::Metadata| meta.modified().is_ok_and(|t| t < cutoff);
+        let remove = |path: &Path| -> Result<()> {
+            if !dry_run {
+                std::fs::remove_file(path)
+                    .with_context(|| format!("removing {}", path.display()))?;
+            }
+            Ok(())
+        };
+        let mut report = GcReport::default();
+
+        let mut referenced = HashSet::new();
+        for path in files(&self.root.join(REFS), 2)? {
+            let meta = std::fs::metadata(&path)?;
+            if old(&meta) {
+                remove(&path)?;
+                report.refs_removed += 1;
+                continue;
+            }
+            let doc = std::fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|text| Ok(serde_json::from_str::<Value>(&text)?));
+            match doc {
+                Ok(doc) => hashes_in(&doc, &mut referenced),
+                Err(e) => tracing::warn!("unreadable ref {}: {e:#}", path.display()),
+            }
+        }
+
+        let objects = self.root.join(OBJECTS);
+        for path in files(&objects, 2)? {
+            let meta = std::fs::metadata(&path)?;
+            let name = path.strip_prefix(&objects).unwrap_or(&path);
+            let sha256: String = name
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect();
+            let temporary = sha256.starts_with(TMP_PREFIX);
+            if old(&meta) && (temporary || !referenced.contains(&sha256)) {
+                remove(&path)?;
+                report.objects_removed += usize::from(!temporary);
+                report.bytes_removed += meta.len();
+            } else if !temporary {
+                report.objects_kept += 1;
+                report.bytes_kept += meta.len();
+            }
+        }
+        if !dry_run {
+            for dir in [objects, self.root.join(REFS)] {
+                for sub in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+                    // Only empty ones go.
+                    let _ = std::fs::remove_dir(sub.path());
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// The files at most `depth` levels of directories under `dir`; none if
+/// it does not exist.
+fn files(dir: &Path, depth: usize) -> Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", dir.display())),
+    };
+    let mut found = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            if depth > 1 {
+                found.extend(files(&path, depth - 1)?);
+            }
+        } else {
+            found.push(path);
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+fn is_sha256(text: &str) -> bool {
+    text.len() == 64 && text.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Every SHA-256 string in `value`.
+fn hashes_in(value: &Value, found: &mut HashSet<String>) {
+    match value {
+        Value::String(s) if is_sha256(s) => {
+            found.insert(s.clone());
+        }
+        Value::Array(items) => items.iter().for_each(|v| hashes_in(v, found)),
+        Value::Object(map) => map.values().for_each(|v| hashes_in(v, found)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("auton-store-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn age(path: &Path, by: Duration) {
+        let file = std::fs::File::open(path).unwrap();
+        file.set_modified(SystemTime::now() - by).unwrap();
+    }
+
+    #[test]
+    fn stores_files_once_and_collects_what_refs_do_not_use() {
+        let dir = scratch("gc");
+        let store = ArtifactStore::new(dir.join("store"));
+        let week = Duration::from_secs(7 * 86_400);
+        let day = Duration::from_secs(86_400);
+        std::fs::write(dir.join("kernel"), "kernel").unwrap();
+        std::fs::write(dir.join("dump"), "dump").unwrap();
+        std::fs::write(dir.join("log"), "log").unwrap();
+
+        let kernel = store.put(&dir.join("kernel")).unwrap();
+        assert_eq!(store.put(&dir.join("kernel")).unwrap(), kernel);
+        assert!(store.contains(&kernel.sha256));
+        assert_eq!(
+            std::fs::read_to_string(store.object(&kernel.sha256)).unwrap(),
+            "kernel"
+        );
+        let dump = store.put(&dir.join("dump")).unwrap();
+        let log = store.put(&dir.join("log")).unwrap();
+        let fresh = store
+            .add_ref("runs", "new", &json!({"files": {"vmcore": dump.sha256}}))
+            .unwrap();
+        let stale = store
+            .add_ref("runs", "old", &json!({"kernel_sha256": kernel.sha256}))
+            .unwrap();
+        age(&stale, week + day);
+        for object in [&kernel, &dump, &log] {
+            age(&store.object(&object.sha256), week + day);
+        }
+
+        let dry = store.gc(week, true).unwrap();
+        assert_eq!((dry.refs_removed, dry.objects_removed), (1, 2));
+        assert!(stale.exists() && store.contains(&log.sha256));
+
+        let report = store.gc(week, false).unwrap();
+        assert_eq!(report, dry);
+        assert_eq!(report.bytes_removed, 6 + 3);
+        assert!(!stale.exists() && fresh.exists());
+        // Referenced, though old.
+        assert!(store.contains(&dump.sha256));
+        assert!(!store.contains(&kernel.sha256) && !store.contains(&log.sha256));
+
+        // Unreferenced but recent.
+        store.put(&dir.join("log")).unwrap();
+        assert_eq!(store.gc(week, false).unwrap().objects_kept, 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn builds_are_stored_with_their_manifest_as_a_ref() {
+        let dir = scratch("build");
+        let kernel = dir.join("kernel.elf");
+        let symbols = dir.join("symbols.json");
+        std::fs::write(&kernel, "\x7fELF").unwrap();
+        std::fs::write(&symbols, "[]").unwrap();
+        BuildManifest {
+            kernel: kernel.display().to_string(),
+            symbols: Some(symbols.display().to_string()),
+            artifacts: vec![Artifact::from_file(&kernel, ArtifactKind::Kernel).unwrap()],
+            ..BuildManifest::default()
+        }
+        .write(&dir)
+        .unwrap();
+
+        let store = ArtifactStore::new(dir.join("store"));
+        let manifest = store.add_build(&dir).unwrap();
+        assert_eq!(manifest.artifacts.len(), 2);
+        assert_eq!(manifest.artifacts[1].kind, ArtifactKind::Symbols);
+        for artifact in &manifest.artifacts {
+            assert!(store.contains(&artifact.sha256));
+        }
+        let written = BuildManifest::read(&dir.join(MANIFEST_NAME)).unwrap();
+        assert_eq!(
+            written.store.as_deref(),
+            Some(store.root().to_str().unwrap())
+        );
+        let name = format!("{}.json", manifest.artifacts[0].sha256);
+        assert!(store.root().join(REFS).join("builds").join(name).is_file());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,477 @@
+//! OpenTelemetry traces: spans exported to an OTLP collector, with the
+//! trace context carried into every tool a process runs, so one agent
+//! iteration (auton's pipeline, kernel-builder's build, test-runner's VMs)
+//! is one distributed trace.
+//!
+//! Export is off unless `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` (the full
+//! URL) or `OTEL_EXPORTER_OTLP_ENDPOINT` (with `/v1/traces` added) is set.
+//! Spans go out in batches as OTLP/HTTP JSON (`http/json`), from a thread
+//! of their own; only `http://` is supported ([`crate::http`]), so point
+//! it at a local collector. `OTEL_SERVICE_NAME` names the service, else
+//! the binary's name does. Spans still open when the process exits are
+//! sent ended then; exporting never fails a tool, a collector that cannot
+//! be reached only costs a warning.
+//!
+//! The context travels as a W3C `traceparent` in [`TRACEPARENT`]:
+//! [`process::run`](crate::process::run) sets it to the span the tool is
+//! run under, and a process started with it makes its root spans children
+//! of that span. Spans are `tracing` spans; an `otel.name` field, if any,
+//! names the span instead of its static name, and an error event inside a
+//! span marks it failed.
+
+use crate::hash;
+use crate::http::{self, HttpUrl};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// The environment variable a tool's parent span travels in.
+pub const TRACEPARENT: &str = "TRACEPARENT";
+
+/// Spans sent in one request, at most.
+const BATCH: usize = 512;
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long exiting waits for the last spans to go out.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A span's identity in its trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+}
+
+impl SpanContext {
+    /// Parse a `traceparent`: `00-<trace id>-<span id>-<flags>`.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let parts: Vec<&str> = traceparent.trim().split('-').collect();
+        let [version, trace, span, flags, ..] = parts[..] else {
+            return None;
+        };
+        if version.len() != 2 || version == "ff" || flags.len() != 2 {
+            return None;
+        }
+        if version == "00" && parts.len() != 4 {
+            return None;
+        }
+        let context = Self {
+            trace_id: unhex(trace)?,
+            span_id: unhex(span)?,
+        };
+        (context.trace_id != [0; 16] && context.span_id != [0; 8]).then_some(context)
+    }
+}
+
+impl fmt::Display for SpanContext {
+    /// As a `traceparent`, sampled.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (trace, span) = (hash::hex(&self.trace_id), hash::hex(&self.span_id));
+        write!(f, "00-{trace}-{span}-01")
+    }
+}
+
+fn unhex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != 2 * N {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Random bytes for an id: from `/dev/urandom`, else hashed from the time
+/// and a counter.
+pub(crate) fn random<const N: usize>() -> [u8; N] {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut bytes = [0; N];
+    let read = std::fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes));
+    if read.is_err() || bytes == [0; N] {
+        let seed = format!(
+            "{:?} {} {}",
+            SystemTime::now(),
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        bytes.copy_from_slice(&hash::sha256(seed.as_bytes())[..N]);
+    }
+    bytes
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// A span as it runs.
+#[derive(Debug)]
+struct SpanData {
+    context: SpanContext,
+    parent: Option<[u8; 8]>,
+    name: String,
+    start: u64,
+    attributes: Vec<(String, Value)>,
+    error: Option<String>,
+}
+
+impl SpanData {
+    /// The span, ended now, in OTLP's JSON encoding.
+    fn to_otlp(&self) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({"key": key, "value": any_value(value)}))
+            .collect();
+        let status = match &self.error {
+            Some(message) => json!({"code": 2, "message": message}),
+            None => json!({"code": 0}),
+        };
+        let mut span = json!({
+            "traceId": hash::hex(&self.context.trace_id),
+            "spanId": hash::hex(&self.context.span_id),
+            "name": self.name,
+            "kind": 1,
+            "startTimeUnixNano": self.start.to_string(),
+            "endTimeUnixNano": now_nanos().to_string(),
+            "attributes": attributes,
+            "status": status,
+        });
+        if let Some(parent) = self.parent {
+            span["parentSpanId"] = json!(hash::hex(&parent));
+        }
+        span
+    }
+}
+
+fn any_value(value: &Value) -> Value {
+    match value {
+        Value::Bool(b) => json!({"boolValue": b}),
+        Value::Number(n) if n.is_f64() => json!({"doubleValue": n}),
+        Value::Number(n) => json!({"intValue": n.to_string()}),
+        Value::String(s) => json!({"stringValue": s}),
+        other => json!({"stringValue": other.to_string()}),
+    }
+}
+
+/// Collects fields as attributes, and `otel.name` as the name.
+struct Fields<'a>(&'a mut SpanData);
+
+impl Fields<'_> {
+    fn add(&mut self, field: &Field, value: Value) {
+        if field.name() == "otel.name" {
+            if let Value::String(name) = value {
+                self.0.name = name;
+            }
+            return;
+        }
+        let attributes = &mut self.0.attributes;
+        attributes.retain(|(key, _)| key != field.name());
+        attributes.push((field.name().to_string(), value));
+    }
+}
+
+impl Visit for Fields<'_> {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.add(field, json!(value));
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.add(field, json!(value));
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.add(field, json!(value));
+    }
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.add(field, json!(value));
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.add(field, json!(value));
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.add(field, json!(format!("{value:?}")));
+    }
+}
+
+/// An event's message.
+#[derive(Default)]
+struct Message(Option<String>);
+
+impl Visit for Message {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0 = Some(value.to_string());
+        }
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = Some(format!("{value:?}"));
+        }
+    }
+}
+
+enum Export {
+    Span(Value),
+    /// Answered once everything sent before it has been exported.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Spans open now, by `tracing` id, so an exit can still send them.
+static OPEN: OnceLock<Mutex<HashMap<u64, SpanData>>> = OnceLock::new();
+static EXPORTER: OnceLock<mpsc::Sender<Export>> = OnceLock::new();
+
+fn open() -> std::sync::MutexGuard<'static, HashMap<u64, SpanData>> {
+    OPEN.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// The `tracing` layer that records spans for export; see [`layer`].
+pub struct OtlpLayer {
+    /// The span this process was started under.
+    remote: Option<SpanContext>,
+}
+
+/// The collector's URL, from the environment.
+pub fn endpoint() -> Option<String> {
+    let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+    var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").or_else(|| {
+        var("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .map(|base| format!("{}/v1/traces", base.trim_end_matches('/')))
+    })
+}
+
+/// The layer, exporting to [`endpoint`], if one is set. Only the first
+/// call starts an exporter.
+pub fn layer() -> Option<OtlpLayer> {
+    let endpoint = endpoint()?;
+    let url = match HttpUrl::parse(&endpoint) {
+        Ok(url) => url,
+        Err(e) => {
+            eprintln!("not exporting traces: {e:#}");
+            return None;
+        }
+    };
+    let service = std::env::var("OTEL_SERVICE_NAME").ok().unwrap_or_else(|| {
+        std::env::current_exe()
+            .ok()
+            .and_then(|exe| Some(exe.file_stem()?.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| "auton".to_string())
+    });
+    let resource = json!({"attributes": [
+        {"key": "service.name", "value": {"stringValue": service}},
+        {"key": "process.pid", "value": {"intValue": std::process::id().to_string()}},
+    ]});
+    if EXPORTER.get().is_none() {
+        let (sender, spans) = mpsc::channel();
+        let started = std::thread::Builder::new()
+            .name("otlp-export".into())
+            .spawn(move || export(url, resource, spans));
+        if started.is_err() || EXPORTER.set(sender).is_err() {
+            return None;
+        }
+        extern "C" fn at_exit() {
+            flush();
+        }
+        // SAFETY: `at_exit` is a plain function that does not unwind.
+        unsafe { libc::atexit(at_exit) };
+    }
+    let remote = std::env::var(TRACEPARENT)
+        .ok()
+        .and_then(|v| SpanContext::parse(&v));
+    Some(OtlpLayer { remote })
+}
+
+/// The current span's context, to start a child process under; `None`
+/// when spans are not exported.
+pub fn current() -> Option<SpanContext> {
+    let id = tracing::Span::current().id()?;
+    open().get(&id.into_u64()).map(|span| span.context)
+}
+
+/// Send every span ended so far, and those still open as if they ended
+/// now, waiting a little for them to go out. Exiting does this.
+pub fn flush() {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    for (_, span) in open().drain() {
+        let _ = exporter.send(Export::Span(span.to_otlp()));
+    }
+    let (done, wait) = mpsc::channel();
+    if exporter.send(Export::Flush(done)).is_ok() {
+        let _ = wait.recv_timeout(FLUSH_TIMEOUT);
+    }
+}
+
+/// The exporter thread: batch spans as they come and POST them.
+fn export(url: HttpUrl, resource: Value, spans: mpsc::Receiver<Export>) {
+    let Ok(runtime) = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    else {
+        return;
+    };
+    let warned = AtomicBool::new(false);
+    let mut batch = Vec::new();
+    while let Ok(first) = spans.recv() {
+        let mut flushes = Vec::new();
+        let mut next = Some(first);
+        while let Some(message) = next.take() {
+            match message {
+                Export::Span(span) => batch.push(span),
+                Export::Flush(done) => flushes.push(done),
+            }
+            if batch.len() < BATCH {
+                next = spans.try_recv().ok();
+            }
+        }
+        if !batch.is_empty() {
+            let body = json!({"resourceSpans": [{
+                "resource": resource,
+                "scopeSpans": [{"scope": {"name": "auton"}, "spans": std::mem::take(&mut batch)}],
+            }]});
+            let body = body.to_string();
+            let post = http::post(&url, &[], body.as_bytes());
+            let sent = runtime.block_on(async { tokio::time::timeout(EXPORT_TIMEOUT, post).await });
+            let why = match sent {
+                Ok(Ok(status)) if (200..300).contains(&status) => None,
+                Ok(Ok(status)) => Some(format!("HTTP {status}")),
+                Ok(Err(e)) => Some(format!("{e:#}")),
+                Err(_) => Some(format!("no response in {}s", EXPORT_TIMEOUT.as_secs())),
+            };
+            if let Some(why) = why.filter(|_| !warned.swap(true, Ordering::Relaxed)) {
+                tracing::warn!(
+                    "exporting traces to {}:{}{}: {why}",
+                    url.host,
+                    url.port,
+                    url.path
+                );
+            }
+        }
+        for done in flushes {
+            let _ = done.send(());
+        }
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut open = open();
+        let parent = ctx
+            .span(id)
+            .and_then(|span| span.parent())
+            .and_then(|parent| open.get(&parent.id().into_u64()))
+            .map(|parent| parent.context)
+            .or(self.remote);
+        let mut span = SpanData {
+            context: SpanContext {
+                trace_id: parent.map_or_else(random, |p| p.trace_id),
+                span_id: random(),
+            },
+            parent: parent.map(|p| p.span_id),
+            name: attrs.metadata().name().to_string(),
+            start: now_nanos(),
+            attributes: vec![("code.namespace".into(), json!(attrs.metadata().target()))],
+            error: None,
+        };
+        attrs.record(&mut Fields(&mut span));
+        open.insert(id.into_u64(), span);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+        if let Some(span) = open().get_mut(&id.into_u64()) {
+            values.record(&mut Fields(span));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        let Some(id) = ctx.event_span(event).map(|span| span.id()) else {
+            return;
+        };
+        let mut message = Message::default();
+        event.record(&mut message);
+        if let Some(span) = open().get_mut(&id.into_u64()) {
+            span.error = Some(message.0.unwrap_or_else(|| "error".into()));
+        }
+    }
+
+    fn on_close(&self, id: Id, _ctx: Context<'_, S>) {
+        let Some(span) = open().remove(&id.into_u64()) else {
+            return;
+        };
+        if let Some(exporter) = EXPORTER.get() {
+            let _ = exporter.send(Export::Span(span.to_otlp()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparents_round_trip() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = SpanContext::parse(header).unwrap();
+        assert_eq!(
+            context.span_id,
+            [0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7]
+        );
+        assert_eq!(context.to_string(), header);
+        // Later versions may add fields; all zeros is invalid.
+        assert!(SpanContext::parse(&format!("01{}-extra", &header[2..])).is_some());
+        assert!(SpanContext::parse(&format!("{header}-extra")).is_none());
+        assert!(
+            SpanContext::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(SpanContext::parse("00-4bf92f3577b34da6-00f067aa0ba902b7-01").is_none());
+        assert!(SpanContext::parse("not a traceparent").is_none());
+    }
+
+    #[test]
+    fn spans_encode_as_otlp_json() {
+        let mut span = SpanData {
+            context: SpanContext {
+                trace_id: [1; 16],
+                span_id: [2; 8],
+            },
+            parent: Some([3; 8]),
+            name: "stage".into(),
+            start: 1_000,
+            attributes: Vec::new(),
+            error: None,
+        };
+        span.attributes.push(("jobs".into(), json!(4)));
+        span.error = Some("compile failed".into());
+        let otlp = span.to_otlp();
+        assert_eq!(otlp["traceId"], "01".repeat(16));
+        assert_eq!(otlp["parentSpanId"], "03".repeat(8));
+        assert_eq!(otlp["startTimeUnixNano"], "1000");
+        assert_eq!(
+            otlp["attributes"][0],
+            json!({"key": "jobs", "value": {"intValue": "4"}})
+        );
+        assert_eq!(
+            otlp["status"],
+            json!({"code": 2, "message": "compile failed"})
+        );
+    }
+}
@@ -0,0 +1,971 @@
+//! Minimal TOML for the tools' configuration files (`auton-build.toml`, test
+//! specs, validator rules).
+//!
+//! Documents parse into a [`serde_json::Value`] tree, so config structs only
+//! need `Deserialize` and go through [`serde_json::from_value`]. Supported:
+//! comments, `[tables]`, `[[arrays of tables]]`, dotted and quoted keys,
+//! basic/literal (and multi-line) strings, integers (with `0x`/`0o`/`0b` and
+//! `_` separators), floats, booleans, arrays and inline tables. Dates and
+//! times are rejected with an error rather than misread.
+//!
+//! [`from_file`] also says where a document went wrong for its config
+//! struct, which sees only the value tree: the parser keeps the line of
+//! every key it read, and serde's error is matched back to the key or value
+//! it names, with a suggestion when one of the names it expected is close:
+//!
+//! ```text
+//! unknown key `expct` at tests/boot.toml:12, did you mean `expect`?
+//! ```
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Number, Value};
+use std::fmt;
+use std::path::Path;
+
+/// A syntax error and the 1-based line it was found on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Parse a document into a JSON object tree.
+pub fn parse(text: &str) -> Result<Value, Error> {
+    Parser::new(text).document().map(|(value, _)| value)
+}
+
+/// Parse a document and deserialize it into `T`.
+pub fn from_str<T: DeserializeOwned>(text: &str) -> anyhow::Result<T> {
+    Ok(serde_json::from_value(parse(text)?)?)
+}
+
+/// Read the document at `path` and deserialize it into `T`, with errors
+/// that give the file and line.
+pub fn from_file<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    from_named(&text, &path.display().to_string())
+}
+
+/// [`from_str`] for a document called `name` in errors.
+fn from_named<T: DeserializeOwned>(text: &str, name: &str) -> anyhow::Result<T> {
+    let (value, keys) = Parser::new(text)
+        .document()
+        .map_err(|e| anyhow::anyhow!("{} at {name}:{}", e.message, e.line))?;
+    serde_json::from_value(value).map_err(|e| anyhow::anyhow!(locate(&e.to_string(), &keys, name)))
+}
+
+/// A key the parser read: its last segment, the line it is on and the
+/// scalars given it (an array's items), as serde would quote them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Key {
+    name: String,
+    line: usize,
+    values: Vec<String>,
+}
+
+fn scalars(value: &Value, out: &mut Vec<String>) {
+    match value {
+        Value::String(s) => out.push(s.clone()),
+        Value::Number(n) => out.push(n.to_string()),
+        Value::Bool(b) => out.push(b.to_string()),
+        Value::Array(items) => items.iter().for_each(|v| scalars(v, out)),
+        Value::Null | Value::Object(_) => {}
+    }
+}
+
+/// serde's `message` about a document called `name`, with the line of the
+/// key or value it is about and a suggestion for an unknown name.
+fn locate(message: &str, keys: &[Key], name: &str) -> String {
+    let quoted = |s: &str| s.split('`').nth(1).map(str::to_string);
+    let (head, subject, is_key, expected) =
+        if let Some(rest) = message.strip_prefix("unknown field ") {
+            let field = quoted(rest).unwrap_or_default();
+            (format!("unknown key `{field}`"), Some(field), true, rest)
+        } else if let Some(rest) = message.strip_prefix("unknown variant ") {
+            let variant = quoted(rest).unwrap_or_default();
+            (
+                format!("unknown value `{variant}`"),
+                Some(variant),
+                false,
+                rest,
+            )
+        } else {
+            // Other errors name the value they are about, if any, as
+            // `` `5` `` or `"64X"`.
+            let value = quoted(message).or_else(|| message.split('"').nth(1).map(str::to_string));
+            (message.to_string(), value, false, "")
+        };
+    let line = subject.as_deref().and_then(|s| {
+        keys.iter()
+            .find(|k| k.name == s || !is_key && k.values.iter().any(|v| v == s))
+            .map(|k| k.line)
+    });
+    let mut out = match line {
+        Some(line) => format!("{head} at {name}:{line}"),
+        None => format!("{head} in {name}"),
+    };
+    // `` `x`, expected one of `a`, `b` ``: the names after the first are
+    // the ones serde expected.
+    let names: Vec<&str> = expected.split('`').skip(3).step_by(2).collect();
+    match subject.as_deref().and_then(|s| closest(s, &names)) {
+        Some(close) => out.push_str(&format!(", did you mean `{close}`?")),
+        // A value's few choices are worth listing; a struct's keys are not.
+        None if !is_key && !names.is_empty() => {
+            if let Some((_, choices)) = expected.split_once(", ") {
+                out.push_str(&format!(", {}", choices.trim()));
+            }
+        }
+        None => {}
+    }
+    out
+}
+
+/// The name in `names` closest to `word`, if it is a likely typo of it.
+fn closest<'a>(word: &str, names: &[&'a str]) -> Option<&'a str> {
+    let words = |s: &str| s.to_lowercase().replace('_', "-");
+    let word = words(word);
+    names
+        .iter()
+        .map(|n| (distance(&word, &words(n)), *n))
+        .filter(|(d, _)| *d <= (word.chars().count() / 3).max(1))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, n)| n)
+}
+
+/// Edits from `a` to `b`: inserted, deleted or replaced characters, and
+/// swapped neighbours (optimal string alignment distance).
+fn distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    keys: Vec<Key>,
+}
+
+/// A header segment resolves to the last element when it names an array of
+/// tables.
+fn table_mut<'a>(
+    mut table: &'a mut Map<String, Value>,
+    path: &[String],
+) -> Result<&'a mut Map<String, Value>, String> {
+    for key in path {
+        let slot = table
+            .entry(key.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+        let slot = match slot {
+            Value::Array(items) => items
+                .last_mut()
+                .ok_or_else(|| format!("`{key}` is an empty array, not a table"))?,
+            other => other,
+        };
+        table = slot
+            .as_object_mut()
+            .ok_or_else(|| format!("`{key}` is already a value, not a table"))?;
+    }
+    Ok(table)
+}
+
+fn insert(table: &mut Map<String, Value>, key: &[String], value: Value) -> Result<(), String> {
+    let (last, parents) = key.split_last().expect("keys have at least one segment");
+    let table = table_mut(table, parents)?;
+    if table.contains_key(last) {
+        return Err(format!("duplicate key `{}`", key.join(".")));
+    }
+    table.insert(last.clone(), value);
+    Ok(())
+}
+
+impl Parser {
+    fn new(text: &str) -> Self {
+        Self {
+            chars: text.chars().collect(),
+            pos: 0,
+            line: 1,
+            keys: Vec::new(),
+        }
+    }
+
+    /// Note that `key` was given `value` on `line`. A dotted key's parents
+    /// are on it too.
+    fn record(&mut self, key: &[String], line: usize, value: &Value) {
+        for (i, name) in key.iter().enumerate() {
+            let mut values = Vec::new();
+            if i + 1 == key.len() {
+                scalars(value, &mut values);
+            }
+            self.keys.push(Key {
+                name: name.clone(),
+                line,
+                values,
+            });
+        }
+    }
+
+    fn err<T>(&self, message: impl Into<String>) -> Result<T, Error> {
+        Err(Error {
+            line: self.line,
+            message: message.into(),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), Error> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            match self.peek() {
+                Some(found) => self.err(format!("expected `{c}`, found `{found}`")),
+                None => self.err(format!("expected `{c}`, found end of file")),
+            }
+        }
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars()
+            .enumerate()
+            .all(|(i, c)| self.peek_at(i) == Some(c))
+    }
+
+    /// Spaces and tabs only.
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Whitespace, newlines and comments (inside arrays and between lines).
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_ws();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') => {
+                    self.bump();
+                }
+                Some('\r') if self.peek_at(1) == Some('\n') => {
+                    self.bump();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), Error> {
+        self.skip_ws();
+        self.skip_comment();
+        self.eat('\r');
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.bump();
+                Ok(())
+            }
+            Some(c) => self.err(format!("unexpected `{c}` after value")),
+        }
+    }
+
+    /// The document and the keys in it, in the order they came.
+    fn document(mut self) -> Result<(Value, Vec<Key>), Error> {
+        let mut root = Map::new();
+        let mut current: Vec<String> = Vec::new();
+        loop {
+            self.skip_blank();
+            let Some(c) = self.peek() else {
+                return Ok((Value::Object(root), self.keys));
+            };
+            if c == '[' {
+                let line = self.line;
+                self.bump();
+                let array = self.eat('[');
+                self.skip_ws();
+                let path = self.key()?;
+                self.record(&path, line, &Value::Null);
+                self.skip_ws();
+                self.expect(']')?;
+                if array {
+                    self.expect(']')?;
+                }
+                self.end_of_line()?;
+                let (last, parents) = path.split_last().expect("keys have a segment");
+                let fail = |message: String| Error { line, message };
+                let parent = table_mut(&mut root, parents).map_err(fail)?;
+                if array {
+                    let slot = parent
+                        .entry(last.clone())
+                        .or_insert_with(|| Value::Array(Vec::new()));
+                    let Value::Array(items) = slot else {
+                        return Err(fail(format!(
+                            "`{}` is not an array of tables",
+                            path.join(".")
+                        )));
+                    };
+                    items.push(Value::Object(Map::new()));
+                } else {
+                    match parent.get(last) {
+                        Some(Value::Object(t)) if t.values().any(|v| !v.is_object()) => {
+                            return Err(fail(format!("table `{}` defined twice", path.join("."))));
+                        }
+                        Some(Value::Object(_)) | None => {
+                            parent
+                                .entry(last.clone())
+                                .or_insert_with(|| Value::Object(Map::new()));
+                        }
+                        Some(_) => {
+                            return Err(fail(format!("`{}` is already a value", path.join("."))));
+                        }
+                    }
+                }
+                current = path;
+                continue;
+            }
+            let key_line = self.line;
+            let key = self.key()?;
+            self.skip_ws();
+            self.expect('=')?;
+            self.skip_ws();
+            let line = self.line;
+            let value = self.value()?;
+            self.record(&key, key_line, &value);
+            self.end_of_line()?;
+            let table =
+                table_mut(&mut root, &current).map_err(|message| Error { line, message })?;
+            insert(table, &key, value).map_err(|message| Error { line, message })?;
+        }
+    }
+
+    /// `a`, `a.b`, `"quoted key".c`.
+    fn key(&mut self) -> Result<Vec<String>, Error> {
+        let mut parts = Vec::new();
+        loop {
+            self.skip_ws();
+            let part = match self.peek() {
+                Some('"') => {
+                    self.bump();
+                    self.basic_string()?
+                }
+                Some('\'') => {
+                    self.bump();
+                    self.literal_string()?
+                }
+                _ => {
+                    let start = self.pos;
+                    while self
+                        .peek()
+                        .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                    {
+                        self.bump();
+                    }
+                    if self.pos == start {
+                        return match self.peek() {
+                            Some(c) => self.err(format!("expected a key, found `{c}`")),
+                            None => self.err("expected a key, found end of file"),
+                        };
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            parts.push(part);
+            self.skip_ws();
+            if !self.eat('.') {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        match self.peek() {
+            Some('"') if self.starts_with("\"\"\"") => {
+                self.pos += 3;
+                self.multiline_basic_string().map(Value::String)
+            }
+            Some('"') => {
+                self.bump();
+                self.basic_string().map(Value::String)
+            }
+            Some('\'') if self.starts_with("'''") => {
+                self.pos += 3;
+                self.multiline_literal_string().map(Value::String)
+            }
+            Some('\'') => {
+                self.bump();
+                self.literal_string().map(Value::String)
+            }
+            Some('[') => {
+                self.bump();
+                self.array()
+            }
+            Some('{') => {
+                self.bump();
+                self.inline_table()
+            }
+            Some(_) if self.starts_with("true") => {
+                self.pos += 4;
+                Ok(Value::Bool(true))
+            }
+            Some(_) if self.starts_with("false") => {
+                self.pos += 5;
+                Ok(Value::Bool(false))
+            }
+            Some(_) => self.number(),
+            None => self.err("expected a value, found end of file"),
+        }
+    }
+
+    fn escape(&mut self, out: &mut String) -> Result<(), Error> {
+        let c = match self.bump() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('e') => '\u{1b}',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some(u @ ('u' | 'U')) => {
+                let len = if u == 'u' { 4 } else { 8 };
+                let hex: String = (0..len).filter_map(|_| self.bump()).collect();
+                let code = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                match code {
+                    Some(c) if hex.len() == len => c,
+                    _ => return self.err(format!("invalid unicode escape `\\{u}{hex}`")),
+                }
+            }
+            Some(c) => return self.err(format!("invalid escape `\\{c}`")),
+            None => return self.err("unterminated string"),
+        };
+        out.push(c);
+        Ok(())
+    }
+
+    fn basic_string(&mut self) -> Result<String, Error> {
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(out),
+                Some('\\') => self.escape(&mut out)?,
+                Some('\n') | None => return self.err("unterminated string"),
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, Error> {
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                Some('\'') => return Ok(out),
+                Some('\n') | None => return self.err("unterminated string"),
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    /// A newline directly after the opening quotes is not part of the string.
+    fn skip_leading_newline(&mut self) {
+        if self.starts_with("\r\n") {
+            self.bump();
+        }
+        self.eat('\n');
+    }
+
+    fn multiline_basic_string(&mut self) -> Result<String, Error> {
+        self.skip_leading_newline();
+        let mut out = String::new();
+        loop {
+            if self.starts_with("\"\"\"") {
+                self.pos += 3;
+                return Ok(out);
+            }
+            match self.bump() {
+                // Line-ending backslash: drop the newline and leading
+                // whitespace of the next line.
+                Some('\\') if matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) => {
+                    while matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) {
+                        self.bump();
+                    }
+                }
+                Some('\\') => self.escape(&mut out)?,
+                Some(c) => out.push(c),
+                None => return self.err("unterminated multi-line string"),
+            }
+        }
+    }
+
+    fn multiline_literal_string(&mut self) -> Result<String, Error> {
+        self.skip_leading_newline();
+        let mut out = String::new();
+        loop {
+            if self.starts_with("'''") {
+                self.pos += 3;
+                return Ok(out);
+            }
+            match self.bump() {
+                Some(c) => out.push(c),
+                None => return self.err("unterminated multi-line string"),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, Error> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.eat(']') {
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_blank();
+            if self.eat(']') {
+                return Ok(Value::Array(items));
+            }
+            self.expect(',')?;
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value, Error> {
+        let mut table = Map::new();
+        self.skip_ws();
+        if self.eat('}') {
+            return Ok(Value::Object(table));
+        }
+        loop {
+            let line = self.line;
+            let key = self.key()?;
+            self.skip_ws();
+            self.expect('=')?;
+            self.skip_ws();
+            let value = self.value()?;
+            self.record(&key, line, &value);
+            insert(&mut table, &key, value).map_err(|message| Error {
+                line: self.line,
+                message,
+            })?;
+            self.skip_ws();
+            if self.eat('}') {
+                return Ok(Value::Object(table));
+            }
+            self.expect(',')?;
+            self.skip_ws();
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, Error> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.' | '_' | ':'))
+        {
+            self.bump();
+        }
+        let raw: String = self.chars[start..self.pos].iter().collect();
+        if raw.is_empty() {
+            return match self.peek() {
+                Some(c) => self.err(format!("expected a value, found `{c}`")),
+                None => self.err("expected a value, found end of file"),
+            };
+        }
+        let bad = || format!("invalid value `{raw}`");
+        if raw.contains(':') || raw.matches('-').count() >= 2 && !raw.contains(['e', 'E']) {
+            return self.err(format!("dates and times are not supported (`{raw}`)"));
+        }
+        let digits = raw.replace('_', "");
+        let (sign, body) = match digits.strip_prefix('-') {
+            Some(rest) => (-1, rest),
+            None => (1, digits.strip_prefix('+').unwrap_or(&digits)),
+        };
+        let radix = [("0x", 16), ("0o", 8), ("0b", 2)]
+            .iter()
+            .find_map(|(p, r)| body.strip_prefix(p).map(|b| (b, *r)));
+        if let Some((b, radix)) = radix {
+            return match i64::from_str_radix(b, radix) {
+                Ok(n) => Ok(Value::from(sign * n)),
+                Err(_) => self.err(bad()),
+            };
+        }
+        match body {
+            "inf" | "nan" => return self.err(format!("`{raw}` has no JSON representation")),
+            _ => {}
+        }
+        if body.contains(['.', 'e', 'E']) {
+            return match digits.parse::<f64>().ok().and_then(Number::from_f64) {
+                Some(n) => Ok(Value::Number(n)),
+                None => self.err(bad()),
+            };
+        }
+        match digits.parse::<i64>() {
+            Ok(n) => Ok(Value::from(n)),
+            Err(_) => self.err(bad()),
+        }
+    }
+}
+
+/// Serialize an object tree as a TOML document. `null`s are omitted (TOML
+/// has no null); the top level must be an object.
+pub fn to_string(value: &Value) -> String {
+    let mut out = String::new();
+    if let Value::Object(table) = value {
+        write_table(&mut out, &[], table);
+    }
+    out
+}
+
+fn is_table_array(v: &Value) -> bool {
+    matches!(v, Value::Array(items) if !items.is_empty() && items.iter().all(Value::is_object))
+}
+
+fn write_table(out: &mut String, path: &[String], table: &Map<String, Value>) {
+    for (k, v) in table {
+        if v.is_null() || v.is_object() || is_table_array(v) {
+            continue;
+        }
+        out.push_str(&format!("{} = {}\n", key(k), inline(v)));
+    }
+    for (k, v) in table {
+        let mut sub = path.to_vec();
+        sub.push(key(k));
+        match v {
+            Value::Object(t) => {
+                let direct = t
+                    .values()
+                    .any(|v| !v.is_null() && !v.is_object() && !is_table_array(v));
+                if direct || t.is_empty() {
+                    sep(out);
+                    out.push_str(&format!("[{}]\n", sub.join(".")));
+                }
+                write_table(out, &sub, t);
+            }
+            Value::Array(items) if is_table_array(v) => {
+                for item in items {
+                    sep(out);
+                    out.push_str(&format!("[[{}]]\n", sub.join(".")));
+                    if let Value::Object(t) = item {
+                        write_table(out, &sub, t);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn sep(out: &mut String) {
+    if !out.is_empty() {
+        out.push('\n');
+    }
+}
+
+fn key(k: &str) -> String {
+    let bare = !k.is_empty()
+        && k.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if bare {
+        k.to_string()
+    } else {
+        quote(k)
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn inline(v: &Value) -> String {
+    match v {
+        Value::String(s) => quote(s),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().filter(|v| !v.is_null()).map(inline).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Object(t) => {
+            let pairs: Vec<String> = t
+                .iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| format!("{} = {}", key(k), inline(v)))
+                .collect();
+            if pairs.is_empty() {
+                "{}".to_string()
+            } else {
+                format!("{{ {} }}", pairs.join(", "))
+            }
+        }
+        Value::Number(n) if n.is_f64() && !n.to_string().contains(['.', 'e', 'E']) => {
+            format!("{n}.0")
+        }
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_tables_keys_and_scalars() {
+        let doc = parse(
+            r#"
+# build defaults
+workspace = "kernels/x86_64"   # trailing comment
+jobs = 8
+max = 0x10_0000
+ratio = 1.5
+native = true
+sources.exclude = ['kernel\slm\*']
+
+[flags]
+"kernel/slm" = ["-O3", "-ffast-math"]
+
+[image]
+format = "iso"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            doc,
+            json!({
+                "workspace": "kernels/x86_64",
+                "jobs": 8,
+                "max": 0x100000,
+                "ratio": 1.5,
+                "native": true,
+                "sources": {"exclude": ["kernel\\slm\\*"]},
+                "flags": {"kernel/slm": ["-O3", "-ffast-math"]},
+                "image": {"format": "iso"},
+            })
+        );
+    }
+
+    #[test]
+    fn parses_arrays_of_tables_and_inline_tables() {
+        let doc = parse(
+            "[[test]]\nname = \"boot\"\nqemu = { memory = 256, smp = 2 }\n\
+             [test.expect]\nordered = [\n  \"[BOOT] OK\", # first\n  \"done\",\n]\n\
+             [[test]]\nname = \"pmm\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            doc,
+            json!({"test": [
+                {"name": "boot", "qemu": {"memory": 256, "smp": 2},
+                 "expect": {"ordered": ["[BOOT] OK", "done"]}},
+                {"name": "pmm"},
+            ]})
+        );
+    }
+
+    #[test]
+    fn parses_string_forms() {
+        let doc = parse(
+            "a = \"tab\\there \\u00e9\"\nb = '''\nraw \\n\n'''\nc = \"\"\"\nx \\\n    y\"\"\"\n",
+        )
+        .unwrap();
+        assert_eq!(doc["a"], "tab\there é");
+        assert_eq!(doc["b"], "raw \\n\n");
+        assert_eq!(doc["c"], "x y");
+    }
+
+    #[test]
+    fn errors_carry_line_numbers() {
+        let err = parse("a = 1\n\nb = \n").unwrap_err();
+        assert_eq!(err.line, 3);
+        let err = parse("a = 1\na = 2\n").unwrap_err();
+        assert_eq!((err.line, err.message.as_str()), (2, "duplicate key `a`"));
+        assert!(parse("a = 1 2\n").is_err());
+        assert!(parse("d = 1979-05-27\n")
+            .unwrap_err()
+            .message
+            .contains("dates"));
+        assert!(parse("s = \"open\n").is_err());
+        assert!(parse("[t]\nx = 1\n[t]\ny = 2\n").is_err());
+    }
+
+    #[test]
+    fn round_trips_through_to_string() {
+        let value = json!({
+            "arch": "x86_64",
+            "archs": ["x86_64", "aarch64"],
+            "skipped": null,
+            "flags": {"kernel/slm": ["-O3"]},
+            "nested": {"deeper": {"x": 1.0}},
+            "test": [{"name": "boot"}, {"name": "quoted \"name\""}],
+        });
+        let text = to_string(&value);
+        assert!(text.starts_with("arch = \"x86_64\"\narchs = [\"x86_64\", \"aarch64\"]\n"));
+        assert!(text.contains("[flags]\n\"kernel/slm\" = [\"-O3\"]\n"));
+        assert!(!text.contains("[nested]\n"));
+        let mut expected = value.clone();
+        expected.as_object_mut().unwrap().remove("skipped");
+        assert_eq!(parse(&text).unwrap(), expected);
+    }
+
+    #[test]
+    fn from_str_deserializes_structs() {
+        #[derive(serde::Deserialize)]
+        struct Conf {
+            name: String,
+            #[serde(default)]
+            jobs: Option<u32>,
+        }
+        let c: Conf = from_str("name = \"x\"\njobs = 4\n").unwrap();
+        assert_eq!((c.name.as_str(), c.jobs), ("x", Some(4)));
+        assert!(from_str::<Conf>("jobs = 4\n").is_err());
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(deny_unknown_fields, rename_all = "kebab-case")]
+    #[allow(dead_code)]
+    struct Spec {
+        #[serde(default)]
+        expect: Vec<String>,
+        #[serde(default)]
+        timeout: Option<u64>,
+        #[serde(default)]
+        accel: Option<Accel>,
+        #[serde(default)]
+        build: Option<Build>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(deny_unknown_fields, rename_all = "kebab-case")]
+    #[allow(dead_code)]
+    struct Build {
+        workspace: String,
+        #[serde(default)]
+        max_stack_bytes: u64,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    enum Accel {
+        Auto,
+        Kvm,
+        Tcg,
+    }
+
+    fn error(text: &str) -> String {
+        from_named::<Spec>(text, "tests/boot.toml")
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn errors_name_the_file_and_line_and_suggest_names() {
+        assert_eq!(
+            error("timeout = 5\n\nexpct = [\"ok\"]\n"),
+            "unknown key `expct` at tests/boot.toml:3, did you mean `expect`?"
+        );
+        assert_eq!(
+            error("[build]\nworkspace = \"k\"\nmax_stack_bytes = 1\n"),
+            "unknown key `max_stack_bytes` at tests/boot.toml:3, did you mean `max-stack-bytes`?"
+        );
+        assert_eq!(
+            error("build = { workspace = \"k\", jobs = 4 }\n"),
+            "unknown key `jobs` at tests/boot.toml:1"
+        );
+        assert_eq!(
+            error("timeout = 5\naccel = \"kmv\"\n"),
+            "unknown value `kmv` at tests/boot.toml:2, did you mean `kvm`?"
+        );
+        assert_eq!(
+            error("accel = \"fast\"\n"),
+            "unknown value `fast` at tests/boot.toml:1, expected one of `auto`, `kvm`, `tcg`"
+        );
+        assert_eq!(
+            error("expect = []\ntimeout = \"5s\"\n"),
+            "invalid type: string \"5s\", expected u64 at tests/boot.toml:2"
+        );
+        assert_eq!(
+            error("[build]\n"),
+            "missing field `workspace` in tests/boot.toml"
+        );
+        assert_eq!(
+            error("expect = []\ntimeout = 5 6\n"),
+            "unexpected `6` after value at tests/boot.toml:2"
+        );
+    }
+
+    #[test]
+    fn typos_are_a_few_edits_away() {
+        assert_eq!(distance("expct", "expect"), 1);
+        assert_eq!(distance("", "abc"), 3);
+        assert_eq!(distance("kmv", "kvm"), 1);
+        assert_eq!(closest("tiemout", &["timeout", "trace"]), Some("timeout"));
+        assert_eq!(closest("qemu", &["timeout", "trace"]), None);
+    }
+}
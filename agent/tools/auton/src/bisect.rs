@@ -0,0 +1,323 @@
+//! `auton bisect`: the first commit in a range of the workspace's git
+//! history that broke a test.
+//!
+//! The commits in `good..bad` that touch the workspace, along the first
+//! parent, are the candidates; `good` is taken to pass and `bad` is checked
+//! to fail first. Each candidate tried is exported with `git archive` into
+//! `<scratch>/tree` (the workspace and its repository are never written
+//! to), built into `<scratch>/build` and booted with the test spec. Every
+//! candidate builds into the same output directory, so kernel-builder's
+//! object cache carries over and only what a commit changed is rebuilt.
+//!
+//! A candidate that does not build counts as bad, since it broke the test
+//! too, unless [`Bisect::skip_broken`] is set: then it is skipped, as `git
+//! bisect skip` would, and if the skipped ones leave more than one commit
+//! that could be first, they are all reported as suspects.
+
+use crate::verify::{arg, build_stage, run_tool, Options};
+use crate::{Stage, Status};
+use anyhow::{bail, Context, Result};
+use auton_core::process;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+use tokio::process::Command;
+
+/// `good..bad`: a commit where the test passes and a later one where it
+/// fails.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Range {
+    pub good: String,
+    pub bad: String,
+}
+
+impl FromStr for Range {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        match text.split_once("..") {
+            Some((good, bad)) if !good.is_empty() && !bad.is_empty() && !bad.starts_with('.') => {
+                Ok(Self {
+                    good: good.to_string(),
+                    bad: bad.to_string(),
+                })
+            }
+            _ => bail!("invalid range `{text}` (use GOOD..BAD)"),
+        }
+    }
+}
+
+/// What to bisect, with [`Options`] for the workspace, tools and scratch
+/// directory.
+#[derive(Debug, Clone)]
+pub struct Bisect {
+    pub range: Range,
+    /// The test spec that broke.
+    pub spec: PathBuf,
+    /// Skip commits that do not build instead of counting them as bad.
+    pub skip_broken: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Commit {
+    pub sha: String,
+    pub subject: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mark {
+    Good,
+    Bad,
+    Skip,
+}
+
+/// One candidate tried.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    pub commit: Commit,
+    pub mark: Mark,
+    /// Its `build` stage, and `test` stage if it built.
+    pub stages: Vec<Stage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bisection {
+    /// Candidates in the range.
+    pub commits: usize,
+    /// The first commit where the test fails.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_bad: Option<Commit>,
+    /// When skipped commits hide which one that is: every commit it could
+    /// be, oldest first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub suspects: Vec<Commit>,
+    /// Every candidate tried, in the order tried.
+    pub steps: Vec<Step>,
+}
+
+/// Bisect `bisect.range`, reporting each step as it is marked.
+pub async fn bisect(
+    opts: &Options,
+    bisect: &Bisect,
+    progress: &mut (dyn FnMut(&Step) + Send),
+) -> Result<Bisection> {
+    std::fs::create_dir_all(&opts.scratch)
+        .with_context(|| format!("creating {}", opts.scratch.display()))?;
+    let commits = commits(&opts.workspace, &bisect.range).await?;
+    if commits.is_empty() {
+        bail!(
+            "no commits in {}..{} touch {}",
+            bisect.range.good,
+            bisect.range.bad,
+            opts.workspace.display()
+        );
+    }
+    let mut steps = Vec::new();
+    let mut marks = vec![None; commits.len()];
+    // The first bad commit is in lo..=hi, and hi is bad.
+    let (mut lo, mut hi) = (0, commits.len() - 1);
+    let mut next = Some(hi);
+    while let Some(index) = next {
+        let step = try_commit(opts, bisect, &commits[index]).await?;
+        progress(&step);
+        let mark = step.mark;
+        steps.push(step);
+        if index == commits.len() - 1 && mark != Mark::Bad {
+            bail!(
+                "{} is not bad: {}",
+                bisect.range.bad,
+                match mark {
+                    Mark::Good => "the test passes there",
+                    _ => "it does not build",
+                }
+            );
+        }
+        marks[index] = Some(mark);
+        match mark {
+            Mark::Good => lo = index + 1,
+            Mark::Bad => hi = index,
+            Mark::Skip => {}
+        }
+        next = candidate(&marks, lo, hi);
+    }
+
+    let (first_bad, suspects) = if lo == hi {
+        (Some(commits[hi].clone()), Vec::new())
+    } else {
+        (None, commits[lo..=hi].to_vec())
+    };
+    Ok(Bisection {
+        commits: commits.len(),
+        first_bad,
+        suspects,
+        steps,
+    })
+}
+
+/// The untried commit in `lo..hi` nearest its middle, if any is left.
+fn candidate(marks: &[Option<Mark>], lo: usize, hi: usize) -> Option<usize> {
+    let mid = lo + (hi - lo) / 2;
+    (lo..hi)
+        .filter(|&i| marks[i].is_none())
+        .min_by_key(|&i| (i.abs_diff(mid), i))
+}
+
+/// The candidates in `range`, oldest first.
+async fn commits(workspace: &Path, range: &Range) -> Result<Vec<Commit>> {
+    let mut cmd = Command::new("git");
+    cmd.arg("-C")
+        .arg(workspace)
+        .args(["log", "--first-parent", "--reverse", "--format=%H%x09%s"])
+        .arg(format!("{}..{}", range.good, range.bad))
+        .args(["--", "."]);
+    let output = process::run(cmd, None)
+        .await?
+        .check(&format!("listing commits in {}", workspace.display()))?;
+    Ok(output
+        .stdout
+        .lines()
+        .filter_map(|line| {
+            let (sha, subject) = line.split_once('\t')?;
+            Some(Commit {
+                sha: sha.to_string(),
+                subject: subject.to_string(),
+            })
+        })
+        .collect())
+}
+
+/// Export, build and test `commit`.
+async fn try_commit(opts: &Options, bisect: &Bisect, commit: &Commit) -> Result<Step> {
+    let tree = opts.scratch.join("tree");
+    export(&opts.workspace, &commit.sha, &tree, &opts.scratch).await?;
+
+    let started = Instant::now();
+    let (mut build, image) = build_stage(opts, &tree).await;
+    build.duration_ms = started.elapsed().as_millis() as u64;
+    let Some(image) = image else {
+        let mark = if bisect.skip_broken {
+            Mark::Skip
+        } else {
+            Mark::Bad
+        };
+        return Ok(Step {
+            commit: commit.clone(),
+            mark,
+            stages: vec![build],
+        });
+    };
+
+    let started = Instant::now();
+    let mut test = spec_stage(opts, &bisect.spec, &image).await;
+    test.duration_ms = started.elapsed().as_millis() as u64;
+    Ok(Step {
+        commit: commit.clone(),
+        mark: match test.status {
+            Status::Passed => Mark::Good,
+            _ => Mark::Bad,
+        },
+        stages: vec![build, test],
+    })
+}
+
+/// Replace `tree` with the workspace as of `sha`.
+async fn export(workspace: &Path, sha: &str, tree: &Path, scratch: &Path) -> Result<()> {
+    let mut cmd = Command::new("git");
+    cmd.arg("-C")
+        .arg(workspace)
+        .args(["rev-parse", "--show-toplevel", "--show-prefix"]);
+    let found = process::run(cmd, None)
+        .await?
+        .check(&format!(
+            "finding {} in its repository",
+            workspace.display()
+        ))?
+        .stdout;
+    let mut lines = found.lines();
+    let (top, prefix) = (lines.next().unwrap_or("."), lines.next().unwrap_or(""));
+
+    let tar = std::path::absolute(scratch.join("tree.tar"))?;
+    let mut cmd = Command::new("git");
+    // From the top: git archive will not take a tree in a subdirectory.
+    cmd.arg("-C")
+        .arg(top)
+        .args(["archive", "--format=tar", "-o"])
+        .arg(&tar)
+        .arg(format!("{sha}:{prefix}"));
+    process::run(cmd, None)
+        .await?
+        .check(&format!("exporting {sha}"))?;
+
+    match std::fs::remove_dir_all(tree) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("removing {}", tree.display()))
+        }
+        _ => {}
+    }
+    std::fs::create_dir_all(tree).with_context(|| format!("creating {}", tree.display()))?;
+    let mut cmd = Command::new("tar");
+    cmd.arg("-xf").arg(&tar).arg("-C").arg(tree);
+    process::run(cmd, None)
+        .await?
+        .check(&format!("unpacking {sha}"))?;
+    std::fs::remove_file(&tar).with_context(|| format!("removing {}", tar.display()))
+}
+
+/// test-runner on one spec: passes if the test does.
+async fn spec_stage(opts: &Options, spec: &Path, image: &Path) -> Stage {
+    let mut args = vec![
+        "--spec".to_string(),
+        arg(spec),
+        "--kernel".to_string(),
+        arg(image),
+        "--json".to_string(),
+        "--results-dir".to_string(),
+        arg(&opts.scratch.join("results")),
+    ];
+    args.extend(opts.test_args.iter().cloned());
+    let mut stage = run_tool("test", &opts.tools.runner, args).await;
+    let reason = stage
+        .report
+        .as_ref()
+        .and_then(|r| r["exit_reason"]["kind"].as_str());
+    if let Some(reason) = reason {
+        stage.summary = match stage.status {
+            Status::Passed => format!("passed ({reason})"),
+            _ => format!("failed ({reason})"),
+        };
+    }
+    stage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_parse() {
+        assert_eq!(
+            "v1..HEAD".parse::<Range>().unwrap(),
+            Range {
+                good: "v1".into(),
+                bad: "HEAD".into()
+            }
+        );
+        for bad in ["HEAD", "..HEAD", "v1..", "v1...HEAD"] {
+            assert!(bad.parse::<Range>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn candidates_halve_the_range_around_skips() {
+        let mut marks = vec![None; 8];
+        assert_eq!(candidate(&marks, 0, 7), Some(3));
+        marks[3] = Some(Mark::Skip);
+        assert_eq!(candidate(&marks, 0, 7), Some(2));
+        marks[2] = Some(Mark::Skip);
+        assert_eq!(candidate(&marks, 0, 7), Some(4));
+        assert_eq!(candidate(&marks, 2, 4), None);
+        assert_eq!(candidate(&marks, 5, 5), None);
+    }
+}
@@ -0,0 +1,138 @@
+//! `auton config validate`: every configuration file in a workspace, read
+//! the way the tool it configures reads it.
+//!
+//! That is `auton-build.toml` (kernel-builder), `diff-validator.toml`,
+//! `auton-notify.toml` and the test specs (`*.toml`) in the test
+//! directories, `<workspace>/tests` unless others are given. All of them
+//! reject unknown keys, and an error names the file and line, and the key
+//! meant when a name is misspelled.
+
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// What a file configures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+    Build,
+    Rules,
+    Notify,
+    TestSpec,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Build => "build config",
+            Self::Rules => "validator rules",
+            Self::Notify => "notifications",
+            Self::TestSpec => "test spec",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Checked {
+    pub path: PathBuf,
+    pub kind: Kind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The configuration files in `workspace` and `test_dirs` (those that do
+/// not exist are left out), in that order.
+pub fn find(workspace: &Path, test_dirs: &[PathBuf]) -> Vec<(PathBuf, Kind)> {
+    let mut found: Vec<(PathBuf, Kind)> = [
+        (kernel_builder::config::CONFIG_NAME, Kind::Build),
+        (diff_validator::rules::RULES_NAME, Kind::Rules),
+        (crate::notify::CONFIG_NAME, Kind::Notify),
+    ]
+    .into_iter()
+    .map(|(name, kind)| (workspace.join(name), kind))
+    .filter(|(path, _)| path.is_file())
+    .collect();
+    for dir in test_dirs {
+        let mut specs: Vec<PathBuf> = std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|p| p.extension().is_some_and(|e| e == "toml") && p.is_file())
+            .collect();
+        specs.sort();
+        found.extend(specs.into_iter().map(|p| (p, Kind::TestSpec)));
+    }
+    found
+}
+
+/// Read `path` as a `kind` file.
+pub fn check(path: &Path, kind: Kind) -> Checked {
+    let read = match kind {
+        Kind::Build => kernel_builder::config::BuildConfig::load(path).map(drop),
+        Kind::Rules => diff_validator::rules::Rules::load(path).map(drop),
+        Kind::Notify => crate::notify::NotifyConfig::load(path).map(drop),
+        Kind::TestSpec => test_runner::spec::TestSpec::load(path).map(drop),
+    };
+    Checked {
+        path: path.to_path_buf(),
+        kind,
+        error: read.err().map(|e| format!("{e:#}")),
+    }
+}
+
+/// Check every file [`find`] finds.
+pub fn validate(workspace: &Path, test_dirs: &[PathBuf]) -> Vec<Checked> {
+    find(workspace, test_dirs)
+        .into_iter()
+        .map(|(path, kind)| check(&path, kind))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_each_config_the_way_its_tool_reads_it() {
+        let dir = std::env::temp_dir().join(format!("auton-config-{}", std::process::id()));
+        let tests = dir.join("tests");
+        std::fs::create_dir_all(&tests).unwrap();
+        std::fs::write(dir.join("auton-build.toml"), "jobs = 4\n").unwrap();
+        std::fs::write(dir.join("diff-validator.toml"), "max-stack-bytes = 2048\n").unwrap();
+        std::fs::write(tests.join("boot.toml"), "timeout = 30\n\nexpct = ['OK']\n").unwrap();
+        std::fs::write(tests.join("mm.toml"), "accel = 'kmv'\n").unwrap();
+        std::fs::write(tests.join("notes.txt"), "not a spec").unwrap();
+
+        let checked = validate(&dir, std::slice::from_ref(&tests));
+        let got: Vec<(&Path, Kind, Option<&str>)> = checked
+            .iter()
+            .map(|c| (c.path.as_path(), c.kind, c.error.as_deref()))
+            .collect();
+        let boot = format!(
+            "unknown key `expct` at {}:3, did you mean `expect`?",
+            tests.join("boot.toml").display()
+        );
+        let mm = format!(
+            "unknown value `kmv` at {}:1, did you mean `kvm`?",
+            tests.join("mm.toml").display()
+        );
+        assert_eq!(
+            got,
+            [
+                (dir.join("auton-build.toml").as_path(), Kind::Build, None),
+                (dir.join("diff-validator.toml").as_path(), Kind::Rules, None),
+                (
+                    tests.join("boot.toml").as_path(),
+                    Kind::TestSpec,
+                    Some(boot.as_str())
+                ),
+                (
+                    tests.join("mm.toml").as_path(),
+                    Kind::TestSpec,
+                    Some(mm.as_str())
+                ),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
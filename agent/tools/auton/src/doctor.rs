@@ -0,0 +1,408 @@
+//! `auton doctor`: whether this host has what the tools need.
+//!
+//! A build that fails for want of a cross compiler, or a test that times
+//! out because QEMU runs without KVM, looks much like a broken kernel, so
+//! `doctor` checks the host on its own. Each [`Check`] passes, warns or
+//! fails. A check fails when what it looks for is missing and no run for
+//! one of [`Options::arches`] can do without it: that architecture's QEMU
+//! and C compiler, and [`MIN_FREE`] of disk space for the scratch
+//! directory. It warns when only some runs need it: the other
+//! architectures' QEMU and compilers, KVM (TCG stands in, with longer
+//! timeouts), nasm for `.asm` boot code, grub-mkrescue and xorriso for ISO
+//! images, mtools for raw ones, OVMF for UEFI boots, and resource limits
+//! too low for a suite's parallel QEMUs.
+
+use crate::verify::plural;
+use anyhow::Result;
+use auton_core::process;
+use kernel_builder::{exec, toolchain, ArchToolchain};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::process::Command;
+
+/// The architectures the tools build and boot.
+pub const ARCHES: [&str; 3] = ["x86_64", "aarch64", "riscv64"];
+
+/// Free space below which the scratch directory's check fails, and below
+/// which it warns.
+pub const MIN_FREE: u64 = 1 << 30;
+pub const LOW_FREE: u64 = 5 << 30;
+
+/// How long a `--version` probe may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pass => "pass",
+            Self::Warn => "warn",
+            Self::Fail => "fail",
+        }
+    }
+}
+
+/// One thing checked.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Check {
+    /// `qemu-system-x86_64`, `ulimit -n`.
+    pub name: String,
+    pub status: Status,
+    /// The version and path found, or what is wrong.
+    pub detail: String,
+    /// What to do about a warning or failure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> Self {
+        if self.status != Status::Pass {
+            self.hint = Some(hint.into());
+        }
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The architectures runs are for; missing tools for the others only
+    /// warn.
+    pub arches: Vec<String>,
+    /// Where builds and test results go, for the disk space check.
+    pub scratch: PathBuf,
+}
+
+/// Every check, in the order printed.
+pub async fn run(opts: &Options) -> Result<Vec<Check>> {
+    for arch in &opts.arches {
+        ArchToolchain::for_arch(arch)?;
+    }
+    let mut checks = Vec::new();
+    for arch in ARCHES {
+        let tc = ArchToolchain::for_arch(arch)?;
+        let missing = if opts.arches.iter().any(|a| a == arch) {
+            Status::Fail
+        } else {
+            Status::Warn
+        };
+        let package = match arch {
+            "x86_64" => "qemu-system-x86",
+            "aarch64" => "qemu-system-arm",
+            _ => "qemu-system-misc",
+        };
+        let qemu = tool(&tc.qemu, &["--version"], missing).await;
+        checks.push(qemu.hint(format!(
+            "{arch} guests boot in {}: install {package}",
+            tc.qemu
+        )));
+        checks.push(compiler(&tc, missing).await);
+    }
+    checks.push(kvm());
+    checks.push(
+        tool("nasm", &["-v"], Status::Warn)
+            .await
+            .hint("x86_64 `.asm` boot code is assembled with nasm: install nasm"),
+    );
+    checks.push(
+        tool("grub-mkrescue", &["--version"], Status::Warn)
+            .await
+            .hint(
+            "ISO images need grub-mkrescue: install grub-pc-bin (and grub-efi-amd64-bin for UEFI)",
+        ),
+    );
+    checks.push(
+        tool("xorriso", &["-version"], Status::Warn)
+            .await
+            .hint("grub-mkrescue writes ISOs with xorriso: install xorriso"),
+    );
+    checks.push(
+        tool("mformat", &["--version"], Status::Warn)
+            .await
+            .hint("raw disk images are formatted with mtools: install mtools"),
+    );
+    checks.push(ovmf());
+    checks.push(disk(&opts.scratch));
+    checks.extend(limits());
+    Ok(checks)
+}
+
+/// `program` on PATH and its version; `missing` if it is not there or
+/// does not run.
+async fn tool(program: &str, version_args: &[&str], missing: Status) -> Check {
+    let Some(path) = exec::which(program) else {
+        return Check::new(program, missing, "not found on PATH");
+    };
+    let mut cmd = Command::new(&path);
+    cmd.args(version_args);
+    match process::run(cmd, Some(PROBE_TIMEOUT)).await {
+        Ok(output) if output.success() => {
+            let version = version(&(output.stdout + &output.stderr));
+            Check::new(
+                program,
+                Status::Pass,
+                format!("{version} ({})", path.display()),
+            )
+        }
+        Ok(output) => Check::new(
+            program,
+            missing,
+            format!("{} exited with {}", path.display(), output.status()),
+        ),
+        Err(e) => Check::new(program, missing, format!("{e:#}")),
+    }
+}
+
+/// The compiler kernel-builder would pick for `tc`, with kernel-builder's
+/// advice on what to install when there is none.
+async fn compiler(tc: &ArchToolchain, missing: Status) -> Check {
+    let name = format!("cc ({})", tc.arch);
+    match toolchain::detect(tc, None).await {
+        Ok(cc) => Check::new(
+            name,
+            Status::Pass,
+            format!("{} {} ({})", cc.program, cc.version, cc.path.display()),
+        ),
+        Err(e) => {
+            let message = format!("{e:#}");
+            let (detail, rest) = message.split_once('\n').unwrap_or((&message, ""));
+            let check = Check::new(name, missing, detail);
+            match rest.trim() {
+                "" => check.hint(format!(
+                    "kernel-builder --cc names a compiler for {}",
+                    tc.arch
+                )),
+                rest => check.hint(rest),
+            }
+        }
+    }
+}
+
+fn kvm() -> Check {
+    match test_runner::accel::probe_kvm() {
+        Ok(()) => Check::new(
+            "kvm",
+            Status::Pass,
+            format!("usable for {} guests", std::env::consts::ARCH),
+        ),
+        Err(why) => Check::new("kvm", Status::Warn, why).hint(format!(
+            "{} guests run under TCG, with timeouts {}x as long; load kvm and join the kvm group",
+            std::env::consts::ARCH,
+            test_runner::accel::DEFAULT_TCG_TIMEOUT_FACTOR
+        )),
+    }
+}
+
+fn ovmf() -> Check {
+    match test_runner::machine::find_ovmf() {
+        Some(path) => Check::new("ovmf", Status::Pass, path.display().to_string()),
+        None => Check::new(
+            "ovmf",
+            Status::Warn,
+            "not in any of the usual /usr/share paths",
+        )
+        .hint("UEFI boots need OVMF: install ovmf (edk2-ovmf), or give test-runner --firmware"),
+    }
+}
+
+/// Free space for the scratch directory, or the nearest parent that
+/// exists.
+fn disk(scratch: &Path) -> Check {
+    let dir = scratch
+        .ancestors()
+        .find(|d| d.is_dir())
+        .unwrap_or(Path::new("."));
+    match free_bytes(dir) {
+        Ok(free) => disk_check(dir, free),
+        Err(e) => Check::new("disk", Status::Warn, format!("{}: {e}", dir.display())),
+    }
+}
+
+fn disk_check(dir: &Path, free: u64) -> Check {
+    let status = if free < MIN_FREE {
+        Status::Fail
+    } else if free < LOW_FREE {
+        Status::Warn
+    } else {
+        Status::Pass
+    };
+    let gib = free as f64 / (1u64 << 30) as f64;
+    Check::new(
+        "disk",
+        status,
+        format!("{gib:.1} GiB free in {}", dir.display()),
+    )
+    .hint("builds, disk images and test results need the space: `auton gc` drops unreferenced artifacts")
+}
+
+fn free_bytes(dir: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    // SAFETY: `path` is NUL-terminated and `statvfs` fills in the struct
+    // it is given.
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut st) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(st.f_bavail as u64 * st.f_frsize as u64)
+}
+
+/// The soft limits a suite's parallel QEMUs run into.
+fn limits() -> Vec<Check> {
+    [
+        (
+            "ulimit -n",
+            libc::RLIMIT_NOFILE,
+            1024,
+            "each QEMU holds its disks, serial log and QMP socket open; raise it with `ulimit -n`",
+        ),
+        (
+            "ulimit -u",
+            libc::RLIMIT_NPROC,
+            1024,
+            "parallel builds and QEMUs each take processes and threads; raise it with `ulimit -u`",
+        ),
+        (
+            "ulimit -v",
+            libc::RLIMIT_AS,
+            u64::MAX,
+            "QEMU maps all of the guest's memory up front; make it unlimited with `ulimit -v unlimited`",
+        ),
+    ]
+    .into_iter()
+    .map(|(name, resource, want, hint)| {
+        // SAFETY: `getrlimit` fills in the struct it is given.
+        let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrlimit(resource, &mut limit) } != 0 {
+            let e = std::io::Error::last_os_error();
+            return Check::new(name, Status::Warn, e.to_string());
+        }
+        // RLIM_INFINITY is u64::MAX, which limit_check takes as unlimited.
+        limit_check(name, limit.rlim_cur, want).hint(hint)
+    })
+    .collect()
+}
+
+fn limit_check(name: &str, soft: u64, want: u64) -> Check {
+    let shown = match soft {
+        u64::MAX => "unlimited".to_string(),
+        n => n.to_string(),
+    };
+    let detail = match want {
+        u64::MAX if soft < want => format!("{shown}, not unlimited"),
+        _ if soft < want => format!("{shown}, below {want}"),
+        _ => shown,
+    };
+    let status = if soft < want {
+        Status::Warn
+    } else {
+        Status::Pass
+    };
+    Check::new(name, status, detail)
+}
+
+/// The version in a `--version` banner: the first word that starts with a
+/// digit and has a dot in it, else the banner's first line.
+fn version(banner: &str) -> String {
+    let first = banner.lines().find(|l| !l.trim().is_empty()).unwrap_or("");
+    first
+        .split_whitespace()
+        .find(|w| w.starts_with(|c: char| c.is_ascii_digit()) && w.contains('.'))
+        .unwrap_or(first.trim())
+        .trim_end_matches([',', ':'])
+        .to_string()
+}
+
+/// `14 checks: 2 warnings, 1 failure`.
+pub fn summary(checks: &[Check]) -> String {
+    let count = |status| checks.iter().filter(|c| c.status == status).count();
+    let (warnings, failures) = (count(Status::Warn), count(Status::Fail));
+    let mut found = Vec::new();
+    if warnings > 0 {
+        found.push(plural(warnings, "warning"));
+    }
+    if failures > 0 {
+        found.push(plural(failures, "failure"));
+    }
+    if found.is_empty() {
+        found.push("all passed".to_string());
+    }
+    format!("{}: {}", plural(checks.len(), "check"), found.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_come_from_the_banner() {
+        assert_eq!(
+            version("QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)\nCopyright"),
+            "8.2.2"
+        );
+        assert_eq!(
+            version("NASM version 2.16.01 compiled on Mar 31 2024"),
+            "2.16.01"
+        );
+        assert_eq!(
+            version("grub-mkrescue (GRUB) 2.12-1ubuntu7\n"),
+            "2.12-1ubuntu7"
+        );
+        assert_eq!(
+            version("xorriso 1.5.6 : RockRidge filesystem manipulator"),
+            "1.5.6"
+        );
+        assert_eq!(version("\nsomething odd\n"), "something odd");
+    }
+
+    #[test]
+    fn thresholds_decide_the_status() {
+        let dir = Path::new("/build");
+        assert_eq!(disk_check(dir, MIN_FREE - 1).status, Status::Fail);
+        assert_eq!(disk_check(dir, MIN_FREE).status, Status::Warn);
+        let plenty = disk_check(dir, 10 << 30);
+        assert_eq!(plenty.status, Status::Pass);
+        assert_eq!(plenty.detail, "10.0 GiB free in /build");
+        assert_eq!(plenty.hint, None);
+
+        assert_eq!(
+            limit_check("ulimit -n", 256, 1024).detail,
+            "256, below 1024"
+        );
+        assert_eq!(limit_check("ulimit -n", 4096, 1024).status, Status::Pass);
+        let capped = limit_check("ulimit -v", 8 << 30, u64::MAX);
+        assert_eq!(
+            (capped.status, capped.detail.as_str()),
+            (Status::Warn, "8589934592, not unlimited")
+        );
+        assert_eq!(
+            limit_check("ulimit -v", u64::MAX, u64::MAX).detail,
+            "unlimited"
+        );
+
+        let checks = [
+            Check::new("a", Status::Pass, ""),
+            Check::new("b", Status::Warn, ""),
+            Check::new("c", Status::Fail, ""),
+        ];
+        assert_eq!(summary(&checks), "3 checks: 1 warning, 1 failure");
+        assert_eq!(summary(&checks[..1]), "1 check: all passed");
+    }
+}
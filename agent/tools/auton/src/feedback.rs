@@ -0,0 +1,544 @@
+//! Feedback bundles: what went wrong in one pipeline run, gathered into a
+//! single document for the agent to read before its next attempt.
+//!
+//! A [`Bundle`] is read from a run's `verdict.json` and the diff it names:
+//!
+//! - the validator's findings;
+//! - the compiler's diagnostics, from kernel-builder's report (its
+//!   `diagnostics`, else parsed from its stderr);
+//! - per failed test: the exit reason, the classified cause with its
+//!   symbolized backtrace, and the serial lines around the failure (the
+//!   classified line, else the offending pattern's, else the tail);
+//! - the diff's hunks that a finding, diagnostic or backtrace frame points
+//!   into, each with why.
+//!
+//! `auton feedback` prints it as Markdown ([`Bundle::markdown`]) or JSON.
+
+use crate::{Status, Verdict};
+use anyhow::{Context, Result};
+use auton_core::diagnostics::{self, Diagnostic};
+use auton_core::diff::{self, FilePatch, Hunk, HunkLine};
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+const VERDICT_NAME: &str = "verdict.json";
+
+/// Serial lines kept before and after the failing line.
+pub const CONTEXT_BEFORE: usize = 10;
+pub const CONTEXT_AFTER: usize = 20;
+/// Serial lines kept when nothing points at one.
+pub const TAIL_LINES: usize = 40;
+
+/// One stage, in brief.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StageBrief {
+    pub name: String,
+    pub status: Status,
+    pub summary: String,
+}
+
+/// Serial output, `first_line` (1-based) onwards.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Excerpt {
+    pub first_line: usize,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TestFailure {
+    pub name: String,
+    /// How the run ended: test-runner's exit reason, `kind` and all.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<Value>,
+    /// The classified cause: panic, CPU exception or triple fault.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub backtrace: Vec<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<Excerpt>,
+    /// Why the test could not run at all.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A hunk of the diff something points into.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImplicatedHunk {
+    pub file: String,
+    pub header: String,
+    /// The hunk as it is in the diff, header included.
+    pub text: String,
+    /// What points into it: `error at kernel/mm/pmm.c:12`, ...
+    pub why: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bundle {
+    /// The verdict read.
+    pub run: PathBuf,
+    pub passed: bool,
+    /// The first stage that failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_stage: Option<String>,
+    pub stages: Vec<StageBrief>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub findings: Vec<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tests: Vec<TestFailure>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hunks: Vec<ImplicatedHunk>,
+    /// The end of a failed tool's stderr, when nothing above explains it.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub log: String,
+}
+
+/// The verdict of run `run`: a job id of `auton serve`'s under
+/// `<scratch>/jobs`, a directory holding a `verdict.json`, or the file
+/// itself; `None` for the last `auton verify` in `scratch`.
+pub fn verdict_path(scratch: &Path, run: Option<&str>) -> PathBuf {
+    match run {
+        None => scratch.join(VERDICT_NAME),
+        Some(id) if !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()) => {
+            scratch.join("jobs").join(id).join(VERDICT_NAME)
+        }
+        Some(path) if Path::new(path).is_dir() => Path::new(path).join(VERDICT_NAME),
+        Some(path) => PathBuf::from(path),
+    }
+}
+
+/// The bundle for the verdict at `path`.
+pub fn load(path: &Path) -> Result<Bundle> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let verdict: Verdict =
+        serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))?;
+    // A merge applied the rebased diff, whose hunks are the ones built.
+    let rebased = path.with_file_name("rebased.diff");
+    let diff = match &verdict.diff {
+        Some(_) if rebased.is_file() => Some(rebased),
+        diff => diff.clone(),
+    };
+    let patches = match diff {
+        Some(diff) => {
+            let text = std::fs::read_to_string(&diff)
+                .with_context(|| format!("reading {}", diff.display()))?;
+            diff::parse(&text).map_err(|e| anyhow::anyhow!("{}: {e}", diff.display()))?
+        }
+        None => Vec::new(),
+    };
+    Ok(bundle(path, &verdict, &patches))
+}
+
+/// What went wrong in `verdict`, with the hunks of `patches` implicated.
+pub fn bundle(run: &Path, verdict: &Verdict, patches: &[FilePatch]) -> Bundle {
+    let mut bundle = Bundle {
+        run: run.to_path_buf(),
+        passed: verdict.passed,
+        failed_stage: None,
+        stages: Vec::new(),
+        findings: Vec::new(),
+        diagnostics: Vec::new(),
+        tests: Vec::new(),
+        hunks: Vec::new(),
+        log: String::new(),
+    };
+    // (file, line, why) for each thing that points into the source.
+    let mut pointers: Vec<(String, usize, String)> = Vec::new();
+    for stage in &verdict.stages {
+        bundle.stages.push(StageBrief {
+            name: stage.name.clone(),
+            status: stage.status,
+            summary: stage.summary.clone(),
+        });
+        let failed = stage.status == Status::Failed;
+        if failed && bundle.failed_stage.is_none() {
+            bundle.failed_stage = Some(stage.name.clone());
+        }
+        let report = stage.report.as_ref().unwrap_or(&Value::Null);
+        match stage.name.as_str() {
+            "validate" => {
+                let findings = report
+                    .as_array()
+                    .or_else(|| report.get("findings")?.as_array());
+                for finding in findings.into_iter().flatten() {
+                    if let (Some(file), Some(line)) =
+                        (finding["file"].as_str(), finding["line"].as_u64())
+                    {
+                        let rule = finding["rule"].as_str().unwrap_or("finding");
+                        pointers.push((file.into(), line as usize, format!("{rule} finding")));
+                    }
+                    bundle.findings.push(finding.clone());
+                }
+            }
+            "build" if failed => {
+                bundle.diagnostics = build_diagnostics(report);
+                for d in &bundle.diagnostics {
+                    if let (Some(file), Some(line)) = (&d.file, d.line) {
+                        let why = format!("{} at {file}:{line}", severity(d));
+                        pointers.push((file.clone(), line as usize, why));
+                    }
+                }
+            }
+            "test" if failed => {
+                let outcomes = report.as_array().into_iter().flatten();
+                for outcome in outcomes.filter(|o| o["passed"] != true) {
+                    let test = test_failure(outcome);
+                    for frame in &test.backtrace {
+                        let Some((file, line)) = frame["location"].as_str().and_then(location)
+                        else {
+                            continue;
+                        };
+                        let function = frame["function"].as_str().unwrap_or("?");
+                        let why = format!("{} backtrace: {function} ({file}:{line})", test.name);
+                        pointers.push((file.to_string(), line, why));
+                    }
+                    bundle.tests.push(test);
+                }
+            }
+            _ => {}
+        }
+        let explained = !bundle.diagnostics.is_empty() || !bundle.tests.is_empty();
+        if failed && bundle.log.is_empty() && !explained {
+            bundle.log = stage.log.clone();
+        }
+    }
+    bundle.hunks = implicated(patches, &pointers);
+    bundle
+}
+
+fn severity(d: &Diagnostic) -> &'static str {
+    match d.severity {
+        diagnostics::Severity::Error => "error",
+        diagnostics::Severity::Warning => "warning",
+        diagnostics::Severity::Note => "note",
+    }
+}
+
+/// kernel-builder's diagnostics, else those in its stderr.
+pub(crate) fn build_diagnostics(report: &Value) -> Vec<Diagnostic> {
+    let reported: Vec<Diagnostic> = report
+        .get("diagnostics")
+        .and_then(|d| serde_json::from_value(d.clone()).ok())
+        .unwrap_or_default();
+    if !reported.is_empty() {
+        return reported;
+    }
+    let stderr = report["stderr"].as_str().unwrap_or("");
+    let compiler = diagnostics::parse("cc", stderr);
+    if compiler.is_empty() {
+        diagnostics::parse("ld", stderr)
+    } else {
+        compiler
+    }
+}
+
+fn test_failure(outcome: &Value) -> TestFailure {
+    let result = &outcome["result"];
+    let some = |v: &Value| (!v.is_null()).then(|| v.clone());
+    let transcript = result["transcript"].as_str().unwrap_or("");
+    // The classified line if it came from serial, else the pattern's.
+    let failure = &result["failure"];
+    let line = match failure["source"].as_str() {
+        Some("serial") => failure["line_no"].as_u64(),
+        _ => result["reason"]["line_no"].as_u64(),
+    };
+    TestFailure {
+        name: outcome["name"].as_str().unwrap_or("?").to_string(),
+        reason: some(&result["reason"]),
+        failure: some(failure),
+        backtrace: result["backtrace"].as_array().cloned().unwrap_or_default(),
+        serial: (!transcript.is_empty()).then(|| excerpt(transcript, line.map(|l| l as usize))),
+        error: outcome["error"].as_str().map(str::to_string),
+    }
+}
+
+/// The lines of `transcript` around 1-based `line`, else its tail.
+pub fn excerpt(transcript: &str, line: Option<usize>) -> Excerpt {
+    let lines: Vec<&str> = transcript.lines().collect();
+    let (start, end) = match line.filter(|&l| l >= 1 && l <= lines.len()) {
+        Some(line) => (
+            line.saturating_sub(CONTEXT_BEFORE + 1),
+            (line + CONTEXT_AFTER).min(lines.len()),
+        ),
+        None => (lines.len().saturating_sub(TAIL_LINES), lines.len()),
+    };
+    Excerpt {
+        first_line: start + 1,
+        text: lines[start..end].join("\n"),
+    }
+}
+
+/// `kernel/mm/pmm.c:14` (with an optional `:column` or ` (discriminator
+/// 2)`) as a file and line.
+fn location(text: &str) -> Option<(&str, usize)> {
+    let text = text.split(" (").next()?;
+    let mut parts = text.rsplitn(3, ':');
+    let last = parts.next()?;
+    let (file, line) = match (parts.next(), parts.next()) {
+        (Some(line), Some(file)) if line.parse::<usize>().is_ok() => (file, line),
+        (Some(file), _) => (file, last),
+        _ => return None,
+    };
+    Some((file, line.parse().ok().filter(|&l| l > 0)?))
+}
+
+/// Whether `path`, as a tool printed it, is the diff's `file`.
+fn same_file(path: &str, file: &str) -> bool {
+    let path = path.trim_start_matches("./");
+    path == file || path.ends_with(&format!("/{file}"))
+}
+
+/// The hunks some pointer's line falls in, in diff order.
+fn implicated(patches: &[FilePatch], pointers: &[(String, usize, String)]) -> Vec<ImplicatedHunk> {
+    let mut hunks = Vec::new();
+    for patch in patches {
+        let file = patch.path();
+        for hunk in &patch.hunks {
+            let lines = hunk.new_start..hunk.new_start + hunk.new_len.max(1);
+            let mut why: Vec<String> = Vec::new();
+            for (path, line, reason) in pointers {
+                if same_file(path, file) && lines.contains(line) && !why.contains(reason) {
+                    why.push(reason.clone());
+                }
+            }
+            if !why.is_empty() {
+                hunks.push(ImplicatedHunk {
+                    file: file.to_string(),
+                    header: hunk.header.clone(),
+                    text: hunk_text(hunk),
+                    why,
+                });
+            }
+        }
+    }
+    hunks
+}
+
+fn hunk_text(hunk: &Hunk) -> String {
+    let mut text = hunk.header.clone();
+    for line in &hunk.lines {
+        let (marker, line) = match line {
+            HunkLine::Context(l) => (' ', l),
+            HunkLine::Removed(l) => ('-', l),
+            HunkLine::Added(l) => ('+', l),
+        };
+        let _ = write!(text, "\n{marker}{line}");
+    }
+    text
+}
+
+impl Bundle {
+    /// The bundle as Markdown.
+    pub fn markdown(&self) -> String {
+        let mut out = String::new();
+        let _ = match &self.failed_stage {
+            Some(stage) => writeln!(out, "# Run failed at `{stage}`\n"),
+            None if self.passed => writeln!(out, "# Run passed\n"),
+            None => writeln!(out, "# Run failed\n"),
+        };
+        for stage in &self.stages {
+            let _ = writeln!(out, "- {}: {}", stage.name, stage.status.as_str());
+            if !stage.summary.is_empty() {
+                out.pop();
+                let _ = writeln!(out, " — {}", stage.summary);
+            }
+        }
+        if !self.findings.is_empty() {
+            out += "\n## Validation findings\n\n";
+            for f in &self.findings {
+                let _ = writeln!(
+                    out,
+                    "- **{}** `{}:{}` [{}] {}",
+                    f["severity"].as_str().unwrap_or("finding"),
+                    f["file"].as_str().unwrap_or("?"),
+                    f["line"],
+                    f["rule"].as_str().unwrap_or("?"),
+                    f["message"].as_str().unwrap_or("")
+                );
+            }
+        }
+        if !self.diagnostics.is_empty() {
+            out += "\n## Compiler diagnostics\n\n";
+            for d in &self.diagnostics {
+                let at = match (&d.file, d.line, d.column) {
+                    (Some(file), Some(line), Some(column)) => format!(" `{file}:{line}:{column}`"),
+                    (Some(file), Some(line), None) => format!(" `{file}:{line}`"),
+                    (Some(file), None, _) => format!(" `{file}`"),
+                    (None, ..) => String::new(),
+                };
+                let _ = writeln!(out, "- **{}**{at} {}", severity(d), d.message);
+            }
+        }
+        if !self.tests.is_empty() {
+            out += "\n## Test failures\n";
+            for test in &self.tests {
+                markdown_test(&mut out, test);
+            }
+        }
+        if !self.hunks.is_empty() {
+            out += "\n## Implicated diff hunks\n";
+            for hunk in &self.hunks {
+                let _ = writeln!(out, "\n### `{}`\n", hunk.file);
+                for why in &hunk.why {
+                    let _ = writeln!(out, "- {why}");
+                }
+                let _ = writeln!(out, "\n```diff\n{}\n```", hunk.text);
+            }
+        }
+        if !self.log.is_empty() {
+            let _ = writeln!(out, "\n## Tool output\n\n```\n{}\n```", self.log.trim_end());
+        }
+        out
+    }
+}
+
+fn markdown_test(out: &mut String, test: &TestFailure) {
+    let kind = test.reason.as_ref().and_then(|r| r["kind"].as_str());
+    let _ = match kind {
+        Some(kind) => writeln!(out, "\n### `{}`: {kind}\n", test.name),
+        None => writeln!(out, "\n### `{}`\n", test.name),
+    };
+    if let Some(error) = &test.error {
+        let _ = writeln!(out, "Could not run: {error}\n");
+    }
+    if let Some(failure) = &test.failure {
+        let cause = failure["kind"].as_str().unwrap_or("failure");
+        let _ = writeln!(out, "Cause: **{cause}**");
+        if let Some(message) = failure["message"].as_str() {
+            let _ = writeln!(out, "\n> {message}");
+        }
+        for (field, name) in [("rip", "RIP"), ("cr2", "CR2"), ("error_code", "error code")] {
+            if let Some(value) = failure[field].as_u64() {
+                let _ = writeln!(out, "- {name}: {value:#x}");
+            }
+        }
+        out.push('\n');
+    }
+    if !test.backtrace.is_empty() {
+        out.push_str("Backtrace:\n\n```\n");
+        for frame in &test.backtrace {
+            let _ = write!(
+                out,
+                "{:#x} {}+{:#x}",
+                frame["address"].as_u64().unwrap_or(0),
+                frame["function"].as_str().unwrap_or("?"),
+                frame["offset"].as_u64().unwrap_or(0)
+            );
+            if let Some(location) = frame["location"].as_str() {
+                let _ = write!(out, " ({location})");
+            }
+            out.push('\n');
+        }
+        out.push_str("```\n\n");
+    }
+    if let Some(serial) = &test.serial {
+        let _ = writeln!(
+            out,
+            "Serial, from line {}:\n\n```\n{}\n```",
+            serial.first_line, serial.text
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Stage;
+    use serde_json::json;
+
+    const DIFF: &str = "\
+--- a/kernel/mm/pmm.c
++++ b/kernel/mm/pmm.c
+@@ -10,3 +10,4 @@ void pmm_init(void)
+ {
++    x = 1;
+     free = 0;
+ }
+@@ -40,2 +41,3 @@
+ void pmm_free(void)
++{}
+ ;
+";
+
+    fn stage(name: &str, status: Status, report: Value) -> Stage {
+        Stage {
+            status,
+            report: Some(report),
+            ..Stage::skipped(name, "")
+        }
+    }
+
+    fn verdict(stages: Vec<Stage>) -> Verdict {
+        Verdict::new(Some("change.diff".into()), "ws".into(), None, stages)
+    }
+
+    #[test]
+    fn gathers_diagnostics_and_the_hunks_they_point_into() {
+        let stderr = "kernel/mm/pmm.c: In function 'pmm_init':\n\
+                      /tmp/scratch/tree/kernel/mm/pmm.c:11:5: error: 'x' undeclared\n";
+        let stages = vec![
+            stage("validate", Status::Passed, json!([])),
+            stage("apply", Status::Passed, Value::Null),
+            stage(
+                "build",
+                Status::Failed,
+                json!({"success": false, "stderr": stderr}),
+            ),
+            Stage::skipped("test", "the build failed"),
+        ];
+        let patches = diff::parse(DIFF).unwrap();
+        let bundle = bundle(Path::new("verdict.json"), &verdict(stages), &patches);
+        assert_eq!(bundle.failed_stage.as_deref(), Some("build"));
+        assert_eq!(bundle.diagnostics.len(), 1);
+        assert_eq!(bundle.hunks.len(), 1);
+        let hunk = &bundle.hunks[0];
+        assert_eq!(hunk.why, ["error at /tmp/scratch/tree/kernel/mm/pmm.c:11"]);
+        assert!(hunk.text.starts_with("@@ -10,3 +10,4 @@"));
+        assert!(hunk.text.contains("\n+    x = 1;"));
+        assert!(bundle.log.is_empty());
+        let markdown = bundle.markdown();
+        assert!(markdown.starts_with("# Run failed at `build`"));
+        assert!(markdown
+            .contains("- **error** `/tmp/scratch/tree/kernel/mm/pmm.c:11:5` 'x' undeclared"));
+    }
+
+    #[test]
+    fn test_failures_keep_cause_backtrace_and_serial() {
+        let transcript: Vec<String> = (1..=60).map(|n| format!("line {n}")).collect();
+        let outcomes = json!([
+            {"name": "boot", "passed": true},
+            {"name": "alloc", "passed": false, "result": {
+                "reason": {"kind": "panic", "line_no": 50},
+                "failure": {"kind": "panic", "source": "serial", "line_no": 30, "message": "oom"},
+                "backtrace": [{"address": 0x1000, "function": "pmm_free", "offset": 4,
+                               "location": "kernel/mm/pmm.c:42 (discriminator 1)"}],
+                "transcript": transcript.join("\n"),
+            }},
+        ]);
+        let stages = vec![stage("test", Status::Failed, outcomes)];
+        let patches = diff::parse(DIFF).unwrap();
+        let bundle = bundle(Path::new("verdict.json"), &verdict(stages), &patches);
+        assert_eq!(bundle.tests.len(), 1);
+        let test = &bundle.tests[0];
+        let serial = test.serial.as_ref().unwrap();
+        assert_eq!(serial.first_line, 20);
+        assert!(serial.text.starts_with("line 20\n"));
+        assert!(serial.text.ends_with("\nline 50"));
+        assert_eq!(bundle.hunks.len(), 1);
+        assert_eq!(
+            bundle.hunks[0].why,
+            ["alloc backtrace: pmm_free (kernel/mm/pmm.c:42)"]
+        );
+        assert!(bundle.markdown().contains("### `alloc`: panic"));
+
+        let tail = excerpt(&transcript.join("\n"), None);
+        assert_eq!(tail.first_line, 21);
+        assert_eq!(location("a.c:3:7"), Some(("a.c", 3)));
+        assert_eq!(location("??:0"), None);
+    }
+}
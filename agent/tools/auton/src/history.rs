@@ -0,0 +1,494 @@
+//! The run history: every stage a pipeline ran, kept for queries.
+//!
+//! Each finished pipeline appends one [`Run`] per stage it ran to a JSON
+//! Lines file (`<scratch>/history.jsonl` by default), except that a test
+//! stage adds one per test in the suite. Skipped stages are not runs. A
+//! run carries the SHA-256 of what it checked or produced (the diff for
+//! `validate` and `apply`, the image for `build` and `test`), how long it
+//! took and, when it failed, a failure class: the validator rule that
+//! failed, `compile` or `link` for builds, the test's exit reason, or
+//! `tool` when the tool could not run. A test that crashed also carries its
+//! crash signature ([`crate::signature`]).
+//!
+//! A pipeline's runs are appended in one write to a file opened for
+//! appending, so `auton serve`'s jobs can share a history. `auton history`
+//! filters it ([`Filter`]) and adds up failure rates ([`Rate`]).
+
+use crate::signature::Crash;
+use crate::{Stage, Status};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The history's file in a scratch directory.
+pub const HISTORY_NAME: &str = "history.jsonl";
+
+/// One stage, or one test, as it ended.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    /// Seconds since the Unix epoch when its pipeline finished.
+    pub time: u64,
+    pub stage: String,
+    /// The test, for a test stage's runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub test: Option<String>,
+    pub passed: bool,
+    pub duration_ms: u64,
+    pub workspace: PathBuf,
+    pub arch: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diff: Option<PathBuf>,
+    /// SHA-256 of the diff or image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact: Option<String>,
+    /// Why it failed, as a class to count by.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<String>,
+    /// A failed test's crash signature, and the normalized crash it hashes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crash: Option<String>,
+}
+
+impl Run {
+    /// One line for a listing: how long ago, the stage, how long it took,
+    /// whether it passed and what it ran on.
+    pub fn line(&self, now: u64) -> String {
+        let what = match (&self.test, &self.diff) {
+            (Some(test), _) => test.clone(),
+            (None, Some(diff)) => diff.display().to_string(),
+            (None, None) => self.workspace.display().to_string(),
+        };
+        let result = match &self.failure {
+            _ if self.passed => "passed".to_string(),
+            Some(class) => format!("FAILED ({class})"),
+            None => "FAILED".to_string(),
+        };
+        format!(
+            "{:>8}  {:<8}  {:>7.1}s  {result:<20}  {what}",
+            age(now.saturating_sub(self.time)),
+            self.stage,
+            self.duration_ms as f64 / 1000.0
+        )
+    }
+}
+
+/// `45s ago`, `3h ago`, `2d ago`.
+pub fn age(secs: u64) -> String {
+    let (n, unit) = match secs {
+        0..60 => (secs, "s"),
+        60..3600 => (secs / 60, "m"),
+        3600..86_400 => (secs / 3600, "h"),
+        _ => (secs / 86_400, "d"),
+    };
+    format!("{n}{unit} ago")
+}
+
+/// What a pipeline's runs were of.
+#[derive(Debug, Clone, Copy)]
+pub struct Subject<'a> {
+    pub workspace: &'a Path,
+    pub arch: &'a str,
+    pub diff: Option<&'a Path>,
+    /// The image built or tested.
+    pub image: Option<&'a Path>,
+}
+
+/// The runs in `stages`, all at `time`.
+pub fn runs(subject: Subject, stages: &[Stage], time: u64) -> Vec<Run> {
+    let hash = |path: Option<&Path>| {
+        let path = path.filter(|p| p.is_file())?;
+        auton_core::manifest::hash_file(path)
+            .ok()
+            .map(|(_, sha)| sha)
+    };
+    let diff_hash = hash(subject.diff);
+    let image_hash = hash(subject.image);
+    let mut runs = Vec::new();
+    for stage in stages.iter().filter(|s| s.status != Status::Skipped) {
+        let passed = stage.status == Status::Passed;
+        let run = Run {
+            time,
+            stage: stage.name.clone(),
+            test: None,
+            passed,
+            duration_ms: stage.duration_ms,
+            workspace: subject.workspace.to_path_buf(),
+            arch: subject.arch.to_string(),
+            diff: subject.diff.map(Path::to_path_buf),
+            artifact: match stage.name.as_str() {
+                "validate" | "apply" => diff_hash.clone(),
+                _ => image_hash.clone(),
+            },
+            failure: (!passed).then(|| failure(stage)),
+            signature: None,
+            crash: None,
+        };
+        match stage.report.as_ref().and_then(Value::as_array) {
+            Some(outcomes) if stage.name == "test" => {
+                runs.extend(outcomes.iter().map(|outcome| test_run(&run, outcome)))
+            }
+            _ => runs.push(run),
+        }
+    }
+    runs
+}
+
+/// One test of a suite run, from its test-runner outcome.
+fn test_run(suite: &Run, outcome: &Value) -> Run {
+    let passed = outcome["passed"] == true;
+    let failure = if passed {
+        None
+    } else if outcome["error"].is_string() {
+        Some("error".to_string())
+    } else {
+        Some(
+            outcome["result"]["reason"]["kind"]
+                .as_str()
+                .unwrap_or("failed")
+                .to_string(),
+        )
+    };
+    let crash = Crash::of(outcome);
+    Run {
+        test: outcome["name"].as_str().map(str::to_string),
+        passed,
+        duration_ms: outcome["result"]["duration_ms"].as_u64().unwrap_or(0),
+        failure,
+        signature: crash.as_ref().map(|c| c.signature.clone()),
+        crash: crash.map(|c| c.text),
+        ..suite.clone()
+    }
+}
+
+/// The failure class of a failed stage.
+fn failure(stage: &Stage) -> String {
+    let Some(report) = &stage.report else {
+        return if stage.name == "apply" {
+            "apply"
+        } else {
+            "tool"
+        }
+        .to_string();
+    };
+    match stage.name.as_str() {
+        "validate" => {
+            let findings = report
+                .as_array()
+                .or_else(|| report.get("findings")?.as_array());
+            findings
+                .into_iter()
+                .flatten()
+                .find(|f| f["severity"] == "error")
+                .and_then(|f| f["rule"].as_str())
+                .unwrap_or("rejected")
+                .to_string()
+        }
+        "build" => build_failure(report).to_string(),
+        _ => "failed".to_string(),
+    }
+}
+
+/// `compile` or `link` from kernel-builder's report, by its diagnostics
+/// when it parsed them, else by its stderr; `build` for anything else.
+fn build_failure(report: &Value) -> &'static str {
+    let diagnostics = report["diagnostics"].as_array().into_iter().flatten();
+    if let Some(error) = diagnostics.into_iter().find(|d| d["severity"] == "error") {
+        let tool = error["tool"].as_str().unwrap_or_default();
+        let linker = tool == "ld" || tool.ends_with("-ld") || tool.starts_with("ld.");
+        return if linker { "link" } else { "compile" };
+    }
+    let stderr = report["stderr"].as_str().unwrap_or_default();
+    if stderr.contains("undefined reference") || stderr.contains("ld: ") {
+        "link"
+    } else if stderr.contains("error:") {
+        "compile"
+    } else {
+        "build"
+    }
+}
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Append `runs` to the history at `path`.
+pub fn append(path: &Path, runs: &[Run]) -> Result<()> {
+    if runs.is_empty() {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    }
+    let mut text = String::new();
+    for run in runs {
+        text += &serde_json::to_string(run)?;
+        text.push('\n');
+    }
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(text.as_bytes()))
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+/// Every run in the history at `path`, oldest first; none if it does not
+/// exist yet. Lines that do not parse (a write cut short) are skipped with
+/// a warning.
+pub fn load(path: &Path) -> Result<Vec<Run>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    let mut runs = Vec::new();
+    for (n, line) in text
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+    {
+        match serde_json::from_str(line) {
+            Ok(run) => runs.push(run),
+            Err(e) => tracing::warn!("skipping {}:{}: {e}", path.display(), n + 1),
+        }
+    }
+    Ok(runs)
+}
+
+/// Which runs a query is about.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub stage: Option<String>,
+    pub test: Option<String>,
+    /// Only failed runs.
+    pub failed: bool,
+    /// Only runs this recent.
+    pub since: Option<Duration>,
+    /// Only runs that took longer.
+    pub slower_than: Option<Duration>,
+    /// Only the last this many of the runs that match the rest.
+    pub last: Option<usize>,
+}
+
+impl Filter {
+    /// The runs that match, oldest first, as of `now`.
+    pub fn apply<'a>(&self, runs: &'a [Run], now: u64) -> Vec<&'a Run> {
+        let mut matched: Vec<&Run> = runs.iter().filter(|r| self.matches(r, now)).collect();
+        if let Some(last) = self.last {
+            matched.drain(..matched.len().saturating_sub(last));
+        }
+        matched
+    }
+
+    fn matches(&self, run: &Run, now: u64) -> bool {
+        self.stage.as_ref().is_none_or(|s| *s == run.stage)
+            && self
+                .test
+                .as_ref()
+                .is_none_or(|t| run.test.as_ref() == Some(t))
+            && (!self.failed || !run.passed)
+            && self
+                .since
+                .is_none_or(|d| run.time >= now.saturating_sub(d.as_secs()))
+            && self
+                .slower_than
+                .is_none_or(|d| u128::from(run.duration_ms) > d.as_millis())
+    }
+}
+
+/// How often some runs failed, and why.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Rate {
+    pub runs: usize,
+    pub failed: usize,
+    /// Failed runs by failure class.
+    pub failures: BTreeMap<String, usize>,
+}
+
+impl Rate {
+    pub fn of(runs: &[&Run]) -> Self {
+        let mut rate = Self {
+            runs: runs.len(),
+            ..Self::default()
+        };
+        for run in runs.iter().filter(|r| !r.passed) {
+            rate.failed += 1;
+            let class = run.failure.clone().unwrap_or_else(|| "failed".into());
+            *rate.failures.entry(class).or_default() += 1;
+        }
+        rate
+    }
+
+    /// The failed fraction, 0 with no runs.
+    pub fn fraction(&self) -> f64 {
+        if self.runs == 0 {
+            0.0
+        } else {
+            self.failed as f64 / self.runs as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn stage(name: &str, status: Status, report: Option<Value>) -> Stage {
+        Stage {
+            status,
+            duration_ms: 1500,
+            report,
+            ..Stage::skipped(name, "")
+        }
+    }
+
+    #[test]
+    fn stages_become_runs_with_failure_classes() {
+        let stages = [
+            stage(
+                "validate",
+                Status::Failed,
+                Some(json!([{"severity": "warning", "rule": "style"},
+                            {"severity": "error", "rule": "banned-function"}])),
+            ),
+            stage("apply", Status::Passed, None),
+            stage(
+                "build",
+                Status::Failed,
+                Some(
+                    json!({"success": false, "stderr": "main.c:(.text+0x5): undefined reference to `foo'"}),
+                ),
+            ),
+            stage(
+                "test",
+                Status::Failed,
+                Some(json!([
+                    {"name": "boot", "passed": true, "result": {"duration_ms": 800}},
+                    {"name": "net", "passed": false, "result": {"reason": {"kind": "panic"}, "duration_ms": 40}},
+                    {"name": "disk", "passed": false, "error": "no such spec"},
+                ])),
+            ),
+            Stage::skipped("later", "the build failed"),
+        ];
+        let subject = Subject {
+            workspace: Path::new("kernels/x86_64"),
+            arch: "x86_64",
+            diff: Some(Path::new("/nonexistent.diff")),
+            image: None,
+        };
+        let runs = runs(subject, &stages, 100);
+        let got: Vec<_> = runs
+            .iter()
+            .map(|r| {
+                (
+                    r.stage.as_str(),
+                    r.test.as_deref(),
+                    r.duration_ms,
+                    r.failure.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            got,
+            [
+                ("validate", None, 1500, Some("banned-function")),
+                ("apply", None, 1500, None),
+                ("build", None, 1500, Some("link")),
+                ("test", Some("boot"), 800, None),
+                ("test", Some("net"), 40, Some("panic")),
+                ("test", Some("disk"), 0, Some("error")),
+            ]
+        );
+        assert!(runs.iter().all(|r| r.time == 100 && r.artifact.is_none()));
+        let crashes: Vec<_> = runs.iter().map(|r| r.crash.as_deref()).collect();
+        assert_eq!(
+            crashes,
+            [None, None, None, None, Some("panic in net"), None]
+        );
+        assert!(runs[4].signature.is_some());
+
+        let compile = json!({"stderr": "kernel/main.c:3:1: error: expected ';'"});
+        assert_eq!(build_failure(&compile), "compile");
+        let parsed = json!({"diagnostics": [{"tool": "x86_64-elf-ld", "severity": "error"}]});
+        assert_eq!(build_failure(&parsed), "link");
+        assert_eq!(failure(&stage("build", Status::Failed, None)), "tool");
+    }
+
+    #[test]
+    fn history_appends_and_answers_queries() {
+        let path = std::env::temp_dir().join(format!("auton-history-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(load(&path).unwrap(), []);
+
+        let run = |time, passed, duration_ms, failure: Option<&str>| Run {
+            time,
+            stage: "test".into(),
+            test: Some("boot".into()),
+            passed,
+            duration_ms,
+            workspace: "kernels/x86_64".into(),
+            arch: "x86_64".into(),
+            diff: None,
+            artifact: Some("ab12".into()),
+            failure: failure.map(str::to_string),
+            signature: None,
+            crash: None,
+        };
+        let runs = [
+            run(1_000, false, 200_000, Some("timeout")),
+            run(90_000, true, 1_000, None),
+            run(95_000, false, 3_000, Some("panic")),
+            run(99_000, false, 4_000, Some("panic")),
+        ];
+        append(&path, &runs[..2]).unwrap();
+        append(&path, &runs[2..]).unwrap();
+        // A write cut short.
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"time\": 1")
+            .unwrap();
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded, runs);
+
+        let last = Filter {
+            test: Some("boot".into()),
+            last: Some(3),
+            ..Filter::default()
+        };
+        let rate = Rate::of(&last.apply(&loaded, 100_000));
+        assert_eq!((rate.runs, rate.failed), (3, 2));
+        assert_eq!(rate.failures, BTreeMap::from([("panic".into(), 2)]));
+
+        let slow = Filter {
+            stage: Some("test".into()),
+            slower_than: Some(Duration::from_secs(2)),
+            since: Some(Duration::from_secs(10_000)),
+            ..Filter::default()
+        };
+        let times: Vec<u64> = slow
+            .apply(&loaded, 100_000)
+            .iter()
+            .map(|r| r.time)
+            .collect();
+        assert_eq!(times, [95_000, 99_000]);
+        let other = Filter {
+            stage: Some("build".into()),
+            ..Filter::default()
+        };
+        assert_eq!(Rate::of(&other.apply(&loaded, 100_000)).fraction(), 0.0);
+        std::fs::remove_file(path).unwrap();
+    }
+}
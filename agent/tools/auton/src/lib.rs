@@ -0,0 +1,140 @@
+//! auton: the agent tools composed into pipelines.
+//!
+//! Each pipeline runs diff-validator, kernel-builder and test-runner as
+//! stages, reports every stage as it finishes, and adds the stages up to
+//! one [`Verdict`]: `auton verify` runs them all on a diff ([`verify`]),
+//! in a view of the workspace it can commit all at once ([`txn`]);
+//! `auton serve` runs them as jobs for clients over JSON-RPC ([`serve`]),
+//! keeping the jobs on disk across restarts ([`queue`]) and counting what
+//! they did for Prometheus ([`metrics`]). Every run can be
+//! recorded in a history to query later ([`history`]), its crashes told
+//! apart by signature ([`signature`]), and sent as events
+//! to webhooks and pipes ([`notify`]), and `auton bisect`
+//! builds and tests a workspace's history to find the commit that broke a
+//! test ([`bisect`]). `auton feedback` gathers what went wrong in a run
+//! for the agent's next attempt ([`feedback`]), and several agents can
+//! work at once, each in a session of its own ([`session`]). `auton tui`
+//! puts a run as it goes on one screen ([`tui`]), `auton config
+//! validate` checks a workspace's configuration files ([`config`]), and
+//! `auton doctor` the host ([`doctor`]).
+
+pub mod bisect;
+pub mod config;
+pub mod doctor;
+pub mod feedback;
+pub mod history;
+pub mod metrics;
+pub mod notify;
+pub mod queue;
+pub mod serve;
+pub mod session;
+pub mod signature;
+pub mod tui;
+pub mod txn;
+pub mod verify;
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Passed,
+    Failed,
+    /// Not run: an earlier stage failed, or there was nothing to run.
+    Skipped,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Passed => "passed",
+            Status::Failed => "failed",
+            Status::Skipped => "skipped",
+        }
+    }
+}
+
+/// One stage of a pipeline, as it ended.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stage {
+    pub name: String,
+    pub status: Status,
+    pub duration_ms: u64,
+    /// One line on what the stage did or why it failed.
+    pub summary: String,
+    /// The tool's command line.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<String>,
+    /// The tool's own `--json` report.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report: Option<serde_json::Value>,
+    /// The end of the tool's stderr, when it failed.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub log: String,
+}
+
+impl Stage {
+    pub fn skipped(name: &str, why: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            status: Status::Skipped,
+            duration_ms: 0,
+            summary: why.to_string(),
+            command: Vec::new(),
+            report: None,
+            log: String::new(),
+        }
+    }
+
+    /// `passed in 1.2s: <summary>`, or `skipped: <why>`.
+    pub fn outcome(&self) -> String {
+        let status = match self.status {
+            Status::Passed => "passed",
+            Status::Failed => "FAILED",
+            Status::Skipped => "skipped",
+        };
+        match self.status {
+            Status::Skipped => format!("{status}: {}", self.summary),
+            _ => format!(
+                "{status} in {:.1}s: {}",
+                self.duration_ms as f64 / 1000.0,
+                self.summary
+            ),
+        }
+    }
+}
+
+/// A pipeline's combined result: passed unless a stage failed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Verdict {
+    pub passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<PathBuf>,
+    pub workspace: PathBuf,
+    /// The patched copy of the workspace that was built and tested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tree: Option<PathBuf>,
+    pub stages: Vec<Stage>,
+    /// The workspace paths the diff was committed to, if it was.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub committed: Vec<PathBuf>,
+}
+
+impl Verdict {
+    pub fn new(
+        diff: Option<PathBuf>,
+        workspace: PathBuf,
+        tree: Option<PathBuf>,
+        stages: Vec<Stage>,
+    ) -> Self {
+        Self {
+            passed: stages.iter().all(|s| s.status != Status::Failed),
+            diff,
+            workspace,
+            tree,
+            stages,
+            committed: Vec::new(),
+        }
+    }
+}
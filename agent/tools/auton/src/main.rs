@@ -0,0 +1,1086 @@
+//! auton: run the agent tools as one pipeline, or serve them as jobs.
+
+use anyhow::Result;
+use auton::bisect::{self, Bisect, Bisection, Mark, Range, Step};
+use auton::config;
+use auton::doctor;
+use auton::feedback;
+use auton::history::{self, Filter, Rate, Run};
+use auton::metrics;
+use auton::notify::Notifier;
+use auton::serve::{self, JobStatus, Listen, Server};
+use auton::session::{self, Session};
+use auton::signature;
+use auton::tui;
+use auton::verify::{self, plural, Options, Progress, Tools};
+use auton::Verdict;
+use auton_core::store::{self, ArtifactStore};
+use clap::{Args, Parser, Subcommand};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(name = "auton", about = "AUTON agent tool pipelines")]
+struct Cli {
+    #[command(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Validate a diff, apply it to a copy of the workspace, build the copy
+    /// and test the image, with one verdict for all four.
+    Verify(VerifyArgs),
+    /// Serve validate, build, test and verify as JSON-RPC jobs on a Unix
+    /// socket or TCP, with progress notifications and cancellation.
+    Serve(ServeArgs),
+    /// Inspect and cancel a server's jobs.
+    Jobs(JobsArgs),
+    /// Query the run history: past runs, or how often they failed.
+    History(HistoryArgs),
+    /// The distinct crashes in the run history, by signature.
+    Failures(FailuresArgs),
+    /// Build and test commits in a range of the workspace's history to find
+    /// the first one that broke a test.
+    Bisect(BisectArgs),
+    /// Remove artifacts no build or test run references any longer.
+    Gc(GcArgs),
+    /// Gather what went wrong in a run (findings, diagnostics, test
+    /// failures and the diff hunks they point into) for the agent.
+    Feedback(FeedbackArgs),
+    /// Start, list and close agent sessions: worktrees on branches of their
+    /// own, with their own build outputs and cache.
+    Session(SessionArgs),
+    /// Watch a run as it goes: its stages, serial output, build
+    /// diagnostics and the run history, on one screen.
+    Tui(TuiArgs),
+    /// Check a workspace's configuration files.
+    Config(ConfigArgs),
+    /// Check the host for what the tools need: QEMU, KVM, compilers,
+    /// image tools, OVMF, disk space and resource limits.
+    Doctor(DoctorArgs),
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// The unified diff to verify.
+    #[arg(short, long)]
+    diff: PathBuf,
+
+    /// Kernel workspace the diff is against (modified only by --commit).
+    #[arg(short, long, default_value = "kernels/x86_64")]
+    workspace: PathBuf,
+
+    /// Run in this session's worktree, scratch directory and build cache,
+    /// after any other run of it.
+    #[arg(long, value_name = "NAME", conflicts_with = "workspace")]
+    session: Option<String>,
+
+    /// Target architecture.
+    #[arg(short, long, default_value = "x86_64")]
+    arch: String,
+
+    /// Directory of test specs to run against the built image; without
+    /// it the test stage is skipped.
+    #[arg(long, value_name = "DIR")]
+    tests: Option<PathBuf>,
+
+    /// Write the diff to the workspace if every stage passes, all of it
+    /// or none even if auton is killed.
+    #[arg(long)]
+    commit: bool,
+
+    /// Where the patched tree, build, test results and verdict.json go.
+    #[arg(long, value_name = "DIR", default_value = "build/auton")]
+    scratch: PathBuf,
+
+    /// Merge hunks that no longer apply three ways and apply the rebased
+    /// diff.
+    #[arg(long)]
+    merge: bool,
+
+    /// Context lines per hunk end that may differ.
+    #[arg(long, default_value_t = 2)]
+    fuzz: usize,
+
+    /// Build and test the diff even if validation fails.
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Print the commands each stage would run and the files it would
+    /// write, and run none of them.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Directory holding diff-validator, kernel-builder and test-runner
+    /// [default: next to auton, else PATH].
+    #[arg(long, value_name = "DIR")]
+    tools: Option<PathBuf>,
+
+    /// Extra diff-validator argument (repeatable).
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    validate_arg: Vec<String>,
+
+    /// Extra kernel-builder argument (repeatable).
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    build_arg: Vec<String>,
+
+    /// Extra test-runner argument (repeatable).
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    test_arg: Vec<String>,
+
+    /// Run history to add the stages that ran to [default:
+    /// <scratch>/history.jsonl].
+    #[arg(long, value_name = "PATH")]
+    history: Option<PathBuf>,
+
+    /// Record nothing in the run history.
+    #[arg(long, conflicts_with = "history")]
+    no_history: bool,
+
+    /// Event sinks config [default: ./auton-notify.toml if present].
+    #[arg(long, value_name = "FILE")]
+    notify: Option<PathBuf>,
+
+    /// Print the verdict as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Unix socket to listen on.
+    #[arg(long, value_name = "PATH", default_value = "build/auton.sock")]
+    socket: PathBuf,
+
+    /// Listen on TCP instead, e.g. 127.0.0.1:7070 (unauthenticated: keep
+    /// it on loopback).
+    #[arg(long, value_name = "ADDR")]
+    tcp: Option<String>,
+
+    /// Jobs run at once; the rest queue.
+    #[arg(short, long, default_value_t = 1)]
+    jobs: usize,
+
+    /// Default kernel workspace for jobs.
+    #[arg(short, long, default_value = "kernels/x86_64")]
+    workspace: PathBuf,
+
+    /// Default target architecture.
+    #[arg(short, long, default_value = "x86_64")]
+    arch: String,
+
+    /// Default test spec directory.
+    #[arg(long, value_name = "DIR")]
+    tests: Option<PathBuf>,
+
+    /// Jobs' scratch directories go in `<DIR>/jobs/<id>`.
+    #[arg(long, value_name = "DIR", default_value = "build/auton")]
+    scratch: PathBuf,
+
+    /// Default fuzz.
+    #[arg(long, default_value_t = 2)]
+    fuzz: usize,
+
+    /// Directory holding diff-validator, kernel-builder and test-runner
+    /// [default: next to auton, else PATH].
+    #[arg(long, value_name = "DIR")]
+    tools: Option<PathBuf>,
+
+    /// Run jobs that were running when the server last stopped again,
+    /// instead of marking them unknown.
+    #[arg(long)]
+    requeue: bool,
+
+    /// Run history to add the stages that ran to [default:
+    /// <scratch>/history.jsonl].
+    #[arg(long, value_name = "PATH")]
+    history: Option<PathBuf>,
+
+    /// Record nothing in the run history.
+    #[arg(long, conflicts_with = "history")]
+    no_history: bool,
+
+    /// Event sinks config [default: ./auton-notify.toml if present].
+    #[arg(long, value_name = "FILE")]
+    notify: Option<PathBuf>,
+
+    /// Also serve Prometheus metrics at `/metrics` on this TCP address,
+    /// e.g. 127.0.0.1:9464 (unauthenticated: keep it on loopback).
+    #[arg(long, value_name = "ADDR")]
+    metrics: Option<String>,
+}
+
+#[derive(Args)]
+struct JobsArgs {
+    #[command(subcommand)]
+    cmd: JobsCmd,
+
+    /// The server's Unix socket.
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        default_value = "build/auton.sock"
+    )]
+    socket: PathBuf,
+
+    /// The server's TCP address instead.
+    #[arg(long, global = true, value_name = "ADDR")]
+    tcp: Option<String>,
+
+    /// Print the server's JSON.
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+#[derive(Subcommand)]
+enum JobsCmd {
+    /// Every job the server keeps, oldest first.
+    List,
+    /// One job, with its stages.
+    Show { job: u64 },
+    /// Stop a queued or running job.
+    Cancel { job: u64 },
+}
+
+#[derive(Args)]
+struct BisectArgs {
+    /// The test spec that broke.
+    #[arg(long, value_name = "SPEC")]
+    test: PathBuf,
+
+    /// Commits to search, GOOD..BAD: the test passes at GOOD and fails at
+    /// BAD.
+    #[arg(long, value_name = "GOOD..BAD")]
+    range: Range,
+
+    /// Kernel workspace, in a git repository (never modified).
+    #[arg(short, long, default_value = "kernels/x86_64")]
+    workspace: PathBuf,
+
+    /// Target architecture.
+    #[arg(short, long, default_value = "x86_64")]
+    arch: String,
+
+    /// Where each candidate's tree, build and test results go; the build
+    /// cache is kept across candidates.
+    #[arg(long, value_name = "DIR", default_value = "build/auton/bisect")]
+    scratch: PathBuf,
+
+    /// Skip commits that do not build instead of counting them as bad.
+    #[arg(long)]
+    skip_broken: bool,
+
+    /// Directory holding kernel-builder and test-runner [default: next to
+    /// auton, else PATH].
+    #[arg(long, value_name = "DIR")]
+    tools: Option<PathBuf>,
+
+    /// Extra kernel-builder argument (repeatable).
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    build_arg: Vec<String>,
+
+    /// Extra test-runner argument (repeatable).
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    test_arg: Vec<String>,
+
+    /// Print the bisection as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct FeedbackArgs {
+    /// The run: an `auton serve` job id, or a directory holding a
+    /// verdict.json [default: the last `auton verify` in --scratch].
+    #[arg(long, value_name = "ID|DIR")]
+    run: Option<String>,
+
+    /// The scratch directory runs were in.
+    #[arg(long, value_name = "DIR", default_value = "build/auton")]
+    scratch: PathBuf,
+
+    /// Print the bundle as JSON instead of Markdown.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct SessionArgs {
+    #[command(subcommand)]
+    cmd: SessionCmd,
+
+    /// The scratch directory sessions are kept in.
+    #[arg(long, global = true, value_name = "DIR", default_value = "build/auton")]
+    scratch: PathBuf,
+
+    /// Print JSON.
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+#[derive(Subcommand)]
+enum SessionCmd {
+    /// Start a session: a worktree of the workspace's repository on a
+    /// branch no other session has.
+    New {
+        name: String,
+
+        /// Kernel workspace, in a git repository.
+        #[arg(short, long, default_value = "kernels/x86_64")]
+        workspace: PathBuf,
+
+        /// The session's branch [default: auton/<NAME>]; made if it does
+        /// not exist.
+        #[arg(long)]
+        branch: Option<String>,
+
+        /// What a new branch starts from.
+        #[arg(long, value_name = "REV", default_value = "HEAD")]
+        from: String,
+    },
+    /// Every session, and whether a run has it.
+    List,
+    /// Remove a session's worktree, builds and cache; its branch is kept.
+    Close {
+        name: String,
+
+        /// Throw away changes not committed to the branch.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Args)]
+struct TuiArgs {
+    /// The scratch directory the run is in; an `auton serve` job's is
+    /// `<DIR>/jobs/<id>`.
+    #[arg(long, value_name = "DIR", default_value = "build/auton")]
+    scratch: PathBuf,
+
+    /// Watch this session's runs.
+    #[arg(long)]
+    session: Option<String>,
+
+    /// The run history [default: <scratch>/history.jsonl].
+    #[arg(long, value_name = "PATH")]
+    history: Option<PathBuf>,
+
+    /// How often to redraw.
+    #[arg(
+        long,
+        value_name = "TIME",
+        default_value = "1s",
+        value_parser = kernel_builder::clean::parse_duration
+    )]
+    interval: Duration,
+
+    /// Print one screen and exit, without taking over the terminal.
+    #[arg(long)]
+    once: bool,
+
+    /// Print what one screen shows as JSON and exit.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct ConfigArgs {
+    #[command(subcommand)]
+    cmd: ConfigCmd,
+
+    /// Print JSON.
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+#[derive(Subcommand)]
+enum ConfigCmd {
+    /// Read every build config, validator rules file, notification config
+    /// and test spec in the workspace, and report the ones that are wrong.
+    Validate {
+        /// The workspace.
+        #[arg(default_value = ".")]
+        workspace: PathBuf,
+        /// Directory of test specs [default: <workspace>/tests]
+        /// (repeatable).
+        #[arg(long, value_name = "DIR")]
+        tests: Vec<PathBuf>,
+    },
+}
+
+#[derive(Args)]
+struct DoctorArgs {
+    /// Architecture runs are for; missing QEMU or compilers for the
+    /// others only warn (repeatable).
+    #[arg(short, long, default_value = "x86_64")]
+    arch: Vec<String>,
+
+    /// Where builds and test results go, for the disk space check.
+    #[arg(long, value_name = "DIR", default_value = "build/auton")]
+    scratch: PathBuf,
+
+    /// Print the checks as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct GcArgs {
+    /// The artifact store.
+    #[arg(long, value_name = "DIR", default_value = store::DEFAULT_DIR)]
+    store: PathBuf,
+
+    /// Retention: refs this old are dropped, and unreferenced artifacts
+    /// last stored this long ago are removed.
+    #[arg(
+        long,
+        value_name = "AGE",
+        default_value = "30d",
+        value_parser = kernel_builder::clean::parse_duration
+    )]
+    older_than: Duration,
+
+    /// Report what would be removed without removing it.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Print the report as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct HistoryArgs {
+    #[command(subcommand)]
+    cmd: HistoryCmd,
+
+    /// The run history.
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        default_value = "build/auton/history.jsonl"
+    )]
+    history: PathBuf,
+
+    /// Print JSON.
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+#[derive(Subcommand)]
+enum HistoryCmd {
+    /// Runs that match, oldest first.
+    Runs {
+        #[command(flatten)]
+        filter: FilterArgs,
+        /// Only failed runs.
+        #[arg(long)]
+        failed: bool,
+    },
+    /// How often the runs that match failed, by failure class.
+    Rate {
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+}
+
+#[derive(Args)]
+struct FailuresArgs {
+    #[command(subcommand)]
+    cmd: FailuresCmd,
+
+    /// The run history.
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        default_value = "build/auton/history.jsonl"
+    )]
+    history: PathBuf,
+
+    /// Print JSON.
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+#[derive(Subcommand)]
+enum FailuresCmd {
+    /// The crashes the failed runs that match hit, most often hit first.
+    Top {
+        #[command(flatten)]
+        filter: FilterArgs,
+        /// Crashes shown.
+        #[arg(long, value_name = "N", default_value_t = 10)]
+        limit: usize,
+    },
+}
+
+#[derive(Args)]
+struct FilterArgs {
+    /// Only this stage's runs: validate, apply, build or test.
+    #[arg(long)]
+    stage: Option<String>,
+
+    /// Only this test's runs.
+    #[arg(long)]
+    test: Option<String>,
+
+    /// Only runs this recent, e.g. 7d or 12h.
+    #[arg(long, value_name = "AGE", value_parser = kernel_builder::clean::parse_duration)]
+    since: Option<Duration>,
+
+    /// Only runs that took longer, e.g. 2m.
+    #[arg(long, value_name = "TIME", value_parser = kernel_builder::clean::parse_duration)]
+    slower_than: Option<Duration>,
+
+    /// Only the last N of the runs that match the rest.
+    #[arg(long, value_name = "N")]
+    last: Option<usize>,
+}
+
+impl FilterArgs {
+    fn filter(self, failed: bool) -> Filter {
+        Filter {
+            stage: self.stage,
+            test: self.test,
+            failed,
+            since: self.since,
+            slower_than: self.slower_than,
+            last: self.last,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    auton_core::logging::init();
+    let cli = Cli::parse();
+    match cli.cmd {
+        Cmd::Verify(args) => run_verify(args).await,
+        Cmd::Serve(args) => run_serve(args).await,
+        Cmd::Jobs(args) => run_jobs(args).await,
+        Cmd::History(args) => run_history(args),
+        Cmd::Failures(args) => run_failures(args),
+        Cmd::Bisect(args) => run_bisect(args).await,
+        Cmd::Gc(args) => run_gc(args),
+        Cmd::Feedback(args) => run_feedback(args),
+        Cmd::Session(args) => run_session(args).await,
+        Cmd::Tui(args) => run_tui(args),
+        Cmd::Config(args) => run_config(args),
+        Cmd::Doctor(args) => run_doctor(args).await,
+    }
+}
+
+async fn run_verify(args: VerifyArgs) -> Result<()> {
+    let session = args
+        .session
+        .as_deref()
+        .map(|name| Session::load(&session::root(&args.scratch), name))
+        .transpose()?;
+    let mut opts = Options {
+        diff: args.diff,
+        workspace: args.workspace,
+        arch: args.arch,
+        tests: args.tests,
+        merge: args.merge,
+        fuzz: args.fuzz,
+        keep_going: args.keep_going,
+        tools: tools(args.tools.as_deref()),
+        validate_args: args.validate_arg,
+        build_args: args.build_arg,
+        test_args: args.test_arg,
+        history: history_path(&args.scratch, args.history, args.no_history),
+        notify: Notifier::find(args.notify.as_deref())?,
+        commit: args.commit,
+        scratch: args.scratch,
+    };
+    if let Some(session) = &session {
+        session.apply(&mut opts);
+        opts.scratch = session.scratch();
+    }
+    if args.dry_run {
+        let plan = verify::plan(&opts).await?;
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&plan)?);
+        } else {
+            print!("{}", plan.render());
+        }
+        return Ok(());
+    }
+    let _lock = match &session {
+        Some(session) => {
+            if session.busy() {
+                eprintln!("waiting for session `{}`", session.name);
+            }
+            Some(session.lock().await?)
+        }
+        None => None,
+    };
+    let verdict = verify::verify(&opts, &mut report_progress).await?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&verdict)?);
+    } else {
+        print_verdict(&verdict);
+    }
+    if !verdict.passed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+async fn run_serve(args: ServeArgs) -> Result<()> {
+    let base = Options {
+        diff: PathBuf::new(),
+        workspace: args.workspace,
+        arch: args.arch,
+        tests: args.tests,
+        merge: false,
+        fuzz: args.fuzz,
+        keep_going: false,
+        tools: tools(args.tools.as_deref()),
+        validate_args: Vec::new(),
+        build_args: Vec::new(),
+        test_args: Vec::new(),
+        history: history_path(&args.scratch, args.history, args.no_history),
+        notify: Notifier::find(args.notify.as_deref())?,
+        commit: false,
+        scratch: args.scratch,
+    };
+    let server = Server::new(base, args.jobs);
+    let recovery = server.recover(args.requeue)?;
+    if !recovery.requeued.is_empty() || !recovery.unknown.is_empty() {
+        tracing::info!(requeued = ?recovery.requeued, unknown = ?recovery.unknown, "recovered jobs");
+    }
+    let listen = listen(args.socket, args.tcp);
+    match args.metrics {
+        Some(addr) => tokio::select! {
+            served = serve::serve(Arc::clone(&server), &listen) => served,
+            metrics = metrics::serve(server, &addr) => metrics,
+        },
+        None => serve::serve(server, &listen).await,
+    }
+}
+
+async fn run_bisect(args: BisectArgs) -> Result<()> {
+    let opts = Options {
+        diff: PathBuf::new(),
+        workspace: args.workspace,
+        arch: args.arch,
+        tests: None,
+        scratch: args.scratch,
+        merge: false,
+        fuzz: 0,
+        keep_going: false,
+        tools: tools(args.tools.as_deref()),
+        validate_args: Vec::new(),
+        build_args: args.build_arg,
+        test_args: args.test_arg,
+        history: None,
+        notify: None,
+        commit: false,
+    };
+    let what = Bisect {
+        range: args.range,
+        spec: args.test,
+        skip_broken: args.skip_broken,
+    };
+    let bisection = bisect::bisect(&opts, &what, &mut report_step).await?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&bisection)?);
+    } else {
+        print_bisection(&bisection);
+    }
+    Ok(())
+}
+
+/// Each candidate on stderr as it is marked.
+fn report_step(step: &Step) {
+    let mark = match step.mark {
+        Mark::Good => "good",
+        Mark::Bad => "bad",
+        Mark::Skip => "skip",
+    };
+    eprintln!(
+        "{} {mark:<4}  {}",
+        short(&step.commit.sha),
+        step.commit.subject
+    );
+    for stage in &step.stages {
+        eprintln!("    {:<8} {}", stage.name, stage.outcome());
+    }
+}
+
+fn short(sha: &str) -> &str {
+    &sha[..sha.len().min(12)]
+}
+
+fn print_bisection(bisection: &Bisection) {
+    println!(
+        "tried {} of {} commits",
+        bisection.steps.len(),
+        bisection.commits
+    );
+    match &bisection.first_bad {
+        Some(commit) => println!("first bad commit: {} {}", commit.sha, commit.subject),
+        None => {
+            println!("skipped commits hide the first bad one; it is one of:");
+            for commit in &bisection.suspects {
+                println!("    {} {}", commit.sha, commit.subject);
+            }
+        }
+    }
+}
+
+fn history_path(scratch: &Path, path: Option<PathBuf>, off: bool) -> Option<PathBuf> {
+    (!off).then(|| path.unwrap_or_else(|| scratch.join(history::HISTORY_NAME)))
+}
+
+fn listen(socket: PathBuf, tcp: Option<String>) -> Listen {
+    match tcp {
+        Some(addr) => Listen::Tcp(addr),
+        None => Listen::Unix(socket),
+    }
+}
+
+async fn run_jobs(args: JobsArgs) -> Result<()> {
+    let (method, params) = match args.cmd {
+        JobsCmd::List => ("jobs", Value::Null),
+        JobsCmd::Show { job } => ("status", json!({ "job": job })),
+        JobsCmd::Cancel { job } => ("cancel", json!({ "job": job })),
+    };
+    let result = serve::request(&listen(args.socket, args.tcp), method, params).await?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+        return Ok(());
+    }
+    if method == "jobs" {
+        let jobs: Vec<JobStatus> = serde_json::from_value(result)?;
+        for job in &jobs {
+            print_job_line(job);
+        }
+    } else {
+        let job: JobStatus = serde_json::from_value(result)?;
+        print_job_line(&job);
+        for stage in &job.stages {
+            println!("    {:<8} {}", stage.name, stage.outcome());
+            for line in stage.log.lines() {
+                println!("        {line}");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_history(args: HistoryArgs) -> Result<()> {
+    let runs = history::load(&args.history)?;
+    let now = history::now();
+    match args.cmd {
+        HistoryCmd::Runs { filter, failed } => {
+            let matched = filter.filter(failed).apply(&runs, now);
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&matched)?);
+            } else {
+                for run in matched {
+                    print_run(run, now);
+                }
+            }
+        }
+        HistoryCmd::Rate { filter } => {
+            let rate = Rate::of(&filter.filter(false).apply(&runs, now));
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&rate)?);
+            } else {
+                let classes: Vec<String> = rate
+                    .failures
+                    .iter()
+                    .map(|(class, n)| format!("{class} {n}"))
+                    .collect();
+                println!(
+                    "{} of {} runs failed ({:.1}%){}",
+                    rate.failed,
+                    rate.runs,
+                    rate.fraction() * 100.0,
+                    if classes.is_empty() {
+                        String::new()
+                    } else {
+                        format!(": {}", classes.join(", "))
+                    }
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_failures(args: FailuresArgs) -> Result<()> {
+    let runs = history::load(&args.history)?;
+    let now = history::now();
+    match args.cmd {
+        FailuresCmd::Top { filter, limit } => {
+            let mut clusters = signature::clusters(&filter.filter(true).apply(&runs, now));
+            clusters.truncate(limit);
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&clusters)?);
+            } else if clusters.is_empty() {
+                println!("no crashes recorded in {}", args.history.display());
+            } else {
+                println!(
+                    "{:>6}  {:>5}  {:>10}  {:<16}  crash",
+                    "runs", "tests", "last", "signature"
+                );
+                for c in &clusters {
+                    println!(
+                        "{:>6}  {:>5}  {:>10}  {}  {}",
+                        c.runs,
+                        c.tests.len(),
+                        history::age(now.saturating_sub(c.last)),
+                        c.signature,
+                        c.crash
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_gc(args: GcArgs) -> Result<()> {
+    let report = ArtifactStore::new(&args.store).gc(args.older_than, args.dry_run)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    println!(
+        "{} {} ({} bytes) and {}; kept {} ({} bytes)",
+        if args.dry_run {
+            "would remove"
+        } else {
+            "removed"
+        },
+        plural(report.objects_removed, "artifact"),
+        report.bytes_removed,
+        plural(report.refs_removed, "ref"),
+        plural(report.objects_kept, "artifact"),
+        report.bytes_kept
+    );
+    Ok(())
+}
+
+fn run_feedback(args: FeedbackArgs) -> Result<()> {
+    let path = feedback::verdict_path(&args.scratch, args.run.as_deref());
+    let bundle = feedback::load(&path)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&bundle)?);
+    } else {
+        print!("{}", bundle.markdown());
+    }
+    Ok(())
+}
+
+async fn run_session(args: SessionArgs) -> Result<()> {
+    let root = session::root(&args.scratch);
+    match args.cmd {
+        SessionCmd::New {
+            name,
+            workspace,
+            branch,
+            from,
+        } => {
+            let session =
+                Session::open(&root, &name, &workspace, branch.as_deref(), Some(&from)).await?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&session_json(&session))?);
+            } else {
+                println!(
+                    "session `{}` on `{}` in {}",
+                    session.name,
+                    session.branch,
+                    session.workspace().display()
+                );
+            }
+        }
+        SessionCmd::List => {
+            let sessions = Session::list(&root)?;
+            if args.json {
+                let list: Vec<Value> = sessions.iter().map(session_json).collect();
+                println!("{}", serde_json::to_string_pretty(&list)?);
+            } else {
+                for session in &sessions {
+                    println!(
+                        "{:<16} {:<24} {}{}",
+                        session.name,
+                        session.branch,
+                        session.workspace().display(),
+                        if session.busy() { "  (running)" } else { "" }
+                    );
+                }
+            }
+        }
+        SessionCmd::Close { name, force } => {
+            Session::load(&root, &name)?.close(force).await?;
+            if !args.json {
+                println!("closed session `{name}`");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_config(args: ConfigArgs) -> Result<()> {
+    match args.cmd {
+        ConfigCmd::Validate {
+            workspace,
+            mut tests,
+        } => {
+            if tests.is_empty() {
+                tests.push(workspace.join("tests"));
+            }
+            let checked = config::validate(&workspace, &tests);
+            let invalid = checked.iter().filter(|c| c.error.is_some()).count();
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&checked)?);
+            } else if checked.is_empty() {
+                println!("no configuration files in {}", workspace.display());
+            } else {
+                for c in &checked {
+                    match &c.error {
+                        None => println!("ok     {} ({})", c.path.display(), c.kind.name()),
+                        Some(e) => println!("error  {e}"),
+                    }
+                }
+                println!(
+                    "{} checked, {invalid} invalid",
+                    plural(checked.len(), "file")
+                );
+            }
+            if invalid > 0 {
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_doctor(args: DoctorArgs) -> Result<()> {
+    let opts = doctor::Options {
+        arches: args.arch,
+        scratch: args.scratch,
+    };
+    let checks = doctor::run(&opts).await?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&checks)?);
+    } else {
+        let width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+        for c in &checks {
+            println!("{}  {:width$}  {}", c.status.as_str(), c.name, c.detail);
+            for line in c.hint.iter().flat_map(|h| h.lines()) {
+                println!("      {:width$}  {}", "", line.trim());
+            }
+        }
+        println!("{}", doctor::summary(&checks));
+    }
+    if checks.iter().any(|c| c.status == doctor::Status::Fail) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_tui(args: TuiArgs) -> Result<()> {
+    let scratch = match &args.session {
+        Some(name) => Session::load(&session::root(&args.scratch), name)?.scratch(),
+        None => args.scratch.clone(),
+    };
+    let sources = tui::Sources {
+        scratch,
+        history: history_path(&args.scratch, args.history, false),
+    };
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&tui::Snapshot::read(&sources))?
+        );
+    } else if args.once {
+        tui::print_once(&sources);
+    } else {
+        tui::run(&sources, args.interval.max(Duration::from_millis(100)))?;
+    }
+    Ok(())
+}
+
+fn session_json(session: &Session) -> Value {
+    json!({
+        "name": session.name,
+        "branch": session.branch,
+        "workspace": session.workspace(),
+        "scratch": session.scratch(),
+        "cache": session.cache(),
+        "created": session.created,
+        "busy": session.busy(),
+    })
+}
+
+fn print_run(run: &Run, now: u64) {
+    println!("{}", run.line(now));
+}
+
+fn print_job_line(job: &JobStatus) {
+    let state = serde_json::to_value(job.state).unwrap_or_default();
+    let operation = serde_json::to_value(job.operation).unwrap_or_default();
+    let detail = match (&job.error, job.stages.last()) {
+        (Some(error), _) => error.clone(),
+        (None, Some(stage)) => format!("{}: {}", stage.name, stage.summary),
+        (None, None) => String::new(),
+    };
+    println!(
+        "{:>4}  {:<8}  {:<9}  {detail}",
+        job.job,
+        operation.as_str().unwrap_or_default(),
+        state.as_str().unwrap_or_default()
+    );
+}
+
+fn tools(dir: Option<&Path>) -> Tools {
+    dir.map_or_else(Tools::find, Tools::in_dir)
+}
+
+/// Stage progress on stderr, so `--json` output stays parseable.
+fn report_progress(progress: Progress) {
+    let step = |index: usize, total: usize, name: &str| format!("[{}/{total}] {name}", index + 1);
+    match progress {
+        Progress::Started { index, total, name } => eprintln!("{} ...", step(index, total, name)),
+        Progress::Finished {
+            index,
+            total,
+            stage,
+        } => {
+            eprintln!("{} {}", step(index, total, &stage.name), stage.outcome());
+            for line in stage.log.lines() {
+                eprintln!("    {line}");
+            }
+        }
+    }
+}
+
+fn print_verdict(verdict: &Verdict) {
+    for stage in &verdict.stages {
+        println!("{:<8} {}", stage.name, stage.outcome());
+    }
+    let what = verdict.diff.as_ref().unwrap_or(&verdict.workspace);
+    println!(
+        "{}: {}",
+        what.display(),
+        if verdict.passed { "PASS" } else { "FAIL" }
+    );
+    if !verdict.committed.is_empty() {
+        println!(
+            "committed {} to {}",
+            verify::plural(verdict.committed.len(), "file"),
+            verdict.workspace.display()
+        );
+    }
+}
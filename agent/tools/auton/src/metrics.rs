@@ -0,0 +1,350 @@
+//! `auton serve --metrics ADDR`: Prometheus metrics over HTTP at
+//! `/metrics`.
+//!
+//! Counted from the stages jobs finish, as they finish, since the server
+//! started:
+//!
+//! - `auton_builds_total{result}`: builds that passed or failed
+//!   (`rate(auton_builds_total[1h]) * 3600` is builds per hour);
+//! - `auton_build_cache_hits_total`, `auton_build_cache_misses_total`:
+//!   kernel-builder's object cache, with `auton_build_cache_hit_ratio`
+//!   over both;
+//! - `auton_tests_total{result, accel}`: tests that passed, failed or could
+//!   not run, by the accelerator they asked for;
+//! - `auton_qemu_boot_seconds{accel}`: a histogram of how long each test's
+//!   QEMU run took. A jump in it, or `tcg` where there was `kvm`, shows a
+//!   host that lost KVM;
+//! - `auton_jobs_finished_total{operation, state}`, and the gauge
+//!   `auton_jobs{state}` of jobs queued and running (the queue depth).
+//!
+//! The endpoint speaks just enough HTTP/1.1 for a scraper: `GET /metrics`,
+//! anything else is a 404. Like the JSON-RPC listener it has no
+//! authentication, so keep it on loopback or a trusted network.
+
+use crate::serve::{JobStatus, Operation, Server, State};
+use crate::{Stage, Status};
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Upper bounds of the boot-time histogram's buckets, in seconds.
+pub const BOOT_BUCKETS: [f64; 10] = [0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0];
+
+/// Request head read before giving up on a client.
+const MAX_REQUEST: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Histogram {
+    /// Observations per bucket of [`BOOT_BUCKETS`], then above the last.
+    counts: [u64; BOOT_BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let bucket = BOOT_BUCKETS
+            .iter()
+            .position(|&le| value <= le)
+            .unwrap_or(BOOT_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    builds: BTreeMap<&'static str, u64>,
+    cache_hits: u64,
+    cache_misses: u64,
+    /// By (result, accel).
+    tests: BTreeMap<(&'static str, String), u64>,
+    boots: BTreeMap<String, Histogram>,
+    /// By (operation, state).
+    jobs: BTreeMap<(&'static str, &'static str), u64>,
+}
+
+/// Counters and histograms, shared by the jobs that update them.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counts: Mutex<Counts>,
+}
+
+impl Metrics {
+    /// Count a stage a job finished.
+    pub fn stage(&self, stage: &Stage) {
+        let mut counts = self.counts.lock().unwrap();
+        match stage.name.as_str() {
+            "build" if stage.status != Status::Skipped => {
+                *counts.builds.entry(stage.status.as_str()).or_default() += 1;
+                if let Some(cache) = stage.report.as_ref().map(|r| &r["cache"]) {
+                    counts.cache_hits += cache["hits"].as_u64().unwrap_or(0);
+                    counts.cache_misses += cache["misses"].as_u64().unwrap_or(0);
+                }
+            }
+            "test" => {
+                let outcomes = stage.report.as_ref().and_then(Value::as_array);
+                for outcome in outcomes.into_iter().flatten() {
+                    let run = &outcome["result"];
+                    let accel = run["accel"].as_str().unwrap_or("unknown").to_string();
+                    let result = match (outcome["passed"].as_bool(), run.is_null()) {
+                        (_, true) => "error",
+                        (Some(true), _) => "passed",
+                        _ => "failed",
+                    };
+                    *counts.tests.entry((result, accel.clone())).or_default() += 1;
+                    if let Some(ms) = run["duration_ms"].as_u64() {
+                        let boots = counts.boots.entry(accel).or_default();
+                        boots.observe(ms as f64 / 1000.0);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Count a job that ended as `state`.
+    pub fn job(&self, operation: Operation, state: State) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts
+            .jobs
+            .entry((operation_name(operation), state_name(state)))
+            .or_default() += 1;
+    }
+
+    /// Everything in the Prometheus text format, with `jobs` for the queue
+    /// gauges.
+    pub fn render(&self, jobs: &[JobStatus]) -> String {
+        let counts = self.counts.lock().unwrap();
+        let mut out = String::new();
+        header(
+            &mut out,
+            "auton_builds_total",
+            "counter",
+            "Builds finished.",
+        );
+        for (result, n) in &counts.builds {
+            let _ = writeln!(out, "auton_builds_total{{result=\"{result}\"}} {n}");
+        }
+        header(
+            &mut out,
+            "auton_build_cache_hits_total",
+            "counter",
+            "Objects kernel-builder took from its cache.",
+        );
+        let _ = writeln!(out, "auton_build_cache_hits_total {}", counts.cache_hits);
+        header(
+            &mut out,
+            "auton_build_cache_misses_total",
+            "counter",
+            "Objects kernel-builder had to compile.",
+        );
+        let _ = writeln!(
+            out,
+            "auton_build_cache_misses_total {}",
+            counts.cache_misses
+        );
+        header(
+            &mut out,
+            "auton_build_cache_hit_ratio",
+            "gauge",
+            "Cache hits over all cache lookups.",
+        );
+        let lookups = counts.cache_hits + counts.cache_misses;
+        let ratio = if lookups == 0 {
+            0.0
+        } else {
+            counts.cache_hits as f64 / lookups as f64
+        };
+        let _ = writeln!(out, "auton_build_cache_hit_ratio {ratio}");
+
+        header(&mut out, "auton_tests_total", "counter", "Tests run.");
+        for ((result, accel), n) in &counts.tests {
+            let _ = writeln!(
+                out,
+                "auton_tests_total{{result=\"{result}\",accel=\"{}\"}} {n}",
+                escape(accel)
+            );
+        }
+        header(
+            &mut out,
+            "auton_qemu_boot_seconds",
+            "histogram",
+            "How long each test's QEMU run took.",
+        );
+        for (accel, histogram) in &counts.boots {
+            let accel = escape(accel);
+            let mut cumulative = 0;
+            for (le, n) in BOOT_BUCKETS.iter().zip(&histogram.counts) {
+                cumulative += n;
+                let _ = writeln!(
+                    out,
+                    "auton_qemu_boot_seconds_bucket{{accel=\"{accel}\",le=\"{le}\"}} {cumulative}"
+                );
+            }
+            cumulative += histogram.counts[BOOT_BUCKETS.len()];
+            let _ = writeln!(
+                out,
+                "auton_qemu_boot_seconds_bucket{{accel=\"{accel}\",le=\"+Inf\"}} {cumulative}"
+            );
+            let _ = writeln!(
+                out,
+                "auton_qemu_boot_seconds_sum{{accel=\"{accel}\"}} {}",
+                histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "auton_qemu_boot_seconds_count{{accel=\"{accel}\"}} {cumulative}"
+            );
+        }
+
+        header(
+            &mut out,
+            "auton_jobs_finished_total",
+            "counter",
+            "Jobs ended, by how.",
+        );
+        for ((operation, state), n) in &counts.jobs {
+            let _ = writeln!(
+                out,
+                "auton_jobs_finished_total{{operation=\"{operation}\",state=\"{state}\"}} {n}"
+            );
+        }
+        header(&mut out, "auton_jobs", "gauge", "Jobs waiting or running.");
+        for state in [State::Queued, State::Running] {
+            let n = jobs.iter().filter(|j| j.state == state).count();
+            let _ = writeln!(out, "auton_jobs{{state=\"{}\"}} {n}", state_name(state));
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// A label value, escaped.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+fn operation_name(operation: Operation) -> &'static str {
+    match operation {
+        Operation::Verify => "verify",
+        Operation::Validate => "validate",
+        Operation::Build => "build",
+        Operation::Test => "test",
+    }
+}
+
+fn state_name(state: State) -> &'static str {
+    match state {
+        State::Queued => "queued",
+        State::Running => "running",
+        State::Passed => "passed",
+        State::Failed => "failed",
+        State::Cancelled => "cancelled",
+        State::Error => "error",
+        State::Unknown => "unknown",
+    }
+}
+
+/// Serve `server`'s metrics on `addr` until the process is stopped.
+pub async fn serve(server: Arc<Server>, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("listening on {addr}"))?;
+    tracing::info!(addr = %listener.local_addr()?, "serving metrics");
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(scrape(Arc::clone(&server), stream));
+    }
+}
+
+/// Answer one HTTP request.
+async fn scrape(server: Arc<Server>, mut stream: TcpStream) {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => head.extend_from_slice(&buf[..n]),
+        }
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut words = head.lines().next().unwrap_or("").split_whitespace();
+    let (method, path) = (words.next(), words.next());
+    let path = path.map(|p| p.split('?').next().unwrap_or(p));
+    let (status, kind, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            server.metrics.render(&server.list()),
+        ),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {kind}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn stage(name: &str, status: Status, report: Value) -> Stage {
+        Stage {
+            report: Some(report),
+            status,
+            ..Stage::skipped(name, "")
+        }
+    }
+
+    #[test]
+    fn counts_builds_tests_and_boot_times() {
+        let metrics = Metrics::default();
+        let build = json!({"success": true, "cache": {"hits": 3, "misses": 1, "rebuilt": 1}});
+        metrics.stage(&stage("build", Status::Passed, build));
+        metrics.stage(&stage("build", Status::Failed, json!({"success": false})));
+        metrics.stage(&Stage::skipped("build", "validation failed"));
+        let tests = json!([
+            {"passed": true, "result": {"duration_ms": 800, "accel": "kvm"}},
+            {"passed": false, "result": {"duration_ms": 45_000, "accel": "tcg"}},
+            {"passed": false, "error": "no QEMU"},
+        ]);
+        metrics.stage(&stage("test", Status::Failed, tests));
+        metrics.job(Operation::Verify, State::Failed);
+
+        let text = metrics.render(&[]);
+        for line in [
+            "auton_builds_total{result=\"passed\"} 1",
+            "auton_builds_total{result=\"failed\"} 1",
+            "auton_build_cache_hit_ratio 0.75",
+            "auton_tests_total{result=\"passed\",accel=\"kvm\"} 1",
+            "auton_tests_total{result=\"failed\",accel=\"tcg\"} 1",
+            "auton_tests_total{result=\"error\",accel=\"unknown\"} 1",
+            "auton_qemu_boot_seconds_bucket{accel=\"kvm\",le=\"0.5\"} 0",
+            "auton_qemu_boot_seconds_bucket{accel=\"kvm\",le=\"1\"} 1",
+            "auton_qemu_boot_seconds_bucket{accel=\"tcg\",le=\"30\"} 0",
+            "auton_qemu_boot_seconds_bucket{accel=\"tcg\",le=\"60\"} 1",
+            "auton_qemu_boot_seconds_bucket{accel=\"tcg\",le=\"+Inf\"} 1",
+            "auton_qemu_boot_seconds_sum{accel=\"tcg\"} 45",
+            "auton_jobs_finished_total{operation=\"verify\",state=\"failed\"} 1",
+            "auton_jobs{state=\"queued\"} 0",
+        ] {
+            assert!(text.lines().any(|l| l == line), "no `{line}` in\n{text}");
+        }
+        assert!(text.contains("# TYPE auton_qemu_boot_seconds histogram"));
+    }
+}
@@ -0,0 +1,426 @@
+//! Pipeline events, pushed to webhooks and named pipes as they happen.
+//!
+//! When a pipeline finishes, each stage it ran becomes an [`Event`], one
+//! per test for a test stage, followed by a `pipeline-finished` event for
+//! the verdict. An event is the run the history would record
+//! ([`history::Run`]) plus its kind and a delivery id, as one JSON object.
+//!
+//! Where events go is `auton-notify.toml` (`--notify <path>`, else
+//! `./auton-notify.toml`):
+//!
+//! ```toml
+//! [[webhook]]
+//! url = "http://127.0.0.1:8080/auton"
+//! events = ["build-finished", "test-failed", "validation-rejected"]
+//! secret-env = "AUTON_WEBHOOK_SECRET"
+//! retries = 3
+//!
+//! [[pipe]]
+//! path = "build/auton/events"
+//! ```
+//!
+//! A webhook gets one `POST` per event, signed when it has a secret:
+//! `X-Auton-Signature: sha256=<hex>` is the HMAC-SHA256 of the body under
+//! the secret, read from the named environment variable so it stays out of
+//! the file. Failed deliveries (no connection, timeouts, 5xx, 408 and 429)
+//! are retried with doubling backoff; other 4xx are not. Only `http://` is
+//! supported: there is no TLS here, so put a relay in front of anything
+//! remote. A pipe gets each event as a JSON line; it is created as a FIFO
+//! if missing, and a FIFO no one is reading drops the events (with a
+//! warning) instead of stalling the pipeline. `events` filters either; left
+//! out, every event is sent. Delivery never fails a pipeline.
+
+use crate::history::Run;
+use crate::Verdict;
+use anyhow::{bail, Context, Result};
+use auton_core::http::{self, HttpUrl};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+pub const CONFIG_NAME: &str = "auton-notify.toml";
+
+pub const DEFAULT_RETRIES: u32 = 3;
+pub const DEFAULT_BACKOFF_MS: u64 = 500;
+pub const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+    ValidationPassed,
+    ValidationRejected,
+    PatchApplied,
+    PatchRejected,
+    /// Passed or not; `passed` says.
+    BuildFinished,
+    TestPassed,
+    TestFailed,
+    /// The verdict: `stage` is the pipeline, `failure` its first failed
+    /// stage.
+    PipelineFinished,
+}
+
+impl Kind {
+    fn name(self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    /// Unique per event, so receivers can drop retried duplicates.
+    pub id: String,
+    pub event: Kind,
+    #[serde(flatten)]
+    pub run: Run,
+}
+
+/// The events for `pipeline`'s `runs`, then its verdict.
+pub fn events(pipeline: &str, runs: &[Run], verdict: &Verdict, time: u64) -> Vec<Event> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let event = |event, run: Run| Event {
+        id: format!(
+            "{time}-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ),
+        event,
+        run,
+    };
+    let mut events: Vec<Event> = runs
+        .iter()
+        .filter_map(|run| {
+            let kind = match (run.stage.as_str(), run.passed) {
+                ("validate", true) => Kind::ValidationPassed,
+                ("validate", false) => Kind::ValidationRejected,
+                ("apply", true) => Kind::PatchApplied,
+                ("apply", false) => Kind::PatchRejected,
+                ("build", _) => Kind::BuildFinished,
+                ("test", true) => Kind::TestPassed,
+                ("test", false) => Kind::TestFailed,
+                _ => return None,
+            };
+            Some(event(kind, run.clone()))
+        })
+        .collect();
+    let finished = Run {
+        time,
+        stage: pipeline.to_string(),
+        test: None,
+        passed: verdict.passed,
+        duration_ms: verdict.stages.iter().map(|s| s.duration_ms).sum(),
+        workspace: verdict.workspace.clone(),
+        arch: runs.first().map(|r| r.arch.clone()).unwrap_or_default(),
+        diff: verdict.diff.clone(),
+        artifact: None,
+        signature: None,
+        crash: None,
+        failure: verdict
+            .stages
+            .iter()
+            .find(|s| s.status == crate::Status::Failed)
+            .map(|s| s.name.clone()),
+    };
+    events.push(event(Kind::PipelineFinished, finished));
+    events
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct NotifyConfig {
+    pub webhook: Vec<WebhookConfig>,
+    pub pipe: Vec<PipeConfig>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Only these events [default: all].
+    pub events: Vec<Kind>,
+    /// Environment variable holding the HMAC secret.
+    pub secret_env: Option<String>,
+    /// Attempts after the first [default: 3].
+    pub retries: Option<u32>,
+    /// Wait before the first retry, doubling after each [default: 500].
+    pub backoff_ms: Option<u64>,
+    /// Per attempt [default: 10].
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PipeConfig {
+    pub path: PathBuf,
+    /// Only these events [default: all].
+    pub events: Vec<Kind>,
+}
+
+impl NotifyConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        auton_toml::from_file(path)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Webhook {
+    config: WebhookConfig,
+    url: HttpUrl,
+    secret: Option<Vec<u8>>,
+}
+
+/// Sends events where a [`NotifyConfig`] says.
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    webhooks: Vec<Webhook>,
+    pipes: Vec<PipeConfig>,
+}
+
+impl Notifier {
+    /// Check the URLs and read the secrets.
+    pub fn new(config: NotifyConfig) -> Result<Self> {
+        let mut webhooks = Vec::new();
+        for hook in config.webhook {
+            let url = HttpUrl::parse(&hook.url)?;
+            let secret = match &hook.secret_env {
+                Some(var) => Some(
+                    std::env::var(var)
+                        .with_context(|| format!("{}: secret ${var} is not set", hook.url))?
+                        .into_bytes(),
+                ),
+                None => None,
+            };
+            webhooks.push(Webhook {
+                config: hook,
+                url,
+                secret,
+            });
+        }
+        Ok(Self {
+            webhooks,
+            pipes: config.pipe,
+        })
+    }
+
+    /// The notifier `--notify <explicit>` or `./auton-notify.toml` sets up,
+    /// if either exists; an explicit path must.
+    pub fn find(explicit: Option<&Path>) -> Result<Option<Self>> {
+        let path = match explicit {
+            Some(path) => path,
+            None if Path::new(CONFIG_NAME).is_file() => Path::new(CONFIG_NAME),
+            None => return Ok(None),
+        };
+        Self::new(NotifyConfig::load(path)?)
+            .with_context(|| format!("in {}", path.display()))
+            .map(Some)
+    }
+
+    /// Deliver `events` to every sink that wants them, webhooks in
+    /// parallel; failures are logged, not returned.
+    pub async fn send(&self, events: &[Event]) {
+        let wanted =
+            |filter: &[Kind], event: &Event| filter.is_empty() || filter.contains(&event.event);
+        for pipe in &self.pipes {
+            let lines: Vec<&Event> = events.iter().filter(|e| wanted(&pipe.events, e)).collect();
+            if let Err(e) = write_pipe(&pipe.path, &lines) {
+                tracing::warn!("events not written: {e:#}");
+            }
+        }
+        let mut deliveries = tokio::task::JoinSet::new();
+        for hook in &self.webhooks {
+            let hook = hook.clone();
+            let events: Vec<Event> = events
+                .iter()
+                .filter(|e| wanted(&hook.config.events, e))
+                .cloned()
+                .collect();
+            deliveries.spawn(async move {
+                for event in &events {
+                    if let Err(e) = deliver(&hook, event).await {
+                        tracing::warn!(url = %hook.config.url, id = %event.id, "event not delivered: {e:#}");
+                    }
+                }
+            });
+        }
+        while deliveries.join_next().await.is_some() {}
+    }
+}
+
+/// What `secret` signs `body` as: the value of `X-Auton-Signature`.
+pub fn signature(secret: &[u8], body: &[u8]) -> String {
+    let mac = auton_core::hash::hmac_sha256(secret, body);
+    format!("sha256={}", auton_core::hash::hex(&mac))
+}
+
+/// POST `event` to `hook`, retrying as configured.
+async fn deliver(hook: &Webhook, event: &Event) -> Result<()> {
+    let body = serde_json::to_vec(event)?;
+    let config = &hook.config;
+    let retries = config.retries.unwrap_or(DEFAULT_RETRIES);
+    let mut backoff = Duration::from_millis(config.backoff_ms.unwrap_or(DEFAULT_BACKOFF_MS));
+    let timeout = Duration::from_secs(config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS));
+    let mut headers = vec![
+        ("X-Auton-Event", event.event.name()),
+        ("X-Auton-Delivery", event.id.clone()),
+    ];
+    if let Some(secret) = &hook.secret {
+        headers.push(("X-Auton-Signature", signature(secret, &body)));
+    }
+    let mut attempt = 0;
+    loop {
+        let why = match tokio::time::timeout(timeout, http::post(&hook.url, &headers, &body)).await
+        {
+            Ok(Ok(status)) if (200..300).contains(&status) => return Ok(()),
+            Ok(Ok(status)) if (400..500).contains(&status) && status != 408 && status != 429 => {
+                bail!("rejected with HTTP {status}")
+            }
+            Ok(Ok(status)) => format!("HTTP {status}"),
+            Ok(Err(e)) => format!("{e:#}"),
+            Err(_) => format!("no response in {}s", timeout.as_secs()),
+        };
+        if attempt >= retries {
+            bail!("{why}, after {} attempts", attempt + 1);
+        }
+        tracing::debug!(url = %config.url, "delivery failed ({why}); retrying in {backoff:?}");
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// Write `events` to `path` as JSON lines, making it a FIFO if it does not
+/// exist.
+fn write_pipe(path: &Path, events: &[&Event]) -> Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    if !path.exists() {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        let c_path = std::ffi::CString::new(path.as_os_str().as_encoded_bytes())?;
+        // SAFETY: `c_path` is a valid NUL-terminated string.
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() != std::io::ErrorKind::AlreadyExists {
+                return Err(e).with_context(|| format!("creating {}", path.display()));
+            }
+        }
+    }
+    let fifo = std::fs::metadata(path).is_ok_and(|m| m.file_type().is_fifo());
+    let file = std::fs::OpenOptions::new()
+        .append(true)
+        .custom_flags(if fifo { libc::O_NONBLOCK } else { 0 })
+        .open(path);
+    let mut file = match file {
+        Ok(file) => file,
+        Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+            bail!("no one is reading {}", path.display())
+        }
+        Err(e) => return Err(e).with_context(|| format!("opening {}", path.display())),
+    };
+    for event in events {
+        // One write per line, so lines up to PIPE_BUF arrive whole.
+        let line = serde_json::to_string(event)? + "\n";
+        file.write_all(line.as_bytes())
+            .with_context(|| format!("writing {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Stage, Status};
+
+    #[test]
+    fn runs_become_events_then_the_verdict() {
+        let run = |stage: &str, test: Option<&str>, passed| Run {
+            time: 7,
+            stage: stage.into(),
+            test: test.map(str::to_string),
+            passed,
+            duration_ms: 10,
+            workspace: "ws".into(),
+            arch: "x86_64".into(),
+            diff: None,
+            artifact: None,
+            failure: None,
+            signature: None,
+            crash: None,
+        };
+        let runs = [
+            run("validate", None, true),
+            run("build", None, true),
+            run("test", Some("boot"), true),
+            run("test", Some("net"), false),
+        ];
+        let stages = vec![
+            Stage {
+                status: Status::Passed,
+                ..Stage::skipped("build", "")
+            },
+            Stage {
+                status: Status::Failed,
+                duration_ms: 5,
+                ..Stage::skipped("test", "")
+            },
+        ];
+        let verdict = Verdict::new(None, "ws".into(), None, stages);
+        let events = events("verify", &runs, &verdict, 7);
+        let kinds: Vec<Kind> = events.iter().map(|e| e.event).collect();
+        assert_eq!(
+            kinds,
+            [
+                Kind::ValidationPassed,
+                Kind::BuildFinished,
+                Kind::TestPassed,
+                Kind::TestFailed,
+                Kind::PipelineFinished
+            ]
+        );
+        let last = &events[4];
+        assert_eq!(
+            (last.run.stage.as_str(), last.run.passed),
+            ("verify", false)
+        );
+        assert_eq!(last.run.failure.as_deref(), Some("test"));
+        assert_ne!(events[0].id, events[1].id);
+
+        let json = serde_json::to_value(&events[3]).unwrap();
+        assert_eq!(json["event"], "test-failed");
+        assert_eq!(json["test"], "net");
+    }
+
+    #[test]
+    fn config_and_urls_are_checked() {
+        let config: NotifyConfig = auton_toml::from_str(
+            "[[webhook]]\nurl = \"http://127.0.0.1:8080/hook\"\nevents = [\"test-failed\"]\n\n\
+             [[pipe]]\npath = \"events\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.webhook[0].events, [Kind::TestFailed]);
+        assert_eq!(config.pipe[0].path, PathBuf::from("events"));
+        assert!(auton_toml::from_str::<NotifyConfig>("[[webhook]]\nurll = \"x\"\n").is_err());
+        assert!(
+            auton_toml::from_str::<NotifyConfig>("[[pipe]]\nevents = [\"tests-failed\"]\n")
+                .is_err()
+        );
+
+        assert!(Notifier::new(NotifyConfig {
+            webhook: vec![WebhookConfig {
+                url: "http://localhost/".into(),
+                secret_env: Some("AUTON_TEST_UNSET_SECRET".into()),
+                ..WebhookConfig::default()
+            }],
+            pipe: Vec::new(),
+        })
+        .is_err());
+    }
+}
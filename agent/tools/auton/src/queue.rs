@@ -0,0 +1,103 @@
+//! `auton serve`'s jobs on disk, so the queue survives a restart or crash.
+//!
+//! Each job is a [`Record`] in `<scratch>/jobs/<id>/job.json`, rewritten
+//! whenever the job's state changes or a stage finishes. On startup the
+//! server reads them back ([`Server::recover`](crate::serve::Server::recover)):
+//! ended jobs are kept as they are, queued ones are queued again, and one
+//! that was running when the server stopped is marked `unknown`, or queued
+//! again with `--requeue`. Only one server may use a scratch directory.
+
+use crate::serve::{JobParams, JobStatus};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A job's file in its scratch directory.
+pub const RECORD_NAME: &str = "job.json";
+
+/// What is kept of a job: enough to report it, or to run it again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub status: JobStatus,
+    pub params: JobParams,
+}
+
+impl Record {
+    /// Write the record to `path`, replacing it whole.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("writing {}", path.display()))
+    }
+
+    /// Every record under `jobs`, the jobs' parent directory, by id; none
+    /// if it does not exist yet. Unreadable records are skipped with a
+    /// warning.
+    pub fn load_all(jobs: &Path) -> Result<Vec<Record>> {
+        let entries = match std::fs::read_dir(jobs) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", jobs.display())),
+        };
+        let mut records = Vec::new();
+        for entry in entries {
+            let path = entry?.path().join(RECORD_NAME);
+            if !path.is_file() {
+                continue;
+            }
+            let record = std::fs::read_to_string(&path)
+                .with_context(|| format!("reading {}", path.display()))
+                .and_then(|text| {
+                    serde_json::from_str(&text)
+                        .with_context(|| format!("parsing {}", path.display()))
+                });
+            match record {
+                Ok(record) => records.push(record),
+                Err(e) => tracing::warn!("skipping job: {e:#}"),
+            }
+        }
+        records.sort_by_key(|r: &Record| r.status.job);
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serve::{Operation, State};
+
+    #[test]
+    fn records_round_trip_and_load_in_id_order() {
+        let dir = std::env::temp_dir().join(format!("auton-queue-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(Record::load_all(&dir).unwrap(), []);
+
+        let record = |job| Record {
+            status: JobStatus {
+                job,
+                operation: Operation::Build,
+                state: State::Queued,
+                stages: Vec::new(),
+                verdict: None,
+                error: None,
+            },
+            params: JobParams {
+                arch: Some("riscv64".into()),
+                ..JobParams::default()
+            },
+        };
+        for job in [10, 9] {
+            record(job)
+                .save(&dir.join(job.to_string()).join(RECORD_NAME))
+                .unwrap();
+        }
+        std::fs::create_dir_all(dir.join("11")).unwrap();
+        std::fs::write(dir.join("11").join(RECORD_NAME), "{").unwrap();
+        assert_eq!(Record::load_all(&dir).unwrap(), [record(9), record(10)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,756 @@
+//! `auton serve`: the pipelines as a long-running JSON-RPC 2.0 service, so
+//! the agent controller does not start a process per request.
+//!
+//! Requests, responses and notifications are JSON objects, one per line,
+//! over a Unix socket or TCP. There is no authentication: keep TCP on
+//! loopback. Every operation runs as a background job with its own scratch
+//! directory, `<scratch>/jobs/<id>`, at most `jobs` of them at a time:
+//!
+//! - `verify`, `validate`, `build`, `test`: start a job with
+//!   [`JobParams`], answered with `{"job": <id>}`. `verify` and `validate`
+//!   need a `diff`; `test` needs a `kernel` and `tests`. A job with a
+//!   `session` runs on that [session]'s workspace and build cache, once the
+//!   session's other runs are done;
+//! - `status` `{"job": <id>}`: the job's [`JobStatus`], with the stages it
+//!   has finished so far;
+//! - `wait` `{"job": <id>}`: the same, answered once the job has ended;
+//! - `cancel` `{"job": <id>}`: stop the job, killing the tool it is running;
+//! - `jobs`: every job's status.
+//!
+//! The connection that started a job is sent `progress` notifications as
+//! its stages start and finish, then `done` with its final status. A job
+//! outlives its connection, and is kept on disk so it outlives the server
+//! too ([`crate::queue`]); the last [`KEPT_JOBS`] ended jobs are kept,
+//! older ones are forgotten and their scratch directories removed.
+//! [`request`] is a client, for `auton jobs`. With `--metrics` the server
+//! also counts its builds, tests and jobs for Prometheus ([`crate::metrics`]).
+//!
+//! [session]: crate::session
+
+use crate::metrics::Metrics;
+use crate::queue::{Record, RECORD_NAME};
+use crate::session::{self, Session};
+use crate::verify::{self, Options, Progress};
+use crate::{Stage, Verdict};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, watch, Semaphore};
+use tokio::task::AbortHandle;
+
+/// Ended jobs whose status is kept.
+pub const KEPT_JOBS: usize = 100;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The `job` named is unknown, or was forgotten.
+pub const NO_SUCH_JOB: i64 = -32001;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Verify,
+    Validate,
+    Build,
+    Test,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    /// Waiting for a free job slot.
+    Queued,
+    Running,
+    Passed,
+    Failed,
+    Cancelled,
+    /// The job could not run at all (its scratch directory, say).
+    Error,
+    /// The server stopped while the job ran, so how it ended is unknown.
+    Unknown,
+}
+
+impl State {
+    pub fn ended(self) -> bool {
+        !matches!(self, State::Queued | State::Running)
+    }
+}
+
+/// What a job runs on; unset fields take the server's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobParams {
+    pub diff: Option<PathBuf>,
+    pub workspace: Option<PathBuf>,
+    pub arch: Option<String>,
+    pub tests: Option<PathBuf>,
+    /// The image `test` boots.
+    pub kernel: Option<PathBuf>,
+    pub merge: bool,
+    pub fuzz: Option<usize>,
+    pub keep_going: bool,
+    pub validate_args: Vec<String>,
+    pub build_args: Vec<String>,
+    pub test_args: Vec<String>,
+    /// The [`crate::session`] to run in, in place of `workspace`.
+    pub session: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub job: u64,
+    pub operation: Operation,
+    pub state: State,
+    /// Finished stages, in order.
+    pub stages: Vec<Stage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verdict: Option<Verdict>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// The jobs [`Server::recover`] took up, by id.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recovery {
+    pub requeued: Vec<u64>,
+    /// Jobs that were running, now [`State::Unknown`].
+    pub unknown: Vec<u64>,
+}
+
+/// Where a connection's responses and notifications go, one line each.
+pub type Outbox = mpsc::UnboundedSender<String>;
+
+struct Job {
+    status: JobStatus,
+    params: JobParams,
+    /// Where the job's [`Record`] is kept.
+    record: PathBuf,
+    abort: Option<AbortHandle>,
+    ended: watch::Sender<bool>,
+    /// The connection that started the job.
+    outbox: Outbox,
+}
+
+impl Job {
+    fn save(&self) {
+        let record = Record {
+            status: self.status.clone(),
+            params: self.params.clone(),
+        };
+        if let Err(e) = record.save(&self.record) {
+            tracing::warn!("saving job {}: {e:#}", self.status.job);
+        }
+    }
+
+    fn notify(&self, method: &str, params: Value) {
+        let message = json!({"jsonrpc": "2.0", "method": method, "params": params});
+        let _ = self.outbox.send(message.to_string());
+    }
+
+    fn progress(&mut self, progress: Progress) {
+        let (index, total, event, name, stage) = match progress {
+            Progress::Started { index, total, name } => (index, total, "started", name, None),
+            Progress::Finished {
+                index,
+                total,
+                stage,
+            } => {
+                self.status.stages.push(stage.clone());
+                self.save();
+                (index, total, "finished", stage.name.as_str(), Some(stage))
+            }
+        };
+        let mut params = json!({
+            "job": self.status.job,
+            "event": event,
+            "index": index,
+            "total": total,
+            "name": name,
+        });
+        if let Some(stage) = stage {
+            params["stage"] = json!(stage);
+        }
+        self.notify("progress", params);
+    }
+
+    /// End the job as `state`, unless it already has; whether it did.
+    fn end(&mut self, state: State) -> bool {
+        if self.status.state.ended() {
+            return false;
+        }
+        self.status.state = state;
+        self.abort = None;
+        self.save();
+        self.notify("done", json!(self.status));
+        self.ended.send_replace(true);
+        true
+    }
+}
+
+#[derive(Default)]
+struct Jobs {
+    next: u64,
+    jobs: BTreeMap<u64, Job>,
+}
+
+pub struct Server {
+    /// Defaults for every job; `scratch` is the root of the jobs' own.
+    base: Options,
+    slots: Arc<Semaphore>,
+    jobs: Mutex<Jobs>,
+    pub metrics: Metrics,
+}
+
+impl Server {
+    /// A server running at most `jobs` jobs at a time.
+    pub fn new(base: Options, jobs: usize) -> Arc<Self> {
+        Arc::new(Self {
+            base,
+            slots: Arc::new(Semaphore::new(jobs.max(1))),
+            jobs: Mutex::new(Jobs::default()),
+            metrics: Metrics::default(),
+        })
+    }
+
+    fn with_job<T>(&self, id: u64, f: impl FnOnce(&mut Job) -> T) -> Result<T, RpcError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs
+            .jobs
+            .get_mut(&id)
+            .ok_or_else(|| RpcError::new(NO_SUCH_JOB, format!("no job {id}")))?;
+        Ok(f(job))
+    }
+
+    fn options(&self, id: u64, params: JobParams) -> Options {
+        let base = &self.base;
+        Options {
+            diff: params.diff.unwrap_or_default(),
+            workspace: params.workspace.unwrap_or_else(|| base.workspace.clone()),
+            arch: params.arch.unwrap_or_else(|| base.arch.clone()),
+            tests: params.tests.or_else(|| base.tests.clone()),
+            scratch: self.scratch(id),
+            merge: params.merge,
+            fuzz: params.fuzz.unwrap_or(base.fuzz),
+            keep_going: params.keep_going,
+            tools: base.tools.clone(),
+            validate_args: params.validate_args,
+            build_args: params.build_args,
+            test_args: params.test_args,
+            history: base.history.clone(),
+            notify: base.notify.clone(),
+            commit: false,
+        }
+    }
+
+    /// Start `operation` in the background; its id.
+    pub fn start(
+        self: &Arc<Self>,
+        operation: Operation,
+        params: JobParams,
+        outbox: Outbox,
+    ) -> Result<u64, RpcError> {
+        let missing = |what: &str| {
+            RpcError::new(
+                INVALID_PARAMS,
+                format!("`{what}` is required for this operation"),
+            )
+        };
+        match operation {
+            Operation::Verify | Operation::Validate if params.diff.is_none() => {
+                return Err(missing("diff"))
+            }
+            Operation::Test if params.kernel.is_none() => return Err(missing("kernel")),
+            Operation::Test if params.tests.is_none() && self.base.tests.is_none() => {
+                return Err(missing("tests"))
+            }
+            _ => {}
+        }
+        if let Some(name) = &params.session {
+            if params.workspace.is_some() {
+                let both = "give a `session` or a `workspace`, not both";
+                return Err(RpcError::new(INVALID_PARAMS, both));
+            }
+            self.session(name)
+                .map_err(|e| RpcError::new(INVALID_PARAMS, format!("{e:#}")))?;
+        }
+        let id = {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.next += 1;
+            let id = jobs.next;
+            let status = JobStatus {
+                job: id,
+                operation,
+                state: State::Queued,
+                stages: Vec::new(),
+                verdict: None,
+                error: None,
+            };
+            let job = self.job(status, params, outbox);
+            job.save();
+            jobs.jobs.insert(id, job);
+            self.forget_old(&mut jobs);
+            id
+        };
+        self.spawn(id);
+        Ok(id)
+    }
+
+    fn scratch(&self, id: u64) -> PathBuf {
+        self.base.scratch.join("jobs").join(id.to_string())
+    }
+
+    fn session(&self, name: &str) -> Result<Session> {
+        Session::load(&session::root(&self.base.scratch), name)
+    }
+
+    fn job(&self, status: JobStatus, params: JobParams, outbox: Outbox) -> Job {
+        Job {
+            record: self.scratch(status.job).join(RECORD_NAME),
+            ended: watch::channel(status.state.ended()).0,
+            status,
+            params,
+            abort: None,
+            outbox,
+        }
+    }
+
+    /// Run queued job `id` in the background.
+    fn spawn(self: &Arc<Self>, id: u64) {
+        let Ok((operation, params)) =
+            self.with_job(id, |job| (job.status.operation, job.params.clone()))
+        else {
+            return;
+        };
+        let kernel = params.kernel.clone().unwrap_or_default();
+        let session = params.session.as_deref().map(|name| self.session(name));
+        let mut opts = self.options(id, params);
+        if let Some(Ok(session)) = &session {
+            session.apply(&mut opts);
+        }
+
+        let server = Arc::clone(self);
+        let task = tokio::spawn(async move {
+            // Waiting for the session does not take a slot from other jobs.
+            let locked = match &session {
+                Some(Ok(session)) => session.lock().await.map(Some),
+                Some(Err(e)) => Err(anyhow::anyhow!("{e:#}")),
+                None => Ok(None),
+            };
+            let _slot = server.slots.clone().acquire_owned().await;
+            let _ = server.with_job(id, |job| {
+                job.status.state = State::Running;
+                job.save();
+            });
+            let progress_server = Arc::clone(&server);
+            let mut progress = move |p: Progress| {
+                if let Progress::Finished { stage, .. } = p {
+                    progress_server.metrics.stage(stage);
+                }
+                let _ = progress_server.with_job(id, |job| job.progress(p));
+            };
+            let result = match locked {
+                Err(e) => Err(e),
+                Ok(_lock) => match operation {
+                    Operation::Verify => verify::verify(&opts, &mut progress).await,
+                    Operation::Validate => verify::validate(&opts, &mut progress).await,
+                    Operation::Build => verify::build(&opts, &mut progress).await,
+                    Operation::Test => verify::test(&opts, &kernel, &mut progress).await,
+                },
+            };
+            let _ = server.with_job(id, |job| match result {
+                Ok(verdict) => {
+                    let passed = verdict.passed;
+                    job.status.verdict = Some(verdict);
+                    server.end(job, if passed { State::Passed } else { State::Failed });
+                }
+                Err(e) => {
+                    job.status.error = Some(format!("{e:#}"));
+                    server.end(job, State::Error);
+                }
+            });
+        });
+        // A job that has already ended has nothing left to abort.
+        let _ = self.with_job(id, |job| {
+            if !job.status.state.ended() {
+                job.abort = Some(task.abort_handle());
+            }
+        });
+    }
+
+    /// Take up the jobs a previous server left in the scratch directory
+    /// (see [`crate::queue`]): queued jobs run again, and so do ones that
+    /// were running if `requeue`, else they end as unknown.
+    pub fn recover(self: &Arc<Self>, requeue: bool) -> Result<Recovery> {
+        let records = Record::load_all(&self.base.scratch.join("jobs"))?;
+        let mut recovery = Recovery::default();
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            for Record { mut status, params } in records {
+                let id = status.job;
+                jobs.next = jobs.next.max(id);
+                let run_again = match status.state {
+                    State::Queued => true,
+                    State::Running if requeue => true,
+                    State::Running => {
+                        status.state = State::Unknown;
+                        status.error = Some("the server stopped while the job was running".into());
+                        recovery.unknown.push(id);
+                        false
+                    }
+                    _ => false,
+                };
+                if run_again {
+                    status.state = State::Queued;
+                    status.stages.clear();
+                    recovery.requeued.push(id);
+                }
+                // Nobody is listening for the job's notifications any more.
+                let job = self.job(status, params, mpsc::unbounded_channel().0);
+                job.save();
+                jobs.jobs.insert(id, job);
+            }
+            self.forget_old(&mut jobs);
+        }
+        for id in &recovery.requeued {
+            self.spawn(*id);
+        }
+        Ok(recovery)
+    }
+
+    /// Drop the oldest ended jobs beyond [`KEPT_JOBS`], and their scratch
+    /// directories.
+    fn forget_old(&self, jobs: &mut Jobs) {
+        let ended: Vec<u64> = jobs
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.status.state.ended())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &ended[..ended.len().saturating_sub(KEPT_JOBS)] {
+            jobs.jobs.remove(id);
+            let scratch = self.scratch(*id);
+            if let Err(e) = std::fs::remove_dir_all(&scratch) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("removing {}: {e}", scratch.display());
+                }
+            }
+        }
+    }
+
+    pub fn status(&self, id: u64) -> Result<JobStatus, RpcError> {
+        self.with_job(id, |job| job.status.clone())
+    }
+
+    /// The status once the job has ended.
+    pub async fn wait(&self, id: u64) -> Result<JobStatus, RpcError> {
+        let mut ended = self.with_job(id, |job| job.ended.subscribe())?;
+        let _ = ended.wait_for(|ended| *ended).await;
+        self.status(id)
+    }
+
+    /// End `job` as `state`, counting it if it had not already ended.
+    fn end(&self, job: &mut Job, state: State) {
+        if job.end(state) {
+            self.metrics.job(job.status.operation, state);
+        }
+    }
+
+    /// Stop the job; its status, which a job that had already ended keeps.
+    pub fn cancel(&self, id: u64) -> Result<JobStatus, RpcError> {
+        self.with_job(id, |job| {
+            if let Some(abort) = job.abort.take() {
+                abort.abort();
+            }
+            self.end(job, State::Cancelled);
+            job.status.clone()
+        })
+    }
+
+    pub fn list(&self) -> Vec<JobStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.jobs.values().map(|job| job.status.clone()).collect()
+    }
+
+    /// Answer one request line. Requests are answered in the order they
+    /// arrive, except that `wait` is answered through `outbox` once its job
+    /// ends; a notification, which has no id, gets no response.
+    pub fn handle(self: &Arc<Self>, line: &str, outbox: &Outbox) -> Option<Value> {
+        let (id, result) = match serde_json::from_str::<Value>(line) {
+            Err(e) => (
+                Value::Null,
+                Err(RpcError::new(PARSE_ERROR, format!("parse error: {e}"))),
+            ),
+            Ok(request) => {
+                let id = request.get("id").cloned();
+                match self.call(request, outbox) {
+                    Ok(Answer::Later(job)) => {
+                        let (server, outbox) = (Arc::clone(self), outbox.clone());
+                        tokio::spawn(async move {
+                            let status = server.wait(job).await.map(|s| json!(s));
+                            if let Some(id) = id {
+                                let _ = outbox.send(response(id, status).to_string());
+                            }
+                        });
+                        return None;
+                    }
+                    Ok(Answer::Now(result)) => (id?, Ok(result)),
+                    Err(error) => (id?, Err(error)),
+                }
+            }
+        };
+        Some(response(id, result))
+    }
+
+    fn call(self: &Arc<Self>, request: Value, outbox: &Outbox) -> Result<Answer, RpcError> {
+        if request["jsonrpc"] != "2.0" {
+            return Err(RpcError::new(INVALID_REQUEST, "not a JSON-RPC 2.0 request"));
+        }
+        let Some(method) = request["method"].as_str() else {
+            return Err(RpcError::new(INVALID_REQUEST, "no method"));
+        };
+        let params = match &request["params"] {
+            Value::Null => json!({}),
+            params => params.clone(),
+        };
+        let job = || -> Result<u64, RpcError> {
+            params["job"]
+                .as_u64()
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, "`job` must be a job id"))
+        };
+        let operation = match method {
+            "verify" => Operation::Verify,
+            "validate" => Operation::Validate,
+            "build" => Operation::Build,
+            "test" => Operation::Test,
+            "status" => return Ok(Answer::Now(json!(self.status(job()?)?))),
+            "wait" => return Ok(Answer::Later(job()?)),
+            "cancel" => return Ok(Answer::Now(json!(self.cancel(job()?)?))),
+            "jobs" => return Ok(Answer::Now(json!(self.list()))),
+            _ => {
+                return Err(RpcError::new(
+                    METHOD_NOT_FOUND,
+                    format!("no method `{method}`"),
+                ))
+            }
+        };
+        let params: JobParams = serde_json::from_value(params)
+            .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?;
+        let id = self.start(operation, params, outbox.clone())?;
+        Ok(Answer::Now(json!({ "job": id })))
+    }
+}
+
+enum Answer {
+    Now(Value),
+    /// The job's status once it ends.
+    Later(u64),
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(error) => json!({"jsonrpc": "2.0", "id": id, "error": error}),
+    }
+}
+
+/// Where the server listens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listen {
+    Unix(PathBuf),
+    /// `host:port`.
+    Tcp(String),
+}
+
+/// Accept connections until the process is stopped.
+pub async fn serve(server: Arc<Server>, listen: &Listen) -> Result<()> {
+    match listen {
+        Listen::Unix(path) => {
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("creating {}", dir.display()))?;
+            }
+            // A socket left by an earlier server.
+            let _ = std::fs::remove_file(path);
+            let listener = tokio::net::UnixListener::bind(path)
+                .with_context(|| format!("listening on {}", path.display()))?;
+            tracing::info!(socket = %path.display(), "serving");
+            loop {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(connection(Arc::clone(&server), stream));
+            }
+        }
+        Listen::Tcp(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("listening on {addr}"))?;
+            tracing::info!(addr = %listener.local_addr()?, "serving");
+            loop {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(connection(Arc::clone(&server), stream));
+            }
+        }
+    }
+}
+
+/// Serve one client until it disconnects.
+pub async fn connection(server: Arc<Server>, stream: impl AsyncRead + AsyncWrite + Send + 'static) {
+    let (read, mut write) = tokio::io::split(stream);
+    let (outbox, mut messages) = mpsc::unbounded_channel::<String>();
+    tokio::spawn(async move {
+        while let Some(message) = messages.recv().await {
+            if write.write_all((message + "\n").as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = server.handle(&line, &outbox) {
+            let _ = outbox.send(response.to_string());
+        }
+    }
+}
+
+/// Send one request to the server at `listen`; its result.
+pub async fn request(listen: &Listen, method: &str, params: Value) -> Result<Value> {
+    let running = "is `auton serve` running?";
+    match listen {
+        Listen::Unix(path) => {
+            let stream = tokio::net::UnixStream::connect(path)
+                .await
+                .with_context(|| format!("connecting to {} ({running})", path.display()))?;
+            exchange(stream, method, params).await
+        }
+        Listen::Tcp(addr) => {
+            let stream = tokio::net::TcpStream::connect(addr)
+                .await
+                .with_context(|| format!("connecting to {addr} ({running})"))?;
+            exchange(stream, method, params).await
+        }
+    }
+}
+
+async fn exchange(
+    stream: impl AsyncRead + AsyncWrite,
+    method: &str,
+    params: Value,
+) -> Result<Value> {
+    let (read, mut write) = tokio::io::split(stream);
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+    write.write_all(format!("{request}\n").as_bytes()).await?;
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let message: Value =
+            serde_json::from_str(&line).context("parsing the server's response")?;
+        // Notifications of the server's jobs are not ours.
+        if message["id"] != 1 {
+            continue;
+        }
+        if let Some(error) = message.get("error") {
+            bail!(
+                "{} (error {})",
+                error["message"].as_str().unwrap_or("no message"),
+                error["code"]
+            );
+        }
+        return Ok(message["result"].clone());
+    }
+    bail!("the server closed the connection without answering")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::Tools;
+    use std::path::Path;
+
+    fn server() -> Arc<Server> {
+        let opts = Options {
+            diff: PathBuf::new(),
+            workspace: PathBuf::from("kernels/x86_64"),
+            arch: "x86_64".into(),
+            tests: None,
+            scratch: std::env::temp_dir().join(format!("auton-serve-{}", std::process::id())),
+            merge: false,
+            fuzz: 2,
+            keep_going: false,
+            tools: Tools::in_dir(Path::new("/nonexistent")),
+            validate_args: Vec::new(),
+            build_args: Vec::new(),
+            test_args: Vec::new(),
+            history: None,
+            notify: None,
+            commit: false,
+        };
+        Server::new(opts, 1)
+    }
+
+    async fn error(server: &Arc<Server>, line: &str) -> (Value, i64) {
+        let (outbox, _) = mpsc::unbounded_channel();
+        let response = server.handle(line, &outbox).unwrap();
+        (
+            response["id"].clone(),
+            response["error"]["code"].as_i64().unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn bad_requests_get_json_rpc_errors() {
+        let server = server();
+        assert_eq!(error(&server, "{").await, (Value::Null, PARSE_ERROR));
+        assert_eq!(
+            error(&server, r#"{"id": 1, "method": "jobs"}"#).await,
+            (json!(1), INVALID_REQUEST)
+        );
+        assert_eq!(
+            error(
+                &server,
+                r#"{"jsonrpc": "2.0", "id": 2, "method": "deploy"}"#
+            )
+            .await,
+            (json!(2), METHOD_NOT_FOUND)
+        );
+        assert_eq!(
+            error(
+                &server,
+                r#"{"jsonrpc": "2.0", "id": 3, "method": "verify"}"#
+            )
+            .await,
+            (json!(3), INVALID_PARAMS)
+        );
+        let unknown = r#"{"jsonrpc": "2.0", "id": 4, "method": "build", "params": {"colour": 1}}"#;
+        assert_eq!(error(&server, unknown).await, (json!(4), INVALID_PARAMS));
+        let status = r#"{"jsonrpc": "2.0", "id": "s", "method": "status", "params": {"job": 9}}"#;
+        assert_eq!(error(&server, status).await, (json!("s"), NO_SUCH_JOB));
+
+        // Notifications are not answered, even when they fail.
+        let (outbox, _) = mpsc::unbounded_channel();
+        let notification = r#"{"jsonrpc": "2.0", "method": "jobs"}"#;
+        assert_eq!(server.handle(notification, &outbox), None);
+        assert!(server.list().is_empty());
+    }
+}
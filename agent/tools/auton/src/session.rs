@@ -0,0 +1,304 @@
+//! `auton session`: agent sessions working on different branches of one
+//! repository at the same time, without getting in each other's way.
+//!
+//! A session is a git worktree of the repository on a branch of its own
+//! (git refuses to check one branch out twice, so two sessions never share
+//! one), kept with everything its runs make in `<scratch>/sessions/<name>/`:
+//!
+//! - `session.json`: the [`Session`];
+//! - `worktree/`: the checkout, whose copy of the workspace the session's
+//!   runs build and `--commit` to;
+//! - `scratch/`: the scratch directory of `auton verify --session`;
+//! - `cache/`: kernel-builder's object cache (`--cache-dir`), shared by the
+//!   session's builds, including its `auton serve` jobs, and nobody else's;
+//! - `lock`: held by a run of the session while it runs, so one session's
+//!   runs take turns while different sessions' run at once.
+//!
+//! QEMU ports (a scripted gdb session's) are leased from
+//! [`auton_core::lock::lease_port`], so concurrent runs are never handed
+//! the same one.
+
+use crate::verify::{arg, Options};
+use anyhow::{bail, Context, Result};
+use auton_core::lock::Lock;
+use auton_core::process;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// Where sessions are kept, in the scratch directory.
+pub const SESSIONS_DIR: &str = "sessions";
+const SESSION_NAME: &str = "session.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub name: String,
+    pub branch: String,
+    /// The repository's top level.
+    pub repo: PathBuf,
+    /// The workspace, relative to the top level.
+    pub subdir: PathBuf,
+    /// Unix seconds.
+    pub created: u64,
+    /// `<scratch>/sessions/<name>`.
+    #[serde(skip)]
+    pub dir: PathBuf,
+}
+
+/// The sessions directory in `scratch`.
+pub fn root(scratch: &Path) -> PathBuf {
+    scratch.join(SESSIONS_DIR)
+}
+
+fn check_name(name: &str) -> Result<()> {
+    let ok = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if name.is_empty() || name.starts_with('.') || !name.chars().all(ok) {
+        bail!("invalid session name `{name}`: use letters, digits, `-`, `_` and `.`");
+    }
+    Ok(())
+}
+
+async fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let mut cmd = Command::new("git");
+    cmd.arg("-C").arg(dir).args(args);
+    let output = process::run(cmd, None)
+        .await?
+        .check(&format!("git {}", args.join(" ")))?;
+    Ok(output.stdout.trim().to_string())
+}
+
+impl Session {
+    /// Start session `name` in `root` on `branch` (made from `from`, by
+    /// default `HEAD`, unless it exists), for the repository `workspace` is
+    /// in.
+    pub async fn open(
+        root: &Path,
+        name: &str,
+        workspace: &Path,
+        branch: Option<&str>,
+        from: Option<&str>,
+    ) -> Result<Self> {
+        check_name(name)?;
+        let dir = root.join(name);
+        if dir.exists() {
+            bail!("session `{name}` already exists in {}", root.display());
+        }
+        let top = git(workspace, &["rev-parse", "--show-toplevel"])
+            .await
+            .with_context(|| format!("{} is not in a git repository", workspace.display()))?;
+        let repo = PathBuf::from(top)
+            .canonicalize()
+            .context("resolving the repository")?;
+        let workspace = workspace
+            .canonicalize()
+            .with_context(|| format!("resolving {}", workspace.display()))?;
+        let subdir = workspace
+            .strip_prefix(&repo)
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+        let branch = branch.map_or_else(|| format!("auton/{name}"), str::to_string);
+        let session = Self {
+            name: name.to_string(),
+            branch,
+            repo,
+            subdir,
+            created: crate::history::now(),
+            dir: std::path::absolute(dir)?,
+        };
+
+        std::fs::create_dir_all(&session.dir)
+            .with_context(|| format!("creating {}", session.dir.display()))?;
+        let worktree = arg(&session.worktree());
+        let exists = git(
+            &session.repo,
+            &[
+                "rev-parse",
+                "--verify",
+                "--quiet",
+                &format!("refs/heads/{}", session.branch),
+            ],
+        )
+        .await
+        .is_ok();
+        let added = if exists {
+            git(
+                &session.repo,
+                &["worktree", "add", &worktree, &session.branch],
+            )
+            .await
+        } else {
+            let from = from.unwrap_or("HEAD");
+            let args = ["worktree", "add", "-b", &session.branch, &worktree, from];
+            git(&session.repo, &args).await
+        };
+        if let Err(e) = added {
+            let _ = std::fs::remove_dir_all(&session.dir);
+            return Err(e.context(format!("checking out `{}`", session.branch)));
+        }
+        let path = session.dir.join(SESSION_NAME);
+        std::fs::write(&path, serde_json::to_string_pretty(&session)? + "\n")
+            .with_context(|| format!("writing {}", path.display()))?;
+        Ok(session)
+    }
+
+    /// Session `name` in `root`.
+    pub fn load(root: &Path, name: &str) -> Result<Self> {
+        check_name(name)?;
+        let dir = root.join(name);
+        let path = dir.join(SESSION_NAME);
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("no session `{name}` in {}", root.display()))?;
+        let mut session: Self =
+            serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))?;
+        session.dir = std::path::absolute(dir)?;
+        Ok(session)
+    }
+
+    /// Every session in `root`, by name.
+    pub fn list(root: &Path) -> Result<Vec<Self>> {
+        let entries = match std::fs::read_dir(root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", root.display())),
+        };
+        let mut sessions = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if let Ok(session) = Self::load(root, &name) {
+                sessions.push(session);
+            }
+        }
+        sessions.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(sessions)
+    }
+
+    pub fn worktree(&self) -> PathBuf {
+        self.dir.join("worktree")
+    }
+
+    /// The session's copy of the workspace.
+    pub fn workspace(&self) -> PathBuf {
+        self.worktree().join(&self.subdir)
+    }
+
+    pub fn scratch(&self) -> PathBuf {
+        self.dir.join("scratch")
+    }
+
+    pub fn cache(&self) -> PathBuf {
+        self.dir.join("cache")
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        self.dir.join("lock")
+    }
+
+    /// Run on the session's workspace and cache.
+    pub fn apply(&self, opts: &mut Options) {
+        opts.workspace = self.workspace();
+        opts.build_args
+            .extend(["--cache-dir".to_string(), arg(&self.cache())]);
+    }
+
+    /// Wait for the session's other runs, then hold it until the lock is
+    /// dropped.
+    pub async fn lock(&self) -> Result<Lock> {
+        let path = self.lock_path();
+        tokio::task::spawn_blocking(move || Lock::acquire(&path)).await?
+    }
+
+    /// Whether a run holds the session.
+    pub fn busy(&self) -> bool {
+        matches!(Lock::try_acquire(&self.lock_path()), Ok(None))
+    }
+
+    /// Remove the worktree and everything the session's runs made; its
+    /// branch is kept. Changes not committed to the branch are only thrown
+    /// away with `force`.
+    pub async fn close(self, force: bool) -> Result<()> {
+        let Some(_lock) = Lock::try_acquire(&self.lock_path())? else {
+            bail!("session `{}` is running", self.name);
+        };
+        let worktree = self.worktree();
+        if worktree.exists() {
+            let status = git(&worktree, &["status", "--porcelain"]).await?;
+            if !status.is_empty() && !force {
+                bail!(
+                    "session `{}` has changes not committed to `{}`:\n{status}",
+                    self.name,
+                    self.branch
+                );
+            }
+            git(
+                &self.repo,
+                &["worktree", "remove", "--force", &arg(&worktree)],
+            )
+            .await?;
+        }
+        std::fs::remove_dir_all(&self.dir)
+            .with_context(|| format!("removing {}", self.dir.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sh(dir: &Path, script: &str) {
+        let status = std::process::Command::new("sh")
+            .args(["-c", script])
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "{script}");
+    }
+
+    #[tokio::test]
+    async fn sessions_get_worktrees_of_their_own() {
+        let dir = std::env::temp_dir().join(format!("auton-session-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("repo/kernels/x86_64")).unwrap();
+        sh(
+            &dir.join("repo"),
+            "git init -q && echo one > kernels/x86_64/main.c && git add -A && \
+             git -c user.name=t -c user.email=t@t commit -q -m one",
+        );
+        let root = dir.join("sessions");
+        let ws = dir.join("repo/kernels/x86_64");
+        let a = Session::open(&root, "a", &ws, None, None).await.unwrap();
+        let b = Session::open(&root, "b", &ws, Some("topic"), None)
+            .await
+            .unwrap();
+        assert_eq!((a.branch.as_str(), b.branch.as_str()), ("auton/a", "topic"));
+        assert_eq!(a.workspace(), a.dir.join("worktree/kernels/x86_64"));
+        assert!(b.workspace().join("main.c").is_file());
+        assert_ne!(a.cache(), b.cache());
+
+        // One branch, one session.
+        let again = Session::open(&root, "c", &ws, Some("topic"), None).await;
+        assert!(again.is_err());
+        assert!(!root.join("c").exists());
+        assert!(Session::open(&root, "a", &ws, None, None).await.is_err());
+        assert!(Session::open(&root, "../x", &ws, None, None).await.is_err());
+
+        let names: Vec<String> = Session::list(&root)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(Session::load(&root, "a").unwrap(), a);
+
+        let lock = a.lock().await.unwrap();
+        assert!(a.busy() && !b.busy());
+        assert!(a.clone().close(false).await.is_err());
+        drop(lock);
+
+        std::fs::write(a.workspace().join("main.c"), "two\n").unwrap();
+        assert!(a.clone().close(false).await.is_err());
+        a.close(true).await.unwrap();
+        b.close(false).await.unwrap();
+        assert!(Session::list(&root).unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
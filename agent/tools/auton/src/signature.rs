@@ -0,0 +1,277 @@
+//! Crash signatures: which failed tests hit the same bug.
+//!
+//! A test that crashed is recorded in the history with its crash, normalized
+//! to what stays the same from one run of the bug to the next: the fault
+//! type (`panic`, `cpu-exception #PF`, `triple-fault`) and the function
+//! names of the top [`FRAMES`] symbolized frames, innermost first. Offsets,
+//! addresses and gcc's clone suffixes (`.isra.0`, `.constprop.1`, `.part.2`,
+//! `.cold`) are dropped, since any rebuild moves them. A crash without
+//! frames keeps its panic message instead, with numbers masked, and a
+//! failure with neither (a hang, a missed pattern) the test and its exit
+//! reason, so that different tests' timeouts stay apart. The signature is
+//! the first 16 hex digits of the SHA-256 of that text.
+//!
+//! `auton failures top` adds up the failed runs in the history by signature
+//! ([`clusters`]): the distinct crashes, most often hit first.
+
+use crate::history::Run;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Frames a signature keeps.
+pub const FRAMES: usize = 5;
+
+/// A crash, normalized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Crash {
+    /// `cpu-exception #PF: pmm_alloc < kmalloc < vfs_open`.
+    pub text: String,
+    /// What [`Crash::text`] hashes to.
+    pub signature: String,
+}
+
+impl Crash {
+    pub fn new(text: String) -> Self {
+        let signature =
+            auton_core::hash::hex(&auton_core::hash::sha256(text.as_bytes()))[..16].to_string();
+        Self { text, signature }
+    }
+
+    /// The crash in a failed test-runner outcome.
+    pub fn of(outcome: &Value) -> Option<Self> {
+        if outcome["passed"] == true || outcome["error"].is_string() {
+            return None;
+        }
+        let result = &outcome["result"];
+        let failure = &result["failure"];
+        let frames: Vec<&str> = result["backtrace"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|f| f["function"].as_str())
+            .map(base_name)
+            .filter(|name| !name.is_empty())
+            .take(FRAMES)
+            .collect();
+        let reason = result["reason"]["kind"].as_str().unwrap_or("failed");
+        let kind = match (
+            failure["kind"].as_str(),
+            failure["exception"]["mnemonic"].as_str(),
+        ) {
+            (Some(kind), Some(mnemonic)) => format!("{kind} {mnemonic}"),
+            (Some(kind), None) => kind.to_string(),
+            (None, _) => reason.to_string(),
+        };
+        let text = if !frames.is_empty() {
+            format!("{kind}: {}", frames.join(" < "))
+        } else if let Some(message) = failure["message"].as_str() {
+            format!("{kind}: {}", mask_numbers(message))
+        } else {
+            let test = outcome["name"].as_str().unwrap_or("?");
+            format!("{kind} in {test}")
+        };
+        Some(Self::new(text))
+    }
+}
+
+/// `name` without the suffixes gcc gives clones and split-off parts.
+fn base_name(name: &str) -> &str {
+    let name = name.trim();
+    name.split_once('.').map_or(name, |(base, _)| base)
+}
+
+/// `text` with each run of digits (and `0x` hex numbers) replaced by `N`:
+/// addresses, counts and PIDs differ between runs of the same panic.
+fn mask_numbers(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        if !c.is_ascii_digit() {
+            out.push(c);
+            continue;
+        }
+        if c == '0' && chars.peek() == Some(&'x') {
+            chars.next();
+        }
+        while chars.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+            chars.next();
+        }
+        out.push('N');
+    }
+    out
+}
+
+/// Failed runs with one signature.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Cluster {
+    pub signature: String,
+    pub crash: String,
+    pub runs: usize,
+    /// The tests that hit it.
+    pub tests: BTreeSet<String>,
+    /// Seconds since the Unix epoch of the first and last run that did.
+    pub first: u64,
+    pub last: u64,
+}
+
+/// The signed runs in `runs` by signature, most runs first (then most
+/// recent).
+pub fn clusters(runs: &[&Run]) -> Vec<Cluster> {
+    let mut by_signature: BTreeMap<&str, Cluster> = BTreeMap::new();
+    for run in runs.iter().filter(|r| !r.passed) {
+        let (Some(signature), Some(crash)) = (&run.signature, &run.crash) else {
+            continue;
+        };
+        let cluster = by_signature.entry(signature).or_insert_with(|| Cluster {
+            signature: signature.clone(),
+            crash: crash.clone(),
+            runs: 0,
+            tests: BTreeSet::new(),
+            first: run.time,
+            last: run.time,
+        });
+        cluster.runs += 1;
+        cluster.tests.extend(run.test.clone());
+        cluster.first = cluster.first.min(run.time);
+        cluster.last = cluster.last.max(run.time);
+    }
+    let mut clusters: Vec<Cluster> = by_signature.into_values().collect();
+    clusters.sort_by(|a, b| b.runs.cmp(&a.runs).then(b.last.cmp(&a.last)));
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn crashed(name: &str, rip: u64, frames: &[(&str, u64)]) -> Value {
+        let backtrace: Vec<Value> = frames
+            .iter()
+            .map(|(function, offset)| json!({"address": rip + offset, "function": function, "offset": offset}))
+            .collect();
+        json!({
+            "name": name,
+            "passed": false,
+            "result": {
+                "reason": {"kind": "panic"},
+                "failure": {
+                    "kind": "cpu-exception",
+                    "exception": {"vector": 14, "mnemonic": "#PF", "name": "Page Fault"},
+                    "rip": rip,
+                },
+                "backtrace": backtrace,
+            },
+        })
+    }
+
+    #[test]
+    fn the_same_crash_in_another_build_has_the_same_signature() {
+        let a = Crash::of(&crashed(
+            "vfs",
+            0x101000,
+            &[
+                ("pmm_alloc", 0x1c),
+                ("kmalloc.constprop.0", 0x40),
+                ("vfs_open", 0x8),
+            ],
+        ))
+        .unwrap();
+        let b = Crash::of(&crashed(
+            "fs-stress",
+            0x102200,
+            &[("pmm_alloc", 0x24), ("kmalloc", 0x44), ("vfs_open", 0x10)],
+        ))
+        .unwrap();
+        assert_eq!(a.text, "cpu-exception #PF: pmm_alloc < kmalloc < vfs_open");
+        assert_eq!(a, b);
+        assert_eq!(a.signature.len(), 16);
+        let other = Crash::of(&crashed("vfs", 0x101000, &[("pmm_free", 0x4)])).unwrap();
+        assert_ne!(a.signature, other.signature);
+        let frames: Vec<(&str, u64)> = (0..8).map(|_| ("f", 0)).collect();
+        let deep = Crash::of(&crashed("vfs", 0, &frames)).unwrap();
+        assert_eq!(deep.text.matches('f').count(), FRAMES);
+    }
+
+    #[test]
+    fn crashes_without_frames_fall_back_to_the_message_or_test() {
+        let panic = json!({
+            "name": "mm",
+            "passed": false,
+            "result": {
+                "reason": {"kind": "panic"},
+                "failure": {"kind": "panic", "message": "out of memory at 0xffff8000 after 4096 pages"},
+            },
+        });
+        assert_eq!(
+            Crash::of(&panic).unwrap().text,
+            "panic: out of memory at N after N pages"
+        );
+        let hang =
+            json!({"name": "net", "passed": false, "result": {"reason": {"kind": "timeout"}}});
+        assert_eq!(Crash::of(&hang).unwrap().text, "timeout in net");
+        assert_eq!(Crash::of(&json!({"name": "a", "passed": true})), None);
+        assert_eq!(
+            Crash::of(&json!({"name": "a", "passed": false, "error": "no qemu"})),
+            None
+        );
+    }
+
+    #[test]
+    fn clusters_count_runs_and_tests_most_hit_first() {
+        let run = |time, test: &str, crash: Option<&str>| Run {
+            time,
+            stage: "test".into(),
+            test: Some(test.into()),
+            passed: crash.is_none(),
+            duration_ms: 10,
+            workspace: "ws".into(),
+            arch: "x86_64".into(),
+            diff: None,
+            artifact: None,
+            failure: crash.map(|_| "panic".into()),
+            signature: crash.map(|c| Crash::new(c.into()).signature),
+            crash: crash.map(str::to_string),
+        };
+        let runs = [
+            run(1, "vfs", Some("panic: a")),
+            run(2, "boot", None),
+            run(3, "fs-stress", Some("panic: a")),
+            run(4, "net", Some("timeout in net")),
+            run(5, "vfs", Some("panic: a")),
+        ];
+        let refs: Vec<&Run> = runs.iter().collect();
+        let got: Vec<_> = clusters(&refs)
+            .into_iter()
+            .map(|c| {
+                (
+                    c.crash,
+                    c.runs,
+                    c.tests.into_iter().collect::<Vec<_>>(),
+                    c.first,
+                    c.last,
+                )
+            })
+            .collect();
+        assert_eq!(
+            got,
+            [
+                (
+                    "panic: a".to_string(),
+                    3,
+                    vec!["fs-stress".to_string(), "vfs".to_string()],
+                    1,
+                    5
+                ),
+                (
+                    "timeout in net".to_string(),
+                    1,
+                    vec!["net".to_string()],
+                    4,
+                    4
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,681 @@
+//! `auton tui`: a live run on one screen, for whoever is watching an agent
+//! session.
+//!
+//! Every `--interval` the dashboard reads what the run leaves in its
+//! scratch directory and redraws four panes:
+//!
+//! - the pipeline: the stages that ended and the one running, from
+//!   `live.json` ([`Live`]);
+//! - serial: the end of the newest `results/*/serial.log`, which
+//!   test-runner writes as the VM prints it;
+//! - diagnostics: the build stage's once it has ended, else the warnings
+//!   kernel-builder has logged to the run's JSON Lines log so far (with
+//!   `AUTON_LOG_DIR` set);
+//! - history: the last runs in the run history.
+//!
+//! It draws with plain ANSI escapes on the terminal's alternate screen, in
+//! raw mode so a key is read as soon as it is pressed: `q` or Ctrl-C quits
+//! and the terminal is put back as it was, any other key redraws at once.
+//! A [`Snapshot`] and its [`render`]ing are plain data, so `--once` and
+//! `--json` print one without a terminal.
+
+use crate::feedback;
+use crate::history::{self, Run};
+use crate::verify::Live;
+use crate::Status;
+use anyhow::{bail, Result};
+use auton_core::diagnostics::{self, Diagnostic, Severity};
+use serde::Serialize;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Bytes read from the end of a serial log.
+const SERIAL_TAIL: u64 = 64 * 1024;
+
+/// Runs kept from the history.
+const HISTORY_RUNS: usize = 50;
+
+/// The screen without a terminal to ask.
+const DEFAULT_SIZE: (usize, usize) = (100, 40);
+
+/// Where a run's state is read from.
+#[derive(Debug, Clone)]
+pub struct Sources {
+    /// The run's scratch directory.
+    pub scratch: PathBuf,
+    pub history: Option<PathBuf>,
+}
+
+/// The end of a serial log.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Serial {
+    pub path: PathBuf,
+    pub lines: Vec<String>,
+}
+
+/// Everything on the screen, as read at `time`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub scratch: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub live: Option<Live>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<Serial>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// The last runs, oldest first.
+    pub history: Vec<Run>,
+}
+
+impl Snapshot {
+    /// Read `sources` as they are now. What is missing (no run yet, no
+    /// tests, no history) is left empty: the run may not have got there.
+    pub fn read(sources: &Sources) -> Self {
+        let live = Live::load(&sources.scratch).ok();
+        let diagnostics = live.as_ref().map(live_diagnostics).unwrap_or_default();
+        let mut history = sources
+            .history
+            .as_deref()
+            .and_then(|path| history::load(path).ok())
+            .unwrap_or_default();
+        history.drain(..history.len().saturating_sub(HISTORY_RUNS));
+        Self {
+            time: history::now(),
+            scratch: sources.scratch.clone(),
+            serial: newest_serial(&sources.scratch.join("results")),
+            live,
+            diagnostics,
+            history,
+        }
+    }
+}
+
+/// The build stage's diagnostics once it has ended, else those in what
+/// kernel-builder has logged while it runs.
+fn live_diagnostics(live: &Live) -> Vec<Diagnostic> {
+    if let Some(stage) = live.stages.iter().find(|s| s.name == "build") {
+        return stage
+            .report
+            .as_ref()
+            .map(feedback::build_diagnostics)
+            .unwrap_or_default();
+    }
+    match (&live.log, live.running.as_deref()) {
+        (Some(log), Some("build")) => logged_diagnostics(log),
+        _ => Vec::new(),
+    }
+}
+
+/// The diagnostics in kernel-builder's warnings in a JSON Lines log: each
+/// carries a tool's stderr after a line saying what ran.
+pub fn logged_diagnostics(log: &Path) -> Vec<Diagnostic> {
+    let text = std::fs::read_to_string(log).unwrap_or_default();
+    text.lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|event| event["tool"] == "kernel-builder" && event["level"] == "WARN")
+        .flat_map(|event| {
+            let program = event["fields"]["program"].as_str().unwrap_or("cc");
+            diagnostics::parse(program, event["message"].as_str().unwrap_or(""))
+        })
+        .collect()
+}
+
+/// The newest test run's serial log; results directories are named for
+/// when they started, so the newest sorts last.
+fn newest_serial(results: &Path) -> Option<Serial> {
+    let path = std::fs::read_dir(results)
+        .ok()?
+        .filter_map(|entry| Some(entry.ok()?.path().join("serial.log")))
+        .filter(|path| path.is_file())
+        .max()?;
+    let lines = tail(&path, SERIAL_TAIL).ok()?;
+    Some(Serial { path, lines })
+}
+
+/// The lines in the last `bytes` of `path`, less the first if it was cut.
+fn tail(path: &Path, bytes: u64) -> std::io::Result<Vec<String>> {
+    let mut file = File::open(path)?;
+    let start = file.metadata()?.len().saturating_sub(bytes);
+    file.seek(SeekFrom::Start(start))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    let text = String::from_utf8_lossy(&buf);
+    let skip = usize::from(start > 0);
+    Ok(text.lines().skip(skip).map(printable).collect())
+}
+
+/// `line` without the escape sequences and control characters a guest
+/// prints, which would move the dashboard's cursor.
+fn printable(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            // CSI sequences end at a letter; the rest are one character.
+            '\x1b' => {
+                if chars.next() == Some('[') {
+                    for c in chars.by_ref() {
+                        if c.is_ascii_alphabetic() {
+                            break;
+                        }
+                    }
+                }
+            }
+            '\t' => out.push_str("    "),
+            c if c.is_control() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// How a line is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Plain,
+    Title,
+    Good,
+    Bad,
+    Dim,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub text: String,
+    pub style: Style,
+}
+
+impl Line {
+    fn new(style: Style, text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            style,
+        }
+    }
+}
+
+/// The screen for `snapshot`: `height` lines, none wider than `width`.
+///
+/// The pipeline's stages and a footer take a line each; diagnostics and
+/// history get up to a quarter of the rest each, and serial output what
+/// they leave.
+pub fn render(snapshot: &Snapshot, width: usize, height: usize) -> Vec<Line> {
+    let mut lines = vec![Line::new(Style::Title, title(snapshot))];
+    lines.extend(stage_lines(snapshot));
+
+    let history: Vec<Line> = snapshot
+        .history
+        .iter()
+        .map(|run| {
+            let style = if run.passed { Style::Plain } else { Style::Bad };
+            Line::new(style, run.line(snapshot.time))
+        })
+        .collect();
+    let mut diagnostics = snapshot.diagnostics.clone();
+    diagnostics.sort_by_key(|d| match d.severity {
+        Severity::Error => 0,
+        Severity::Warning => 1,
+        Severity::Note => 2,
+    });
+    let diagnostics: Vec<Line> = diagnostics.iter().map(diagnostic_line).collect();
+    let serial: Vec<Line> = snapshot
+        .serial
+        .iter()
+        .flat_map(|s| &s.lines)
+        .map(|line| Line::new(Style::Plain, line.as_str()))
+        .collect();
+
+    // Three pane titles and the footer.
+    let rest = height.saturating_sub(lines.len() + 4);
+    let history_rows = history.len().clamp(1, (rest / 4).max(1));
+    let diagnostic_rows = diagnostics.len().clamp(1, (rest / 4).max(1));
+    let serial_rows = rest.saturating_sub(history_rows + diagnostic_rows).max(1);
+
+    let serial_title = match &snapshot.serial {
+        Some(s) => format!("serial: {}", s.path.display()),
+        None => "serial".to_string(),
+    };
+    pane(
+        &mut lines,
+        &serial_title,
+        serial,
+        serial_rows,
+        true,
+        "no serial output yet",
+    );
+    pane(
+        &mut lines,
+        &format!("diagnostics ({})", snapshot.diagnostics.len()),
+        diagnostics,
+        diagnostic_rows,
+        false,
+        "no diagnostics",
+    );
+    pane(
+        &mut lines,
+        "history",
+        history,
+        history_rows,
+        true,
+        "no runs recorded",
+    );
+    lines.push(Line::new(Style::Dim, "q quits; any other key redraws"));
+
+    lines.truncate(height);
+    lines.resize(height, Line::new(Style::Plain, ""));
+    for line in &mut lines {
+        // Pane titles are rules across the screen.
+        if line.style == Style::Title && line.text.starts_with("-- ") {
+            let fill = width.saturating_sub(line.text.chars().count());
+            line.text += &"-".repeat(fill);
+        }
+        if let Some((at, _)) = line.text.char_indices().nth(width) {
+            line.text.truncate(at);
+        }
+    }
+    lines
+}
+
+fn title(snapshot: &Snapshot) -> String {
+    let scratch = snapshot.scratch.display();
+    match &snapshot.live {
+        Some(live) => {
+            let state = if live.finished() {
+                let passed = live.stages.iter().all(|s| s.status != Status::Failed);
+                if passed {
+                    "passed"
+                } else {
+                    "FAILED"
+                }
+            } else {
+                "running"
+            };
+            format!(
+                "auton {}  {state}  run {}  {scratch}",
+                live.pipeline, live.run_id
+            )
+        }
+        None => format!("auton  no run in {scratch} yet"),
+    }
+}
+
+/// One line per stage: ended, running or still to come.
+fn stage_lines(snapshot: &Snapshot) -> Vec<Line> {
+    let Some(live) = &snapshot.live else {
+        return Vec::new();
+    };
+    live.names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| match live.stages.get(i) {
+            Some(stage) => {
+                let (mark, style) = match stage.status {
+                    Status::Passed => ("ok", Style::Good),
+                    Status::Failed => ("!!", Style::Bad),
+                    Status::Skipped => ("--", Style::Dim),
+                };
+                Line::new(style, format!("  [{mark}] {name:<8} {}", stage.outcome()))
+            }
+            None if live.running.as_deref() == Some(name) => {
+                let secs = snapshot.time.saturating_sub(live.since);
+                Line::new(
+                    Style::Title,
+                    format!("  [>>] {name:<8} running for {secs}s"),
+                )
+            }
+            None => Line::new(Style::Dim, format!("  [  ] {name}")),
+        })
+        .collect()
+}
+
+fn diagnostic_line(d: &Diagnostic) -> Line {
+    let (word, style) = match d.severity {
+        Severity::Error => ("error", Style::Bad),
+        Severity::Warning => ("warning", Style::Plain),
+        Severity::Note => ("note", Style::Dim),
+    };
+    let at = match (&d.file, d.line, d.column) {
+        (Some(file), Some(line), Some(column)) => format!("{file}:{line}:{column}"),
+        (Some(file), Some(line), None) => format!("{file}:{line}"),
+        (Some(file), None, _) => file.clone(),
+        (None, ..) => d.tool.clone(),
+    };
+    Line::new(style, format!("{word:<7}  {at}  {}", d.message))
+}
+
+/// A titled pane of `rows` lines: the last of `content` when `tail`, else
+/// the first.
+fn pane(
+    out: &mut Vec<Line>,
+    title: &str,
+    content: Vec<Line>,
+    rows: usize,
+    tail: bool,
+    empty: &str,
+) {
+    out.push(Line::new(Style::Title, format!("-- {title} ")));
+    if content.is_empty() {
+        out.push(Line::new(Style::Dim, format!("  ({empty})")));
+        out.extend((1..rows).map(|_| Line::new(Style::Plain, "")));
+        return;
+    }
+    let skip = if tail {
+        content.len().saturating_sub(rows)
+    } else {
+        0
+    };
+    let shown = content.len().min(rows);
+    out.extend(content.into_iter().skip(skip).take(rows));
+    out.extend((shown..rows).map(|_| Line::new(Style::Plain, "")));
+}
+
+/// Print one screen and exit: the terminal's size if stdout is one.
+pub fn print_once(sources: &Sources) {
+    let (width, height) = size().unwrap_or(DEFAULT_SIZE);
+    for line in render(&Snapshot::read(sources), width, height) {
+        println!("{}", line.text.trim_end());
+    }
+}
+
+/// Redraw `sources` every `interval` until `q` or Ctrl-C.
+pub fn run(sources: &Sources, interval: Duration) -> Result<()> {
+    let terminal = Terminal::enter()?;
+    loop {
+        let (width, height) = size().unwrap_or(DEFAULT_SIZE);
+        terminal.draw(&render(&Snapshot::read(sources), width, height))?;
+        if let Some(b'q' | b'Q' | 0x03) = terminal.key(interval) {
+            return Ok(());
+        }
+    }
+}
+
+/// stdout's terminal size, as (columns, rows).
+fn size() -> Option<(usize, usize)> {
+    // SAFETY: TIOCGWINSZ fills in the `winsize` it is given.
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) } == 0;
+    (ok && ws.ws_col > 0 && ws.ws_row > 0).then(|| (ws.ws_col.into(), ws.ws_row.into()))
+}
+
+/// The terminal in raw mode on its alternate screen, put back on drop.
+struct Terminal {
+    saved: libc::termios,
+}
+
+impl Terminal {
+    fn enter() -> Result<Self> {
+        // SAFETY: `isatty` only reads the descriptor; `tcgetattr` fills in
+        // the `termios` it is given.
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        let tty = unsafe {
+            libc::isatty(libc::STDIN_FILENO) == 1
+                && libc::isatty(libc::STDOUT_FILENO) == 1
+                && libc::tcgetattr(libc::STDIN_FILENO, &mut saved) == 0
+        };
+        if !tty {
+            bail!("`auton tui` needs a terminal; --once or --json print one snapshot without one");
+        }
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        // SAFETY: `raw` is the terminal's own settings, changed above.
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) };
+        let terminal = Self { saved };
+        terminal.write("\x1b[?1049h\x1b[?25l")?;
+        Ok(terminal)
+    }
+
+    fn write(&self, text: &str) -> std::io::Result<()> {
+        let mut out = std::io::stdout().lock();
+        out.write_all(text.as_bytes())?;
+        out.flush()
+    }
+
+    /// Draw `lines` over the last screen, clearing what they leave.
+    fn draw(&self, lines: &[Line]) -> std::io::Result<()> {
+        let mut out = String::from("\x1b[H");
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 {
+                out += "\r\n";
+            }
+            out += match line.style {
+                Style::Plain => "",
+                Style::Title => "\x1b[1m",
+                Style::Good => "\x1b[32m",
+                Style::Bad => "\x1b[1;31m",
+                Style::Dim => "\x1b[2m",
+            };
+            out += &line.text;
+            out += "\x1b[0m\x1b[K";
+        }
+        out += "\x1b[J";
+        self.write(&out)
+    }
+
+    /// A key pressed within `timeout`, if any.
+    fn key(&self, timeout: Duration) -> Option<u8> {
+        let mut fd = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+        // SAFETY: one valid `pollfd`, and one byte read into `byte`.
+        if unsafe { libc::poll(&mut fd, 1, ms) } <= 0 {
+            return None;
+        }
+        let mut byte = 0u8;
+        let read = unsafe { libc::read(libc::STDIN_FILENO, (&mut byte as *mut u8).cast(), 1) };
+        (read == 1).then_some(byte)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = self.write("\x1b[0m\x1b[?25h\x1b[?1049l");
+        // SAFETY: the settings `enter` saved.
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Stage;
+    use std::path::Path;
+
+    fn stage(name: &str, status: Status, summary: &str) -> Stage {
+        Stage {
+            status,
+            duration_ms: 1200,
+            summary: summary.to_string(),
+            ..Stage::skipped(name, "")
+        }
+    }
+
+    fn live(stages: Vec<Stage>, running: Option<&str>) -> Live {
+        Live {
+            pipeline: "verify".into(),
+            run_id: "r1".into(),
+            log: None,
+            names: ["validate", "apply", "build", "test"]
+                .map(String::from)
+                .to_vec(),
+            stages,
+            running: running.map(String::from),
+            since: 990,
+        }
+    }
+
+    fn snapshot(live: Option<Live>) -> Snapshot {
+        Snapshot {
+            time: 1000,
+            scratch: PathBuf::from("build/auton"),
+            live,
+            serial: None,
+            diagnostics: Vec::new(),
+            history: Vec::new(),
+        }
+    }
+
+    fn texts(lines: &[Line]) -> Vec<&str> {
+        lines.iter().map(|l| l.text.trim_end()).collect()
+    }
+
+    #[test]
+    fn a_running_pipeline_shows_every_stage_and_pane() {
+        let stages = vec![
+            stage("validate", Status::Passed, "0 errors, 0 warnings, 0 notes"),
+            stage("apply", Status::Passed, "patched 1 file"),
+        ];
+        let mut snap = snapshot(Some(live(stages, Some("build"))));
+        snap.serial = Some(Serial {
+            path: PathBuf::from("results/t/serial.log"),
+            lines: (1..=30).map(|n| format!("boot line {n}")).collect(),
+        });
+        snap.diagnostics = diagnostics::parse(
+            "gcc",
+            "kernel/mm.c:3:1: warning: unused variable 'x'\nkernel/main.c:9:5: error: expected ';'\n",
+        );
+        let lines = render(&snap, 60, 24);
+        assert_eq!(lines.len(), 24);
+        assert!(lines.iter().all(|l| l.text.chars().count() <= 60));
+        let text = texts(&lines);
+        assert_eq!(text[0], "auton verify  running  run r1  build/auton");
+        assert_eq!(
+            &text[1..5],
+            [
+                "  [ok] validate passed in 1.2s: 0 errors, 0 warnings, 0 notes",
+                "  [ok] apply    passed in 1.2s: patched 1 file",
+                "  [>>] build    running for 10s",
+                "  [  ] test",
+            ]
+            .map(|l| &l[..l.len().min(60)])
+        );
+        assert!(text[5].starts_with("-- serial: results/t/serial.log ---"));
+        // Serial output is tailed: the newest line is the last one shown.
+        let serial_end = text
+            .iter()
+            .position(|l| l.starts_with("-- diagnostics"))
+            .unwrap();
+        assert_eq!(text[serial_end - 1], "boot line 30");
+        // Errors come first.
+        assert_eq!(
+            text[serial_end],
+            format!("-- diagnostics (2) {}", "-".repeat(41))
+        );
+        assert!(text[serial_end + 1].starts_with("error    kernel/main.c:9:5  expected"));
+        assert_eq!(lines[serial_end + 1].style, Style::Bad);
+        assert!(text.contains(&"  (no runs recorded)"));
+        assert_eq!(text[23], "q quits; any other key redraws");
+    }
+
+    #[test]
+    fn a_finished_run_says_whether_it_passed() {
+        let stages = vec![
+            stage("validate", Status::Passed, "ok"),
+            stage("apply", Status::Passed, "ok"),
+            stage("build", Status::Failed, "compile failed"),
+            Stage::skipped("test", "the build failed"),
+        ];
+        let lines = render(&snapshot(Some(live(stages, None))), 80, 12);
+        let text = texts(&lines);
+        assert_eq!(text[0], "auton verify  FAILED  run r1  build/auton");
+        assert_eq!(text[3], "  [!!] build    FAILED in 1.2s: compile failed");
+        assert_eq!(text[4], "  [--] test     skipped: the build failed");
+        let lines = render(&snapshot(None), 80, 3);
+        let rule = format!("-- serial {}", "-".repeat(70));
+        assert_eq!(
+            texts(&lines),
+            [
+                "auton  no run in build/auton yet",
+                &rule,
+                "  (no serial output yet)"
+            ]
+        );
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("auton-tui-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, text: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, text).unwrap();
+    }
+
+    #[test]
+    fn snapshots_read_the_newest_serial_log_and_logged_warnings() {
+        let dir = scratch("read");
+        let log = dir.join("logs/r1.jsonl");
+        let mut state = live(vec![stage("validate", Status::Passed, "ok")], Some("build"));
+        state.log = Some(log.clone());
+        write(
+            &dir.join("live.json"),
+            &serde_json::to_string(&state).unwrap(),
+        );
+        write(
+            &dir.join("results/20261014T100000.000Z-boot/serial.log"),
+            "old\n",
+        );
+        write(
+            &dir.join("results/20261014T110000.000Z-boot/serial.log"),
+            "\x1b[1;32mOK\x1b[0m\tmm\r\nkernel panic\n",
+        );
+        let warning = serde_json::json!({
+            "tool": "kernel-builder",
+            "level": "WARN",
+            "message": "compiling kernel/mm.c:\nkernel/mm.c:3:1: warning: unused variable 'x'",
+            "fields": {"program": "gcc"},
+        });
+        let info = serde_json::json!({"tool": "kernel-builder", "level": "INFO", "message": "kernel/a.c:1:1: warning: no"});
+        write(&log, &format!("{warning}\n{info}\nnot json\n"));
+        write(
+            &dir.join("history.jsonl"),
+            r#"{"time":1,"stage":"build","passed":true,"duration_ms":5,"workspace":"ws","arch":"x86_64"}"#,
+        );
+
+        let snap = Snapshot::read(&Sources {
+            scratch: dir.clone(),
+            history: Some(dir.join("history.jsonl")),
+        });
+        assert_eq!(snap.live, Some(state));
+        let serial = snap.serial.unwrap();
+        assert!(serial
+            .path
+            .ends_with("20261014T110000.000Z-boot/serial.log"));
+        assert_eq!(serial.lines, ["OK    mm", "kernel panic"]);
+        assert_eq!(snap.diagnostics.len(), 1);
+        assert_eq!(snap.diagnostics[0].file.as_deref(), Some("kernel/mm.c"));
+        assert_eq!(snap.history.len(), 1);
+
+        let empty = Snapshot::read(&Sources {
+            scratch: dir.join("nothing"),
+            history: None,
+        });
+        assert!(empty.live.is_none() && empty.serial.is_none() && empty.history.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_cut_tail_drops_its_partial_first_line() {
+        let dir = scratch("tail");
+        let path = dir.join("serial.log");
+        std::fs::write(&path, "first line\nsecond\nthird\n").unwrap();
+        assert_eq!(tail(&path, 13).unwrap(), ["third"]);
+        assert_eq!(
+            tail(&path, 1024).unwrap(),
+            ["first line", "second", "third"]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
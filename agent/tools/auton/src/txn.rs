@@ -0,0 +1,481 @@
+//! Workspace transactions: a diff is applied to a view of the workspace,
+//! built and tested there, and only then, if at all, written to the
+//! workspace itself, all at once or not at all.
+//!
+//! [`Transaction::begin`] makes the view at the path it is given: a git
+//! worktree of `HEAD` when the workspace is a checkout with nothing
+//! modified, untracked or ignored in it (so the two are the same files),
+//! else a copy. Every stage after it works on [`Transaction::view`].
+//! [`Transaction::abort`] removes the view; dropping a transaction leaves
+//! it for inspection, and the next `begin` on the same path replaces it.
+//! Either way the workspace is untouched.
+//!
+//! [`Transaction::commit`] writes the paths the transaction [touched]
+//! back. Everything it will write is first staged in
+//! `<workspace>/.auton-txn/new`, then a journal of the paths is synced,
+//! then each path is renamed into place with the one it replaces renamed
+//! to `.auton-txn/old`; last the directory is removed. A process killed
+//! before the journal is written left the workspace as it was, and one
+//! killed after it is rolled back from `old`: the next `begin` on the
+//! workspace, or [`recover`], finishes the job. So no run leaves half a
+//! diff in the workspace.
+//!
+//! [touched]: Transaction::touch
+
+use anyhow::{bail, Context, Result};
+use auton_core::plan::{Plan, Planned};
+use auton_core::process;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use tokio::process::Command;
+
+/// Where a commit stages its files and journal, in the workspace.
+pub const TXN_DIR: &str = ".auton-txn";
+const JOURNAL_NAME: &str = "journal.json";
+
+/// How the view was made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewKind {
+    Worktree,
+    Copy,
+}
+
+impl ViewKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Worktree => "worktree",
+            Self::Copy => "copy",
+        }
+    }
+}
+
+/// One path a commit writes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    path: PathBuf,
+    existed: bool,
+    /// Whether the view has it; if not, the commit removes it.
+    exists: bool,
+}
+
+/// A commit in progress, as synced before the workspace is touched.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Journal {
+    entries: Vec<Entry>,
+    /// Directories made for new files, parents first.
+    dirs: Vec<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct Transaction {
+    workspace: PathBuf,
+    view: PathBuf,
+    kind: ViewKind,
+    touched: Vec<PathBuf>,
+}
+
+impl Transaction {
+    /// Finish any commit a killed process left in `workspace`, then make
+    /// `view` a fresh view of it.
+    pub async fn begin(workspace: &Path, view: &Path) -> Result<Self> {
+        check_view(workspace, view)?;
+        recover(workspace)?;
+        remove_view(workspace, view).await?;
+        let kind = if pristine_checkout(workspace).await {
+            ViewKind::Worktree
+        } else {
+            ViewKind::Copy
+        };
+        if let Some(parent) = view.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("creating {}", parent.display()))?;
+        }
+        if kind == ViewKind::Copy {
+            std::fs::create_dir_all(view)
+                .with_context(|| format!("creating {}", view.display()))?;
+        }
+        process::run(view_command(workspace, view, kind)?, None)
+            .await?
+            .check(&format!("copying {}", workspace.display()))?;
+        Ok(Self {
+            workspace: workspace.to_path_buf(),
+            view: view.to_path_buf(),
+            kind,
+            touched: Vec::new(),
+        })
+    }
+
+    pub fn view(&self) -> &Path {
+        &self.view
+    }
+
+    pub fn kind(&self) -> ViewKind {
+        self.kind
+    }
+
+    /// Mark `path`, relative to the workspace, for the commit to write
+    /// (or remove, if the view no longer has it).
+    pub fn touch(&mut self, path: &Path) -> Result<()> {
+        if path.as_os_str().is_empty()
+            || !path.components().all(|c| matches!(c, Component::Normal(_)))
+        {
+            bail!("{}: not a path inside the workspace", path.display());
+        }
+        if path.starts_with(TXN_DIR) || path.starts_with(".git") {
+            bail!("{}: not a workspace file", path.display());
+        }
+        if !self.touched.iter().any(|p| p == path) {
+            self.touched.push(path.to_path_buf());
+        }
+        Ok(())
+    }
+
+    /// Write the touched paths to the workspace, all or none; the paths
+    /// that changed.
+    pub async fn commit(self) -> Result<Vec<PathBuf>> {
+        let changed = commit(&self.workspace, &self.view, &self.touched);
+        self.abort().await?;
+        changed
+    }
+
+    /// Remove the view.
+    pub async fn abort(self) -> Result<()> {
+        remove_view(&self.workspace, &self.view).await
+    }
+}
+
+/// What [`Transaction::begin`] would run to make `view`, for a dry run:
+/// only the read-only git probes that pick the kind are run.
+pub async fn plan(workspace: &Path, view: &Path, plan: &mut Plan) -> Result<()> {
+    check_view(workspace, view)?;
+    let kind = if pristine_checkout(workspace).await {
+        ViewKind::Worktree
+    } else {
+        ViewKind::Copy
+    };
+    let cmd = view_command(workspace, view, kind)?;
+    let what = format!("making the view as a {}", kind.as_str());
+    plan.run(Planned::of(what, cmd.as_std()));
+    plan.write(view);
+    Ok(())
+}
+
+fn check_view(workspace: &Path, view: &Path) -> Result<()> {
+    if !workspace.is_dir() {
+        bail!("workspace {} is not a directory", workspace.display());
+    }
+    if std::path::absolute(view)?.starts_with(std::path::absolute(workspace)?) {
+        bail!(
+            "the view {} is inside the workspace {}",
+            view.display(),
+            workspace.display()
+        );
+    }
+    Ok(())
+}
+
+/// The command that fills `view` with the workspace's files.
+fn view_command(workspace: &Path, view: &Path, kind: ViewKind) -> Result<Command> {
+    Ok(match kind {
+        ViewKind::Worktree => {
+            let mut cmd = Command::new("git");
+            cmd.arg("-C")
+                .arg(workspace)
+                .args(["worktree", "add", "--detach"]);
+            cmd.arg(std::path::absolute(view)?).arg("HEAD");
+            cmd
+        }
+        ViewKind::Copy => {
+            let mut cmd = Command::new("cp");
+            cmd.arg("-a").arg(workspace.join(".")).arg(view);
+            cmd
+        }
+    })
+}
+
+/// Whether `workspace` is the top of a git checkout whose files are all
+/// tracked and unmodified.
+async fn pristine_checkout(workspace: &Path) -> bool {
+    let git = |args: &[&str]| {
+        let mut cmd = Command::new("git");
+        cmd.arg("-C").arg(workspace).args(args);
+        process::run(cmd, None)
+    };
+    let Ok(top) = git(&["rev-parse", "--show-toplevel"]).await else {
+        return false;
+    };
+    let top = PathBuf::from(top.stdout.trim());
+    if !top.is_dir() || top.canonicalize().ok() != workspace.canonicalize().ok() {
+        return false;
+    }
+    match git(&["status", "--porcelain", "--ignored"]).await {
+        Ok(status) => status.success() && status.stdout.trim().is_empty(),
+        Err(_) => false,
+    }
+}
+
+/// Remove `view`, and its worktree's registration if it is one.
+async fn remove_view(workspace: &Path, view: &Path) -> Result<()> {
+    if view.join(".git").is_file() {
+        let mut cmd = Command::new("git");
+        cmd.arg("-C")
+            .arg(workspace)
+            .args(["worktree", "remove", "--force"]);
+        cmd.arg(std::path::absolute(view)?);
+        // A worktree of another repository, or one git has forgotten, is
+        // just a directory.
+        let _ = process::run(cmd, None).await;
+    }
+    match std::fs::remove_dir_all(view) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("removing {}", view.display()))
+        }
+        _ => {}
+    }
+    if workspace.join(".git").exists() {
+        let mut cmd = Command::new("git");
+        cmd.arg("-C").arg(workspace).args(["worktree", "prune"]);
+        let _ = process::run(cmd, None).await;
+    }
+    Ok(())
+}
+
+fn same_contents(a: &Path, b: &Path) -> bool {
+    match (std::fs::read(a), std::fs::read(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> Result<()> {
+    let mut file =
+        std::fs::File::create(path).with_context(|| format!("creating {}", path.display()))?;
+    file.write_all(data)
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+fn rename(from: &Path, to: &Path) -> Result<()> {
+    std::fs::rename(from, to)
+        .with_context(|| format!("moving {} to {}", from.display(), to.display()))
+}
+
+fn commit(workspace: &Path, view: &Path, touched: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let txn = workspace.join(TXN_DIR);
+    let mut journal = Journal {
+        entries: Vec::new(),
+        dirs: Vec::new(),
+    };
+    for path in touched {
+        let (old, new) = (workspace.join(path), view.join(path));
+        let entry = Entry {
+            path: path.clone(),
+            existed: old.is_file(),
+            exists: new.is_file(),
+        };
+        let unchanged = match (entry.existed, entry.exists) {
+            (true, true) => same_contents(&old, &new),
+            (existed, exists) => !existed && !exists,
+        };
+        if !unchanged {
+            journal.entries.push(entry);
+        }
+    }
+    if journal.entries.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Stage: nothing in the workspace changes yet.
+    let _ = std::fs::remove_dir_all(&txn);
+    for entry in journal.entries.iter().filter(|e| e.exists) {
+        let staged = txn.join("new").join(&entry.path);
+        std::fs::create_dir_all(staged.parent().unwrap())?;
+        std::fs::copy(view.join(&entry.path), &staged)
+            .with_context(|| format!("staging {}", entry.path.display()))?;
+        let parents: Vec<&Path> = entry.path.ancestors().skip(1).collect();
+        for dir in parents
+            .into_iter()
+            .rev()
+            .filter(|d| !d.as_os_str().is_empty())
+        {
+            if !workspace.join(dir).exists() && !journal.dirs.iter().any(|d| d == dir) {
+                journal.dirs.push(dir.to_path_buf());
+            }
+        }
+    }
+    std::fs::create_dir_all(txn.join("old"))?;
+    write_synced(
+        &txn.join(JOURNAL_NAME),
+        &serde_json::to_vec_pretty(&journal)?,
+    )?;
+
+    // From here on a crash is rolled back from the journal.
+    let applied = (|| -> Result<()> {
+        for dir in &journal.dirs {
+            std::fs::create_dir_all(workspace.join(dir))?;
+        }
+        for entry in &journal.entries {
+            let target = workspace.join(&entry.path);
+            if entry.existed {
+                let old = txn.join("old").join(&entry.path);
+                std::fs::create_dir_all(old.parent().unwrap())?;
+                rename(&target, &old)?;
+            }
+            if entry.exists {
+                rename(&txn.join("new").join(&entry.path), &target)?;
+            }
+        }
+        Ok(())
+    })();
+    if let Err(e) = applied {
+        rollback(workspace)?;
+        return Err(e.context("committing to the workspace; rolled back"));
+    }
+    std::fs::remove_dir_all(&txn).with_context(|| format!("removing {}", txn.display()))?;
+    Ok(journal.entries.into_iter().map(|e| e.path).collect())
+}
+
+/// Undo the commit journalled in `workspace`, if any.
+fn rollback(workspace: &Path) -> Result<()> {
+    let txn = workspace.join(TXN_DIR);
+    let journal = std::fs::read(txn.join(JOURNAL_NAME))
+        .ok()
+        .and_then(|j| serde_json::from_slice::<Journal>(&j).ok());
+    for entry in journal.iter().flat_map(|j| &j.entries) {
+        let target = workspace.join(&entry.path);
+        let old = txn.join("old").join(&entry.path);
+        if old.is_file() {
+            rename(&old, &target)?;
+        } else if !entry.existed {
+            match std::fs::remove_file(&target) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("removing {}", target.display()))
+                }
+                _ => {}
+            }
+        }
+    }
+    for dir in journal.iter().flat_map(|j| j.dirs.iter().rev()) {
+        // Only if empty: something else may have put files there since.
+        let _ = std::fs::remove_dir(workspace.join(dir));
+    }
+    std::fs::remove_dir_all(&txn).with_context(|| format!("removing {}", txn.display()))
+}
+
+/// Roll back a commit a killed process left half done in `workspace`;
+/// whether there was one.
+pub fn recover(workspace: &Path) -> Result<bool> {
+    if !workspace.join(TXN_DIR).exists() {
+        return Ok(false);
+    }
+    tracing::warn!(
+        workspace = %workspace.display(),
+        "rolling back an unfinished commit"
+    );
+    rollback(workspace)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("auton-txn-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("ws/kernel")).unwrap();
+        std::fs::write(dir.join("ws/kernel/main.c"), "old main\n").unwrap();
+        std::fs::write(dir.join("ws/kernel/gone.c"), "gone\n").unwrap();
+        dir
+    }
+
+    fn read(path: &Path) -> Option<String> {
+        std::fs::read_to_string(path).ok()
+    }
+
+    #[tokio::test]
+    async fn commits_touched_paths_and_nothing_else() {
+        let dir = scratch("commit");
+        let ws = dir.join("ws");
+        let mut txn = Transaction::begin(&ws, &dir.join("tree")).await.unwrap();
+        assert_eq!(txn.kind(), ViewKind::Copy);
+        let view = txn.view().to_path_buf();
+        std::fs::write(view.join("kernel/main.c"), "new main\n").unwrap();
+        std::fs::remove_file(view.join("kernel/gone.c")).unwrap();
+        std::fs::create_dir_all(view.join("kernel/mm")).unwrap();
+        std::fs::write(view.join("kernel/mm/pmm.c"), "pmm\n").unwrap();
+        std::fs::write(view.join("kernel/main.o"), "build output\n").unwrap();
+        for path in ["kernel/main.c", "kernel/gone.c", "kernel/mm/pmm.c"] {
+            txn.touch(Path::new(path)).unwrap();
+        }
+        assert!(txn.touch(Path::new("../escape")).is_err());
+        assert!(txn.touch(Path::new(".git/config")).is_err());
+
+        let changed = txn.commit().await.unwrap();
+        assert_eq!(changed.len(), 3);
+        assert_eq!(read(&ws.join("kernel/main.c")).unwrap(), "new main\n");
+        assert!(!ws.join("kernel/gone.c").exists());
+        assert_eq!(read(&ws.join("kernel/mm/pmm.c")).unwrap(), "pmm\n");
+        assert!(!ws.join("kernel/main.o").exists());
+        assert!(!ws.join(TXN_DIR).exists());
+        assert!(!view.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_commit_killed_midway_is_rolled_back() {
+        let dir = scratch("crash");
+        let ws = dir.join("ws");
+        let view = dir.join("tree");
+        std::fs::create_dir_all(view.join("kernel/mm")).unwrap();
+        std::fs::write(view.join("kernel/main.c"), "new main\n").unwrap();
+        std::fs::write(view.join("kernel/mm/pmm.c"), "pmm\n").unwrap();
+        let touched = ["kernel/main.c", "kernel/gone.c", "kernel/mm/pmm.c"].map(PathBuf::from);
+        commit(&ws, &view, &touched).unwrap();
+
+        // As a process killed between the journal and the last rename
+        // would leave it: main.c swapped, gone.c moved aside, pmm.c not
+        // yet in place.
+        let txn = ws.join(TXN_DIR);
+        std::fs::create_dir_all(txn.join("old/kernel")).unwrap();
+        std::fs::create_dir_all(txn.join("new/kernel/mm")).unwrap();
+        std::fs::write(txn.join("old/kernel/main.c"), "old main\n").unwrap();
+        std::fs::write(txn.join("old/kernel/gone.c"), "gone\n").unwrap();
+        std::fs::write(txn.join("new/kernel/mm/pmm.c"), "pmm\n").unwrap();
+        std::fs::remove_file(ws.join("kernel/mm/pmm.c")).unwrap();
+        let journal = Journal {
+            entries: vec![
+                Entry {
+                    path: "kernel/main.c".into(),
+                    existed: true,
+                    exists: true,
+                },
+                Entry {
+                    path: "kernel/gone.c".into(),
+                    existed: true,
+                    exists: false,
+                },
+                Entry {
+                    path: "kernel/mm/pmm.c".into(),
+                    existed: false,
+                    exists: true,
+                },
+            ],
+            dirs: vec!["kernel/mm".into()],
+        };
+        write_synced(
+            &txn.join(JOURNAL_NAME),
+            &serde_json::to_vec(&journal).unwrap(),
+        )
+        .unwrap();
+
+        assert!(recover(&ws).unwrap());
+        assert_eq!(read(&ws.join("kernel/main.c")).unwrap(), "old main\n");
+        assert_eq!(read(&ws.join("kernel/gone.c")).unwrap(), "gone\n");
+        assert!(!ws.join("kernel/mm").exists());
+        assert!(!txn.exists());
+        assert!(!recover(&ws).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,749 @@
+//! `auton verify`: a diff validated, applied, built and tested.
+//!
+//! The stages run in order, each one run of a tool:
+//!
+//! 1. `validate`: diff-validator checks the diff against the workspace;
+//!    with [`Options::merge`] it also writes the diff rebased onto the
+//!    workspace, which is what gets applied;
+//! 2. `apply`: a [transaction] on the workspace makes its view in
+//!    `<scratch>/tree` and the diff is patched into the view with `patch`,
+//!    at the validator's fuzz;
+//! 3. `build`: kernel-builder builds the view into `<scratch>/build`;
+//! 4. `test`: test-runner runs the [`Options::tests`] suite against the
+//!    image the build's manifest names, or is skipped without one.
+//!
+//! A failed stage skips the ones after it, except that with
+//! [`Options::keep_going`] a diff that fails validation is still built and
+//! tested. The workspace itself is only written to with [`Options::commit`],
+//! once every stage has passed, and then all of the diff or none of it;
+//! the scratch directory is left for inspection, with the verdict in
+//! `verdict.json`. While it runs, `live.json` has the stages so far and
+//! the one running ([`Live`]), for `auton tui`. With
+//! [`Options::history`], the stages that ran are added to the run history,
+//! and with [`Options::notify`] they are sent as events.
+//!
+//! [`validate`], [`build`] and [`test`] run one stage on its own, for
+//! `auton serve`; `build` builds the workspace as it is. [`plan`] is
+//! `verify --dry-run`: the stages' commands and the files they would
+//! write, with nothing run but git's read-only probes of the workspace.
+//!
+//! [transaction]: crate::txn
+
+use crate::history::{self, Subject};
+use crate::notify::{self, Notifier};
+use crate::txn::{self, Transaction};
+use crate::{Stage, Status, Verdict};
+use anyhow::{Context, Result};
+use auton_core::diff;
+use auton_core::manifest::{BuildManifest, MANIFEST_NAME};
+use auton_core::plan::{Plan, Planned};
+use auton_core::process;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::process::Command;
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+
+/// The stages, in the order they run.
+pub const STAGES: [&str; 4] = ["validate", "apply", "build", "test"];
+
+/// stderr lines kept from a failed tool.
+const LOG_TAIL: usize = 20;
+
+/// The live state's file in a scratch directory.
+pub const LIVE_NAME: &str = "live.json";
+
+/// Where the tools are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tools {
+    pub validator: PathBuf,
+    pub builder: PathBuf,
+    pub runner: PathBuf,
+}
+
+impl Tools {
+    /// The tools next to this executable (same cargo target dir), else on
+    /// PATH.
+    pub fn find() -> Self {
+        Self {
+            validator: process::sibling("diff-validator"),
+            builder: process::sibling("kernel-builder"),
+            runner: process::sibling("test-runner"),
+        }
+    }
+
+    /// The tools in `dir`.
+    pub fn in_dir(dir: &Path) -> Self {
+        Self {
+            validator: dir.join("diff-validator"),
+            builder: dir.join("kernel-builder"),
+            runner: dir.join("test-runner"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub diff: PathBuf,
+    pub workspace: PathBuf,
+    pub arch: String,
+    /// Directory of test specs for the `test` stage.
+    pub tests: Option<PathBuf>,
+    /// Where the patched tree, build, test results and verdict go.
+    pub scratch: PathBuf,
+    /// Merge hunks that no longer fit three ways and apply the rebased diff.
+    pub merge: bool,
+    pub fuzz: usize,
+    /// Build and test a diff that failed validation.
+    pub keep_going: bool,
+    pub tools: Tools,
+    /// Extra arguments for each tool.
+    pub validate_args: Vec<String>,
+    pub build_args: Vec<String>,
+    pub test_args: Vec<String>,
+    /// The run history to append the pipeline's runs to.
+    pub history: Option<PathBuf>,
+    /// Where to send the pipeline's events.
+    pub notify: Option<Notifier>,
+    /// Write the diff to the workspace if every stage passes.
+    pub commit: bool,
+}
+
+/// What a pipeline reports as it goes: stage `index` of `total`.
+#[derive(Debug, Clone, Copy)]
+pub enum Progress<'a> {
+    Started {
+        index: usize,
+        total: usize,
+        name: &'a str,
+    },
+    Finished {
+        index: usize,
+        total: usize,
+        stage: &'a Stage,
+    },
+}
+
+/// A pipeline as it runs, rewritten to `<scratch>/live.json` whenever a
+/// stage starts or ends.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Live {
+    pub pipeline: String,
+    /// The run ID, naming the run's JSON Lines log.
+    pub run_id: String,
+    /// That log, if the run writes one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log: Option<PathBuf>,
+    /// Every stage the pipeline has, in order.
+    pub names: Vec<String>,
+    /// The stages that ended.
+    pub stages: Vec<Stage>,
+    /// The stage running.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub running: Option<String>,
+    /// Seconds since the Unix epoch when the running stage started.
+    #[serde(default)]
+    pub since: u64,
+}
+
+impl Live {
+    /// Read `<scratch>/live.json`.
+    pub fn load(scratch: &Path) -> Result<Self> {
+        let path = scratch.join(LIVE_NAME);
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// Every stage has ended.
+    pub fn finished(&self) -> bool {
+        self.running.is_none() && self.stages.len() == self.names.len()
+    }
+
+    /// Replace the file in one rename, so readers never see half of it.
+    fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)? + "\n")?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+struct Pipeline<'a> {
+    /// The pipeline, for its `pipeline-finished` event.
+    name: &'static str,
+    names: &'static [&'static str],
+    /// The stages so far, in `live.stages`.
+    live: Live,
+    /// Where `live` is kept.
+    live_path: PathBuf,
+    /// Why the remaining stages are skipped.
+    blocked: Option<String>,
+    progress: &'a mut (dyn FnMut(Progress) + Send),
+    /// The pipeline's span; each stage's is a child of it.
+    span: Span,
+}
+
+impl<'a> Pipeline<'a> {
+    fn new(
+        name: &'static str,
+        names: &'static [&'static str],
+        scratch: &Path,
+        progress: &'a mut (dyn FnMut(Progress) + Send),
+    ) -> Self {
+        let live = Live {
+            pipeline: name.to_string(),
+            run_id: auton_core::logging::run_id().to_string(),
+            log: auton_core::logging::log_dir().map(|dir| auton_core::logging::log_path(&dir)),
+            names: names.iter().map(|n| n.to_string()).collect(),
+            stages: Vec::new(),
+            running: None,
+            since: 0,
+        };
+        let pipeline = Self {
+            name,
+            names,
+            live,
+            live_path: scratch.join(LIVE_NAME),
+            blocked: None,
+            progress,
+            span: tracing::info_span!("pipeline", pipeline = name, passed = Empty),
+        };
+        pipeline.save_live();
+        pipeline
+    }
+
+    /// Only a dashboard reads it, so a failed write is not the run's.
+    fn save_live(&self) {
+        if let Err(e) = self.live.save(&self.live_path) {
+            tracing::debug!("not writing {}: {e:#}", self.live_path.display());
+        }
+    }
+
+    /// Run the next stage unless an earlier one blocked it; its value if it
+    /// passed.
+    async fn stage<T>(&mut self, run: impl Future<Output = (Stage, Option<T>)>) -> Option<T> {
+        let (index, total) = (self.live.stages.len(), self.names.len());
+        let name = self.names[index];
+        let (stage, value) = match &self.blocked {
+            Some(why) => (Stage::skipped(name, why), None),
+            None => {
+                self.live.running = Some(name.to_string());
+                self.live.since = history::now();
+                self.save_live();
+                (self.progress)(Progress::Started { index, total, name });
+                let started = Instant::now();
+                let span = tracing::info_span!(parent: &self.span, "stage", otel.name = name, status = Empty);
+                let (mut stage, value) = run.instrument(span.clone()).await;
+                stage.duration_ms = started.elapsed().as_millis() as u64;
+                span.record("status", stage.status.as_str());
+                (stage, value)
+            }
+        };
+        (self.progress)(Progress::Finished {
+            index,
+            total,
+            stage: &stage,
+        });
+        self.live.running = None;
+        self.live.stages.push(stage);
+        self.save_live();
+        value
+    }
+
+    fn block(&mut self, why: String) {
+        self.blocked.get_or_insert(why);
+    }
+
+    /// The verdict, also saved as `<scratch>/verdict.json`; `image` is
+    /// the kernel built or tested, for the history and events.
+    async fn finish(
+        self,
+        opts: &Options,
+        diff: Option<&Path>,
+        tree: Option<PathBuf>,
+        image: Option<&Path>,
+        committed: Vec<PathBuf>,
+    ) -> Result<Verdict> {
+        let now = history::now();
+        let runs = if opts.history.is_some() || opts.notify.is_some() {
+            let subject = Subject {
+                workspace: &opts.workspace,
+                arch: &opts.arch,
+                diff,
+                image,
+            };
+            history::runs(subject, &self.live.stages, now)
+        } else {
+            Vec::new()
+        };
+        if let Some(path) = &opts.history {
+            // Losing the history is no reason to lose the verdict.
+            if let Err(e) = history::append(path, &runs) {
+                tracing::warn!("not recording runs: {e:#}");
+            }
+        }
+        let mut verdict = Verdict::new(
+            diff.map(Path::to_path_buf),
+            opts.workspace.clone(),
+            tree,
+            self.live.stages,
+        );
+        verdict.committed = committed;
+        self.span.record("passed", verdict.passed);
+        let path = opts.scratch.join("verdict.json");
+        std::fs::write(&path, serde_json::to_string_pretty(&verdict)? + "\n")
+            .with_context(|| format!("writing {}", path.display()))?;
+        if let Some(notifier) = &opts.notify {
+            notifier
+                .send(&notify::events(self.name, &runs, &verdict, now))
+                .await;
+        }
+        Ok(verdict)
+    }
+}
+
+fn create_scratch(opts: &Options) -> Result<()> {
+    std::fs::create_dir_all(&opts.scratch)
+        .with_context(|| format!("creating {}", opts.scratch.display()))
+}
+
+/// Run every stage on `opts.diff`. Only setting up the scratch directory,
+/// or failing to commit a diff that passed, is an error; a stage that
+/// fails just fails the verdict.
+pub async fn verify(
+    opts: &Options,
+    progress: &mut (dyn FnMut(Progress) + Send),
+) -> Result<Verdict> {
+    create_scratch(opts)?;
+    let tree = opts.scratch.join("tree");
+    let mut pipeline = Pipeline::new("verify", &STAGES, &opts.scratch, progress);
+
+    let validated = pipeline.stage(validate_stage(opts)).await;
+    if validated.is_none() && !opts.keep_going {
+        pipeline.block("the diff failed validation".into());
+    }
+    let diff = validated.flatten().unwrap_or_else(|| opts.diff.clone());
+    let txn = pipeline.stage(apply_stage(opts, &diff, &tree)).await;
+    if txn.is_none() {
+        pipeline.block("the diff did not apply".into());
+    }
+    let image = pipeline.stage(build_stage(opts, &tree)).await;
+    if image.is_none() {
+        pipeline.block("the build failed".into());
+    }
+    let image = image.unwrap_or_default();
+    pipeline.stage(test_stage(opts, &image)).await;
+    let built = Some(image.as_path()).filter(|i| !i.as_os_str().is_empty());
+    let passed = pipeline
+        .live
+        .stages
+        .iter()
+        .all(|s| s.status != Status::Failed);
+    let committed = match txn {
+        Some(txn) if opts.commit && passed => txn.commit().await.with_context(|| {
+            format!(
+                "the diff passed but was not committed to {}",
+                opts.workspace.display()
+            )
+        })?,
+        _ => Vec::new(),
+    };
+    pipeline
+        .finish(opts, Some(&opts.diff), Some(tree), built, committed)
+        .await
+}
+
+/// What [`verify`] would run and write, without running it. The build and
+/// test stages are listed as the kernel-builder and test-runner commands,
+/// not the compilers and QEMU those would run: that depends on the
+/// patched tree, which a dry run does not make.
+pub async fn plan(opts: &Options) -> Result<Plan> {
+    let mut plan = Plan::default();
+    let (args, rebased) = validate_args(opts);
+    plan.run(Planned::new(
+        "validating the diff",
+        &arg(&opts.tools.validator),
+        &args,
+    ));
+    plan.writes.extend(rebased.clone());
+
+    let tree = opts.scratch.join("tree");
+    txn::plan(&opts.workspace, &tree, &mut plan).await?;
+    let diff = rebased.unwrap_or_else(|| opts.diff.clone());
+    let diff =
+        std::path::absolute(&diff).with_context(|| format!("resolving {}", diff.display()))?;
+    plan.run(Planned::new(
+        "applying the diff",
+        "patch",
+        &patch_args(opts, &tree, &diff),
+    ));
+
+    let out = opts.scratch.join("build");
+    plan.run(Planned::new(
+        format!("building {}", tree.display()),
+        &arg(&opts.tools.builder),
+        &build_args(opts, &tree),
+    ));
+    plan.write(&out);
+    if let Some(tests) = &opts.tests {
+        // The manifest a build would write is not there yet, so the image
+        // is the one a previous build named, else the usual name.
+        let image = BuildManifest::read(&out.join(MANIFEST_NAME))
+            .ok()
+            .and_then(|m| m.boot_image())
+            .unwrap_or_else(|| out.join("kernel.elf"));
+        plan.run(Planned::new(
+            format!("testing {}", image.display()),
+            &arg(&opts.tools.runner),
+            &test_args(opts, tests, &image),
+        ));
+        plan.write(opts.scratch.join("suite"));
+        plan.write(opts.scratch.join("results"));
+    }
+
+    plan.write(opts.scratch.join(LIVE_NAME));
+    plan.write(opts.scratch.join("verdict.json"));
+    plan.writes.extend(opts.history.clone());
+    if opts.commit {
+        // If every stage passed: the original diff's paths, which a
+        // rebased one shares.
+        for path in patched_paths(&opts.diff)? {
+            let path = opts.workspace.join(path);
+            if !plan.writes.contains(&path) {
+                plan.write(path);
+            }
+        }
+    }
+    Ok(plan)
+}
+
+/// Just the `validate` stage.
+pub async fn validate(
+    opts: &Options,
+    progress: &mut (dyn FnMut(Progress) + Send),
+) -> Result<Verdict> {
+    create_scratch(opts)?;
+    let mut pipeline = Pipeline::new("validate", &["validate"], &opts.scratch, progress);
+    pipeline.stage(validate_stage(opts)).await;
+    pipeline
+        .finish(opts, Some(&opts.diff), None, None, Vec::new())
+        .await
+}
+
+/// Just the `build` stage, of the workspace itself.
+pub async fn build(opts: &Options, progress: &mut (dyn FnMut(Progress) + Send)) -> Result<Verdict> {
+    create_scratch(opts)?;
+    let mut pipeline = Pipeline::new("build", &["build"], &opts.scratch, progress);
+    let image = pipeline.stage(build_stage(opts, &opts.workspace)).await;
+    pipeline
+        .finish(opts, None, None, image.as_deref(), Vec::new())
+        .await
+}
+
+/// Just the `test` stage, against `kernel`.
+pub async fn test(
+    opts: &Options,
+    kernel: &Path,
+    progress: &mut (dyn FnMut(Progress) + Send),
+) -> Result<Verdict> {
+    create_scratch(opts)?;
+    let mut pipeline = Pipeline::new("test", &["test"], &opts.scratch, progress);
+    pipeline.stage(test_stage(opts, kernel)).await;
+    pipeline
+        .finish(opts, None, None, Some(kernel), Vec::new())
+        .await
+}
+
+pub(crate) fn arg(path: &Path) -> String {
+    path.display().to_string()
+}
+
+/// Run `program`, taking its stdout as a JSON report. The stage passes if
+/// the program exits 0.
+pub(crate) async fn run_tool(name: &str, program: &Path, args: Vec<String>) -> Stage {
+    let mut cmd = Command::new(program);
+    cmd.args(&args);
+    let mut stage = Stage {
+        command: std::iter::once(arg(program)).chain(args).collect(),
+        ..Stage::skipped(name, "")
+    };
+    stage.status = Status::Failed;
+    match process::run(cmd, None).await {
+        Err(e) => stage.summary = format!("{e:#}"),
+        Ok(output) => {
+            stage.report = serde_json::from_str(&output.stdout).ok();
+            if output.success() {
+                stage.status = Status::Passed;
+            } else {
+                stage.summary = format!("{} exited with {}", program.display(), output.status());
+                stage.log = output.stderr_tail(LOG_TAIL);
+            }
+        }
+    }
+    stage
+}
+
+/// diff-validator's arguments, and where it writes the rebased diff when
+/// merging.
+fn validate_args(opts: &Options) -> (Vec<String>, Option<PathBuf>) {
+    let rebased = opts.merge.then(|| opts.scratch.join("rebased.diff"));
+    let mut args = vec![
+        "-i".to_string(),
+        arg(&opts.diff),
+        "-w".to_string(),
+        arg(&opts.workspace),
+        "--fuzz".to_string(),
+        opts.fuzz.to_string(),
+        "--format".to_string(),
+        "json".to_string(),
+    ];
+    if let Some(rebased) = &rebased {
+        args.extend(["--merge".to_string(), "--rebased".to_string(), arg(rebased)]);
+    }
+    args.extend(opts.validate_args.iter().cloned());
+    (args, rebased)
+}
+
+/// The rebased diff, when merging.
+async fn validate_stage(opts: &Options) -> (Stage, Option<Option<PathBuf>>) {
+    let (args, rebased) = validate_args(opts);
+    let mut stage = run_tool("validate", &opts.tools.validator, args).await;
+    if let Some(counts) = stage.report.as_ref().and_then(findings_summary) {
+        stage.summary = counts;
+    }
+    let passed = stage.status == Status::Passed;
+    (stage, passed.then_some(rebased))
+}
+
+/// `3 errors, 1 warning, 0 notes` from diff-validator's JSON: its findings,
+/// or an object holding them.
+pub fn findings_summary(report: &Value) -> Option<String> {
+    let findings = report
+        .as_array()
+        .or_else(|| report.get("findings")?.as_array())?;
+    let count = |severity: &str| {
+        findings
+            .iter()
+            .filter(|f| f["severity"] == severity)
+            .count()
+    };
+    Some(format!(
+        "{}, {}, {}",
+        plural(count("error"), "error"),
+        plural(count("warning"), "warning"),
+        plural(count("info"), "note")
+    ))
+}
+
+/// The last [`LOG_TAIL`] lines of `text`.
+fn tail(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    lines[lines.len().saturating_sub(LOG_TAIL)..].join("\n")
+}
+
+pub fn plural(n: usize, what: &str) -> String {
+    format!("{n} {what}{}", if n == 1 { "" } else { "s" })
+}
+
+/// The transaction whose view has the diff applied; a failed one is left
+/// for inspection.
+async fn apply_stage(opts: &Options, diff: &Path, tree: &Path) -> (Stage, Option<Transaction>) {
+    let mut stage = Stage::skipped("apply", "");
+    stage.status = Status::Failed;
+    let mut txn = match Transaction::begin(&opts.workspace, tree).await {
+        Ok(txn) => txn,
+        Err(e) => {
+            stage.summary = format!("{e:#}");
+            return (stage, None);
+        }
+    };
+    if opts.commit {
+        if let Err(e) = touch_patched(&mut txn, diff) {
+            stage.summary = format!("{e:#}");
+            return (stage, None);
+        }
+    }
+    let diff = match std::path::absolute(diff) {
+        Ok(diff) => diff,
+        Err(e) => {
+            stage.summary = format!("resolving {}: {e}", diff.display());
+            return (stage, None);
+        }
+    };
+    let args = patch_args(opts, tree, &diff);
+    let mut cmd = Command::new("patch");
+    cmd.args(&args);
+    stage.command = std::iter::once("patch".to_string()).chain(args).collect();
+    match process::run(cmd, None).await {
+        Err(e) => stage.summary = format!("{e:#}"),
+        Ok(output) if output.success() => {
+            let files = output
+                .stdout
+                .lines()
+                .filter(|l| l.starts_with("patching file "))
+                .count();
+            stage.status = Status::Passed;
+            stage.summary = format!("patched {} in {}", plural(files, "file"), tree.display());
+            return (stage, Some(txn));
+        }
+        Ok(output) => {
+            stage.summary = format!("patch exited with {}", output.status());
+            // patch reports rejected hunks on stdout.
+            stage.log = tail(&(output.stdout + &output.stderr));
+        }
+    }
+    (stage, None)
+}
+
+/// `patch`'s arguments to apply `diff`, an absolute path, in `tree`.
+fn patch_args(opts: &Options, tree: &Path, diff: &Path) -> Vec<String> {
+    vec![
+        "-p1".to_string(),
+        "--batch".to_string(),
+        "--forward".to_string(),
+        format!("--fuzz={}", opts.fuzz),
+        "-d".to_string(),
+        arg(tree),
+        "-i".to_string(),
+        arg(diff),
+    ]
+}
+
+/// The paths `diff` adds, removes or changes, relative to the workspace.
+fn patched_paths(diff: &Path) -> Result<Vec<PathBuf>> {
+    let text =
+        std::fs::read_to_string(diff).with_context(|| format!("reading {}", diff.display()))?;
+    let patches = diff::parse(&text).with_context(|| format!("parsing {}", diff.display()))?;
+    Ok(patches
+        .iter()
+        .flat_map(|p| p.old_path.iter().chain(&p.new_path))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Mark every path `diff` adds, removes or changes for the commit.
+fn touch_patched(txn: &mut Transaction, diff: &Path) -> Result<()> {
+    for path in patched_paths(diff)? {
+        txn.touch(&path)?;
+    }
+    Ok(())
+}
+
+/// kernel-builder's arguments to build `tree`.
+fn build_args(opts: &Options, tree: &Path) -> Vec<String> {
+    let out = opts.scratch.join("build");
+    let mut args = vec![
+        "-w".to_string(),
+        arg(tree),
+        "-a".to_string(),
+        opts.arch.clone(),
+        "-o".to_string(),
+        arg(&out),
+        "--json".to_string(),
+    ];
+    args.extend(opts.build_args.iter().cloned());
+    args
+}
+
+/// The image to boot.
+pub(crate) async fn build_stage(opts: &Options, tree: &Path) -> (Stage, Option<PathBuf>) {
+    let out = opts.scratch.join("build");
+    let args = build_args(opts, tree);
+    let mut stage = run_tool("build", &opts.tools.builder, args).await;
+    if stage.status != Status::Passed {
+        // With --json, the compiler's output is in the report.
+        let stderr = stage.report.as_ref().and_then(|r| r["stderr"].as_str());
+        if let Some(stderr) = stderr.filter(|s| !s.trim().is_empty()) {
+            stage.log = tail(stderr);
+        }
+        return (stage, None);
+    }
+    let manifest = out.join(MANIFEST_NAME);
+    let image = BuildManifest::read(&manifest).and_then(|m| {
+        m.boot_image()
+            .with_context(|| format!("{} names no kernel or image", manifest.display()))
+    });
+    match image {
+        Ok(image) => {
+            stage.summary = format!("built {}", image.display());
+            (stage, Some(image))
+        }
+        Err(e) => {
+            stage.status = Status::Failed;
+            stage.summary = format!("{e:#}");
+            (stage, None)
+        }
+    }
+}
+
+/// test-runner's arguments to run the `tests` suite against `image`.
+fn test_args(opts: &Options, tests: &Path, image: &Path) -> Vec<String> {
+    let mut args = vec![
+        "suite".to_string(),
+        arg(tests),
+        "--kernel".to_string(),
+        arg(image),
+        "--json".to_string(),
+        "--build-dir".to_string(),
+        arg(&opts.scratch.join("suite")),
+        "--results-dir".to_string(),
+        arg(&opts.scratch.join("results")),
+    ];
+    args.extend(opts.test_args.iter().cloned());
+    args
+}
+
+async fn test_stage(opts: &Options, image: &Path) -> (Stage, Option<()>) {
+    let Some(tests) = &opts.tests else {
+        return (Stage::skipped("test", "no test suite given"), None);
+    };
+    let args = test_args(opts, tests, image);
+    let mut stage = run_tool("test", &opts.tools.runner, args).await;
+    if let Some(counts) = stage.report.as_ref().and_then(tests_summary) {
+        stage.summary = counts;
+    }
+    let passed = stage.status == Status::Passed;
+    (stage, passed.then_some(()))
+}
+
+/// `3 of 4 tests passed` from test-runner's suite JSON.
+pub fn tests_summary(report: &Value) -> Option<String> {
+    let outcomes = report.as_array()?;
+    let passed = outcomes.iter().filter(|t| t["passed"] == true).count();
+    Some(format!(
+        "{passed} of {} passed",
+        plural(outcomes.len(), "test")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn summaries_count_the_tools_reports() {
+        let findings = json!([
+            {"severity": "error", "rule": "banned-function"},
+            {"severity": "warning", "rule": "unused-variable"},
+            {"severity": "warning", "rule": "unused-variable"},
+        ]);
+        assert_eq!(
+            findings_summary(&findings).unwrap(),
+            "1 error, 2 warnings, 0 notes"
+        );
+        let with_risk = json!({"findings": [], "risk": {"score": 3}});
+        assert_eq!(
+            findings_summary(&with_risk).unwrap(),
+            "0 errors, 0 warnings, 0 notes"
+        );
+        assert_eq!(findings_summary(&json!({"usage": "-i"})), None);
+
+        let outcomes = json!([{"name": "boot", "passed": true}, {"name": "net", "passed": false}]);
+        assert_eq!(tests_summary(&outcomes).unwrap(), "1 of 2 tests passed");
+    }
+}
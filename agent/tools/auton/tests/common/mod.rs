@@ -0,0 +1,136 @@
+//! Helpers shared by the integration tests.
+
+// Each test file uses some of these.
+#![allow(dead_code)]
+
+use auton::verify::{Options, Tools};
+use serde_json::Value;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+/// An empty scratch directory for the test `name`, with `ws` and `tools`
+/// directories in it.
+pub fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("auton-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("ws")).unwrap();
+    std::fs::create_dir_all(dir.join("tools")).unwrap();
+    dir
+}
+
+/// Write `text` to `path` as an executable script.
+pub fn script(path: &Path, text: &str) {
+    std::fs::write(path, text).unwrap();
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// Pipeline options for the [`scratch`] directory `dir`: its `ws`, the
+/// stand-in tools in its `tools`, and no diff, tests, fuzzing or extras.
+pub fn options(dir: &Path) -> Options {
+    Options {
+        diff: PathBuf::new(),
+        workspace: dir.join("ws"),
+        arch: "x86_64".into(),
+        tests: None,
+        scratch: dir.join("scratch"),
+        merge: false,
+        fuzz: 0,
+        keep_going: false,
+        tools: Tools::in_dir(&dir.join("tools")),
+        validate_args: Vec::new(),
+        build_args: Vec::new(),
+        test_args: Vec::new(),
+        history: None,
+        notify: None,
+        commit: false,
+    }
+}
+
+/// `git -C dir args…` as a test user; its stdout without the final newline.
+pub fn git(dir: &Path, args: &[&str]) -> String {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["-c", "user.name=t", "-c", "user.email=t@example.com"])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "git {args:?}: {output:?}");
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .trim_end()
+        .to_string()
+}
+
+/// An HTTP request as received: its request line, headers (names
+/// lowercased) and body.
+pub struct Request {
+    pub line: String,
+    pub headers: Vec<(String, String)>,
+    pub raw: String,
+    pub body: Value,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_ascii_lowercase();
+        self.headers
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// An HTTP receiver on a loopback port: its port, and the requests it gets
+/// with JSON bodies. The `n`th request (from 0) is answered with
+/// `status(n)`, once handed over, so the sender sees it on return.
+pub async fn receiver(status: fn(usize) -> &'static str) -> (u16, UnboundedReceiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (requests, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        for n in 0.. {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut data = Vec::new();
+            let mut buf = [0u8; 4096];
+            let (head, body) = loop {
+                let read = stream.read(&mut buf).await.unwrap();
+                data.extend_from_slice(&buf[..read]);
+                let text = String::from_utf8_lossy(&data).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length: usize = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("Content-Length: "))
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    if body.len() >= length {
+                        break (head.to_string(), body.to_string());
+                    }
+                }
+            };
+            let mut lines = head.lines();
+            let line = lines.next().unwrap_or_default().to_string();
+            let headers = lines
+                .filter_map(|l| l.split_once(": "))
+                .map(|(n, v)| (n.to_ascii_lowercase(), v.to_string()))
+                .collect();
+            let _ = requests.send(Request {
+                line,
+                headers,
+                body: serde_json::from_str(&body).unwrap(),
+                raw: body,
+            });
+            stream
+                .write_all(
+                    format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status(n)).as_bytes(),
+                )
+                .await
+                .unwrap();
+        }
+    });
+    (port, received)
+}
@@ -0,0 +1,136 @@
+//! Integration tests for `auton bisect`, over a real git history with
+//! stand-in tools.
+
+mod common;
+
+use auton::bisect::{self, Bisect, Mark};
+use auton::verify::Options;
+use common::{git, options, scratch, script};
+use std::path::PathBuf;
+
+/// Builds `kernel/main.c` as the image, failing on `NOBUILD`.
+const BUILDER: &str = r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -w) tree=$2; shift ;;
+        -o) out=$2; shift ;;
+    esac
+    shift
+done
+grep -q NOBUILD "$tree/kernel/main.c" && { echo '{"success": false}'; exit 1; }
+mkdir -p "$out"
+echo build >> "$(dirname "$0")/builds"
+cp "$tree/kernel/main.c" "$out/kernel.bin"
+echo "{\"kernel\": \"$out/kernel.bin\"}" > "$out/manifest.json"
+echo '{"success": true}'
+"#;
+
+/// Fails a kernel with `BUG` in it.
+const RUNNER: &str = r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+    case "$1" in
+        --kernel) kernel=$2; shift ;;
+    esac
+    shift
+done
+if grep -q BUG "$kernel"; then
+    echo '{"exit_reason": {"kind": "panic"}}'
+    exit 1
+fi
+echo '{"exit_reason": {"kind": "pattern-matched"}}'
+"#;
+
+/// A repository whose `kernels/x86_64` workspace gets one commit per
+/// `main.c` in `versions`, with an unrelated commit between each; the
+/// first commit's sha, and options for bisecting it.
+fn setup(name: &str, versions: &[&str]) -> (PathBuf, String, Options) {
+    let dir = scratch(name);
+    let repo = dir.join("repo");
+    let ws = repo.join("kernels/x86_64");
+    let tools = dir.join("tools");
+    std::fs::create_dir_all(ws.join("kernel")).unwrap();
+    script(&tools.join("kernel-builder"), BUILDER);
+    script(&tools.join("test-runner"), RUNNER);
+    git(&repo, &["init", "-q"]);
+    std::fs::write(ws.join("kernel/main.c"), "void kmain(void) {}\n").unwrap();
+    git(&repo, &["add", "-A"]);
+    git(&repo, &["commit", "-qm", "good"]);
+    let good = git(&repo, &["rev-parse", "HEAD"]);
+    for (n, version) in versions.iter().enumerate() {
+        std::fs::write(ws.join("kernel/main.c"), version).unwrap();
+        git(&repo, &["commit", "-qam", &format!("change {n}")]);
+        std::fs::write(repo.join("README"), n.to_string()).unwrap();
+        git(&repo, &["add", "README"]);
+        git(&repo, &["commit", "-qm", &format!("docs {n}")]);
+    }
+    let opts = Options {
+        workspace: ws,
+        ..options(&dir)
+    };
+    (dir, good, opts)
+}
+
+fn bisecting(good: &str, skip_broken: bool) -> Bisect {
+    Bisect {
+        range: format!("{good}..HEAD").parse().unwrap(),
+        spec: PathBuf::from("boot.toml"),
+        skip_broken,
+    }
+}
+
+#[tokio::test]
+async fn finds_the_commit_that_broke_the_test() {
+    let versions = ["a", "b", "c", "d BUG", "e BUG", "f BUG", "g BUG"];
+    let (dir, good, opts) = setup("bisect", &versions);
+    let mut seen = Vec::new();
+    let bisection = bisect::bisect(&opts, &bisecting(&good, false), &mut |step| {
+        seen.push(step.commit.subject.clone())
+    })
+    .await
+    .unwrap();
+
+    // Only the workspace's commits are candidates.
+    assert_eq!(bisection.commits, 7);
+    assert_eq!(bisection.first_bad.unwrap().subject, "change 3");
+    assert!(bisection.suspects.is_empty());
+    assert_eq!(seen[0], "change 6");
+    assert!(seen.len() <= 4, "{seen:?}");
+    assert_eq!(bisection.steps[0].stages[1].summary, "failed (panic)");
+    // The repository was left as it was.
+    assert_eq!(git(&dir.join("repo"), &["status", "--porcelain"]), "");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn broken_builds_are_bad_or_skipped() {
+    let versions = ["a", "NOBUILD", "c BUG", "d BUG"];
+    let (dir, good, opts) = setup("bisect-skip", &versions);
+    let bisection = bisect::bisect(&opts, &bisecting(&good, false), &mut |_| {})
+        .await
+        .unwrap();
+    assert_eq!(bisection.first_bad.unwrap().subject, "change 1");
+
+    let bisection = bisect::bisect(&opts, &bisecting(&good, true), &mut |_| {})
+        .await
+        .unwrap();
+    assert_eq!(bisection.first_bad, None);
+    let suspects: Vec<&str> = bisection
+        .suspects
+        .iter()
+        .map(|c| c.subject.as_str())
+        .collect();
+    assert_eq!(suspects, ["change 1", "change 2"]);
+    assert!(bisection.steps.iter().any(|s| s.mark == Mark::Skip));
+
+    // A range whose end passes is not bisected.
+    let (dir2, good, opts) = setup("bisect-pass", &["a", "b"]);
+    let error = bisect::bisect(&opts, &bisecting(&good, false), &mut |_| {})
+        .await
+        .unwrap_err();
+    assert!(
+        error.to_string().contains("the test passes there"),
+        "{error}"
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_dir_all(dir2).unwrap();
+}
@@ -0,0 +1,108 @@
+//! Integration tests for pipeline events, with a stand-in kernel-builder
+//! and a webhook receiver on loopback.
+
+mod common;
+
+use auton::notify::{self, Notifier, NotifyConfig, PipeConfig, WebhookConfig};
+use auton::verify::{self, Options};
+use common::{options, receiver, scratch, script};
+use serde_json::Value;
+use std::os::unix::fs::FileTypeExt;
+use std::time::Duration;
+
+const BUILDER: &str = r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -o) out=$2; shift ;;
+    esac
+    shift
+done
+mkdir -p "$out"
+echo kernel > "$out/kernel.bin"
+echo "{\"kernel\": \"$out/kernel.bin\"}" > "$out/manifest.json"
+echo '{"success": true}'
+"#;
+
+#[tokio::test]
+async fn events_reach_webhooks_and_pipes() {
+    let dir = scratch("notify");
+    script(&dir.join("tools/kernel-builder"), BUILDER);
+    // The first delivery fails, to be retried.
+    let (port, mut requests) = receiver(|n| match n {
+        0 => "500 Internal Server Error",
+        _ => "204 No Content",
+    })
+    .await;
+
+    std::env::set_var("AUTON_NOTIFY_TEST_SECRET", "hunter2");
+    let log = dir.join("events.jsonl");
+    std::fs::write(&log, "").unwrap();
+    let fifo = dir.join("events.fifo");
+    let config = NotifyConfig {
+        webhook: vec![WebhookConfig {
+            url: format!("http://127.0.0.1:{port}/hook"),
+            secret_env: Some("AUTON_NOTIFY_TEST_SECRET".into()),
+            backoff_ms: Some(10),
+            ..WebhookConfig::default()
+        }],
+        pipe: vec![
+            PipeConfig {
+                path: log.clone(),
+                events: vec![notify::Kind::PipelineFinished],
+            },
+            // Missing, so made a FIFO; no one reads it.
+            PipeConfig {
+                path: fifo.clone(),
+                events: Vec::new(),
+            },
+        ],
+    };
+    let opts = Options {
+        fuzz: 2,
+        notify: Some(Notifier::new(config).unwrap()),
+        ..options(&dir)
+    };
+    let verdict = tokio::time::timeout(Duration::from_secs(10), verify::build(&opts, &mut |_| {}))
+        .await
+        .expect("delivery stalled the pipeline")
+        .unwrap();
+    assert!(verdict.passed);
+
+    // The first attempt failed and was retried, with the same body.
+    let mut received = Vec::new();
+    while let Ok(request) = requests.try_recv() {
+        received.push(request);
+    }
+    let kinds: Vec<&str> = received
+        .iter()
+        .map(|r| r.header("X-Auton-Event").unwrap())
+        .collect();
+    assert_eq!(
+        kinds,
+        ["build-finished", "build-finished", "pipeline-finished"]
+    );
+    assert_eq!(received[0].body, received[1].body);
+    let build = &received[1];
+    assert_eq!(build.body["event"], "build-finished");
+    assert_eq!(build.body["passed"], true);
+    assert_eq!(build.body["artifact"].as_str().unwrap().len(), 64);
+    assert_eq!(
+        build.header("X-Auton-Delivery").unwrap(),
+        build.body["id"].as_str().unwrap()
+    );
+    assert_eq!(
+        build.header("X-Auton-Signature").unwrap(),
+        notify::signature(b"hunter2", build.raw.as_bytes())
+    );
+
+    let lines: Vec<Value> = std::fs::read_to_string(&log)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["event"], "pipeline-finished");
+    assert_eq!(lines[0]["stage"], "build");
+    assert!(std::fs::metadata(&fifo).unwrap().file_type().is_fifo());
+    std::fs::remove_dir_all(dir).unwrap();
+}
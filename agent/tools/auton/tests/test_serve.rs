@@ -0,0 +1,401 @@
+//! Integration tests for `auton serve`, over a Unix socket with stand-in
+//! tools.
+
+mod common;
+
+use auton::metrics;
+use auton::serve::{self, JobParams, Listen, Operation, Recovery, Server, State};
+use auton::session::Session;
+use auton::verify::Options;
+use common::{options, scratch, script};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::mpsc;
+
+const DIFF: &str = "\
+--- a/kernel/main.c
++++ b/kernel/main.c
+@@ -1,2 +1,3 @@
+ void kmain(void)
++{}
+ ;
+";
+
+/// A workspace, a diff and stand-in tools, with `builder` as
+/// kernel-builder; the server options for them.
+fn setup(name: &str, builder: &str) -> (PathBuf, Options) {
+    let dir = scratch(name);
+    let tools = dir.join("tools");
+    std::fs::create_dir_all(dir.join("ws/kernel")).unwrap();
+    std::fs::write(dir.join("ws/kernel/main.c"), "void kmain(void)\n;\n").unwrap();
+    std::fs::write(dir.join("change.diff"), DIFF).unwrap();
+    script(&tools.join("diff-validator"), "#!/bin/sh\necho '[]'\n");
+    script(&tools.join("kernel-builder"), builder);
+    let base = Options {
+        fuzz: 2,
+        ..options(&dir)
+    };
+    (dir, base)
+}
+
+/// A server on `<dir>/auton.sock` whose kernel-builder is `builder`.
+async fn start(name: &str, builder: &str) -> (PathBuf, Client) {
+    let (dir, base) = setup(name, builder);
+    let socket = dir.join("auton.sock");
+    let listen = Listen::Unix(socket.clone());
+    tokio::spawn(async move { serve::serve(Server::new(base, 1), &listen).await });
+    let started = Instant::now();
+    let stream = loop {
+        match UnixStream::connect(&socket).await {
+            Ok(stream) => break stream,
+            Err(_) if started.elapsed() < Duration::from_secs(5) => {
+                tokio::time::sleep(Duration::from_millis(10)).await
+            }
+            Err(e) => panic!("connecting: {e}"),
+        }
+    };
+    let (read, write) = stream.into_split();
+    let client = Client {
+        lines: BufReader::new(read).lines(),
+        write,
+        next: 0,
+        seen: Vec::new(),
+    };
+    (dir, client)
+}
+
+struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    write: OwnedWriteHalf,
+    next: u64,
+    /// Notifications read while looking for a response.
+    seen: Vec<Value>,
+}
+
+impl Client {
+    async fn send(&mut self, method: &str, params: Value) -> u64 {
+        self.next += 1;
+        let request =
+            json!({"jsonrpc": "2.0", "id": self.next, "method": method, "params": params});
+        self.write
+            .write_all(format!("{request}\n").as_bytes())
+            .await
+            .unwrap();
+        self.next
+    }
+
+    async fn message(&mut self) -> Value {
+        let line = tokio::time::timeout(Duration::from_secs(10), self.lines.next_line())
+            .await
+            .expect("no message within 10s")
+            .unwrap()
+            .unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    /// The response to request `id`, with the notifications that came
+    /// before it.
+    async fn response(&mut self, id: u64) -> (Value, Vec<Value>) {
+        let mut notifications = Vec::new();
+        loop {
+            let message = self.message().await;
+            if message["id"] == id {
+                self.seen.extend(notifications.iter().cloned());
+                return (message, notifications);
+            }
+            notifications.push(message);
+        }
+    }
+
+    /// Wait for job `job`'s first stage to start.
+    async fn started(&mut self, job: u64) {
+        let is_start = |n: &Value| n["params"]["job"] == job && n["params"]["event"] == "started";
+        if self.seen.iter().any(is_start) {
+            return;
+        }
+        while !is_start(&self.message().await) {}
+    }
+}
+
+const BUILDER: &str = r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -o) out=$2; shift ;;
+    esac
+    shift
+done
+mkdir -p "$out"
+echo "{\"kernel\": \"$out/kernel.bin\"}" > "$out/manifest.json"
+echo '{"success": true}'
+"#;
+
+#[tokio::test]
+async fn jobs_stream_progress_and_end_with_a_verdict() {
+    let (dir, mut client) = start("serve-verify", BUILDER).await;
+    let diff = dir.join("change.diff");
+    let id = client.send("verify", json!({"diff": diff})).await;
+    let (response, _) = client.response(id).await;
+    assert_eq!(response["result"], json!({"job": 1}));
+
+    let id = client.send("wait", json!({"job": 1})).await;
+    let (response, notifications) = client.response(id).await;
+    let status = &response["result"];
+    assert_eq!(status["state"], "passed");
+    assert_eq!(status["verdict"]["passed"], true);
+    assert_eq!(status["stages"][3]["summary"], "no test suite given");
+
+    let events: Vec<String> = notifications
+        .iter()
+        .map(|n| match n["method"].as_str().unwrap() {
+            "progress" => format!("{} {}", n["params"]["event"], n["params"]["name"]),
+            method => format!("{method} {}", n["params"]["state"]),
+        })
+        .collect();
+    assert_eq!(
+        events,
+        [
+            r#""started" "validate""#,
+            r#""finished" "validate""#,
+            r#""started" "apply""#,
+            r#""finished" "apply""#,
+            r#""started" "build""#,
+            r#""finished" "build""#,
+            r#""started" "test""#,
+            r#""finished" "test""#,
+            r#"done "passed""#,
+        ]
+    );
+    assert!(dir.join("scratch/jobs/1/verdict.json").is_file());
+
+    let id = client.send("jobs", Value::Null).await;
+    let (response, _) = client.response(id).await;
+    assert_eq!(response["result"][0]["job"], 1);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn cancelling_a_job_stops_its_tool() {
+    let (dir, mut client) = start("serve-cancel", "#!/bin/sh\nexec sleep 30\n").await;
+    let id = client.send("build", json!({})).await;
+    client.response(id).await;
+    // A second job queues behind the first.
+    let id = client.send("build", json!({})).await;
+    client.response(id).await;
+
+    client.started(1).await;
+    let started = Instant::now();
+    let id = client.send("status", json!({"job": 2})).await;
+    let (response, _) = client.response(id).await;
+    assert_eq!(response["result"]["state"], "queued");
+    let id = client.send("cancel", json!({"job": 1})).await;
+    let (response, _) = client.response(id).await;
+    assert_eq!(response["result"]["state"], "cancelled");
+    client.started(2).await;
+    let id = client.send("status", json!({"job": 2})).await;
+    let (response, _) = client.response(id).await;
+    assert_eq!(response["result"]["state"], "running");
+    let id = client.send("cancel", json!({"job": 2})).await;
+    let (response, _) = client.response(id).await;
+    assert_eq!(response["result"]["state"], "cancelled");
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // Cancelling an ended job changes nothing.
+    let id = client.send("cancel", json!({"job": 1})).await;
+    let (response, _) = client.response(id).await;
+    assert_eq!(response["result"]["state"], "cancelled");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn metrics_count_what_jobs_did() {
+    let builder = BUILDER.replace(
+        r#"{"success": true}"#,
+        r#"{"success": true, "cache": {"hits": 3, "misses": 1, "rebuilt": 1}}"#,
+    );
+    let (dir, base) = setup("serve-metrics", &builder);
+    let server = Server::new(base, 1);
+    let (outbox, _inbox) = mpsc::unbounded_channel();
+    let id = server
+        .start(Operation::Build, JobParams::default(), outbox)
+        .unwrap();
+    assert_eq!(server.wait(id).await.unwrap().state, State::Passed);
+
+    // Free a port for the endpoint to take.
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let metrics_server = Arc::clone(&server);
+    tokio::spawn(async move { metrics::serve(metrics_server, &addr.to_string()).await });
+    let scrape = |path: &'static str| async move {
+        let started = Instant::now();
+        let mut stream = loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(_) if started.elapsed() < Duration::from_secs(5) => {
+                    tokio::time::sleep(Duration::from_millis(10)).await
+                }
+                Err(e) => panic!("connecting: {e}"),
+            }
+        };
+        let request = format!("GET {path} HTTP/1.1\r\nHost: auton\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    };
+
+    let response = scrape("/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    for line in [
+        "auton_builds_total{result=\"passed\"} 1",
+        "auton_build_cache_hit_ratio 0.75",
+        "auton_jobs_finished_total{operation=\"build\",state=\"passed\"} 1",
+        "auton_jobs{state=\"running\"} 0",
+    ] {
+        assert!(
+            response.lines().any(|l| l == line),
+            "no `{line}` in\n{response}"
+        );
+    }
+    assert!(scrape("/").await.starts_with("HTTP/1.1 404"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_sessions_jobs_take_turns_on_its_worktree() {
+    // Records its arguments, then holds on while the marker is there.
+    let builder = BUILDER.replacen(
+        "\n",
+        "\necho \"$@\" >> \"$(dirname \"$0\")/builds\"\n\
+         while [ -e \"$(dirname \"$0\")/hold\" ]; do sleep 0.05; done\n",
+        1,
+    );
+    let (dir, mut base) = setup("serve-session", &builder);
+    let repo = dir.join("ws");
+    let status = std::process::Command::new("sh")
+        .args([
+            "-c",
+            "git init -q && git add -A && git -c user.name=t -c user.email=t@t commit -q -m base",
+        ])
+        .current_dir(&repo)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let sessions = auton::session::root(&base.scratch);
+    let a = Session::open(&sessions, "a", &repo, None, None)
+        .await
+        .unwrap();
+    Session::open(&sessions, "b", &repo, None, None)
+        .await
+        .unwrap();
+    base.workspace = PathBuf::from("/nonexistent");
+    let server = Server::new(base, 3);
+    std::fs::write(dir.join("tools/hold"), "").unwrap();
+
+    let (outbox, _inbox) = mpsc::unbounded_channel();
+    let start = |session: &str| {
+        let params = JobParams {
+            session: Some(session.into()),
+            ..JobParams::default()
+        };
+        server
+            .start(Operation::Build, params, outbox.clone())
+            .unwrap()
+    };
+    let (first, second, other) = (start("a"), start("a"), start("b"));
+    let started = Instant::now();
+    while [first, other].map(|id| server.status(id).unwrap().state) != [State::Running; 2] {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // A free slot, but session `a` is busy.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(server.status(second).unwrap().state, State::Queued);
+    std::fs::remove_file(dir.join("tools/hold")).unwrap();
+    for id in [first, second, other] {
+        assert_eq!(server.wait(id).await.unwrap().state, State::Passed);
+    }
+
+    let builds = std::fs::read_to_string(dir.join("tools/builds")).unwrap();
+    let ws = format!("-w {}", a.workspace().display());
+    let cache = format!("--cache-dir {}", a.cache().display());
+    assert_eq!(
+        builds
+            .lines()
+            .filter(|l| l.contains(&ws) && l.contains(&cache))
+            .count(),
+        2,
+        "{builds}"
+    );
+    let unknown = JobParams {
+        session: Some("c".into()),
+        ..JobParams::default()
+    };
+    assert!(server.start(Operation::Build, unknown, outbox).is_err());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn jobs_survive_a_server_crash() {
+    // Slow until the marker goes.
+    let builder = BUILDER.replacen(
+        "\n",
+        "\n[ -e \"$(dirname \"$0\")/slow\" ] && exec sleep 30\n",
+        1,
+    );
+    let (dir, base) = setup("serve-crash", &builder);
+    std::fs::write(dir.join("tools/slow"), "").unwrap();
+    let build = |id: u64| format!(r#"{{"jsonrpc": "2.0", "id": {id}, "method": "build"}}"#);
+    let (outbox, _messages) = mpsc::unbounded_channel();
+
+    let first = tokio::runtime::Runtime::new().unwrap();
+    first.block_on(async {
+        let server = Server::new(base.clone(), 1);
+        server.handle(&build(1), &outbox).unwrap();
+        server.handle(&build(2), &outbox).unwrap();
+        while server.status(1).unwrap().state != State::Running {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    });
+    // Gone without a chance to record how its jobs ended.
+    first.shutdown_background();
+    std::fs::remove_file(dir.join("tools/slow")).unwrap();
+
+    let second = tokio::runtime::Runtime::new().unwrap();
+    second.block_on(async {
+        let server = Server::new(base.clone(), 1);
+        assert_eq!(
+            server.recover(false).unwrap(),
+            Recovery {
+                requeued: vec![2],
+                unknown: vec![1]
+            }
+        );
+        let status = server.wait(2).await.unwrap();
+        assert_eq!(status.state, State::Passed);
+        assert_eq!(status.stages.len(), 1);
+        let status = server.status(1).unwrap();
+        assert_eq!(status.state, State::Unknown);
+        assert!(status.error.unwrap().contains("stopped"));
+
+        let response = server.handle(&build(3), &outbox).unwrap();
+        assert_eq!(response["result"]["job"], 3);
+        server.wait(3).await.unwrap();
+    });
+
+    // A third server finds everything ended.
+    let third = tokio::runtime::Runtime::new().unwrap();
+    third.block_on(async {
+        let server = Server::new(base, 1);
+        assert_eq!(server.recover(true).unwrap(), Recovery::default());
+        let states: Vec<State> = server.list().iter().map(|j| j.state).collect();
+        assert_eq!(states, [State::Unknown, State::Passed, State::Passed]);
+    });
+    std::fs::remove_dir_all(dir).unwrap();
+}
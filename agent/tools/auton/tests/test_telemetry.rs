@@ -0,0 +1,102 @@
+//! Integration tests for trace export, with a stand-in kernel-builder and
+//! an OTLP receiver on loopback.
+
+mod common;
+
+use auton::verify::{self, Options};
+use auton_core::telemetry::{self, SpanContext};
+use common::{options, receiver, scratch, script};
+use serde_json::Value;
+
+/// Keeps the trace context it was started with.
+const BUILDER: &str = r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -o) out=$2; shift ;;
+    esac
+    shift
+done
+mkdir -p "$out"
+echo "$TRACEPARENT" > "$out/traceparent"
+echo "{\"kernel\": \"$out/kernel.bin\"}" > "$out/manifest.json"
+echo '{"success": true}'
+"#;
+
+#[tokio::test]
+async fn a_pipeline_is_one_trace_with_its_tools() {
+    let dir = scratch("telemetry");
+    script(&dir.join("tools/kernel-builder"), BUILDER);
+    let (port, mut exports) = receiver(|_| "200 OK").await;
+    std::env::set_var(
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        format!("http://127.0.0.1:{port}"),
+    );
+    auton_core::logging::init();
+
+    let opts = Options {
+        fuzz: 2,
+        ..options(&dir)
+    };
+    let verdict = verify::build(&opts, &mut |_| {}).await.unwrap();
+    assert!(verdict.passed);
+    // Flushing blocks until the receiver has answered, so off this thread.
+    tokio::task::spawn_blocking(telemetry::flush).await.unwrap();
+
+    let mut received = Vec::new();
+    while let Ok(export) = exports.try_recv() {
+        assert!(
+            export.line.starts_with("POST /v1/traces "),
+            "{}",
+            export.line
+        );
+        for resource in export.body["resourceSpans"].as_array().unwrap() {
+            for scope in resource["scopeSpans"].as_array().unwrap() {
+                received.extend(scope["spans"].as_array().unwrap().iter().cloned());
+            }
+        }
+    }
+    let named = |name: &str| {
+        received
+            .iter()
+            .find(|s| s["name"] == name)
+            .unwrap_or_else(|| panic!("no `{name}` span in {received:#?}"))
+    };
+    let (pipeline, stage) = (named("pipeline"), named("build"));
+    assert!(pipeline.get("parentSpanId").is_none());
+    assert_eq!(stage["parentSpanId"], pipeline["spanId"]);
+    assert_eq!(stage["traceId"], pipeline["traceId"]);
+    let attribute = |span: &Value, key: &str| {
+        span["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|a| a["key"] == key)
+            .map(|a| a["value"].clone())
+    };
+    assert_eq!(
+        attribute(pipeline, "pipeline"),
+        Some(serde_json::json!({"stringValue": "build"}))
+    );
+    assert_eq!(
+        attribute(pipeline, "passed"),
+        Some(serde_json::json!({"boolValue": true}))
+    );
+    assert_eq!(
+        attribute(stage, "status"),
+        Some(serde_json::json!({"stringValue": "passed"}))
+    );
+
+    // kernel-builder was started inside the stage's span.
+    let traceparent = std::fs::read_to_string(dir.join("scratch/build/traceparent"));
+    let traceparent = traceparent.expect("the builder's output directory");
+    let parent = SpanContext::parse(&traceparent).unwrap();
+    assert_eq!(
+        auton_core::hash::hex(&parent.trace_id),
+        stage["traceId"].as_str().unwrap()
+    );
+    assert_eq!(
+        auton_core::hash::hex(&parent.span_id),
+        stage["spanId"].as_str().unwrap()
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,326 @@
+//! Integration tests for the `auton verify` pipeline, with stand-in tools.
+
+mod common;
+
+use auton::history::{self, Filter, Rate};
+use auton::verify::{self, Live, Options, Progress};
+use auton::Status;
+use common::{git, options, scratch, script};
+use std::path::PathBuf;
+
+const DIFF: &str = "\
+diff --git a/kernel/main.c b/kernel/main.c
+--- a/kernel/main.c
++++ b/kernel/main.c
+@@ -1,3 +1,4 @@
+ void kmain(void)
+ {
++\tconsole_init();
+ }
+";
+
+const BUILDER: &str = r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -w) tree=$2; shift ;;
+        -o) out=$2; shift ;;
+    esac
+    shift
+done
+mkdir -p "$out"
+cp "$tree/kernel/main.c" "$out/kernel.bin"
+echo "{\"kernel\": \"$out/kernel.bin\"}" > "$out/manifest.json"
+echo '{"success": true}'
+"#;
+
+const RUNNER: &str = r#"#!/bin/sh
+echo "$@" > "$(dirname "$0")/runner-args"
+echo '[{"name": "boot", "passed": true}, {"name": "net", "passed": true}]'
+"#;
+
+/// A workspace, the diff and stand-in tools; `validator` is the
+/// diff-validator script.
+fn setup(name: &str, validator: &str) -> (PathBuf, Options) {
+    let dir = scratch(name);
+    let tools = dir.join("tools");
+    std::fs::create_dir_all(dir.join("ws/kernel")).unwrap();
+    std::fs::create_dir_all(dir.join("specs")).unwrap();
+    std::fs::write(dir.join("ws/kernel/main.c"), "void kmain(void)\n{\n}\n").unwrap();
+    std::fs::write(dir.join("change.diff"), DIFF).unwrap();
+    script(&tools.join("diff-validator"), validator);
+    script(&tools.join("kernel-builder"), BUILDER);
+    script(&tools.join("test-runner"), RUNNER);
+    let opts = Options {
+        diff: dir.join("change.diff"),
+        tests: Some(dir.join("specs")),
+        fuzz: 2,
+        test_args: vec!["-j".into(), "1".into()],
+        ..options(&dir)
+    };
+    (dir, opts)
+}
+
+#[tokio::test]
+async fn a_good_diff_passes_every_stage() {
+    let validator = "#!/bin/sh\necho '[{\"severity\": \"warning\"}]'\n";
+    let (dir, opts) = setup("pass", validator);
+    let mut events = Vec::new();
+    let verdict = verify::verify(&opts, &mut |p| {
+        events.push(match p {
+            Progress::Started { index, .. } => format!("start {index}"),
+            Progress::Finished { index, stage, .. } => format!("{index} {:?}", stage.status),
+        })
+    })
+    .await
+    .unwrap();
+
+    assert!(verdict.passed);
+    let summaries: Vec<(&str, &str)> = verdict
+        .stages
+        .iter()
+        .map(|s| (s.name.as_str(), s.summary.as_str()))
+        .collect();
+    let image = dir.join("scratch/build/kernel.bin");
+    let tree = dir.join("scratch/tree");
+    assert_eq!(
+        summaries,
+        [
+            ("validate", "0 errors, 1 warning, 0 notes"),
+            (
+                "apply",
+                format!("patched 1 file in {}", tree.display()).as_str()
+            ),
+            ("build", format!("built {}", image.display()).as_str()),
+            ("test", "2 of 2 tests passed"),
+        ]
+    );
+    assert_eq!(
+        events,
+        [
+            "start 0", "0 Passed", "start 1", "1 Passed", "start 2", "2 Passed", "start 3",
+            "3 Passed"
+        ]
+    );
+
+    // The copy was patched and built; the workspace was left alone.
+    assert!(std::fs::read_to_string(&image)
+        .unwrap()
+        .contains("console_init"));
+    assert!(!std::fs::read_to_string(dir.join("ws/kernel/main.c"))
+        .unwrap()
+        .contains("console_init"));
+    let runner_args = std::fs::read_to_string(dir.join("tools/runner-args")).unwrap();
+    assert!(runner_args.starts_with(&format!(
+        "suite {} --kernel {} --json",
+        dir.join("specs").display(),
+        image.display()
+    )));
+    assert!(runner_args.trim_end().ends_with("-j 1"));
+
+    let saved: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("scratch/verdict.json")).unwrap())
+            .unwrap();
+    assert_eq!(saved["passed"], true);
+    assert_eq!(saved["stages"][3]["report"][1]["name"], "net");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn live_state_follows_the_stages() {
+    let (dir, opts) = setup("live", "#!/bin/sh\necho '[]'\n");
+    let mut seen = Vec::new();
+    let verdict = verify::verify(&opts, &mut |p| {
+        if let Progress::Started { name, .. } = p {
+            let live = Live::load(&opts.scratch).unwrap();
+            seen.push((live.running.unwrap_or_default(), live.stages.len()));
+            assert_eq!(name, verify::STAGES[live.stages.len()]);
+        }
+    })
+    .await
+    .unwrap();
+    // Each stage is marked running by the time it is reported as started.
+    let running: Vec<(&str, usize)> = seen.iter().map(|(s, n)| (s.as_str(), *n)).collect();
+    assert_eq!(
+        running,
+        [("validate", 0), ("apply", 1), ("build", 2), ("test", 3)]
+    );
+    let live = Live::load(&opts.scratch).unwrap();
+    assert!(live.finished());
+    assert_eq!(live.pipeline, "verify");
+    assert_eq!(live.run_id, auton_core::logging::run_id());
+    assert_eq!(live.stages, verdict.stages);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn failed_validation_skips_the_rest_unless_told_to_keep_going() {
+    let validator =
+        "#!/bin/sh\necho '[{\"severity\": \"error\"}]'\necho 'banned-function' >&2\nexit 1\n";
+    let (dir, mut opts) = setup("fail", validator);
+    let verdict = verify::verify(&opts, &mut |_| {}).await.unwrap();
+    assert!(!verdict.passed);
+    let statuses: Vec<Status> = verdict.stages.iter().map(|s| s.status).collect();
+    assert_eq!(
+        statuses,
+        [
+            Status::Failed,
+            Status::Skipped,
+            Status::Skipped,
+            Status::Skipped
+        ]
+    );
+    assert_eq!(verdict.stages[0].summary, "1 error, 0 warnings, 0 notes");
+    assert_eq!(verdict.stages[0].log, "banned-function");
+    assert_eq!(verdict.stages[2].summary, "the diff failed validation");
+
+    opts.keep_going = true;
+    opts.tests = None;
+    let verdict = verify::verify(&opts, &mut |_| {}).await.unwrap();
+    assert!(!verdict.passed);
+    let statuses: Vec<Status> = verdict.stages.iter().map(|s| s.status).collect();
+    assert_eq!(
+        statuses,
+        [
+            Status::Failed,
+            Status::Passed,
+            Status::Passed,
+            Status::Skipped
+        ]
+    );
+    assert_eq!(verdict.stages[3].summary, "no test suite given");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_diff_that_does_not_apply_is_not_built() {
+    let (dir, opts) = setup("stale", "#!/bin/sh\necho '[]'\n");
+    std::fs::write(dir.join("ws/kernel/main.c"), "int unrelated;\n").unwrap();
+    let verdict = verify::verify(&opts, &mut |_| {}).await.unwrap();
+    assert!(!verdict.passed);
+    assert_eq!(verdict.stages[1].status, Status::Failed);
+    assert!(
+        verdict.stages[1].log.contains("FAILED"),
+        "{}",
+        verdict.stages[1].log
+    );
+    assert_eq!(verdict.stages[2].summary, "the diff did not apply");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn runs_are_recorded_in_the_history() {
+    let validator =
+        "#!/bin/sh\necho '[{\"severity\": \"error\", \"rule\": \"banned-function\"}]'\nexit 1\n";
+    let (dir, mut opts) = setup("history", validator);
+    let path = dir.join("scratch/history.jsonl");
+    opts.history = Some(path.clone());
+    verify::verify(&opts, &mut |_| {}).await.unwrap();
+    opts.keep_going = true;
+    verify::verify(&opts, &mut |_| {}).await.unwrap();
+
+    let runs = history::load(&path).unwrap();
+    let got: Vec<(&str, Option<&str>, bool)> = runs
+        .iter()
+        .map(|r| (r.stage.as_str(), r.test.as_deref(), r.passed))
+        .collect();
+    assert_eq!(
+        got,
+        [
+            ("validate", None, false),
+            ("validate", None, false),
+            ("apply", None, true),
+            ("build", None, true),
+            ("test", Some("boot"), true),
+            ("test", Some("net"), true),
+        ]
+    );
+    // The diff's hash for validate, the image's for build.
+    let (_, diff_sha) = auton_core::manifest::hash_file(&dir.join("change.diff")).unwrap();
+    let (_, image_sha) =
+        auton_core::manifest::hash_file(&dir.join("scratch/build/kernel.bin")).unwrap();
+    assert_eq!(runs[0].artifact.as_ref(), Some(&diff_sha));
+    assert_eq!(runs[3].artifact.as_ref(), Some(&image_sha));
+
+    let validations = Filter {
+        stage: Some("validate".into()),
+        ..Filter::default()
+    };
+    let rate = Rate::of(&validations.apply(&runs, history::now()));
+    assert_eq!((rate.runs, rate.failed), (2, 2));
+    assert_eq!(rate.failures["banned-function"], 2);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_passing_diff_is_committed_from_a_worktree() {
+    let (dir, mut opts) = setup("commit", "#!/bin/sh\necho '[]'\n");
+    let ws = dir.join("ws");
+    git(&ws, &["init", "-q"]);
+    git(&ws, &["add", "-A"]);
+    git(&ws, &["commit", "-q", "-m", "base"]);
+    opts.commit = true;
+    let verdict = verify::verify(&opts, &mut |_| {}).await.unwrap();
+
+    assert!(verdict.passed);
+    assert_eq!(verdict.committed, [PathBuf::from("kernel/main.c")]);
+    assert!(std::fs::read_to_string(ws.join("kernel/main.c"))
+        .unwrap()
+        .contains("console_init"));
+    // The view was a worktree, and is gone with the transaction.
+    assert!(!dir.join("scratch/tree").exists());
+    assert!(!ws.join(auton::txn::TXN_DIR).exists());
+    assert_eq!(git(&ws, &["worktree", "list"]).lines().count(), 1);
+    assert_eq!(git(&ws, &["status", "--porcelain"]), " M kernel/main.c");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_failing_diff_is_not_committed() {
+    let (dir, mut opts) = setup("no-commit", "#!/bin/sh\necho '[]'\n");
+    script(&dir.join("tools/test-runner"), "#!/bin/sh\nexit 1\n");
+    opts.commit = true;
+    let verdict = verify::verify(&opts, &mut |_| {}).await.unwrap();
+
+    assert!(!verdict.passed);
+    assert!(verdict.committed.is_empty());
+    assert!(!std::fs::read_to_string(dir.join("ws/kernel/main.c"))
+        .unwrap()
+        .contains("console_init"));
+    // Left for inspection.
+    assert!(
+        std::fs::read_to_string(dir.join("scratch/tree/kernel/main.c"))
+            .unwrap()
+            .contains("console_init")
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_dry_run_lists_the_stages_and_runs_none() {
+    let (dir, mut opts) = setup("dry-run", "#!/bin/sh\nexit 1\n");
+    opts.commit = true;
+    let plan = verify::plan(&opts).await.unwrap();
+
+    let programs: Vec<&str> = plan.commands.iter().map(|c| c.program.as_str()).collect();
+    let tools = dir.join("tools");
+    let tool = |name: &str| tools.join(name).display().to_string();
+    assert_eq!(
+        programs,
+        [
+            tool("diff-validator").as_str(),
+            "cp",
+            "patch",
+            &tool("kernel-builder"),
+            &tool("test-runner"),
+        ]
+    );
+    let image = dir.join("scratch/build/kernel.elf").display().to_string();
+    assert!(plan.commands[4].args.contains(&image));
+    assert!(plan.writes.contains(&dir.join("scratch/verdict.json")));
+    assert!(plan.writes.contains(&dir.join("ws/kernel/main.c")));
+    assert!(
+        !opts.scratch.exists(),
+        "a dry run made the scratch directory"
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
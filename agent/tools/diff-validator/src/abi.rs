@@ -0,0 +1,120 @@
+//! ABI changes in the kernel a diff links to, from kernel-builder's
+//! [`symdiff`](kernel_builder::symdiff) of the image built before the diff
+//! and the one built after it (`--old-elf` and `--new-elf`):
+//!
+//! - `abi-export-removed`: a global or weak symbol is gone;
+//! - `abi-load-address-moved`: the lowest `PT_LOAD` physical address
+//!   changed, so bootloaders and the test harness load the kernel
+//!   somewhere else.
+//!
+//! Both are errors when the diff gives no sign of meaning them, and
+//! warnings when it does: for a removed export, a removed line naming the
+//! symbol (the finding points at it); for the load address, a linker
+//! script (`*.ld`, `*.lds`) among the files it touches. Either way the
+//! change is reported, so a reviewer sees it.
+
+use crate::patch::{FilePatch, HunkLine};
+use crate::{Finding, Severity};
+use kernel_builder::symdiff::SymDiff;
+
+pub fn check(files: &[FilePatch], delta: &SymDiff) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for symbol in delta.removed_exports() {
+        let (severity, file, line, message) = match removed_mention(files, &symbol.name) {
+            Some((file, line)) => (
+                Severity::Warning,
+                file,
+                line,
+                format!("exported symbol `{}` is removed", symbol.name),
+            ),
+            None => (
+                Severity::Error,
+                String::new(),
+                0,
+                format!(
+                    "exported symbol `{}` is gone from the linked kernel, though the diff removes no line naming it",
+                    symbol.name
+                ),
+            ),
+        };
+        findings.push(Finding {
+            severity,
+            file,
+            line,
+            rule: "abi-export-removed".into(),
+            message,
+            fix: None,
+        });
+    }
+    if let Some(moved) = delta.load_address {
+        let script = files.iter().find(|f| is_linker_script(f.path()));
+        let message = format!(
+            "the kernel's load address moves from {:#x} to {:#x}",
+            moved.before, moved.after
+        );
+        findings.push(match script {
+            Some(f) => Finding {
+                severity: Severity::Warning,
+                file: f.path().to_string(),
+                line: 0,
+                rule: "abi-load-address-moved".into(),
+                message,
+                fix: None,
+            },
+            None => Finding {
+                severity: Severity::Error,
+                file: String::new(),
+                line: 0,
+                rule: "abi-load-address-moved".into(),
+                message: format!("{message}, though the diff touches no linker script"),
+                fix: None,
+            },
+        });
+    }
+    findings
+}
+
+fn is_linker_script(path: &str) -> bool {
+    path.ends_with(".ld") || path.ends_with(".lds")
+}
+
+/// The file and old line number of the diff's first removed line that
+/// names `symbol`.
+fn removed_mention(files: &[FilePatch], symbol: &str) -> Option<(String, usize)> {
+    for file in files {
+        for hunk in &file.hunks {
+            let mut old_line = hunk.old_start;
+            for line in &hunk.lines {
+                match line {
+                    HunkLine::Removed(text) if names(text, symbol) => {
+                        return Some((file.path().to_string(), old_line));
+                    }
+                    HunkLine::Removed(_) | HunkLine::Context(_) => old_line += 1,
+                    HunkLine::Added(_) => {}
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Whether `text` has `symbol` as a whole identifier.
+fn names(text: &str, symbol: &str) -> bool {
+    let ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    text.match_indices(symbol).any(|(at, _)| {
+        !text[..at].ends_with(ident) && !text[at + symbol.len()..].starts_with(ident)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_only_whole_identifiers() {
+        assert!(names("void pmm_alloc(void)", "pmm_alloc"));
+        assert!(!names("void pmm_alloc_page(void)", "pmm_alloc"));
+        assert!(!names("x = my_pmm_alloc;", "pmm_alloc"));
+        assert!(names("EXPORT(pmm_alloc);", "pmm_alloc"));
+    }
+}
@@ -0,0 +1,428 @@
+//! An external static analyzer run on the files a diff changes.
+//!
+//! With `--analyzer clang-tidy`, the patched files are written to a
+//! throwaway overlay of the workspace (as for `--compile`) and clang-tidy
+//! analyses each changed `.c` file there with the flags the build used,
+//! taken from a `compile_commands.json` (kernel-builder's `--emit-compdb`).
+//! The database's paths into the workspace are rebased onto the overlay so
+//! patched headers are seen too. The diagnostics clang-tidy exports with
+//! `--export-fixes` become findings under their check names (so
+//! `[allow]` and `disable` take `bugprone-sizeof-expression` and the like):
+//! errors anywhere, warnings and remarks only in files the diff changed.
+//!
+//! The check profile leaves out what fights kernel code (reserved `__`
+//! identifiers, `memcpy` deprecation, parameter-swapping heuristics); the
+//! rules file's `[clang-tidy]` table replaces it:
+//!
+//! ```toml
+//! [clang-tidy]
+//! program = "clang-tidy-18"
+//! checks = ["-*", "bugprone-*", "clang-analyzer-core.*"]
+//! ```
+
+use crate::apply::Tree;
+use crate::compile::Overlay;
+use crate::patch::FilePatch;
+use crate::{Finding, Severity};
+use anyhow::{bail, Context, Result};
+use auton_core::process;
+use kernel_builder::compdb::{CompileCommand, COMPDB_NAME};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+/// How long one clang-tidy run may take.
+const TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Flags taking a path as their next argument.
+const PATH_FLAGS: &[&str] = &["-I", "-iquote", "-isystem", "-idirafter", "-include"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AnalyzerKind {
+    ClangTidy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ClangTidy {
+    pub program: String,
+    /// `--checks` globs, joined with commas.
+    pub checks: Vec<String>,
+}
+
+impl Default for ClangTidy {
+    fn default() -> Self {
+        Self {
+            program: "clang-tidy".into(),
+            checks: [
+                "-*",
+                "bugprone-*",
+                "-bugprone-easily-swappable-parameters",
+                "-bugprone-reserved-identifier",
+                "-bugprone-narrowing-conversions",
+                "-bugprone-implicit-widening-of-multiplication-result",
+                "clang-analyzer-core.*",
+                "clang-analyzer-deadcode.*",
+                "clang-analyzer-security.*",
+                "-clang-analyzer-security.insecureAPI.*",
+                "cert-err34-c",
+                "misc-redundant-expression",
+            ]
+            .map(String::from)
+            .into(),
+        }
+    }
+}
+
+/// Where to look for the compile database: `explicit`, else in the
+/// workspace, its `build/` or `./build/` (kernel-builder's default output).
+pub fn find_compdb(explicit: Option<&Path>, workspace: &Path) -> Option<PathBuf> {
+    if let Some(path) = explicit {
+        return Some(path.to_path_buf());
+    }
+    [
+        workspace.join(COMPDB_NAME),
+        workspace.join("build").join(COMPDB_NAME),
+        Path::new("build").join(COMPDB_NAME),
+    ]
+    .into_iter()
+    .find(|p| p.is_file())
+}
+
+/// Runs clang-tidy on what each diff of a series leaves in one overlay.
+pub struct Analyzer {
+    overlay: Overlay,
+    /// The workspace, absolute, as the database names it.
+    workspace: PathBuf,
+    commands: Vec<CompileCommand>,
+    config: ClangTidy,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl Analyzer {
+    pub fn new(workspace: &Path, compdb: &Path, config: ClangTidy) -> Result<Self> {
+        let text = std::fs::read_to_string(compdb)
+            .with_context(|| format!("reading {}", compdb.display()))?;
+        let commands =
+            serde_json::from_str(&text).with_context(|| format!("parsing {}", compdb.display()))?;
+        let workspace = std::fs::canonicalize(workspace)
+            .with_context(|| format!("resolving {}", workspace.display()))?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(Self {
+            overlay: Overlay::new(&workspace)?,
+            workspace,
+            commands,
+            config,
+            runtime,
+        })
+    }
+
+    /// Write a diff's files (already applied to `tree`) to the overlay and
+    /// analyse the changed C files.
+    pub fn check(&mut self, tree: &Tree, files: &[FilePatch]) -> Vec<Finding> {
+        match self.try_check(tree, files) {
+            Ok(findings) => findings,
+            Err(e) => vec![Finding {
+                severity: Severity::Error,
+                file: String::new(),
+                line: 0,
+                rule: "analyzer-unavailable".into(),
+                message: format!("{e:#}"),
+                fix: None,
+            }],
+        }
+    }
+
+    fn try_check(&mut self, tree: &Tree, files: &[FilePatch]) -> Result<Vec<Finding>> {
+        self.overlay.write(tree, files)?;
+        let changed: BTreeSet<&str> = files.iter().filter_map(|f| f.new_path.as_deref()).collect();
+        let commands: Vec<CompileCommand> = self
+            .commands
+            .iter()
+            .filter_map(|c| self.rebase(c))
+            .filter(|c| {
+                let rel = self.relative(Path::new(&c.file));
+                rel.ends_with(".c") && changed.contains(rel.as_str())
+            })
+            .collect();
+        if commands.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let dir = self.overlay.root.join(".analyzer");
+        std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        std::fs::write(dir.join(COMPDB_NAME), serde_json::to_string(&commands)?)
+            .context("writing the overlay's compile database")?;
+        let fixes = dir.join("fixes.yaml");
+        let _ = std::fs::remove_file(&fixes);
+
+        let mut cmd = tokio::process::Command::new(&self.config.program);
+        cmd.arg("-p")
+            .arg(&dir)
+            .arg(format!("--checks={}", self.config.checks.join(",")))
+            .arg(format!("--export-fixes={}", fixes.display()))
+            .arg("--quiet")
+            .args(commands.iter().map(|c| &c.file));
+        let out = self.runtime.block_on(process::run(cmd, Some(TIMEOUT)))?;
+        let yaml = match std::fs::read_to_string(&fixes) {
+            Ok(yaml) => yaml,
+            // No diagnostics, no file.
+            Err(_) if out.success() => return Ok(Vec::new()),
+            Err(_) => {
+                bail!(
+                    "{} exited with {}: {}",
+                    out.program,
+                    out.status(),
+                    out.stderr_tail(5)
+                )
+            }
+        };
+
+        let mut sources: HashMap<String, Option<String>> = HashMap::new();
+        let mut seen = BTreeSet::new();
+        let mut findings = Vec::new();
+        for d in parse_fixes(&yaml) {
+            let file = self.relative(Path::new(&d.file));
+            let severity = match d.level.as_str() {
+                "Error" => Severity::Error,
+                _ if !changed.contains(file.as_str()) => continue,
+                "Warning" => Severity::Warning,
+                _ => Severity::Info,
+            };
+            let text = sources
+                .entry(d.file.clone())
+                .or_insert_with(|| std::fs::read_to_string(&d.file).ok());
+            let line = text.as_deref().map_or(0, |t| line_of(t, d.offset));
+            if seen.insert((file.clone(), line, d.name.clone())) {
+                findings.push(Finding {
+                    severity,
+                    file,
+                    line,
+                    rule: d.name,
+                    message: d.message,
+                    fix: None,
+                });
+            }
+        }
+        Ok(findings)
+    }
+
+    /// `command` with its paths into the workspace moved into the overlay;
+    /// `None` if its file is not in the workspace.
+    fn rebase(&self, command: &CompileCommand) -> Option<CompileCommand> {
+        let dir = Path::new(&command.directory);
+        let file = self.in_overlay(dir, &command.file)?;
+        let mut arguments = Vec::with_capacity(command.arguments.len());
+        let mut path_next = false;
+        for arg in &command.arguments {
+            let moved = if path_next {
+                self.in_overlay(dir, arg)
+            } else if let Some(p) = arg.strip_prefix("-I").filter(|p| !p.is_empty()) {
+                self.in_overlay(dir, p).map(|p| format!("-I{p}"))
+            } else if *arg == command.file {
+                Some(file.clone())
+            } else {
+                None
+            };
+            path_next = PATH_FLAGS.contains(&arg.as_str());
+            arguments.push(moved.unwrap_or_else(|| arg.clone()));
+        }
+        Some(CompileCommand {
+            directory: command.directory.clone(),
+            file,
+            arguments,
+            output: command.output.clone(),
+        })
+    }
+
+    fn in_overlay(&self, dir: &Path, path: &str) -> Option<String> {
+        let rel = normalize(&dir.join(path))
+            .strip_prefix(&self.workspace)
+            .ok()?
+            .to_path_buf();
+        Some(self.overlay.root.join(rel).display().to_string())
+    }
+
+    /// `path` relative to the overlay, as the diff names it.
+    fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.overlay.root)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// `path` with `.` and `..` resolved lexically.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// 1-based line of the byte at `offset`.
+fn line_of(text: &str, offset: usize) -> usize {
+    let end = offset.min(text.len());
+    text.as_bytes()[..end]
+        .iter()
+        .filter(|&&b| b == b'\n')
+        .count()
+        + 1
+}
+
+/// One diagnostic of a clang-tidy fixes file.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct TidyDiagnostic {
+    name: String,
+    message: String,
+    file: String,
+    offset: usize,
+    level: String,
+}
+
+/// The diagnostics of `--export-fixes` YAML. Only the shape clang-tidy
+/// writes is read: a `Diagnostics` list whose entries carry their message,
+/// file and offset in a `DiagnosticMessage` map (or, before clang-tidy 9,
+/// directly); notes and replacements are skipped.
+fn parse_fixes(yaml: &str) -> Vec<TidyDiagnostic> {
+    let mut out: Vec<TidyDiagnostic> = Vec::new();
+    // Indent of the entry's keys, and of its `DiagnosticMessage`'s.
+    let mut entry_indent = 0;
+    let mut message_indent: Option<usize> = None;
+    let mut in_message = false;
+    for line in yaml.lines() {
+        let trimmed = line.trim_start();
+        let mut indent = line.len() - trimmed.len();
+        let item = trimmed.strip_prefix("- ");
+        if item.is_some() {
+            indent += 2;
+        }
+        let Some((key, value)) = item.unwrap_or(trimmed).split_once(':') else {
+            continue;
+        };
+        let value = scalar(value.trim());
+        if key == "DiagnosticName" {
+            out.push(TidyDiagnostic {
+                name: value,
+                ..Default::default()
+            });
+            entry_indent = indent;
+            in_message = false;
+            continue;
+        }
+        let Some(d) = out.last_mut() else {
+            continue;
+        };
+        if indent <= entry_indent {
+            in_message = key == "DiagnosticMessage";
+            message_indent = None;
+        } else if in_message && *message_indent.get_or_insert(indent) != indent {
+            continue;
+        }
+        if indent != entry_indent && !in_message {
+            continue;
+        }
+        match key {
+            "Level" => d.level = value,
+            "Message" => d.message = value,
+            "FilePath" => d.file = value,
+            "FileOffset" => d.offset = value.parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// A YAML scalar: quoted strings unescaped, anything else as written.
+fn scalar(value: &str) -> String {
+    if let Some(inner) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        return inner.replace("''", "'");
+    }
+    if let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        return inner.replace("\\\"", "\"").replace("\\\\", "\\");
+    }
+    value.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXES: &str = "\
+---
+MainSourceFile:  '/w/kernel/a.c'
+Diagnostics:
+  - DiagnosticName:  bugprone-sizeof-expression
+    DiagnosticMessage:
+      Message:         'suspicious usage of ''sizeof(K)'''
+      FilePath:        '/w/kernel/a.c'
+      FileOffset:      12
+      Replacements:
+        - FilePath:        '/w/kernel/other.c'
+          Offset:          3
+          Length:          1
+          ReplacementText: ''
+    Notes:
+      - Message:         'declared here'
+        FilePath:        '/w/kernel/b.h'
+        FileOffset:      99
+    Level:           Warning
+    BuildDirectory:  '/w'
+  - DiagnosticName:  clang-diagnostic-error
+    Message:         \"use of undeclared identifier \\\"x\\\"\"
+    FileOffset:      4
+    FilePath:        '/w/kernel/a.c'
+    Replacements:    []
+    Level:           Error
+...
+";
+
+    #[test]
+    fn reads_diagnostics_not_notes_or_replacements() {
+        let diags = parse_fixes(FIXES);
+        assert_eq!(
+            diags,
+            [
+                TidyDiagnostic {
+                    name: "bugprone-sizeof-expression".into(),
+                    message: "suspicious usage of 'sizeof(K)'".into(),
+                    file: "/w/kernel/a.c".into(),
+                    offset: 12,
+                    level: "Warning".into(),
+                },
+                TidyDiagnostic {
+                    name: "clang-diagnostic-error".into(),
+                    message: "use of undeclared identifier \"x\"".into(),
+                    file: "/w/kernel/a.c".into(),
+                    offset: 4,
+                    level: "Error".into(),
+                },
+            ]
+        );
+        assert_eq!(
+            parse_fixes("---\nMainSourceFile: ''\nDiagnostics: []\n"),
+            []
+        );
+    }
+
+    #[test]
+    fn offsets_become_lines_and_paths_are_normalized() {
+        assert_eq!(line_of("ab\ncd\nef", 0), 1);
+        assert_eq!(line_of("ab\ncd\nef", 4), 2);
+        assert_eq!(line_of("ab\n", 100), 2);
+        assert_eq!(
+            normalize(Path::new("/r/./kernels/x86_64/../x86_64/kernel")),
+            PathBuf::from("/r/kernels/x86_64/kernel")
+        );
+    }
+}
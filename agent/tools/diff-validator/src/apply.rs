@@ -0,0 +1,718 @@
+//! Checking that a diff applies cleanly to a workspace.
+//!
+//! Each hunk is looked for where its header says, shifted by what the
+//! file's earlier hunks added, removed and moved, and then ever further
+//! away, as `patch` does. With fuzz F, up to F context lines at either end
+//! of a hunk may differ too. Hunks are applied in memory as they are found,
+//! so a file patched twice, or by several diffs of a series (see
+//! [`Tree`]), is checked against the earlier patches' result. A
+//! hunk that does not apply is reported with the closest place it nearly
+//! matched and the first line that differs there.
+//!
+//! When the workspace has moved on since the diff was made, its hunks may
+//! no longer fit. With [`Tree::merge`] set, a file whose hunks do not all
+//! apply is instead merged three ways: the diff is applied to the blob its
+//! git `index` line names (read from the workspace's repository), and that
+//! result's changes are merged with the workspace's own. A clean merge is
+//! context drift (`hunk-merged`, info); changes that overlap are real
+//! conflicts (`merge-conflict`, errors). [`rebased`] writes the diff again
+//! against the workspace as it is now.
+
+use crate::patch::{self, Change, FilePatch, Hunk, HunkLine};
+use crate::{Finding, Severity};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+/// Context lines per hunk end that may differ without `--fuzz`, as for
+/// `patch`.
+pub const DEFAULT_FUZZ: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum HunkStatus {
+    /// Applied at `line` (1-based, in the file as patched so far), `offset`
+    /// lines from where the header put it, ignoring `fuzz` context lines.
+    Applied {
+        line: usize,
+        offset: isize,
+        fuzz: usize,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HunkResult {
+    /// 1-based within the file.
+    pub index: usize,
+    pub header: String,
+    pub old_start: usize,
+    #[serde(flatten)]
+    pub status: HunkStatus,
+}
+
+impl HunkResult {
+    pub fn applied(&self) -> bool {
+        matches!(self.status, HunkStatus::Applied { .. })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileResult {
+    pub path: String,
+    /// Why the file could not be patched at all (missing, already there).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// A binary patch, which is not checked.
+    pub binary: bool,
+    pub hunks: Vec<HunkResult>,
+    /// The three-way merge done because some hunks did not apply.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merge: Option<Merge>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Merge {
+    /// The blob the diff was made against.
+    pub base: String,
+    /// Why the merge could not be done.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub conflicts: Vec<Conflict>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Conflict {
+    /// 1-based, in the workspace's file.
+    pub line: usize,
+    /// What the workspace and the diff each have there.
+    pub workspace: Vec<String>,
+    pub diff: Vec<String>,
+}
+
+impl Merge {
+    pub fn clean(&self) -> bool {
+        self.error.is_none() && self.conflicts.is_empty()
+    }
+}
+
+impl FileResult {
+    pub fn clean(&self) -> bool {
+        self.error.is_none()
+            && (self.hunks.iter().all(HunkResult::applied)
+                || self.merge.as_ref().is_some_and(Merge::clean))
+    }
+}
+
+/// Leading and trailing context lines of a hunk.
+fn context_ends(hunk: &Hunk) -> (usize, usize) {
+    let is_context = |l: &&HunkLine| matches!(l, HunkLine::Context(_));
+    let lead = hunk.lines.iter().take_while(is_context).count();
+    let trail = hunk.lines.iter().rev().take_while(is_context).count();
+    // An all-context hunk: count it once.
+    (lead, trail.min(hunk.lines.len() - lead))
+}
+
+fn matches_at(lines: &[String], pattern: &[&str], at: usize) -> bool {
+    at + pattern.len() <= lines.len() && pattern.iter().zip(&lines[at..]).all(|(p, l)| p == l)
+}
+
+/// Where `pattern` is, searching outward from `expected` but not before
+/// `min`.
+fn find(lines: &[String], pattern: &[&str], expected: usize, min: usize) -> Option<usize> {
+    let last = lines.len().checked_sub(pattern.len())?;
+    let expected = expected.clamp(min, last.max(min));
+    for distance in 0..=lines.len() {
+        let later = expected + distance;
+        if later <= last && matches_at(lines, pattern, later) {
+            return Some(later);
+        }
+        if let Some(earlier) = expected.checked_sub(distance).filter(|&e| e >= min) {
+            if distance > 0 && matches_at(lines, pattern, earlier) {
+                return Some(earlier);
+            }
+        }
+        if later > last && expected.saturating_sub(distance) <= min {
+            break;
+        }
+    }
+    None
+}
+
+/// Why `pattern` is not at or near `expected`: the position where most of
+/// its lines match and the first that does not.
+fn explain_mismatch(lines: &[String], pattern: &[&str], expected: usize) -> String {
+    let score = |at: usize| {
+        pattern
+            .iter()
+            .enumerate()
+            .filter(|&(k, p)| lines.get(at + k).is_some_and(|l| l == p))
+            .count()
+    };
+    let best = (0..lines.len().max(1))
+        .max_by_key(|&at| (score(at), std::cmp::Reverse(at.abs_diff(expected))))
+        .unwrap_or(0);
+    let matched = score(best);
+    if matched == 0 {
+        return format!(
+            "none of its {} lines appear near line {}",
+            pattern.len(),
+            expected + 1
+        );
+    }
+    let k = (0..pattern.len())
+        .find(|&k| lines.get(best + k).is_none_or(|l| l != pattern[k]))
+        .unwrap_or(0);
+    let found = match lines.get(best + k) {
+        Some(l) if l.split_whitespace().eq(pattern[k].split_whitespace()) => {
+            format!("`{l}` (whitespace differs)")
+        }
+        Some(l) => format!("`{l}`"),
+        None => "the end of the file".to_string(),
+    };
+    format!(
+        "closest match at line {} ({matched} of {} lines): line {} should be `{}` but is {found}",
+        best + 1,
+        pattern.len(),
+        best + k + 1,
+        pattern[k]
+    )
+}
+
+/// Apply `hunks` to `lines` in order, skipping the ones that do not fit.
+pub fn apply_hunks(lines: &mut Vec<String>, hunks: &[Hunk], fuzz: usize) -> Vec<HunkResult> {
+    let mut results = Vec::new();
+    // Lines earlier hunks added (or removed), and how far the last moved.
+    let mut delta: isize = 0;
+    let mut drift: isize = 0;
+    // Earlier hunks' text is not searched again.
+    let mut min = 0;
+    for (i, hunk) in hunks.iter().enumerate() {
+        let old = hunk.old_lines();
+        let new = hunk.new_lines();
+        let (lead, trail) = context_ends(hunk);
+        // Where the header puts the hunk in the file as patched so far.
+        let header_at = (hunk.old_start.saturating_sub(1) as isize + delta).max(0) as usize;
+        let mut status = None;
+        for f in 0..=fuzz {
+            let (front, back) = (f.min(lead), f.min(trail));
+            if f > lead.max(trail) {
+                // All the context is already ignored.
+                break;
+            }
+            let pattern = &old[front..old.len() - back];
+            let expected = (header_at as isize + drift).max(0) as usize + front;
+            let at = if pattern.is_empty() {
+                Some(expected.clamp(min, lines.len()))
+            } else {
+                find(lines, pattern, expected, min)
+            };
+            if let Some(at) = at {
+                let replacement = new[front..new.len() - back].iter().map(|s| s.to_string());
+                lines.splice(at..at + pattern.len(), replacement);
+                let offset = at as isize - (header_at + front) as isize;
+                drift = offset;
+                delta += new.len() as isize - old.len() as isize;
+                min = at + new.len() - front - back;
+                status = Some(HunkStatus::Applied {
+                    line: at.saturating_sub(front) + 1,
+                    offset,
+                    fuzz: f,
+                });
+                break;
+            }
+        }
+        let status = status.unwrap_or_else(|| HunkStatus::Failed {
+            reason: explain_mismatch(lines, &old, (header_at as isize + drift).max(0) as usize),
+        });
+        results.push(HunkResult {
+            index: i + 1,
+            header: hunk.header.clone(),
+            old_start: hunk.old_start,
+            status,
+        });
+    }
+    results
+}
+
+/// A diff path that stays inside the workspace.
+fn confined(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Where a [`Tree`] reads the files no diff has touched yet.
+enum Source {
+    Dir(PathBuf),
+    /// `git show REV:PATH` in a repository.
+    Revision {
+        repo: PathBuf,
+        rev: String,
+    },
+}
+
+impl Source {
+    fn read(&self, path: &str) -> Option<String> {
+        match self {
+            Source::Dir(root) => std::fs::read_to_string(root.join(path)).ok(),
+            Source::Revision { repo, rev } => {
+                let out = std::process::Command::new("git")
+                    .arg("-C")
+                    .arg(repo)
+                    .args(["show", &format!("{rev}:{path}")])
+                    .output()
+                    .ok()?;
+                out.status
+                    .success()
+                    .then(|| String::from_utf8_lossy(&out.stdout).into_owned())
+            }
+        }
+    }
+
+    /// The blob `hash` in the repository the files come from.
+    fn blob(&self, hash: &str) -> Option<String> {
+        let repo = match self {
+            Source::Dir(root) => root,
+            Source::Revision { repo, .. } => repo,
+        };
+        let out = std::process::Command::new("git")
+            .arg("-C")
+            .arg(repo)
+            .args(["cat-file", "blob", hash])
+            .output()
+            .ok()?;
+        out.status
+            .success()
+            .then(|| String::from_utf8_lossy(&out.stdout).into_owned())
+    }
+}
+
+/// A workspace as the diffs checked so far have left it, in memory.
+pub struct Tree {
+    source: Source,
+    /// Files read or patched; `None` once removed.
+    files: HashMap<String, Option<Vec<String>>>,
+    /// Merge files whose hunks do not all apply with the blob the diff was
+    /// made against.
+    pub merge: bool,
+}
+
+impl Tree {
+    /// The tree of files under `root`.
+    pub fn new(root: &Path) -> Self {
+        Self {
+            source: Source::Dir(root.to_path_buf()),
+            files: HashMap::new(),
+            merge: false,
+        }
+    }
+
+    /// The tree of revision `rev` in the git repository at `repo`.
+    pub fn at_revision(repo: &Path, rev: &str) -> Self {
+        Self {
+            source: Source::Revision {
+                repo: repo.to_path_buf(),
+                rev: rev.to_string(),
+            },
+            files: HashMap::new(),
+            merge: false,
+        }
+    }
+
+    /// The lines of `path` as the diffs so far left it, if they touched or
+    /// read it and it exists.
+    pub fn file(&self, path: &str) -> Option<&[String]> {
+        self.files.get(path)?.as_deref()
+    }
+
+    fn read(&mut self, path: &str) -> Option<Vec<String>> {
+        let source = &self.source;
+        self.files
+            .entry(path.to_string())
+            .or_insert_with(|| Some(source.read(path)?.lines().map(String::from).collect()))
+            .clone()
+    }
+
+    /// Check every file of a parsed diff, in order, and apply what fits.
+    pub fn apply(&mut self, files: &[FilePatch], fuzz: usize) -> Vec<FileResult> {
+        files
+            .iter()
+            .map(|file| self.apply_file(file, fuzz))
+            .collect()
+    }
+
+    fn apply_file(&mut self, file: &FilePatch, fuzz: usize) -> FileResult {
+        let mut result = FileResult {
+            path: file.path().to_string(),
+            error: None,
+            binary: file.binary,
+            hunks: Vec::new(),
+            merge: None,
+        };
+        let paths = [&file.old_path, &file.new_path];
+        if let Some(bad) = paths.into_iter().flatten().find(|p| !confined(p)) {
+            result.error = Some(format!("`{bad}` is outside the workspace"));
+            return result;
+        }
+        let change = file.change();
+        let base = match (&file.old_path, change) {
+            (_, Change::Added) => match self.read(&result.path) {
+                Some(_) => Err(format!("`{}` is added but already exists", result.path)),
+                None => Ok(Vec::new()),
+            },
+            (Some(old), _) => self
+                .read(old)
+                .ok_or_else(|| format!("`{old}` does not exist")),
+            (None, _) => unreachable!("only additions lack an old path"),
+        };
+        let target_taken =
+            matches!(change, Change::Renamed | Change::Copied) && self.read(&result.path).is_some();
+        let mut lines = match base {
+            Ok(_) if target_taken => {
+                let what = if change == Change::Renamed {
+                    "rename"
+                } else {
+                    "copy"
+                };
+                result.error = Some(format!(
+                    "the {what} target `{}` already exists",
+                    result.path
+                ));
+                return result;
+            }
+            Ok(lines) => lines,
+            Err(e) => {
+                result.error = Some(e);
+                return result;
+            }
+        };
+        if !file.binary {
+            let workspace = lines.clone();
+            result.hunks = apply_hunks(&mut lines, &file.hunks, fuzz);
+            if let (true, false, Some(base)) = (
+                self.merge,
+                result.hunks.iter().all(HunkResult::applied),
+                &file.old_blob,
+            ) {
+                let (merge, merged) = self.merge_file(base, &workspace, &file.hunks);
+                if let Some(merged) = merged {
+                    lines = merged;
+                }
+                result.merge = Some(merge);
+            }
+        }
+        if change == Change::Deleted && result.clean() && !lines.is_empty() {
+            result.error = Some(format!(
+                "`{}` is deleted but {} of its lines are left",
+                result.path,
+                lines.len()
+            ));
+        }
+        if let (Change::Renamed | Change::Deleted, Some(old)) = (change, &file.old_path) {
+            self.files.insert(old.clone(), None);
+        }
+        if let Some(new) = &file.new_path {
+            self.files.insert(new.clone(), Some(lines));
+        }
+        result
+    }
+}
+
+fn strs(lines: &[String]) -> Vec<&str> {
+    lines.iter().map(String::as_str).collect()
+}
+
+impl Tree {
+    /// Merge the changes `hunks` make to blob `base` into `workspace`, the
+    /// file as it is: the merge, and the merged lines unless it failed.
+    fn merge_file(
+        &self,
+        base: &str,
+        workspace: &[String],
+        hunks: &[Hunk],
+    ) -> (Merge, Option<Vec<String>>) {
+        let mut merge = Merge {
+            base: base.to_string(),
+            error: None,
+            conflicts: Vec::new(),
+        };
+        let Some(text) = self.source.blob(base) else {
+            merge.error = Some(format!("blob {base} is not in the repository"));
+            return (merge, None);
+        };
+        let base_lines: Vec<String> = text.lines().map(String::from).collect();
+        let mut theirs = base_lines.clone();
+        if let Some(h) = apply_hunks(&mut theirs, hunks, 0)
+            .iter()
+            .find(|h| !h.applied())
+        {
+            merge.error = Some(format!(
+                "hunk {} does not apply to blob {base} either",
+                h.index
+            ));
+            return (merge, None);
+        }
+        let merged = patch::merge(&strs(&base_lines), &strs(workspace), &strs(&theirs));
+        merge.conflicts = merged
+            .conflicts
+            .into_iter()
+            .map(|c| Conflict {
+                line: c.line,
+                workspace: c.ours,
+                diff: c.theirs,
+            })
+            .collect();
+        (merge, Some(merged.lines))
+    }
+}
+
+/// The diff `files` again, against the files under `workspace` as they are
+/// now, as `tree` has applied or merged it; files that did not apply
+/// cleanly (see `results`) are left out.
+pub fn rebased(
+    files: &[FilePatch],
+    results: &[FileResult],
+    workspace: &Path,
+    tree: &Tree,
+) -> String {
+    let mut out = String::new();
+    for (file, result) in files.iter().zip(results) {
+        if !result.clean() || file.binary {
+            continue;
+        }
+        let read = |path: &str| {
+            std::fs::read_to_string(workspace.join(path))
+                .map(|t| t.lines().map(String::from).collect::<Vec<_>>())
+                .unwrap_or_default()
+        };
+        let before = file.old_path.as_deref().map(read).unwrap_or_default();
+        let after = file
+            .new_path
+            .as_deref()
+            .and_then(|p| tree.file(p))
+            .unwrap_or_default();
+        let (old, new) = (file.old_path.as_deref(), file.new_path.as_deref());
+        let path = file.path();
+        let mode_change = match (file.old_mode, file.new_mode) {
+            (Some(a), Some(b)) if a != b => Some((a, b)),
+            _ => None,
+        };
+        if file.change() == Change::Modified && before == after && mode_change.is_none() {
+            continue;
+        }
+        out.push_str(&format!("diff --git a/{} b/{path}\n", old.unwrap_or(path)));
+        match file.change() {
+            Change::Added => out.push_str(&format!(
+                "new file mode {:o}\n",
+                file.new_mode.unwrap_or(0o100644)
+            )),
+            Change::Deleted => out.push_str(&format!(
+                "deleted file mode {:o}\n",
+                file.old_mode.unwrap_or(0o100644)
+            )),
+            Change::Renamed | Change::Copied => {
+                let how = if file.rename { "rename" } else { "copy" };
+                out.push_str(&format!(
+                    "{how} from {}\n{how} to {path}\n",
+                    old.unwrap_or_default()
+                ));
+            }
+            Change::Modified => {}
+        }
+        if let Some((a, b)) = mode_change {
+            out.push_str(&format!("old mode {a:o}\nnew mode {b:o}\n"));
+        }
+        if before == after {
+            continue;
+        }
+        let name =
+            |side: &str, p: Option<&str>| p.map_or("/dev/null".into(), |p| format!("{side}/{p}"));
+        out.push_str(&patch::unified_diff(
+            &strs(&before),
+            &strs(after),
+            &name("a", old),
+            &name("b", new),
+        ));
+    }
+    out
+}
+
+/// Check every file of a parsed diff against `workspace`, in order.
+pub fn check(files: &[FilePatch], workspace: &Path, fuzz: usize) -> Vec<FileResult> {
+    Tree::new(workspace).apply(files, fuzz)
+}
+
+/// Findings for files and hunks that do not apply cleanly (errors) or
+/// only apply moved or fuzzed (info).
+pub fn findings(results: &[FileResult]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for file in results {
+        let finding = |severity, line, rule: &str, message: String| Finding {
+            severity,
+            file: file.path.clone(),
+            line,
+            rule: rule.into(),
+            message,
+            fix: None,
+        };
+        if let Some(e) = &file.error {
+            findings.push(finding(Severity::Error, 0, "patch-file", e.clone()));
+        }
+        if file.binary {
+            findings.push(finding(
+                Severity::Info,
+                0,
+                "binary-not-checked",
+                "binary patch not checked".into(),
+            ));
+        }
+        let merged = file.merge.as_ref().filter(|m| m.error.is_none());
+        if let Some(m) = &file.merge {
+            if let Some(e) = &m.error {
+                findings.push(finding(
+                    Severity::Info,
+                    0,
+                    "merge-unavailable",
+                    format!("no three-way merge: {e}"),
+                ));
+            }
+            for c in &m.conflicts {
+                let show = |lines: &[String]| match lines {
+                    [] => "nothing".to_string(),
+                    [one] => format!("`{}`", one.trim()),
+                    [first, ..] => format!("`{}` and {} more", first.trim(), lines.len() - 1),
+                };
+                findings.push(finding(
+                    Severity::Error,
+                    c.line,
+                    "merge-conflict",
+                    format!(
+                        "conflicts with a workspace change since blob {}: the workspace has {}, the diff {}",
+                        m.base,
+                        show(&c.workspace),
+                        show(&c.diff)
+                    ),
+                ));
+            }
+        }
+        for h in &file.hunks {
+            match &h.status {
+                HunkStatus::Failed { .. } if merged.is_some_and(Merge::clean) => findings.push(
+                    finding(
+                        Severity::Info,
+                        h.old_start,
+                        "hunk-merged",
+                        format!(
+                            "hunk {} `{}` applies only by a three-way merge with blob {}: the workspace changed around it",
+                            h.index,
+                            h.header,
+                            merged.map_or("", |m| m.base.as_str())
+                        ),
+                    ),
+                ),
+                // The conflicts say why.
+                HunkStatus::Failed { .. } if merged.is_some() => {}
+                HunkStatus::Failed { reason } => findings.push(finding(
+                    Severity::Error,
+                    h.old_start,
+                    "hunk-does-not-apply",
+                    format!("hunk {} `{}` does not apply: {reason}", h.index, h.header),
+                )),
+                &HunkStatus::Applied { line, offset, fuzz } if offset != 0 || fuzz > 0 => findings
+                    .push(finding(
+                        Severity::Info,
+                        h.old_start,
+                        "hunk-offset",
+                        format!(
+                            "hunk {} applies at line {line} (offset {offset:+}, fuzz {fuzz})",
+                            h.index
+                        ),
+                    )),
+                HunkStatus::Applied { .. } => {}
+            }
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::parse;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(String::from).collect()
+    }
+
+    fn hunks(diff: &str) -> Vec<Hunk> {
+        parse(&format!("--- a/f.c\n+++ b/f.c\n{diff}"))
+            .unwrap()
+            .remove(0)
+            .hunks
+    }
+
+    #[test]
+    fn applies_in_place_with_offset_and_with_fuzz() {
+        let mut file = lines("a\nb\nc\nd\ne\nf\n");
+        let results = apply_hunks(&mut file, &hunks("@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n"), 0);
+        assert_eq!(
+            results[0].status,
+            HunkStatus::Applied {
+                line: 2,
+                offset: 0,
+                fuzz: 0
+            }
+        );
+        assert_eq!(file, lines("a\nb\nC\nd\ne\nf\n"));
+
+        let mut moved = lines("x\nx\na\nb\nc\nd\n");
+        let results = apply_hunks(&mut moved, &hunks("@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n"), 0);
+        assert!(matches!(
+            results[0].status,
+            HunkStatus::Applied { offset: 2, .. }
+        ));
+
+        let mut edited = lines("a\nB\nc\nd\n");
+        let hunk = hunks("@@ -1,4 +1,4 @@\n a\n b\n-c\n+C\n d\n");
+        assert!(!apply_hunks(&mut edited.clone(), &hunk, 0)[0].applied());
+        let results = apply_hunks(&mut edited, &hunk, 2);
+        assert!(matches!(
+            results[0].status,
+            HunkStatus::Applied { fuzz: 2, .. }
+        ));
+        assert_eq!(edited, lines("a\nB\nC\nd\n"));
+    }
+
+    #[test]
+    fn later_hunks_follow_earlier_ones() {
+        let mut file = lines("1\n2\n3\n4\n5\n6\n7\n8\n");
+        let results = apply_hunks(
+            &mut file,
+            &hunks("@@ -1,2 +1,4 @@\n 1\n+1a\n+1b\n 2\n@@ -7,2 +9,1 @@\n 7\n-8\n"),
+            0,
+        );
+        assert!(results.iter().all(HunkResult::applied), "{results:?}");
+        assert_eq!(file, lines("1\n1a\n1b\n2\n3\n4\n5\n6\n7\n"));
+    }
+
+    #[test]
+    fn failures_say_where_and_why() {
+        let mut file = lines("int a;\nint b;\nint  c;\nint d;\n");
+        let results = apply_hunks(
+            &mut file,
+            &hunks("@@ -1,4 +1,4 @@\n int a;\n int b;\n-int c;\n+int C;\n int d;\n"),
+            0,
+        );
+        let HunkStatus::Failed { reason } = &results[0].status else {
+            panic!("{results:?}");
+        };
+        assert_eq!(
+            reason,
+            "closest match at line 1 (3 of 4 lines): line 3 should be `int c;` but is `int  c;` (whitespace differs)"
+        );
+        assert_eq!(file, lines("int a;\nint b;\nint  c;\nint d;\n"));
+    }
+}
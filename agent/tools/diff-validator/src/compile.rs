@@ -0,0 +1,304 @@
+//! Compile-checking a patched tree before it is approved.
+//!
+//! Agent diffs most often apply cleanly and then fail to build. With
+//! `--compile`, the workspace is copied once to a throwaway overlay (leaving
+//! out hidden entries and `build`/`out`/`target` directories), every diff's
+//! patched files are written over it, and kernel-builder's native compile
+//! stage, used as a library with its cache off, builds the translation
+//! units the diff affects: the changed `.c` files, or every selected source
+//! once a header changes. `auton-build.toml`'s `[sources]` and `[flags]` are
+//! honoured. Compiler errors are reported anywhere; warnings only in files
+//! the diff changed, since the rest were there before it. Assembly and
+//! linking are not checked.
+
+use crate::apply::Tree;
+use crate::patch::{Change, FilePatch};
+use crate::{Finding, Severity};
+use anyhow::{Context, Result};
+use auton_core::diagnostics::{self, Diagnostic};
+use kernel_builder::cache::BuildCache;
+use kernel_builder::config::{self, BuildConfig};
+use kernel_builder::profile::Profile;
+use kernel_builder::toolchain::{self, Compiler};
+use kernel_builder::{jobs, ArchToolchain};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Directories of build outputs, never copied into the overlay.
+const OUTPUT_DIRS: &[&str] = &["build", "out", "target"];
+
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    pub arch: String,
+    /// Compiler override, as kernel-builder's `--cc`; else the workspace's
+    /// `cc`, else the architecture's default.
+    pub cc: Option<String>,
+    pub profile: Profile,
+    pub jobs: usize,
+}
+
+/// A throwaway copy of a workspace, removed on drop.
+pub(crate) struct Overlay {
+    pub(crate) root: PathBuf,
+}
+
+impl Overlay {
+    pub(crate) fn new(workspace: &Path) -> Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let root = std::env::temp_dir().join(format!(
+            "diff-validator-{}-overlay-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let overlay = Self { root };
+        copy_tree(workspace, &overlay.root)
+            .with_context(|| format!("copying {} to an overlay", workspace.display()))?;
+        Ok(overlay)
+    }
+
+    /// Bring `files` of the overlay up to date with `tree`.
+    pub(crate) fn write(&self, tree: &Tree, files: &[FilePatch]) -> Result<()> {
+        for file in files {
+            if let (Change::Renamed | Change::Deleted, Some(old)) = (file.change(), &file.old_path)
+            {
+                let _ = std::fs::remove_file(self.root.join(old));
+            }
+            let Some(new) = &file.new_path else {
+                continue;
+            };
+            let Some(lines) = tree.file(new) else {
+                continue;
+            };
+            let path = self.root.join(new);
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("creating {}", dir.display()))?;
+            }
+            let mut text = lines.join("\n");
+            text.push('\n');
+            std::fs::write(&path, text).with_context(|| format!("writing {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Overlay {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+fn copy_tree(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let kind = entry.file_type()?;
+        if name.starts_with('.') {
+            continue;
+        }
+        if kind.is_dir() {
+            if !OUTPUT_DIRS.contains(&name.as_ref()) {
+                copy_tree(&entry.path(), &to.join(&*name))?;
+            }
+        } else if entry.path().is_file() {
+            std::fs::copy(entry.path(), to.join(&*name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Compiles the files a series of diffs leaves in one overlay.
+pub struct CompileCheck {
+    overlay: Overlay,
+    options: CompileOptions,
+    config: BuildConfig,
+    /// The compiler, or why none could be used.
+    compiler: Result<Compiler, String>,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl CompileCheck {
+    pub fn new(workspace: &Path, options: CompileOptions) -> Result<Self> {
+        let overlay = Overlay::new(workspace)?;
+        let path = overlay.root.join(config::CONFIG_NAME);
+        let config = if path.is_file() {
+            BuildConfig::load(&path)?
+        } else {
+            BuildConfig::default()
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let compiler = runtime.block_on(async {
+            let tc = ArchToolchain::for_arch(&options.arch)?;
+            let cc = options.cc.as_deref().or(config.cc.as_deref());
+            toolchain::detect(&tc, cc).await
+        });
+        Ok(Self {
+            overlay,
+            options,
+            config,
+            compiler: compiler.map_err(|e| format!("{e:#}")),
+            runtime,
+        })
+    }
+
+    /// Write a diff's files (already applied to `tree`) to the overlay and
+    /// compile what they affect.
+    pub fn check(&mut self, tree: &Tree, files: &[FilePatch]) -> Vec<Finding> {
+        match self.try_check(tree, files) {
+            Ok(findings) => findings,
+            Err(e) => vec![finding(
+                Severity::Error,
+                String::new(),
+                0,
+                "compile-unavailable",
+                format!("{e:#}"),
+            )],
+        }
+    }
+
+    fn try_check(&mut self, tree: &Tree, files: &[FilePatch]) -> Result<Vec<Finding>> {
+        self.overlay.write(tree, files)?;
+        let changed: BTreeSet<&str> = files.iter().filter_map(|f| f.new_path.as_deref()).collect();
+        let header = changed.iter().any(|p| p.ends_with(".h"));
+        let sources: Vec<PathBuf> = self
+            .sources()?
+            .into_iter()
+            .filter(|s| header || changed.contains(self.relative(s).as_str()))
+            .collect();
+        if sources.is_empty() {
+            return Ok(Vec::new());
+        }
+        let compiler = self.compiler.as_ref().map_err(|e| anyhow::anyhow!("{e}"))?;
+
+        let root = &self.overlay.root;
+        let out = root.join(OUTPUT_DIRS[0]);
+        let mut cc_jobs = toolchain::plan_files(
+            root,
+            &out.join("obj"),
+            compiler,
+            self.options.profile,
+            sources,
+        )?;
+        for job in &mut cc_jobs {
+            let rel = job.source.strip_prefix(root).unwrap_or(&job.source);
+            job.args.extend(config::flags_for(&self.config.flags, rel));
+        }
+        let cache = BuildCache::new(&out, false);
+        diagnostics::take();
+        let failures =
+            self.runtime
+                .block_on(jobs::run_bounded(cc_jobs, self.options.jobs, |job| {
+                    let cache = cache.clone();
+                    async move {
+                        let failed = toolchain::compile(&job, &cache).await.err();
+                        Ok(failed.map(|e| (job.source, e)))
+                    }
+                }))?;
+
+        let mut seen = BTreeSet::new();
+        let mut findings = Vec::new();
+        let mut report = |d: &Diagnostic, fallback: &str| {
+            let file = d
+                .file
+                .as_deref()
+                .map_or(fallback.to_string(), |f| self.relative(Path::new(f)));
+            let (severity, rule) = match d.severity {
+                diagnostics::Severity::Error => (Severity::Error, "compile-error"),
+                diagnostics::Severity::Warning if changed.contains(file.as_str()) => {
+                    (Severity::Warning, "compile-warning")
+                }
+                _ => return,
+            };
+            let line = d.line.unwrap_or(0) as usize;
+            if seen.insert((file.clone(), line, d.message.clone())) {
+                findings.push(finding(severity, file, line, rule, d.message.clone()));
+            }
+        };
+        for (source, err) in failures.into_iter().flatten() {
+            let source = self.relative(&source);
+            let parsed = diagnostics::from_error(&err);
+            if !parsed
+                .iter()
+                .any(|d| d.severity == diagnostics::Severity::Error)
+            {
+                let message = format!("{err:#}");
+                report(
+                    &Diagnostic {
+                        tool: compiler.program.clone(),
+                        file: None,
+                        line: None,
+                        column: None,
+                        severity: diagnostics::Severity::Error,
+                        message: message.lines().last().unwrap_or_default().to_string(),
+                    },
+                    &source,
+                );
+            }
+            parsed.iter().for_each(|d| report(d, &source));
+        }
+        diagnostics::take().iter().for_each(|d| report(d, ""));
+        Ok(findings)
+    }
+
+    /// Every C source the build would compile, in the overlay.
+    fn sources(&self) -> Result<Vec<PathBuf>> {
+        let root = &self.overlay.root;
+        if self.config.sources.is_default() {
+            let kernel = root.join("kernel");
+            if !kernel.is_dir() {
+                return Ok(Vec::new());
+            }
+            return toolchain::discover_sources(&kernel);
+        }
+        self.config.sources.select(root)
+    }
+
+    /// `path` relative to the overlay, as the diff names it.
+    fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.overlay.root)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+fn finding(severity: Severity, file: String, line: usize, rule: &str, message: String) -> Finding {
+    Finding {
+        severity,
+        file,
+        line,
+        rule: rule.into(),
+        message,
+        fix: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_leaves_out_outputs_and_hidden_entries() {
+        let ws = std::env::temp_dir().join(format!("diff-validator-{}-ws", std::process::id()));
+        let _ = std::fs::remove_dir_all(&ws);
+        for dir in ["kernel/lib", "build/obj", ".git"] {
+            std::fs::create_dir_all(ws.join(dir)).unwrap();
+        }
+        std::fs::write(ws.join("kernel/lib/a.c"), "int a;\n").unwrap();
+        std::fs::write(ws.join("build/obj/a.o"), "").unwrap();
+        std::fs::write(ws.join(".git/HEAD"), "").unwrap();
+
+        let overlay = Overlay::new(&ws).unwrap();
+        let root = overlay.root.clone();
+        assert!(root.join("kernel/lib/a.c").is_file());
+        assert!(!root.join("build").exists() && !root.join(".git").exists());
+        drop(overlay);
+        assert!(!root.exists());
+        std::fs::remove_dir_all(ws).unwrap();
+    }
+}
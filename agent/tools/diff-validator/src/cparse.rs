@@ -0,0 +1,545 @@
+//! Just enough of a C parser for the semantic checks: the function
+//! definitions and enums in a stretch of source, each function body as a
+//! tree of statements.
+//!
+//! Input is lines with their line numbers, usually one hunk's new side, so
+//! a fragment may start or end inside a function: only definitions whose
+//! braces close within the fragment are returned. Comments and literals go
+//! through [`rules::lex`](crate::rules), preprocessor lines are skipped and
+//! their macros taken as written. Expressions are not parsed; statements
+//! keep the token range they cover.
+
+use crate::rules::{is_ident, lex};
+use std::ops::Range;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Token {
+    pub(crate) text: String,
+    pub(crate) line: usize,
+}
+
+/// Operators of more than one character, longest first.
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "...", "->", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=",
+    "-=", "*=", "/=", "%=", "&=", "|=", "^=",
+];
+
+const KEYWORDS: &[&str] = &[
+    "if", "else", "while", "for", "do", "switch", "case", "default", "return", "goto", "break",
+    "continue", "sizeof", "typedef", "struct", "union", "enum", "static", "extern", "const",
+    "volatile", "inline",
+];
+
+pub(crate) fn is_keyword(token: &str) -> bool {
+    KEYWORDS.contains(&token)
+}
+
+/// Tokens of `lines`, comments, literals' contents and preprocessor lines
+/// left out.
+pub(crate) fn tokenize<'a>(lines: impl IntoIterator<Item = (usize, &'a str)>) -> Vec<Token> {
+    let mut out = Vec::new();
+    let mut in_comment = false;
+    let mut directive = false;
+    for (line, text) in lines {
+        let code = lex(text, &mut in_comment).code;
+        let continued = text.trim_end().ends_with('\\');
+        if directive || code.trim_start().starts_with('#') {
+            directive = continued;
+            continue;
+        }
+        let bytes = code.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            let c = bytes[i];
+            let start = i;
+            if c.is_ascii_whitespace() {
+                i += 1;
+                continue;
+            }
+            if c.is_ascii_alphanumeric() || c == b'_' {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+            } else if let Some(op) = OPERATORS.iter().find(|op| code[i..].starts_with(*op)) {
+                i += op.len();
+            } else {
+                i += code[i..].chars().next().map_or(1, char::len_utf8);
+            }
+            out.push(Token {
+                text: code[start..i].to_string(),
+                line,
+            });
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Stmt {
+    Block(Vec<Stmt>),
+    /// An expression or declaration, up to its `;`.
+    Simple(Range<usize>),
+    If {
+        cond: Range<usize>,
+        then: Box<Stmt>,
+        els: Option<Box<Stmt>>,
+    },
+    /// `while`, `for`, `do` or a `foreach`-style macro: the head's tokens
+    /// (a `do`'s condition) and the body.
+    Loop {
+        head: Range<usize>,
+        body: Box<Stmt>,
+    },
+    Switch {
+        at: usize,
+        cond: Range<usize>,
+        body: Box<Stmt>,
+    },
+    /// `case` with its label's tokens, or `default` with none.
+    Case {
+        label: Range<usize>,
+    },
+    Return {
+        at: usize,
+        value: Range<usize>,
+    },
+    Goto {
+        label: String,
+    },
+    Label(String),
+    Break,
+    Continue,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Param {
+    pub(crate) name: String,
+    /// A pointer or array, as opposed to a value.
+    pub(crate) pointer: bool,
+    /// Type words before the name (`unsigned`, `int`, `struct`, `foo`).
+    pub(crate) types: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Function {
+    pub(crate) name: String,
+    pub(crate) params: Vec<Param>,
+    pub(crate) body: Vec<Stmt>,
+    /// Tokens of the whole body, braces included.
+    pub(crate) span: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Enum {
+    /// The tag, else the typedef name.
+    pub(crate) name: Option<String>,
+    pub(crate) members: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Unit {
+    pub(crate) tokens: Vec<Token>,
+    pub(crate) functions: Vec<Function>,
+    pub(crate) enums: Vec<Enum>,
+}
+
+impl Unit {
+    pub(crate) fn text(&self, range: Range<usize>) -> Vec<&str> {
+        self.tokens[range].iter().map(|t| t.text.as_str()).collect()
+    }
+
+    pub(crate) fn line(&self, at: usize) -> usize {
+        self.tokens[at.min(self.tokens.len().saturating_sub(1))].line
+    }
+}
+
+/// The token closing the bracket opened at `open`, if it closes.
+pub(crate) fn closing(tokens: &[Token], open: usize) -> Option<usize> {
+    let (o, c) = match tokens.get(open)?.text.as_str() {
+        "(" => ("(", ")"),
+        "[" => ("[", "]"),
+        "{" => ("{", "}"),
+        _ => return None,
+    };
+    let mut depth = 0usize;
+    for (i, t) in tokens.iter().enumerate().skip(open) {
+        if t.text == o {
+            depth += 1;
+        } else if t.text == c {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+    }
+    None
+}
+
+pub(crate) fn parse<'a>(lines: impl IntoIterator<Item = (usize, &'a str)>) -> Unit {
+    let tokens = tokenize(lines);
+    let enums = enums(&tokens);
+    let mut functions = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i].text.as_str() {
+            "{" => match closing(&tokens, i) {
+                Some(end) => i = end + 1,
+                None => break,
+            },
+            "(" => {
+                let Some(close) = closing(&tokens, i) else {
+                    break;
+                };
+                let named =
+                    i > 0 && is_ident(&tokens[i - 1].text) && !is_keyword(&tokens[i - 1].text);
+                let after_assign = i > 1 && tokens[i - 2].text == "=";
+                if named && !after_assign && tokens.get(close + 1).is_some_and(|t| t.text == "{") {
+                    let open = close + 1;
+                    let Some(end) = closing(&tokens, open) else {
+                        break;
+                    };
+                    let mut parser = Parser {
+                        tokens: &tokens,
+                        pos: open + 1,
+                        end,
+                    };
+                    if let Some(body) = parser.block_items() {
+                        functions.push(Function {
+                            name: tokens[i - 1].text.clone(),
+                            params: params(&tokens[i + 1..close]),
+                            body,
+                            span: open..end + 1,
+                        });
+                    }
+                    i = end + 1;
+                } else {
+                    i = close + 1;
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    Unit {
+        tokens,
+        functions,
+        enums,
+    }
+}
+
+fn params(tokens: &[Token]) -> Vec<Param> {
+    let mut out = Vec::new();
+    for param in tokens.split(|t| t.text == ",") {
+        let words: Vec<&str> = param.iter().map(|t| t.text.as_str()).collect();
+        // The name is the last identifier outside an array suffix.
+        let end = words.iter().position(|w| *w == "[").unwrap_or(words.len());
+        let Some(name_at) = words[..end].iter().rposition(|w| is_ident(w)) else {
+            continue;
+        };
+        let name = words[name_at];
+        if name == "void" || name_at == 0 {
+            continue;
+        }
+        out.push(Param {
+            name: name.to_string(),
+            pointer: words.iter().any(|w| matches!(*w, "*" | "[")),
+            types: words[..name_at]
+                .iter()
+                .filter(|w| is_ident(w))
+                .map(|w| w.to_string())
+                .collect(),
+        });
+    }
+    out
+}
+
+/// Every `enum [tag] { ... } [name]` in `tokens`.
+fn enums(tokens: &[Token]) -> Vec<Enum> {
+    let mut out = Vec::new();
+    for (i, t) in tokens.iter().enumerate() {
+        if t.text != "enum" {
+            continue;
+        }
+        let tag = tokens
+            .get(i + 1)
+            .filter(|t| is_ident(&t.text))
+            .map(|t| t.text.clone());
+        let open = i + 1 + usize::from(tag.is_some());
+        if tokens.get(open).is_none_or(|t| t.text != "{") {
+            continue;
+        }
+        let Some(close) = closing(tokens, open) else {
+            continue;
+        };
+        let mut members = Vec::new();
+        let mut expect = true;
+        let mut depth = 0usize;
+        for t in &tokens[open + 1..close] {
+            match t.text.as_str() {
+                "(" => depth += 1,
+                ")" => depth = depth.saturating_sub(1),
+                "," if depth == 0 => expect = true,
+                name if expect && is_ident(name) => {
+                    members.push(name.to_string());
+                    expect = false;
+                }
+                _ => expect = false,
+            }
+        }
+        let typedef = tokens
+            .get(close + 1)
+            .filter(|t| is_ident(&t.text))
+            .map(|t| t.text.clone());
+        out.push(Enum {
+            name: tag.or(typedef),
+            members,
+        });
+    }
+    out
+}
+
+struct Parser<'t> {
+    tokens: &'t [Token],
+    pos: usize,
+    /// The closing brace of the body.
+    end: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        (self.pos < self.end).then(|| self.tokens[self.pos].text.as_str())
+    }
+
+    fn peek_at(&self, n: usize) -> Option<&str> {
+        (self.pos + n < self.end).then(|| self.tokens[self.pos + n].text.as_str())
+    }
+
+    /// Statements up to the parser's end.
+    fn block_items(&mut self) -> Option<Vec<Stmt>> {
+        let mut items = Vec::new();
+        while self.pos < self.end {
+            items.push(self.stmt()?);
+        }
+        Some(items)
+    }
+
+    /// A parenthesised head: the tokens inside the parentheses.
+    fn parens(&mut self) -> Option<Range<usize>> {
+        if self.peek()? != "(" {
+            return None;
+        }
+        let close = closing(self.tokens, self.pos).filter(|&c| c < self.end)?;
+        let inner = self.pos + 1..close;
+        self.pos = close + 1;
+        Some(inner)
+    }
+
+    /// Tokens up to the next `;` outside brackets, which is consumed.
+    fn until_semicolon(&mut self) -> Range<usize> {
+        let start = self.pos;
+        let mut depth = 0usize;
+        while self.pos < self.end {
+            match self.tokens[self.pos].text.as_str() {
+                "(" | "[" | "{" => depth += 1,
+                ")" | "]" | "}" if depth > 0 => depth -= 1,
+                "}" => break,
+                ";" if depth == 0 => {
+                    self.pos += 1;
+                    return start..self.pos - 1;
+                }
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        start..self.pos
+    }
+
+    fn stmt(&mut self) -> Option<Stmt> {
+        let at = self.pos;
+        let word = self.peek()?.to_string();
+        match word.as_str() {
+            "{" => {
+                let close = closing(self.tokens, self.pos).filter(|&c| c < self.end)?;
+                let mut inner = Parser {
+                    tokens: self.tokens,
+                    pos: self.pos + 1,
+                    end: close,
+                };
+                let items = inner.block_items()?;
+                self.pos = close + 1;
+                Some(Stmt::Block(items))
+            }
+            ";" => {
+                self.pos += 1;
+                Some(Stmt::Block(Vec::new()))
+            }
+            "if" => {
+                self.pos += 1;
+                let cond = self.parens()?;
+                let then = Box::new(self.stmt()?);
+                let els = if self.peek() == Some("else") {
+                    self.pos += 1;
+                    Some(Box::new(self.stmt()?))
+                } else {
+                    None
+                };
+                Some(Stmt::If { cond, then, els })
+            }
+            "while" | "for" => {
+                self.pos += 1;
+                let head = self.parens()?;
+                let body = Box::new(self.stmt()?);
+                Some(Stmt::Loop { head, body })
+            }
+            "do" => {
+                self.pos += 1;
+                let body = Box::new(self.stmt()?);
+                if self.peek() != Some("while") {
+                    return None;
+                }
+                self.pos += 1;
+                let head = self.parens()?;
+                if self.peek() == Some(";") {
+                    self.pos += 1;
+                }
+                Some(Stmt::Loop { head, body })
+            }
+            "switch" => {
+                self.pos += 1;
+                let cond = self.parens()?;
+                let body = Box::new(self.stmt()?);
+                Some(Stmt::Switch { at, cond, body })
+            }
+            "case" | "default" => {
+                self.pos += 1;
+                let start = self.pos;
+                while self.peek().is_some_and(|t| t != ":") {
+                    self.pos += 1;
+                }
+                let label = start..self.pos;
+                self.pos += 1;
+                Some(Stmt::Case { label })
+            }
+            "return" => {
+                self.pos += 1;
+                let value = self.until_semicolon();
+                Some(Stmt::Return { at, value })
+            }
+            "goto" => {
+                self.pos += 1;
+                let label = self.peek()?.to_string();
+                self.until_semicolon();
+                Some(Stmt::Goto { label })
+            }
+            "break" | "continue" => {
+                self.until_semicolon();
+                Some(if word == "break" {
+                    Stmt::Break
+                } else {
+                    Stmt::Continue
+                })
+            }
+            _ if is_ident(&word) && !is_keyword(&word) && self.peek_at(1) == Some(":") => {
+                self.pos += 2;
+                Some(Stmt::Label(word))
+            }
+            // `list_for_each(pos, head) { ... }` and friends.
+            _ if is_ident(&word) && self.peek_at(1) == Some("(") => {
+                let close = closing(self.tokens, self.pos + 1).filter(|&c| c < self.end)?;
+                if self.tokens.get(close + 1).is_some_and(|t| t.text == "{") && close + 1 < self.end
+                {
+                    let head = self.pos..close + 1;
+                    self.pos = close + 1;
+                    let body = Box::new(self.stmt()?);
+                    return Some(Stmt::Loop { head, body });
+                }
+                Some(Stmt::Simple(self.until_semicolon()))
+            }
+            "}" => None,
+            _ => Some(Stmt::Simple(self.until_semicolon())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(src: &str) -> Unit {
+        parse(src.lines().enumerate().map(|(i, l)| (i + 1, l)))
+    }
+
+    #[test]
+    fn tokens_keep_operators_and_drop_comments_and_directives() {
+        let toks = tokenize([
+            (1, "#define X(a) \\"),
+            (2, "  (a)"),
+            (3, "x->y <<= 2; /* a < b */ s = \"a;b\";"),
+        ]);
+        let text: Vec<&str> = toks.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(
+            text,
+            ["x", "->", "y", "<<=", "2", ";", "s", "=", "\"", "\"", ";"]
+        );
+        assert!(toks.iter().all(|t| t.line == 3));
+    }
+
+    #[test]
+    fn functions_enums_and_statements() {
+        let u = unit(
+            "typedef enum { RED, GREEN = 2, BLUE } colour_t;\n\
+             static int table[4] = { 1, 2 };\n\
+             int paint(struct canvas *c, unsigned int n, char buf[])\n\
+             {\n\
+             \tif (n > 3)\n\
+             \t\treturn -1;\n\
+             \telse {\n\
+             \t\tlist_for_each(p, &c->items) { n++; }\n\
+             \t}\n\
+             \tswitch (n) { case RED: break; default: goto out; }\n\
+             out:\n\
+             \tdo { n--; } while (n);\n\
+             \treturn 0;\n\
+             }\n\
+             void cut(void) {\n\
+             \tif (x",
+        );
+        assert_eq!(
+            u.enums,
+            [Enum {
+                name: Some("colour_t".into()),
+                members: vec!["RED".into(), "GREEN".into(), "BLUE".into()],
+            }]
+        );
+        assert_eq!(u.functions.len(), 1, "the cut-off function is left out");
+        let f = &u.functions[0];
+        assert_eq!(f.name, "paint");
+        let names: Vec<(&str, bool)> = f.params.iter().map(|p| (&*p.name, p.pointer)).collect();
+        assert_eq!(names, [("c", true), ("n", false), ("buf", true)]);
+        assert_eq!(f.params[1].types, ["unsigned", "int"]);
+
+        let kinds: Vec<&str> = f
+            .body
+            .iter()
+            .map(|s| match s {
+                Stmt::If { els: Some(e), .. } => {
+                    let Stmt::Block(items) = &**e else { panic!() };
+                    assert!(matches!(items[0], Stmt::Loop { .. }));
+                    "if-else"
+                }
+                Stmt::Switch { body, .. } => {
+                    let Stmt::Block(items) = &**body else {
+                        panic!()
+                    };
+                    assert_eq!(items.len(), 4);
+                    "switch"
+                }
+                Stmt::Label(_) => "label",
+                Stmt::Loop { .. } => "loop",
+                Stmt::Return { .. } => "return",
+                other => panic!("{other:?}"),
+            })
+            .collect();
+        assert_eq!(kinds, ["if-else", "switch", "label", "loop", "return"]);
+        assert_eq!(u.line(f.span.end - 1), 14);
+    }
+}
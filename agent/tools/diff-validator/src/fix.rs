@@ -0,0 +1,461 @@
+//! Suggested fixes for mechanical findings, as patches on the files a diff
+//! leaves, so an agent can apply them rather than re-derive them from the
+//! message.
+//!
+//! - `banned-function`: `sprintf`/`vsprintf` become `snprintf`/`vsnprintf`
+//!   and `strcpy`/`strcat` become `strlcpy`/`strlcat`, bounded by the
+//!   `sizeof` of the destination (offered only when the diff shows it
+//!   declared as an array);
+//! - `missing-include-guard`: an `#ifndef AUTON_<NAME>_H` guard around the
+//!   new header, after its leading comment;
+//! - `style-format`: the lines as clang-format writes them.
+//!
+//! Each [`Fix`] is a patch with a few lines of context, taken from the diff;
+//! `--fixes <path>` writes them all as one patch (see [`patch`]), dropping
+//! any fix that would change lines an earlier one already does.
+
+use crate::patch::{FilePatch, HunkLine};
+use crate::Finding;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Lines of context around each change.
+const CONTEXT: usize = 3;
+
+/// Banned calls with a bounded replacement, and whether the bound goes
+/// second (`snprintf(buf, size, fmt, ...)`) or last (`strlcpy(d, s, size)`).
+const BOUNDED: &[(&str, &str, bool)] = &[
+    ("sprintf", "snprintf", true),
+    ("vsprintf", "vsnprintf", true),
+    ("strcpy", "strlcpy", false),
+    ("strcat", "strlcat", false),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Fix {
+    pub description: String,
+    /// A unified diff against the file as the diff leaves it.
+    pub patch: String,
+    #[serde(skip)]
+    file: String,
+    #[serde(skip)]
+    edits: Vec<Edit>,
+}
+
+/// One change: `remove` (possibly nothing) from `line` on replaced by
+/// `insert`, with the context around it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Edit {
+    pub(crate) line: usize,
+    pub(crate) before: Vec<String>,
+    pub(crate) remove: Vec<String>,
+    pub(crate) insert: Vec<String>,
+    pub(crate) after: Vec<String>,
+}
+
+impl Fix {
+    pub(crate) fn new(file: &str, description: String, edits: Vec<Edit>) -> Self {
+        Self {
+            description,
+            patch: render(file, &edits),
+            file: file.to_string(),
+            edits,
+        }
+    }
+}
+
+/// Every fix of `findings` as one patch, file by file.
+pub fn patch(findings: &[Finding]) -> String {
+    let mut files: BTreeMap<&str, Vec<Edit>> = BTreeMap::new();
+    for fix in findings.iter().filter_map(|f| f.fix.as_ref()) {
+        let edits = files.entry(&fix.file).or_default();
+        for e in &fix.edits {
+            if !edits.contains(e) {
+                edits.push(e.clone());
+            }
+        }
+    }
+    files
+        .into_iter()
+        .map(|(file, edits)| render(file, &edits))
+        .collect()
+}
+
+/// A hunk being built: its first old line, its lines with their markers,
+/// and the old line after it.
+struct Building {
+    start: usize,
+    lines: Vec<(char, String)>,
+    end: usize,
+}
+
+fn render(file: &str, edits: &[Edit]) -> String {
+    let mut edits = edits.to_vec();
+    edits.sort_by_key(|e| e.line);
+    let mut hunks: Vec<Building> = Vec::new();
+    let mut removed_to = 0;
+    for e in edits {
+        if e.line < removed_to {
+            continue;
+        }
+        removed_to = e.line + e.remove.len();
+        let start = e.line - e.before.len();
+        match hunks.last_mut() {
+            Some(h) if start <= h.end => {
+                // Overlapping context: end the hunk where this edit starts.
+                while h.end > e.line && h.lines.last().is_some_and(|(m, _)| *m == ' ') {
+                    h.lines.pop();
+                    h.end -= 1;
+                }
+                let skip = h.end - start;
+                h.lines
+                    .extend(e.before.iter().skip(skip).map(|l| (' ', l.clone())));
+            }
+            _ => hunks.push(Building {
+                start,
+                lines: e.before.iter().map(|l| (' ', l.clone())).collect(),
+                end: e.line,
+            }),
+        }
+        let h = hunks.last_mut().expect("a hunk was just opened");
+        h.lines.extend(e.remove.iter().map(|l| ('-', l.clone())));
+        h.lines.extend(e.insert.iter().map(|l| ('+', l.clone())));
+        h.lines.extend(e.after.iter().map(|l| (' ', l.clone())));
+        h.end = removed_to + e.after.len();
+    }
+
+    let mut out = format!("--- a/{file}\n+++ b/{file}\n");
+    let mut delta = 0isize;
+    for h in hunks {
+        let old = h.lines.iter().filter(|(m, _)| *m != '+').count();
+        let new = h.lines.iter().filter(|(m, _)| *m != '-').count();
+        let new_start = (h.start as isize + delta) as usize;
+        let start = |s: usize, len: usize| if len == 0 { s - 1 } else { s };
+        out.push_str(&format!(
+            "@@ -{},{old} +{},{new} @@\n",
+            start(h.start, old),
+            start(new_start, new)
+        ));
+        for (marker, line) in &h.lines {
+            out.push(*marker);
+            out.push_str(line);
+            out.push('\n');
+        }
+        delta += new as isize - old as isize;
+    }
+    out
+}
+
+/// The new-side lines a file patch shows, by line number.
+fn known_lines(file: &FilePatch) -> BTreeMap<usize, &str> {
+    let mut known = BTreeMap::new();
+    for hunk in &file.hunks {
+        let mut line = hunk.new_start;
+        for l in &hunk.lines {
+            match l {
+                HunkLine::Removed(_) => continue,
+                HunkLine::Context(t) | HunkLine::Added(t) => known.insert(line, t.as_str()),
+            };
+            line += 1;
+        }
+    }
+    known
+}
+
+/// An edit replacing `remove` lines from `line` on, with whatever context
+/// `known` has.
+pub(crate) fn edit(
+    known: &BTreeMap<usize, &str>,
+    line: usize,
+    remove: usize,
+    insert: Vec<String>,
+) -> Edit {
+    let mut before: Vec<String> = (1..=CONTEXT)
+        .map_while(|k| line.checked_sub(k).and_then(|l| known.get(&l)))
+        .map(|l| l.to_string())
+        .collect();
+    before.reverse();
+    let after = (0..CONTEXT)
+        .map_while(|k| known.get(&(line + remove + k)))
+        .map(|l| l.to_string())
+        .collect();
+    Edit {
+        line,
+        before,
+        remove: (line..line + remove)
+            .filter_map(|l| known.get(&l))
+            .map(|l| l.to_string())
+            .collect(),
+        insert,
+        after,
+    }
+}
+
+/// Attach fixes to the `banned-function` and `missing-include-guard`
+/// findings of the diff `files`.
+pub fn attach(files: &[FilePatch], findings: &mut [Finding]) {
+    let known: HashMap<&str, BTreeMap<usize, &str>> = files
+        .iter()
+        .filter_map(|f| Some((f.new_path.as_deref()?, known_lines(f))))
+        .collect();
+    for f in findings.iter_mut().filter(|f| f.fix.is_none()) {
+        let Some(lines) = known.get(f.file.as_str()) else {
+            continue;
+        };
+        f.fix = match f.rule.as_str() {
+            "banned-function" => f
+                .message
+                .split('`')
+                .nth(1)
+                .and_then(|name| bounded_call(&f.file, lines, f.line, name)),
+            "missing-include-guard" => include_guard(&f.file, lines),
+            _ => None,
+        };
+    }
+}
+
+fn bounded_call(file: &str, lines: &BTreeMap<usize, &str>, line: usize, name: &str) -> Option<Fix> {
+    let text = lines.get(&line)?;
+    let &(banned, with, second) = BOUNDED.iter().find(|(banned, ..)| *banned == name)?;
+    let (fixed, dest) = rewrite_call(text, banned, with, second)?;
+    let array = dest.rsplit(['.', '>']).next()?;
+    if !lines.values().any(|l| declares_array(l, array)) {
+        return None;
+    }
+    let description = format!("use {with} bounded by sizeof({dest})");
+    Some(Fix::new(
+        file,
+        description,
+        vec![edit(lines, line, 1, vec![fixed])],
+    ))
+}
+
+/// `text` with its first call of `banned` made a bounded call of `with`,
+/// and the destination that bounds it.
+fn rewrite_call(text: &str, banned: &str, with: &str, second: bool) -> Option<(String, String)> {
+    let bytes = text.as_bytes();
+    let boundary = |i: usize| {
+        bytes
+            .get(i)
+            .is_none_or(|b| !(b.is_ascii_alphanumeric() || *b == b'_'))
+    };
+    let at = text.match_indices(banned).map(|(i, _)| i).find(|&i| {
+        let prev_ok =
+            i == 0 || (boundary(i - 1) && !text[..i].ends_with('.') && !text[..i].ends_with("->"));
+        prev_ok
+            && boundary(i + banned.len())
+            && text[i + banned.len()..].trim_start().starts_with('(')
+    })?;
+    let open = at + banned.len() + text[at + banned.len()..].find('(')?;
+
+    // The argument list, and where its first comma is, outside literals.
+    let (mut depth, mut quote, mut comma, mut close) = (0usize, None, None, None);
+    let mut escaped = false;
+    for (i, c) in text[open..].char_indices().map(|(i, c)| (open + i, c)) {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        close = Some(i);
+                        break;
+                    }
+                }
+                ',' if depth == 1 && comma.is_none() => comma = Some(i),
+                _ => {}
+            },
+        }
+    }
+    let (close, comma) = (close?, comma?);
+    let dest = text[open + 1..comma].trim();
+    let named = dest.split("->").flat_map(|p| p.split('.')).all(|p| {
+        p.chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && p.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    });
+    if !named {
+        return None;
+    }
+    let call = if second {
+        format!("{with}({dest}, sizeof({dest}),{})", &text[comma + 1..close])
+    } else {
+        format!("{with}({}, sizeof({dest}))", &text[open + 1..close])
+    };
+    let fixed = format!("{}{call}{}", &text[..at], &text[close + 1..]);
+    Some((fixed, dest.to_string()))
+}
+
+/// Whether `line` declares `name` as an array, as `char name[16];` does.
+fn declares_array(line: &str, name: &str) -> bool {
+    let code = crate::rules::lex(line, &mut false).code;
+    let toks = crate::rules::tokens(&code);
+    toks.windows(3).any(|w| {
+        w[1] == name
+            && w[2] == "["
+            && w[0].chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !matches!(w[0], "return" | "sizeof" | "case" | "goto")
+    }) && toks.last() == Some(&";")
+}
+
+/// The guard macro for a header: `kernel/include/pmm.h` → `AUTON_PMM_H`.
+fn guard_name(file: &str) -> String {
+    let name = file.rsplit('/').next().unwrap_or(file);
+    let stem = name.strip_suffix(".h").unwrap_or(name);
+    let stem: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("AUTON_{stem}_H")
+}
+
+fn include_guard(file: &str, lines: &BTreeMap<usize, &str>) -> Option<Fix> {
+    let last = *lines.keys().next_back()?;
+    // After the leading comment, as the kernel's headers have it.
+    let mut in_comment = false;
+    let first_code = lines.iter().find_map(|(&n, l)| {
+        let code = crate::rules::lex(l, &mut in_comment).code;
+        (!code.trim().is_empty()).then_some(n)
+    })?;
+    let guard = guard_name(file);
+    let open = edit(
+        lines,
+        first_code,
+        0,
+        vec![
+            format!("#ifndef {guard}"),
+            format!("#define {guard}"),
+            String::new(),
+        ],
+    );
+    let close = edit(
+        lines,
+        last + 1,
+        0,
+        vec![String::new(), format!("#endif /* {guard} */")],
+    );
+    Some(Fix::new(
+        file,
+        format!("guard the header with {guard}"),
+        vec![open, close],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(lines: &[&'static str]) -> BTreeMap<usize, &'static str> {
+        lines.iter().enumerate().map(|(i, l)| (i + 1, *l)).collect()
+    }
+
+    #[test]
+    fn banned_calls_become_bounded_ones() {
+        assert_eq!(
+            rewrite_call(
+                "\tsprintf(buf, \"%d, %d\", a, b);",
+                "sprintf",
+                "snprintf",
+                true
+            ),
+            Some((
+                "\tsnprintf(buf, sizeof(buf), \"%d, %d\", a, b);".to_string(),
+                "buf".to_string()
+            ))
+        );
+        assert_eq!(
+            rewrite_call("n = strcpy(d->name, src) + 1;", "strcpy", "strlcpy", false)
+                .unwrap()
+                .0,
+            "n = strlcpy(d->name, src, sizeof(d->name)) + 1;"
+        );
+        // A pointer expression has no useful sizeof.
+        assert_eq!(
+            rewrite_call("strcpy(p + 1, s);", "strcpy", "strlcpy", false),
+            None
+        );
+        assert_eq!(
+            rewrite_call("my_strcpy(d, s);", "strcpy", "strlcpy", false),
+            None
+        );
+
+        assert!(declares_array(
+            "\tchar name[32]; /* NUL-terminated */",
+            "name"
+        ));
+        assert!(!declares_array("\tname[0] = 0;", "name"));
+        assert!(!declares_array("void f(char *name);", "name"));
+    }
+
+    #[test]
+    fn fixes_render_with_context_and_combine() {
+        let lines = numbered(&["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l"]);
+        let one = Fix::new(
+            "k.c",
+            "x".into(),
+            vec![edit(&lines, 2, 1, vec!["B".into()])],
+        );
+        assert_eq!(
+            one.patch,
+            "--- a/k.c\n+++ b/k.c\n@@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n"
+        );
+
+        // Nearby edits share a hunk; later hunks shift by earlier insertions.
+        let two = Fix::new(
+            "k.c",
+            "y".into(),
+            vec![
+                edit(&lines, 4, 0, vec!["new".into()]),
+                edit(&lines, 13, 0, vec!["end".into()]),
+            ],
+        );
+        let findings: Vec<Finding> = [one, two]
+            .into_iter()
+            .map(|fix| Finding {
+                severity: crate::Severity::Error,
+                file: "k.c".into(),
+                line: 1,
+                rule: "r".into(),
+                message: String::new(),
+                fix: Some(fix),
+            })
+            .collect();
+        assert_eq!(
+            patch(&findings),
+            "--- a/k.c\n+++ b/k.c\n\
+             @@ -1,6 +1,7 @@\n a\n-b\n+B\n c\n+new\n d\n e\n f\n\
+             @@ -10,3 +11,4 @@\n j\n k\n l\n+end\n"
+        );
+    }
+
+    #[test]
+    fn include_guards_go_after_the_leading_comment() {
+        let lines = numbered(&["/* Frame allocator. */", "", "int pmm_pages(void);"]);
+        let fix = include_guard("kernel/include/pmm.h", &lines).unwrap();
+        assert_eq!(fix.description, "guard the header with AUTON_PMM_H");
+        assert_eq!(
+            fix.patch,
+            "--- a/kernel/include/pmm.h\n+++ b/kernel/include/pmm.h\n\
+             @@ -1,3 +1,8 @@\n /* Frame allocator. */\n \n\
+             +#ifndef AUTON_PMM_H\n+#define AUTON_PMM_H\n+\n int pmm_pages(void);\n\
+             +\n+#endif /* AUTON_PMM_H */\n"
+        );
+    }
+}
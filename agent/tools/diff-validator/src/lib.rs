@@ -2,11 +2,43 @@
 //!
 //! Parses unified diffs into the set of *added* lines (with file + new line
 //! number) and applies freestanding-kernel coding rules. Pure functions — the
-//! binary in `main.rs` only handles I/O.
-
-use serde::Serialize;
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+//! binary in `main.rs` only handles I/O. [`patch`] (auton-core's diff
+//! model) parses whole diffs into files, hunks, renames and modes, and
+//! [`apply`] checks that one applies cleanly to a workspace, hunk by hunk.
+//! [`series`] validates the commits of a git range or a directory of patches
+//! one by one, each with its own verdict. The
+//! kernel C rules and the rules file that tunes every rule are in [`rules`],
+//! [`security`] flags privileged-state and user-memory hazards,
+//! [`semantic`] checks the functions a small C parser finds in each hunk, and
+//! [`policy`] limits a diff's size and the paths it may touch. Beyond the
+//! static rules, [`compile`] builds the patched tree, [`analyzer`] runs
+//! clang-tidy on it, [`style`] holds added lines to the workspace's
+//! `.clang-format` and [`abi`] flags exports the linked kernel loses and a
+//! load address it moves. [`fix`] suggests a patch for the mechanical
+//! findings, [`risk`] scores how risky a diff is, and [`sarif`] writes
+//! findings for other tools.
+
+pub mod abi;
+pub mod analyzer;
+pub mod apply;
+pub mod compile;
+mod cparse;
+pub mod fix;
+pub mod policy;
+pub mod risk;
+pub mod rules;
+pub mod sarif;
+pub mod security;
+pub mod semantic;
+pub mod series;
+pub mod style;
+
+pub use auton_core::diff as patch;
+
+use rules::Rules;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Severity {
     Error,
@@ -21,6 +53,8 @@ pub struct Finding {
     pub line: usize,
     pub rule: String,
     pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fix: Option<fix::Fix>,
 }
 
 /// An added (`+`) line in a unified diff, with its file and new-file line number.
@@ -80,26 +114,24 @@ pub fn parse_added_lines(diff: &str) -> Vec<AddedLine> {
     added
 }
 
-const HOSTED_HEADERS: &[&str] = &[
-    "<stdio.h>",
-    "<stdlib.h>",
-    "<string.h>",
-    "<stdio>",
-    "<assert.h>",
-    "<math.h>",
-];
-
-/// Apply freestanding-kernel rules to the added lines of a diff.
+/// Apply the default freestanding-kernel rules to the added lines of a diff.
 pub fn validate(diff: &str) -> Vec<Finding> {
+    validate_with(diff, &Rules::default())
+}
+
+/// Apply freestanding-kernel rules, as tuned by `rules`, to the added lines
+/// of a diff.
+pub fn validate_with(diff: &str, rules: &Rules) -> Vec<Finding> {
     let mut findings = Vec::new();
+    let added = parse_added_lines(diff);
 
-    for added in parse_added_lines(diff) {
+    for added in &added {
         let content = &added.content;
         let trimmed = content.trim_start();
 
         // ERROR: hosted libc headers are unavailable in a freestanding kernel.
         if trimmed.starts_with("#include") {
-            for h in HOSTED_HEADERS {
+            for h in &rules.hosted_headers {
                 if content.contains(h) {
                     findings.push(Finding {
                         severity: Severity::Error,
@@ -109,6 +141,7 @@ pub fn validate(diff: &str) -> Vec<Finding> {
                         message: format!(
                             "hosted libc header {h} not available in freestanding kernel"
                         ),
+                        fix: None,
                     });
                 }
             }
@@ -122,6 +155,7 @@ pub fn validate(diff: &str) -> Vec<Finding> {
                 line: added.line,
                 rule: "todo-marker".into(),
                 message: "added line contains a TODO/FIXME marker".into(),
+                fix: None,
             });
         }
 
@@ -133,11 +167,21 @@ pub fn validate(diff: &str) -> Vec<Finding> {
                 line: added.line,
                 rule: "trailing-whitespace".into(),
                 message: "added line has trailing whitespace".into(),
+                fix: None,
             });
         }
     }
 
-    findings
+    findings.extend(rules::check_c(&added, rules));
+    findings.extend(security::check(&added, rules));
+    // Bare `+++`/`@@` fragments do not parse; they carry only added lines.
+    if let Ok(files) = patch::parse(diff) {
+        findings.extend(rules::check_headers(&files));
+        findings.extend(semantic::check(&files, rules));
+        findings.extend(policy::check(&files, &rules.policy));
+        fix::attach(&files, &mut findings);
+    }
+    rules.finish(findings)
 }
 
 /// True if any finding is an error (used to set the process exit code).
@@ -1,8 +1,20 @@
 //! diff-validator: static analysis of agent-proposed C-kernel diffs.
 
 use anyhow::{Context, Result};
-use clap::Parser;
-use diff_validator::{has_errors, validate};
+use clap::{Parser, ValueEnum};
+use diff_validator::analyzer::{self, Analyzer, AnalyzerKind};
+use diff_validator::apply::{self, Tree, DEFAULT_FUZZ};
+use diff_validator::compile::{CompileCheck, CompileOptions};
+use diff_validator::fix;
+use diff_validator::risk::{History, Risk};
+use diff_validator::rules::Rules;
+use diff_validator::sarif;
+use diff_validator::series::{self, Checks, Verdict};
+use diff_validator::style::{self, StyleCheck};
+use diff_validator::{has_errors, patch, Finding};
+use kernel_builder::jobs;
+use kernel_builder::profile::Profile;
+use kernel_builder::symdiff;
 use std::io::Read;
 use std::path::PathBuf;
 
@@ -13,43 +25,247 @@ use std::path::PathBuf;
 )]
 struct Cli {
     /// Unified diff file to validate. Reads stdin if omitted.
-    #[arg(short, long)]
+    #[arg(short, long, conflicts_with_all = ["git_range", "patches"])]
     input: Option<PathBuf>,
 
-    /// Emit findings as JSON.
+    /// Validate each commit of a git range (e.g. `main..topic`) on its own,
+    /// checking that it applies on top of the ones before it.
+    #[arg(long, value_name = "BASE..HEAD", conflicts_with = "patches")]
+    git_range: Option<String>,
+
+    /// Repository for `--git-range`.
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    repo: PathBuf,
+
+    /// Validate each `*.patch`/`*.diff` file of DIR on its own, in name
+    /// order, as written by `git format-patch`.
+    #[arg(long, value_name = "DIR")]
+    patches: Option<PathBuf>,
+
+    /// Also check that every hunk applies cleanly to the tree at DIR (for
+    /// `--git-range`, instead of the range's base commit).
+    #[arg(short, long, value_name = "DIR")]
+    workspace: Option<PathBuf>,
+
+    /// Rules file tuning the checks. Defaults to `diff-validator.toml` in the
+    /// current directory, else in the workspace (or repository).
+    #[arg(long, value_name = "PATH")]
+    rules: Option<PathBuf>,
+
+    /// Let this diff touch a path the policy forbids (repeatable).
+    #[arg(long, value_name = "PREFIX")]
+    allow_path: Vec<String>,
+
+    /// Context lines at each end of a hunk that may differ when applying
+    /// (as `patch --fuzz`).
+    #[arg(long, default_value_t = DEFAULT_FUZZ)]
+    fuzz: usize,
+
+    /// Merge files whose hunks do not apply three ways, with the blob the
+    /// diff's `index` line names, telling context drift from conflicts.
     #[arg(long)]
+    merge: bool,
+
+    /// Write the diff rebased onto the workspace as it is now here.
+    #[arg(long, value_name = "PATH", requires_all = ["merge", "workspace"], conflicts_with_all = ["git_range", "patches"])]
+    rebased: Option<PathBuf>,
+
+    /// Also compile the patched tree (an overlay of the workspace) with
+    /// kernel-builder and report compiler diagnostics.
+    #[arg(long, requires = "workspace")]
+    compile: bool,
+
+    /// Target architecture for `--compile`.
+    #[arg(long, default_value = "x86_64")]
+    arch: String,
+
+    /// C compiler for `--compile` (e.g. x86_64-linux-gnu-gcc).
+    #[arg(long)]
+    cc: Option<String>,
+
+    /// Flag profile for `--compile`.
+    #[arg(long, value_enum, default_value_t = Profile::default())]
+    profile: Profile,
+
+    /// Concurrent compile jobs (default: number of CPUs).
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Also run this analyzer on the changed files of the patched tree,
+    /// with the flags of a `compile_commands.json` (skipped without one).
+    #[arg(long, value_enum, requires = "workspace")]
+    analyzer: Option<AnalyzerKind>,
+
+    /// Compile database for `--analyzer`. Defaults to
+    /// `compile_commands.json` in the workspace, its `build/`, or `./build/`.
+    #[arg(long, value_name = "PATH")]
+    compdb: Option<PathBuf>,
+
+    /// Also check that added lines are formatted as the workspace's
+    /// `.clang-format` asks (skipped without one).
+    #[arg(long, requires = "workspace")]
+    style: bool,
+
+    /// Write the patch that fixes the formatting `--style` reports here,
+    /// to apply on top of the diff.
+    #[arg(long, value_name = "PATH", requires = "style", conflicts_with_all = ["git_range", "patches"])]
+    style_patch: Option<PathBuf>,
+
+    /// Write the fixes suggested for the findings (bounded string calls,
+    /// include guards, formatting) here as one patch, to apply on top of
+    /// the diff.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["git_range", "patches"])]
+    fixes: Option<PathBuf>,
+
+    /// The kernel ELF linked before the diff, to compare with `--new-elf`
+    /// for removed exports and a moved load address.
+    #[arg(long, value_name = "PATH", requires = "new_elf", conflicts_with_all = ["git_range", "patches"])]
+    old_elf: Option<PathBuf>,
+
+    /// The kernel ELF linked with the diff applied.
+    #[arg(long, value_name = "PATH", requires = "old_elf")]
+    new_elf: Option<PathBuf>,
+
+    /// Also score each diff's risk, 0–100, with the factors behind it.
+    #[arg(long)]
+    risk: bool,
+
+    /// Per-file outcomes `--risk` weighs failure rates from.
+    #[arg(long, value_name = "PATH")]
+    history: Option<PathBuf>,
+
+    /// Record the diff's outcome for each file it touches in `--history`.
+    #[arg(long, value_enum, requires = "history", conflicts_with_all = ["git_range", "patches"])]
+    record: Option<Outcome>,
+
+    /// How to print findings.
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Same as `--format json`.
+    #[arg(long, conflicts_with = "format")]
     json: bool,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// A line per finding, tab-separated.
+    Text,
+    /// The findings (or verdicts, for a series) as a JSON array; with
+    /// `--risk`, one diff's are an object of `findings` and `risk`.
+    Json,
+    /// A SARIF 2.1.0 log.
+    Sarif,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Outcome {
+    Pass,
+    Fail,
+}
+
+impl Cli {
+    fn format(&self) -> Format {
+        if self.json {
+            Format::Json
+        } else {
+            self.format
+        }
+    }
+}
+
 fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    auton_core::logging::init();
     let cli = Cli::parse();
 
-    let diff = match &cli.input {
-        Some(path) => {
-            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?
-        }
+    let mut dirs = Vec::new();
+    dirs.extend(cli.workspace.as_deref());
+    if cli.git_range.is_some() {
+        dirs.push(cli.repo.as_path());
+    }
+    let mut rules = Rules::find(cli.rules.as_deref(), &dirs)?;
+    rules
+        .allow
+        .entry("policy-forbidden-path".into())
+        .or_default()
+        .extend(cli.allow_path.iter().cloned());
+
+    if cli.git_range.is_some() || cli.patches.is_some() {
+        return run_series(&cli, &rules);
+    }
+
+    // Invalid UTF-8 is read as U+FFFD, for `policy-suspicious-encoding`.
+    let bytes = match &cli.input {
+        Some(path) => std::fs::read(path).with_context(|| format!("reading {}", path.display()))?,
         None => {
-            let mut buf = String::new();
+            let mut buf = Vec::new();
             std::io::stdin()
-                .read_to_string(&mut buf)
+                .read_to_end(&mut buf)
                 .context("reading stdin")?;
             buf
         }
     };
+    let diff = String::from_utf8_lossy(&bytes).into_owned();
 
-    let findings = validate(&diff);
+    let mut checks = checks(&cli, &rules)?;
+    checks.tree = cli
+        .workspace
+        .as_deref()
+        .map(|w| merging(&cli, Tree::new(w)));
+    let findings = checks.check(&diff);
+    if let (Some(path), Some(style)) = (&cli.style_patch, &checks.style) {
+        std::fs::write(path, &style.patch)
+            .with_context(|| format!("writing {}", path.display()))?;
+    }
+    if let Some(path) = &cli.fixes {
+        std::fs::write(path, fix::patch(&findings))
+            .with_context(|| format!("writing {}", path.display()))?;
+    }
+    if let (Some(path), Some(workspace)) = (&cli.rebased, &cli.workspace) {
+        let files = patch::parse(&diff).unwrap_or_default();
+        let mut tree = merging(&cli, Tree::new(workspace));
+        let results = tree.apply(&files, cli.fuzz);
+        std::fs::write(path, apply::rebased(&files, &results, workspace, &tree))
+            .with_context(|| format!("writing {}", path.display()))?;
+    }
+    let risk = checks.score(&diff, &findings);
+    // Exiting below skips destructors, and the overlays must go.
+    drop(checks);
+    if let (Some(outcome), Some(path)) = (cli.record, &cli.history) {
+        let mut history = History::load(path)?;
+        history.record(
+            &patch::parse(&diff).unwrap_or_default(),
+            outcome == Outcome::Pass,
+        );
+        history.save(path)?;
+    }
 
-    if cli.json {
-        println!("{}", serde_json::to_string_pretty(&findings)?);
-    } else if findings.is_empty() {
-        println!("diff-validator: no issues");
-    } else {
-        for f in &findings {
-            println!(
-                "{:?}\t{}:{}\t{}\t{}",
-                f.severity, f.file, f.line, f.rule, f.message
-            );
+    match (cli.format(), &risk) {
+        (Format::Json, None) => println!("{}", serde_json::to_string_pretty(&findings)?),
+        (Format::Json, Some(risk)) => println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "findings": findings,
+                "risk": risk,
+            }))?
+        ),
+        (Format::Sarif, _) => {
+            let mut log = sarif::log(&findings);
+            if let Some(risk) = &risk {
+                log["runs"][0]["properties"] = serde_json::json!({ "risk": risk });
+            }
+            println!("{}", serde_json::to_string_pretty(&log)?);
+        }
+        (Format::Text, _) => {
+            if findings.is_empty() {
+                println!("diff-validator: no issues");
+            }
+            for f in &findings {
+                println!("{}", line(f));
+            }
+            if let Some(risk) = &risk {
+                print_risk(risk, "");
+            }
         }
     }
 
@@ -58,3 +274,125 @@ fn main() -> Result<()> {
     }
     Ok(())
 }
+
+/// The checks `cli` asks for, short of the tree to apply to.
+fn checks<'r>(cli: &Cli, rules: &'r Rules) -> Result<Checks<'r>> {
+    let mut checks = Checks::new(rules);
+    checks.fuzz = cli.fuzz;
+    if cli.risk {
+        checks.risk = Some(match &cli.history {
+            Some(path) => History::load(path)?,
+            None => History::default(),
+        });
+    }
+    if let (Some(old), Some(new)) = (&cli.old_elf, &cli.new_elf) {
+        checks.abi = Some(symdiff::compare(old, new)?);
+    }
+    if let (true, Some(workspace)) = (cli.compile, &cli.workspace) {
+        let options = CompileOptions {
+            arch: cli.arch.clone(),
+            cc: cli.cc.clone(),
+            profile: cli.profile,
+            jobs: cli.jobs.unwrap_or_else(jobs::default_jobs),
+        };
+        checks.compile = Some(CompileCheck::new(workspace, options)?);
+    }
+    if let (Some(AnalyzerKind::ClangTidy), Some(workspace)) = (cli.analyzer, &cli.workspace) {
+        match analyzer::find_compdb(cli.compdb.as_deref(), workspace) {
+            Some(compdb) => {
+                let config = rules.clang_tidy.clone();
+                checks.analyzer = Some(Analyzer::new(workspace, &compdb, config)?);
+            }
+            None => tracing::warn!(
+                "no {} found (build with kernel-builder --emit-compdb); skipping clang-tidy",
+                kernel_builder::compdb::COMPDB_NAME
+            ),
+        }
+    }
+    if let (true, Some(workspace)) = (cli.style, &cli.workspace) {
+        checks.style = StyleCheck::new(workspace, rules.clang_format.clone())?;
+        if checks.style.is_none() {
+            tracing::warn!(
+                "no {} in {}; skipping the style check",
+                style::STYLE_NAME,
+                workspace.display()
+            );
+        }
+    }
+    Ok(checks)
+}
+
+fn line(f: &Finding) -> String {
+    format!(
+        "{:?}\t{}:{}\t{}\t{}",
+        f.severity, f.file, f.line, f.rule, f.message
+    )
+}
+
+fn merging(cli: &Cli, mut tree: Tree) -> Tree {
+    tree.merge = cli.merge;
+    tree
+}
+
+fn print_risk(risk: &Risk, indent: &str) {
+    println!("{indent}risk {}/100", risk.score);
+    for f in &risk.factors {
+        println!("{indent}  {}\t{}/{}\t{}", f.name, f.points, f.max, f.detail);
+    }
+}
+
+/// `--git-range` / `--patches`: a verdict per commit or patch.
+fn run_series(cli: &Cli, rules: &Rules) -> Result<()> {
+    let (items, tree) = if let Some(range) = &cli.git_range {
+        let items = series::git_range(&cli.repo, range)
+            .with_context(|| format!("reading commits {range}"))?;
+        let tree = match (&cli.workspace, items.first()) {
+            (Some(workspace), _) => Some(Tree::new(workspace)),
+            (None, Some(first)) => Some(series::range_base(&cli.repo, first)),
+            (None, None) => None,
+        };
+        (items, tree)
+    } else {
+        let dir = cli.patches.as_ref().expect("a series was asked for");
+        let items = series::patch_dir(dir).with_context(|| format!("reading {}", dir.display()))?;
+        (items, cli.workspace.as_deref().map(Tree::new))
+    };
+
+    let mut checks = checks(cli, rules)?;
+    checks.tree = tree.map(|t| merging(cli, t));
+    let verdicts = checks.series(&items);
+    drop(checks);
+    match cli.format() {
+        Format::Json => println!("{}", serde_json::to_string_pretty(&verdicts)?),
+        Format::Sarif => println!(
+            "{}",
+            serde_json::to_string_pretty(&sarif::series_log(&verdicts))?
+        ),
+        Format::Text => print_verdicts(&verdicts),
+    }
+
+    if verdicts.iter().any(|v| !v.passed) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn print_verdicts(verdicts: &[Verdict]) {
+    for v in verdicts {
+        let status = if v.passed { "PASS" } else { "FAIL" };
+        println!(
+            "{status}\t{}\t{}",
+            v.id,
+            v.subject.as_deref().unwrap_or_default()
+        );
+        for f in &v.findings {
+            println!("    {}", line(f));
+        }
+        if let Some(risk) = &v.risk {
+            print_risk(risk, "    ");
+        }
+    }
+    let passed = verdicts.iter().filter(|v| v.passed).count();
+    let noun = if verdicts.len() == 1 { "diff" } else { "diffs" };
+    println!("diff-validator: {passed} of {} {noun} pass", verdicts.len());
+}
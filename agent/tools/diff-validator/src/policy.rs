@@ -0,0 +1,382 @@
+//! Policy on a diff's size and on the paths it touches, checked on the
+//! whole parsed diff rather than its added lines:
+//!
+//! - `policy-max-lines`: more than `max-lines` lines added and removed;
+//! - `policy-max-files`: more than `max-files` files touched;
+//! - `policy-forbidden-path`: a file under a `forbidden` path (a rename
+//!   counts for both of its paths);
+//! - `policy-required-path`: a file under a requirement's `changed` path
+//!   with no file under any of its `with` paths in the same diff;
+//! - `policy-binary-file`: a binary file added or changed;
+//! - `policy-file-too-large`: more than `max-file-bytes` of text added to
+//!   one file;
+//! - `policy-build-artifact`: a file added under an `artifacts` path, or
+//!   named like one (`*.o`);
+//! - `policy-suspicious-encoding`: added text with NUL or other control
+//!   characters, invalid UTF-8 (read as U+FFFD), a byte-order mark or a
+//!   bidirectional-override character that can make code read other than
+//!   it compiles.
+//!
+//! All are errors, and their rule IDs all start with `policy-` so the
+//! orchestrator can tell "make the patch smaller or pair it with a test"
+//! from a coding fault. Paths match at any directory level, like the other
+//! path lists. By default patches stay under 500 lines and 20 files, leave
+//! `boot/` and `linker.ld` alone, bring a test (under `tests/`) along
+//! with driver changes, add at most 64 KiB to a file and add no objects,
+//! images or `build/` output. A forbidden path is allowed like any rule, in the
+//! rules file's `[allow]` table or for one run with `--allow-path`:
+//!
+//! ```toml
+//! [policy]
+//! max-lines = 800
+//! max-files = 0  # no limit
+//! forbidden = ["boot/", "linker.ld", "grub/"]
+//! max-file-bytes = 131072
+//! artifacts = ["*.o", "*.img", "build/", "out/"]
+//!
+//! [[policy.require]]
+//! changed = "kernel/drivers/"
+//! with = ["tests/", "kernel/tests/"]
+//!
+//! [allow]
+//! policy-forbidden-path = ["boot/limine.conf"]
+//! ```
+
+use crate::patch::{FilePatch, HunkLine};
+use crate::rules::under;
+use crate::{Finding, Severity};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Policy {
+    /// Lines added plus removed, over every file; 0 for no limit.
+    pub max_lines: usize,
+    /// Files touched; 0 for no limit.
+    pub max_files: usize,
+    /// Path prefixes, at any directory level, no diff may touch.
+    pub forbidden: Vec<String>,
+    pub require: Vec<Requirement>,
+    /// Text added to one file, in bytes; 0 for no limit.
+    pub max_file_bytes: usize,
+    /// What no diff may add: `*.ext` file names, or path prefixes at any
+    /// directory level.
+    pub artifacts: Vec<String>,
+}
+
+/// Changes under `changed` must come with a change under one of `with`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Requirement {
+    pub changed: String,
+    pub with: Vec<String>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            max_lines: 500,
+            max_files: 20,
+            forbidden: vec!["boot/".into(), "linker.ld".into()],
+            require: vec![Requirement {
+                changed: "kernel/drivers/".into(),
+                with: vec!["tests/".into()],
+            }],
+            max_file_bytes: 64 * 1024,
+            artifacts: [
+                "*.o", "*.a", "*.so", "*.ko", "*.d", "*.elf", "*.bin", "*.img", "*.iso", "*.qcow2",
+                "build/", "target/",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        }
+    }
+}
+
+/// Every path a file patch touches: both sides of a rename.
+pub(crate) fn paths(file: &FilePatch) -> impl Iterator<Item = &str> {
+    let old = file.old_path.as_deref().filter(|_| file.rename);
+    file.new_path
+        .as_deref()
+        .or(file.old_path.as_deref())
+        .into_iter()
+        .chain(old)
+}
+
+pub(crate) fn changed_lines(file: &FilePatch) -> usize {
+    file.hunks
+        .iter()
+        .flat_map(|h| &h.lines)
+        .filter(|l| !matches!(l, HunkLine::Context(_)))
+        .count()
+}
+
+fn violation(file: &str, rule: &str, message: String) -> Finding {
+    Finding {
+        severity: Severity::Error,
+        file: file.to_string(),
+        line: 0,
+        rule: rule.into(),
+        message,
+        fix: None,
+    }
+}
+
+fn is_artifact(path: &str, pattern: &str) -> bool {
+    match pattern.strip_prefix('*') {
+        Some(suffix) => path.rsplit('/').next().is_some_and(|n| n.ends_with(suffix)),
+        None => under(path, pattern),
+    }
+}
+
+/// What makes `text` suspicious, if anything.
+fn suspicious(text: &str) -> Option<String> {
+    text.chars().find_map(|c| {
+        let what = match c {
+            '\t' | '\u{c}' => return None,
+            '\0' => "a NUL byte",
+            '\u{fffd}' => "invalid UTF-8",
+            '\u{feff}' => "a byte-order mark",
+            '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}' => {
+                "a bidirectional-override character"
+            }
+            c if c.is_control() => "a control character",
+            _ => return None,
+        };
+        Some(format!("{what} (U+{:04X})", c as u32))
+    })
+}
+
+pub fn check(files: &[FilePatch], policy: &Policy) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    let lines: usize = files.iter().map(changed_lines).sum();
+    if policy.max_lines > 0 && lines > policy.max_lines {
+        findings.push(violation(
+            "",
+            "policy-max-lines",
+            format!(
+                "diff changes {lines} lines, over the limit of {}; split it into smaller patches",
+                policy.max_lines
+            ),
+        ));
+    }
+    if policy.max_files > 0 && files.len() > policy.max_files {
+        findings.push(violation(
+            "",
+            "policy-max-files",
+            format!(
+                "diff touches {} files, over the limit of {}; split it into smaller patches",
+                files.len(),
+                policy.max_files
+            ),
+        ));
+    }
+
+    for path in files.iter().flat_map(paths) {
+        if let Some(prefix) = policy.forbidden.iter().find(|p| under(path, p)) {
+            findings.push(violation(
+                path,
+                "policy-forbidden-path",
+                format!("{prefix} may not be changed without explicit permission"),
+            ));
+        }
+    }
+
+    for file in files {
+        // Deleting a file is the cure for all of these.
+        let Some(path) = file.new_path.as_deref() else {
+            continue;
+        };
+        if file.binary {
+            findings.push(violation(
+                path,
+                "policy-binary-file",
+                "binary files may not be added or changed; keep sources only".into(),
+            ));
+        }
+        if let Some(pattern) = policy.artifacts.iter().find(|p| is_artifact(path, p)) {
+            findings.push(violation(
+                path,
+                "policy-build-artifact",
+                format!("{pattern} is build output; build it rather than committing it"),
+            ));
+        }
+        // Added lines with their new line numbers.
+        let added = file.hunks.iter().flat_map(|h| {
+            h.lines
+                .iter()
+                .filter(|l| !matches!(l, HunkLine::Removed(_)))
+                .zip(h.new_start..)
+                .filter_map(|(l, n)| match l {
+                    HunkLine::Added(text) => Some((n, text)),
+                    _ => None,
+                })
+        });
+        let bytes: usize = added.clone().map(|(_, t)| t.len() + 1).sum();
+        if policy.max_file_bytes > 0 && bytes > policy.max_file_bytes {
+            findings.push(violation(
+                path,
+                "policy-file-too-large",
+                format!(
+                    "adds {bytes} bytes to one file, over the limit of {}",
+                    policy.max_file_bytes
+                ),
+            ));
+        }
+        if let Some((line, what)) = added.clone().find_map(|(n, t)| Some((n, suspicious(t)?))) {
+            findings.push(Finding {
+                line,
+                ..violation(
+                    path,
+                    "policy-suspicious-encoding",
+                    format!("added text has {what}"),
+                )
+            });
+        }
+    }
+
+    for req in &policy.require {
+        let Some(path) = files
+            .iter()
+            .flat_map(paths)
+            .find(|p| under(p, &req.changed))
+        else {
+            continue;
+        };
+        if !files
+            .iter()
+            .flat_map(paths)
+            .any(|p| req.with.iter().any(|w| under(p, w)))
+        {
+            findings.push(violation(
+                path,
+                "policy-required-path",
+                format!(
+                    "changes under {} must come with a change under {}",
+                    req.changed,
+                    req.with.join(" or ")
+                ),
+            ));
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::parse;
+
+    fn new_file(path: &str, lines: usize) -> String {
+        let mut diff = format!("--- /dev/null\n+++ b/{path}\n@@ -0,0 +1,{lines} @@\n");
+        for i in 0..lines {
+            diff.push_str(&format!("+int v{i};\n"));
+        }
+        diff
+    }
+
+    fn rules_of(files: &[FilePatch], policy: &Policy) -> Vec<(String, String)> {
+        check(files, policy)
+            .into_iter()
+            .map(|f| (f.rule, f.file))
+            .collect()
+    }
+
+    #[test]
+    fn limits_count_changed_lines_and_files() {
+        let policy = Policy {
+            max_lines: 4,
+            max_files: 1,
+            ..Default::default()
+        };
+        let small = parse(&new_file("kernel/a.c", 4)).unwrap();
+        assert_eq!(rules_of(&small, &policy), []);
+        let big = parse(&(new_file("kernel/a.c", 3) + &new_file("kernel/b.c", 2))).unwrap();
+        let rules: Vec<String> = rules_of(&big, &policy).into_iter().map(|r| r.0).collect();
+        assert_eq!(rules, ["policy-max-lines", "policy-max-files"]);
+    }
+
+    #[test]
+    fn forbidden_paths_and_required_companions() {
+        let policy = Policy::default();
+        let diff = new_file("kernels/x86_64/kernel/arch/x86_64/linker.ld", 1)
+            + &new_file("kernel/drivers/e1000.c", 1);
+        assert_eq!(
+            rules_of(&parse(&diff).unwrap(), &policy),
+            [
+                (
+                    "policy-forbidden-path".to_string(),
+                    "kernels/x86_64/kernel/arch/x86_64/linker.ld".to_string()
+                ),
+                (
+                    "policy-required-path".to_string(),
+                    "kernel/drivers/e1000.c".to_string()
+                ),
+            ]
+        );
+
+        let with_test = new_file("kernel/drivers/e1000.c", 1) + &new_file("tests/e1000.sh", 1);
+        assert_eq!(rules_of(&parse(&with_test).unwrap(), &policy), []);
+
+        // Moving a file out of a forbidden path still touches it.
+        let rename = "diff --git a/boot/stage2.asm b/src/stage2.asm\n\
+                      similarity index 100%\n\
+                      rename from boot/stage2.asm\n\
+                      rename to src/stage2.asm\n";
+        let files = parse(rename).unwrap();
+        assert_eq!(
+            rules_of(&files, &policy),
+            [(
+                "policy-forbidden-path".to_string(),
+                "boot/stage2.asm".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn binaries_artifacts_and_odd_text_are_rejected() {
+        let policy = Policy {
+            max_file_bytes: 40,
+            ..Default::default()
+        };
+        let diff = new_file("kernel/obj/irq.o", 1)
+            + &new_file("kernels/x86_64/build/kernel.map", 1)
+            + &new_file("kernel/big.c", 6)
+            + "--- a/kernel/irq.c\n+++ b/kernel/irq.c\n@@ -4,1 +4,2 @@\n int a;\n+/* admin \u{202e} */\n"
+            + "diff --git a/disk.raw b/disk.raw\nnew file mode 100644\nBinary files /dev/null and b/disk.raw differ\n";
+        let files = parse(&diff).unwrap();
+        let found: Vec<(String, String, usize)> = check(&files, &policy)
+            .into_iter()
+            .map(|f| (f.rule, f.file, f.line))
+            .collect();
+        let expect = [
+            ("policy-build-artifact", "kernel/obj/irq.o", 0),
+            (
+                "policy-build-artifact",
+                "kernels/x86_64/build/kernel.map",
+                0,
+            ),
+            ("policy-file-too-large", "kernel/big.c", 0),
+            ("policy-suspicious-encoding", "kernel/irq.c", 5),
+            ("policy-binary-file", "disk.raw", 0),
+        ];
+        assert_eq!(
+            found,
+            expect.map(|(r, f, l)| (r.to_string(), f.to_string(), l)),
+            "{found:?}"
+        );
+
+        assert_eq!(suspicious("\tint x;\u{c}"), None);
+        assert_eq!(suspicious("a\0b").unwrap(), "a NUL byte (U+0000)");
+        assert_eq!(
+            suspicious("\u{feff}int x;").unwrap(),
+            "a byte-order mark (U+FEFF)"
+        );
+
+        // Deleting an artifact is fine.
+        let gone = "diff --git a/irq.o b/irq.o\ndeleted file mode 100644\nBinary files a/irq.o and /dev/null differ\n";
+        assert_eq!(rules_of(&parse(gone).unwrap(), &policy), []);
+    }
+}
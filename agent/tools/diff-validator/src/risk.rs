@@ -0,0 +1,385 @@
+//! A 0–100 risk score for a diff (`--risk`), so the orchestrator can send
+//! risky patches to extra testing.
+//!
+//! The score adds up five factors, each worth some points (by default 15,
+//! 15, 30, 20 and 20; they are scaled to total 100):
+//!
+//! - `files`: files touched, full marks at `max-files`;
+//! - `lines`: lines added and removed, full marks at `max-lines`;
+//! - `criticality`: the most critical subsystem touched, by the longest
+//!   `criticality` path prefix a file is under (a percentage), else
+//!   `default-criticality`;
+//! - `findings`: the diff's findings, an error counting 1, a warning 0.4 and
+//!   a note 0.1, full marks at 3;
+//! - `history`: the worst failure rate of a touched file over its recent
+//!   outcomes in the `--history` file, once it has `min-attempts` of them.
+//!
+//! Each factor comes with its points and what earned them. Outcomes are
+//! recorded per file with `--record pass|fail`, keeping the last
+//! [`HISTORY_LEN`]:
+//!
+//! ```toml
+//! [risk]
+//! max-lines = 300
+//! default-criticality = 10
+//!
+//! [risk.criticality]
+//! "kernel/arch/" = 100
+//! "kernel/lib/" = 30
+//!
+//! [risk.points]
+//! history = 40
+//! ```
+
+use crate::patch::FilePatch;
+use crate::policy::{changed_lines, paths};
+use crate::rules::under;
+use crate::{Finding, Severity};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Outcomes kept per file.
+pub const HISTORY_LEN: usize = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RiskModel {
+    pub max_files: usize,
+    pub max_lines: usize,
+    /// Path prefix, at any directory level → criticality, 0–100.
+    pub criticality: BTreeMap<String, u32>,
+    pub default_criticality: u32,
+    /// Outcomes a file needs before its failure rate counts.
+    pub min_attempts: usize,
+    pub points: Points,
+}
+
+/// What each factor is worth, before scaling to 100.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Points {
+    pub files: u32,
+    pub lines: u32,
+    pub criticality: u32,
+    pub findings: u32,
+    pub history: u32,
+}
+
+impl Default for RiskModel {
+    fn default() -> Self {
+        let criticality = [
+            ("kernel/arch/", 100),
+            ("kernel/boot/", 100),
+            ("kernel/sys/", 80),
+            ("kernel/include/", 70),
+            ("kernel/dev/", 60),
+            ("kernel/drivers/", 60),
+            ("kernel/net/", 50),
+            ("kernel/lib/", 40),
+            ("kernel/server/", 30),
+            ("kernel/slm/", 30),
+            ("tests/", 10),
+        ];
+        Self {
+            max_files: 10,
+            max_lines: 500,
+            criticality: criticality
+                .into_iter()
+                .map(|(p, c)| (p.to_string(), c))
+                .collect(),
+            default_criticality: 20,
+            min_attempts: 3,
+            points: Points::default(),
+        }
+    }
+}
+
+impl Default for Points {
+    fn default() -> Self {
+        Self {
+            files: 15,
+            lines: 15,
+            criticality: 30,
+            findings: 20,
+            history: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Risk {
+    pub score: u32,
+    pub factors: Vec<Factor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Factor {
+    pub name: String,
+    pub points: u32,
+    pub max: u32,
+    /// What earned the points.
+    pub detail: String,
+}
+
+/// Recent pass/fail outcomes of diffs touching each file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct History {
+    /// Oldest outcome first; `true` for a pass.
+    pub files: BTreeMap<String, Vec<bool>>,
+}
+
+impl History {
+    /// The history at `path`; empty if the file does not exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("writing {}", path.display()))
+    }
+
+    /// Record `passed` for every file `files` touch.
+    pub fn record(&mut self, files: &[FilePatch], passed: bool) {
+        for path in files.iter().flat_map(paths) {
+            let runs = self.files.entry(path.to_string()).or_default();
+            runs.push(passed);
+            let excess = runs.len().saturating_sub(HISTORY_LEN);
+            runs.drain(..excess);
+        }
+    }
+}
+
+fn plural(n: usize, what: &str) -> String {
+    format!("{n} {what}{}", if n == 1 { "" } else { "s" })
+}
+
+/// Score the diff `files`, which got `findings`.
+pub fn score(
+    files: &[FilePatch],
+    findings: &[Finding],
+    model: &RiskModel,
+    history: &History,
+) -> Risk {
+    let touched: Vec<&str> = files.iter().flat_map(paths).collect();
+    let ratio = |n: usize, full: usize| {
+        if full == 0 {
+            0.0
+        } else {
+            (n as f64 / full as f64).min(1.0)
+        }
+    };
+
+    let lines: usize = files.iter().map(changed_lines).sum();
+
+    let criticality = |path: &str| {
+        model
+            .criticality
+            .iter()
+            .filter(|(prefix, _)| under(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(model.default_criticality, |(_, &c)| c)
+            .min(100)
+    };
+    let critical = touched.iter().map(|p| (criticality(p), *p)).max();
+
+    let count = |s: Severity| findings.iter().filter(|f| f.severity == s).count();
+    let (errors, warnings, notes) = (
+        count(Severity::Error),
+        count(Severity::Warning),
+        count(Severity::Info),
+    );
+    let weight = errors as f64 + 0.4 * warnings as f64 + 0.1 * notes as f64;
+
+    let failing = touched
+        .iter()
+        .filter_map(|p| Some((*p, history.files.get(*p)?)))
+        .filter(|(_, runs)| runs.len() >= model.min_attempts.max(1))
+        .map(|(p, runs)| {
+            (
+                p,
+                runs.iter().filter(|passed| !**passed).count(),
+                runs.len(),
+            )
+        })
+        .max_by(|a, b| (a.1 * b.2).cmp(&(b.1 * a.2)));
+
+    let p = &model.points;
+    let raw = [
+        (
+            "files",
+            p.files,
+            ratio(touched.len(), model.max_files),
+            plural(touched.len(), "file"),
+        ),
+        (
+            "lines",
+            p.lines,
+            ratio(lines, model.max_lines),
+            format!("{} changed", plural(lines, "line")),
+        ),
+        (
+            "criticality",
+            p.criticality,
+            critical.map_or(0.0, |(c, _)| c as f64 / 100.0),
+            critical.map_or("no files".into(), |(c, path)| {
+                format!("{path} is {c}% critical")
+            }),
+        ),
+        (
+            "findings",
+            p.findings,
+            (weight / 3.0).min(1.0),
+            format!(
+                "{}, {}, {}",
+                plural(errors, "error"),
+                plural(warnings, "warning"),
+                plural(notes, "note")
+            ),
+        ),
+        (
+            "history",
+            p.history,
+            failing.map_or(0.0, |(_, failed, runs)| failed as f64 / runs as f64),
+            failing.map_or(
+                "no history for these files".into(),
+                |(path, failed, runs)| {
+                    format!("{path} failed {failed} of its last {runs} outcomes")
+                },
+            ),
+        ),
+    ];
+
+    let total: u32 = raw.iter().map(|(_, points, ..)| points).sum();
+    let scale = |points: f64| {
+        if total == 0 {
+            0.0
+        } else {
+            points * 100.0 / total as f64
+        }
+    };
+    let exact: f64 = raw
+        .iter()
+        .map(|(_, points, r, _)| scale(*points as f64 * r))
+        .sum();
+    Risk {
+        score: (exact.round() as u32).min(100),
+        factors: raw
+            .into_iter()
+            .map(|(name, points, r, detail)| Factor {
+                name: name.to_string(),
+                points: scale(points as f64 * r).round() as u32,
+                max: scale(points as f64).round() as u32,
+                detail,
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch;
+
+    const PMM: &str = "\
+--- a/kernel/arch/x86_64/pmm.c
++++ b/kernel/arch/x86_64/pmm.c
+@@ -1,2 +1,3 @@
+ int pages;
++int frames;
+ int zones;
+";
+
+    fn finding(severity: Severity) -> Finding {
+        Finding {
+            severity,
+            file: "kernel/arch/x86_64/pmm.c".into(),
+            line: 2,
+            rule: "r".into(),
+            message: String::new(),
+            fix: None,
+        }
+    }
+
+    #[test]
+    fn factors_add_up_to_the_score() {
+        let files = patch::parse(PMM).unwrap();
+        let mut history = History::default();
+        history.record(&files, true);
+        history.record(&files, false);
+        let model = RiskModel::default();
+
+        // Two outcomes are not yet a rate.
+        let risk = score(&files, &[], &model, &history);
+        let points: Vec<(&str, u32)> = risk
+            .factors
+            .iter()
+            .map(|f| (f.name.as_str(), f.points))
+            .collect();
+        assert_eq!(
+            points,
+            [
+                ("files", 2),
+                ("lines", 0),
+                ("criticality", 30),
+                ("findings", 0),
+                ("history", 0)
+            ]
+        );
+        assert_eq!(risk.score, 32);
+        assert_eq!(
+            risk.factors[2].detail,
+            "kernel/arch/x86_64/pmm.c is 100% critical"
+        );
+
+        history.record(&files, false);
+        let findings = [finding(Severity::Error), finding(Severity::Warning)];
+        let risk = score(&files, &findings, &model, &history);
+        assert_eq!(risk.factors[3].points, 9);
+        assert_eq!(risk.factors[3].detail, "1 error, 1 warning, 0 notes");
+        assert_eq!(risk.factors[4].points, 13);
+        assert_eq!(
+            risk.factors[4].detail,
+            "kernel/arch/x86_64/pmm.c failed 2 of its last 3 outcomes"
+        );
+        assert_eq!(risk.score, 54);
+    }
+
+    #[test]
+    fn points_scale_to_100_and_history_is_bounded() {
+        let files = patch::parse(PMM).unwrap();
+        let model = RiskModel {
+            default_criticality: 50,
+            criticality: BTreeMap::new(),
+            points: Points {
+                files: 0,
+                lines: 0,
+                criticality: 1,
+                findings: 0,
+                history: 1,
+            },
+            ..RiskModel::default()
+        };
+        let risk = score(&files, &[], &model, &History::default());
+        assert_eq!((risk.score, risk.factors[2].max), (25, 50));
+
+        let mut history = History::default();
+        for _ in 0..HISTORY_LEN + 5 {
+            history.record(&files, true);
+        }
+        assert_eq!(history.files["kernel/arch/x86_64/pmm.c"].len(), HISTORY_LEN);
+    }
+}
@@ -0,0 +1,814 @@
+//! Kernel C rules for the added lines of `.c`/`.h` files, and the rules
+//! file that tunes every rule.
+//!
+//! Lines are lexed just enough to tell code from comments and string
+//! literals (a block comment is followed across added lines), then checked
+//! for:
+//!
+//! - `banned-function`: calls to unbounded string functions;
+//! - `float-in-kernel`: `float`/`double` or floating literals outside the
+//!   `float-allowed` paths;
+//! - `mmio-without-volatile`: a non-`volatile` pointer cast of an address
+//!   literal, or a pointer cast or declaration named like MMIO;
+//! - `cli-without-sti`: interrupts disabled in a file's added lines and
+//!   never re-enabled there (`cli; hlt` halts and is fine);
+//! - `large-stack-allocation`: an array local over `max-stack-bytes` (a
+//!   local being a declaration in a function body, going by the braces of
+//!   the added lines, or when they do not say, an indented one);
+//! - `missing-include-guard`: a new header whose first directive is not an
+//!   `#ifndef`/`#if !defined` guard, and that has no `#pragma once`.
+//!
+//! These are line heuristics, not a C parser. The rules file (looked up as
+//! `--rules <path>`, else `./diff-validator.toml`, else in the workspace)
+//! can turn any rule off, change its severity or allow it under some paths;
+//! a list or table it gives replaces the default one:
+//!
+//! ```toml
+//! disable = ["todo-marker"]
+//! max-stack-bytes = 2048
+//! float-allowed = ["kernel/slm/", "kernel/lib/math.c"]
+//!
+//! [severity]
+//! mmio-without-volatile = "error"
+//!
+//! [allow]
+//! msr-write = ["kernel/arch/", "kernel/sys/perf.c"]
+//!
+//! [banned-functions]
+//! sprintf = "use snprintf"
+//! strtok = "not reentrant"
+//! ```
+
+use crate::analyzer::ClangTidy;
+use crate::patch::FilePatch;
+use crate::policy::Policy;
+use crate::risk::RiskModel;
+use crate::security::Security;
+use crate::style::ClangFormat;
+use crate::{AddedLine, Finding, Severity};
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+pub const RULES_NAME: &str = "diff-validator.toml";
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Rules {
+    /// Rule IDs that are not reported.
+    pub disable: Vec<String>,
+    /// Rule ID → severity, replacing the rule's own.
+    pub severity: BTreeMap<String, Severity>,
+    /// Rule ID → path prefixes, at any directory level, where it is not
+    /// reported.
+    pub allow: BTreeMap<String, Vec<String>>,
+    /// `#include`s flagged by `hosted-libc-header`.
+    pub hosted_headers: Vec<String>,
+    /// Function → what to use instead (may be empty).
+    pub banned_functions: BTreeMap<String, String>,
+    /// Path prefixes, at any directory level, where floating point is
+    /// allowed.
+    pub float_allowed: Vec<String>,
+    /// Substrings (any case) of identifiers that hold MMIO addresses.
+    pub mmio_markers: Vec<String>,
+    pub interrupts: Interrupts,
+    pub max_stack_bytes: usize,
+    /// Lock call → the call that releases it, for `lock-not-released`.
+    pub locks: BTreeMap<String, String>,
+    pub security: Security,
+    pub policy: Policy,
+    /// How `--risk` scores a diff.
+    pub risk: RiskModel,
+    /// The `--analyzer clang-tidy` program and check profile.
+    pub clang_tidy: ClangTidy,
+    /// The `--style` formatter.
+    pub clang_format: ClangFormat,
+}
+
+/// What disables and re-enables interrupts, as calls or asm mnemonics.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Interrupts {
+    pub disable: Vec<String>,
+    pub enable: Vec<String>,
+}
+
+impl Default for Interrupts {
+    fn default() -> Self {
+        Self {
+            disable: vec!["cli".into()],
+            enable: vec!["sti".into()],
+        }
+    }
+}
+
+fn strings(items: &[&str]) -> Vec<String> {
+    items.iter().map(|s| s.to_string()).collect()
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        let banned = [
+            ("sprintf", "use snprintf"),
+            ("vsprintf", "use vsnprintf"),
+            ("strcpy", "use strncpy or strlcpy"),
+            ("strcat", "use strncat or strlcat"),
+            ("gets", "no bound on the input"),
+        ];
+        // Privileged state is the architecture code's business.
+        let arch_only = [
+            "control-register-write",
+            "msr-write",
+            "segment-register-asm",
+        ];
+        Self {
+            disable: Vec::new(),
+            severity: BTreeMap::new(),
+            allow: arch_only
+                .into_iter()
+                .map(|rule| (rule.to_string(), strings(&["kernel/arch/"])))
+                .collect(),
+            hosted_headers: strings(&[
+                "<stdio.h>",
+                "<stdlib.h>",
+                "<string.h>",
+                "<stdio>",
+                "<assert.h>",
+                "<math.h>",
+            ]),
+            banned_functions: banned
+                .into_iter()
+                .map(|(f, hint)| (f.to_string(), hint.to_string()))
+                .collect(),
+            // The neural SLM backend and the math it uses are in float.
+            float_allowed: strings(&[
+                "kernel/slm/",
+                "kernel/lib/kmath.c",
+                "kernel/include/kmath.h",
+                "kernel/include/neural.h",
+            ]),
+            mmio_markers: strings(&["mmio", "regs"]),
+            interrupts: Interrupts::default(),
+            max_stack_bytes: 1024,
+            locks: [
+                ("spin_lock", "spin_unlock"),
+                ("spin_lock_irqsave", "spin_unlock_irqrestore"),
+                ("raw_spin_lock", "raw_spin_unlock"),
+                ("mutex_lock", "mutex_unlock"),
+                ("read_lock", "read_unlock"),
+                ("write_lock", "write_unlock"),
+            ]
+            .into_iter()
+            .map(|(lock, release)| (lock.to_string(), release.to_string()))
+            .collect(),
+            security: Security::default(),
+            policy: Policy::default(),
+            risk: RiskModel::default(),
+            clang_tidy: ClangTidy::default(),
+            clang_format: ClangFormat::default(),
+        }
+    }
+}
+
+impl Rules {
+    pub fn load(path: &Path) -> Result<Self> {
+        auton_toml::from_file(path)
+    }
+
+    /// The rules in effect: `explicit` (which must exist), else the first
+    /// [`RULES_NAME`] in the current directory or `dirs`, else the defaults.
+    pub fn find(explicit: Option<&Path>, dirs: &[&Path]) -> Result<Self> {
+        if let Some(path) = explicit {
+            return Self::load(path);
+        }
+        let candidates = std::iter::once(PathBuf::from(RULES_NAME))
+            .chain(dirs.iter().map(|d| d.join(RULES_NAME)));
+        match candidates.into_iter().find(|p| p.is_file()) {
+            Some(path) => Self::load(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Drop disabled and allowed findings and apply severity overrides.
+    pub fn finish(&self, findings: Vec<Finding>) -> Vec<Finding> {
+        let allowed = |f: &Finding| {
+            self.allow
+                .get(&f.rule)
+                .is_some_and(|paths| paths.iter().any(|p| under(&f.file, p)))
+        };
+        findings
+            .into_iter()
+            .filter(|f| !self.disable.contains(&f.rule) && !allowed(f))
+            .map(|mut f| {
+                if let Some(&severity) = self.severity.get(&f.rule) {
+                    f.severity = severity;
+                }
+                f
+            })
+            .collect()
+    }
+}
+
+/// Whether `file` starts with `prefix` at any directory level, so that
+/// `kernel/slm/` matches `kernels/x86_64/kernel/slm/x.c`.
+pub(crate) fn under(file: &str, prefix: &str) -> bool {
+    file.starts_with(prefix) || file.contains(&format!("/{prefix}"))
+}
+
+pub(crate) fn is_c(file: &str) -> bool {
+    file.ends_with(".c") || file.ends_with(".h")
+}
+
+/// One added line with comments removed and string and character literals
+/// emptied, plus the literals' contents (asm templates, mostly).
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Lexed {
+    pub(crate) code: String,
+    pub(crate) literals: Vec<String>,
+}
+
+pub(crate) fn lex(line: &str, in_comment: &mut bool) -> Lexed {
+    let mut out = Lexed::default();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if *in_comment {
+            if c == '*' && chars.peek() == Some(&'/') {
+                chars.next();
+                *in_comment = false;
+                out.code.push(' ');
+            }
+            continue;
+        }
+        match c {
+            '/' if chars.peek() == Some(&'/') => break,
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                *in_comment = true;
+            }
+            '"' | '\'' => {
+                let mut literal = String::new();
+                while let Some(d) = chars.next() {
+                    match d {
+                        '\\' => {
+                            literal.push(d);
+                            literal.extend(chars.next());
+                        }
+                        _ if d == c => break,
+                        _ => literal.push(d),
+                    }
+                }
+                out.code.push(c);
+                out.code.push(c);
+                if c == '"' {
+                    out.literals.push(literal);
+                }
+            }
+            _ => out.code.push(c),
+        }
+    }
+    out
+}
+
+/// Identifiers, numbers (suffixes and exponents included) and single
+/// punctuation characters.
+pub(crate) fn tokens(code: &str) -> Vec<&str> {
+    let bytes = code.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c.is_ascii_alphabetic() || c == b'_' {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+        } else if c.is_ascii_digit()
+            || (c == b'.' && bytes.get(i + 1).is_some_and(u8::is_ascii_digit))
+        {
+            while i < bytes.len() {
+                let d = bytes[i];
+                let exponent = matches!(d, b'+' | b'-')
+                    && matches!(bytes[i - 1], b'e' | b'E' | b'p' | b'P')
+                    && !code[start..i].starts_with("0x");
+                if !(d.is_ascii_alphanumeric() || d == b'.' || d == b'_' || exponent) {
+                    break;
+                }
+                i += 1;
+            }
+        } else {
+            i += code[i..].chars().next().map_or(1, char::len_utf8);
+        }
+        out.push(&code[start..i]);
+    }
+    out
+}
+
+pub(crate) fn is_ident(token: &str) -> bool {
+    token
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+}
+
+fn is_hex(token: &str) -> bool {
+    token.starts_with("0x") || token.starts_with("0X")
+}
+
+fn is_float_literal(token: &str) -> bool {
+    let digit = token
+        .trim_start_matches('.')
+        .starts_with(|c: char| c.is_ascii_digit());
+    digit && !is_hex(token) && token.contains(['.', 'e', 'E'])
+}
+
+fn has_marker(ident: &str, markers: &[String]) -> bool {
+    let ident = ident.to_ascii_lowercase();
+    markers
+        .iter()
+        .any(|m| ident.contains(&m.to_ascii_lowercase()))
+}
+
+/// Keywords that start a statement rather than a declaration.
+const STATEMENT_KEYWORDS: &[&str] = &[
+    "return", "case", "goto", "sizeof", "if", "while", "for", "switch", "do", "else",
+];
+
+const STORAGE: &[&str] = &["static", "extern"];
+
+/// A declaration at the start of a line: `type... [*...] name` followed
+/// by `=`, `;`, `,`, `[` or the end of the line.
+struct Declaration<'t> {
+    /// Type words, qualifiers and storage class included.
+    types: &'t [&'t str],
+    pointer: bool,
+    name: &'t str,
+    rest: &'t [&'t str],
+}
+
+fn declaration<'t>(toks: &'t [&'t str]) -> Option<Declaration<'t>> {
+    let words = toks.iter().take_while(|t| is_ident(t)).count();
+    let stars = toks[words..].iter().take_while(|t| **t == "*").count();
+    let (types, name) = if stars > 0 {
+        (
+            &toks[..words],
+            *toks.get(words + stars).filter(|t| is_ident(t))?,
+        )
+    } else {
+        (&toks[..words.checked_sub(1)?], toks[words - 1])
+    };
+    let name_at = if stars > 0 { words + stars } else { words - 1 };
+    let rest = &toks[name_at + 1..];
+    let ends = rest
+        .first()
+        .is_none_or(|t| ["=", ";", ",", "["].contains(t));
+    (!types.is_empty() && !STATEMENT_KEYWORDS.contains(&types[0]) && ends).then_some(Declaration {
+        types,
+        pointer: stars > 0,
+        name,
+        rest,
+    })
+}
+
+/// The index of the `)` of a cast `( type... )` starting at `toks[at]`.
+fn cast_end(toks: &[&str], at: usize) -> Option<usize> {
+    let inner = toks[at + 1..]
+        .iter()
+        .take_while(|t| is_ident(t) || **t == "*")
+        .count();
+    let close = at + 1 + inner;
+    let starts = toks.get(at + 1).is_some_and(|t| is_ident(t));
+    (starts && toks.get(close) == Some(&")")).then_some(close)
+}
+
+/// Bytes of one element of a local's type, and whether that is exact.
+fn type_size(types: &[&str], pointer: bool) -> (usize, bool) {
+    if pointer {
+        return (8, true);
+    }
+    let size = |t: &str| match t {
+        "char" | "bool" | "_Bool" | "int8_t" | "uint8_t" | "u8" | "s8" | "i8" => Some(1),
+        "short" | "int16_t" | "uint16_t" | "u16" | "s16" | "i16" => Some(2),
+        "int" | "unsigned" | "signed" | "float" | "int32_t" | "uint32_t" | "u32" | "s32"
+        | "i32" => Some(4),
+        "long" | "double" | "size_t" | "ssize_t" | "uintptr_t" | "intptr_t" | "int64_t"
+        | "uint64_t" | "u64" | "s64" | "i64" => Some(8),
+        _ => None,
+    };
+    // `unsigned char`, `long long`: the last word that has a size decides,
+    // except that `long` wins over `int`.
+    let sizes: Vec<usize> = types.iter().filter_map(|t| size(t)).collect();
+    if types.contains(&"long") {
+        return (8, true);
+    }
+    match sizes.last() {
+        Some(&s) => (s, true),
+        None => (1, false),
+    }
+}
+
+/// The value of an array dimension: integer literals and earlier
+/// `#define`s, multiplied.
+fn dimension(toks: &[&str], defines: &HashMap<String, usize>) -> Option<usize> {
+    let mut product = 1usize;
+    for (i, t) in toks.iter().enumerate() {
+        if i % 2 == 1 {
+            (*t == "*").then_some(())?;
+            continue;
+        }
+        let value = if is_ident(t) {
+            *defines.get(*t)?
+        } else {
+            int_literal(t)?
+        };
+        product = product.checked_mul(value)?;
+    }
+    (!toks.is_empty()).then_some(product)
+}
+
+pub(crate) fn int_literal(token: &str) -> Option<usize> {
+    let digits = token.trim_end_matches(['u', 'U', 'l', 'L']);
+    match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => digits.parse().ok(),
+    }
+}
+
+struct Checker<'r> {
+    rules: &'r Rules,
+    findings: Vec<Finding>,
+}
+
+impl Checker<'_> {
+    fn push(&mut self, severity: Severity, added: &AddedLine, rule: &str, message: String) {
+        self.findings.push(Finding {
+            severity,
+            file: added.file.clone(),
+            line: added.line,
+            rule: rule.into(),
+            message,
+            fix: None,
+        });
+    }
+
+    fn banned(&mut self, added: &AddedLine, toks: &[&str]) {
+        for (i, t) in toks.iter().enumerate() {
+            let Some(hint) = self.rules.banned_functions.get(*t) else {
+                continue;
+            };
+            let prev = i.checked_sub(1).map(|p| toks[p]);
+            let member = matches!(prev, Some("." | ">"));
+            // `int sprintf(...)` declares it rather than calling it.
+            let declared = prev.is_some_and(|p| is_ident(p) && !STATEMENT_KEYWORDS.contains(&p));
+            if toks.get(i + 1) != Some(&"(") || member || declared {
+                continue;
+            }
+            let message = match hint.as_str() {
+                "" => format!("`{t}` is banned in kernel code"),
+                hint => format!("`{t}` is banned in kernel code: {hint}"),
+            };
+            self.push(Severity::Error, added, "banned-function", message);
+        }
+    }
+
+    fn float(&mut self, added: &AddedLine, toks: &[&str]) {
+        if self
+            .rules
+            .float_allowed
+            .iter()
+            .any(|p| under(&added.file, p))
+        {
+            return;
+        }
+        let Some(t) = toks
+            .iter()
+            .find(|t| matches!(**t, "float" | "double") || is_float_literal(t))
+        else {
+            return;
+        };
+        let message =
+            format!("floating point (`{t}`) in kernel code outside the float-allowed paths");
+        self.push(Severity::Error, added, "float-in-kernel", message);
+    }
+
+    fn mmio(&mut self, added: &AddedLine, toks: &[&str]) {
+        let markers = &self.rules.mmio_markers;
+        if let Some(decl) = declaration(toks) {
+            if decl.pointer && !decl.types.contains(&"volatile") && has_marker(decl.name, markers) {
+                let message = format!(
+                    "MMIO pointer `{}` is not to `volatile`; the compiler may cache or drop \
+                     its accesses",
+                    decl.name
+                );
+                self.push(Severity::Warning, added, "mmio-without-volatile", message);
+                return;
+            }
+        }
+        for at in 0..toks.len() {
+            let after_ident = at > 0 && is_ident(toks[at - 1]);
+            if toks[at] != "(" || after_ident {
+                continue;
+            }
+            let Some(close) = cast_end(toks, at) else {
+                continue;
+            };
+            let cast = &toks[at + 1..close];
+            if cast.last() != Some(&"*") || cast.contains(&"volatile") {
+                continue;
+            }
+            // Skip further casts of the value, as in `(T *)(uintptr_t)bar`.
+            let mut operand = close + 1;
+            while let Some(end) = cast_end(toks, operand).filter(|_| toks[operand] == "(") {
+                operand = end + 1;
+            }
+            let address = toks.get(operand).is_some_and(|t| is_hex(t));
+            // The value cast, or what it is assigned to.
+            let operand = toks[operand..]
+                .iter()
+                .take_while(|t| !matches!(**t, ";" | ","));
+            let named = toks[..at]
+                .iter()
+                .chain(operand)
+                .any(|t| is_ident(t) && has_marker(t, markers));
+            if address || named {
+                let message =
+                    "pointer cast to a device address is not to `volatile`; the compiler may \
+                     cache or drop its accesses"
+                        .to_string();
+                self.push(Severity::Warning, added, "mmio-without-volatile", message);
+                return;
+            }
+        }
+    }
+
+    fn stack(
+        &mut self,
+        added: &AddedLine,
+        toks: &[&str],
+        block: Option<Block>,
+        defines: &HashMap<String, usize>,
+    ) {
+        // Without the enclosing braces in the added lines, locals are
+        // taken to be the indented lines.
+        let local = match block {
+            Some(block) => block == Block::Code,
+            None => added.content.starts_with([' ', '\t']),
+        };
+        if !local {
+            return;
+        }
+        let Some(decl) = declaration(toks) else {
+            return;
+        };
+        if decl.types.iter().any(|t| STORAGE.contains(t)) || decl.rest.first() != Some(&"[") {
+            return;
+        }
+        let mut count = 1usize;
+        let mut rest = decl.rest;
+        while rest.first() == Some(&"[") {
+            let Some(close) = rest.iter().position(|t| *t == "]") else {
+                return;
+            };
+            let Some(n) = dimension(&rest[1..close], defines) else {
+                return;
+            };
+            count = count.saturating_mul(n);
+            rest = &rest[close + 1..];
+        }
+        let (size, exact) = type_size(decl.types, decl.pointer);
+        let bytes = count.saturating_mul(size);
+        if bytes > self.rules.max_stack_bytes {
+            let at_least = if exact { "" } else { "at least " };
+            let message = format!(
+                "`{}` puts {at_least}{bytes} bytes on the kernel stack (more than {}); \
+                 make it static or allocate it",
+                decl.name, self.rules.max_stack_bytes
+            );
+            self.push(Severity::Warning, added, "large-stack-allocation", message);
+        }
+    }
+}
+
+/// What a `{` opens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Block {
+    /// A function body or a statement block in one.
+    Code,
+    /// A struct, union or enum body, or an initializer.
+    Aggregate,
+}
+
+/// The braces open at the end of a file's added lines so far, and the last
+/// token seen, which tells what the next `{` opens.
+#[derive(Default)]
+pub(crate) struct Nesting {
+    pub(crate) open: Vec<Block>,
+    last: String,
+}
+
+impl Nesting {
+    pub(crate) fn update(&mut self, toks: &[&str]) {
+        if toks.first() == Some(&"#") {
+            return;
+        }
+        for t in toks {
+            match *t {
+                "{" => {
+                    let after_code = matches!(
+                        self.last.as_str(),
+                        ")" | "else" | "do" | "{" | "}" | ";" | ":"
+                    );
+                    let block = match self.open.last() {
+                        Some(Block::Aggregate) => Block::Aggregate,
+                        _ if after_code => Block::Code,
+                        _ => Block::Aggregate,
+                    };
+                    self.open.push(block);
+                }
+                "}" => {
+                    self.open.pop();
+                }
+                _ => {}
+            }
+            self.last = t.to_string();
+        }
+    }
+}
+
+/// Whether a literal (an asm template) disables interrupts only to halt.
+fn halts(literal: &str, at: usize) -> bool {
+    let words: Vec<&str> = literal
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect();
+    words.get(at + 1) == Some(&"hlt")
+}
+
+/// Run the C rules over the added lines of C files.
+pub fn check_c(added: &[AddedLine], rules: &Rules) -> Vec<Finding> {
+    let mut checker = Checker {
+        rules,
+        findings: Vec::new(),
+    };
+    let mut in_comment: HashMap<&str, bool> = HashMap::new();
+    let mut lexed = Vec::new();
+    let mut defines = HashMap::new();
+    for line in added.iter().filter(|a| is_c(&a.file)) {
+        let lexed_line = lex(&line.content, in_comment.entry(&line.file).or_default());
+        let toks = tokens(&lexed_line.code);
+        if let ["#", "define", name, value] = toks[..] {
+            if let Some(value) = int_literal(value) {
+                defines.insert(name.to_string(), value);
+            }
+        }
+        lexed.push((line, lexed_line));
+    }
+
+    // Interrupt disables not yet re-enabled, per file.
+    let mut pending: Vec<(&AddedLine, &str)> = Vec::new();
+    let irq = &rules.interrupts;
+    let mut file = None;
+    let mut nesting = Nesting::default();
+    for (line, lexed_line) in &lexed {
+        if file != Some(&line.file) {
+            unmatched(&mut checker, &mut pending);
+            file = Some(&line.file);
+            nesting = Nesting::default();
+        }
+        let toks = tokens(&lexed_line.code);
+        checker.banned(line, &toks);
+        checker.float(line, &toks);
+        checker.mmio(line, &toks);
+        let block = nesting.open.last().copied();
+        checker.stack(line, &toks, block, &defines);
+        nesting.update(&toks);
+
+        let code_words = toks.iter().map(|t| (*t, false));
+        let literal_words = lexed_line.literals.iter().flat_map(|l| {
+            l.split(|c: char| !c.is_ascii_alphanumeric())
+                .filter(|w| !w.is_empty())
+                .enumerate()
+                .map(move |(i, w)| (w, halts(l, i)))
+        });
+        for (word, halt) in code_words.chain(literal_words) {
+            if irq.disable.iter().any(|d| d == word) && !halt {
+                pending.push((line, word));
+            } else if irq.enable.iter().any(|e| e == word) {
+                pending.pop();
+            }
+        }
+    }
+    unmatched(&mut checker, &mut pending);
+    checker.findings
+}
+
+/// Flag new headers without an include guard.
+pub fn check_headers(files: &[FilePatch]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for file in files.iter().filter(|f| f.old_path.is_none()) {
+        let Some(path) = file.new_path.as_deref().filter(|p| p.ends_with(".h")) else {
+            continue;
+        };
+        let mut in_comment = false;
+        let mut guarded = None;
+        let mut pragma_once = false;
+        for line in file.hunks.iter().flat_map(|h| h.new_lines()) {
+            let code = lex(line, &mut in_comment).code;
+            let toks = tokens(&code);
+            if toks.is_empty() {
+                continue;
+            }
+            pragma_once |= toks[..] == ["#", "pragma", "once"];
+            guarded.get_or_insert(matches!(
+                toks[..],
+                ["#", "ifndef", ..] | ["#", "if", "!", "defined", ..]
+            ));
+        }
+        // An empty header has nothing to guard.
+        let guarded = guarded.unwrap_or(true);
+        if !guarded && !pragma_once {
+            findings.push(Finding {
+                severity: Severity::Warning,
+                file: path.to_string(),
+                line: 1,
+                rule: "missing-include-guard".into(),
+                message: "new header has no include guard".into(),
+                fix: None,
+            });
+        }
+    }
+    findings
+}
+
+fn unmatched(checker: &mut Checker, pending: &mut Vec<(&AddedLine, &str)>) {
+    let enable = checker.rules.interrupts.enable.join("`/`");
+    for (line, word) in pending.drain(..) {
+        let message = format!("`{word}` is not followed by `{enable}` in the added lines");
+        checker.push(Severity::Warning, line, "cli-without-sti", message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lexing_drops_comments_and_keeps_literals_apart() {
+        let mut in_comment = false;
+        let lexed = lex(r#"x = "a\"b"; /* c */ y('q'); // z"#, &mut in_comment);
+        assert_eq!(lexed.code, r#"x = "";   y(''); "#);
+        assert_eq!(lexed.literals, [r#"a\"b"#]);
+        assert!(!in_comment);
+        assert_eq!(lex("a /* open", &mut in_comment).code, "a ");
+        assert!(in_comment);
+        assert_eq!(lex("still */ b", &mut in_comment).code, "  b");
+    }
+
+    #[test]
+    fn tokens_keep_numbers_whole() {
+        assert_eq!(
+            tokens("f(1.5e-3f, 0x1e+2, x->y)"),
+            ["f", "(", "1.5e-3f", ",", "0x1e", "+", "2", ",", "x", "-", ">", "y", ")"]
+        );
+        assert!(is_float_literal(".5") && is_float_literal("1e9"));
+        assert!(!is_float_literal("0x1e") && !is_float_literal("10UL") && !is_float_literal("."));
+    }
+
+    #[test]
+    fn dimensions_multiply_literals_and_defines() {
+        let defines = HashMap::from([("PAGE".to_string(), 4096)]);
+        assert_eq!(dimension(&["PAGE", "*", "2"], &defines), Some(8192));
+        assert_eq!(dimension(&["0x10"], &defines), Some(16));
+        assert_eq!(dimension(&["n"], &defines), None);
+        assert_eq!(dimension(&["4", "+", "1"], &defines), None);
+        assert_eq!(type_size(&["unsigned", "char"], false), (1, true));
+        assert_eq!(type_size(&["unsigned", "long", "int"], false), (8, true));
+        assert_eq!(type_size(&["struct", "page"], false), (1, false));
+    }
+
+    #[test]
+    fn braces_tell_bodies_from_aggregates() {
+        let mut nesting = Nesting::default();
+        for line in ["struct s {", "};", "void f(void)", "{", "if (x) {"] {
+            nesting.update(&tokens(line));
+        }
+        assert_eq!(nesting.open, [Block::Code, Block::Code]);
+        nesting.update(&tokens("int t[] = { 1, 2 };"));
+        nesting.update(&tokens("static const struct s table = {"));
+        assert_eq!(nesting.open.last(), Some(&Block::Aggregate));
+    }
+
+    #[test]
+    fn halt_idiom_is_not_an_unmatched_cli() {
+        assert!(halts("cli; hlt", 0));
+        assert!(!halts("cli", 0));
+        assert!(!halts("pushf; cli", 1));
+    }
+}
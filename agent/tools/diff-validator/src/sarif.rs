@@ -0,0 +1,167 @@
+//! Findings as a SARIF 2.1.0 log (`--format sarif`), for code-review UIs and
+//! other tools that read static-analysis results.
+//!
+//! One run, by `diff-validator`, lists each rule that fired once and a
+//! result per finding. Paths are relative to the `SRCROOT` base (the
+//! repository or workspace the diff is against); a finding about the whole
+//! diff has no location, and one with no line no region. Severities map to
+//! levels `error`, `warning` and `note`. In a series, each result carries the
+//! commit or patch it was found in as its `diff` property.
+
+use crate::series::Verdict;
+use crate::{Finding, Severity};
+use serde_json::{json, Value};
+
+pub const SARIF_VERSION: &str = "2.1.0";
+
+const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+fn level(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+        Severity::Info => "note",
+    }
+}
+
+/// The log for one diff's findings.
+pub fn log(findings: &[Finding]) -> Value {
+    let tagged: Vec<(&Finding, Option<&str>)> = findings.iter().map(|f| (f, None)).collect();
+    run(&tagged)
+}
+
+/// The log for a series, each result tagged with its diff's id.
+pub fn series_log(verdicts: &[Verdict]) -> Value {
+    let tagged: Vec<(&Finding, Option<&str>)> = verdicts
+        .iter()
+        .flat_map(|v| v.findings.iter().map(|f| (f, Some(v.id.as_str()))))
+        .collect();
+    run(&tagged)
+}
+
+fn run(findings: &[(&Finding, Option<&str>)]) -> Value {
+    let mut rules: Vec<&str> = Vec::new();
+    let results: Vec<Value> = findings
+        .iter()
+        .map(|(f, diff)| {
+            let index = match rules.iter().position(|r| *r == f.rule) {
+                Some(i) => i,
+                None => {
+                    rules.push(&f.rule);
+                    rules.len() - 1
+                }
+            };
+            let mut result = json!({
+                "ruleId": f.rule,
+                "ruleIndex": index,
+                "level": level(f.severity),
+                "message": { "text": f.message },
+            });
+            if !f.file.is_empty() {
+                let mut location = json!({
+                    "artifactLocation": { "uri": f.file, "uriBaseId": "SRCROOT" },
+                });
+                if f.line > 0 {
+                    location["region"] = json!({ "startLine": f.line });
+                }
+                result["locations"] = json!([{ "physicalLocation": location }]);
+            }
+            if let Some(diff) = diff {
+                result["properties"] = json!({ "diff": diff });
+            }
+            result
+        })
+        .collect();
+    json!({
+        "$schema": SARIF_SCHEMA,
+        "version": SARIF_VERSION,
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "diff-validator",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "rules": rules.iter().map(|id| json!({ "id": id })).collect::<Vec<_>>(),
+                },
+            },
+            "results": results,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finding(severity: Severity, file: &str, line: usize, rule: &str) -> Finding {
+        Finding {
+            severity,
+            file: file.into(),
+            line,
+            rule: rule.into(),
+            message: format!("{rule} here"),
+            fix: None,
+        }
+    }
+
+    #[test]
+    fn results_reference_rules_and_locations() {
+        let log = log(&[
+            finding(Severity::Error, "kernel/a.c", 3, "banned-function"),
+            finding(Severity::Info, "kernel/a.c", 0, "trailing-whitespace"),
+            finding(Severity::Error, "", 0, "policy-max-lines"),
+            finding(Severity::Warning, "kernel/b.c", 9, "banned-function"),
+        ]);
+        assert_eq!(log["version"], "2.1.0");
+        let run = &log["runs"][0];
+        let rules: Vec<&str> = run["tool"]["driver"]["rules"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            rules,
+            ["banned-function", "trailing-whitespace", "policy-max-lines"]
+        );
+        let results = &run["results"];
+        assert_eq!(results[0]["level"], "error");
+        assert_eq!(
+            results[0]["locations"][0]["physicalLocation"],
+            json!({
+                "artifactLocation": { "uri": "kernel/a.c", "uriBaseId": "SRCROOT" },
+                "region": { "startLine": 3 },
+            })
+        );
+        assert_eq!(results[1]["level"], "note");
+        assert!(results[1]["locations"][0]["physicalLocation"]
+            .get("region")
+            .is_none());
+        assert!(results[2].get("locations").is_none());
+        assert_eq!(results[3]["ruleIndex"], 0);
+        assert!(results[3].get("properties").is_none());
+    }
+
+    #[test]
+    fn series_results_name_their_diff() {
+        let verdicts = [
+            Verdict {
+                id: "0001-a.patch".into(),
+                subject: None,
+                passed: true,
+                findings: vec![],
+                risk: None,
+            },
+            Verdict {
+                id: "0002-b.patch".into(),
+                subject: None,
+                passed: false,
+                findings: vec![finding(Severity::Error, "k.c", 1, "hunk-failed")],
+                risk: None,
+            },
+        ];
+        let log = series_log(&verdicts);
+        let results = log["runs"][0]["results"].as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["properties"]["diff"], "0002-b.patch");
+    }
+}
@@ -0,0 +1,423 @@
+//! Security rules for the added lines of C and assembly (`.S`/`.s`) files:
+//! code that touches privileged CPU state or trusts user memory.
+//!
+//! - `control-register-write`: a `mov` to `%cr0`/`%cr3`/`%cr4`, or a
+//!   `write_crN`-style wrapper call;
+//! - `msr-write`: `wrmsr`, or a `wrmsr`/`write_msr` wrapper call;
+//! - `segment-register-asm`: asm that loads or stores a segment register
+//!   or its base (`mov %ax, %ds`, `swapgs`, `wrgsbase`, far jumps);
+//! - `protection-disabled`: clearing SMEP, SMAP, NX or WP (by a named bit
+//!   such as `~X86_CR4_SMEP`, or `~(1 << 20)` next to `cr4`), or `stac`;
+//! - `user-copy-unchecked`: `memcpy` and friends reading from a user
+//!   pointer (named like one, or declared `__user`) with no access check
+//!   called earlier in the function.
+//!
+//! All are errors by default. The first three are allowed under
+//! `kernel/arch/`; the rules file's `[allow]` table sets the paths for each
+//! rule, and `[security]` names user pointers and access checks:
+//!
+//! ```toml
+//! [allow]
+//! user-copy-unchecked = ["kernel/sys/uaccess.c"]
+//!
+//! [security]
+//! user-markers = ["user", "uptr"]
+//! user-checks = ["access_ok", "user_range_ok"]
+//! ```
+//!
+//! In C, asm templates are the string literals of an `asm` statement; in
+//! assembly files every line is. A template is split into instructions at
+//! `;` and `\n`, and an instruction whose operands carry `%` is read as
+//! AT&T (destination last), otherwise as Intel (destination first).
+
+use crate::rules::{int_literal, is_c, is_ident, lex, tokens, Nesting, Rules};
+use crate::{AddedLine, Finding, Severity};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Security {
+    /// `_`-separated parts (any case) of identifiers that hold user
+    /// pointers.
+    pub user_markers: Vec<String>,
+    /// Calls that validate a user pointer before it is read.
+    pub user_checks: Vec<String>,
+}
+
+impl Default for Security {
+    fn default() -> Self {
+        Self {
+            user_markers: ["user", "uptr", "uaddr", "ubuf"].map(String::from).into(),
+            user_checks: ["access_ok", "user_range_ok", "validate_user_ptr"]
+                .map(String::from)
+                .into(),
+        }
+    }
+}
+
+const CONTROL_REGISTERS: &[&str] = &["cr0", "cr3", "cr4"];
+
+const SEGMENT_REGISTERS: &[&str] = &["cs", "ds", "es", "fs", "gs", "ss"];
+
+/// Instructions that load a segment register or its base whatever their
+/// operands.
+const SEGMENT_INSTRUCTIONS: &[&str] = &[
+    "swapgs", "wrfsbase", "wrgsbase", "lds", "les", "lfs", "lgs", "lss", "ljmp", "ljmpq", "lcall",
+];
+
+/// Calls that read memory from their second argument.
+const COPIES: &[&str] = &[
+    "memcpy", "memmove", "strcpy", "strncpy", "strlcpy", "memcmp",
+];
+
+/// Names of protection bits, as parts of a constant such as `X86_CR4_SMEP`.
+const PROTECTION_BITS: &[&str] = &["SMEP", "SMAP", "NX", "NXE", "WP"];
+
+/// (register, bit, name) of protection bits cleared by number.
+const NUMBERED_BITS: &[(&str, usize, &str)] = &[
+    ("cr4", 20, "SMEP"),
+    ("cr4", 21, "SMAP"),
+    ("efer", 11, "NXE"),
+    ("cr0", 16, "WP"),
+];
+
+const ASM: &[&str] = &["asm", "__asm__", "__asm"];
+
+fn is_asm_file(file: &str) -> bool {
+    file.ends_with(".S") || file.ends_with(".s")
+}
+
+fn parts(ident: &str) -> impl Iterator<Item = &str> {
+    ident.split('_').filter(|p| !p.is_empty())
+}
+
+/// What one asm instruction does that the rules care about.
+fn instruction(insn: &str) -> Option<(&'static str, String)> {
+    let mut insn = insn.trim().to_ascii_lowercase();
+    // `1: mov ...`: drop the label.
+    while let Some((label, rest)) = insn.split_once(':') {
+        if label.is_empty() || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            break;
+        }
+        insn = rest.trim().to_string();
+    }
+    let (mnemonic, operands) = insn.split_once(char::is_whitespace).unwrap_or((&insn, ""));
+    let operands: Vec<&str> = operands.split(',').map(str::trim).collect();
+    let register = |op: &str| op.trim_start_matches('%').to_string();
+    if mnemonic.starts_with("mov") {
+        let att = operands.iter().any(|op| op.starts_with('%'));
+        let dest = if att {
+            operands.last()
+        } else {
+            operands.first()
+        };
+        let dest = register(dest.copied().unwrap_or_default());
+        if CONTROL_REGISTERS.contains(&dest.as_str()) {
+            return Some((
+                "control-register-write",
+                format!("`{}` writes `{dest}`", insn.trim()),
+            ));
+        }
+    }
+    if mnemonic.starts_with("wrmsr") {
+        return Some(("msr-write", format!("`{mnemonic}` writes an MSR")));
+    }
+    if mnemonic == "stac" {
+        return Some((
+            "protection-disabled",
+            "`stac` suspends SMAP for kernel accesses to user pages".into(),
+        ));
+    }
+    let segment = operands
+        .iter()
+        .map(|op| register(op))
+        .find(|op| SEGMENT_REGISTERS.contains(&op.as_str()));
+    if let Some(segment) = segment {
+        return Some((
+            "segment-register-asm",
+            format!("`{}` touches segment register `{segment}`", insn.trim()),
+        ));
+    }
+    if SEGMENT_INSTRUCTIONS.contains(&mnemonic) {
+        return Some((
+            "segment-register-asm",
+            format!("`{mnemonic}` changes a segment register or its base"),
+        ));
+    }
+    None
+}
+
+/// Split an asm template into instructions, at `;`, newlines and `\n`
+/// escapes.
+fn instructions(template: &str) -> impl Iterator<Item = &str> {
+    template
+        .split(['\n', ';'])
+        .flat_map(|s| s.split("\\n"))
+        .map(|s| s.trim_start_matches("\\t").trim())
+        .filter(|s| !s.is_empty())
+}
+
+/// The bit number of `~(1 << N)`, `~(1UL << N)` or `~BIT(N)` at `toks[at]`.
+fn cleared_bit(toks: &[&str], at: usize) -> Option<usize> {
+    let rest = &toks[at + 1..];
+    let shift = match rest {
+        ["(", one, "<", "<", n, ")", ..] if int_literal(one) == Some(1) => n,
+        ["BIT" | "BIT_ULL", "(", n, ")", ..] => n,
+        _ => return None,
+    };
+    int_literal(shift)
+}
+
+/// A call's arguments within the line, as token slices.
+fn call_args<'t>(toks: &'t [&'t str], open: usize) -> Vec<&'t [&'t str]> {
+    let mut args = Vec::new();
+    let (mut depth, mut start) = (0usize, open + 1);
+    for (i, t) in toks.iter().enumerate().skip(open) {
+        match *t {
+            "(" => depth += 1,
+            ")" => {
+                depth -= 1;
+                if depth == 0 {
+                    args.push(&toks[start..i]);
+                    break;
+                }
+            }
+            "," if depth == 1 => {
+                args.push(&toks[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    args
+}
+
+/// Per-file state carried across added lines.
+#[derive(Default)]
+struct FileState {
+    in_comment: bool,
+    nesting: Nesting,
+    /// Paren depth inside an `asm` statement still open.
+    asm: Option<usize>,
+    /// Identifiers declared `__user`.
+    user_pointers: HashSet<String>,
+    /// Whether an access check was called in the current function.
+    checked: bool,
+}
+
+impl FileState {
+    /// Whether this line is (part of) an asm statement.
+    fn asm_line(&mut self, toks: &[&str]) -> bool {
+        let from = match (self.asm, toks.iter().position(|t| ASM.contains(t))) {
+            (Some(_), _) => 0,
+            (None, Some(at)) => at,
+            (None, None) => return false,
+        };
+        let mut depth = self.asm.unwrap_or(0);
+        self.asm = Some(depth);
+        for t in &toks[from..] {
+            match *t {
+                "(" => depth += 1,
+                ")" => {
+                    depth = depth.saturating_sub(1);
+                    if depth == 0 {
+                        self.asm = None;
+                        return true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.asm = Some(depth);
+        true
+    }
+}
+
+struct Checker<'r> {
+    security: &'r Security,
+    findings: Vec<Finding>,
+}
+
+impl Checker<'_> {
+    fn push(&mut self, added: &AddedLine, rule: &str, message: String) {
+        self.findings.push(Finding {
+            severity: Severity::Error,
+            file: added.file.clone(),
+            line: added.line,
+            rule: rule.into(),
+            message,
+            fix: None,
+        });
+    }
+
+    fn asm(&mut self, added: &AddedLine, template: &str) {
+        for insn in instructions(template) {
+            if let Some((rule, message)) = instruction(insn) {
+                self.push(added, rule, message);
+            }
+        }
+    }
+
+    fn is_user(&self, ident: &str, state: &FileState) -> bool {
+        state.user_pointers.contains(ident)
+            || parts(ident).any(|p| {
+                self.security
+                    .user_markers
+                    .iter()
+                    .any(|m| p.eq_ignore_ascii_case(m))
+            })
+    }
+
+    fn c(&mut self, added: &AddedLine, toks: &[&str], state: &mut FileState) {
+        for (i, t) in toks.iter().enumerate() {
+            let call = toks.get(i + 1) == Some(&"(");
+            let wrapper = t
+                .strip_prefix("write_cr")
+                .or_else(|| t.strip_prefix("load_cr"))
+                .filter(|n| CONTROL_REGISTERS.contains(&format!("cr{n}").as_str()));
+            if call && wrapper.is_some() {
+                self.push(
+                    added,
+                    "control-register-write",
+                    format!("`{t}` writes a control register"),
+                );
+            }
+            if call && matches!(*t, "wrmsr" | "wrmsrl" | "wrmsr_safe" | "write_msr") {
+                self.push(added, "msr-write", format!("`{t}` writes an MSR"));
+            }
+            if call && self.security.user_checks.iter().any(|c| c == t) {
+                state.checked = true;
+            }
+            if *t == "__user" {
+                let name = toks[i + 1..]
+                    .iter()
+                    .skip_while(|t| **t == "*" || **t == "const")
+                    .find(|t| is_ident(t));
+                state.user_pointers.extend(name.map(|n| n.to_string()));
+            }
+            if call && COPIES.contains(t) && !state.checked {
+                let args = call_args(toks, i + 1);
+                let user = args
+                    .get(1)
+                    .and_then(|src| src.iter().find(|a| is_ident(a) && self.is_user(a, state)));
+                if let Some(user) = user {
+                    let check = self.security.user_checks.first().map_or("", String::as_str);
+                    self.push(
+                        added,
+                        "user-copy-unchecked",
+                        format!(
+                            "`{t}` reads user pointer `{user}` with no `{check}`-style check \
+                             before it; use copy_from_user"
+                        ),
+                    );
+                }
+            }
+            if *t == "~" {
+                self.cleared(added, toks, i);
+            }
+        }
+    }
+
+    fn cleared(&mut self, added: &AddedLine, toks: &[&str], at: usize) {
+        let named = toks[at + 1..]
+            .iter()
+            .find(|t| **t != "(")
+            .filter(|t| is_ident(t) && parts(t).any(|p| PROTECTION_BITS.contains(&p)));
+        let bit = match named {
+            Some(name) => Some(name.to_string()),
+            None => cleared_bit(toks, at).and_then(|n| {
+                let registers: Vec<String> = toks
+                    .iter()
+                    .filter(|t| is_ident(t))
+                    .map(|t| t.to_ascii_lowercase())
+                    .collect();
+                NUMBERED_BITS
+                    .iter()
+                    .find(|(reg, bit, _)| {
+                        *bit == n && registers.iter().any(|r| parts(r).any(|p| p == *reg))
+                    })
+                    .map(|(reg, _, name)| format!("{} bit {n} ({name})", reg.to_uppercase()))
+            }),
+        };
+        if let Some(bit) = bit {
+            self.push(
+                added,
+                "protection-disabled",
+                format!("clears `{bit}`, turning off a hardware protection"),
+            );
+        }
+    }
+}
+
+/// Run the security rules over the added lines of C and assembly files.
+pub fn check(added: &[AddedLine], rules: &Rules) -> Vec<Finding> {
+    let mut checker = Checker {
+        security: &rules.security,
+        findings: Vec::new(),
+    };
+    let mut states: HashMap<&str, FileState> = HashMap::new();
+    for line in added {
+        let (c, asm_file) = (is_c(&line.file), is_asm_file(&line.file));
+        if !c && !asm_file {
+            continue;
+        }
+        let state = states.entry(&line.file).or_default();
+        let lexed = lex(&line.content, &mut state.in_comment);
+        let toks = tokens(&lexed.code);
+        if toks.first() == Some(&"#") {
+            continue;
+        }
+        if asm_file {
+            checker.asm(line, &lexed.code);
+            continue;
+        }
+        if state.asm_line(&toks) {
+            for template in &lexed.literals {
+                checker.asm(line, template);
+            }
+        }
+        checker.c(line, &toks, state);
+        state.nesting.update(&toks);
+        if state.nesting.open.is_empty() {
+            state.checked = false;
+        }
+    }
+    checker.findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_destinations_in_either_syntax() {
+        let rule = |insn| instruction(insn).map(|(rule, _)| rule);
+        assert_eq!(rule("movq %0, %%cr3"), Some("control-register-write"));
+        assert_eq!(rule("mov cr4, rax"), Some("control-register-write"));
+        assert_eq!(rule("mov %%cr3, %0"), None);
+        assert_eq!(rule("mov rax, cr0"), None);
+        assert_eq!(rule("wrmsr"), Some("msr-write"));
+        assert_eq!(rule("movw %%ax, %%ds"), Some("segment-register-asm"));
+        assert_eq!(rule("swapgs"), Some("segment-register-asm"));
+        assert_eq!(rule("movq %%fs:0x28, %0"), None);
+        assert_eq!(rule("stac"), Some("protection-disabled"));
+    }
+
+    #[test]
+    fn splits_templates_into_instructions() {
+        let insns: Vec<&str> = instructions("cli\\n\\tmov %0, %%cr3; sti").collect();
+        assert_eq!(insns, ["cli", "mov %0, %%cr3", "sti"]);
+    }
+
+    #[test]
+    fn finds_bits_cleared_by_number() {
+        let toks = tokens("cr4 &= ~(1UL << 20);");
+        let at = toks.iter().position(|t| *t == "~").unwrap();
+        assert_eq!(cleared_bit(&toks, at), Some(20));
+        let toks = tokens("x & ~BIT(11)");
+        assert_eq!(cleared_bit(&toks, 2), Some(11));
+        let toks = tokens("x & ~mask");
+        assert_eq!(cleared_bit(&toks, 2), None);
+    }
+}
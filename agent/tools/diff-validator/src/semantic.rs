@@ -0,0 +1,600 @@
+//! Checks on the parsed functions of C files, for faults that show in a
+//! function's structure rather than in a single line:
+//!
+//! - `unchecked-index`: a value parameter used as an array index with no
+//!   comparison (`<`, `<=`, `>`, `>=`) or range-check call (a name with
+//!   `check`, `valid`, `bound`, `range`, `clamp`, `min`, `max` or `assert`)
+//!   on it before the index, nor a mask or modulus in the index itself;
+//! - `switch-missing-enum-case`: a `switch` whose cases are all members of
+//!   one enum, with no `default`, leaving members out (sentinels such as
+//!   `*_COUNT`, `*_MAX` and `NR_*` need no case);
+//! - `lock-not-released`: a return, or the end of the function, reached
+//!   with a lock held that the function releases on some other path. Paths
+//!   follow `if`/`else`, loops, `switch`, `break` and forward `goto`s.
+//!
+//! All are warnings, reported only for functions with an added line. The
+//! functions come from the `cparse` module run on each hunk's new side, so
+//! only definitions wholly inside a hunk (a new function, or one the hunk's
+//! context covers) are seen, and only enums in the diff are known. Lock
+//! calls and their releases are the rules file's `[locks]` table:
+//!
+//! ```toml
+//! [locks]
+//! spin_lock = "spin_unlock"
+//! irq_lock = "irq_unlock"
+//! ```
+
+use crate::cparse::{self, closing, Enum, Function, Stmt, Unit};
+use crate::patch::{FilePatch, HunkLine};
+use crate::rules::{is_c, is_ident, Rules};
+use crate::{Finding, Severity};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Range;
+
+/// Parts of a call's name that mark it as a range check.
+const RANGE_CHECKS: &[&str] = &[
+    "check", "valid", "bound", "range", "clamp", "min", "max", "assert",
+];
+
+const RELATIONAL: &[&str] = &["<", "<=", ">", ">="];
+
+/// One hunk's new side, parsed, with the lines it adds.
+struct Fragment {
+    unit: Unit,
+    added: HashSet<usize>,
+}
+
+impl Fragment {
+    fn new(hunk: &crate::patch::Hunk) -> Self {
+        let mut line = hunk.new_start;
+        let mut lines = Vec::new();
+        let mut added = HashSet::new();
+        for l in &hunk.lines {
+            let text = match l {
+                HunkLine::Removed(_) => continue,
+                HunkLine::Context(t) => t,
+                HunkLine::Added(t) => {
+                    added.insert(line);
+                    t
+                }
+            };
+            lines.push((line, text.as_str()));
+            line += 1;
+        }
+        Self {
+            unit: cparse::parse(lines),
+            added,
+        }
+    }
+
+    fn touches(&self, range: Range<usize>) -> bool {
+        self.unit.tokens[range]
+            .iter()
+            .any(|t| self.added.contains(&t.line))
+    }
+}
+
+fn warning(file: &str, line: usize, rule: &str, message: String) -> Finding {
+    Finding {
+        severity: Severity::Warning,
+        file: file.to_string(),
+        line,
+        rule: rule.into(),
+        message,
+        fix: None,
+    }
+}
+
+pub fn check(files: &[FilePatch], rules: &Rules) -> Vec<Finding> {
+    let parsed: Vec<(&str, Vec<Fragment>)> = files
+        .iter()
+        .filter_map(|f| Some((f.new_path.as_deref()?, f)))
+        .filter(|(path, _)| is_c(path))
+        .map(|(path, f)| (path, f.hunks.iter().map(Fragment::new).collect()))
+        .collect();
+    let enums: Vec<&Enum> = parsed
+        .iter()
+        .flat_map(|(_, frags)| frags)
+        .flat_map(|frag| &frag.unit.enums)
+        .collect();
+
+    let mut findings = Vec::new();
+    for (path, frags) in &parsed {
+        for frag in frags {
+            for f in &frag.unit.functions {
+                if !frag.touches(f.span.clone()) {
+                    continue;
+                }
+                findings.extend(unchecked_index(path, frag, f));
+                findings.extend(missing_cases(path, frag, &f.body, &enums));
+                findings.extend(held_locks(path, frag, f, &rules.locks));
+            }
+        }
+    }
+    findings
+}
+
+fn unchecked_index(path: &str, frag: &Fragment, f: &Function) -> Vec<Finding> {
+    let toks = &frag.unit.tokens;
+    let values: HashSet<&str> = f
+        .params
+        .iter()
+        .filter(|p| !p.pointer)
+        .map(|p| p.name.as_str())
+        .collect();
+    let mut checked: HashSet<&str> = HashSet::new();
+    let mut findings = Vec::new();
+    for i in f.span.clone() {
+        let text = toks[i].text.as_str();
+        if values.contains(text) {
+            let next = toks.get(i + 1).map(|t| t.text.as_str());
+            let compared = RELATIONAL.contains(&toks[i - 1].text.as_str())
+                || next.is_some_and(|n| RELATIONAL.contains(&n));
+            if compared || next == Some("=") {
+                checked.insert(text);
+            }
+        } else if toks.get(i + 1).is_some_and(|t| t.text == "(") && is_ident(text) {
+            let name = text.to_ascii_lowercase();
+            if RANGE_CHECKS.iter().any(|c| name.contains(c)) {
+                let end = closing(toks, i + 1).unwrap_or(i + 1);
+                for t in &toks[i + 1..end] {
+                    if let Some(&p) = values.get(t.text.as_str()) {
+                        checked.insert(p);
+                    }
+                }
+            }
+        } else if text == "[" {
+            let array = toks[i - 1].text.as_str();
+            // `type name[...]` declares; `return a[i]` and `x = a[i]` index.
+            let declares = toks[i - 2].text != "return" && is_ident(&toks[i - 2].text);
+            if !is_ident(array) || cparse::is_keyword(array) || declares {
+                continue;
+            }
+            let Some(end) = closing(toks, i) else {
+                continue;
+            };
+            let index = &toks[i + 1..end];
+            if index.iter().any(|t| t.text == "&" || t.text == "%") {
+                continue;
+            }
+            for t in index {
+                let Some(&p) = values.get(t.text.as_str()) else {
+                    continue;
+                };
+                if checked.insert(p) && frag.added.contains(&t.line) {
+                    findings.push(warning(
+                        path,
+                        t.line,
+                        "unchecked-index",
+                        format!(
+                            "parameter `{p}` indexes `{array}` in `{}` without a bounds check; compare it against the array's length first",
+                            f.name
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+    findings
+}
+
+/// Enum members that count the others rather than name a value.
+fn sentinel(member: &str) -> bool {
+    ["_COUNT", "_MAX", "_NUM", "_LAST", "_NR"]
+        .iter()
+        .any(|s| member.ends_with(s))
+        || member.starts_with("NR_")
+        || member.starts_with("MAX_")
+}
+
+fn missing_cases(path: &str, frag: &Fragment, body: &[Stmt], enums: &[&Enum]) -> Vec<Finding> {
+    let mut switches = Vec::new();
+    each_switch(body, &mut switches);
+    let mut findings = Vec::new();
+    for (at, switch_body) in switches {
+        let mut labels = Vec::new();
+        case_labels(switch_body, &mut labels);
+        let unit = &frag.unit;
+        // A default covers the rest; a label other than a name is no enum's.
+        let mut names = BTreeSet::new();
+        for l in &labels {
+            match unit.text(l.clone())[..] {
+                [name] if is_ident(name) => names.insert(name),
+                _ => break,
+            };
+        }
+        let touched = frag.added.contains(&unit.line(at))
+            || labels
+                .iter()
+                .any(|l| frag.added.contains(&unit.line(l.start)));
+        if names.is_empty() || names.len() != labels.len() || !touched {
+            continue;
+        }
+        let Some(e) = enums
+            .iter()
+            .find(|e| names.iter().all(|n| e.members.iter().any(|m| m == n)))
+        else {
+            continue;
+        };
+        let missing: Vec<&str> = e
+            .members
+            .iter()
+            .map(String::as_str)
+            .filter(|m| !names.contains(m) && !sentinel(m))
+            .collect();
+        if missing.is_empty() {
+            continue;
+        }
+        let enum_name = e.name.as_deref().unwrap_or("anonymous enum");
+        findings.push(warning(
+            path,
+            unit.line(at),
+            "switch-missing-enum-case",
+            format!(
+                "switch on {enum_name} has no case for {} and no default",
+                missing.join(", ")
+            ),
+        ));
+    }
+    findings
+}
+
+/// Every `switch` in `items`, nested ones included, as (token, body).
+fn each_switch<'s>(items: &'s [Stmt], out: &mut Vec<(usize, &'s Stmt)>) {
+    for s in items {
+        match s {
+            Stmt::Block(inner) => each_switch(inner, out),
+            Stmt::If { then, els, .. } => {
+                each_switch(std::slice::from_ref(then), out);
+                if let Some(e) = els {
+                    each_switch(std::slice::from_ref(e), out);
+                }
+            }
+            Stmt::Loop { body, .. } => each_switch(std::slice::from_ref(body), out),
+            Stmt::Switch { at, body, .. } => {
+                out.push((*at, body));
+                each_switch(std::slice::from_ref(body), out);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The labels of a switch body's cases, not those of switches inside it.
+fn case_labels(s: &Stmt, out: &mut Vec<Range<usize>>) {
+    match s {
+        Stmt::Case { label } => out.push(label.clone()),
+        Stmt::Block(items) => items.iter().for_each(|i| case_labels(i, out)),
+        Stmt::If { then, els, .. } => {
+            case_labels(then, out);
+            if let Some(e) = els {
+                case_labels(e, out);
+            }
+        }
+        Stmt::Loop { body, .. } => case_labels(body, out),
+        _ => {}
+    }
+}
+
+/// A lock taken and not yet released.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Held {
+    release: String,
+    lock: String,
+    line: usize,
+}
+
+/// Locks held on some path; `None` where no path reaches.
+type State = Option<BTreeSet<Held>>;
+
+fn join(a: State, b: State) -> State {
+    match (a, b) {
+        (Some(mut a), Some(b)) => {
+            a.extend(b);
+            Some(a)
+        }
+        (a, b) => a.or(b),
+    }
+}
+
+struct LockWalk<'a> {
+    unit: &'a Unit,
+    locks: &'a BTreeMap<String, String>,
+    /// State at each label, from the `goto`s seen so far.
+    labels: HashMap<String, State>,
+    /// State at the exit of each enclosing loop or switch.
+    breaks: Vec<State>,
+    /// State on entry to each enclosing switch, for its case labels.
+    switches: Vec<State>,
+    /// (release, lock) pairs released somewhere in the function.
+    released: HashSet<(String, String)>,
+    /// Lines where a lock is still held as the function returns.
+    leaks: Vec<(usize, Held)>,
+}
+
+impl LockWalk<'_> {
+    /// Apply the lock and release calls in `range`.
+    fn calls(&mut self, range: Range<usize>, state: &mut State) {
+        let toks = &self.unit.tokens;
+        for i in range {
+            if toks.get(i + 1).is_none_or(|t| t.text != "(") {
+                continue;
+            }
+            let name = toks[i].text.as_str();
+            let lock = first_arg(self.unit, i + 1);
+            if let Some(release) = self.locks.get(name) {
+                if let Some(held) = state {
+                    held.insert(Held {
+                        release: release.clone(),
+                        lock,
+                        line: toks[i].line,
+                    });
+                }
+            } else if self.locks.values().any(|r| r == name) {
+                if let Some(held) = state {
+                    held.retain(|h| !(h.release == name && h.lock == lock));
+                }
+                self.released.insert((name.to_string(), lock));
+            }
+        }
+    }
+
+    fn leak(&mut self, line: usize, state: &State) {
+        for h in state.iter().flatten() {
+            self.leaks.push((line, h.clone()));
+        }
+    }
+
+    fn block(&mut self, items: &[Stmt], mut state: State) -> State {
+        for s in items {
+            state = self.stmt(s, state);
+        }
+        state
+    }
+
+    fn stmt(&mut self, s: &Stmt, mut state: State) -> State {
+        match s {
+            Stmt::Block(items) => self.block(items, state),
+            Stmt::Simple(range) => {
+                self.calls(range.clone(), &mut state);
+                state
+            }
+            Stmt::If { cond, then, els } => {
+                self.calls(cond.clone(), &mut state);
+                let taken = self.stmt(then, state.clone());
+                let other = match els {
+                    Some(e) => self.stmt(e, state),
+                    None => state,
+                };
+                join(taken, other)
+            }
+            Stmt::Loop { head, body } => {
+                self.calls(head.clone(), &mut state);
+                let forever = matches!(
+                    self.unit.text(head.clone())[..],
+                    [";", ";"] | ["1"] | ["true"]
+                );
+                self.breaks.push(None);
+                let after_body = self.stmt(body, state.clone());
+                let broken = self.breaks.pop().flatten();
+                if forever {
+                    broken
+                } else {
+                    join(join(state, after_body), broken)
+                }
+            }
+            Stmt::Switch { cond, body, .. } => {
+                self.calls(cond.clone(), &mut state);
+                let mut labels = Vec::new();
+                case_labels(body, &mut labels);
+                let has_default = labels.iter().any(|l| l.is_empty());
+                self.breaks.push(None);
+                self.switches.push(state.clone());
+                let fell_out = self.stmt(body, None);
+                self.switches.pop();
+                let broken = self.breaks.pop().flatten();
+                let skipped = if has_default { None } else { state };
+                join(join(fell_out, broken), skipped)
+            }
+            Stmt::Case { .. } => {
+                let entry = self.switches.last().cloned().flatten();
+                join(state, entry)
+            }
+            Stmt::Return { at, value } => {
+                self.calls(value.clone(), &mut state);
+                self.leak(self.unit.line(*at), &state);
+                None
+            }
+            Stmt::Goto { label } => {
+                let at = self.labels.entry(label.clone()).or_default();
+                *at = join(at.take(), state);
+                None
+            }
+            Stmt::Label(label) => {
+                let from_gotos = self.labels.remove(label).flatten();
+                join(state, from_gotos)
+            }
+            Stmt::Break => {
+                if let Some(exit) = self.breaks.last_mut() {
+                    *exit = join(exit.take(), state);
+                }
+                None
+            }
+            Stmt::Continue => None,
+        }
+    }
+}
+
+/// The first argument of the call whose `(` is at `open`, as written, minus
+/// a leading `&`; empty when the `(` is never closed.
+fn first_arg(unit: &Unit, open: usize) -> String {
+    let Some(end) = closing(&unit.tokens, open) else {
+        return String::new();
+    };
+    let mut depth = 0usize;
+    let mut arg = String::new();
+    for t in &unit.tokens[open + 1..end] {
+        match t.text.as_str() {
+            "(" | "[" => depth += 1,
+            ")" | "]" => depth = depth.saturating_sub(1),
+            "," if depth == 0 => break,
+            _ => {}
+        }
+        arg.push_str(&t.text);
+    }
+    arg.strip_prefix('&').unwrap_or(&arg).to_string()
+}
+
+fn held_locks(
+    path: &str,
+    frag: &Fragment,
+    f: &Function,
+    locks: &BTreeMap<String, String>,
+) -> Vec<Finding> {
+    let mut walk = LockWalk {
+        unit: &frag.unit,
+        locks,
+        labels: HashMap::new(),
+        breaks: Vec::new(),
+        switches: Vec::new(),
+        released: HashSet::new(),
+        leaks: Vec::new(),
+    };
+    let end = walk.block(&f.body, Some(BTreeSet::new()));
+    walk.leak(frag.unit.line(f.span.end - 1), &end);
+
+    let mut seen = HashSet::new();
+    let mut findings = Vec::new();
+    for (line, h) in walk.leaks {
+        // A function that never releases the lock hands it to its caller.
+        if !walk.released.contains(&(h.release.clone(), h.lock.clone()))
+            || !seen.insert((line, h.lock.clone()))
+        {
+            continue;
+        }
+        let at = if line == frag.unit.line(f.span.end - 1) && end.is_some() {
+            format!("at the end of `{}`", f.name)
+        } else {
+            "at this return".to_string()
+        };
+        findings.push(warning(
+            path,
+            line,
+            "lock-not-released",
+            format!(
+                "`{}` locked at line {} is still held {at}; call `{}` first",
+                h.lock, h.line, h.release
+            ),
+        ));
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::parse;
+
+    fn new_file(path: &str, src: &str) -> String {
+        let lines: Vec<&str> = src.lines().collect();
+        let mut diff = format!(
+            "--- /dev/null\n+++ b/{path}\n@@ -0,0 +1,{} @@\n",
+            lines.len()
+        );
+        for l in lines {
+            diff.push_str(&format!("+{l}\n"));
+        }
+        diff
+    }
+
+    fn found(src: &str) -> Vec<(String, usize)> {
+        let files = parse(&new_file("kernel/x.c", src)).unwrap();
+        check(&files, &Rules::default())
+            .into_iter()
+            .map(|f| (f.rule, f.line))
+            .collect()
+    }
+
+    #[test]
+    fn indexes_need_a_check_on_the_parameter() {
+        let src = "int get(int *table, int idx, unsigned n)\n\
+                   {\n\
+                   \tint local[4];\n\
+                   \tif (n >= 4)\n\
+                   \t\treturn local[n];\n\
+                   \treturn table[idx] + local[n & 3] + table[idx];\n\
+                   }";
+        assert_eq!(found(src), [("unchecked-index".to_string(), 6)]);
+
+        let checked = "int get(int *table, int idx)\n\
+                       {\n\
+                       \tif (!index_valid(idx))\n\
+                       \t\treturn -1;\n\
+                       \treturn table[idx];\n\
+                       }";
+        assert_eq!(found(checked), []);
+    }
+
+    #[test]
+    fn switches_over_an_enum_cover_every_member() {
+        let src = "enum state { IDLE, RUNNING, BLOCKED, STATE_COUNT };\n\
+                   const char *name(enum state s)\n\
+                   {\n\
+                   \tswitch (s) {\n\
+                   \tcase IDLE: return \"idle\";\n\
+                   \tcase RUNNING: return \"running\";\n\
+                   \t}\n\
+                   \treturn 0;\n\
+                   }";
+        let files = parse(&new_file("kernel/x.c", src)).unwrap();
+        let findings = check(&files, &Rules::default());
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].line, 4);
+        assert_eq!(
+            findings[0].message,
+            "switch on state has no case for BLOCKED and no default"
+        );
+
+        let with_default = src.replace("\t}\n", "\tdefault: break;\n\t}\n");
+        assert_eq!(found(&with_default), []);
+    }
+
+    #[test]
+    fn locks_are_released_on_every_path() {
+        let src = "int take(struct dev *d)\n\
+                   {\n\
+                   \tspin_lock(&d->lock);\n\
+                   \tif (d->busy)\n\
+                   \t\treturn -1;\n\
+                   \tif (d->dead)\n\
+                   \t\tgoto out;\n\
+                   \td->busy = 1;\n\
+                   out:\n\
+                   \tspin_unlock(&d->lock);\n\
+                   \treturn 0;\n\
+                   }";
+        assert_eq!(found(src), [("lock-not-released".to_string(), 5)]);
+
+        // Handing the lock to the caller is not a leak.
+        let wrapper = "void dev_lock(struct dev *d)\n{\n\tspin_lock(&d->lock);\n}";
+        assert_eq!(found(wrapper), []);
+
+        let looped = "void drain(struct q *q)\n\
+                      {\n\
+                      \tfor (;;) {\n\
+                      \t\tmutex_lock(&q->m);\n\
+                      \t\tif (!q->len) {\n\
+                      \t\t\tmutex_unlock(&q->m);\n\
+                      \t\t\tbreak;\n\
+                      \t\t}\n\
+                      \t\tq->len--;\n\
+                      \t\tmutex_unlock(&q->m);\n\
+                      \t}\n\
+                      }";
+        assert_eq!(found(looped), []);
+
+        // A half-written call: its `(` never closes.
+        assert_eq!(found("r(){=(}"), []);
+        assert_eq!(found("void f(void)\n{\n\tspin_lock(&l;\n}"), []);
+    }
+}
@@ -0,0 +1,306 @@
+//! Validating a series of diffs — the commits of a git range or a
+//! directory of `git format-patch` files — one at a time.
+//!
+//! Each diff of a series is held to the same rules as a lone diff and gets
+//! its own [`Verdict`]. When applying is checked, the diffs are applied in
+//! turn to one [`Tree`], so each is checked against the tree its
+//! predecessors left. A range is checked against its first commit's parent
+//! read straight from the repository, so no checkout is needed; a compile
+//! check (see [`crate::compile`]) builds on the same cumulative tree. git is run
+//! as a command; there is no libgit2 binding.
+
+use crate::abi;
+use crate::analyzer::Analyzer;
+use crate::apply::{self, Tree, DEFAULT_FUZZ};
+use crate::compile::CompileCheck;
+use crate::risk::{self, History, Risk};
+use crate::rules::Rules;
+use crate::style::StyleCheck;
+use crate::{has_errors, patch, validate_with, Finding, Severity};
+use kernel_builder::symdiff::SymDiff;
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The cover letter `git format-patch --cover-letter` writes, which has
+/// no diff.
+const COVER_LETTER: &str = "0000-cover-letter.patch";
+
+/// One diff of a series.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeriesItem {
+    /// Abbreviated commit hash or patch file name.
+    pub id: String,
+    pub subject: Option<String>,
+    pub diff: String,
+}
+
+/// What a series item was found to be.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Verdict {
+    pub id: String,
+    pub subject: Option<String>,
+    pub passed: bool,
+    pub findings: Vec<Finding>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub risk: Option<Risk>,
+}
+
+fn git(repo: &Path, args: &[&str]) -> io::Result<String> {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .output()?;
+    if !out.status.success() {
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        return Err(io::Error::other(format!(
+            "git {}: {}",
+            args.join(" "),
+            stderr.trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// The non-merge commits of `range` (anything `git log` takes, usually
+/// `base..head`), oldest first.
+pub fn git_range(repo: &Path, range: &str) -> io::Result<Vec<SeriesItem>> {
+    let log = git(
+        repo,
+        &[
+            "log",
+            "--reverse",
+            "--no-merges",
+            "--format=%H%x00%s",
+            range,
+        ],
+    )?;
+    let mut items = Vec::new();
+    for entry in log.lines() {
+        let (sha, subject) = entry.split_once('\0').unwrap_or((entry, ""));
+        let diff = git(
+            repo,
+            &[
+                "show",
+                "--no-color",
+                "--no-ext-diff",
+                "-M",
+                "--format=",
+                sha,
+            ],
+        )?;
+        items.push(SeriesItem {
+            id: sha[..sha.len().min(12)].to_string(),
+            subject: Some(subject.to_string()).filter(|s| !s.is_empty()),
+            diff,
+        });
+    }
+    Ok(items)
+}
+
+/// The tree a range's first commit was made on, read from `repo`.
+pub fn range_base(repo: &Path, first: &SeriesItem) -> Tree {
+    Tree::at_revision(repo, &format!("{}^", first.id))
+}
+
+/// The `*.patch` and `*.diff` files of `dir`, in name order (numbered, as
+/// `git format-patch` writes them).
+pub fn patch_dir(dir: &Path) -> io::Result<Vec<SeriesItem>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    paths.retain(|p| {
+        p.is_file()
+            && p.extension().is_some_and(|e| e == "patch" || e == "diff")
+            && p.file_name() != Some(COVER_LETTER.as_ref())
+    });
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let text = String::from_utf8_lossy(&std::fs::read(&path)?).into_owned();
+            Ok(SeriesItem {
+                id: path.file_name().unwrap().to_string_lossy().into_owned(),
+                subject: mail_subject(&text),
+                diff: mail_diff(&text).to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The `Subject:` of a mailed patch, unfolded and without its `[PATCH n/m]`
+/// tag.
+fn mail_subject(text: &str) -> Option<String> {
+    let mut lines = text.lines().take_while(|l| !l.is_empty());
+    let first = lines.find_map(|l| l.strip_prefix("Subject:"))?;
+    let mut subject = first.trim().to_string();
+    for more in lines.take_while(|l| l.starts_with([' ', '\t'])) {
+        subject.push(' ');
+        subject.push_str(more.trim());
+    }
+    if subject.starts_with('[') {
+        if let Some((_, rest)) = subject.split_once(']') {
+            subject = rest.trim_start().to_string();
+        }
+    }
+    Some(subject)
+}
+
+/// The diff of a mailed patch: from its first file header up to the
+/// `-- ` signature line, so the commit message is not taken for diff lines.
+fn mail_diff(text: &str) -> &str {
+    let start = text
+        .match_indices('\n')
+        .map(|(i, _)| i + 1)
+        .chain(std::iter::once(0))
+        .filter(|&i| text[i..].starts_with("diff --git ") || text[i..].starts_with("--- "))
+        .min()
+        .unwrap_or(0);
+    let diff = &text[start..];
+    match diff.find("\n-- \n") {
+        Some(end) => &diff[..end + 1],
+        None => diff,
+    }
+}
+
+/// What diffs are checked against besides the static rules.
+pub struct Checks<'r> {
+    pub rules: &'r Rules,
+    /// Check that each diff applies here, applying the ones that do.
+    pub tree: Option<Tree>,
+    pub fuzz: usize,
+    /// Also compile what each diff that applies leaves (needs `tree`).
+    pub compile: Option<CompileCheck>,
+    /// Also run a static analyzer on what each diff that applies leaves
+    /// (needs `tree`).
+    pub analyzer: Option<Analyzer>,
+    /// Also check the formatting of what each diff that applies adds
+    /// (needs `tree`).
+    pub style: Option<StyleCheck>,
+    /// Also score each diff's risk, with this history of outcomes.
+    pub risk: Option<History>,
+    /// Also flag ABI changes in this comparison of the kernel linked
+    /// before and after the diff.
+    pub abi: Option<SymDiff>,
+}
+
+impl<'r> Checks<'r> {
+    /// The static rules alone.
+    pub fn new(rules: &'r Rules) -> Self {
+        Self {
+            rules,
+            tree: None,
+            fuzz: DEFAULT_FUZZ,
+            compile: None,
+            analyzer: None,
+            style: None,
+            risk: None,
+            abi: None,
+        }
+    }
+
+    /// Findings for one diff.
+    pub fn check(&mut self, diff: &str) -> Vec<Finding> {
+        let mut findings = validate_with(diff, self.rules);
+        if let Some(tree) = &mut self.tree {
+            match patch::parse(diff) {
+                Ok(files) => {
+                    let applied = apply::findings(&tree.apply(&files, self.fuzz));
+                    let clean = !has_errors(&applied);
+                    findings.extend(applied);
+                    if let (Some(compile), true) = (&mut self.compile, clean) {
+                        findings.extend(compile.check(tree, &files));
+                    }
+                    if let (Some(analyzer), true) = (&mut self.analyzer, clean) {
+                        findings.extend(analyzer.check(tree, &files));
+                    }
+                    if let (Some(style), true) = (&mut self.style, clean) {
+                        findings.extend(style.check(tree, &files));
+                    }
+                }
+                Err(e) => findings.push(Finding {
+                    severity: Severity::Error,
+                    file: String::new(),
+                    line: e.line,
+                    rule: "malformed-diff".into(),
+                    message: e.message,
+                    fix: None,
+                }),
+            }
+        }
+        if let (Some(delta), Ok(files)) = (&self.abi, patch::parse(diff)) {
+            findings.extend(abi::check(&files, delta));
+        }
+        self.rules.finish(findings)
+    }
+
+    /// The risk of a diff that got `findings`, if risk is scored.
+    pub fn score(&self, diff: &str, findings: &[Finding]) -> Option<Risk> {
+        let history = self.risk.as_ref()?;
+        let files = patch::parse(diff).unwrap_or_default();
+        Some(risk::score(&files, findings, &self.rules.risk, history))
+    }
+
+    /// A verdict for every item of a series, each checked against what the
+    /// ones before it left.
+    pub fn series(&mut self, items: &[SeriesItem]) -> Vec<Verdict> {
+        items
+            .iter()
+            .map(|item| {
+                let findings = self.check(&item.diff);
+                Verdict {
+                    id: item.id.clone(),
+                    subject: item.subject.clone(),
+                    passed: !has_errors(&findings),
+                    risk: self.score(&item.diff, &findings),
+                    findings,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIL: &str = "\
+From 1234 Mon Sep 17 00:00:00 2001
+From: Dev <dev@example.org>
+Subject: [PATCH 2/3] mm: count frames
+ in pmm_init
+
+Also +1 frame for the guard page.
+---
+ kernel/mm/pmm.c | 1 +
+ 1 file changed, 1 insertion(+)
+
+diff --git a/kernel/mm/pmm.c b/kernel/mm/pmm.c
+--- a/kernel/mm/pmm.c
++++ b/kernel/mm/pmm.c
+@@ -1 +1,2 @@
+ int pages;
++int frames;
+--\x20
+2.43.0
+";
+
+    #[test]
+    fn reads_folded_subject_without_tag() {
+        assert_eq!(
+            mail_subject(MAIL).as_deref(),
+            Some("mm: count frames in pmm_init")
+        );
+        assert_eq!(mail_subject("diff --git a/x b/x\n"), None);
+    }
+
+    #[test]
+    fn mail_diff_drops_message_and_signature() {
+        let diff = mail_diff(MAIL);
+        assert!(diff.starts_with("diff --git "), "{diff}");
+        assert!(diff.ends_with("+int frames;\n"), "{diff}");
+        assert_eq!(patch::parse(diff).unwrap().len(), 1);
+    }
+}
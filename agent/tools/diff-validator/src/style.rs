@@ -0,0 +1,253 @@
+//! Formatting of the lines a diff adds, as the workspace's `.clang-format`
+//! wants it.
+//!
+//! With `--style`, each changed `.c`/`.h` file as the diff leaves it is piped
+//! through `clang-format --style=file` with `--lines` set to the added
+//! ranges, so code the diff did not touch is left alone. Where the output
+//! differs, a `style-format` error is reported at the first line of each
+//! difference, and the differences together make a corrective patch, to be
+//! applied on top of the diff (`--style-patch`). The rules file's
+//! `[clang-format]` table names the program:
+//!
+//! ```toml
+//! [clang-format]
+//! program = "clang-format-18"
+//! ```
+
+use crate::apply::Tree;
+use crate::fix::{self, Fix};
+use crate::patch::{self, FilePatch, HunkLine};
+use crate::rules::is_c;
+use crate::{Finding, Severity};
+use anyhow::{bail, Result};
+use auton_core::diff::unified_diff;
+use auton_core::process;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const STYLE_NAME: &str = ".clang-format";
+
+/// How long formatting one file may take.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ClangFormat {
+    pub program: String,
+}
+
+impl Default for ClangFormat {
+    fn default() -> Self {
+        Self {
+            program: "clang-format".into(),
+        }
+    }
+}
+
+/// Checks the formatting of what each diff adds.
+pub struct StyleCheck {
+    workspace: PathBuf,
+    config: ClangFormat,
+    /// Corrective patch for the last diff checked; empty if none is needed.
+    pub patch: String,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl StyleCheck {
+    /// `None` if `workspace` has no `.clang-format` to check against.
+    pub fn new(workspace: &Path, config: ClangFormat) -> Result<Option<Self>> {
+        if !workspace.join(STYLE_NAME).is_file() {
+            return Ok(None);
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(Some(Self {
+            workspace: workspace.to_path_buf(),
+            config,
+            patch: String::new(),
+            runtime,
+        }))
+    }
+
+    /// Format the added lines of a diff (already applied to `tree`).
+    pub fn check(&mut self, tree: &Tree, files: &[FilePatch]) -> Vec<Finding> {
+        self.patch.clear();
+        let mut findings = Vec::new();
+        for file in files {
+            let Some(path) = file.new_path.as_deref().filter(|p| is_c(p)) else {
+                continue;
+            };
+            let ranges = added_ranges(file);
+            let Some(lines) = tree.file(path).filter(|_| !ranges.is_empty()) else {
+                continue;
+            };
+            match self.format(path, lines, &ranges) {
+                Ok(formatted) => findings.extend(self.compare(path, lines, &formatted)),
+                Err(e) => findings.push(Finding {
+                    severity: Severity::Error,
+                    file: path.to_string(),
+                    line: 0,
+                    rule: "style-unavailable".into(),
+                    message: format!("{e:#}"),
+                    fix: None,
+                }),
+            }
+        }
+        findings
+    }
+
+    fn format(&self, path: &str, lines: &[String], ranges: &[(usize, usize)]) -> Result<String> {
+        let mut text = lines.join("\n");
+        text.push('\n');
+        let mut cmd = tokio::process::Command::new(&self.config.program);
+        cmd.arg("--style=file")
+            .arg(format!(
+                "--assume-filename={}",
+                self.workspace.join(path).display()
+            ))
+            .args(ranges.iter().map(|(a, b)| format!("--lines={a}:{b}")));
+        let out = self
+            .runtime
+            .block_on(process::run_with_input(cmd, text, Some(TIMEOUT)))?;
+        if !out.success() {
+            bail!(
+                "{} exited with {}: {}",
+                out.program,
+                out.status(),
+                out.stderr_tail(5)
+            );
+        }
+        Ok(out.stdout)
+    }
+
+    /// A finding for each difference between `lines` and `formatted`,
+    /// which is added to the corrective patch.
+    fn compare(&mut self, path: &str, lines: &[String], formatted: &str) -> Vec<Finding> {
+        let old: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let new: Vec<&str> = formatted.lines().collect();
+        if old == new {
+            return Vec::new();
+        }
+        let diff = unified_diff(&old, &new, &format!("a/{path}"), &format!("b/{path}"));
+        let hunks = patch::parse(&diff).map(|f| f.into_iter().flat_map(|f| f.hunks).collect());
+        let known: BTreeMap<usize, &str> = (1..).zip(old.iter().copied()).collect();
+        let finding = |(at, removed, want): (usize, usize, Vec<&str>)| {
+            let insert = want.iter().map(|l| l.to_string()).collect();
+            let fix = Fix::new(
+                path,
+                "reformat as .clang-format asks".into(),
+                vec![fix::edit(&known, at, removed, insert)],
+            );
+            mismatch(path, at, &want, fix)
+        };
+        let mut findings = Vec::new();
+        for hunk in hunks.unwrap_or_else(|_| Vec::new()) {
+            let mut line = hunk.old_start;
+            let mut run: Option<(usize, usize, Vec<&str>)> = None;
+            for l in &hunk.lines {
+                match l {
+                    HunkLine::Context(_) => {
+                        findings.extend(run.take().map(finding));
+                        line += 1;
+                    }
+                    HunkLine::Removed(_) => {
+                        run.get_or_insert((line, 0, Vec::new())).1 += 1;
+                        line += 1;
+                    }
+                    HunkLine::Added(text) => {
+                        run.get_or_insert((line, 0, Vec::new()))
+                            .2
+                            .push(text.as_str());
+                    }
+                }
+            }
+            findings.extend(run.map(finding));
+        }
+        self.patch.push_str(&diff);
+        findings
+    }
+}
+
+fn mismatch(path: &str, line: usize, want: &[&str], fix: Fix) -> Finding {
+    let message = match want {
+        [] => "not formatted as .clang-format asks: remove this line".to_string(),
+        [one] => format!("not formatted as .clang-format asks: `{}`", one.trim()),
+        [first, rest @ ..] => format!(
+            "not formatted as .clang-format asks: `{}` (and {} more line{})",
+            first.trim(),
+            rest.len(),
+            if rest.len() == 1 { "" } else { "s" }
+        ),
+    };
+    Finding {
+        severity: Severity::Error,
+        file: path.to_string(),
+        line,
+        rule: "style-format".into(),
+        message,
+        fix: Some(fix),
+    }
+}
+
+/// The new-file line ranges (inclusive) of a file patch's added lines.
+fn added_ranges(file: &FilePatch) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for hunk in &file.hunks {
+        let mut line = hunk.new_start;
+        for l in &hunk.lines {
+            match l {
+                HunkLine::Removed(_) => continue,
+                HunkLine::Added(_) => match ranges.last_mut() {
+                    Some((_, end)) if *end + 1 == line => *end = line,
+                    _ => ranges.push((line, line)),
+                },
+                HunkLine::Context(_) => {}
+            }
+            line += 1;
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn added_lines_merge_into_ranges() {
+        let diff = "--- a/k.c\n+++ b/k.c\n\
+                    @@ -1,4 +1,6 @@\n a\n+b\n+c\n-d\n e\n+f\n g\n\
+                    @@ -20,1 +22,2 @@\n h\n+i\n";
+        let files = patch::parse(diff).unwrap();
+        assert_eq!(added_ranges(&files[0]), [(2, 3), (5, 5), (23, 23)]);
+    }
+
+    #[test]
+    fn differences_become_findings_and_a_patch() {
+        let dir = std::env::temp_dir().join(format!("diff-validator-{}-style", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(STYLE_NAME), "BasedOnStyle: LLVM\n").unwrap();
+        let mut style = StyleCheck::new(&dir, ClangFormat::default())
+            .unwrap()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let lines: Vec<String> = ["int a;", "if(x)", "  y();", "int b;"]
+            .map(String::from)
+            .into();
+        let findings = style.compare("k.c", &lines, "int a;\nif (x)\n\ty();\nint b;\n");
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].line, 2);
+        assert_eq!(
+            findings[0].message,
+            "not formatted as .clang-format asks: `if (x)` (and 1 more line)"
+        );
+        assert!(style
+            .patch
+            .starts_with("--- a/k.c\n+++ b/k.c\n@@ -1,4 +1,4 @@\n"));
+        assert_eq!(patch::parse(&style.patch).unwrap()[0].hunks.len(), 1);
+    }
+}
@@ -0,0 +1,132 @@
+//! Integration tests for ABI checks against the kernel linked before and
+//! after a diff.
+
+use diff_validator::rules::Rules;
+use diff_validator::series::Checks;
+use diff_validator::Severity;
+use kernel_builder::elf::{
+    Elf, Section, Segment, Symbol, PT_LOAD, SHF_ALLOC, STB_GLOBAL, STT_FUNC,
+};
+use kernel_builder::symdiff;
+
+fn kernel(base: u64, exports: &[&str]) -> Elf {
+    Elf {
+        machine: 62,
+        entry: base,
+        sections: vec![
+            Section {
+                name: String::new(),
+                kind: 0,
+                flags: 0,
+                addr: 0,
+                offset: 0,
+                size: 0,
+            },
+            Section {
+                name: ".text".into(),
+                kind: 1,
+                flags: SHF_ALLOC,
+                addr: base,
+                offset: 0x1000,
+                size: 0x4000,
+            },
+        ],
+        segments: vec![Segment {
+            kind: PT_LOAD,
+            flags: 5,
+            offset: 0x1000,
+            vaddr: base,
+            paddr: base,
+            filesz: 0x4000,
+            memsz: 0x4000,
+        }],
+        symbols: exports
+            .iter()
+            .map(|name| Symbol {
+                name: name.to_string(),
+                value: base,
+                size: 0x40,
+                kind: STT_FUNC,
+                bind: STB_GLOBAL,
+                shndx: 1,
+            })
+            .collect(),
+    }
+}
+
+fn abi_findings(diff: &str, old: &Elf, new: &Elf) -> Vec<(String, Severity, String, usize)> {
+    let rules = Rules::default();
+    let mut checks = Checks::new(&rules);
+    checks.abi = Some(symdiff::diff("old.elf", old, "new.elf", new));
+    checks
+        .check(diff)
+        .into_iter()
+        .filter(|f| f.rule.starts_with("abi-"))
+        .map(|f| (f.rule, f.severity, f.file, f.line))
+        .collect()
+}
+
+const DROP_PMM_FREE: &str = "\
+--- a/kernel/mm/pmm.c
++++ b/kernel/mm/pmm.c
+@@ -20,4 +20,1 @@
+ int pmm_count;
+-void pmm_free(void *p)
+-{
+-}
+";
+
+const TOUCH_MAIN: &str = "\
+--- a/kernel/main.c
++++ b/kernel/main.c
+@@ -1,1 +1,2 @@
+ int kmain_ready;
++int kmain_ticks;
+";
+
+#[test]
+fn removing_an_export_the_diff_deletes_is_a_warning() {
+    let old = kernel(0x100000, &["kmain", "pmm_free"]);
+    let new = kernel(0x100000, &["kmain"]);
+    assert_eq!(
+        abi_findings(DROP_PMM_FREE, &old, &new),
+        [(
+            "abi-export-removed".to_string(),
+            Severity::Warning,
+            "kernel/mm/pmm.c".to_string(),
+            21
+        )]
+    );
+}
+
+#[test]
+fn an_unexplained_lost_export_or_load_address_is_an_error() {
+    let old = kernel(0x100000, &["kmain", "pmm_free"]);
+    let new = kernel(0x200000, &["kmain"]);
+    let findings = abi_findings(TOUCH_MAIN, &old, &new);
+    let rules: Vec<_> = findings.iter().map(|f| (f.0.as_str(), f.1)).collect();
+    assert_eq!(
+        rules,
+        [
+            ("abi-export-removed", Severity::Error),
+            ("abi-load-address-moved", Severity::Error)
+        ]
+    );
+    let script = "--- a/linker.ld\n+++ b/linker.ld\n@@ -1,1 +1,1 @@\n-. = 1M;\n+. = 2M;\n";
+    let findings = abi_findings(script, &kernel(0x100000, &[]), &kernel(0x200000, &[]));
+    assert_eq!(
+        findings,
+        [(
+            "abi-load-address-moved".to_string(),
+            Severity::Warning,
+            "linker.ld".to_string(),
+            0
+        )]
+    );
+}
+
+#[test]
+fn unchanged_kernels_raise_nothing() {
+    let image = kernel(0x100000, &["kmain"]);
+    assert!(abi_findings(TOUCH_MAIN, &image, &image).is_empty());
+}
@@ -0,0 +1,145 @@
+//! Integration tests for running clang-tidy on patched trees, with a
+//! stand-in clang-tidy that reports a fixed diagnostic and records the
+//! compile database it was given.
+
+use diff_validator::analyzer::{Analyzer, ClangTidy};
+use diff_validator::apply::Tree;
+use diff_validator::rules::Rules;
+use diff_validator::series::Checks;
+use diff_validator::Severity;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Reports `sizeof` in each file it is given, at its first occurrence,
+/// and the analysed files' compile database to `seen-compdb.json`.
+const FAKE_TIDY: &str = r#"#!/bin/sh
+for arg; do
+	case "$arg" in
+	-p) next=db ;;
+	--export-fixes=*) fixes="${arg#--export-fixes=}" ;;
+	-*) ;;
+	*) if [ "$next" = db ]; then db="$arg"; next=; else files="$files $arg"; fi ;;
+	esac
+done
+cp "$db/compile_commands.json" "$(dirname "$0")/seen-compdb.json"
+{
+	echo "---"
+	echo "Diagnostics:"
+	for f in $files; do
+		off=$(grep -bo sizeof "$f" | head -n1 | cut -d: -f1)
+		[ -n "$off" ] || continue
+		echo "  - DiagnosticName:  bugprone-sizeof-expression"
+		echo "    DiagnosticMessage:"
+		echo "      Message:         'suspicious usage of ''sizeof(K)'''"
+		echo "      FilePath:        '$f'"
+		echo "      FileOffset:      $off"
+		echo "      Replacements:    []"
+		echo "    Level:           Warning"
+	done
+	echo "..."
+} > "$fixes"
+"#;
+
+fn setup(name: &str) -> (PathBuf, PathBuf, PathBuf) {
+    let root = std::env::temp_dir().join(format!("diff-validator-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let ws = root.join("kernels/x86_64");
+    std::fs::create_dir_all(ws.join("kernel/include")).unwrap();
+    std::fs::write(ws.join("kernel/include/k.h"), "#define K 4\n").unwrap();
+    std::fs::write(
+        ws.join("kernel/a.c"),
+        "#include \"k.h\"\n\nint a(void)\n{\n\treturn K;\n}\n",
+    )
+    .unwrap();
+    std::fs::write(ws.join("kernel/b.c"), "int b;\n").unwrap();
+
+    let file = ws.join("kernel/a.c").display().to_string();
+    let compdb = root.join("compile_commands.json");
+    let entries = serde_json::json!([{
+        "directory": root.display().to_string(),
+        "file": file,
+        "arguments": ["gcc", "-Ikernels/x86_64/kernel/include", "-I", "/usr/include/x", "-c", file],
+        "output": "build/obj/a.o",
+    }]);
+    std::fs::write(&compdb, entries.to_string()).unwrap();
+
+    let tidy = root.join("clang-tidy");
+    std::fs::write(&tidy, FAKE_TIDY).unwrap();
+    std::fs::set_permissions(&tidy, std::fs::Permissions::from_mode(0o755)).unwrap();
+    (ws, compdb, tidy)
+}
+
+fn analyzer(ws: &Path, compdb: &Path, tidy: &Path) -> Analyzer {
+    let config = ClangTidy {
+        program: tidy.display().to_string(),
+        ..Default::default()
+    };
+    Analyzer::new(ws, compdb, config).unwrap()
+}
+
+const SIZEOF_IN_A: &str = "\
+--- a/kernel/a.c
++++ b/kernel/a.c
+@@ -4,3 +4,3 @@ int a(void)
+ {
+-\treturn K;
++\treturn sizeof(K);
+ }
+";
+
+#[test]
+fn diagnostics_in_changed_files_become_findings() {
+    let (ws, compdb, tidy) = setup("tidy-findings");
+    let rules = Rules::default();
+    let mut checks = Checks::new(&rules);
+    checks.tree = Some(Tree::new(&ws));
+    checks.analyzer = Some(analyzer(&ws, &compdb, &tidy));
+
+    let findings = checks.check(SIZEOF_IN_A);
+    assert_eq!(findings.len(), 1, "{findings:?}");
+    let f = &findings[0];
+    assert_eq!(
+        (f.severity, f.file.as_str(), f.line, f.rule.as_str()),
+        (
+            Severity::Warning,
+            "kernel/a.c",
+            5,
+            "bugprone-sizeof-expression"
+        )
+    );
+    assert_eq!(f.message, "suspicious usage of 'sizeof(K)'");
+
+    // Paths into the workspace now point into the overlay; others stay.
+    let seen = std::fs::read_to_string(tidy.with_file_name("seen-compdb.json")).unwrap();
+    let seen: serde_json::Value = serde_json::from_str(&seen).unwrap();
+    let args = seen[0]["arguments"].as_array().unwrap();
+    let file = seen[0]["file"].as_str().unwrap();
+    assert!(file.ends_with("/kernel/a.c") && !file.starts_with(&*ws.to_string_lossy()));
+    let include = args[1].as_str().unwrap();
+    assert!(include.starts_with("-I/") && include.ends_with("/kernel/include"));
+    assert!(!include.contains("kernels/x86_64"), "{include}");
+    assert_eq!(args[3], "/usr/include/x");
+    assert_eq!(args[5].as_str().unwrap(), file);
+
+    // A diff that changes no analysed file runs nothing.
+    let other = "--- a/kernel/b.c\n+++ b/kernel/b.c\n@@ -1 +1 @@\n-int b;\n+int b = 1;\n";
+    assert_eq!(checks.check(other), []);
+    drop(checks);
+    std::fs::remove_dir_all(ws.parent().unwrap().parent().unwrap()).unwrap();
+}
+
+#[test]
+fn a_missing_program_is_reported_once_per_diff() {
+    let (ws, compdb, tidy) = setup("tidy-missing");
+    let rules = Rules::default();
+    let mut checks = Checks::new(&rules);
+    checks.tree = Some(Tree::new(&ws));
+    checks.analyzer = Some(analyzer(&ws, &compdb, &tidy.with_file_name("no-such-tidy")));
+
+    let findings = checks.check(SIZEOF_IN_A);
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].rule, "analyzer-unavailable");
+    assert_eq!(findings[0].severity, Severity::Error);
+    drop(checks);
+    std::fs::remove_dir_all(ws.parent().unwrap().parent().unwrap()).unwrap();
+}
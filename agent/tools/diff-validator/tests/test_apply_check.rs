@@ -0,0 +1,118 @@
+//! Integration tests for checking that diffs apply to a workspace.
+
+use diff_validator::apply::{check, findings, HunkStatus};
+use diff_validator::patch::parse;
+use std::path::PathBuf;
+
+fn workspace(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("diff-validator-{}-{name}", std::process::id()));
+    for (path, text) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, text).unwrap();
+    }
+    dir
+}
+
+const PMM: &str = "void pmm_init(void)\n{\n\tint pages = 0;\n\tscan();\n}\n";
+
+#[test]
+fn clean_diff_applies_to_every_file() {
+    let dir = workspace(
+        "clean",
+        &[("kernel/mm/pmm.c", PMM), ("kernel/old.c", "int old;\n")],
+    );
+    let diff = "\
+diff --git a/kernel/mm/pmm.c b/kernel/mm/pmm.c
+--- a/kernel/mm/pmm.c
++++ b/kernel/mm/pmm.c
+@@ -2,3 +2,4 @@ void pmm_init(void)
+ {
+ \tint pages = 0;
++\tint frames = 0;
+ \tscan();
+diff --git a/kernel/old.c b/kernel/new.c
+similarity index 100%
+rename from kernel/old.c
+rename to kernel/new.c
+diff --git a/kernel/fresh.c b/kernel/fresh.c
+new file mode 100644
+--- /dev/null
++++ b/kernel/fresh.c
+@@ -0,0 +1,1 @@
++int fresh;
+";
+    let results = check(&parse(diff).unwrap(), &dir, 0);
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|r| r.clean()), "{results:?}");
+    assert!(findings(&results).is_empty());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn reports_each_failing_hunk_and_file() {
+    let dir = workspace(
+        "broken",
+        &[("kernel/mm/pmm.c", PMM), ("kernel/fresh.c", "")],
+    );
+    let diff = "\
+--- a/kernel/mm/pmm.c
++++ b/kernel/mm/pmm.c
+@@ -1,2 +1,2 @@
+-void pmm_init(void)
++void pmm_init(unsigned flags)
+ {
+@@ -3,2 +3,2 @@
+-\tint pages = 1;
++\tint pages = 2;
+ \tscan();
+--- /dev/null
++++ b/kernel/fresh.c
+@@ -0,0 +1 @@
++int fresh;
+--- a/kernel/missing.c
++++ b/kernel/missing.c
+@@ -1 +1 @@
+-a
++b
+";
+    let results = check(&parse(diff).unwrap(), &dir, 2);
+    let pmm = &results[0];
+    assert!(pmm.hunks[0].applied());
+    let HunkStatus::Failed { reason } = &pmm.hunks[1].status else {
+        panic!("{pmm:?}");
+    };
+    assert!(
+        reason.contains("line 3 should be `\tint pages = 1;`"),
+        "{reason}"
+    );
+    assert_eq!(
+        results[1].error.as_deref(),
+        Some("`kernel/fresh.c` is added but already exists")
+    );
+    assert_eq!(
+        results[2].error.as_deref(),
+        Some("`kernel/missing.c` does not exist")
+    );
+
+    let findings = findings(&results);
+    let rules: Vec<&str> = findings.iter().map(|f| f.rule.as_str()).collect();
+    assert_eq!(rules, ["hunk-does-not-apply", "patch-file", "patch-file"]);
+    assert_eq!(
+        (findings[0].file.as_str(), findings[0].line),
+        ("kernel/mm/pmm.c", 3)
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn rejects_paths_outside_the_workspace() {
+    let dir = workspace("escape", &[("x.c", "a\n")]);
+    let diff = "--- a/../x.c\n+++ b/../x.c\n@@ -1 +1 @@\n-a\n+b\n";
+    let results = check(&parse(diff).unwrap(), &dir, 0);
+    assert_eq!(
+        results[0].error.as_deref(),
+        Some("`../x.c` is outside the workspace")
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
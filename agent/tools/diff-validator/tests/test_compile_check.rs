@@ -0,0 +1,137 @@
+//! Integration tests for compile-checking patched trees.
+
+use diff_validator::apply::Tree;
+use diff_validator::compile::{CompileCheck, CompileOptions};
+use diff_validator::rules::Rules;
+use diff_validator::series::{Checks, SeriesItem};
+use diff_validator::Severity;
+use kernel_builder::profile::Profile;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn have_gcc() -> bool {
+    let found = Command::new("gcc").arg("--version").output().is_ok();
+    if !found {
+        eprintln!("skipping: no gcc");
+    }
+    found
+}
+
+fn workspace(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("diff-validator-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("kernel/include")).unwrap();
+    std::fs::write(dir.join("kernel/include/pmm.h"), "int pmm_pages(void);\n").unwrap();
+    std::fs::write(
+        dir.join("kernel/pmm.c"),
+        "#include \"pmm.h\"\n\nint pmm_pages(void)\n{\n\treturn 0;\n}\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("kernel/main.c"),
+        "#include \"pmm.h\"\n\nint kmain(void)\n{\n\treturn pmm_pages();\n}\n",
+    )
+    .unwrap();
+    dir
+}
+
+fn checks<'r>(rules: &'r Rules, dir: &Path) -> Checks<'r> {
+    let options = CompileOptions {
+        arch: "x86_64".into(),
+        cc: Some("gcc".into()),
+        profile: Profile::default(),
+        jobs: 2,
+    };
+    let mut checks = Checks::new(rules);
+    checks.tree = Some(Tree::new(dir));
+    checks.compile = Some(CompileCheck::new(dir, options).unwrap());
+    checks
+}
+
+fn item(id: &str, diff: &str) -> SeriesItem {
+    SeriesItem {
+        id: id.into(),
+        subject: None,
+        diff: diff.into(),
+    }
+}
+
+const WIDEN_IN_HEADER: &str = "\
+--- a/kernel/include/pmm.h
++++ b/kernel/include/pmm.h
+@@ -1 +1 @@
+-int pmm_pages(void);
++long pmm_pages(void);
+";
+
+const WIDEN_IN_SOURCE: &str = "\
+--- a/kernel/pmm.c
++++ b/kernel/pmm.c
+@@ -1,6 +1,6 @@
+ #include \"pmm.h\"
+\x20
+-int pmm_pages(void)
++long pmm_pages(void)
+ {
+ \treturn 0;
+ }
+";
+
+#[test]
+fn compiler_errors_fail_the_diff_that_caused_them() {
+    if !have_gcc() {
+        return;
+    }
+    let dir = workspace("compile-series");
+    let rules = Rules::default();
+    let mut checks = checks(&rules, &dir);
+
+    // Changing the prototype alone conflicts with the definition; the
+    // follow-up fixes it, compiled against the header the first diff left.
+    let verdicts = checks.series(&[item("1", WIDEN_IN_HEADER), item("2", WIDEN_IN_SOURCE)]);
+    assert!(!verdicts[0].passed);
+    let files: Vec<(&str, usize)> = verdicts[0]
+        .findings
+        .iter()
+        .filter(|f| f.rule == "compile-error")
+        .map(|f| (f.file.as_str(), f.line))
+        .collect();
+    assert!(files.contains(&("kernel/pmm.c", 3)), "{files:?}");
+    assert!(verdicts[1].passed, "{:#?}", verdicts[1]);
+    drop(checks);
+
+    // The overlay took the diffs, not the workspace.
+    let header = std::fs::read_to_string(dir.join("kernel/include/pmm.h")).unwrap();
+    assert_eq!(header, "int pmm_pages(void);\n");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn warnings_are_reported_only_in_changed_files() {
+    if !have_gcc() {
+        return;
+    }
+    let dir = workspace("compile-warning");
+    let rules = Rules::default();
+    let mut checks = checks(&rules, &dir);
+    let diff = "\
+--- a/kernel/pmm.c
++++ b/kernel/pmm.c
+@@ -3,4 +3,5 @@
+ int pmm_pages(void)
+ {
++\tint spare;
+ \treturn 0;
+ }
+";
+    let findings = checks.check(diff);
+    assert_eq!(findings.len(), 1, "{findings:#?}");
+    assert_eq!(findings[0].rule, "compile-warning");
+    assert_eq!(findings[0].severity, Severity::Warning);
+    assert_eq!(
+        (findings[0].file.as_str(), findings[0].line),
+        ("kernel/pmm.c", 5)
+    );
+    drop(checks);
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,100 @@
+//! Integration tests for the fixes suggested with findings.
+
+use diff_validator::apply::Tree;
+use diff_validator::{fix, patch, validate};
+
+const NEW_FILES: &str = "\
+diff --git a/kernel/include/klog.h b/kernel/include/klog.h
+new file mode 100644
+--- /dev/null
++++ b/kernel/include/klog.h
+@@ -0,0 +1,3 @@
++/* Kernel log ring. */
++
++void klog_name(char *out, int cpu);
+diff --git a/kernel/klog.c b/kernel/klog.c
+new file mode 100644
+--- /dev/null
++++ b/kernel/klog.c
+@@ -0,0 +1,9 @@
++#include \"klog.h\"
++
++static char prefix[16];
++
++void klog_name(char *out, int cpu)
++{
++\tsprintf(prefix, \"cpu%d: \", cpu);
++\tstrcpy(out, prefix);
++}
+";
+
+#[test]
+fn fixes_apply_on_top_of_the_diff() {
+    let findings = validate(NEW_FILES);
+    let fixes: Vec<(usize, Option<&str>)> = findings
+        .iter()
+        .map(|f| (f.line, f.fix.as_ref().map(|x| x.description.as_str())))
+        .collect();
+    // `out` is a pointer, so its size bounds nothing.
+    assert_eq!(
+        fixes,
+        [
+            (7, Some("use snprintf bounded by sizeof(prefix)")),
+            (8, None),
+            (1, Some("guard the header with AUTON_KLOG_H")),
+        ]
+    );
+
+    let dir = std::env::temp_dir().join(format!("diff-validator-{}-fixes", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut tree = Tree::new(&dir);
+    assert!(tree
+        .apply(&patch::parse(NEW_FILES).unwrap(), 0)
+        .iter()
+        .all(|r| r.clean()));
+    let fixes = patch::parse(&fix::patch(&findings)).unwrap();
+    assert!(tree.apply(&fixes, 0).iter().all(|r| r.clean()));
+    assert_eq!(
+        tree.file("kernel/include/klog.h").unwrap(),
+        [
+            "/* Kernel log ring. */",
+            "",
+            "#ifndef AUTON_KLOG_H",
+            "#define AUTON_KLOG_H",
+            "",
+            "void klog_name(char *out, int cpu);",
+            "",
+            "#endif /* AUTON_KLOG_H */",
+        ]
+    );
+    assert_eq!(
+        tree.file("kernel/klog.c").unwrap()[6],
+        "\tsnprintf(prefix, sizeof(prefix), \"cpu%d: \", cpu);"
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn guarded_headers_and_unfixable_calls_get_no_fix() {
+    let diff = "\
+diff --git a/kernel/include/kbuf.h b/kernel/include/kbuf.h
+new file mode 100644
+--- /dev/null
++++ b/kernel/include/kbuf.h
+@@ -0,0 +1,4 @@
++#ifndef AUTON_KBUF_H
++#define AUTON_KBUF_H
++void kbuf_fill(char *p, const char *s) { strcpy(p + 1, s); }
++#endif
+";
+    let findings = validate(diff);
+    assert!(findings.iter().all(|f| f.rule != "missing-include-guard"));
+    let banned: Vec<_> = findings
+        .iter()
+        .filter(|f| f.rule == "banned-function")
+        .collect();
+    assert_eq!(banned.len(), 1);
+    assert!(banned[0].fix.is_none());
+    assert_eq!(fix::patch(&findings), "");
+}
@@ -0,0 +1,106 @@
+//! Integration tests for three-way merging diffs the workspace has moved
+//! on from.
+
+mod common;
+
+use common::{git, scratch_repo};
+use diff_validator::apply::{self, Tree};
+use diff_validator::patch;
+use std::path::PathBuf;
+
+fn numbered(edit: impl Fn(usize) -> Option<&'static str>) -> String {
+    (1..=10)
+        .map(|i| edit(i).map_or(format!("int l{i};\n"), |l| format!("{l}\n")))
+        .collect()
+}
+
+/// A repository whose `kernel/irq.c` the returned diff (made against its
+/// first commit) changes at line 6, after which the workspace changed its
+/// line `changed` to `now`.
+fn repo(name: &str, changed: usize, now: &'static str) -> (PathBuf, String) {
+    let dir = scratch_repo(name);
+    std::fs::create_dir_all(dir.join("kernel")).unwrap();
+    let file = dir.join("kernel/irq.c");
+    std::fs::write(&file, numbered(|_| None)).unwrap();
+    git(&dir, &["add", "-A"]);
+    git(&dir, &["commit", "-q", "-m", "base"]);
+
+    std::fs::write(&file, numbered(|i| (i == 6).then_some("int agent;"))).unwrap();
+    let diff = git(&dir, &["diff"]);
+    std::fs::write(&file, numbered(|i| (i == changed).then_some(now))).unwrap();
+    git(&dir, &["commit", "-q", "-am", "moved on"]);
+    (dir, diff)
+}
+
+#[test]
+fn drifted_context_merges_and_rebases() {
+    let (dir, diff) = repo("merge-drift", 4, "int workspace;");
+    let files = patch::parse(&diff).unwrap();
+    assert!(files[0].old_blob.is_some());
+
+    // Plainly, the changed context line stops the hunk.
+    let plain = Tree::new(&dir).apply(&files, 0);
+    assert!(!plain[0].clean());
+
+    let mut tree = Tree::new(&dir);
+    tree.merge = true;
+    let results = tree.apply(&files, 0);
+    assert!(results[0].clean(), "{results:?}");
+    let findings = apply::findings(&results);
+    assert_eq!(findings.len(), 1, "{findings:?}");
+    assert_eq!(findings[0].rule, "hunk-merged");
+    let lines = tree.file("kernel/irq.c").unwrap();
+    assert_eq!(
+        (lines[3].as_str(), lines[5].as_str()),
+        ("int workspace;", "int agent;")
+    );
+
+    // The rebased diff applies to the workspace as it is.
+    let rebased = apply::rebased(&files, &results, &dir, &tree);
+    let mut fresh = Tree::new(&dir);
+    assert!(fresh
+        .apply(&patch::parse(&rebased).unwrap(), 0)
+        .iter()
+        .all(|r| r.clean() && r.merge.is_none()));
+    assert_eq!(fresh.file("kernel/irq.c"), tree.file("kernel/irq.c"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn overlapping_changes_conflict() {
+    let (dir, diff) = repo("merge-conflict", 6, "int workspace;");
+    let mut tree = Tree::new(&dir);
+    tree.merge = true;
+    let results = tree.apply(&patch::parse(&diff).unwrap(), 0);
+    assert!(!results[0].clean());
+    let findings = apply::findings(&results);
+    let rules: Vec<(&str, usize)> = findings.iter().map(|f| (f.rule.as_str(), f.line)).collect();
+    assert_eq!(rules, [("merge-conflict", 6)]);
+    assert!(
+        findings[0]
+            .message
+            .ends_with("the workspace has `int workspace;`, the diff `int agent;`"),
+        "{}",
+        findings[0].message
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn unknown_base_blobs_fall_back_to_plain_failures() {
+    let (dir, diff) = repo("merge-no-base", 4, "int workspace;");
+    let diff: String = diff
+        .lines()
+        .map(|l| match l.starts_with("index ") {
+            true => "index 1234567..89abcde 100644\n".to_string(),
+            false => format!("{l}\n"),
+        })
+        .collect();
+    let mut tree = Tree::new(&dir);
+    tree.merge = true;
+    let results = tree.apply(&patch::parse(&diff).unwrap(), 0);
+    let findings = apply::findings(&results);
+    let rules: Vec<&str> = findings.iter().map(|f| f.rule.as_str()).collect();
+    assert_eq!(rules, ["merge-unavailable", "hunk-does-not-apply"]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,109 @@
+//! Integration tests for diff scope and path policy.
+
+use diff_validator::rules::Rules;
+use diff_validator::{has_errors, validate, validate_with, Severity};
+
+fn new_file(path: &str, lines: usize) -> String {
+    let mut diff = format!("diff --git a/{path} b/{path}\nnew file mode 100644\n");
+    diff.push_str(&format!(
+        "--- /dev/null\n+++ b/{path}\n@@ -0,0 +1,{lines} @@\n"
+    ));
+    for i in 0..lines {
+        diff.push_str(&format!("+int v{i};\n"));
+    }
+    diff
+}
+
+fn policy_rules(diff: &str, rules: &Rules) -> Vec<(String, String)> {
+    validate_with(diff, rules)
+        .into_iter()
+        .filter(|f| f.rule.starts_with("policy-"))
+        .map(|f| (f.rule, f.file))
+        .collect()
+}
+
+#[test]
+fn default_policy_fails_oversized_and_forbidden_diffs() {
+    let big = new_file("kernel/mm/vmm.c", 501);
+    let findings = validate(&big);
+    assert!(has_errors(&findings));
+    assert_eq!(findings[0].rule, "policy-max-lines");
+    assert!(findings[0].message.contains("501 lines"));
+
+    let boot = new_file("kernels/x86_64/kernel/boot/kernel_main.c", 3);
+    let findings = validate(&boot);
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].rule, "policy-forbidden-path");
+    assert_eq!(findings[0].severity, Severity::Error);
+
+    assert_eq!(validate(&new_file("kernel/mm/vmm.c", 10)), []);
+}
+
+#[test]
+fn rules_file_sets_limits_paths_and_requirements() {
+    let path =
+        std::env::temp_dir().join(format!("diff-validator-{}-policy.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        "[policy]\n\
+         max-lines = 0\n\
+         max-files = 2\n\
+         forbidden = [\"grub/\"]\n\
+         \n\
+         [[policy.require]]\n\
+         changed = \"kernel/net/\"\n\
+         with = [\"tests/net/\"]\n\
+         \n\
+         [allow]\n\
+         policy-forbidden-path = [\"grub/grub-neural.cfg\"]\n",
+    )
+    .unwrap();
+    let rules = Rules::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // No line limit, and boot/ is no longer forbidden.
+    assert_eq!(
+        policy_rules(&new_file("kernel/boot/kernel_main.c", 900), &rules),
+        []
+    );
+
+    let diff = new_file("kernel/net/tcp.c", 1)
+        + &new_file("grub/grub.cfg", 1)
+        + &new_file("grub/grub-neural.cfg", 1);
+    let found = policy_rules(&diff, &rules);
+    let expect = [
+        ("policy-max-files", ""),
+        ("policy-forbidden-path", "grub/grub.cfg"),
+        ("policy-required-path", "kernel/net/tcp.c"),
+    ];
+    assert_eq!(
+        found,
+        expect.map(|(r, f)| (r.to_string(), f.to_string())),
+        "{found:?}"
+    );
+
+    let paired = new_file("kernel/net/tcp.c", 1) + &new_file("tests/net/tcp.sh", 1);
+    assert_eq!(policy_rules(&paired, &rules), []);
+}
+
+#[test]
+fn disk_images_and_invalid_text_are_rejected_per_file() {
+    let raw = b"--- /dev/null\n+++ b/kernel/font.c\n@@ -0,0 +1,1 @@\n+char glyph = '\xff';\n";
+    let diff = String::from_utf8_lossy(raw).into_owned()
+        + "diff --git a/kernels/x86_64/os.img b/kernels/x86_64/os.img\n\
+           new file mode 100644\n\
+           GIT binary patch\n\
+           literal 4\n\
+           Lcmb=e00961>\n\n";
+    let found = policy_rules(&diff, &Rules::default());
+    let expect = [
+        ("policy-suspicious-encoding", "kernel/font.c"),
+        ("policy-binary-file", "kernels/x86_64/os.img"),
+        ("policy-build-artifact", "kernels/x86_64/os.img"),
+    ];
+    assert_eq!(
+        found,
+        expect.map(|(r, f)| (r.to_string(), f.to_string())),
+        "{found:?}"
+    );
+}
@@ -0,0 +1,84 @@
+//! Integration tests for risk scoring and the outcome history behind it.
+
+use diff_validator::patch;
+use diff_validator::risk::History;
+use diff_validator::rules::Rules;
+use diff_validator::series::{Checks, SeriesItem};
+
+const DRIVER: &str = "\
+--- a/kernel/drivers/serial.c
++++ b/kernel/drivers/serial.c
+@@ -1,2 +1,3 @@
+ int baud;
++#include <stdio.h>
+ int parity;
+";
+
+const TEST: &str = "\
+--- a/tests/serial.toml
++++ b/tests/serial.toml
+@@ -1,1 +1,2 @@
+ name = \"serial\"
++timeout = 30
+";
+
+fn item(id: &str, diff: &str) -> SeriesItem {
+    SeriesItem {
+        id: id.into(),
+        subject: None,
+        diff: diff.into(),
+    }
+}
+
+#[test]
+fn riskier_diffs_score_higher() {
+    let mut history = History::default();
+    let driver = patch::parse(DRIVER).unwrap();
+    for passed in [false, false, true, false] {
+        history.record(&driver, passed);
+    }
+    let rules = Rules::default();
+    let mut checks = Checks::new(&rules);
+    checks.risk = Some(history);
+
+    let verdicts = checks.series(&[item("driver", DRIVER), item("test", TEST)]);
+    let driver = verdicts[0].risk.as_ref().unwrap();
+    let test = verdicts[1].risk.as_ref().unwrap();
+    assert!(driver.score > test.score, "{driver:?} vs {test:?}");
+    let history = driver.factors.iter().find(|f| f.name == "history").unwrap();
+    assert_eq!(
+        history.detail,
+        "kernel/drivers/serial.c failed 3 of its last 4 outcomes"
+    );
+    assert_eq!(history.points, 15);
+
+    let json = serde_json::to_value(&verdicts[1]).unwrap();
+    assert_eq!(json["risk"]["score"], test.score);
+    assert_eq!(
+        json["risk"]["factors"][2]["detail"],
+        "tests/serial.toml is 10% critical"
+    );
+}
+
+#[test]
+fn history_round_trips_through_its_file() {
+    let dir = std::env::temp_dir().join(format!("diff-validator-{}-risk", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("build/diff-history.json");
+    assert_eq!(History::load(&path).unwrap(), History::default());
+
+    let mut history = History::default();
+    history.record(&patch::parse(TEST).unwrap(), true);
+    history.save(&path).unwrap();
+    assert_eq!(History::load(&path).unwrap(), history);
+    assert_eq!(history.files["tests/serial.toml"], [true]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn unscored_verdicts_leave_risk_out() {
+    let rules = Rules::default();
+    let verdicts = Checks::new(&rules).series(&[item("test", TEST)]);
+    let json = serde_json::to_value(&verdicts[0]).unwrap();
+    assert!(json.get("risk").is_none());
+}
@@ -0,0 +1,129 @@
+//! Integration tests for the security rules.
+
+use diff_validator::rules::Rules;
+use diff_validator::{has_errors, validate, validate_with, Finding, Severity};
+
+fn added(path: &str, lines: &[&str]) -> String {
+    let mut diff = format!("+++ b/{path}\n@@ -0,0 +1,{} @@\n", lines.len());
+    for line in lines {
+        diff.push('+');
+        diff.push_str(line);
+        diff.push('\n');
+    }
+    diff
+}
+
+fn rules_of(findings: &[Finding]) -> Vec<(&str, usize)> {
+    findings.iter().map(|f| (f.rule.as_str(), f.line)).collect()
+}
+
+const PRIVILEGED: &[&str] = &[
+    "void mm_switch(uint64_t pml4, uint64_t efer)",
+    "{",
+    "\twrite_cr3(pml4);",
+    "\t__asm__ volatile(\"mov %0, %%cr4\" : : \"r\"(efer));",
+    "\t__asm__ volatile(",
+    "\t\t\"movl %0, %%ecx\\n\\t\"",
+    "\t\t\"wrmsr\" : : \"r\"(0xC0000080));",
+    "\tcr4 &= ~X86_CR4_SMEP;",
+    "\tefer &= ~(1UL << 11);",
+    "\t__asm__ volatile(\"swapgs\");",
+    "}",
+];
+
+#[test]
+fn privileged_state_outside_arch_is_a_hard_failure() {
+    let findings = validate(&added("kernel/mm/switch.c", PRIVILEGED));
+    assert_eq!(
+        rules_of(&findings),
+        [
+            ("control-register-write", 3),
+            ("control-register-write", 4),
+            ("msr-write", 7),
+            ("protection-disabled", 8),
+            ("protection-disabled", 9),
+            ("segment-register-asm", 10),
+        ]
+    );
+    assert!(findings.iter().all(|f| f.severity == Severity::Error));
+    assert!(findings[4].message.contains("EFER bit 11 (NXE)"));
+
+    // The same code is the architecture layer's to write, except for
+    // turning protections off.
+    let findings = validate(&added("kernel/arch/x86_64/mm.c", PRIVILEGED));
+    let rules: Vec<&str> = findings.iter().map(|f| f.rule.as_str()).collect();
+    assert_eq!(rules, ["protection-disabled", "protection-disabled"]);
+}
+
+#[test]
+fn reads_of_privileged_state_are_clean() {
+    let diff = added(
+        "kernel/sys/info.c",
+        &[
+            "uint64_t cr3_now(void)",
+            "{",
+            "\tuint64_t v;",
+            "\t__asm__ volatile(\"mov %%cr3, %0\" : \"=r\"(v));",
+            "\tmask &= ~FLAG_DIRTY; /* wrmsr in a comment */",
+            "\treturn v;",
+            "}",
+        ],
+    );
+    assert_eq!(validate(&diff), []);
+}
+
+#[test]
+fn user_copies_need_an_access_check_first() {
+    let diff = added(
+        "kernel/sys/syscall.c",
+        &[
+            "long sys_write(const char __user *buf, size_t n)",
+            "{",
+            "\tchar k[64];",
+            "\tmemcpy(k, buf, n);",
+            "\tmemcpy(k, ubuf, n);",
+            "}",
+            "long sys_read(const char *ubuf, size_t n)",
+            "{",
+            "\tif (!access_ok(ubuf, n))",
+            "\t\treturn -1;",
+            "\tmemcpy(k, ubuf, n);",
+            "\tmemcpy(ubuf, k, n);",
+            "}",
+        ],
+    );
+    let findings = validate(&diff);
+    assert_eq!(
+        rules_of(&findings),
+        [("user-copy-unchecked", 4), ("user-copy-unchecked", 5)]
+    );
+    assert!(has_errors(&findings));
+}
+
+#[test]
+fn allowlist_comes_from_the_rules_file() {
+    let path = std::env::temp_dir().join(format!(
+        "diff-validator-{}-security.toml",
+        std::process::id()
+    ));
+    std::fs::write(
+        &path,
+        "[allow]\nmsr-write = [\"kernel/mm/\"]\n\n[security]\nuser-checks = [\"uaccess_ok\"]\n",
+    )
+    .unwrap();
+    let rules = Rules::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let findings = validate_with(&added("kernel/mm/switch.c", PRIVILEGED), &rules);
+    assert!(!findings.iter().any(|f| f.rule == "msr-write"));
+    // The table replaces the defaults, so control registers are no longer
+    // allowed anywhere.
+    let findings = validate_with(&added("kernel/arch/x86_64/mm.c", PRIVILEGED), &rules);
+    assert!(findings.iter().any(|f| f.rule == "control-register-write"));
+
+    let copy = added(
+        "x.c",
+        &["{", "\tuaccess_ok(ubuf, n);", "\tmemcpy(k, ubuf, n);", "}"],
+    );
+    assert_eq!(validate_with(&copy, &rules), []);
+}
@@ -0,0 +1,115 @@
+//! Integration tests for the checks on parsed C functions.
+
+use diff_validator::rules::Rules;
+use diff_validator::{has_errors, validate, validate_with, Finding};
+
+fn new_file(path: &str, src: &str) -> String {
+    let lines: Vec<&str> = src.lines().collect();
+    let mut diff = format!("diff --git a/{path} b/{path}\nnew file mode 100644\n");
+    diff.push_str(&format!(
+        "--- /dev/null\n+++ b/{path}\n@@ -0,0 +1,{} @@\n",
+        lines.len()
+    ));
+    for l in lines {
+        diff.push_str(&format!("+{l}\n"));
+    }
+    diff
+}
+
+fn rules_at(findings: &[Finding]) -> Vec<(&str, &str, usize)> {
+    findings
+        .iter()
+        .map(|f| (f.rule.as_str(), f.file.as_str(), f.line))
+        .collect()
+}
+
+const DRIVER: &str = "\
+enum mode { MODE_OFF, MODE_POLL, MODE_IRQ, NR_MODES };
+
+static int ring[16];
+
+int ring_get(unsigned int slot)
+{
+	return ring[slot];
+}
+
+const char *mode_name(enum mode m)
+{
+	switch (m) {
+	case MODE_OFF:
+		return \"off\";
+	case MODE_POLL:
+		return \"poll\";
+	}
+	return \"?\";
+}
+
+int start(struct dev *d)
+{
+	mutex_lock(&d->m);
+	if (d->mode == MODE_OFF)
+		return -1;
+	d->mode = MODE_IRQ;
+	mutex_unlock(&d->m);
+	return 0;
+}
+";
+
+#[test]
+fn each_check_reports_where_the_fault_is() {
+    let findings = validate(&new_file("kernel/dev/ring.c", DRIVER));
+    assert!(!has_errors(&findings));
+    assert_eq!(
+        rules_at(&findings),
+        [
+            ("unchecked-index", "kernel/dev/ring.c", 7),
+            ("switch-missing-enum-case", "kernel/dev/ring.c", 12),
+            ("lock-not-released", "kernel/dev/ring.c", 25),
+        ]
+    );
+    assert!(findings[1].message.contains("MODE_IRQ"));
+    assert!(findings[2].message.contains("`d->m` locked at line 23"));
+}
+
+#[test]
+fn only_functions_the_diff_changes_are_checked() {
+    // The same function as context, with an unrelated line added after it.
+    let diff = "--- a/kernel/dev/ring.c\n\
+                +++ b/kernel/dev/ring.c\n\
+                @@ -1,5 +1,6 @@\n \
+                int ring_get(unsigned int slot)\n \
+                {\n \
+                \treturn ring[slot];\n \
+                }\n \
+                \n\
+                +int ring_size = 16;\n";
+    assert_eq!(validate(diff), []);
+
+    let changed = diff.replace(
+        " \treturn ring[slot];",
+        "-\treturn ring[0];\n+\treturn ring[slot];",
+    );
+    let findings = validate(&changed);
+    assert_eq!(
+        rules_at(&findings),
+        [("unchecked-index", "kernel/dev/ring.c", 3)]
+    );
+}
+
+#[test]
+fn rules_file_names_the_lock_calls() {
+    let path =
+        std::env::temp_dir().join(format!("diff-validator-{}-locks.toml", std::process::id()));
+    std::fs::write(&path, "[locks]\nirq_lock = \"irq_unlock\"\n").unwrap();
+    let rules = Rules::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let src = DRIVER
+        .replace("mutex_lock", "irq_lock")
+        .replace("mutex_unlock", "irq_unlock");
+    let findings = validate_with(&new_file("kernel/dev/ring.c", &src), &rules);
+    assert_eq!(findings.last().unwrap().rule, "lock-not-released");
+    // The table replaces the defaults.
+    let findings = validate_with(&new_file("kernel/dev/ring.c", DRIVER), &rules);
+    assert!(findings.iter().all(|f| f.rule != "lock-not-released"));
+}
@@ -0,0 +1,107 @@
+//! Integration tests for validating git ranges and patch series.
+
+mod common;
+
+use common::{git, scratch_repo};
+use diff_validator::rules::Rules;
+use diff_validator::series::{git_range, patch_dir, range_base, Checks};
+use std::path::{Path, PathBuf};
+
+fn commit(repo: &Path, path: &str, text: &str, subject: &str) {
+    std::fs::write(repo.join(path), text).unwrap();
+    git(repo, &["add", "-A"]);
+    git(repo, &["commit", "-q", "-m", subject]);
+}
+
+/// A repository with a base commit and three more on top: one clean, one
+/// adding a hosted header, one clean again.
+fn repo(name: &str) -> PathBuf {
+    let dir = scratch_repo(name);
+    commit(&dir, "pmm.c", "int pages;\n", "base");
+    commit(
+        &dir,
+        "pmm.c",
+        "int pages;\nint frames;\n",
+        "mm: count frames",
+    );
+    commit(
+        &dir,
+        "pmm.c",
+        "#include <stdlib.h>\nint pages;\nint frames;\n",
+        "mm: use malloc",
+    );
+    commit(
+        &dir,
+        "pmm.c",
+        "#include <stdlib.h>\nint pages;\nint frames;\nint holes;\n",
+        "mm: count holes",
+    );
+    dir
+}
+
+#[test]
+fn each_commit_of_a_range_gets_a_verdict() {
+    let dir = repo("range");
+    let items = git_range(&dir, "HEAD~3..HEAD").unwrap();
+    let subjects: Vec<_> = items.iter().map(|i| i.subject.as_deref()).collect();
+    assert_eq!(
+        subjects,
+        [
+            Some("mm: count frames"),
+            Some("mm: use malloc"),
+            Some("mm: count holes")
+        ]
+    );
+
+    // Later commits apply on top of earlier ones, read from the base commit.
+    let rules = Rules::default();
+    let mut checks = Checks::new(&rules);
+    checks.tree = Some(range_base(&dir, &items[0]));
+    let verdicts = checks.series(&items);
+    let passed: Vec<bool> = verdicts.iter().map(|v| v.passed).collect();
+    assert_eq!(passed, [true, false, true], "{verdicts:#?}");
+    let rules: Vec<&str> = verdicts[1].findings.iter().map(|f| &*f.rule).collect();
+    assert_eq!(rules, ["hosted-libc-header"]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn patch_directory_is_read_in_order() {
+    let dir = repo("patches");
+    let out = dir.join("out");
+    git(
+        &dir,
+        &[
+            "format-patch",
+            "-q",
+            "--cover-letter",
+            "-o",
+            "out",
+            "HEAD~3",
+        ],
+    );
+    let items = patch_dir(&out).unwrap();
+    assert_eq!(items.len(), 3, "cover letter skipped");
+    assert!(items[0].id.starts_with("0001-"));
+    assert_eq!(items[2].subject.as_deref(), Some("mm: count holes"));
+
+    // Without a workspace only the static rules run.
+    let rules = Rules::default();
+    let verdicts = Checks::new(&rules).series(&items);
+    assert!(!verdicts[1].passed && verdicts[2].passed);
+
+    // Against a workspace that has moved on, the first patch does not apply.
+    let workspace = dir.join("ws");
+    std::fs::create_dir_all(&workspace).unwrap();
+    std::fs::write(workspace.join("pmm.c"), "unsigned long pages;\n").unwrap();
+    let mut checks = Checks::new(&rules);
+    checks.tree = Some(diff_validator::apply::Tree::new(&workspace));
+    checks.fuzz = 0;
+    let verdicts = checks.series(&items);
+    assert!(!verdicts[0].passed);
+    assert!(verdicts[0]
+        .findings
+        .iter()
+        .any(|f| f.rule == "hunk-does-not-apply"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
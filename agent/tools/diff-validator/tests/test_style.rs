@@ -0,0 +1,96 @@
+//! Integration tests for the `.clang-format` style check, with a stand-in
+//! clang-format that only puts a space after `if`.
+
+use diff_validator::apply::Tree;
+use diff_validator::patch;
+use diff_validator::rules::Rules;
+use diff_validator::series::Checks;
+use diff_validator::style::{ClangFormat, StyleCheck, STYLE_NAME};
+use diff_validator::Severity;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+/// Formats stdin, and records its arguments in `format-args` beside itself.
+const FAKE_FORMAT: &str = "#!/bin/sh\n\
+                           printf '%s\\n' \"$@\" > \"$(dirname \"$0\")/format-args\"\n\
+                           sed 's/if(/if (/'\n";
+
+fn workspace(name: &str, style: bool) -> (PathBuf, ClangFormat) {
+    let dir = std::env::temp_dir().join(format!("diff-validator-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("kernel")).unwrap();
+    std::fs::write(
+        dir.join("kernel/irq.c"),
+        "int irq_count;\n\nvoid irq_tick(void)\n{\n\tirq_count++;\n}\n",
+    )
+    .unwrap();
+    if style {
+        std::fs::write(dir.join(STYLE_NAME), "BasedOnStyle: LLVM\n").unwrap();
+    }
+    let program = dir.join("clang-format");
+    std::fs::write(&program, FAKE_FORMAT).unwrap();
+    std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let config = ClangFormat {
+        program: program.display().to_string(),
+    };
+    (dir, config)
+}
+
+const UNSPACED_IF: &str = "\
+--- a/kernel/irq.c
++++ b/kernel/irq.c
+@@ -3,4 +3,6 @@ int irq_count;
+ void irq_tick(void)
+ {
+ \tirq_count++;
++\tif(irq_count > 100)
++\t\tirq_count = 0;
+ }
+";
+
+#[test]
+fn added_lines_off_style_fail_with_a_fix() {
+    let (dir, config) = workspace("style", true);
+    let rules = Rules::default();
+    let mut checks = Checks::new(&rules);
+    checks.tree = Some(Tree::new(&dir));
+    checks.style = StyleCheck::new(&dir, config).unwrap();
+
+    let findings = checks.check(UNSPACED_IF);
+    assert_eq!(findings.len(), 1, "{findings:?}");
+    let f = &findings[0];
+    assert_eq!(
+        (f.severity, f.file.as_str(), f.line, f.rule.as_str()),
+        (Severity::Error, "kernel/irq.c", 6, "style-format")
+    );
+    assert_eq!(
+        f.message,
+        "not formatted as .clang-format asks: `if (irq_count > 100)`"
+    );
+    assert_eq!(
+        f.fix.as_ref().unwrap().patch,
+        "--- a/kernel/irq.c\n+++ b/kernel/irq.c\n@@ -3,6 +3,6 @@\n void irq_tick(void)\n {\n \tirq_count++;\n-\tif(irq_count > 100)\n+\tif (irq_count > 100)\n \t\tirq_count = 0;\n }\n"
+    );
+    let args = std::fs::read_to_string(dir.join("format-args")).unwrap();
+    assert!(args.contains("--style=file\n"), "{args}");
+    assert!(args.contains("--lines=6:7\n"), "{args}");
+
+    // The fix applies on top of the diff and leaves nothing to report.
+    let fix = checks.style.as_ref().unwrap().patch.clone();
+    let tree = checks.tree.as_mut().unwrap();
+    let results = tree.apply(&patch::parse(&fix).unwrap(), 0);
+    assert!(results.iter().all(|r| r.clean()));
+    assert_eq!(
+        tree.file("kernel/irq.c").unwrap()[5],
+        "\tif (irq_count > 100)"
+    );
+    drop(checks);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn workspaces_without_a_clang_format_are_not_checked() {
+    let (dir, config) = workspace("no-style", false);
+    assert!(StyleCheck::new(&dir, config).unwrap().is_none());
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -1,6 +1,7 @@
 //! Integration tests for the validation rules.
 
-use diff_validator::{has_errors, validate, Severity};
+use diff_validator::rules::Rules;
+use diff_validator::{has_errors, validate, validate_with, Finding, Severity};
 
 #[test]
 fn hosted_header_is_an_error() {
@@ -33,3 +34,109 @@ fn multiple_findings_on_one_line() {
     assert!(findings.iter().any(|f| f.rule == "todo-marker"));
     assert!(findings.iter().any(|f| f.rule == "trailing-whitespace"));
 }
+
+fn added(path: &str, lines: &[&str]) -> String {
+    let mut diff = format!("+++ b/{path}\n@@ -0,0 +1,{} @@\n", lines.len());
+    for line in lines {
+        diff.push('+');
+        diff.push_str(line);
+        diff.push('\n');
+    }
+    diff
+}
+
+fn rules_of(findings: &[Finding]) -> Vec<(&str, usize)> {
+    findings.iter().map(|f| (f.rule.as_str(), f.line)).collect()
+}
+
+#[test]
+fn kernel_c_anti_patterns_are_found_on_their_lines() {
+    let diff = added(
+        "kernel/dev/vga.c",
+        &[
+            "#define LINE 160",
+            "static uint16_t *mmio;",
+            "void vga_print(const char *s)",
+            "{",
+            "\tchar line[LINE * 25];",
+            "\tstrcpy(line, s); /* sprintf(line) */",
+            "\tuint8_t *fb = (uint8_t *)0xB8000;",
+            "\tdouble scale = 0.5;",
+            "\t__asm__ volatile(\"cli\");",
+            "}",
+        ],
+    );
+    let findings = validate(&diff);
+    assert_eq!(
+        rules_of(&findings),
+        [
+            ("mmio-without-volatile", 2),
+            ("large-stack-allocation", 5),
+            ("banned-function", 6),
+            ("mmio-without-volatile", 7),
+            ("float-in-kernel", 8),
+            ("cli-without-sti", 9),
+        ]
+    );
+    assert!(findings[1].message.contains("4000 bytes"));
+    assert!(has_errors(&findings));
+}
+
+#[test]
+fn kernel_idioms_are_clean() {
+    let diff = added(
+        "kernel/net/nic.c",
+        &[
+            "static volatile uint32_t *mmio;",
+            "struct ring { uint8_t bytes[4096]; };",
+            "static char pool[65536];",
+            "void nic_init(uintptr_t bar0)",
+            "{",
+            "\tchar name[16];",
+            "\tmmio = (volatile uint32_t *)(uintptr_t)bar0;",
+            "\tsnprintf(name, sizeof(name), \"%s\", \"strcpy(x)\");",
+            "\t__asm__ volatile(\"cli\");",
+            "\tring_reset();",
+            "\t__asm__ volatile(\"sti\");",
+            "\tfor (;;) __asm__ volatile(\"cli; hlt\");",
+            "}",
+        ],
+    );
+    assert_eq!(validate(&diff), []);
+    // Float is fine where the rules allow it, and in non-C files.
+    assert_eq!(validate(&added("kernel/slm/x.c", &["float w = 1.0f;"])), []);
+    assert_eq!(validate(&added("docs/notes.md", &["sprintf(1.5)"])), []);
+}
+
+#[test]
+fn rules_file_disables_and_reclassifies() {
+    let path =
+        std::env::temp_dir().join(format!("diff-validator-{}-rules.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        "disable = [\"todo-marker\"]\nmax-stack-bytes = 8\n\n\
+         [severity]\nlarge-stack-allocation = \"error\"\n\n\
+         [banned-functions]\nstrtok = \"not reentrant\"\n",
+    )
+    .unwrap();
+    let rules = Rules::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let diff = added(
+        "x.c",
+        &[
+            "void f(void) {",
+            "\tint v[4]; // TODO",
+            "\tstrtok(s, \",\");",
+        ],
+    );
+    let findings = validate_with(&diff, &rules);
+    assert_eq!(
+        rules_of(&findings),
+        [("large-stack-allocation", 2), ("banned-function", 3)]
+    );
+    assert_eq!(findings[0].severity, Severity::Error);
+    assert!(findings[1].message.ends_with("not reentrant"));
+    // Only the listed functions are banned once the table is given.
+    assert!(!rules.banned_functions.contains_key("sprintf"));
+}
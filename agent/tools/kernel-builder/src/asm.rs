@@ -0,0 +1,364 @@
+//! Assembly stage: discover boot sources and assemble them into objects.
+//!
+//! `.asm` files are NASM syntax and go through `nasm` (x86_64 only); `.S`
+//! files are GAS syntax (as in the seed kernel) and go through the C compiler
+//! driver, which runs the preprocessor first; plain `.s` files go straight to
+//! the arch's GNU `as`. The output format of each file comes from an
+//! optional `asm.json` manifest in its directory:
+//!
+//! ```json
+//! { "format": "elf64", "files": { "stage2.asm": "bin" } }
+//! ```
+//!
+//! `elf64` objects feed the linker; `bin` outputs are flat images (e.g. a
+//! stage2 loader) and are staged next to the objects but never linked.
+
+use crate::cache::{BuildCache, CacheOutcome};
+use crate::deps;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Per-directory manifest file name.
+pub const MANIFEST_NAME: &str = "asm.json";
+
+/// Flags the Makefile passes when assembling `.S` through the C driver.
+const CPP_ASFLAGS: &[&str] = &["-ffreestanding", "-fno-pic", "-fno-pie"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Elf64,
+    Bin,
+}
+
+impl OutputFormat {
+    fn nasm_flag(self) -> &'static str {
+        match self {
+            OutputFormat::Elf64 => "elf64",
+            OutputFormat::Bin => "bin",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Elf64 => "o",
+            OutputFormat::Bin => "bin",
+        }
+    }
+}
+
+/// Contents of an `asm.json` manifest.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AsmManifest {
+    /// Default format for every source in the directory.
+    #[serde(default)]
+    pub format: Option<OutputFormat>,
+    /// Per-file overrides, keyed by file name.
+    #[serde(default)]
+    pub files: BTreeMap<String, OutputFormat>,
+}
+
+impl AsmManifest {
+    pub fn format_for(&self, file_name: &str) -> OutputFormat {
+        self.files
+            .get(file_name)
+            .copied()
+            .or(self.format)
+            .unwrap_or(OutputFormat::Elf64)
+    }
+}
+
+/// Programs the assembly stage may invoke for one architecture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmTools {
+    /// C compiler driver, for preprocessed `.S`.
+    pub cc: String,
+    /// GNU assembler, for `.s`.
+    pub gas: String,
+    /// Whether NASM `.asm` sources are valid for this arch.
+    pub nasm: bool,
+}
+
+/// One assembler invocation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AsmJob {
+    pub source: PathBuf,
+    pub output: PathBuf,
+    pub format: OutputFormat,
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl AsmJob {
+    /// Whether the output is a relocatable object for the link stage.
+    pub fn is_link_input(&self) -> bool {
+        self.format == OutputFormat::Elf64
+    }
+}
+
+/// `nasm` argument vector: `-f <fmt> -I <dir>/ -o <out> <src>`.
+pub fn nasm_args(source: &Path, output: &Path, format: OutputFormat) -> Vec<String> {
+    let include = match source.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => format!("{}/", dir.display()),
+        _ => "./".to_string(),
+    };
+    vec![
+        "-f".to_string(),
+        format.nasm_flag().to_string(),
+        "-I".to_string(),
+        include,
+        "-o".to_string(),
+        output.display().to_string(),
+        source.display().to_string(),
+    ]
+}
+
+/// GNU `as` argument vector for a `.s` file: `-I <dir> -o <out> <src>`.
+pub fn gas_args(source: &Path, output: &Path) -> Vec<String> {
+    let include = match source.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.display().to_string(),
+        _ => ".".to_string(),
+    };
+    vec![
+        "-I".to_string(),
+        include,
+        "-o".to_string(),
+        output.display().to_string(),
+        source.display().to_string(),
+    ]
+}
+
+/// C-driver argument vector for a GAS `.S` file:
+/// `<ASFLAGS> -c <src> -o <out> -MMD -MF <depfile>`.
+pub fn cpp_asm_args(source: &Path, output: &Path) -> Vec<String> {
+    let mut args: Vec<String> = CPP_ASFLAGS.iter().map(|s| s.to_string()).collect();
+    args.push("-c".to_string());
+    args.push(source.display().to_string());
+    args.push("-o".to_string());
+    args.push(output.display().to_string());
+    args.extend(deps::depfile_args(output));
+    args
+}
+
+/// Recursively collect `.asm`/`.S`/`.s` files under `dir`, sorted for stable output.
+pub fn discover_sources(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    walk(dir, &mut found).with_context(|| format!("scanning {}", dir.display()))?;
+    found.sort();
+    Ok(found)
+}
+
+fn walk(dir: &Path, found: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(&path, found)?;
+        } else if matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("asm") | Some("S") | Some("s")
+        ) {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Load the manifest for `dir`, or the default if none exists.
+pub fn load_manifest(dir: &Path) -> Result<AsmManifest> {
+    let path = dir.join(MANIFEST_NAME);
+    if !path.exists() {
+        return Ok(AsmManifest::default());
+    }
+    let text =
+        std::fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+    serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+}
+
+/// Plan every assembler invocation for the sources under `workspace/boot_dir`.
+///
+/// Outputs mirror the source layout under `obj_dir` (`boot/x.asm` →
+/// `obj_dir/boot/x.asm.o`), matching the Makefile's object naming.
+pub fn plan(
+    workspace: &Path,
+    boot_dir: &Path,
+    obj_dir: &Path,
+    tools: &AsmTools,
+) -> Result<Vec<AsmJob>> {
+    let root = workspace.join(boot_dir);
+    if !root.is_dir() {
+        bail!(
+            "boot directory {} does not exist (set --boot-dir relative to the workspace)",
+            root.display()
+        );
+    }
+
+    let mut manifests: BTreeMap<PathBuf, AsmManifest> = BTreeMap::new();
+    let mut jobs = Vec::new();
+    for source in discover_sources(&root)? {
+        let dir = source.parent().unwrap_or(&root).to_path_buf();
+        if !manifests.contains_key(&dir) {
+            manifests.insert(dir.clone(), load_manifest(&dir)?);
+        }
+        let file_name = source
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        let format = manifests[&dir].format_for(file_name);
+
+        let rel = source.strip_prefix(workspace).unwrap_or(&source);
+        let output = obj_dir.join(format!("{}.{}", rel.display(), format.extension()));
+
+        let ext = source
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default();
+        let (program, args) = match ext {
+            "asm" if !tools.nasm => bail!(
+                "{}: NASM sources are only supported on x86_64; use GAS (.S/.s)",
+                source.display()
+            ),
+            "asm" => ("nasm".to_string(), nasm_args(&source, &output, format)),
+            _ if format == OutputFormat::Bin => bail!(
+                "{}: flat `bin` output requires a NASM (.asm) source",
+                source.display()
+            ),
+            "s" => (tools.gas.clone(), gas_args(&source, &output)),
+            _ => (tools.cc.clone(), cpp_asm_args(&source, &output)),
+        };
+
+        jobs.push(AsmJob {
+            source,
+            output,
+            format,
+            program,
+            args,
+        });
+    }
+    Ok(jobs)
+}
+
+/// Run one assembler job (or restore it from `cache`). On failure the
+/// assembler's stderr becomes the root cause of the returned error.
+pub async fn assemble(job: &AsmJob, cache: &BuildCache) -> Result<CacheOutcome> {
+    let what = format!("assembling {}", job.source.display());
+    cache
+        .run(&job.source, &job.output, &job.program, &job.args, &what)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn scratch_dir() -> PathBuf {
+        static N: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "kb-asm-{}-{}",
+            std::process::id(),
+            N.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn x86_tools() -> AsmTools {
+        AsmTools {
+            cc: "gcc".into(),
+            gas: "as".into(),
+            nasm: true,
+        }
+    }
+
+    #[test]
+    fn nasm_args_select_format_and_include_dir() {
+        let args = nasm_args(
+            Path::new("ws/boot/entry.asm"),
+            Path::new("build/obj/boot/entry.asm.o"),
+            OutputFormat::Elf64,
+        );
+        assert_eq!(
+            args,
+            vec![
+                "-f",
+                "elf64",
+                "-I",
+                "ws/boot/",
+                "-o",
+                "build/obj/boot/entry.asm.o",
+                "ws/boot/entry.asm"
+            ]
+        );
+    }
+
+    #[test]
+    fn manifest_file_override_beats_directory_default() {
+        let m: AsmManifest =
+            serde_json::from_str(r#"{"format": "elf64", "files": {"stage2.asm": "bin"}}"#).unwrap();
+        assert_eq!(m.format_for("stage2.asm"), OutputFormat::Bin);
+        assert_eq!(m.format_for("entry.asm"), OutputFormat::Elf64);
+        assert_eq!(
+            AsmManifest::default().format_for("x.asm"),
+            OutputFormat::Elf64
+        );
+    }
+
+    #[test]
+    fn plan_routes_by_extension_and_manifest() {
+        let ws = scratch_dir();
+        std::fs::create_dir_all(ws.join("boot/stage2")).unwrap();
+        std::fs::write(ws.join("boot/boot.S"), "").unwrap();
+        std::fs::write(ws.join("boot/stage2/loader.asm"), "").unwrap();
+        std::fs::write(ws.join("boot/notes.txt"), "").unwrap();
+        std::fs::write(ws.join("boot/stage2/asm.json"), r#"{"format": "bin"}"#).unwrap();
+
+        let jobs = plan(&ws, Path::new("boot"), Path::new("out"), &x86_tools()).unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].program, "gcc");
+        assert!(jobs[0].is_link_input());
+        assert_eq!(jobs[0].output, Path::new("out/boot/boot.S.o"));
+        assert_eq!(jobs[1].program, "nasm");
+        assert_eq!(jobs[1].format, OutputFormat::Bin);
+        assert_eq!(jobs[1].output, Path::new("out/boot/stage2/loader.asm.bin"));
+        std::fs::remove_dir_all(&ws).unwrap();
+    }
+
+    #[test]
+    fn bin_format_on_gas_source_is_rejected() {
+        let ws = scratch_dir();
+        std::fs::create_dir_all(ws.join("boot")).unwrap();
+        std::fs::write(ws.join("boot/boot.S"), "").unwrap();
+        std::fs::write(ws.join("boot/asm.json"), r#"{"format": "bin"}"#).unwrap();
+        assert!(plan(&ws, Path::new("boot"), Path::new("out"), &x86_tools()).is_err());
+        std::fs::remove_dir_all(&ws).unwrap();
+    }
+
+    #[test]
+    fn gas_arches_reject_nasm_and_route_dot_s_to_as() {
+        let ws = scratch_dir();
+        std::fs::create_dir_all(ws.join("boot")).unwrap();
+        std::fs::write(ws.join("boot/start.s"), "").unwrap();
+        let tools = AsmTools {
+            cc: "aarch64-elf-gcc".into(),
+            gas: "aarch64-elf-as".into(),
+            nasm: false,
+        };
+        let jobs = plan(&ws, Path::new("boot"), Path::new("out"), &tools).unwrap();
+        assert_eq!(jobs[0].program, "aarch64-elf-as");
+
+        std::fs::write(ws.join("boot/legacy.asm"), "").unwrap();
+        let err = plan(&ws, Path::new("boot"), Path::new("out"), &tools).unwrap_err();
+        assert!(err.to_string().contains("only supported on x86_64"));
+        std::fs::remove_dir_all(&ws).unwrap();
+    }
+
+    #[test]
+    fn missing_boot_dir_is_an_error() {
+        let ws = scratch_dir();
+        let err = plan(&ws, Path::new("boot"), Path::new("out"), &x86_tools()).unwrap_err();
+        assert!(err.to_string().contains("--boot-dir"));
+        std::fs::remove_dir_all(&ws).unwrap();
+    }
+}
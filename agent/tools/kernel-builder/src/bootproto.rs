@@ -0,0 +1,444 @@
+//! Boot protocol header verification for the linked kernel.
+//!
+//! A kernel that links fine but carries a broken Multiboot2 header (bad
+//! checksum, unterminated tag list, header pushed past 32 KiB by a linker
+//! script edit) or malformed Limine requests only fails once QEMU sits at a
+//! bootloader error screen, which test-runner reports as a timeout. This
+//! stage reads the image back after linking and names the actual defect.
+//!
+//! * Multiboot2: the header must start 8-byte aligned within the first
+//!   32 KiB of the file, have a zero checksum, an i386 architecture field on
+//!   x86_64, and a tag list of 8-byte aligned tags that ends with the
+//!   `type 0, size 8` end tag exactly at `header_length`.
+//! * Limine: requests are found by their common ID magic. A base revision
+//!   tag must be present, and when the requests start/end markers are used
+//!   every request must sit between them (Limine only scans that range).
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::path::Path;
+
+pub use crate::image::{MULTIBOOT2_MAGIC, MULTIBOOT2_SEARCH};
+
+/// `architecture` field for 32-bit protected-mode i386 entry.
+pub const MULTIBOOT2_ARCH_I386: u32 = 0;
+/// Highest tag type defined by the Multiboot2 spec (relocatable header).
+const MULTIBOOT2_MAX_TAG: u16 = 10;
+
+pub const LIMINE_COMMON_MAGIC: [u64; 2] = [0xc7b1_dd30_df4c_8b88, 0x0a82_e883_a194_f07b];
+pub const LIMINE_BASE_REVISION: [u64; 2] = [0xf956_2b2d_5c95_a6c8, 0x6a7b_3849_4453_6bdc];
+pub const LIMINE_REQUESTS_START: [u64; 4] = [
+    0xf6b8_f4b3_9de7_d1ae,
+    0xfab9_1a69_40fc_b9cf,
+    0x785c_6ed0_15d3_e316,
+    0x181e_920a_7852_b9d9,
+];
+pub const LIMINE_REQUESTS_END: [u64; 2] = [0xadc0_e053_1bb1_0d03, 0x9572_709f_3176_4c62];
+
+/// Which header the kernel must carry (`--boot-protocol`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum BootProtocol {
+    /// Verify whichever header is present; x86_64 kernels must have one.
+    #[default]
+    Auto,
+    Multiboot2,
+    Limine,
+    /// Skip verification (e.g. QEMU `-kernel` loading on aarch64/riscv64).
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Multiboot2Header {
+    /// File offset of the header.
+    pub offset: usize,
+    pub architecture: u32,
+    pub length: u32,
+    /// `(type, size)` of every tag before the end tag.
+    pub tags: Vec<(u16, u32)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LimineRequests {
+    /// Requested base revision.
+    pub base_revision: u64,
+    /// Requests other than the base revision tag.
+    pub requests: usize,
+    /// Whether start/end markers delimit the requests.
+    pub markers: bool,
+}
+
+/// What [`verify`] found.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase", tag = "protocol")]
+pub enum Verified {
+    Multiboot2(Multiboot2Header),
+    Limine(LimineRequests),
+}
+
+fn u16_at(bytes: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(off..off + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(bytes: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(off..off + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u64_at(bytes: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(off..off + 8)?.try_into().ok()?,
+    ))
+}
+
+fn words_at(bytes: &[u8], off: usize, words: &[u64]) -> bool {
+    words
+        .iter()
+        .enumerate()
+        .all(|(i, w)| u64_at(bytes, off + i * 8) == Some(*w))
+}
+
+/// Offsets of the Multiboot2 magic anywhere in `bytes`, aligned or not.
+fn multiboot2_magic_offsets(bytes: &[u8]) -> Vec<usize> {
+    let magic = MULTIBOOT2_MAGIC.to_le_bytes();
+    bytes
+        .windows(4)
+        .enumerate()
+        .filter(|(_, w)| *w == magic)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Parse and validate the header at `offset`.
+pub fn parse_multiboot2(bytes: &[u8], offset: usize) -> Result<Multiboot2Header> {
+    let word = |i: usize| u32_at(bytes, offset + i);
+    let (Some(magic), Some(architecture), Some(length), Some(checksum)) =
+        (word(0), word(4), word(8), word(12))
+    else {
+        bail!("Multiboot2 header at {offset:#x} is truncated");
+    };
+    let sum = magic
+        .wrapping_add(architecture)
+        .wrapping_add(length)
+        .wrapping_add(checksum);
+    if sum != 0 {
+        bail!(
+            "Multiboot2 header at {offset:#x}: checksum {checksum:#010x} is wrong (expected {:#010x})",
+            0u32.wrapping_sub(magic.wrapping_add(architecture).wrapping_add(length))
+        );
+    }
+    if (length as usize) < 16 + 8 {
+        bail!("Multiboot2 header at {offset:#x}: header_length {length} leaves no room for the end tag");
+    }
+    if offset + length as usize > bytes.len() {
+        bail!("Multiboot2 header at {offset:#x}: header_length {length} runs past the end of the file");
+    }
+
+    let end = offset + length as usize;
+    let mut tags = Vec::new();
+    let mut pos = offset + 16;
+    loop {
+        if pos + 8 > end {
+            bail!(
+                "Multiboot2 header at {offset:#x}: tag list is not terminated by an end tag \
+                 (type 0, size 8) within header_length {length}"
+            );
+        }
+        let (Some(ty), Some(size)) = (u16_at(bytes, pos), u32_at(bytes, pos + 4)) else {
+            bail!("Multiboot2 header at {offset:#x}: truncated tag at {pos:#x}");
+        };
+        if ty == 0 {
+            if size != 8 {
+                bail!("Multiboot2 header at {offset:#x}: end tag at {pos:#x} has size {size}, expected 8");
+            }
+            if pos + 8 != end {
+                bail!(
+                    "Multiboot2 header at {offset:#x}: end tag at {pos:#x} does not end at header_length \
+                     ({} trailing bytes)",
+                    end - pos - 8
+                );
+            }
+            return Ok(Multiboot2Header {
+                offset,
+                architecture,
+                length,
+                tags,
+            });
+        }
+        if ty > MULTIBOOT2_MAX_TAG {
+            bail!("Multiboot2 header at {offset:#x}: unknown tag type {ty} at {pos:#x}");
+        }
+        if size < 8 || pos + size as usize > end {
+            bail!(
+                "Multiboot2 header at {offset:#x}: tag type {ty} at {pos:#x} has bad size {size}"
+            );
+        }
+        tags.push((ty, size));
+        // Tags are padded to 8-byte alignment.
+        pos += (size as usize).div_ceil(8) * 8;
+    }
+}
+
+/// Find and validate the Multiboot2 header, explaining near misses (header
+/// past 32 KiB, misaligned, bad checksum) specifically.
+pub fn check_multiboot2(bytes: &[u8], arch: &str) -> Result<Multiboot2Header> {
+    let offsets = multiboot2_magic_offsets(bytes);
+    let in_window = |o: &usize| *o + 16 <= MULTIBOOT2_SEARCH;
+    let candidates: Vec<usize> = offsets
+        .iter()
+        .copied()
+        .filter(|o| o % 8 == 0 && in_window(o))
+        .collect();
+    let mut first_err = None;
+    for &off in &candidates {
+        match parse_multiboot2(bytes, off) {
+            Ok(header) => {
+                if arch == "x86_64" && header.architecture != MULTIBOOT2_ARCH_I386 {
+                    bail!(
+                        "Multiboot2 header at {off:#x}: architecture {} is not i386 (0)",
+                        header.architecture
+                    );
+                }
+                return Ok(header);
+            }
+            // The magic value can also occur in code; keep the first
+            // header-shaped failure for the message.
+            Err(e) => {
+                first_err.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(e) = first_err {
+        return Err(e);
+    }
+    if let Some(off) = offsets.iter().find(|o| in_window(o)) {
+        bail!(
+            "Multiboot2 magic at {off:#x} is not 8-byte aligned (add `.align 8` before the header)"
+        );
+    }
+    if let Some(off) = offsets.first() {
+        bail!(
+            "Multiboot2 magic at {off:#x} is past the first 32 KiB of the file \
+             (place the .multiboot section first in the linker script)"
+        );
+    }
+    bail!("no Multiboot2 header (magic {MULTIBOOT2_MAGIC:#010x}) in the first 32 KiB")
+}
+
+/// Find Limine requests; `None` if the image has none.
+pub fn check_limine(bytes: &[u8]) -> Result<Option<LimineRequests>> {
+    let aligned = (0..bytes.len().saturating_sub(15)).step_by(8);
+    let mut base_revision = None;
+    let mut requests = Vec::new();
+    let mut start = None;
+    let mut end = None;
+    for off in aligned {
+        if words_at(bytes, off, &LIMINE_BASE_REVISION) {
+            base_revision = u64_at(bytes, off + 16).map(|rev| (off, rev));
+        } else if words_at(bytes, off, &LIMINE_COMMON_MAGIC) {
+            requests.push(off);
+        } else if words_at(bytes, off, &LIMINE_REQUESTS_START) {
+            start = start.or(Some(off));
+        } else if words_at(bytes, off, &LIMINE_REQUESTS_END) {
+            end = Some(off);
+        }
+    }
+    if base_revision.is_none() && requests.is_empty() && start.is_none() {
+        return Ok(None);
+    }
+    let markers = match (start, end) {
+        (Some(s), Some(e)) if s < e => {
+            let outside: Vec<usize> = requests
+                .iter()
+                .chain(base_revision.iter().map(|(o, _)| o))
+                .copied()
+                .filter(|o| *o < s || *o > e)
+                .collect();
+            if let Some(o) = outside.first() {
+                bail!(
+                    "Limine request at {o:#x} is outside the requests markers ({s:#x}..{e:#x}); \
+                     Limine will not see it"
+                );
+            }
+            true
+        }
+        (Some(s), Some(e)) => {
+            bail!("Limine requests end marker at {e:#x} precedes the start marker at {s:#x}")
+        }
+        (Some(s), None) => bail!("Limine requests start marker at {s:#x} has no end marker"),
+        (None, Some(e)) => bail!("Limine requests end marker at {e:#x} has no start marker"),
+        (None, None) => false,
+    };
+    let Some((_, revision)) = base_revision else {
+        bail!("Limine requests found but no LIMINE_BASE_REVISION tag");
+    };
+    Ok(Some(LimineRequests {
+        base_revision: revision,
+        requests: requests.len(),
+        markers,
+    }))
+}
+
+/// Verify `bytes` against `expected`. `Ok(None)` means nothing was checked
+/// (`none`, or `auto` on a non-x86_64 kernel without any header).
+pub fn verify(bytes: &[u8], arch: &str, expected: BootProtocol) -> Result<Option<Verified>> {
+    match expected {
+        BootProtocol::None => Ok(None),
+        BootProtocol::Multiboot2 => Ok(Some(Verified::Multiboot2(check_multiboot2(bytes, arch)?))),
+        BootProtocol::Limine => match check_limine(bytes)? {
+            Some(l) => Ok(Some(Verified::Limine(l))),
+            None => bail!("no Limine requests (base revision tag or request IDs) in the image"),
+        },
+        BootProtocol::Auto => {
+            if !multiboot2_magic_offsets(bytes).is_empty() {
+                return Ok(Some(Verified::Multiboot2(check_multiboot2(bytes, arch)?)));
+            }
+            if let Some(l) = check_limine(bytes)? {
+                return Ok(Some(Verified::Limine(l)));
+            }
+            if arch == "x86_64" {
+                bail!("no boot protocol header: neither a Multiboot2 header nor Limine requests");
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// [`verify`] on a file, with the file named in the error.
+pub fn verify_file(path: &Path, arch: &str, expected: BootProtocol) -> Result<Option<Verified>> {
+    let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    verify(&bytes, arch, expected).with_context(|| format!("{}: unbootable", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mb2(tags: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (ty, payload) in tags {
+            let size = 8 + payload.len() as u32;
+            body.extend(ty.to_le_bytes());
+            body.extend(0u16.to_le_bytes());
+            body.extend(size.to_le_bytes());
+            body.extend(*payload);
+            while body.len() % 8 != 0 {
+                body.push(0);
+            }
+        }
+        body.extend([0, 0, 0, 0, 8, 0, 0, 0]);
+        let len = 16 + body.len() as u32;
+        let mut h = Vec::new();
+        for w in [
+            MULTIBOOT2_MAGIC,
+            0,
+            len,
+            0u32.wrapping_sub(MULTIBOOT2_MAGIC + len),
+        ] {
+            h.extend(w.to_le_bytes());
+        }
+        h.extend(body);
+        h
+    }
+
+    fn image_with(header: &[u8], at: usize) -> Vec<u8> {
+        let mut img = vec![0u8; at];
+        img.extend(header);
+        img.resize(img.len() + 64, 0);
+        img
+    }
+
+    fn err(bytes: &[u8]) -> String {
+        format!("{:#}", check_multiboot2(bytes, "x86_64").unwrap_err())
+    }
+
+    #[test]
+    fn accepts_well_formed_multiboot2_with_tags() {
+        // Information request tag (type 1) with one u32 and padding.
+        let img = image_with(&mb2(&[(1, &6u32.to_le_bytes())]), 0x1000);
+        let h = check_multiboot2(&img, "x86_64").unwrap();
+        assert_eq!((h.offset, h.tags.as_slice()), (0x1000, &[(1, 12)][..]));
+    }
+
+    #[test]
+    fn explains_multiboot2_defects() {
+        let good = mb2(&[]);
+        let mut bad_sum = image_with(&good, 0x1000);
+        bad_sum[0x1000 + 12] ^= 1;
+        assert!(err(&bad_sum).contains("checksum"));
+
+        assert!(err(&image_with(&good, 0x1004)).contains("not 8-byte aligned"));
+        assert!(err(&image_with(&good, MULTIBOOT2_SEARCH + 8)).contains("past the first 32 KiB"));
+
+        // End tag replaced by an unknown tag type.
+        let mut unterminated = image_with(&good, 0);
+        unterminated[16] = 0x40;
+        assert!(err(&unterminated).contains("unknown tag type 64"));
+
+        let mut short_end = image_with(&good, 0);
+        short_end[20] = 16;
+        assert!(err(&short_end).contains("has size 16"));
+
+        assert!(err(&[0u8; 64]).contains("no Multiboot2 header"));
+    }
+
+    fn limine(words: &[u64]) -> Vec<u8> {
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn checks_limine_requests_and_markers() {
+        let base = [LIMINE_BASE_REVISION[0], LIMINE_BASE_REVISION[1], 3];
+        let fb = [
+            LIMINE_COMMON_MAGIC[0],
+            LIMINE_COMMON_MAGIC[1],
+            0x9d58,
+            0x4ab0,
+            0,
+            0,
+        ];
+        let mut words = Vec::new();
+        words.extend(LIMINE_REQUESTS_START);
+        words.extend(base);
+        words.extend(fb);
+        words.extend(LIMINE_REQUESTS_END);
+        let found = check_limine(&limine(&words)).unwrap().unwrap();
+        assert_eq!(
+            found,
+            LimineRequests {
+                base_revision: 3,
+                requests: 1,
+                markers: true
+            }
+        );
+
+        let mut outside = words.clone();
+        outside.extend(fb);
+        assert!(check_limine(&limine(&outside)).is_err());
+        assert!(check_limine(&limine(&fb)).is_err()); // no base revision
+        assert_eq!(check_limine(&[0u8; 64]).unwrap(), None);
+    }
+
+    #[test]
+    fn auto_requires_a_header_only_on_x86_64() {
+        assert!(verify(&[0u8; 64], "x86_64", BootProtocol::Auto).is_err());
+        assert_eq!(
+            verify(&[0u8; 64], "aarch64", BootProtocol::Auto).unwrap(),
+            None
+        );
+        assert_eq!(
+            verify(&[0u8; 64], "x86_64", BootProtocol::None).unwrap(),
+            None
+        );
+        let img = image_with(&mb2(&[]), 0x100);
+        assert!(matches!(
+            verify(&img, "x86_64", BootProtocol::Auto).unwrap(),
+            Some(Verified::Multiboot2(_))
+        ));
+        assert!(verify(&img, "x86_64", BootProtocol::Limine).is_err());
+    }
+}
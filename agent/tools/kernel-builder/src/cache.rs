@@ -0,0 +1,344 @@
+//! Content-addressed object cache under `<output>/.cache/`, or a directory
+//! of its own (`--cache-dir`) that several builds share.
+//!
+//! A translation unit's key is the SHA-256 of its source bytes plus the exact
+//! tool and argument vector used to build it, so any flag change is a miss.
+//! Jobs that write a depfile (`-MF <path>`) also store it with the entry,
+//! together with a digest of every header it lists; an entry is only a hit
+//! while those headers are unchanged. Jobs compiled with `-fstack-usage`
+//! store the `.su` frame report written beside the object the same way.
+//! Entries are written under a name no other writer uses and renamed into
+//! place, so builds sharing a cache, in any number of processes, never see
+//! one half written.
+
+use crate::deps;
+use crate::exec::run_tool;
+use crate::hash::{hex, Sha256};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const CACHE_DIR: &str = ".cache";
+
+/// What happened to one translation unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheOutcome {
+    /// Restored from the cache; the tool did not run.
+    Hit,
+    /// Not cached; built and stored.
+    Miss,
+    /// Built with caching disabled.
+    Uncached,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub rebuilt: usize,
+}
+
+impl CacheStats {
+    pub fn record(&mut self, outcome: CacheOutcome) {
+        match outcome {
+            CacheOutcome::Hit => self.hits += 1,
+            CacheOutcome::Miss => {
+                self.misses += 1;
+                self.rebuilt += 1;
+            }
+            CacheOutcome::Uncached => self.rebuilt += 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BuildCache {
+    dir: PathBuf,
+    enabled: bool,
+}
+
+impl BuildCache {
+    /// Cache rooted at `<output>/.cache`; `enabled = false` is `--no-cache`.
+    pub fn new(output: &Path, enabled: bool) -> Self {
+        Self::at(output.join(CACHE_DIR), enabled)
+    }
+
+    /// Cache rooted at `dir`.
+    pub fn at(dir: PathBuf, enabled: bool) -> Self {
+        Self { dir, enabled }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Cache key for building `source_bytes` with `program args…`.
+    pub fn key(source_bytes: &[u8], program: &str, args: &[String]) -> String {
+        let mut h = Sha256::new();
+        h.update(source_bytes);
+        h.update(b"\0");
+        h.update(program.as_bytes());
+        for a in args {
+            h.update(b"\0");
+            h.update(a.as_bytes());
+        }
+        hex(&h.finish())
+    }
+
+    fn entry(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[..2]).join(format!("{key}.o"))
+    }
+
+    /// Whether [`BuildCache::run`] would restore its output rather than run
+    /// the tool (`--dry-run`); `None` with caching disabled. Writes nothing.
+    pub fn would_hit(&self, source: &Path, program: &str, args: &[String]) -> Result<Option<bool>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let bytes =
+            std::fs::read(source).with_context(|| format!("reading {}", source.display()))?;
+        let entry = self.entry(&Self::key(&bytes, program, args));
+        let depfile = depfile_arg(args);
+        Ok(Some(is_hit(
+            &entry,
+            depfile.as_deref(),
+            stack_usage_arg(args),
+        )))
+    }
+
+    /// Build `output` from `source`, or restore it from the cache.
+    pub async fn run(
+        &self,
+        source: &Path,
+        output: &Path,
+        program: &str,
+        args: &[String],
+        what: &str,
+    ) -> Result<CacheOutcome> {
+        if let Some(dir) = output.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        if !self.enabled {
+            run_tool(program, args, what).await?;
+            return Ok(CacheOutcome::Uncached);
+        }
+
+        let bytes =
+            std::fs::read(source).with_context(|| format!("reading {}", source.display()))?;
+        let key = Self::key(&bytes, program, args);
+        let entry = self.entry(&key);
+        let depfile = depfile_arg(args);
+        let su = stack_usage_arg(args).then(|| output.with_extension("su"));
+        if is_hit(&entry, depfile.as_deref(), su.is_some()) {
+            touch(&entry);
+            std::fs::copy(&entry, output)
+                .with_context(|| format!("restoring {} from cache", output.display()))?;
+            if let Some(d) = &depfile {
+                std::fs::copy(entry.with_extension("d"), d)
+                    .with_context(|| format!("restoring {} from cache", d.display()))?;
+            }
+            if let Some(su) = &su {
+                std::fs::copy(entry.with_extension("su"), su)
+                    .with_context(|| format!("restoring {} from cache", su.display()))?;
+            }
+            tracing::debug!(source = %source.display(), key = %key, "cache hit");
+            return Ok(CacheOutcome::Hit);
+        }
+
+        run_tool(program, args, what).await?;
+        self.store(&entry, output)?;
+        if let Some(su) = su.filter(|s| s.is_file()) {
+            self.store(&entry.with_extension("su"), &su)?;
+        }
+        if let Some(d) = &depfile {
+            let text =
+                std::fs::read_to_string(d).with_context(|| format!("reading {}", d.display()))?;
+            let prereqs = deps::parse_depfile(&text)
+                .map(|r| r.prerequisites)
+                .unwrap_or_default();
+            self.store(&entry.with_extension("d"), d)?;
+            write_atomic(
+                &entry.with_extension("deps"),
+                deps::digest(&prereqs).as_bytes(),
+            )?;
+        }
+        Ok(CacheOutcome::Miss)
+    }
+
+    /// Copy via a temp file + rename so a concurrent reader never sees a
+    /// partially written entry.
+    fn store(&self, entry: &Path, object: &Path) -> Result<()> {
+        let dir = entry.parent().expect("cache entries live in a shard dir");
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        let tmp = tmp_path(entry);
+        std::fs::copy(object, &tmp).with_context(|| format!("caching {}", object.display()))?;
+        std::fs::rename(&tmp, entry).with_context(|| format!("caching {}", object.display()))?;
+        Ok(())
+    }
+}
+
+/// Refresh an entry's mtime (and its depfile's) on a hit, so
+/// `clean --stage cache --older-than` only evicts entries nobody uses.
+fn touch(entry: &Path) {
+    let now = std::time::SystemTime::now();
+    for path in [
+        entry.to_path_buf(),
+        entry.with_extension("d"),
+        entry.with_extension("deps"),
+        entry.with_extension("su"),
+    ] {
+        if let Ok(f) = std::fs::File::options().append(true).open(&path) {
+            let _ = f.set_modified(now);
+        }
+    }
+}
+
+/// The `-MF <path>` depfile a job writes, if any.
+/// Whether `entry` is cached with the depfile and `.su` report its job
+/// needs, and the headers the depfile lists are unchanged.
+fn is_hit(entry: &Path, depfile: Option<&Path>, su: bool) -> bool {
+    entry.is_file() && deps_current(entry, depfile) && (!su || entry.with_extension("su").is_file())
+}
+
+/// The files a job writes beside `output`: its depfile and `.su` report.
+pub fn side_outputs(output: &Path, args: &[String]) -> Vec<PathBuf> {
+    let su = stack_usage_arg(args).then(|| output.with_extension("su"));
+    depfile_arg(args).into_iter().chain(su).collect()
+}
+
+fn depfile_arg(args: &[String]) -> Option<PathBuf> {
+    args.windows(2)
+        .find(|w| w[0] == "-MF")
+        .map(|w| PathBuf::from(&w[1]))
+}
+
+/// Whether the job writes a `.su` file beside its object.
+fn stack_usage_arg(args: &[String]) -> bool {
+    args.iter().any(|a| a == crate::stack::STACK_USAGE_FLAG)
+}
+
+/// Whether the headers recorded with `entry` still hash the same. Entries
+/// without a depfile only depend on their key.
+fn deps_current(entry: &Path, depfile: Option<&Path>) -> bool {
+    if depfile.is_none() {
+        return true;
+    }
+    let (Ok(text), Ok(stored)) = (
+        std::fs::read_to_string(entry.with_extension("d")),
+        std::fs::read_to_string(entry.with_extension("deps")),
+    ) else {
+        return false;
+    };
+    deps::parse_depfile(&text).is_some_and(|r| deps::digest(&r.prerequisites) == stored)
+}
+
+/// A name beside `path` that no other writer, in this process or another,
+/// is using.
+fn tmp_path(path: &Path) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.tmp{}-{n}", std::process::id()))
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = tmp_path(path);
+    std::fs::write(&tmp, bytes).with_context(|| format!("writing {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_changes_with_source_and_flags() {
+        let args = vec!["-O2".to_string(), "-c".to_string()];
+        let k = BuildCache::key(b"int x;", "gcc", &args);
+        assert_eq!(k.len(), 64);
+        assert_eq!(k, BuildCache::key(b"int x;", "gcc", &args));
+        assert_ne!(k, BuildCache::key(b"int y;", "gcc", &args));
+        assert_ne!(k, BuildCache::key(b"int x;", "gcc", &["-O0".to_string()]));
+        assert_ne!(k, BuildCache::key(b"int x;", "clang", &args));
+    }
+
+    #[test]
+    fn stats_count_hits_misses_and_rebuilds() {
+        let mut s = CacheStats::default();
+        for o in [
+            CacheOutcome::Hit,
+            CacheOutcome::Miss,
+            CacheOutcome::Miss,
+            CacheOutcome::Uncached,
+        ] {
+            s.record(o);
+        }
+        assert_eq!(
+            s,
+            CacheStats {
+                hits: 1,
+                misses: 2,
+                rebuilt: 3
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn second_run_is_a_hit() {
+        let root = std::env::temp_dir().join(format!("kb-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let src = root.join("a.c");
+        let obj = root.join("out/a.c.o");
+        std::fs::write(&src, "int a;").unwrap();
+        let cache = BuildCache::new(&root, true);
+        // `cp` stands in for a compiler: same inputs, deterministic output.
+        let args = vec![src.display().to_string(), obj.display().to_string()];
+
+        assert_eq!(cache.would_hit(&src, "cp", &args).unwrap(), Some(false));
+        let first = cache.run(&src, &obj, "cp", &args, "copy").await.unwrap();
+        assert_eq!(cache.would_hit(&src, "cp", &args).unwrap(), Some(true));
+        let off = BuildCache::new(&root, false);
+        assert_eq!(off.would_hit(&src, "cp", &args).unwrap(), None);
+        std::fs::remove_file(&obj).unwrap();
+        let second = cache.run(&src, &obj, "cp", &args, "copy").await.unwrap();
+        assert_eq!(first, CacheOutcome::Miss);
+        assert_eq!(second, CacheOutcome::Hit);
+        assert_eq!(std::fs::read_to_string(&obj).unwrap(), "int a;");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn header_change_invalidates_entry() {
+        let root = std::env::temp_dir().join(format!("kb-cache-dep-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("out")).unwrap();
+        let (src, hdr) = (root.join("a.c"), root.join("a.h"));
+        let (obj, dep) = (root.join("out/a.c.o"), root.join("out/a.c.d"));
+        std::fs::write(&src, "#include \"a.h\"").unwrap();
+        std::fs::write(&hdr, "#define A 1").unwrap();
+        // A shell stand-in compiler that writes the object and its depfile.
+        let script = format!(
+            "cp {src} {obj} && printf '%s: %s %s\\n' {obj} {src} {hdr} > {dep}",
+            src = src.display(),
+            obj = obj.display(),
+            hdr = hdr.display(),
+            dep = dep.display()
+        );
+        let args = vec![
+            "-c".to_string(),
+            script,
+            "-MF".to_string(),
+            dep.display().to_string(),
+        ];
+        let cache = BuildCache::new(&root, true);
+        let run = || cache.run(&src, &obj, "sh", &args, "compile");
+
+        assert_eq!(run().await.unwrap(), CacheOutcome::Miss);
+        assert_eq!(run().await.unwrap(), CacheOutcome::Hit);
+        std::fs::write(&hdr, "#define A 2").unwrap();
+        assert_eq!(run().await.unwrap(), CacheOutcome::Miss);
+        assert_eq!(run().await.unwrap(), CacheOutcome::Hit);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,392 @@
+//! `kernel-builder check-workspace`: cheap layout validation before a build.
+//!
+//! Agent-proposed changes sometimes move a directory, drop the linker script,
+//! hard-code a host include path or commit build outputs. Those all fail late
+//! (or, worse, build against the host) once the toolchain is running; this
+//! pass finds them in milliseconds and reports every problem at once instead
+//! of stopping at the first.
+
+use crate::{exec, link, toolchain, ArchToolchain};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// Extensions of build outputs that should never be tracked in a workspace.
+const OUTPUT_EXTENSIONS: &[&str] = &["o", "a", "obj", "elf", "iso", "img"];
+
+/// Source extensions scanned for `#include`/`%include` directives.
+const SOURCE_EXTENSIONS: &[&str] = &["c", "h", "S", "s", "asm", "inc"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Check {
+    Layout,
+    LinkerScript,
+    Includes,
+    Objects,
+    Toolchain,
+}
+
+impl Check {
+    pub fn name(self) -> &'static str {
+        match self {
+            Check::Layout => "layout",
+            Check::LinkerScript => "linker-script",
+            Check::Includes => "includes",
+            Check::Objects => "objects",
+            Check::Toolchain => "toolchain",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    /// Reported but does not fail the check.
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub check: Check,
+    pub severity: Severity,
+    pub message: String,
+    /// Workspace-relative file the finding is about.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+}
+
+impl Finding {
+    fn error(check: Check, message: impl Into<String>) -> Self {
+        Self {
+            check,
+            severity: Severity::Error,
+            message: message.into(),
+            path: None,
+            line: None,
+        }
+    }
+
+    fn warning(check: Check, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(check, message)
+        }
+    }
+
+    fn at(mut self, path: &str, line: Option<usize>) -> Self {
+        self.path = Some(path.to_string());
+        self.line = line;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CheckReport {
+    /// No error-severity findings.
+    pub ok: bool,
+    pub findings: Vec<Finding>,
+}
+
+/// What to validate; `main.rs` fills this from the build flags so the check
+/// looks where the build would.
+#[derive(Debug, Clone)]
+pub struct CheckOptions {
+    pub workspace: PathBuf,
+    pub arch: String,
+    pub cc: Option<String>,
+    pub ld: Option<String>,
+    pub linker_script: Option<PathBuf>,
+    /// Assembly source directory, relative to the workspace.
+    pub boot_dir: PathBuf,
+    /// Validate for the native pipeline rather than the Makefile.
+    pub native: bool,
+}
+
+/// Workspace-relative directories a build reads from.
+pub fn required_dirs(boot_dir: &Path, native: bool) -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from("kernel"), PathBuf::from("kernel/include")];
+    if native {
+        dirs.push(boot_dir.to_path_buf());
+    }
+    dirs
+}
+
+/// `(line, target)` for every include directive in `text` whose target is
+/// an absolute path. Covers C/GAS `#include`, nasm `%include` and GAS
+/// `.include`/`.incbin`.
+pub fn absolute_includes(text: &str) -> Vec<(usize, String)> {
+    let mut found = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim_start();
+        let rest = ["#", "%", "."].iter().find_map(|p| {
+            let after = line.strip_prefix(p)?.trim_start();
+            ["include", "incbin"]
+                .iter()
+                .find_map(|d| after.strip_prefix(d))
+        });
+        let Some(rest) = rest.map(str::trim_start) else {
+            continue;
+        };
+        let target = match rest.chars().next() {
+            Some('"') => rest[1..].split('"').next(),
+            Some('<') => rest[1..].split('>').next(),
+            _ => None,
+        };
+        if let Some(t) = target.filter(|t| t.starts_with('/')) {
+            found.push((n + 1, t.to_string()));
+        }
+    }
+    found
+}
+
+/// `(line, flag)` for Makefile include flags pointing at absolute paths.
+pub fn absolute_include_flags(makefile: &str) -> Vec<(usize, String)> {
+    let mut found = Vec::new();
+    for (n, line) in makefile.lines().enumerate() {
+        let mut toks = line.split_whitespace().peekable();
+        while let Some(tok) = toks.next() {
+            let flag = match tok {
+                "-I" | "-isystem" => toks
+                    .peek()
+                    .filter(|next| next.starts_with('/'))
+                    .map(|next| format!("{tok} {next}")),
+                _ => ["-I/", "-isystem/"]
+                    .iter()
+                    .any(|p| tok.starts_with(p))
+                    .then(|| tok.to_string()),
+            };
+            found.extend(flag.map(|f| (n + 1, f)));
+        }
+    }
+    found
+}
+
+/// Tracked paths that look like build outputs.
+pub fn stray_outputs(tracked: &[String]) -> Vec<&str> {
+    tracked
+        .iter()
+        .map(String::as_str)
+        .filter(|p| {
+            Path::new(p)
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| OUTPUT_EXTENSIONS.contains(&e))
+        })
+        .collect()
+}
+
+fn rel(workspace: &Path, path: &Path) -> String {
+    path.strip_prefix(workspace)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+fn walk_sources(dir: &Path, found: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for path in entries.flatten().map(|e| e.path()) {
+        if path.is_dir() {
+            walk_sources(&path, found);
+        } else if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| SOURCE_EXTENSIONS.contains(&e))
+        {
+            found.push(path);
+        }
+    }
+}
+
+/// Files git tracks under `workspace`, or `None` outside a checkout.
+async fn tracked_files(workspace: &Path) -> Option<Vec<String>> {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(workspace)
+        .args(["ls-files", "-z"])
+        .output()
+        .await
+        .ok()?;
+    out.status.success().then(|| {
+        String::from_utf8_lossy(&out.stdout)
+            .split('\0')
+            .filter(|p| !p.is_empty())
+            .map(str::to_string)
+            .collect()
+    })
+}
+
+fn check_layout(opts: &CheckOptions, findings: &mut Vec<Finding>) {
+    for dir in required_dirs(&opts.boot_dir, opts.native) {
+        if !opts.workspace.join(&dir).is_dir() {
+            findings.push(Finding::error(
+                Check::Layout,
+                format!("missing directory {}/", dir.display()),
+            ));
+        }
+    }
+    if !opts.native && !opts.workspace.join("Makefile").is_file() {
+        findings.push(Finding::error(
+            Check::Layout,
+            "missing Makefile (use --driver native to build without one)",
+        ));
+    }
+}
+
+fn check_linker_script(opts: &CheckOptions, findings: &mut Vec<Finding>) {
+    let script = match link::find_script(&opts.workspace, &opts.arch, opts.linker_script.as_deref())
+    {
+        Ok(script) => script,
+        Err(e) => {
+            findings.push(Finding::error(Check::LinkerScript, format!("{e:#}")));
+            return;
+        }
+    };
+    let path = rel(&opts.workspace, &script);
+    let Ok(text) = std::fs::read_to_string(&script) else {
+        findings.push(Finding::error(Check::LinkerScript, "unreadable").at(&path, None));
+        return;
+    };
+    if link::parse_script(&text).entry.is_none() {
+        findings.push(Finding::error(Check::LinkerScript, "no ENTRY() directive").at(&path, None));
+    }
+}
+
+fn check_includes(opts: &CheckOptions, findings: &mut Vec<Finding>) {
+    let mut sources = Vec::new();
+    walk_sources(&opts.workspace, &mut sources);
+    sources.sort();
+    for source in &sources {
+        let Ok(text) = std::fs::read_to_string(source) else {
+            continue;
+        };
+        for (line, target) in absolute_includes(&text) {
+            findings.push(
+                Finding::error(Check::Includes, format!("absolute include \"{target}\""))
+                    .at(&rel(&opts.workspace, source), Some(line)),
+            );
+        }
+    }
+    if let Ok(text) = std::fs::read_to_string(opts.workspace.join("Makefile")) {
+        for (line, flag) in absolute_include_flags(&text) {
+            findings.push(
+                Finding::error(Check::Includes, format!("absolute include path `{flag}`"))
+                    .at("Makefile", Some(line)),
+            );
+        }
+    }
+}
+
+async fn check_objects(opts: &CheckOptions, findings: &mut Vec<Finding>) {
+    let Some(tracked) = tracked_files(&opts.workspace).await else {
+        findings.push(Finding::warning(
+            Check::Objects,
+            "not a git checkout; committed build outputs not checked",
+        ));
+        return;
+    };
+    for path in stray_outputs(&tracked) {
+        findings
+            .push(Finding::error(Check::Objects, "build output is tracked by git").at(path, None));
+    }
+}
+
+async fn check_toolchain(opts: &CheckOptions, findings: &mut Vec<Finding>) {
+    let tc = match ArchToolchain::for_arch(&opts.arch) {
+        Ok(tc) => tc,
+        Err(e) => {
+            findings.push(Finding::error(Check::Toolchain, format!("{e:#}")));
+            return;
+        }
+    };
+    if !opts.native {
+        let cc = opts.cc.clone().unwrap_or_else(|| tc.cc.clone());
+        if exec::which(&cc).is_none() {
+            findings.push(Finding::error(
+                Check::Toolchain,
+                format!("`{cc}` not found on PATH (pass --cc)"),
+            ));
+        }
+        return;
+    }
+    if let Err(e) = toolchain::detect(&tc, opts.cc.as_deref()).await {
+        findings.push(Finding::error(Check::Toolchain, format!("{e:#}")));
+    }
+    if let Err(e) = link::find_linker(&tc, opts.ld.as_deref()) {
+        findings.push(Finding::error(Check::Toolchain, format!("{e:#}")));
+    }
+}
+
+/// Run every check; later checks still run when earlier ones fail.
+pub async fn check_workspace(opts: &CheckOptions) -> CheckReport {
+    let mut findings = Vec::new();
+    if !opts.workspace.is_dir() {
+        findings.push(Finding::error(
+            Check::Layout,
+            format!("workspace {} does not exist", opts.workspace.display()),
+        ));
+    } else {
+        check_layout(opts, &mut findings);
+        check_linker_script(opts, &mut findings);
+        check_includes(opts, &mut findings);
+        check_objects(opts, &mut findings).await;
+    }
+    check_toolchain(opts, &mut findings).await;
+    CheckReport {
+        ok: !findings.iter().any(|f| f.severity == Severity::Error),
+        findings,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_absolute_includes_in_c_and_asm() {
+        let text = "#include \"mm/pmm.h\"\n\
+                    #include </usr/include/stdint.h>\n\
+                    # include \"/home/agent/x.h\"\n\
+                    %include \"/tmp/defs.inc\"\n\
+                    .incbin \"weights.bin\"\n";
+        assert_eq!(
+            absolute_includes(text),
+            [
+                (2, "/usr/include/stdint.h".to_string()),
+                (3, "/home/agent/x.h".to_string()),
+                (4, "/tmp/defs.inc".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_absolute_makefile_include_flags() {
+        let mk = "CFLAGS += -Ikernel/include -I/usr/include\nCFLAGS += -isystem /opt/x\n";
+        assert_eq!(
+            absolute_include_flags(mk),
+            [
+                (1, "-I/usr/include".to_string()),
+                (2, "-isystem /opt/x".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn flags_tracked_build_outputs() {
+        let tracked = [
+            "kernel/mm/pmm.c".to_string(),
+            "build/obj/kernel/mm/pmm.c.o".to_string(),
+            "build/auton.iso".to_string(),
+            "kernel/slm/weights.bin".to_string(),
+        ];
+        assert_eq!(
+            stray_outputs(&tracked),
+            ["build/obj/kernel/mm/pmm.c.o", "build/auton.iso"]
+        );
+    }
+}
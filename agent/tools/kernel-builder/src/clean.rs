@@ -0,0 +1,222 @@
+//! `kernel-builder clean`: remove build outputs by stage and age.
+//!
+//! Long agent sessions accumulate objects, images and cache entries; wiping
+//! the whole output directory also throws away the incremental cache.
+//! `--stage` picks what goes and `--older-than` keeps anything touched
+//! recently, so e.g. `clean --stage cache --older-than 7d` trims stale cache
+//! entries while the current working set stays warm.
+
+use crate::cache::CACHE_DIR;
+use crate::listing::LISTINGS_DIR;
+use crate::repro::CHECK_DIR;
+use crate::symbols::SYMBOLS_NAME;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CleanStage {
+    /// Objects, depfiles, listings, the Rust target dir, the linked kernel
+    /// with its map and symbol table, and any `--reproducible` check tree.
+    Objects,
+    /// ISO and raw disk images and their staging files.
+    Images,
+    /// The object cache (`.cache/`).
+    Cache,
+    /// The whole output directory.
+    All,
+}
+
+/// Paths under `output` that `stage` owns (some may not exist).
+pub fn targets(output: &Path, stage: CleanStage) -> Vec<PathBuf> {
+    let names: &[&str] = match stage {
+        CleanStage::Objects => &[
+            "obj",
+            LISTINGS_DIR,
+            "rust",
+            "kernel.elf",
+            "kernel.map",
+            SYMBOLS_NAME,
+            CHECK_DIR,
+        ],
+        CleanStage::Images => &[
+            "auton.iso",
+            "auton.img",
+            "auton-uefi.img",
+            "isodir",
+            "limine.conf",
+        ],
+        CleanStage::Cache => &[CACHE_DIR],
+        CleanStage::All => return vec![output.to_path_buf()],
+    };
+    names.iter().map(|n| output.join(n)).collect()
+}
+
+/// `90s`, `30m`, `12h`, `7d`, `2w`; a bare number is seconds.
+pub fn parse_duration(text: &str) -> Result<Duration> {
+    let t = text.trim();
+    let split = t.find(|c: char| !c.is_ascii_digit()).unwrap_or(t.len());
+    let (digits, unit) = t.split_at(split);
+    let n: u64 = digits
+        .parse()
+        .with_context(|| format!("invalid duration `{text}`"))?;
+    let secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        "w" => 7 * 86_400,
+        other => bail!("invalid duration unit `{other}` in `{text}` (use s, m, h, d or w)"),
+    };
+    let total = n
+        .checked_mul(secs)
+        .ok_or_else(|| anyhow!("--older-than {text}: too large"))?;
+    Ok(Duration::from_secs(total))
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CleanReport {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Remove what `stage` owns under `output`; with `older_than`, only files
+/// last modified before that age (directories go once they are empty).
+pub fn clean(
+    output: &Path,
+    stage: CleanStage,
+    older_than: Option<Duration>,
+) -> Result<CleanReport> {
+    let Some(cutoff) = cutoff(older_than) else {
+        return Ok(CleanReport::default());
+    };
+    let mut report = CleanReport::default();
+    for target in targets(output, stage) {
+        remove(&target, cutoff, &mut report)?;
+    }
+    Ok(report)
+}
+
+/// Remove everything in `dir` the way [`clean`] does: a `--cache-dir`
+/// outside the output directory.
+pub fn clean_dir(dir: &Path, older_than: Option<Duration>) -> Result<CleanReport> {
+    let Some(cutoff) = cutoff(older_than) else {
+        return Ok(CleanReport::default());
+    };
+    let mut report = CleanReport::default();
+    remove(dir, cutoff, &mut report)?;
+    Ok(report)
+}
+
+/// The modification time files must predate to go, `Some(None)` for any
+/// file; `None` when `older_than` reaches back past what the clock can
+/// represent, so nothing is that old.
+fn cutoff(older_than: Option<Duration>) -> Option<Option<SystemTime>> {
+    match older_than {
+        None => Some(None),
+        Some(age) => SystemTime::now().checked_sub(age).map(Some),
+    }
+}
+
+fn remove(path: &Path, cutoff: Option<SystemTime>, report: &mut CleanReport) -> Result<()> {
+    let Ok(meta) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if meta.is_dir() {
+        for entry in
+            std::fs::read_dir(path).with_context(|| format!("scanning {}", path.display()))?
+        {
+            remove(&entry?.path(), cutoff, report)?;
+        }
+        // Fails (and is left alone) while newer files remain inside.
+        let _ = std::fs::remove_dir(path);
+        return Ok(());
+    }
+    let old_enough = match (cutoff, meta.modified()) {
+        (Some(cutoff), Ok(modified)) => modified < cutoff,
+        _ => true,
+    };
+    if old_enough {
+        std::fs::remove_file(path).with_context(|| format!("removing {}", path.display()))?;
+        report.files += 1;
+        report.bytes += meta.len();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kb-clean-{tag}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for f in [
+            "obj/kernel/a.c.o",
+            "obj/kernel/a.c.d",
+            ".cache/ab/ab12.o",
+            "auton.iso",
+        ] {
+            let path = dir.join(f);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"1234").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn objects_stage_keeps_the_cache_and_images() {
+        let dir = scratch("objects");
+        let report = clean(&dir, CleanStage::Objects, None).unwrap();
+        assert_eq!(report, CleanReport { files: 2, bytes: 8 });
+        assert!(!dir.join("obj").exists());
+        assert!(dir.join(".cache/ab/ab12.o").exists());
+        assert!(dir.join("auton.iso").exists());
+        clean(&dir, CleanStage::All, None).unwrap();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn older_than_spares_recent_files() {
+        let dir = scratch("age");
+        let report = clean(&dir, CleanStage::Cache, Some(Duration::from_secs(3600))).unwrap();
+        assert_eq!(report.files, 0);
+        assert!(dir.join(".cache/ab/ab12.o").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ages_past_the_clock_spare_everything() {
+        let dir = scratch("forever");
+        let forever = parse_duration(&format!("{}s", u64::MAX)).unwrap();
+        let report = clean(&dir, CleanStage::All, Some(forever)).unwrap();
+        assert_eq!(report, CleanReport::default());
+        assert_eq!(clean_dir(&dir, Some(forever)).unwrap().files, 0);
+        assert!(dir.join("obj/kernel/a.c.o").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(1800));
+        assert_eq!(parse_duration("7d").unwrap(), Duration::from_secs(604_800));
+        assert!(parse_duration("3y").is_err());
+        assert!(parse_duration("m").is_err());
+    }
+
+    #[test]
+    fn rejects_durations_that_overflow() {
+        let max = u64::MAX / 604_800;
+        assert_eq!(
+            parse_duration(&format!("{max}w")).unwrap(),
+            Duration::from_secs(max * 604_800)
+        );
+        let err = parse_duration(&format!("{}w", max + 1)).unwrap_err();
+        assert!(err.to_string().contains("too large"), "{err}");
+        assert!(parse_duration(&format!("{}s", u64::MAX)).is_ok());
+    }
+}
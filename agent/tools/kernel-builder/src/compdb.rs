@@ -0,0 +1,115 @@
+//! `compile_commands.json` export (`--emit-compdb`).
+//!
+//! Entries use the `arguments` form with absolute paths, so clangd,
+//! clang-tidy and diff-validator's static checks see exactly the flags the
+//! native pipeline passes. The database is written after planning and before
+//! compiling, so it exists even when the build itself fails.
+
+use crate::asm::AsmJob;
+use crate::toolchain::CompileJob;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+pub const COMPDB_NAME: &str = "compile_commands.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompileCommand {
+    pub directory: String,
+    pub file: String,
+    pub arguments: Vec<String>,
+    pub output: String,
+}
+
+fn absolute(cwd: &Path, path: &Path) -> String {
+    if path.is_absolute() {
+        path.display().to_string()
+    } else {
+        cwd.join(path).display().to_string()
+    }
+}
+
+/// One entry per translation unit; `cwd` is the directory the tools run in.
+fn entry(
+    cwd: &Path,
+    source: &Path,
+    output: &Path,
+    program: &str,
+    args: &[String],
+) -> CompileCommand {
+    let mut arguments = vec![program.to_string()];
+    arguments.extend(args.iter().cloned());
+    CompileCommand {
+        directory: cwd.display().to_string(),
+        file: absolute(cwd, source),
+        arguments,
+        output: absolute(cwd, output),
+    }
+}
+
+/// Entries for the C units, plus `.S` files that go through the C driver
+/// (nasm and bare `as` inputs are not C-family and tools would reject them).
+pub fn entries(
+    cwd: &Path,
+    asm_jobs: &[AsmJob],
+    cc_jobs: &[CompileJob],
+    cc: &str,
+) -> Vec<CompileCommand> {
+    let asm = asm_jobs
+        .iter()
+        .filter(|j| j.program == cc)
+        .map(|j| entry(cwd, &j.source, &j.output, &j.program, &j.args));
+    let c = cc_jobs
+        .iter()
+        .map(|j| entry(cwd, &j.source, &j.object, &j.program, &j.args));
+    asm.chain(c).collect()
+}
+
+/// Write the database to `<output>/compile_commands.json`.
+pub fn write(output: &Path, commands: &[CompileCommand]) -> Result<PathBuf> {
+    std::fs::create_dir_all(output).with_context(|| format!("creating {}", output.display()))?;
+    let path = output.join(COMPDB_NAME);
+    let text = serde_json::to_string_pretty(commands)?;
+    std::fs::write(&path, text + "\n").with_context(|| format!("writing {}", path.display()))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::OutputFormat;
+
+    #[test]
+    fn entries_are_absolute_and_skip_non_c_assemblers() {
+        let cwd = Path::new("/work");
+        let asm = vec![
+            AsmJob {
+                source: "k/boot.S".into(),
+                output: "out/boot.S.o".into(),
+                format: OutputFormat::Elf64,
+                program: "gcc".into(),
+                args: vec!["-c".into()],
+            },
+            AsmJob {
+                source: "k/stage2.asm".into(),
+                output: "out/stage2.bin".into(),
+                format: OutputFormat::Bin,
+                program: "nasm".into(),
+                args: vec![],
+            },
+        ];
+        let cc = vec![CompileJob {
+            source: "/src/k/main.c".into(),
+            object: "out/main.c.o".into(),
+            program: "gcc".into(),
+            args: vec!["-O2".into(), "-c".into()],
+        }];
+        let db = entries(cwd, &asm, &cc, "gcc");
+        assert_eq!(db.len(), 2);
+        assert_eq!(db[0].file, "/work/k/boot.S");
+        assert_eq!(db[1].file, "/src/k/main.c");
+        assert_eq!(db[1].output, "/work/out/main.c.o");
+        assert_eq!(db[1].arguments, vec!["gcc", "-O2", "-c"]);
+        assert_eq!(db[1].directory, "/work");
+    }
+}
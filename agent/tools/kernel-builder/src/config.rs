@@ -0,0 +1,249 @@
+//! `auton-build.toml`: per-checkout defaults for the build flags.
+//!
+//! Looked up as `--config <path>`, else `./auton-build.toml`, else
+//! `<workspace>/auton-build.toml`. Keys are the long flag names and mean the
+//! same thing (so `boot-dir` and `linker-script` are workspace-relative);
+//! anything given on the command line wins. Three settings have no flag:
+//! `[sources]` include/exclude globs select the C translation units,
+//! `[flags]` maps a workspace-relative directory to extra compiler flags for
+//! the sources under it, and `[stack]` tunes the stack usage analysis (see
+//! [`crate::stack`]; a budget there turns it on).
+//!
+//! ```toml
+//! workspace = "kernels/x86_64"
+//! archs = ["x86_64", "aarch64"]
+//! driver = "native"
+//! boot-dir = "kernel/arch/x86_64"
+//! image-format = "iso"
+//!
+//! [sources]
+//! exclude = ["kernel/slm/neural/**"]
+//!
+//! [flags]
+//! "kernel/slm" = ["-O3"]
+//!
+//! [stack]
+//! budget = 8192
+//! ```
+
+use crate::image::ImageFormat;
+use crate::profile::Profile;
+use crate::stack::StackConfig;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_NAME: &str = "auton-build.toml";
+
+/// Default `[sources] include`: what the pipeline compiles without a config.
+pub const DEFAULT_INCLUDE: &str = "kernel/**/*.c";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct BuildConfig {
+    pub workspace: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    /// Default `--arch`; falls back to the first of `archs`.
+    pub arch: Option<String>,
+    /// Architectures this workspace supports; `--arch` must be one of them.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub archs: Vec<String>,
+    /// `make` or `native`.
+    pub driver: Option<String>,
+    pub cc: Option<String>,
+    pub ld: Option<String>,
+    pub boot_dir: Option<PathBuf>,
+    pub linker_script: Option<PathBuf>,
+    pub image_format: Option<ImageFormat>,
+    pub profile: Option<Profile>,
+    pub jobs: Option<usize>,
+    #[serde(skip_serializing_if = "SourceGlobs::is_default")]
+    pub sources: SourceGlobs,
+    /// Directory → extra C flags; nested directories' flags come last.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub flags: BTreeMap<String, Vec<String>>,
+    #[serde(skip_serializing_if = "StackConfig::is_default")]
+    pub stack: StackConfig,
+}
+
+impl BuildConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        auton_toml::from_file(path)
+    }
+
+    /// The config file in effect, if any (see the module docs for the
+    /// lookup order). An explicit path must exist.
+    pub fn find(explicit: Option<&Path>, workspace: &Path) -> Result<Option<(PathBuf, Self)>> {
+        let path = match explicit {
+            Some(p) => p.to_path_buf(),
+            None => match [PathBuf::from(CONFIG_NAME), workspace.join(CONFIG_NAME)]
+                .into_iter()
+                .find(|p| p.is_file())
+            {
+                Some(p) => p,
+                None => return Ok(None),
+            },
+        };
+        let config = Self::load(&path)?;
+        Ok(Some((path, config)))
+    }
+
+    /// The architecture to build when `--arch` was not given.
+    pub fn default_arch(&self) -> Option<&str> {
+        self.arch
+            .as_deref()
+            .or(self.archs.first().map(String::as_str))
+    }
+
+    /// Reject an `arch` outside the configured list.
+    pub fn check_arch(&self, arch: &str) -> Result<()> {
+        if !self.archs.is_empty() && !self.archs.iter().any(|a| a == arch) {
+            bail!(
+                "arch `{arch}` is not in the configured archs ({})",
+                self.archs.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// `[flags]` entries for the workspace-relative source `rel`, shallowest
+/// directory first so deeper directories override.
+pub fn flags_for(flags: &BTreeMap<String, Vec<String>>, rel: &Path) -> Vec<String> {
+    let mut dirs: Vec<(&String, &Vec<String>)> = flags
+        .iter()
+        .filter(|(dir, _)| rel.starts_with(dir.trim_end_matches('/')))
+        .collect();
+    dirs.sort_by_key(|(dir, _)| Path::new(dir.as_str()).components().count());
+    dirs.into_iter()
+        .flat_map(|(_, f)| f.iter().cloned())
+        .collect()
+}
+
+/// `[sources]`: globs over workspace-relative paths (`*`, `?`, `**`).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceGlobs {
+    /// Defaults to [`DEFAULT_INCLUDE`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+}
+
+impl SourceGlobs {
+    pub fn is_default(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether the workspace-relative `rel` is selected.
+    pub fn matches(&self, rel: &str) -> bool {
+        let included = if self.include.is_empty() {
+            glob_match(DEFAULT_INCLUDE, rel)
+        } else {
+            self.include.iter().any(|g| glob_match(g, rel))
+        };
+        included && !self.exclude.iter().any(|g| glob_match(g, rel))
+    }
+
+    /// Every selected `.c` file under `workspace`, sorted.
+    pub fn select(&self, workspace: &Path) -> Result<Vec<PathBuf>> {
+        let found = crate::toolchain::discover_sources(workspace)?;
+        let selected: Vec<PathBuf> = found
+            .into_iter()
+            .filter(|p| {
+                let rel = p.strip_prefix(workspace).unwrap_or(p);
+                self.matches(&rel.to_string_lossy())
+            })
+            .collect();
+        if selected.is_empty() {
+            bail!(
+                "[sources] in {CONFIG_NAME} selects no C files under {}",
+                workspace.display()
+            );
+        }
+        Ok(selected)
+    }
+}
+
+/// Match `path` against a `/`-separated glob: `*` and `?` stay within one
+/// component, `**` spans any number of them.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pat: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+    let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match_parts(&pat, &parts)
+}
+
+fn match_parts(pat: &[&str], parts: &[&str]) -> bool {
+    match pat.split_first() {
+        None => parts.is_empty(),
+        Some((&"**", rest)) => (0..=parts.len()).any(|i| match_parts(rest, &parts[i..])),
+        Some((p, rest)) => parts.split_first().is_some_and(|(first, tail)| {
+            match_component(p.as_bytes(), first.as_bytes()) && match_parts(rest, tail)
+        }),
+    }
+}
+
+fn match_component(pat: &[u8], s: &[u8]) -> bool {
+    match pat.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|i| match_component(rest, &s[i..])),
+        Some((b'?', rest)) => !s.is_empty() && match_component(rest, &s[1..]),
+        Some((c, rest)) => s.first() == Some(c) && match_component(rest, &s[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_components_and_double_star() {
+        assert!(glob_match("kernel/**/*.c", "kernel/main.c"));
+        assert!(glob_match("kernel/**/*.c", "kernel/mm/pmm.c"));
+        assert!(!glob_match("kernel/*.c", "kernel/mm/pmm.c"));
+        assert!(glob_match("kernel/slm/**", "kernel/slm/neural/attn.c"));
+        assert!(glob_match("kernel/?m/*.c", "kernel/mm/vmm.c"));
+        assert!(!glob_match("kernel/**/*.c", "tests/a.c"));
+    }
+
+    #[test]
+    fn parses_config_and_rejects_unknown_keys() {
+        let config: BuildConfig = auton_toml::from_str(
+            "archs = [\"x86_64\", \"aarch64\"]\nimage-format = \"both\"\nprofile = \"kasan-lite\"\n\
+             [sources]\nexclude = [\"kernel/slm/**\"]\n[flags]\n\"kernel\" = [\"-Wall\"]\n\"kernel/mm/\" = [\"-O3\"]\n",
+        )
+        .unwrap();
+        assert_eq!(config.default_arch(), Some("x86_64"));
+        assert!(config.check_arch("riscv64").is_err());
+        assert_eq!(config.image_format, Some(ImageFormat::Both));
+        assert_eq!(config.profile, Some(Profile::KasanLite));
+        assert!(config.sources.matches("kernel/mm/pmm.c"));
+        assert!(!config.sources.matches("kernel/slm/net.c"));
+        assert_eq!(
+            flags_for(&config.flags, Path::new("kernel/mm/pmm.c")),
+            ["-Wall", "-O3"]
+        );
+        assert_eq!(
+            flags_for(&config.flags, Path::new("kernel/mmx.c")),
+            ["-Wall"]
+        );
+        assert!(auton_toml::from_str::<BuildConfig>("workspce = \"x\"\n").is_err());
+    }
+
+    #[test]
+    fn parses_the_stack_table() {
+        let config: BuildConfig = auton_toml::from_str(
+            "[stack]\nbudget = 4096\ninterrupts = [\"irq*_stub\"]\n\
+             frames = { irq0_stub = 136 }\ncalls = { vfs_read = [\"ramfs_read\"] }\n",
+        )
+        .unwrap();
+        assert_eq!(config.stack.budget, Some(4096));
+        assert!(config.stack.is_interrupt("irq0_stub"));
+        assert!(!config.stack.is_interrupt("isr_default"));
+        assert_eq!(config.stack.frames["irq0_stub"], 136);
+        assert_eq!(config.stack.calls["vfs_read"], ["ramfs_read"]);
+        assert!(auton_toml::from_str::<BuildConfig>("[stack]\nbudjet = 1\n").is_err());
+    }
+}
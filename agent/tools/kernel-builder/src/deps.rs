@@ -0,0 +1,223 @@
+//! Header dependency tracking from compiler-written `-MMD` depfiles.
+//!
+//! Every C-driver job writes `<object minus .o>.d` next to its object. The
+//! cache folds the contents of the headers listed there into its hit check,
+//! and `kernel-builder deps graph` reads the same files back to dump the
+//! translation-unit → header graph (DOT for viewing, JSON for
+//! diff-validator's impact analysis).
+
+use crate::hash::{hex, Sha256};
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// Depfile written alongside `object` (`x.c.o` → `x.c.d`).
+pub fn depfile_for(object: &Path) -> PathBuf {
+    object.with_extension("d")
+}
+
+/// Compiler flags producing [`depfile_for`]`(object)`.
+pub fn depfile_args(object: &Path) -> Vec<String> {
+    vec![
+        "-MMD".to_string(),
+        "-MF".to_string(),
+        depfile_for(object).display().to_string(),
+    ]
+}
+
+/// One `target: prerequisites…` rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DepRule {
+    pub target: PathBuf,
+    pub prerequisites: Vec<PathBuf>,
+}
+
+/// Split on unescaped whitespace, undoing `\ ` and `$$` escapes.
+fn words(text: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut cur = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&' ') => {
+                cur.push(' ');
+                chars.next();
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                cur.push('$');
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                if !cur.is_empty() {
+                    out.push(std::mem::take(&mut cur));
+                }
+            }
+            c => cur.push(c),
+        }
+    }
+    if !cur.is_empty() {
+        out.push(cur);
+    }
+    out
+}
+
+/// Parse the first rule of a depfile (later phony `hdr.h:` rules from `-MP`
+/// carry no information).
+pub fn parse_depfile(text: &str) -> Option<DepRule> {
+    let joined = text.replace("\\\r\n", " ").replace("\\\n", " ");
+    let first = joined.lines().find(|l| !l.trim().is_empty())?;
+    // The target/prereq separator is the first `: ` (Windows drive letters
+    // never appear in our paths).
+    let (target, rest) = first.split_once(": ").or_else(|| first.split_once(':'))?;
+    Some(DepRule {
+        target: PathBuf::from(target.trim()),
+        prerequisites: words(rest).into_iter().map(PathBuf::from).collect(),
+    })
+}
+
+/// Digest over every dependency's path and current contents; a missing file
+/// hashes as a marker so deleting a header invalidates its dependents.
+pub fn digest(deps: &[PathBuf]) -> String {
+    let mut h = Sha256::new();
+    for dep in deps {
+        h.update(dep.to_string_lossy().as_bytes());
+        h.update(b"\0");
+        match std::fs::read(dep) {
+            Ok(bytes) => h.update(&bytes),
+            Err(_) => h.update(b"\xffmissing"),
+        }
+        h.update(b"\0");
+    }
+    hex(&h.finish())
+}
+
+/// Translation unit → headers it includes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DepGraph {
+    pub units: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl DepGraph {
+    /// Load every depfile under `obj_dir`.
+    pub fn load(obj_dir: &Path) -> Result<Self> {
+        if !obj_dir.is_dir() {
+            bail!(
+                "no objects under {} (run a native build first)",
+                obj_dir.display()
+            );
+        }
+        let mut graph = DepGraph::default();
+        let mut stack = vec![obj_dir.to_path_buf()];
+        while let Some(dir) = stack.pop() {
+            for entry in
+                std::fs::read_dir(&dir).with_context(|| format!("scanning {}", dir.display()))?
+            {
+                let path = entry?.path();
+                if path.is_dir() {
+                    stack.push(path);
+                } else if path.extension().is_some_and(|e| e == "d") {
+                    let text = std::fs::read_to_string(&path)
+                        .with_context(|| format!("reading {}", path.display()))?;
+                    if let Some(rule) = parse_depfile(&text) {
+                        graph.insert(&rule);
+                    }
+                }
+            }
+        }
+        Ok(graph)
+    }
+
+    /// Add one rule: the first prerequisite is the source, the rest headers.
+    pub fn insert(&mut self, rule: &DepRule) {
+        let Some((source, headers)) = rule.prerequisites.split_first() else {
+            return;
+        };
+        self.units.insert(
+            source.display().to_string(),
+            headers.iter().map(|h| h.display().to_string()).collect(),
+        );
+    }
+
+    /// Units that include `header` (directly or through another header —
+    /// depfiles already list the transitive closure).
+    pub fn dependents(&self, header: &str) -> Vec<&str> {
+        self.units
+            .iter()
+            .filter(|(_, hs)| hs.contains(header))
+            .map(|(u, _)| u.as_str())
+            .collect()
+    }
+
+    /// Graphviz rendering: units as boxes, headers as ellipses.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph deps {\n    rankdir=LR;\n");
+        let headers: BTreeSet<&String> = self.units.values().flatten().collect();
+        for unit in self.units.keys() {
+            out.push_str(&format!("    \"{}\" [shape=box];\n", escape(unit)));
+        }
+        for h in headers {
+            out.push_str(&format!("    \"{}\" [shape=ellipse];\n", escape(h)));
+        }
+        for (unit, hs) in &self.units {
+            for h in hs {
+                out.push_str(&format!("    \"{}\" -> \"{}\";\n", escape(unit), escape(h)));
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEPFILE: &str = "build/obj/kernel/mm/pmm.c.o: kernel/mm/pmm.c \\\n kernel/include/mm.h kernel/include/my\\ types.h\nkernel/include/mm.h:\n";
+
+    #[test]
+    fn parses_continuations_escapes_and_ignores_phony_rules() {
+        let rule = parse_depfile(DEPFILE).unwrap();
+        assert_eq!(rule.target, Path::new("build/obj/kernel/mm/pmm.c.o"));
+        assert_eq!(
+            rule.prerequisites,
+            [
+                "kernel/mm/pmm.c",
+                "kernel/include/mm.h",
+                "kernel/include/my types.h"
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(depfile_for(Path::new("o/pmm.c.o")), Path::new("o/pmm.c.d"));
+    }
+
+    #[test]
+    fn graph_reports_dependents_and_renders_dot() {
+        let mut g = DepGraph::default();
+        g.insert(&parse_depfile(DEPFILE).unwrap());
+        g.insert(&parse_depfile("a.o: kernel/a.c kernel/include/mm.h\n").unwrap());
+        assert_eq!(
+            g.dependents("kernel/include/mm.h"),
+            ["kernel/a.c", "kernel/mm/pmm.c"]
+        );
+        let dot = g.to_dot();
+        assert!(dot.starts_with("digraph deps {"));
+        assert!(dot.contains("\"kernel/a.c\" -> \"kernel/include/mm.h\";"));
+    }
+
+    #[test]
+    fn digest_tracks_header_contents() {
+        let h = std::env::temp_dir().join(format!("kb-deps-{}.h", std::process::id()));
+        std::fs::write(&h, "#define A 1").unwrap();
+        let before = digest(std::slice::from_ref(&h));
+        std::fs::write(&h, "#define A 2").unwrap();
+        let after = digest(std::slice::from_ref(&h));
+        std::fs::remove_file(&h).unwrap();
+        assert_ne!(before, after);
+        assert_ne!(after, digest(std::slice::from_ref(&h)));
+    }
+}
@@ -0,0 +1,267 @@
+//! Minimal ELF64 little-endian reader: headers, sections, segments, symbols.
+//!
+//! Enough to verify and report on the kernel image without binutils. All
+//! supported targets (x86_64, aarch64, riscv64) are ELF64 LE.
+
+use anyhow::{ensure, Context, Result};
+use serde::Serialize;
+use std::path::Path;
+
+pub const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_NOBITS: u32 = 8;
+pub const SHF_ALLOC: u64 = 2;
+pub const SHF_EXECINSTR: u64 = 4;
+pub const STT_OBJECT: u8 = 1;
+pub const STT_FUNC: u8 = 2;
+pub const STT_SECTION: u8 = 3;
+pub const STT_FILE: u8 = 4;
+pub const STB_GLOBAL: u8 = 1;
+pub const STB_WEAK: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl Section {
+    /// Occupies memory at runtime.
+    pub fn is_alloc(&self) -> bool {
+        self.flags & SHF_ALLOC != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Segment {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+    /// `STT_*` type (low nibble of `st_info`).
+    pub kind: u8,
+    /// `STB_*` binding (high nibble of `st_info`).
+    pub bind: u8,
+    /// Section header index (0 = undefined).
+    pub shndx: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Elf {
+    pub machine: u16,
+    pub entry: u64,
+    pub sections: Vec<Section>,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<Symbol>,
+}
+
+impl Elf {
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    pub fn load_segments(&self) -> impl Iterator<Item = &Segment> {
+        self.segments.iter().filter(|s| s.kind == PT_LOAD)
+    }
+
+    /// Lowest physical address of the loadable segments.
+    pub fn load_address(&self) -> Option<u64> {
+        self.load_segments().map(|s| s.paddr).min()
+    }
+
+    /// Physical span covered by the loadable segments (`max end - min start`).
+    pub fn loaded_span(&self) -> u64 {
+        let start = self.load_address();
+        let end = self
+            .load_segments()
+            .map(|s| s.paddr.saturating_add(s.memsz))
+            .max();
+        match (start, end) {
+            (Some(s), Some(e)) => e - s,
+            _ => 0,
+        }
+    }
+}
+
+fn read<const N: usize>(b: &[u8], off: usize) -> Result<[u8; N]> {
+    off.checked_add(N)
+        .and_then(|end| b.get(off..end))
+        .and_then(|s| s.try_into().ok())
+        .with_context(|| format!("truncated ELF (offset {off:#x})"))
+}
+
+fn u16_at(b: &[u8], off: usize) -> Result<u16> {
+    Ok(u16::from_le_bytes(read(b, off)?))
+}
+
+fn u32_at(b: &[u8], off: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(read(b, off)?))
+}
+
+fn u64_at(b: &[u8], off: usize) -> Result<u64> {
+    Ok(u64::from_le_bytes(read(b, off)?))
+}
+
+fn cstr_at(b: &[u8], off: usize) -> String {
+    let tail = b.get(off..).unwrap_or_default();
+    let end = tail.iter().position(|&c| c == 0).unwrap_or(tail.len());
+    String::from_utf8_lossy(&tail[..end]).into_owned()
+}
+
+/// Parse an ELF64 LE image.
+pub fn parse(b: &[u8]) -> Result<Elf> {
+    ensure!(b.get(..4) == Some(b"\x7fELF"), "not an ELF file");
+    ensure!(b.get(4) == Some(&2), "not a 64-bit ELF");
+    ensure!(b.get(5) == Some(&1), "not a little-endian ELF");
+
+    let machine = u16_at(b, 0x12)?;
+    let entry = u64_at(b, 0x18)?;
+    let phoff = u64_at(b, 0x20)? as usize;
+    let shoff = u64_at(b, 0x28)? as usize;
+    let phentsize = u16_at(b, 0x36)? as usize;
+    let phnum = u16_at(b, 0x38)? as usize;
+    let shentsize = u16_at(b, 0x3A)? as usize;
+    let shnum = u16_at(b, 0x3C)? as usize;
+    let shstrndx = u16_at(b, 0x3E)? as usize;
+
+    let mut segments = Vec::with_capacity(phnum);
+    for i in 0..phnum {
+        let o = phoff + i * phentsize;
+        segments.push(Segment {
+            kind: u32_at(b, o)?,
+            flags: u32_at(b, o + 4)?,
+            offset: u64_at(b, o + 8)?,
+            vaddr: u64_at(b, o + 16)?,
+            paddr: u64_at(b, o + 24)?,
+            filesz: u64_at(b, o + 32)?,
+            memsz: u64_at(b, o + 40)?,
+        });
+    }
+
+    struct RawSection {
+        name_off: usize,
+        kind: u32,
+        flags: u64,
+        addr: u64,
+        offset: u64,
+        size: u64,
+        link: u32,
+        entsize: u64,
+    }
+    let mut raw = Vec::with_capacity(shnum);
+    for i in 0..shnum {
+        let o = shoff + i * shentsize;
+        raw.push(RawSection {
+            name_off: u32_at(b, o)? as usize,
+            kind: u32_at(b, o + 4)?,
+            flags: u64_at(b, o + 8)?,
+            addr: u64_at(b, o + 16)?,
+            offset: u64_at(b, o + 24)?,
+            size: u64_at(b, o + 32)?,
+            link: u32_at(b, o + 40)?,
+            entsize: u64_at(b, o + 56)?,
+        });
+    }
+
+    let shstr_base = raw.get(shstrndx).map(|s| s.offset as usize).unwrap_or(0);
+    let sections: Vec<Section> = raw
+        .iter()
+        .map(|s| Section {
+            name: if shstrndx < raw.len() {
+                cstr_at(b, shstr_base + s.name_off)
+            } else {
+                String::new()
+            },
+            kind: s.kind,
+            flags: s.flags,
+            addr: s.addr,
+            offset: s.offset,
+            size: s.size,
+        })
+        .collect();
+
+    let mut symbols = Vec::new();
+    for symtab in raw.iter().filter(|s| s.kind == SHT_SYMTAB) {
+        let strtab = raw
+            .get(symtab.link as usize)
+            .context("symbol table links to a missing string table")?;
+        let entsize = if symtab.entsize == 0 {
+            24
+        } else {
+            symtab.entsize as usize
+        };
+        let count = symtab.size as usize / entsize;
+        // Entry 0 is the reserved null symbol.
+        for i in 1..count {
+            let o = symtab.offset as usize + i * entsize;
+            let name_off = u32_at(b, o)? as usize;
+            let info = read::<1>(b, o + 4)?[0];
+            symbols.push(Symbol {
+                name: cstr_at(b, strtab.offset as usize + name_off),
+                kind: info & 0xf,
+                bind: info >> 4,
+                shndx: u16_at(b, o + 6)?,
+                value: u64_at(b, o + 8)?,
+                size: u64_at(b, o + 16)?,
+            });
+        }
+    }
+
+    Ok(Elf {
+        machine,
+        entry,
+        sections,
+        segments,
+        symbols,
+    })
+}
+
+/// Read and parse an ELF file from disk.
+pub fn read_file(path: &Path) -> Result<Elf> {
+    let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    parse(&bytes).with_context(|| format!("parsing {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_running_test_binary() {
+        let elf = read_file(Path::new("/proc/self/exe")).unwrap();
+        assert_ne!(elf.entry, 0);
+        assert!(elf.section(".text").is_some_and(|s| s.is_alloc()));
+        assert!(elf.load_segments().count() > 0);
+        assert!(elf.loaded_span() > 0);
+    }
+
+    #[test]
+    fn rejects_non_elf_input() {
+        assert!(parse(b"MZ\x90\x00").is_err());
+        assert!(parse(b"\x7fELF\x01\x01").is_err()); // 32-bit
+    }
+
+    #[test]
+    fn truncated_header_is_an_error_not_a_panic() {
+        assert!(parse(b"\x7fELF\x02\x01\x01").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+//! Process execution shared by the native pipeline stages.
+//!
+//! Every tool invocation goes through the process-wide [`Backend`]: directly
+//! on the host, or inside a toolchain container (`--backend container`). The
+//! container sees the working directory and every [`Backend::Container`]
+//! mount at the same absolute path as the host, so argument vectors, depfiles
+//! and the object cache are identical either way. The `base` stage of the
+//! repository Dockerfile is a suitable image:
+//! `docker build --target base -t auton-toolchain:latest .`.
+
+use crate::diagnostics;
+use anyhow::Result;
+use auton_core::process::{self, ProcessOutput};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use tokio::process::Command;
+
+/// Environment passed through to containers (`-e VAR` copies the engine
+/// process's value and is a no-op when it is unset).
+const FORWARDED_ENV: &[&str] = &["SOURCE_DATE_EPOCH", "CC"];
+
+/// Where tools run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Host,
+    /// `<engine> run --rm` per invocation.
+    Container {
+        /// `docker` or `podman`.
+        engine: String,
+        image: String,
+        /// Host directories bind-mounted at the same path (the working
+        /// directory is always mounted).
+        mounts: Vec<PathBuf>,
+    },
+}
+
+static BACKEND: RwLock<Backend> = RwLock::new(Backend::Host);
+static LOOKUPS: Mutex<Option<HashMap<String, Option<PathBuf>>>> = Mutex::new(None);
+
+/// Select the backend for every subsequent tool invocation.
+pub fn set_backend(backend: Backend) {
+    *BACKEND.write().unwrap() = backend;
+    *LOOKUPS.lock().unwrap() = None;
+}
+
+pub fn backend() -> Backend {
+    BACKEND.read().unwrap().clone()
+}
+
+/// The first of `docker`, `podman` on PATH.
+pub fn default_engine() -> Option<String> {
+    ["docker", "podman"]
+        .iter()
+        .find(|e| which::which(e).is_ok())
+        .map(|e| e.to_string())
+}
+
+/// `<engine> run …` arguments that execute `program args…` in `image` with
+/// `cwd` as the working directory. `user` is `uid:gid` for the files the
+/// tool writes.
+pub fn container_args(
+    image: &str,
+    mounts: &[PathBuf],
+    cwd: &Path,
+    user: Option<&str>,
+    program: &str,
+    args: &[String],
+) -> Vec<String> {
+    let mut out = vec!["run".to_string(), "--rm".to_string()];
+    let mut dirs: Vec<&Path> = vec![cwd];
+    dirs.extend(mounts.iter().map(PathBuf::as_path));
+    dirs.sort();
+    dirs.dedup();
+    // A mount inside another one is already visible.
+    let roots: Vec<&Path> = dirs
+        .iter()
+        .filter(|d| !dirs.iter().any(|o| o != *d && d.starts_with(o)))
+        .copied()
+        .collect();
+    for dir in roots {
+        out.push("-v".to_string());
+        out.push(format!("{0}:{0}", dir.display()));
+    }
+    out.push("-w".to_string());
+    out.push(cwd.display().to_string());
+    if let Some(user) = user {
+        out.push("--user".to_string());
+        out.push(user.to_string());
+    }
+    for var in FORWARDED_ENV {
+        out.push("-e".to_string());
+        out.push(var.to_string());
+    }
+    out.push(image.to_string());
+    out.push(program.to_string());
+    out.extend(args.iter().cloned());
+    out
+}
+
+/// Owner of `dir` as `uid:gid`, so container output is not root-owned.
+fn owner(dir: &Path) -> Option<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let meta = std::fs::metadata(dir).ok()?;
+        Some(format!("{}:{}", meta.uid(), meta.gid()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+        None
+    }
+}
+
+/// A command running `program args…` in `cwd` (default: the current
+/// directory) on the current backend.
+pub fn command(cwd: Option<&Path>, program: &str, args: &[String]) -> Result<Command> {
+    match backend() {
+        Backend::Host => {
+            let mut cmd = Command::new(program);
+            cmd.args(args);
+            if let Some(dir) = cwd {
+                cmd.current_dir(dir);
+            }
+            Ok(cmd)
+        }
+        Backend::Container {
+            engine,
+            image,
+            mounts,
+        } => {
+            let cwd = match cwd {
+                Some(dir) => std::path::absolute(dir)?,
+                None => std::env::current_dir()?,
+            };
+            let user = owner(&cwd);
+            let mut cmd = Command::new(&engine);
+            cmd.args(container_args(
+                &image,
+                &mounts,
+                &cwd,
+                user.as_deref(),
+                program,
+                args,
+            ));
+            Ok(cmd)
+        }
+    }
+}
+
+/// Resolve `program` on the backend's PATH (inside the image for
+/// containers; answers are memoised per backend).
+pub fn which(program: &str) -> Option<PathBuf> {
+    let Backend::Container { engine, image, .. } = backend() else {
+        return which::which(program).ok();
+    };
+    if let Some(hit) = LOOKUPS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .get(program)
+    {
+        return hit.clone();
+    }
+    let found = std::process::Command::new(&engine)
+        .args([
+            "run",
+            "--rm",
+            &image,
+            "sh",
+            "-c",
+            "command -v \"$0\"",
+            program,
+        ])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from);
+    LOOKUPS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(program.to_string(), found.clone());
+    found
+}
+
+/// Run `program args…` to completion, logging the command line.
+///
+/// A non-zero exit becomes an error whose root cause is the tool's stderr
+/// (or its exit status when stderr is empty) and whose context is `what`, so
+/// `{:#}` renders e.g. `compiling kernel/mm/pmm.c: pmm.c:3: error: …`.
+pub async fn run_tool(program: &str, args: &[String], what: &str) -> Result<ProcessOutput> {
+    run(None, program, args, what).await
+}
+
+/// [`run_tool`] with `cwd` as the working directory, for tools that read
+/// per-directory config (cargo's `.cargo/config.toml`, `rust-toolchain.toml`).
+pub async fn run_tool_in(
+    cwd: &Path,
+    program: &str,
+    args: &[String],
+    what: &str,
+) -> Result<ProcessOutput> {
+    run(Some(cwd), program, args, what).await
+}
+
+async fn run(
+    cwd: Option<&Path>,
+    program: &str,
+    args: &[String],
+    what: &str,
+) -> Result<ProcessOutput> {
+    tracing::info!(program, args = ?args, "{what}");
+    let out = process::run(command(cwd, program, args)?, None)
+        .await
+        .map_err(|e| match backend() {
+            Backend::Host => e,
+            Backend::Container { .. } => e.context(format!("running `{program}` in a container")),
+        })?;
+    tracing::debug!(
+        program,
+        elapsed_ms = out.elapsed_ms,
+        status = ?out.code,
+        "{what} finished"
+    );
+
+    // The container engine is what ran; failures name the tool inside it.
+    let out = ProcessOutput {
+        program: program.to_string(),
+        ..out
+    }
+    .check(what)?;
+    // Warnings go out as one event so concurrent jobs never interleave lines.
+    if !out.stderr.trim().is_empty() {
+        tracing::warn!(program, "{what}:\n{}", out.stderr.trim_end());
+        diagnostics::record(program, &out.stderr);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn container_args_mount_roots_at_the_same_path() {
+        let args = container_args(
+            "auton-toolchain:latest",
+            &[PathBuf::from("/src/kernels/x86_64"), PathBuf::from("/out")],
+            Path::new("/src"),
+            Some("1000:1000"),
+            "gcc",
+            &["-c".to_string(), "a.c".to_string()],
+        );
+        let joined = args.join(" ");
+        assert!(joined.starts_with("run --rm -v /out:/out -v /src:/src -w /src --user 1000:1000"));
+        assert!(!joined.contains("/src/kernels/x86_64:"));
+        assert!(joined.ends_with("-e CC auton-toolchain:latest gcc -c a.c"));
+    }
+}
@@ -0,0 +1,623 @@
+//! ext2 images for [`crate::mkfs`].
+//!
+//! Revision 1 with 1K blocks, 8192-block groups, 128-byte inodes and only
+//! the `filetype` feature, so even a minimal driver can read the result.
+//! Without `sparse_super` every group carries a superblock and descriptor
+//! backup. Inodes and blocks are handed out in tree order; files reach
+//! through double-indirect blocks (64M), and symlinks under 60 bytes are
+//! stored in the inode. `lost+found` (inode 11) is created as mke2fs would.
+
+use crate::mkfs::{volume_id, MkfsOptions, Node};
+use anyhow::{bail, Result};
+
+pub const BLOCK: usize = 1024;
+pub const BLOCKS_PER_GROUP: u32 = 8192;
+pub const INODE_SIZE: usize = 128;
+pub const MAGIC: u16 = 0xEF53;
+pub const ROOT_INO: u32 = 2;
+pub const LOST_FOUND_INO: u32 = 11;
+pub const FEATURE_INCOMPAT_FILETYPE: u32 = 0x2;
+const SUPERBLOCK_OFFSET: usize = 1024;
+const DESCRIPTOR_SIZE: usize = 32;
+/// Pointers in an indirect block.
+const POINTERS: usize = BLOCK / 4;
+const DIRECT: usize = 12;
+/// Symlink targets shorter than this live in `i_block`.
+const FAST_SYMLINK: usize = 60;
+/// mke2fs drops a last group with fewer free blocks than this.
+const LAST_GROUP_MIN: u32 = 50;
+
+pub const S_IFREG: u16 = 0o100_000;
+pub const S_IFDIR: u16 = 0o040_000;
+pub const S_IFLNK: u16 = 0o120_000;
+const FT_REG: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+pub fn build(tree: &[Node], opts: &MkfsOptions) -> Result<Vec<u8>> {
+    let layout = Layout::new(opts.size)?;
+    let needed = LOST_FOUND_INO as usize + nodes(tree);
+    if needed > layout.inodes() as usize {
+        bail!(
+            "{} files need more than the {} inodes a {}-byte image has",
+            nodes(tree),
+            layout.inodes() - LOST_FOUND_INO,
+            opts.size
+        );
+    }
+    let label = opts.label.as_deref().unwrap_or("");
+    if label.len() > 16 {
+        bail!("ext2 labels are at most 16 bytes, not `{label}`");
+    }
+    let mut fs = Fs {
+        image: vec![0; layout.blocks as usize * BLOCK],
+        used: vec![false; layout.blocks as usize],
+        inodes: vec![false; layout.inodes() as usize + 1],
+        dirs: vec![0; layout.groups as usize],
+        next_block: 0,
+        next_ino: LOST_FOUND_INO + 1,
+        epoch: opts.epoch as u32,
+        layout,
+    };
+    for g in 0..layout.groups {
+        let start = layout.group_start(g);
+        for b in start..start + layout.overhead() {
+            fs.used[b as usize] = true;
+        }
+    }
+    fs.used[0] = true;
+    fs.next_block = layout.group_start(0) + layout.overhead();
+    for ino in 1..=LOST_FOUND_INO {
+        fs.inodes[ino as usize] = true;
+    }
+    fs.dirs[0] = 2;
+    let lost_found = Entry {
+        ino: LOST_FOUND_INO,
+        kind: FT_DIR,
+        name: "lost+found",
+    };
+    fs.dir(tree, ROOT_INO, ROOT_INO, Some(lost_found))?;
+    fs.dir(&[], LOST_FOUND_INO, ROOT_INO, None)?;
+    fs.finish(label, opts);
+    let mut image = fs.image;
+    image.resize(opts.size as usize / BLOCK * BLOCK, 0);
+    Ok(image)
+}
+
+/// Files, directories and symlinks in `tree`.
+fn nodes(tree: &[Node]) -> usize {
+    tree.iter()
+        .map(|n| match n {
+            Node::Dir { children, .. } => 1 + nodes(children),
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Where the groups and their metadata sit.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    /// Blocks in the filesystem, block 0 (the boot block) included.
+    blocks: u32,
+    groups: u32,
+    inodes_per_group: u32,
+    descriptor_blocks: u32,
+}
+
+impl Layout {
+    fn new(size: u64) -> Result<Self> {
+        let Ok(mut blocks) = u32::try_from(size / BLOCK as u64) else {
+            bail!("ext2 images with 1K blocks are under 4T");
+        };
+        if blocks < 64 {
+            bail!("a {size}-byte ext2 image is too small; it needs 64K at least");
+        }
+        loop {
+            let groups = (blocks - 1).div_ceil(BLOCKS_PER_GROUP);
+            // One inode per 8K, like mke2fs, in whole inode-table blocks.
+            let per_group = ((blocks - 1) / groups / 8).clamp(16, BLOCKS_PER_GROUP);
+            let layout = Self {
+                blocks,
+                groups,
+                inodes_per_group: per_group.next_multiple_of((BLOCK / INODE_SIZE) as u32),
+                descriptor_blocks: (groups as usize * DESCRIPTOR_SIZE).div_ceil(BLOCK) as u32,
+            };
+            let last = layout.group_blocks(groups - 1);
+            if last >= layout.overhead() + LAST_GROUP_MIN {
+                return Ok(layout);
+            }
+            if groups == 1 {
+                bail!("a {size}-byte ext2 image is too small for its own metadata");
+            }
+            blocks = layout.group_start(groups - 1);
+        }
+    }
+
+    fn inodes(&self) -> u32 {
+        self.groups * self.inodes_per_group
+    }
+
+    fn group_start(&self, g: u32) -> u32 {
+        1 + g * BLOCKS_PER_GROUP
+    }
+
+    fn group_blocks(&self, g: u32) -> u32 {
+        (self.blocks - self.group_start(g)).min(BLOCKS_PER_GROUP)
+    }
+
+    fn inode_table_blocks(&self) -> u32 {
+        self.inodes_per_group * INODE_SIZE as u32 / BLOCK as u32
+    }
+
+    /// Superblock, descriptors, both bitmaps and the inode table.
+    fn overhead(&self) -> u32 {
+        1 + self.descriptor_blocks + 2 + self.inode_table_blocks()
+    }
+
+    fn block_bitmap(&self, g: u32) -> u32 {
+        self.group_start(g) + 1 + self.descriptor_blocks
+    }
+
+    fn inode_bitmap(&self, g: u32) -> u32 {
+        self.block_bitmap(g) + 1
+    }
+
+    fn inode_table(&self, g: u32) -> u32 {
+        self.block_bitmap(g) + 2
+    }
+
+    /// Byte offset of inode `ino`.
+    fn inode_offset(&self, ino: u32) -> usize {
+        let (g, index) = (
+            (ino - 1) / self.inodes_per_group,
+            (ino - 1) % self.inodes_per_group,
+        );
+        self.inode_table(g) as usize * BLOCK + index as usize * INODE_SIZE
+    }
+}
+
+/// A directory entry.
+struct Entry<'a> {
+    ino: u32,
+    kind: u8,
+    name: &'a str,
+}
+
+struct Fs {
+    image: Vec<u8>,
+    used: Vec<bool>,
+    /// Indexed by inode number; 0 is unused.
+    inodes: Vec<bool>,
+    /// Directories per group.
+    dirs: Vec<u16>,
+    next_block: u32,
+    next_ino: u32,
+    epoch: u32,
+    layout: Layout,
+}
+
+impl Fs {
+    fn alloc_block(&mut self) -> Result<u32> {
+        while (self.next_block as usize) < self.used.len() && self.used[self.next_block as usize] {
+            self.next_block += 1;
+        }
+        if self.next_block >= self.layout.blocks {
+            bail!("the tree does not fit in the image (try a larger --size)");
+        }
+        self.used[self.next_block as usize] = true;
+        self.next_block += 1;
+        Ok(self.next_block - 1)
+    }
+
+    fn alloc_inode(&mut self, dir: bool) -> u32 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.inodes[ino as usize] = true;
+        if dir {
+            self.dirs[((ino - 1) / self.layout.inodes_per_group) as usize] += 1;
+        }
+        ino
+    }
+
+    fn block_mut(&mut self, block: u32) -> &mut [u8] {
+        &mut self.image[block as usize * BLOCK..][..BLOCK]
+    }
+
+    /// Store `data` in fresh blocks: the inode's `i_block` and how many
+    /// blocks that took, indirect ones included.
+    fn store(&mut self, data: &[u8]) -> Result<([u32; 15], u32)> {
+        let chunks: Vec<&[u8]> = data.chunks(BLOCK).collect();
+        if chunks.len() > DIRECT + POINTERS + POINTERS * POINTERS {
+            bail!(
+                "ext2 files here reach through double-indirect blocks, {} bytes at most",
+                (DIRECT + POINTERS + POINTERS * POINTERS) * BLOCK
+            );
+        }
+        let mut i_block = [0u32; 15];
+        let mut count = 0;
+        let mut rest = chunks.as_slice();
+        for slot in i_block.iter_mut().take(DIRECT) {
+            let Some((chunk, tail)) = rest.split_first() else {
+                break;
+            };
+            *slot = self.data_block(chunk)?;
+            count += 1;
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            let (n, block) = self.indirect(rest)?;
+            i_block[DIRECT] = block;
+            count += n;
+            rest = &rest[rest.len().min(POINTERS)..];
+        }
+        if !rest.is_empty() {
+            let double = self.alloc_block()?;
+            count += 1;
+            let mut pointers = Vec::new();
+            for group in rest.chunks(POINTERS) {
+                let (n, block) = self.indirect(group)?;
+                pointers.push(block);
+                count += n;
+            }
+            self.write_pointers(double, &pointers);
+            i_block[DIRECT + 1] = double;
+        }
+        Ok((i_block, count))
+    }
+
+    fn data_block(&mut self, chunk: &[u8]) -> Result<u32> {
+        let block = self.alloc_block()?;
+        self.block_mut(block)[..chunk.len()].copy_from_slice(chunk);
+        Ok(block)
+    }
+
+    /// An indirect block and the first [`POINTERS`] of `chunks` behind it;
+    /// returns the blocks taken and the indirect block.
+    fn indirect(&mut self, chunks: &[&[u8]]) -> Result<(u32, u32)> {
+        let block = self.alloc_block()?;
+        let mut pointers = Vec::new();
+        for chunk in chunks.iter().take(POINTERS) {
+            pointers.push(self.data_block(chunk)?);
+        }
+        self.write_pointers(block, &pointers);
+        Ok((pointers.len() as u32 + 1, block))
+    }
+
+    fn write_pointers(&mut self, block: u32, pointers: &[u32]) {
+        let bytes: Vec<u8> = pointers.iter().flat_map(|p| p.to_le_bytes()).collect();
+        self.block_mut(block)[..bytes.len()].copy_from_slice(&bytes);
+    }
+
+    fn inode(
+        &mut self,
+        ino: u32,
+        mode: u16,
+        size: u32,
+        links: u16,
+        i_block: [u32; 15],
+        blocks: u32,
+    ) {
+        let at = self.layout.inode_offset(ino);
+        let epoch = self.epoch.to_le_bytes();
+        let inode = &mut self.image[at..at + INODE_SIZE];
+        inode[0..2].copy_from_slice(&mode.to_le_bytes());
+        inode[4..8].copy_from_slice(&size.to_le_bytes());
+        inode[8..12].copy_from_slice(&epoch); // accessed
+        inode[12..16].copy_from_slice(&epoch); // changed
+        inode[16..20].copy_from_slice(&epoch); // modified
+        inode[26..28].copy_from_slice(&links.to_le_bytes());
+        inode[28..32].copy_from_slice(&(blocks * (BLOCK / 512) as u32).to_le_bytes());
+        for (i, b) in i_block.iter().enumerate() {
+            inode[40 + 4 * i..44 + 4 * i].copy_from_slice(&b.to_le_bytes());
+        }
+    }
+
+    /// Write the directory `ino` holding `nodes`, then everything in it.
+    /// `extra` is the root's `lost+found`.
+    fn dir(&mut self, nodes: &[Node], ino: u32, parent: u32, extra: Option<Entry>) -> Result<()> {
+        let inos: Vec<u32> = nodes
+            .iter()
+            .map(|n| self.alloc_inode(matches!(n, Node::Dir { .. })))
+            .collect();
+        let mut entries = vec![
+            Entry {
+                ino,
+                kind: FT_DIR,
+                name: ".",
+            },
+            Entry {
+                ino: parent,
+                kind: FT_DIR,
+                name: "..",
+            },
+        ];
+        entries.extend(extra);
+        for (node, &ino) in nodes.iter().zip(&inos) {
+            if node.name().len() > 255 {
+                bail!("`{}` is longer than ext2's 255-byte names", node.name());
+            }
+            let kind = match node {
+                Node::File { .. } => FT_REG,
+                Node::Dir { .. } => FT_DIR,
+                Node::Symlink { .. } => FT_SYMLINK,
+            };
+            entries.push(Entry {
+                ino,
+                kind,
+                name: node.name(),
+            });
+        }
+        let subdirs = entries[2..].iter().filter(|e| e.kind == FT_DIR).count();
+        let data = dir_blocks(&entries);
+        let (i_block, blocks) = self.store(&data)?;
+        let links = 2 + subdirs as u16;
+        self.inode(
+            ino,
+            S_IFDIR | 0o755,
+            data.len() as u32,
+            links,
+            i_block,
+            blocks,
+        );
+
+        for (node, &child) in nodes.iter().zip(&inos) {
+            match node {
+                Node::File {
+                    data, executable, ..
+                } => {
+                    let (i_block, blocks) = self.store(data)?;
+                    let perm = if *executable { 0o755 } else { 0o644 };
+                    self.inode(child, S_IFREG | perm, data.len() as u32, 1, i_block, blocks);
+                }
+                Node::Dir { children, .. } => self.dir(children, child, ino, None)?,
+                Node::Symlink { name, target } => {
+                    let (i_block, blocks) = if target.len() < FAST_SYMLINK {
+                        let mut inline = [0u8; FAST_SYMLINK];
+                        inline[..target.len()].copy_from_slice(target.as_bytes());
+                        let mut i_block = [0u32; 15];
+                        for (slot, bytes) in i_block.iter_mut().zip(inline.chunks(4)) {
+                            *slot = u32::from_le_bytes(bytes.try_into().expect("4 bytes"));
+                        }
+                        (i_block, 0)
+                    } else if target.len() <= BLOCK {
+                        self.store(target.as_bytes())?
+                    } else {
+                        bail!("{name}: symlink targets are at most {BLOCK} bytes");
+                    };
+                    self.inode(
+                        child,
+                        S_IFLNK | 0o777,
+                        target.len() as u32,
+                        1,
+                        i_block,
+                        blocks,
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Bitmaps, group descriptors and superblocks, now that everything is
+    /// placed.
+    fn finish(&mut self, label: &str, opts: &MkfsOptions) {
+        let layout = self.layout;
+        let per_group = layout.inodes_per_group as usize;
+        let mut descriptors = vec![0u8; layout.descriptor_blocks as usize * BLOCK];
+        let (mut free_blocks, mut free_inodes) = (0u32, 0u32);
+        for g in 0..layout.groups {
+            let start = layout.group_start(g) as usize;
+            let count = layout.group_blocks(g) as usize;
+            // Bits past the group's end, and past its inodes, are set.
+            let mut bitmap = vec![0xFFu8; BLOCK];
+            let mut free = 0u16;
+            for i in 0..count {
+                if self.used[start + i] {
+                    continue;
+                }
+                bitmap[i / 8] &= !(1 << (i % 8));
+                free += 1;
+            }
+            self.block_mut(layout.block_bitmap(g))
+                .copy_from_slice(&bitmap);
+            let mut bitmap = vec![0xFFu8; BLOCK];
+            let mut free_ino = 0u16;
+            for i in 0..per_group {
+                if self.inodes[g as usize * per_group + i + 1] {
+                    continue;
+                }
+                bitmap[i / 8] &= !(1 << (i % 8));
+                free_ino += 1;
+            }
+            self.block_mut(layout.inode_bitmap(g))
+                .copy_from_slice(&bitmap);
+            let d = &mut descriptors[g as usize * DESCRIPTOR_SIZE..][..DESCRIPTOR_SIZE];
+            d[0..4].copy_from_slice(&layout.block_bitmap(g).to_le_bytes());
+            d[4..8].copy_from_slice(&layout.inode_bitmap(g).to_le_bytes());
+            d[8..12].copy_from_slice(&layout.inode_table(g).to_le_bytes());
+            d[12..14].copy_from_slice(&free.to_le_bytes());
+            d[14..16].copy_from_slice(&free_ino.to_le_bytes());
+            d[16..18].copy_from_slice(&self.dirs[g as usize].to_le_bytes());
+            free_blocks += u32::from(free);
+            free_inodes += u32::from(free_ino);
+        }
+
+        let mut sb = vec![0u8; BLOCK];
+        let put =
+            |sb: &mut [u8], at: usize, v: u32| sb[at..at + 4].copy_from_slice(&v.to_le_bytes());
+        let put16 =
+            |sb: &mut [u8], at: usize, v: u16| sb[at..at + 2].copy_from_slice(&v.to_le_bytes());
+        put(&mut sb, 0, layout.inodes());
+        put(&mut sb, 4, layout.blocks);
+        put(&mut sb, 12, free_blocks);
+        put(&mut sb, 16, free_inodes);
+        put(&mut sb, 20, 1); // first data block
+        put(&mut sb, 32, BLOCKS_PER_GROUP);
+        put(&mut sb, 36, BLOCKS_PER_GROUP); // fragments per group
+        put(&mut sb, 40, layout.inodes_per_group);
+        put(&mut sb, 48, self.epoch); // written
+        put16(&mut sb, 54, u16::MAX); // no mount-count checks
+        put16(&mut sb, 56, MAGIC);
+        put16(&mut sb, 58, 1); // clean
+        put16(&mut sb, 60, 1); // continue on errors
+        put(&mut sb, 64, self.epoch); // last checked
+        put(&mut sb, 76, 1); // dynamic revision
+        put(&mut sb, 84, LOST_FOUND_INO); // first non-reserved inode
+        put16(&mut sb, 88, INODE_SIZE as u16);
+        put(&mut sb, 96, FEATURE_INCOMPAT_FILETYPE);
+        sb[104..120].copy_from_slice(&volume_id(opts));
+        sb[120..120 + label.len()].copy_from_slice(label.as_bytes());
+
+        for g in 0..layout.groups {
+            put16(&mut sb, 90, g as u16);
+            let start = layout.group_start(g) as usize * BLOCK;
+            let at = if g == 0 { SUPERBLOCK_OFFSET } else { start };
+            self.image[at..at + BLOCK].copy_from_slice(&sb);
+            self.image[start + BLOCK..][..descriptors.len()].copy_from_slice(&descriptors);
+        }
+    }
+}
+
+/// Directory entries packed into blocks, none crossing a block boundary
+/// and the last in each block stretched to its end.
+fn dir_blocks(entries: &[Entry]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::new();
+    let mut block_start = 0;
+    let mut last = 0;
+    for e in entries {
+        let len = (8 + e.name.len()).next_multiple_of(4);
+        if out.len() + len > block_start + BLOCK {
+            stretch(&mut out, last, block_start + BLOCK);
+            out.resize(block_start + BLOCK, 0);
+            block_start += BLOCK;
+        }
+        last = out.len();
+        out.extend(e.ino.to_le_bytes());
+        out.extend((len as u16).to_le_bytes());
+        out.push(e.name.len() as u8);
+        out.push(e.kind);
+        out.extend(e.name.as_bytes());
+        out.resize(last + len, 0);
+    }
+    stretch(&mut out, last, block_start + BLOCK);
+    out.resize(block_start + BLOCK, 0);
+    out
+}
+
+fn stretch(out: &mut [u8], entry: usize, end: usize) {
+    out[entry + 4..entry + 6].copy_from_slice(&((end - entry) as u16).to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mkfs::Filesystem;
+
+    fn opts() -> MkfsOptions {
+        MkfsOptions {
+            fs: Filesystem::Ext2,
+            size: 20 * 1024 * 1024,
+            label: Some("fixture".into()),
+            epoch: 1_700_000_000,
+        }
+    }
+
+    fn u32_at(b: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+    }
+
+    /// A minimal reader: inode `ino`'s mode and contents.
+    fn read_inode(image: &[u8], ino: u32) -> (u16, Vec<u8>) {
+        let sb = &image[SUPERBLOCK_OFFSET..];
+        let per_group = u32_at(sb, 40);
+        let g = ((ino - 1) / per_group) as usize;
+        let table = u32_at(image, 2 * BLOCK + g * DESCRIPTOR_SIZE + 8) as usize;
+        let inode = &image[table * BLOCK + ((ino - 1) % per_group) as usize * INODE_SIZE..];
+        let mode = u16::from_le_bytes([inode[0], inode[1]]);
+        let size = u32_at(inode, 4) as usize;
+        if mode & 0o170_000 == S_IFLNK && u32_at(inode, 28) == 0 {
+            return (mode, inode[40..40 + size].to_vec());
+        }
+        let block = |b: u32| &image[b as usize * BLOCK..][..BLOCK];
+        let pointers = |b: u32| (0..POINTERS).map(move |i| u32_at(block(b), 4 * i));
+        let mut blocks: Vec<u32> = (0..DIRECT).map(|i| u32_at(inode, 40 + 4 * i)).collect();
+        blocks.extend(pointers(u32_at(inode, 88)));
+        for ind in pointers(u32_at(inode, 92)).filter(|&b| b != 0) {
+            blocks.extend(pointers(ind));
+        }
+        let mut data: Vec<u8> = blocks
+            .into_iter()
+            .take(size.div_ceil(BLOCK))
+            .flat_map(|b| block(b).to_vec())
+            .collect();
+        data.truncate(size);
+        (mode, data)
+    }
+
+    fn lookup(image: &[u8], dir: u32, name: &str) -> Option<u32> {
+        let (_, data) = read_inode(image, dir);
+        let mut at = 0;
+        while at < data.len() {
+            let rec_len = u16::from_le_bytes([data[at + 4], data[at + 5]]) as usize;
+            let len = data[at + 6] as usize;
+            if &data[at + 8..at + 8 + len] == name.as_bytes() {
+                return Some(u32_at(&data, at));
+            }
+            at += rec_len;
+        }
+        None
+    }
+
+    #[test]
+    fn files_read_back_through_indirect_blocks() {
+        let big: Vec<u8> = (0..300 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let tree = vec![
+            Node::Dir {
+                name: "bin".into(),
+                children: vec![Node::File {
+                    name: "init".into(),
+                    data: big.clone(),
+                    executable: true,
+                }],
+            },
+            Node::File {
+                name: "hello.txt".into(),
+                data: b"hello".to_vec(),
+                executable: false,
+            },
+            Node::Symlink {
+                name: "init".into(),
+                target: "bin/init".into(),
+            },
+        ];
+        let image = build(&tree, &opts()).unwrap();
+        assert_eq!(image.len(), 20 * 1024 * 1024);
+        assert_eq!(&image[SUPERBLOCK_OFFSET + 56..][..2], &MAGIC.to_le_bytes());
+        assert_eq!(&image[SUPERBLOCK_OFFSET + 120..][..8], b"fixture\0");
+        assert!(lookup(&image, ROOT_INO, "lost+found") == Some(LOST_FOUND_INO));
+        let bin = lookup(&image, ROOT_INO, "bin").unwrap();
+        let (mode, data) = read_inode(&image, lookup(&image, bin, "init").unwrap());
+        assert_eq!(mode, S_IFREG | 0o755);
+        assert!(data == big);
+        let hello = lookup(&image, ROOT_INO, "hello.txt").unwrap();
+        assert_eq!(
+            read_inode(&image, hello),
+            (S_IFREG | 0o644, b"hello".to_vec())
+        );
+        let link = lookup(&image, ROOT_INO, "init").unwrap();
+        assert_eq!(
+            read_inode(&image, link),
+            (S_IFLNK | 0o777, b"bin/init".to_vec())
+        );
+        assert!(build(&tree, &opts()).unwrap() == image);
+    }
+
+    #[test]
+    fn a_short_last_group_is_dropped() {
+        let layout = Layout::new(8193 * 1024 + 20 * 1024).unwrap();
+        assert_eq!((layout.groups, layout.blocks), (1, 8193));
+        let layout = Layout::new(3 * 8192 * 1024).unwrap();
+        assert_eq!((layout.groups, layout.blocks), (3, 3 * 8192));
+        assert!(Layout::new(32 * 1024).is_err());
+    }
+}
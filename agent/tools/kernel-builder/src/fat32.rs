@@ -0,0 +1,572 @@
+//! FAT32 images for [`crate::mkfs`].
+//!
+//! 512-byte sectors, 32 reserved sectors (the boot sector, FSInfo at 1 and
+//! their backups at 6 and 7), two FATs and the root directory at cluster
+//! 2. Clusters are allocated contiguously in tree order, so every chain
+//! is a run. Names that are not already upper-case 8.3 get long-name
+//! entries and a `NAME~N.EXT` alias. The cluster size follows Microsoft's
+//! table for the image size, which must give the 65525 clusters FAT32
+//! needs (a 33M image at least).
+
+use crate::mkfs::{civil, volume_id, MkfsOptions, Node};
+use anyhow::{bail, Result};
+
+pub const SECTOR: usize = 512;
+const RESERVED_SECTORS: u32 = 32;
+const FATS: u32 = 2;
+const FSINFO_SECTOR: usize = 1;
+const BACKUP_BOOT_SECTOR: usize = 6;
+/// Fewer clusters than this and drivers take the volume for FAT16.
+pub const MIN_CLUSTERS: u32 = 65_525;
+const ROOT_CLUSTER: u32 = 2;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+const MEDIA: u8 = 0xF8;
+
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = 0x0F;
+const ENTRY: usize = 32;
+/// UTF-16 units per long-name entry.
+const LFN_CHARS: usize = 13;
+
+/// Punctuation allowed in short names besides letters and digits.
+const SHORT_NAME_PUNCTUATION: &str = "!#$%&'()-@^_`{}~";
+
+pub fn build(tree: &[Node], opts: &MkfsOptions) -> Result<Vec<u8>> {
+    let Ok(total) = u32::try_from(opts.size / SECTOR as u64) else {
+        bail!("FAT32 images are at most 2T");
+    };
+    let spc = sectors_per_cluster(opts.size);
+    // Microsoft's FAT size formula; it can overestimate by a sector or so.
+    let fat_sectors = (total.saturating_sub(RESERVED_SECTORS)).div_ceil((256 * spc + FATS) / 2);
+    let data_start = RESERVED_SECTORS + FATS * fat_sectors;
+    let clusters = total.saturating_sub(data_start) / spc;
+    if clusters < MIN_CLUSTERS {
+        bail!(
+            "a {}-byte FAT32 image has {clusters} clusters, fewer than the {MIN_CLUSTERS} FAT32 needs (try --size 64M)",
+            opts.size
+        );
+    }
+    let label = short_label(opts.label.as_deref())?;
+    let mut volume = Volume {
+        image: vec![0; total as usize * SECTOR],
+        fat: vec![0; clusters as usize + 2],
+        next: ROOT_CLUSTER,
+        cluster_bytes: spc as usize * SECTOR,
+        data_start: data_start as usize * SECTOR,
+        stamp: Stamp::new(opts.epoch),
+    };
+    volume.fat[0] = 0x0FFF_FF00 | u32::from(MEDIA);
+    volume.fat[1] = END_OF_CHAIN;
+    let root = volume.alloc(dir_len(tree, true, opts.label.is_some())?)?;
+    debug_assert_eq!(root, ROOT_CLUSTER);
+    volume.dir(tree, root, None, opts.label.is_some().then_some(&label))?;
+
+    let used = volume.next - ROOT_CLUSTER;
+    let boot = boot_sector(total, spc, fat_sectors, &label, opts);
+    let fsinfo = fsinfo(clusters - used, volume.next);
+    let image = &mut volume.image;
+    image[..SECTOR].copy_from_slice(&boot);
+    image[FSINFO_SECTOR * SECTOR..][..SECTOR].copy_from_slice(&fsinfo);
+    image[BACKUP_BOOT_SECTOR * SECTOR..][..SECTOR].copy_from_slice(&boot);
+    image[(BACKUP_BOOT_SECTOR + 1) * SECTOR..][..SECTOR].copy_from_slice(&fsinfo);
+    let fat: Vec<u8> = volume.fat.iter().flat_map(|e| e.to_le_bytes()).collect();
+    for i in 0..FATS as usize {
+        let at = (RESERVED_SECTORS as usize + i * fat_sectors as usize) * SECTOR;
+        image[at..at + fat.len()].copy_from_slice(&fat);
+    }
+    Ok(volume.image)
+}
+
+/// Microsoft's cluster size for a FAT32 volume of `size` bytes.
+fn sectors_per_cluster(size: u64) -> u32 {
+    const M: u64 = 1024 * 1024;
+    match size {
+        s if s <= 260 * M => 1,
+        s if s <= 8 * 1024 * M => 8,
+        s if s <= 16 * 1024 * M => 16,
+        s if s <= 32 * 1024 * M => 32,
+        _ => 64,
+    }
+}
+
+fn boot_sector(
+    total: u32,
+    spc: u32,
+    fat_sectors: u32,
+    label: &[u8; 11],
+    opts: &MkfsOptions,
+) -> Vec<u8> {
+    let mut b = vec![0u8; SECTOR];
+    b[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    b[3..11].copy_from_slice(b"AUTON   ");
+    b[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+    b[13] = spc as u8;
+    b[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    b[16] = FATS as u8;
+    b[21] = MEDIA;
+    b[24..26].copy_from_slice(&32u16.to_le_bytes()); // sectors per track
+    b[26..28].copy_from_slice(&64u16.to_le_bytes()); // heads
+    b[32..36].copy_from_slice(&total.to_le_bytes());
+    b[36..40].copy_from_slice(&fat_sectors.to_le_bytes());
+    b[44..48].copy_from_slice(&ROOT_CLUSTER.to_le_bytes());
+    b[48..50].copy_from_slice(&(FSINFO_SECTOR as u16).to_le_bytes());
+    b[50..52].copy_from_slice(&(BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+    b[64] = 0x80; // drive number
+    b[66] = 0x29; // extended boot signature
+    b[67..71].copy_from_slice(&volume_id(opts)[..4]);
+    b[71..82].copy_from_slice(label);
+    b[82..90].copy_from_slice(b"FAT32   ");
+    b[510..512].copy_from_slice(&[0x55, 0xAA]);
+    b
+}
+
+fn fsinfo(free: u32, next: u32) -> Vec<u8> {
+    let mut s = vec![0u8; SECTOR];
+    s[..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    s[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    s[488..492].copy_from_slice(&free.to_le_bytes());
+    s[492..496].copy_from_slice(&next.to_le_bytes());
+    s[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+    s
+}
+
+/// A FAT date and time.
+#[derive(Debug, Clone, Copy)]
+struct Stamp {
+    date: u16,
+    time: u16,
+}
+
+impl Stamp {
+    /// FAT dates run from 1980 to 2107; `epoch` is clamped to those.
+    fn new(epoch: u64) -> Self {
+        const FAT_EPOCH: u64 = 315_532_800; // 1980-01-01
+        const FAT_END: u64 = 4_354_819_199; // 2107-12-31 23:59:59
+        let (y, mo, d, h, mi, s) = civil(epoch.clamp(FAT_EPOCH, FAT_END));
+        Self {
+            date: (((y - 1980) << 9) | (mo << 5) | d) as u16,
+            time: ((h << 11) | (mi << 5) | (s / 2)) as u16,
+        }
+    }
+}
+
+struct Volume {
+    image: Vec<u8>,
+    fat: Vec<u32>,
+    /// The next free cluster.
+    next: u32,
+    cluster_bytes: usize,
+    /// Byte offset of cluster 2.
+    data_start: usize,
+    stamp: Stamp,
+}
+
+impl Volume {
+    /// A run of clusters for `len` bytes, chained in the FAT; 0 for none.
+    fn alloc(&mut self, len: usize) -> Result<u32> {
+        let n = len.div_ceil(self.cluster_bytes) as u32;
+        if n == 0 {
+            return Ok(0);
+        }
+        let start = self.next;
+        if (start + n) as usize > self.fat.len() {
+            bail!("the tree does not fit in the image (try a larger --size)");
+        }
+        for c in start..start + n {
+            self.fat[c as usize] = if c + 1 == start + n {
+                END_OF_CHAIN
+            } else {
+                c + 1
+            };
+        }
+        self.next += n;
+        Ok(start)
+    }
+
+    fn write(&mut self, cluster: u32, data: &[u8]) {
+        let at = self.data_start + (cluster - ROOT_CLUSTER) as usize * self.cluster_bytes;
+        self.image[at..at + data.len()].copy_from_slice(data);
+    }
+
+    /// Write the directory at `cluster` holding `nodes`, then its
+    /// subdirectories. `parent` is `None` for the root.
+    fn dir(
+        &mut self,
+        nodes: &[Node],
+        cluster: u32,
+        parent: Option<u32>,
+        label: Option<&[u8; 11]>,
+    ) -> Result<()> {
+        let mut entries = Vec::new();
+        if let Some(parent) = parent {
+            // `..` of a directory in the root points at cluster 0.
+            let parent = if parent == ROOT_CLUSTER { 0 } else { parent };
+            entries.push(self.entry(b".          ", ATTR_DIRECTORY, cluster, 0));
+            entries.push(self.entry(b"..         ", ATTR_DIRECTORY, parent, 0));
+        }
+        if let Some(label) = label {
+            entries.push(self.entry(label, ATTR_VOLUME_ID, 0, 0));
+        }
+        let mut subdirs = Vec::new();
+        for (node, short) in nodes.iter().zip(short_names(nodes)?) {
+            let (start, size, attr) = match node {
+                Node::File { data, .. } => {
+                    let Ok(size) = u32::try_from(data.len()) else {
+                        bail!("{}: FAT32 files are under 4G", node.name());
+                    };
+                    let start = self.alloc(data.len())?;
+                    if start != 0 {
+                        self.write(start, data);
+                    }
+                    (start, size, ATTR_ARCHIVE)
+                }
+                Node::Dir { children, .. } => {
+                    let start = self.alloc(dir_len(children, false, false)?)?;
+                    subdirs.push((start, children));
+                    (start, 0, ATTR_DIRECTORY)
+                }
+                Node::Symlink { name, .. } => bail!("{name}: FAT has no symlinks"),
+            };
+            if short.long {
+                entries.extend(long_name_entries(node.name(), &short.name));
+            }
+            entries.push(self.entry(&short.name, attr, start, size));
+        }
+        self.write(cluster, &entries.concat());
+        for (start, children) in subdirs {
+            self.dir(children, start, Some(cluster), None)?;
+        }
+        Ok(())
+    }
+
+    fn entry(&self, name: &[u8; 11], attr: u8, cluster: u32, size: u32) -> Vec<u8> {
+        let mut e = vec![0u8; ENTRY];
+        e[..11].copy_from_slice(name);
+        e[11] = attr;
+        let (date, time) = (self.stamp.date.to_le_bytes(), self.stamp.time.to_le_bytes());
+        e[14..16].copy_from_slice(&time); // created
+        e[16..18].copy_from_slice(&date);
+        e[18..20].copy_from_slice(&date); // accessed
+        e[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        e[22..24].copy_from_slice(&time); // written
+        e[24..26].copy_from_slice(&date);
+        e[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        e[28..32].copy_from_slice(&size.to_le_bytes());
+        e
+    }
+}
+
+/// Bytes of directory entries a directory holding `nodes` needs.
+fn dir_len(nodes: &[Node], root: bool, label: bool) -> Result<usize> {
+    let mut entries = if root { usize::from(label) } else { 2 };
+    for (node, short) in nodes.iter().zip(short_names(nodes)?) {
+        entries += 1;
+        if short.long {
+            entries += long_name_len(node.name()).div_ceil(LFN_CHARS);
+        }
+    }
+    // Even an empty root keeps one cluster.
+    Ok(entries.max(1) * ENTRY)
+}
+
+/// A node's 8.3 name, and whether it needs long-name entries too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShortName {
+    pub name: [u8; 11],
+    pub long: bool,
+}
+
+/// Short names for `nodes`, unique among them.
+pub fn short_names(nodes: &[Node]) -> Result<Vec<ShortName>> {
+    let mut taken: Vec<[u8; 11]> = nodes
+        .iter()
+        .filter_map(|n| exact_short_name(n.name()))
+        .collect();
+    let mut names = Vec::new();
+    for node in nodes {
+        let name = node.name();
+        check_long_name(name)?;
+        if let Some(short) = exact_short_name(name) {
+            names.push(ShortName {
+                name: short,
+                long: false,
+            });
+            continue;
+        }
+        let (base, ext) = match name.rsplit_once('.') {
+            Some((base, ext)) if !base.is_empty() => (base, ext),
+            _ => (name, ""),
+        };
+        let base = short_chars(base);
+        let ext = short_chars(ext);
+        let alias = (1..1_000_000)
+            .map(|n| {
+                let tail = format!("~{n}");
+                let keep = base.len().min(8 - tail.len());
+                let mut short = [b' '; 11];
+                let base = if base.is_empty() { "_" } else { &base[..keep] };
+                short[..base.len()].copy_from_slice(base.as_bytes());
+                short[base.len()..base.len() + tail.len()].copy_from_slice(tail.as_bytes());
+                short[8..8 + ext.len().min(3)].copy_from_slice(&ext.as_bytes()[..ext.len().min(3)]);
+                short
+            })
+            .find(|s| !taken.contains(s))
+            .expect("an unused alias");
+        taken.push(alias);
+        names.push(ShortName {
+            name: alias,
+            long: true,
+        });
+    }
+    Ok(names)
+}
+
+/// `name` as an 11-byte short name, if it already is an upper-case 8.3
+/// name.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    let ok = |s: &str, max: usize| s.len() <= max && s.chars().all(is_short_char);
+    if base.is_empty() || !ok(base, 8) || !ok(ext, 3) || (name.contains('.') && ext.is_empty()) {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+fn is_short_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_NAME_PUNCTUATION.contains(c)
+}
+
+/// `s` upper-cased with what short names cannot hold dropped or replaced.
+fn short_chars(s: &str) -> String {
+    s.chars()
+        .filter(|&c| c != ' ' && c != '.')
+        .map(|c| c.to_ascii_uppercase())
+        .map(|c| if is_short_char(c) { c } else { '_' })
+        .collect()
+}
+
+fn check_long_name(name: &str) -> Result<()> {
+    if name
+        .chars()
+        .any(|c| c.is_control() || "\"*/:<>?\\|".contains(c))
+    {
+        bail!("`{name}` cannot be a FAT file name");
+    }
+    if long_name_len(name) > 255 {
+        bail!("`{name}` is longer than FAT's 255 characters");
+    }
+    Ok(())
+}
+
+fn long_name_len(name: &str) -> usize {
+    name.encode_utf16().count()
+}
+
+/// The long-name entries for `name`, last part first, as they precede the
+/// short entry `short`.
+pub fn long_name_entries(name: &str, short: &[u8; 11]) -> Vec<Vec<u8>> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if !units.len().is_multiple_of(LFN_CHARS) {
+        units.push(0);
+        units.resize(units.len().div_ceil(LFN_CHARS) * LFN_CHARS, 0xFFFF);
+    }
+    let checksum = short_checksum(short);
+    let parts = units.len() / LFN_CHARS;
+    (0..parts)
+        .rev()
+        .map(|i| {
+            let chars = &units[i * LFN_CHARS..(i + 1) * LFN_CHARS];
+            let mut e = vec![0u8; ENTRY];
+            e[0] = (i + 1) as u8 | if i + 1 == parts { 0x40 } else { 0 };
+            e[11] = ATTR_LONG_NAME;
+            e[13] = checksum;
+            let spans = [(1, 0..5), (14, 5..11), (28, 11..13)];
+            for (at, range) in spans {
+                for (k, unit) in chars[range].iter().enumerate() {
+                    e[at + 2 * k..at + 2 * k + 2].copy_from_slice(&unit.to_le_bytes());
+                }
+            }
+            e
+        })
+        .collect()
+}
+
+/// The checksum long-name entries carry of their short name.
+pub fn short_checksum(short: &[u8; 11]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// The boot sector's label, `NO NAME` without one.
+fn short_label(label: Option<&str>) -> Result<[u8; 11]> {
+    let label = label.unwrap_or("NO NAME");
+    let upper = label.to_ascii_uppercase();
+    if upper.len() > 11 || !upper.chars().all(|c| c == ' ' || is_short_char(c)) {
+        bail!(
+            "FAT labels are up to 11 letters, digits and `{SHORT_NAME_PUNCTUATION}`, not `{label}`"
+        );
+    }
+    let mut out = [b' '; 11];
+    out[..upper.len()].copy_from_slice(upper.as_bytes());
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mkfs::Filesystem;
+
+    fn opts() -> MkfsOptions {
+        MkfsOptions {
+            fs: Filesystem::Fat32,
+            size: 34 * 1024 * 1024,
+            label: Some("fixture".into()),
+            epoch: 1_700_000_000,
+        }
+    }
+
+    fn file(name: &str, data: &[u8]) -> Node {
+        Node::File {
+            name: name.into(),
+            data: data.to_vec(),
+            executable: false,
+        }
+    }
+
+    /// A minimal reader: the names and contents of the directory at
+    /// `cluster`, long names preferred.
+    fn read_dir(image: &[u8], cluster: u32) -> Vec<(String, u8, u32, Vec<u8>)> {
+        let spc = image[13] as usize;
+        let reserved = u16::from_le_bytes([image[14], image[15]]) as usize;
+        let fat_sectors = u32::from_le_bytes(image[36..40].try_into().unwrap()) as usize;
+        let fat_at = reserved * SECTOR;
+        let data_at = (reserved + 2 * fat_sectors) * SECTOR;
+        let chain = |mut c: u32| {
+            let mut bytes = Vec::new();
+            while (2..0x0FFF_FFF8).contains(&c) {
+                let at = data_at + (c as usize - 2) * spc * SECTOR;
+                bytes.extend(&image[at..at + spc * SECTOR]);
+                let e = fat_at + c as usize * 4;
+                c = u32::from_le_bytes(image[e..e + 4].try_into().unwrap()) & 0x0FFF_FFFF;
+            }
+            bytes
+        };
+        let mut out = Vec::new();
+        let mut long: Vec<u16> = Vec::new();
+        for e in chain(cluster).chunks(ENTRY) {
+            if e[0] == 0 {
+                break;
+            }
+            if e[11] == ATTR_LONG_NAME {
+                let mut part: Vec<u16> = [1..11, 14..26, 28..32]
+                    .into_iter()
+                    .flat_map(|r| {
+                        e[r].chunks(2)
+                            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                            .collect::<Vec<_>>()
+                    })
+                    .collect();
+                part.append(&mut long);
+                long = part;
+                continue;
+            }
+            let short = String::from_utf8_lossy(&e[..11]).into_owned();
+            let name = if long.is_empty() {
+                short
+            } else {
+                let end = long.iter().position(|&u| u == 0).unwrap_or(long.len());
+                String::from_utf16(&long[..end]).unwrap()
+            };
+            long.clear();
+            let start = (u32::from(u16::from_le_bytes([e[20], e[21]])) << 16)
+                | u32::from(u16::from_le_bytes([e[26], e[27]]));
+            let size = u32::from_le_bytes(e[28..32].try_into().unwrap()) as usize;
+            let mut data = chain(start);
+            if e[11] & ATTR_DIRECTORY == 0 {
+                data.truncate(size);
+            }
+            out.push((name, e[11], start, data));
+        }
+        out
+    }
+
+    #[test]
+    fn files_read_back_by_their_long_names() {
+        let big: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        let tree = vec![
+            file("README.TXT", b"short name"),
+            Node::Dir {
+                name: "boot".into(),
+                children: vec![file("kernel config.txt", b"x=1"), file("empty", b"")],
+            },
+            file("big.bin", &big),
+        ];
+        let image = build(&tree, &opts()).unwrap();
+        assert_eq!(&image[82..90], b"FAT32   ");
+        let root = read_dir(&image, ROOT_CLUSTER);
+        let names: Vec<_> = root.iter().map(|(n, _, _, _)| n.as_str()).collect();
+        assert_eq!(names, ["FIXTURE    ", "README  TXT", "boot", "big.bin"]);
+        assert_eq!(root[1].3, b"short name");
+        assert_eq!(root[3].3, big);
+        let (_, attr, start, _) = &root[2];
+        assert_eq!(*attr, ATTR_DIRECTORY);
+        let boot = read_dir(&image, *start);
+        let names: Vec<_> = boot.iter().map(|(n, _, _, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            [".          ", "..         ", "kernel config.txt", "empty"]
+        );
+        assert_eq!(boot[1].2, 0);
+        assert_eq!(boot[2].3, b"x=1");
+        assert!(boot[3].3.is_empty());
+        assert_eq!(build(&tree, &opts()).unwrap(), image);
+    }
+
+    #[test]
+    fn aliases_stay_unique() {
+        let tree = [
+            file("long file name.txt", b""),
+            file("long file name.text", b""),
+            file("LONGFI~1.TXT", b""),
+        ];
+        let names: Vec<_> = short_names(&tree)
+            .unwrap()
+            .into_iter()
+            .map(|s| (String::from_utf8(s.name.to_vec()).unwrap(), s.long))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("LONGFI~2TXT".to_string(), true),
+                ("LONGFI~1TEX".to_string(), true),
+                ("LONGFI~1TXT".to_string(), false)
+            ]
+        );
+        assert_eq!(short_checksum(b"LONGFI~2TXT"), {
+            let mut sum = 0u8;
+            for &c in b"LONGFI~2TXT" {
+                sum = ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c);
+            }
+            sum
+        });
+    }
+
+    #[test]
+    fn small_images_are_refused() {
+        let err = build(
+            &[],
+            &MkfsOptions {
+                size: 16 * 1024 * 1024,
+                ..opts()
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("fewer than the 65525 FAT32 needs"));
+    }
+}
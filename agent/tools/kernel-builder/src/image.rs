@@ -0,0 +1,546 @@
+//! Image stage: wrap the linked kernel into a QEMU-bootable artifact.
+//!
+//! * `iso` — GRUB rescue ISO via `grub-mkrescue` (the `make iso` path).
+//! * `raw` — a disk image booted by either Limine (GPT + FAT partition built
+//!   with sgdisk/mtools, then `limine bios-install`) or a custom stage2 flat
+//!   binary from the workspace, with the kernel appended sector-aligned.
+//!
+//! `--boot uefi` targets OVMF instead of SeaBIOS: the raw image's FAT
+//! partition becomes an EFI system partition with Limine's `BOOTX64.EFI` at
+//! the removable-media path (`auton-uefi.img`, no BIOS install), and the ISO
+//! must carry GRUB's EFI boot image. Both paths load the kernel through
+//! Multiboot2, so UEFI images are x86_64-only like the images themselves.
+//!
+//! Every stage is planned as a list of commands first so the plan can be
+//! tested without the tools installed. The kernel's Multiboot2 header is
+//! checked before anything is built, and each image is checked afterwards.
+
+use crate::exec::run_tool;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+pub const MULTIBOOT2_MAGIC: u32 = 0xE852_50D6;
+/// The Multiboot2 header must sit within the first 32 KiB, 8-byte aligned.
+pub const MULTIBOOT2_SEARCH: usize = 32 * 1024;
+
+const SECTOR: u64 = 512;
+/// Raw images start their first partition at 1 MiB.
+const PARTITION_OFFSET: &str = "@@1M";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Iso,
+    Raw,
+    Both,
+}
+
+impl ImageFormat {
+    pub fn wants_iso(self) -> bool {
+        matches!(self, ImageFormat::Iso | ImageFormat::Both)
+    }
+
+    pub fn wants_raw(self) -> bool {
+        matches!(self, ImageFormat::Raw | ImageFormat::Both)
+    }
+}
+
+pub use auton_core::manifest::BootMode;
+
+/// File name of the raw disk image for `boot`.
+pub fn raw_image_name(boot: BootMode) -> &'static str {
+    match boot {
+        BootMode::Bios => "auton.img",
+        BootMode::Uefi => "auton-uefi.img",
+    }
+}
+
+/// Where UEFI firmware looks for a bootloader on removable media.
+pub const EFI_BOOT_PATH: &str = "::/EFI/BOOT/";
+/// Limine's x86_64 UEFI application.
+pub const LIMINE_EFI: &str = "BOOTX64.EFI";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Bootloader {
+    Limine,
+    Stage2,
+}
+
+/// Offset of the first valid Multiboot2 header (magic + checksum) in `bytes`.
+pub fn find_multiboot2(bytes: &[u8], limit: usize) -> Option<usize> {
+    let end = bytes.len().min(limit);
+    (0..end.saturating_sub(15)).step_by(8).find(|&off| {
+        let word = |i: usize| u32::from_le_bytes(bytes[off + i..off + i + 4].try_into().unwrap());
+        word(0) == MULTIBOOT2_MAGIC
+            && word(0)
+                .wrapping_add(word(4))
+                .wrapping_add(word(8))
+                .wrapping_add(word(12))
+                == 0
+    })
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Check a built image's container signature and that it carries the kernel's
+/// Multiboot2 header somewhere 8-byte aligned. UEFI images must also be
+/// GPT-partitioned (raw) or hold an EFI bootloader (ISO).
+pub fn verify_image(bytes: &[u8], format: ImageFormat, boot: BootMode) -> Result<()> {
+    match format {
+        ImageFormat::Iso => {
+            if bytes.get(0x8001..0x8006) != Some(b"CD001") {
+                bail!("not an ISO 9660 image (no CD001 descriptor at 0x8001)");
+            }
+            // The EFI image inside the ISO is FAT: 8.3 directory entries.
+            if boot == BootMode::Uefi
+                && !contains(bytes, b"BOOTX64 EFI")
+                && !contains(bytes, b"BOOTX64.EFI")
+            {
+                bail!("ISO has no UEFI boot image (install grub-efi-amd64-bin for grub-mkrescue)");
+            }
+        }
+        ImageFormat::Raw => {
+            if bytes.get(510..512) != Some(&[0x55, 0xAA]) {
+                bail!("raw image has no boot signature (0x55AA) at byte 510");
+            }
+            if boot == BootMode::Uefi && bytes.get(512..520) != Some(b"EFI PART") {
+                bail!("UEFI raw image has no GPT header at LBA 1");
+            }
+        }
+        ImageFormat::Both => bail!("verify one image format at a time"),
+    }
+    if find_multiboot2(bytes, bytes.len()).is_none() {
+        bail!("image does not contain a valid Multiboot2 header");
+    }
+    Ok(())
+}
+
+/// One step of an image build.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum Step {
+    /// Create a directory (and parents).
+    Mkdir(PathBuf),
+    /// Copy a file.
+    Copy { from: PathBuf, to: PathBuf },
+    /// Write a generated file.
+    Write { path: PathBuf, contents: String },
+    /// Create a zero-filled file of `size` bytes.
+    Allocate { path: PathBuf, size: u64 },
+    /// Concatenate files, padding each to a sector boundary.
+    Concat { parts: Vec<PathBuf>, to: PathBuf },
+    /// Run an external tool.
+    Run { program: String, args: Vec<String> },
+}
+
+/// GRUB config used when the workspace has no `grub/grub.cfg`.
+pub fn default_grub_cfg() -> String {
+    "set timeout=0\nset default=0\n\nmenuentry \"AUTON\" {\n\tmultiboot2 /boot/kernel.bin\n\tboot\n}\n"
+        .to_string()
+}
+
+/// Limine config booting the kernel through its Multiboot2 support.
+pub fn limine_conf() -> String {
+    "timeout: 0\n\n/AUTON\n    protocol: multiboot2\n    kernel_path: boot():/boot/kernel.elf\n"
+        .to_string()
+}
+
+/// Steps for a GRUB rescue ISO at `out_dir/auton.iso`.
+pub fn plan_iso(kernel: &Path, workspace: &Path, out_dir: &Path) -> Vec<Step> {
+    let isodir = out_dir.join("isodir");
+    let grub_dir = isodir.join("boot/grub");
+    let ws_cfg = workspace.join("grub/grub.cfg");
+    let cfg_step = if ws_cfg.is_file() {
+        Step::Copy {
+            from: ws_cfg,
+            to: grub_dir.join("grub.cfg"),
+        }
+    } else {
+        Step::Write {
+            path: grub_dir.join("grub.cfg"),
+            contents: default_grub_cfg(),
+        }
+    };
+    vec![
+        Step::Mkdir(grub_dir),
+        Step::Copy {
+            from: kernel.to_path_buf(),
+            to: isodir.join("boot/kernel.bin"),
+        },
+        cfg_step,
+        Step::Run {
+            program: "grub-mkrescue".to_string(),
+            args: vec![
+                "-o".to_string(),
+                out_dir.join("auton.iso").display().to_string(),
+                isodir.display().to_string(),
+            ],
+        },
+    ]
+}
+
+/// Steps for a raw disk image at `out_dir/<`[`raw_image_name`]`>`.
+pub fn plan_raw(
+    kernel: &Path,
+    out_dir: &Path,
+    bootloader: Bootloader,
+    boot: BootMode,
+    stage2: Option<&Path>,
+    limine_dir: &Path,
+    size: u64,
+) -> Result<Vec<Step>> {
+    let img = out_dir.join(raw_image_name(boot));
+    let img_s = img.display().to_string();
+    let part = format!("{img_s}{PARTITION_OFFSET}");
+    let run = |program: &str, args: &[&str]| Step::Run {
+        program: program.to_string(),
+        args: args.iter().map(|s| s.to_string()).collect(),
+    };
+
+    match bootloader {
+        Bootloader::Stage2 if boot == BootMode::Uefi => {
+            bail!("the stage2 bootloader is BIOS-only; use --bootloader limine with --boot uefi")
+        }
+        Bootloader::Stage2 => {
+            let Some(stage2) = stage2 else {
+                bail!("raw image with the stage2 bootloader needs --stage2 <flat binary>");
+            };
+            Ok(vec![
+                Step::Mkdir(out_dir.to_path_buf()),
+                Step::Concat {
+                    parts: vec![stage2.to_path_buf(), kernel.to_path_buf()],
+                    to: img,
+                },
+            ])
+        }
+        Bootloader::Limine if boot == BootMode::Uefi => {
+            let conf = out_dir.join("limine.conf");
+            let efi_app = limine_dir.join(LIMINE_EFI);
+            Ok(vec![
+                Step::Mkdir(out_dir.to_path_buf()),
+                Step::Allocate {
+                    path: img.clone(),
+                    size,
+                },
+                run("sgdisk", &[&img_s, "-n", "1:2048", "-t", "1:ef00"]),
+                run("mformat", &["-i", &part, "::"]),
+                run(
+                    "mmd",
+                    &[
+                        "-i",
+                        &part,
+                        "::/EFI",
+                        "::/EFI/BOOT",
+                        "::/boot",
+                        "::/boot/limine",
+                    ],
+                ),
+                run(
+                    "mcopy",
+                    &[
+                        "-i",
+                        &part,
+                        &kernel.display().to_string(),
+                        "::/boot/kernel.elf",
+                    ],
+                ),
+                Step::Write {
+                    path: conf.clone(),
+                    contents: limine_conf(),
+                },
+                run(
+                    "mcopy",
+                    &["-i", &part, &conf.display().to_string(), "::/boot/limine/"],
+                ),
+                run(
+                    "mcopy",
+                    &["-i", &part, &efi_app.display().to_string(), EFI_BOOT_PATH],
+                ),
+            ])
+        }
+        Bootloader::Limine => {
+            let conf = out_dir.join("limine.conf");
+            let bios_sys = limine_dir.join("limine-bios.sys");
+            Ok(vec![
+                Step::Mkdir(out_dir.to_path_buf()),
+                Step::Allocate {
+                    path: img.clone(),
+                    size,
+                },
+                run("sgdisk", &[&img_s, "-n", "1:2048", "-t", "1:ef00"]),
+                run("mformat", &["-i", &part, "::"]),
+                run("mmd", &["-i", &part, "::/boot", "::/boot/limine"]),
+                run(
+                    "mcopy",
+                    &[
+                        "-i",
+                        &part,
+                        &kernel.display().to_string(),
+                        "::/boot/kernel.elf",
+                    ],
+                ),
+                Step::Write {
+                    path: conf.clone(),
+                    contents: limine_conf(),
+                },
+                run(
+                    "mcopy",
+                    &[
+                        "-i",
+                        &part,
+                        &conf.display().to_string(),
+                        &bios_sys.display().to_string(),
+                        "::/boot/limine/",
+                    ],
+                ),
+                run("limine", &["bios-install", &img_s]),
+            ])
+        }
+    }
+}
+
+fn pad_to_sector(buf: &mut Vec<u8>) {
+    let rem = buf.len() as u64 % SECTOR;
+    if rem != 0 {
+        buf.resize(buf.len() + (SECTOR - rem) as usize, 0);
+    }
+}
+
+/// Execute planned steps in order.
+pub async fn execute(steps: &[Step]) -> Result<()> {
+    for step in steps {
+        match step {
+            Step::Mkdir(dir) => std::fs::create_dir_all(dir)
+                .with_context(|| format!("creating {}", dir.display()))?,
+            Step::Copy { from, to } => {
+                std::fs::copy(from, to)
+                    .with_context(|| format!("copying {} to {}", from.display(), to.display()))?;
+            }
+            Step::Write { path, contents } => std::fs::write(path, contents)
+                .with_context(|| format!("writing {}", path.display()))?,
+            Step::Allocate { path, size } => {
+                let f = std::fs::File::create(path)
+                    .with_context(|| format!("creating {}", path.display()))?;
+                f.set_len(*size)
+                    .with_context(|| format!("sizing {}", path.display()))?;
+            }
+            Step::Concat { parts, to } => {
+                let mut buf = Vec::new();
+                for p in parts {
+                    buf.extend(
+                        std::fs::read(p).with_context(|| format!("reading {}", p.display()))?,
+                    );
+                    pad_to_sector(&mut buf);
+                }
+                std::fs::write(to, buf).with_context(|| format!("writing {}", to.display()))?;
+            }
+            Step::Run { program, args } => {
+                run_tool(program, args, &format!("running {program}")).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Options for [`build_images`].
+#[derive(Debug, Clone)]
+pub struct ImageOptions {
+    pub format: ImageFormat,
+    pub bootloader: Bootloader,
+    pub boot: BootMode,
+    pub stage2: Option<PathBuf>,
+    pub limine_dir: PathBuf,
+    pub raw_size: u64,
+}
+
+/// Build the requested images for `kernel`; returns the verified image paths.
+pub async fn build_images(
+    kernel: &Path,
+    workspace: &Path,
+    out_dir: &Path,
+    opts: &ImageOptions,
+) -> Result<Vec<PathBuf>> {
+    let bytes = std::fs::read(kernel).with_context(|| format!("reading {}", kernel.display()))?;
+    crate::bootproto::check_multiboot2(&bytes, "x86_64").with_context(|| {
+        format!(
+            "{}: refusing to build an unbootable image",
+            kernel.display()
+        )
+    })?;
+
+    let mut built = Vec::new();
+    if opts.format.wants_iso() {
+        execute(&plan_iso(kernel, workspace, out_dir)).await?;
+        built.push((out_dir.join("auton.iso"), ImageFormat::Iso));
+    }
+    if opts.format.wants_raw() {
+        let steps = plan_raw(
+            kernel,
+            out_dir,
+            opts.bootloader,
+            opts.boot,
+            opts.stage2.as_deref(),
+            &opts.limine_dir,
+            opts.raw_size,
+        )?;
+        execute(&steps).await?;
+        built.push((out_dir.join(raw_image_name(opts.boot)), ImageFormat::Raw));
+    }
+
+    for (path, format) in &built {
+        let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        verify_image(&bytes, *format, opts.boot)
+            .with_context(|| format!("verifying {}", path.display()))?;
+        tracing::info!(image = %path.display(), "image verified");
+    }
+    Ok(built.into_iter().map(|(p, _)| p).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mb2_header() -> Vec<u8> {
+        let len = 24u32;
+        let mut h = Vec::new();
+        for w in [
+            MULTIBOOT2_MAGIC,
+            0,
+            len,
+            0u32.wrapping_sub(MULTIBOOT2_MAGIC + len),
+        ] {
+            h.extend(w.to_le_bytes());
+        }
+        h.extend([0, 0, 0, 0, 8, 0, 0, 0]); // end tag
+        h
+    }
+
+    #[test]
+    fn finds_aligned_multiboot2_header_with_valid_checksum() {
+        let mut img = vec![0u8; 0x1000];
+        img.extend(mb2_header());
+        assert_eq!(find_multiboot2(&img, MULTIBOOT2_SEARCH), Some(0x1000));
+
+        let mut bad = img.clone();
+        bad[0x1000 + 12] ^= 1; // corrupt checksum
+        assert_eq!(find_multiboot2(&bad, MULTIBOOT2_SEARCH), None);
+    }
+
+    #[test]
+    fn header_past_32k_is_not_found() {
+        let mut img = vec![0u8; MULTIBOOT2_SEARCH];
+        img.extend(mb2_header());
+        assert_eq!(find_multiboot2(&img, MULTIBOOT2_SEARCH), None);
+    }
+
+    #[test]
+    fn raw_image_needs_boot_signature() {
+        let mut img = vec![0u8; 512];
+        img.extend(mb2_header());
+        assert!(verify_image(&img, ImageFormat::Raw, BootMode::Bios).is_err());
+        img[510] = 0x55;
+        img[511] = 0xAA;
+        assert!(verify_image(&img, ImageFormat::Raw, BootMode::Bios).is_ok());
+        assert!(verify_image(&img, ImageFormat::Raw, BootMode::Uefi).is_err());
+    }
+
+    #[test]
+    fn iso_plan_stages_kernel_and_runs_grub_mkrescue() {
+        let steps = plan_iso(
+            Path::new("b/kernel.elf"),
+            Path::new("/no-ws"),
+            Path::new("b"),
+        );
+        assert!(steps.contains(&Step::Copy {
+            from: PathBuf::from("b/kernel.elf"),
+            to: PathBuf::from("b/isodir/boot/kernel.bin"),
+        }));
+        assert!(
+            matches!(&steps[2], Step::Write { contents, .. } if contents.contains("multiboot2"))
+        );
+        assert!(matches!(&steps[3], Step::Run { program, .. } if program == "grub-mkrescue"));
+    }
+
+    #[test]
+    fn stage2_raw_plan_requires_a_stage2_binary() {
+        let k = Path::new("b/kernel.elf");
+        let lim = Path::new("/usr/share/limine");
+        let bios = BootMode::Bios;
+        assert!(plan_raw(
+            k,
+            Path::new("b"),
+            Bootloader::Stage2,
+            bios,
+            None,
+            lim,
+            1 << 20
+        )
+        .is_err());
+        let steps = plan_raw(
+            k,
+            Path::new("b"),
+            Bootloader::Stage2,
+            bios,
+            Some(Path::new("b/stage2.bin")),
+            lim,
+            1 << 20,
+        )
+        .unwrap();
+        assert!(matches!(&steps[1], Step::Concat { parts, .. } if parts.len() == 2));
+    }
+
+    #[test]
+    fn limine_plan_ends_with_bios_install() {
+        let steps = plan_raw(
+            Path::new("b/kernel.elf"),
+            Path::new("b"),
+            Bootloader::Limine,
+            BootMode::Bios,
+            None,
+            Path::new("/usr/share/limine"),
+            64 << 20,
+        )
+        .unwrap();
+        assert!(matches!(steps.last(), Some(Step::Run { program, args })
+            if program == "limine" && args[0] == "bios-install"));
+    }
+
+    #[test]
+    fn uefi_plan_installs_the_efi_app_instead_of_bios_stages() {
+        let limine = Path::new("/usr/share/limine");
+        let steps = plan_raw(
+            Path::new("b/kernel.elf"),
+            Path::new("b"),
+            Bootloader::Limine,
+            BootMode::Uefi,
+            None,
+            limine,
+            64 << 20,
+        )
+        .unwrap();
+        assert!(
+            matches!(&steps[1], Step::Allocate { path, .. } if path.ends_with("auton-uefi.img"))
+        );
+        assert!(matches!(steps.last(), Some(Step::Run { program, args })
+            if program == "mcopy"
+                && args.contains(&limine.join(LIMINE_EFI).display().to_string())
+                && args.last().map(String::as_str) == Some(EFI_BOOT_PATH)));
+        assert!(!steps
+            .iter()
+            .any(|s| matches!(s, Step::Run { program, .. } if program == "limine")));
+        assert!(plan_raw(
+            Path::new("b/kernel.elf"),
+            Path::new("b"),
+            Bootloader::Stage2,
+            BootMode::Uefi,
+            Some(Path::new("b/stage2.bin")),
+            limine,
+            1 << 20,
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Bounded-concurrency job scheduler for independent translation units.
+//!
+//! Results come back in input order regardless of completion order, so link
+//! inputs stay deterministic. Each job runs inside a `job` tracing span and
+//! every tool's captured output is logged as a single event, so lines from
+//! concurrent jobs never interleave mid-message.
+
+use anyhow::Result;
+use std::future::Future;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+/// Default parallelism: one job per available CPU.
+pub fn default_jobs() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// Run `f` over `items` with at most `jobs` in flight.
+///
+/// After the first failure no new jobs start; in-flight jobs are allowed to
+/// finish, and the failure with the lowest input index is returned so the
+/// reported error does not depend on scheduling.
+pub async fn run_bounded<T, R, F, Fut>(items: Vec<T>, jobs: usize, f: F) -> Result<Vec<R>>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = Result<R>> + Send + 'static,
+{
+    let jobs = jobs.max(1);
+    let total = items.len();
+    let mut results: Vec<Option<R>> = (0..total).map(|_| None).collect();
+    let mut first_err: Option<(usize, anyhow::Error)> = None;
+    let mut pending = items.into_iter().enumerate();
+    let mut running = JoinSet::new();
+
+    loop {
+        while first_err.is_none() && running.len() < jobs {
+            let Some((idx, item)) = pending.next() else {
+                break;
+            };
+            let span = tracing::info_span!("job", id = idx);
+            let fut = f(item);
+            running.spawn(async move { (idx, fut.await) }.instrument(span));
+        }
+        let Some(joined) = running.join_next().await else {
+            break;
+        };
+        let (idx, res) = joined.expect("build job panicked");
+        match res {
+            Ok(r) => results[idx] = Some(r),
+            Err(e) => {
+                if first_err.as_ref().is_none_or(|(i, _)| idx < *i) {
+                    first_err = Some((idx, e));
+                }
+            }
+        }
+    }
+
+    if let Some((_, e)) = first_err {
+        return Err(e);
+    }
+    Ok(results
+        .into_iter()
+        .map(|r| r.expect("every job completed"))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn preserves_input_order() {
+        // Later items finish first; results must still be in input order.
+        let out = run_bounded((0..6u64).collect(), 3, |i| async move {
+            tokio::time::sleep(Duration::from_millis(30 - i * 5)).await;
+            Ok(i * 10)
+        })
+        .await
+        .unwrap();
+        assert_eq!(out, vec![0, 10, 20, 30, 40, 50]);
+    }
+
+    #[tokio::test]
+    async fn never_exceeds_the_job_limit() {
+        let live = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        run_bounded((0..12).collect::<Vec<u32>>(), 4, |_| {
+            let (live, peak) = (live.clone(), peak.clone());
+            async move {
+                let now = live.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                live.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            }
+        })
+        .await
+        .unwrap();
+        assert!(peak.load(Ordering::SeqCst) <= 4);
+    }
+
+    #[tokio::test]
+    async fn reports_lowest_index_failure() {
+        let err = run_bounded((0..4u64).collect(), 4, |i| async move {
+            tokio::time::sleep(Duration::from_millis(20 - i * 5)).await;
+            if i == 1 || i == 3 {
+                anyhow::bail!("unit {i} failed");
+            }
+            Ok(())
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "unit 1 failed");
+    }
+}
@@ -1,12 +1,59 @@
 //! Kernel build orchestration: toolchain mapping and `make` command building.
 //!
 //! Pure functions live here so they can be unit-tested without a cross
-//! compiler; `main.rs` handles process spawning and artifact copying.
+//! compiler; `main.rs` handles process spawning and artifact copying. The
+//! native (make-free) pipeline lives in per-stage modules, each of which keeps
+//! its command construction pure and its process spawning in one small async
+//! function.
+
+pub mod asm;
+pub mod bootproto;
+pub mod cache;
+pub mod check;
+pub mod clean;
+pub mod compdb;
+pub mod config;
+pub mod deps;
+pub mod elf;
+pub mod exec;
+pub mod ext2;
+pub mod fat32;
+pub mod image;
+pub mod jobs;
+pub mod link;
+pub mod listing;
+pub mod manifest;
+pub mod metrics;
+pub mod mkfs;
+pub mod pipeline;
+pub mod prelink;
+pub mod profile;
+pub mod repro;
+pub mod rust;
+pub mod size;
+pub mod stack;
+pub mod symbols;
+pub mod symdiff;
+pub mod toolchain;
+pub mod watch;
+
+pub use auton_core::manifest::QemuDefaults;
+pub use auton_core::{diagnostics, hash};
 
 use anyhow::{bail, Result};
 use serde::Serialize;
 use std::path::{Path, PathBuf};
 
+/// Assembly dialect of an architecture's hand-written boot code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AsmSyntax {
+    /// `.asm` through nasm (x86_64 only); `.S`/`.s` still go through GAS.
+    Nasm,
+    /// GNU as only.
+    Gas,
+}
+
 /// Toolchain + QEMU names for a target architecture (mirrors
 /// `orchestrator/arch_registry.py`).
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
@@ -14,21 +61,75 @@ pub struct ArchToolchain {
     pub arch: String,
     pub cc: String,
     pub qemu: String,
+    /// Cross-tool prefixes to try in order (`<prefix>gcc`, `<prefix>ld`, …).
+    pub prefixes: Vec<String>,
+    pub assembler: AsmSyntax,
+    /// Triple for the clang fallback (`--target=`).
+    pub clang_target: String,
+    /// Built-in rustc target used when the workspace ships no target spec.
+    pub rust_target: String,
+    pub qemu_machine: Option<String>,
+    pub qemu_cpu: Option<String>,
+    pub qemu_extra: Vec<String>,
 }
 
 impl ArchToolchain {
     pub fn for_arch(arch: &str) -> Result<Self> {
-        let (cc, qemu) = match arch {
-            "x86_64" => ("x86_64-elf-gcc", "qemu-system-x86_64"),
-            "aarch64" => ("aarch64-elf-gcc", "qemu-system-aarch64"),
-            "riscv64" => ("riscv64-elf-gcc", "qemu-system-riscv64"),
+        let strs = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let tc = match arch {
+            "x86_64" => Self {
+                arch: arch.to_string(),
+                cc: "x86_64-elf-gcc".to_string(),
+                qemu: "qemu-system-x86_64".to_string(),
+                prefixes: strs(&["x86_64-elf-"]),
+                assembler: AsmSyntax::Nasm,
+                clang_target: "x86_64-unknown-none".to_string(),
+                rust_target: "x86_64-unknown-none".to_string(),
+                qemu_machine: None,
+                qemu_cpu: None,
+                qemu_extra: Vec::new(),
+            },
+            "aarch64" => Self {
+                arch: arch.to_string(),
+                cc: "aarch64-elf-gcc".to_string(),
+                qemu: "qemu-system-aarch64".to_string(),
+                prefixes: strs(&["aarch64-elf-", "aarch64-none-elf-"]),
+                assembler: AsmSyntax::Gas,
+                clang_target: "aarch64-unknown-none".to_string(),
+                rust_target: "aarch64-unknown-none".to_string(),
+                qemu_machine: Some("virt".to_string()),
+                qemu_cpu: Some("cortex-a53".to_string()),
+                qemu_extra: Vec::new(),
+            },
+            "riscv64" => Self {
+                arch: arch.to_string(),
+                cc: "riscv64-elf-gcc".to_string(),
+                qemu: "qemu-system-riscv64".to_string(),
+                prefixes: strs(&["riscv64-elf-", "riscv64-unknown-elf-"]),
+                assembler: AsmSyntax::Gas,
+                clang_target: "riscv64-unknown-elf".to_string(),
+                rust_target: "riscv64gc-unknown-none-elf".to_string(),
+                qemu_machine: Some("virt".to_string()),
+                qemu_cpu: None,
+                qemu_extra: strs(&["-bios", "default"]),
+            },
             other => bail!("unsupported architecture: {other}"),
         };
-        Ok(Self {
-            arch: arch.to_string(),
-            cc: cc.to_string(),
-            qemu: qemu.to_string(),
-        })
+        Ok(tc)
+    }
+
+    /// `<prefix><tool>` for every prefix, in preference order.
+    pub fn prefixed(&self, tool: &str) -> Vec<String> {
+        self.prefixes.iter().map(|p| format!("{p}{tool}")).collect()
+    }
+
+    pub fn qemu_defaults(&self) -> QemuDefaults {
+        QemuDefaults {
+            binary: self.qemu.clone(),
+            machine: self.qemu_machine.clone(),
+            cpu: self.qemu_cpu.clone(),
+            extra: self.qemu_extra.clone(),
+        }
     }
 }
 
@@ -53,11 +154,26 @@ pub fn toolchain_available(cc: &str) -> bool {
     which::which(cc).is_ok()
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Default, Serialize)]
 pub struct BuildOutcome {
     pub success: bool,
     pub arch: String,
     pub artifact: Option<String>,
+    /// Relocatable objects produced by the native pipeline.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub objects: Vec<String>,
+    /// Bootable images built from the linked kernel.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+    /// Parsed tool diagnostics (`--diagnostics-format json`).
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<diagnostics::Diagnostic>,
+    /// Object cache statistics (native driver).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<cache::CacheStats>,
+    /// Per-stage wall-clock breakdown, in stage order.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub timings: Vec<manifest::StageTiming>,
     pub stdout: String,
     pub stderr: String,
 }
@@ -78,6 +194,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn non_x86_arches_use_gas_and_virt_machine() {
+        let tc = ArchToolchain::for_arch("aarch64").unwrap();
+        assert_eq!(tc.assembler, AsmSyntax::Gas);
+        assert_eq!(
+            tc.prefixed("gcc"),
+            vec!["aarch64-elf-gcc", "aarch64-none-elf-gcc"]
+        );
+        assert_eq!(tc.qemu_defaults().machine.as_deref(), Some("virt"));
+        let rv = ArchToolchain::for_arch("riscv64").unwrap();
+        assert!(rv
+            .prefixed("ld")
+            .contains(&"riscv64-unknown-elf-ld".to_string()));
+    }
+
     #[test]
     fn toolchain_for_unknown_arch_errors() {
         assert!(ArchToolchain::for_arch("m68k").is_err());
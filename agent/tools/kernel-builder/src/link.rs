@@ -0,0 +1,361 @@
+//! Link stage: objects + linker script → `kernel.elf`, then verify the image.
+//!
+//! Verification reads the linked ELF back and checks what the boot path relies
+//! on: the `ENTRY()` symbol exists and is the ELF entry point, every loadable
+//! segment and allocated section sits at or above the script's load address,
+//! and the loaded image fits the size budget.
+
+use crate::elf::{self, Elf, PF_X};
+use crate::exec::{self, run_tool};
+use crate::{symbols, ArchToolchain};
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Default ceiling for the loaded image (physical span of `PT_LOAD` segments).
+pub const DEFAULT_SIZE_BUDGET: u64 = 16 * 1024 * 1024;
+
+/// Workspace-relative linker script locations, in lookup order: the
+/// arch-specific scripts first, then a shared top-level `linker.ld`.
+pub fn script_candidates(arch: &str) -> Vec<PathBuf> {
+    vec![
+        PathBuf::from(format!("kernel/arch/{arch}/linker.ld")),
+        PathBuf::from(format!("linker-{arch}.ld")),
+        PathBuf::from("linker.ld"),
+    ]
+}
+
+/// Resolve the linker script, reporting every path searched if none exists.
+pub fn find_script(workspace: &Path, arch: &str, explicit: Option<&Path>) -> Result<PathBuf> {
+    let searched: Vec<PathBuf> = match explicit {
+        Some(p) => vec![p.to_path_buf()],
+        None => script_candidates(arch),
+    };
+    for rel in &searched {
+        let path = workspace.join(rel);
+        if path.is_file() {
+            return Ok(path);
+        }
+    }
+    let list: Vec<String> = searched
+        .iter()
+        .map(|p| workspace.join(p).display().to_string())
+        .collect();
+    bail!(
+        "linker script not found; looked for: {} (pass --linker-script)",
+        list.join(", ")
+    )
+}
+
+/// Parse a size or address: `4096`, `0x100000`, `1M`, `64K`, `2G`.
+pub fn parse_size(text: &str) -> Result<u64> {
+    let t = text.trim();
+    if let Some(hex) = t.strip_prefix("0x").or_else(|| t.strip_prefix("0X")) {
+        return u64::from_str_radix(hex, 16).with_context(|| format!("bad hex value `{t}`"));
+    }
+    let (digits, mult) = match t.chars().last() {
+        Some('K' | 'k') => (&t[..t.len() - 1], 1024),
+        Some('M' | 'm') => (&t[..t.len() - 1], 1024 * 1024),
+        Some('G' | 'g') => (&t[..t.len() - 1], 1024 * 1024 * 1024),
+        _ => (t, 1),
+    };
+    let n: u64 = digits
+        .parse()
+        .with_context(|| format!("bad size `{t}` (expected e.g. 4096, 0x1000, 16M)"))?;
+    Ok(n * mult)
+}
+
+/// What verification needs from the linker script.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ScriptInfo {
+    /// Symbol named by `ENTRY(...)`.
+    pub entry: Option<String>,
+    /// First location-counter assignment (`. = 1M;`), i.e. the load base.
+    pub base: Option<u64>,
+}
+
+/// Extract `ENTRY()` and the first `. = <addr>;` from a linker script.
+pub fn parse_script(text: &str) -> ScriptInfo {
+    let mut info = ScriptInfo::default();
+    let mut rest = text;
+    // Drop /* ... */ comments so commented-out directives are ignored.
+    let mut clean = String::with_capacity(text.len());
+    while let Some(start) = rest.find("/*") {
+        clean.push_str(&rest[..start]);
+        rest = match rest[start..].find("*/") {
+            Some(end) => &rest[start + end + 2..],
+            None => "",
+        };
+    }
+    clean.push_str(rest);
+
+    if let Some(pos) = clean.find("ENTRY(") {
+        let after = &clean[pos + "ENTRY(".len()..];
+        if let Some(end) = after.find(')') {
+            info.entry = Some(after[..end].trim().to_string());
+        }
+    }
+    for stmt in clean.split(';') {
+        // Only the tail after the last brace/newline is the assignment itself.
+        let stmt = stmt
+            .rsplit(['{', '}', '\n'])
+            .next()
+            .unwrap_or_default()
+            .trim();
+        if let Some(value) = stmt.strip_prefix('.').map(str::trim_start) {
+            if let Some(value) = value.strip_prefix('=') {
+                if let Ok(addr) = parse_size(value) {
+                    info.base = Some(addr);
+                    break;
+                }
+            }
+        }
+    }
+    info
+}
+
+/// Linkers to try for `tc`, in preference order. The host `ld` is only a
+/// candidate when it targets the same architecture.
+pub fn linker_candidates(tc: &ArchToolchain, ld_override: Option<&str>) -> Vec<String> {
+    if let Some(ld) = ld_override {
+        return vec![ld.to_string()];
+    }
+    let mut cands = tc.prefixed("ld");
+    cands.push("ld.lld".to_string());
+    if tc.arch == std::env::consts::ARCH {
+        cands.push("ld".to_string());
+    }
+    cands
+}
+
+/// Pick the first available linker.
+pub fn find_linker(tc: &ArchToolchain, ld_override: Option<&str>) -> Result<String> {
+    let cands = linker_candidates(tc, ld_override);
+    cands
+        .iter()
+        .find(|c| exec::which(c).is_some())
+        .cloned()
+        .with_context(|| format!("no linker found; searched PATH for: {}", cands.join(", ")))
+}
+
+/// `ld` argument vector (same intent as the Makefile's `LDFLAGS`), plus a
+/// linker map next to the output for [`crate::symbols`].
+pub fn ld_args(script: &Path, output: &Path, objects: &[PathBuf]) -> Vec<String> {
+    let mut args = vec![
+        "-nostdlib".to_string(),
+        "--build-id=none".to_string(),
+        "-T".to_string(),
+        script.display().to_string(),
+        "-o".to_string(),
+        output.display().to_string(),
+        format!("-Map={}", symbols::map_for(output).display()),
+    ];
+    args.extend(objects.iter().map(|o| o.display().to_string()));
+    args
+}
+
+/// Summary of a verified kernel image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LinkReport {
+    pub elf: String,
+    pub entry: u64,
+    pub entry_symbol: Option<String>,
+    pub loaded_size: u64,
+    pub size_budget: u64,
+}
+
+/// Check a linked image against the script; returns one message per problem.
+pub fn verify(image: &Elf, script: &ScriptInfo, size_budget: u64) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    match &script.entry {
+        Some(name) => match image.symbol(name) {
+            None => problems.push(format!("entry symbol `{name}` is not defined")),
+            Some(sym) if sym.value != image.entry => problems.push(format!(
+                "ELF entry {:#x} does not match `{name}` at {:#x}",
+                image.entry, sym.value
+            )),
+            Some(_) => {}
+        },
+        None if image.entry == 0 => problems.push("image has no entry point".to_string()),
+        None => {}
+    }
+
+    let exec_covers_entry = image.load_segments().any(|s| {
+        s.flags & PF_X != 0 && (s.vaddr..s.vaddr.saturating_add(s.memsz)).contains(&image.entry)
+    });
+    if image.entry != 0 && !exec_covers_entry {
+        problems.push(format!(
+            "entry {:#x} is not inside an executable PT_LOAD segment",
+            image.entry
+        ));
+    }
+
+    if let Some(base) = script.base {
+        for seg in image.load_segments() {
+            if seg.paddr < base {
+                problems.push(format!(
+                    "PT_LOAD at physical {:#x} is below the load address {base:#x}",
+                    seg.paddr
+                ));
+            }
+        }
+        for sec in image.sections.iter().filter(|s| s.is_alloc() && s.size > 0) {
+            if sec.addr < base {
+                problems.push(format!(
+                    "section {} at {:#x} is below the load address {base:#x}",
+                    sec.name, sec.addr
+                ));
+            }
+        }
+    }
+
+    let size = image.loaded_span();
+    if size > size_budget {
+        problems.push(format!(
+            "loaded image is {size} bytes, over the {size_budget}-byte budget"
+        ));
+    }
+    problems
+}
+
+/// Link `objects` into `output` and verify the result.
+pub async fn link(
+    linker: &str,
+    script: &Path,
+    objects: &[PathBuf],
+    output: &Path,
+    size_budget: u64,
+) -> Result<LinkReport> {
+    let text = std::fs::read_to_string(script)
+        .with_context(|| format!("reading linker script {}", script.display()))?;
+    let info = parse_script(&text);
+    if let Some(dir) = output.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    }
+
+    let what = format!("linking {}", output.display());
+    run_tool(linker, &ld_args(script, output, objects), &what).await?;
+
+    let image = elf::read_file(output)?;
+    let problems = verify(&image, &info, size_budget);
+    if !problems.is_empty() {
+        bail!(
+            "{} failed verification:\n  - {}",
+            output.display(),
+            problems.join("\n  - ")
+        );
+    }
+    Ok(LinkReport {
+        elf: output.display().to_string(),
+        entry: image.entry,
+        entry_symbol: info.entry,
+        loaded_size: image.loaded_span(),
+        size_budget,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::{Section, Segment, Symbol, PT_LOAD, SHF_ALLOC, STB_GLOBAL, STT_FUNC};
+
+    fn image(entry: u64, paddr: u64, memsz: u64) -> Elf {
+        Elf {
+            machine: 62,
+            entry,
+            sections: vec![Section {
+                name: ".text".into(),
+                kind: 1,
+                flags: SHF_ALLOC,
+                addr: paddr,
+                offset: 0x1000,
+                size: memsz,
+            }],
+            segments: vec![Segment {
+                kind: PT_LOAD,
+                flags: PF_X | 4,
+                offset: 0x1000,
+                vaddr: paddr,
+                paddr,
+                filesz: memsz,
+                memsz,
+            }],
+            symbols: vec![Symbol {
+                name: "_start".into(),
+                value: entry,
+                size: 0,
+                kind: STT_FUNC,
+                bind: STB_GLOBAL,
+                shndx: 1,
+            }],
+        }
+    }
+
+    const SEED_SCRIPT: &str =
+        "/* seed */\nENTRY(_start)\nSECTIONS\n{\n\t. = 1M;\n\t.text : { *(.text) }\n}\n";
+
+    #[test]
+    fn parses_entry_and_base_from_seed_script() {
+        let info = parse_script(SEED_SCRIPT);
+        assert_eq!(info.entry.as_deref(), Some("_start"));
+        assert_eq!(info.base, Some(0x100000));
+        assert_eq!(
+            parse_script("/* . = 2M; */ . = 0x200000;").base,
+            Some(0x200000)
+        );
+    }
+
+    #[test]
+    fn parse_size_accepts_suffixes_and_hex() {
+        assert_eq!(parse_size("16M").unwrap(), 16 << 20);
+        assert_eq!(parse_size("64k").unwrap(), 64 << 10);
+        assert_eq!(parse_size("0x1000").unwrap(), 4096);
+        assert!(parse_size("lots").is_err());
+    }
+
+    #[test]
+    fn good_image_verifies_clean() {
+        let info = parse_script(SEED_SCRIPT);
+        assert!(verify(
+            &image(0x100000, 0x100000, 0x4000),
+            &info,
+            DEFAULT_SIZE_BUDGET
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn reports_missing_entry_low_address_and_size() {
+        let mut img = image(0x1000, 0x1000, 0x4000);
+        img.symbols.clear();
+        let info = parse_script(SEED_SCRIPT);
+        let problems = verify(&img, &info, 0x2000);
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("`_start` is not defined")));
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("below the load address")));
+        assert!(problems.iter().any(|p| p.contains("budget")));
+    }
+
+    #[test]
+    fn linker_candidates_follow_arch_prefixes() {
+        let tc = ArchToolchain::for_arch("aarch64").unwrap();
+        let cands = linker_candidates(&tc, None);
+        assert_eq!(
+            &cands[..3],
+            ["aarch64-elf-ld", "aarch64-none-elf-ld", "ld.lld"]
+        );
+        assert_eq!(linker_candidates(&tc, Some("my-ld")), ["my-ld"]);
+    }
+
+    #[test]
+    fn missing_script_lists_searched_paths() {
+        let err = find_script(Path::new("/nonexistent-ws"), "x86_64", None).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("/nonexistent-ws/linker.ld"));
+        assert!(msg.contains("kernel/arch/x86_64/linker.ld"));
+    }
+}
@@ -0,0 +1,196 @@
+//! `--emit-listings`: per-object disassembly and NASM listings under
+//! `<output>/listings/`, for reviewing hand-written stubs and codegen.
+//!
+//! Every linked object gets `objdump -d -r -w` output beside its name
+//! (`obj/kernel/arch/x86_64/isr.S.o` → `listings/kernel/arch/x86_64/isr.S.dis`),
+//! with relocations inline so calls to other objects stay readable before
+//! linking. NASM sources also get `nasm -l` listings (`isr.asm.lst`); those
+//! come from a separate nasm run so the object cache key is unaffected. The
+//! linked kernel's disassembly is `listings/kernel.dis`.
+
+use crate::asm::AsmJob;
+use crate::exec::{self, run_tool};
+use crate::ArchToolchain;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+pub const LISTINGS_DIR: &str = "listings";
+
+/// Disassembly flags for objects: relocations inline, one line per insn.
+const OBJECT_FLAGS: &[&str] = &["-d", "-r", "-w"];
+
+/// objdumps to try for `tc`, in preference order (the host one only when it
+/// targets the same arch).
+pub fn objdump_candidates(tc: &ArchToolchain) -> Vec<String> {
+    let mut cands = tc.prefixed("objdump");
+    cands.push("llvm-objdump".to_string());
+    if tc.arch == std::env::consts::ARCH {
+        cands.push("objdump".to_string());
+    }
+    cands
+}
+
+/// Pick the first available objdump.
+pub fn find_objdump(tc: &ArchToolchain) -> Result<String> {
+    let cands = objdump_candidates(tc);
+    cands
+        .iter()
+        .find(|c| exec::which(c).is_some())
+        .cloned()
+        .with_context(|| format!("no objdump found; searched PATH for: {}", cands.join(", ")))
+}
+
+/// One listing: run `program args…` and save its stdout to `output`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingJob {
+    pub output: PathBuf,
+    pub program: String,
+    pub args: Vec<String>,
+    /// The tool writes `output` itself (`nasm -l`) instead of to stdout.
+    pub writes_output: bool,
+}
+
+/// Where `object` (under `obj_dir`) lists: the `.o` becomes `.dis`.
+pub fn listing_path(obj_dir: &Path, listings: &Path, object: &Path) -> PathBuf {
+    let rel = object.strip_prefix(obj_dir).unwrap_or(object);
+    let name = rel.display().to_string();
+    listings.join(format!("{}.dis", name.strip_suffix(".o").unwrap_or(&name)))
+}
+
+/// `nasm -l` arguments for `job`: the same flags, but the object goes to
+/// the null device so only the listing is written.
+pub fn nasm_listing_args(job: &AsmJob, listing: &Path) -> Vec<String> {
+    let mut args = Vec::with_capacity(job.args.len() + 2);
+    let mut iter = job.args.iter();
+    while let Some(a) = iter.next() {
+        args.push(a.clone());
+        if a == "-o" {
+            iter.next();
+            args.push("/dev/null".to_string());
+        }
+    }
+    args.push("-l".to_string());
+    args.push(listing.display().to_string());
+    args
+}
+
+/// Jobs for every linkable object, every NASM source, and the linked `elf`.
+pub fn plan(
+    objdump: &str,
+    obj_dir: &Path,
+    output: &Path,
+    objects: &[PathBuf],
+    asm_jobs: &[AsmJob],
+    elf: &Path,
+) -> Vec<ListingJob> {
+    let listings = output.join(LISTINGS_DIR);
+    let mut jobs: Vec<ListingJob> = objects
+        .iter()
+        .filter(|o| o.extension().is_some_and(|e| e == "o"))
+        .map(|o| {
+            let mut args: Vec<String> = OBJECT_FLAGS.iter().map(|s| s.to_string()).collect();
+            args.push(o.display().to_string());
+            ListingJob {
+                output: listing_path(obj_dir, &listings, o),
+                program: objdump.to_string(),
+                args,
+                writes_output: false,
+            }
+        })
+        .collect();
+    for job in asm_jobs.iter().filter(|j| j.program == "nasm") {
+        let rel = job.output.strip_prefix(obj_dir).unwrap_or(&job.output);
+        let rel = rel.with_extension("lst");
+        let listing = listings.join(rel);
+        jobs.push(ListingJob {
+            args: nasm_listing_args(job, &listing),
+            output: listing,
+            program: "nasm".to_string(),
+            writes_output: true,
+        });
+    }
+    jobs.push(ListingJob {
+        output: listings.join("kernel.dis"),
+        program: objdump.to_string(),
+        args: vec![
+            "-d".to_string(),
+            "-w".to_string(),
+            elf.display().to_string(),
+        ],
+        writes_output: false,
+    });
+    jobs
+}
+
+pub async fn run(job: &ListingJob) -> Result<()> {
+    if let Some(dir) = job.output.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    }
+    let what = format!("listing {}", job.output.display());
+    let out = run_tool(&job.program, &job.args, &what).await?;
+    if !job.writes_output {
+        std::fs::write(&job.output, &out.stdout)
+            .with_context(|| format!("writing {}", job.output.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::OutputFormat;
+
+    #[test]
+    fn plans_object_nasm_and_kernel_listings() {
+        let nasm = AsmJob {
+            source: PathBuf::from("ws/boot/isr.asm"),
+            output: PathBuf::from("b/obj/boot/isr.asm.o"),
+            format: OutputFormat::Elf64,
+            program: "nasm".into(),
+            args: [
+                "-f",
+                "elf64",
+                "-I",
+                "ws/boot/",
+                "-o",
+                "b/obj/boot/isr.asm.o",
+                "ws/boot/isr.asm",
+            ]
+            .map(String::from)
+            .to_vec(),
+        };
+        let objects = [
+            PathBuf::from("b/obj/boot/isr.asm.o"),
+            PathBuf::from("b/obj/kernel/main.c.o"),
+            PathBuf::from("b/rust/libkernel.a"),
+        ];
+        let jobs = plan(
+            "objdump",
+            Path::new("b/obj"),
+            Path::new("b"),
+            &objects,
+            &[nasm],
+            Path::new("b/kernel.elf"),
+        );
+        let outputs: Vec<String> = jobs
+            .iter()
+            .map(|j| j.output.display().to_string())
+            .collect();
+        assert_eq!(
+            outputs,
+            [
+                "b/listings/boot/isr.asm.dis",
+                "b/listings/kernel/main.c.dis",
+                "b/listings/boot/isr.asm.lst",
+                "b/listings/kernel.dis",
+            ]
+        );
+        assert_eq!(jobs[1].args, ["-d", "-r", "-w", "b/obj/kernel/main.c.o"]);
+        let lst = &jobs[2];
+        assert!(lst.writes_output);
+        assert!(lst.args.windows(2).any(|w| w == ["-o", "/dev/null"]));
+        assert!(lst
+            .args
+            .ends_with(&["-l".to_string(), "b/listings/boot/isr.asm.lst".to_string()]));
+    }
+}
@@ -1,10 +1,34 @@
 //! kernel-builder: drive the kernel `make` build and stage the artifact.
 
-use anyhow::{Context, Result};
-use clap::Parser;
-use kernel_builder::{artifact_path, make_args, ArchToolchain, BuildOutcome};
-use std::path::PathBuf;
-use tokio::process::Command;
+use anyhow::{anyhow, bail, Context, Result};
+use auton_core::plan::{Plan, Planned};
+use auton_core::store::ArtifactStore;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use kernel_builder::bootproto::{self, BootProtocol};
+use kernel_builder::check::{self, CheckOptions, CheckReport, Severity};
+use kernel_builder::clean::{self, CleanStage};
+use kernel_builder::config::BuildConfig;
+use kernel_builder::deps::DepGraph;
+use kernel_builder::diagnostics;
+use kernel_builder::exec;
+use kernel_builder::image::{self, BootMode, Bootloader, ImageFormat, ImageOptions};
+use kernel_builder::manifest::{self, Artifact, ArtifactKind, BuildManifest, StageTiming, Timings};
+use kernel_builder::metrics::{self, BuildMetrics};
+use kernel_builder::mkfs::{self, Filesystem, MkfsOptions};
+use kernel_builder::profile::Profile;
+use kernel_builder::repro;
+use kernel_builder::size;
+use kernel_builder::stack::StackConfig;
+use kernel_builder::symbols;
+use kernel_builder::symdiff::{self, SymDiff};
+use kernel_builder::watch::{self, WatchOptions};
+use kernel_builder::{
+    artifact_path, jobs, link, listing, make_args, pipeline, ArchToolchain, BuildOutcome,
+};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::Instrument;
 
 #[derive(Parser)]
 #[command(
@@ -12,16 +36,19 @@ use tokio::process::Command;
     about = "Build orchestration for AUTON kernel"
 )]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Cmd>,
+
     /// Path to the kernel source workspace (holds the Makefile).
-    #[arg(short, long, default_value = "kernels/x86_64")]
+    #[arg(short, long, global = true, default_value = "kernels/x86_64")]
     workspace: PathBuf,
 
     /// Target architecture.
-    #[arg(short, long, default_value = "x86_64")]
+    #[arg(short, long, global = true, default_value = "x86_64")]
     arch: String,
 
     /// Directory to copy the built kernel.bin into.
-    #[arg(short, long, default_value = "build")]
+    #[arg(short, long, global = true, default_value = "build")]
     output: PathBuf,
 
     /// Override the C compiler (e.g. x86_64-linux-gnu-gcc).
@@ -32,66 +59,1195 @@ struct Cli {
     #[arg(long, default_value = "all")]
     target: String,
 
-    /// Clean before building.
+    /// Build driver: the workspace Makefile, or the native stage pipeline.
+    #[arg(long, value_enum, default_value_t = Driver::Make)]
+    driver: Driver,
+
+    /// Assembly source directory, relative to the workspace (native driver).
+    #[arg(long, default_value = "boot")]
+    boot_dir: PathBuf,
+
+    /// Linker script, relative to the workspace (native driver). Defaults to
+    /// `linker.ld`, then `kernel/arch/<arch>/linker.ld`.
     #[arg(long)]
-    clean: bool,
+    linker_script: Option<PathBuf>,
 
-    /// Emit the outcome as JSON.
+    /// Override the linker (default: <arch>-elf-ld, ld.lld, then ld).
     #[arg(long)]
+    ld: Option<String>,
+
+    /// Fail if the loaded image exceeds this size (e.g. 16M, 0x200000).
+    #[arg(long, value_parser = link::parse_size, default_value = "16M")]
+    max_image_size: u64,
+
+    /// Rebuild every translation unit, bypassing `<output>/.cache`.
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Keep the object cache here instead of `<output>/.cache`, e.g. to
+    /// share it between the builds of one agent session.
+    #[arg(long, global = true, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Concurrent compile jobs (default: number of CPUs).
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Wrap the linked kernel into bootable images (native driver).
+    #[arg(long, global = true, value_enum)]
+    image_format: Option<ImageFormat>,
+
+    #[command(flatten)]
+    image: ImageArgs,
+
+    /// Emit the outcome as JSON.
+    #[arg(long, global = true)]
     json: bool,
+
+    /// Optimisation and instrumentation profile (native driver). Profiles
+    /// other than `release` build into `<output>/<profile>/`.
+    #[arg(long, value_enum, default_value_t = Profile::Release)]
+    profile: Profile,
+
+    /// Write `<output>/compile_commands.json` for the C sources (native driver).
+    #[arg(long)]
+    emit_compdb: bool,
+
+    /// Write per-object disassembly and NASM listings to
+    /// `<output>/listings/` (native driver).
+    #[arg(long)]
+    emit_listings: bool,
+
+    /// Compile with `-fstack-usage`, write each entry point's worst-case
+    /// stack depth to `<output>/stack.json` and enforce `[stack] budget`
+    /// for interrupt handlers (native driver).
+    #[arg(long)]
+    stack_usage: bool,
+
+    /// Stack budget for interrupt handler paths (e.g. 4096, 8K); implies
+    /// `--stack-usage`.
+    #[arg(long, value_parser = link::parse_size)]
+    stack_budget: Option<u64>,
+
+    /// Boot protocol header to verify in the linked kernel: `auto` checks
+    /// whichever is present and requires one on x86_64.
+    #[arg(long, value_enum, default_value_t = BootProtocol::Auto)]
+    boot_protocol: BootProtocol,
+
+    /// How to report compiler/assembler/linker messages: raw text, or JSON
+    /// Lines (one record per diagnostic, then one for the outcome) on stdout.
+    #[arg(long, value_enum, global = true, default_value_t = DiagnosticsFormat::Human)]
+    diagnostics_format: DiagnosticsFormat,
+
+    /// Normalise paths and timestamps, then build a second time without the
+    /// cache and fail unless every artifact is byte-identical (native driver).
+    #[arg(long)]
+    reproducible: bool,
+
+    /// Where compilers, assemblers, linkers and make run.
+    #[arg(long, global = true, value_enum, default_value_t = BackendKind::Host)]
+    backend: BackendKind,
+
+    /// Toolchain image for `--backend container`.
+    #[arg(
+        long = "image",
+        global = true,
+        default_value = "auton-toolchain:latest"
+    )]
+    container_image: String,
+
+    /// Container engine for `--backend container` (default: docker, then podman).
+    #[arg(long, global = true)]
+    engine: Option<String>,
+
+    /// Append this build's timings to `<output>/metrics.jsonl`.
+    #[arg(long, global = true)]
+    metrics: bool,
+
+    /// Add the kernel, images and symbols to this artifact store, with the
+    /// manifest as a `builds` ref.
+    #[arg(long, global = true, value_name = "DIR")]
+    store: Option<PathBuf>,
+
+    /// Keep running and rebuild whenever workspace sources change.
+    #[arg(long)]
+    watch: bool,
+
+    /// Build defaults file (default: `./auton-build.toml`, then
+    /// `<workspace>/auton-build.toml`). Command-line flags override it.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Print the effective configuration (file merged with flags) and exit.
+    #[arg(long, global = true)]
+    print_config: bool,
+
+    /// Print every command the build would run (with its arguments,
+    /// environment and directory), the files it would write and the
+    /// expected cache hits, and exit without building.
+    #[arg(long, conflicts_with = "watch")]
+    dry_run: bool,
+
+    /// The loaded `auton-build.toml`, if any.
+    #[arg(skip)]
+    build_config: Option<(PathBuf, BuildConfig)>,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Validate the workspace layout, include paths, tracked files and
+    /// toolchain for the selected driver without building.
+    CheckWorkspace,
+    /// Remove build outputs by stage, optionally only those older than a
+    /// given age. With the make driver, `--stage all` also runs `make clean`,
+    /// except with `--older-than` (make cannot spare recent files).
+    Clean {
+        #[arg(long, value_enum, default_value_t = CleanStage::All)]
+        stage: CleanStage,
+
+        /// Only remove files last modified longer ago than this (90s, 30m,
+        /// 12h, 7d, 2w).
+        #[arg(long, value_parser = clean::parse_duration)]
+        older_than: Option<Duration>,
+    },
+    /// Inspect header dependencies recorded by the last native build.
+    Deps {
+        #[command(subcommand)]
+        command: DepsCmd,
+    },
+    /// Wrap an already-linked kernel into bootable images (default: ISO).
+    Image {
+        /// Kernel ELF to wrap.
+        #[arg(long, default_value = "build/kernel.elf")]
+        elf: PathBuf,
+    },
+    /// Disassemble the linked kernel with the target's objdump, streaming to
+    /// stdout. Arguments after `--` go to objdump.
+    Objdump(ObjdumpArgs),
+    /// Report section and symbol sizes of the linked kernel, with deltas
+    /// against the previous report; fails when growth exceeds a limit.
+    Size(SizeArgs),
+    /// Build a FAT32 or ext2 disk image from a directory tree, with fixed
+    /// timestamps so the same tree always gives the same image.
+    Mkfs(MkfsArgs),
+    /// Compare two linked kernels: added, removed and resized symbols,
+    /// moved sections and a moved load address or entry point.
+    Symdiff {
+        /// The earlier build's ELF.
+        old: PathBuf,
+        /// The later build's ELF.
+        new: PathBuf,
+    },
+}
+
+#[derive(Args)]
+struct ObjdumpArgs {
+    /// ELF to disassemble (default: `<output>/kernel.elf`, or `kernel.bin`
+    /// with the make driver).
+    #[arg(long)]
+    elf: Option<PathBuf>,
+
+    /// Only disassemble this symbol.
+    #[arg(long)]
+    symbol: Option<String>,
+
+    /// Interleave source lines (needs debug info).
+    #[arg(long)]
+    source: bool,
+
+    #[arg(last = true)]
+    extra: Vec<String>,
+}
+
+#[derive(Args)]
+struct SizeArgs {
+    /// Kernel ELF to analyse (default: `<output>/kernel.elf`).
+    #[arg(long)]
+    elf: Option<PathBuf>,
+
+    /// Previous report to compare against (default: `<output>/size.json`).
+    #[arg(long)]
+    baseline: Option<PathBuf>,
+
+    /// Symbols to list.
+    #[arg(long, default_value_t = 20)]
+    top: usize,
+
+    /// Fail if executable sections grow by more than this (e.g. 4K).
+    #[arg(long, value_parser = link::parse_size)]
+    max_text_growth: Option<u64>,
+
+    /// Fail if the allocated image grows by more than this.
+    #[arg(long, value_parser = link::parse_size)]
+    max_total_growth: Option<u64>,
+
+    /// Leave the baseline untouched even when the check passes.
+    #[arg(long)]
+    no_save: bool,
+}
+
+#[derive(Args)]
+struct MkfsArgs {
+    /// Filesystem to build.
+    #[arg(long, value_enum)]
+    fs: Filesystem,
+
+    /// Directory whose contents become the image's root.
+    #[arg(long)]
+    from: PathBuf,
+
+    /// Image to write.
+    #[arg(long)]
+    out: PathBuf,
+
+    /// Image size (FAT32 needs 33M at least).
+    #[arg(long, value_parser = link::parse_size, default_value = "64M")]
+    size: u64,
+
+    /// Volume label.
+    #[arg(long)]
+    label: Option<String>,
+
+    /// Seconds since the Unix epoch for every timestamp (default:
+    /// `SOURCE_DATE_EPOCH`, else 0).
+    #[arg(long)]
+    epoch: Option<u64>,
+}
+
+#[derive(Subcommand)]
+enum DepsCmd {
+    /// Dump the translation-unit → header graph.
+    Graph {
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum GraphFormat {
+    Dot,
+    Json,
+}
+
+#[derive(Args)]
+struct ImageArgs {
+    /// Bootloader for raw disk images.
+    #[arg(long, global = true, value_enum, default_value_t = Bootloader::Limine)]
+    bootloader: Bootloader,
+
+    /// Firmware to boot under: `uefi` puts Limine's `BOOTX64.EFI` on an EFI
+    /// system partition (raw) or requires GRUB's EFI image (ISO).
+    #[arg(long, global = true, value_enum, default_value_t = BootMode::Bios)]
+    boot: BootMode,
+
+    /// Flat stage2 binary for `--bootloader stage2` (defaults to the first
+    /// `bin`-format output of the assembly stage).
+    #[arg(long, global = true)]
+    stage2: Option<PathBuf>,
+
+    /// Directory holding Limine's `limine-bios.sys` and `BOOTX64.EFI`.
+    #[arg(long, global = true, default_value = "/usr/share/limine")]
+    limine_dir: PathBuf,
+
+    /// Size of raw disk images.
+    #[arg(long, global = true, value_parser = link::parse_size, default_value = "64M")]
+    raw_size: u64,
+}
+
+impl ImageArgs {
+    fn options(&self, format: ImageFormat) -> ImageOptions {
+        ImageOptions {
+            format,
+            bootloader: self.bootloader,
+            boot: self.boot,
+            stage2: self.stage2.clone(),
+            limine_dir: self.limine_dir.clone(),
+            raw_size: self.raw_size,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DiagnosticsFormat {
+    Human,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BackendKind {
+    /// Run tools directly from the host PATH.
+    Host,
+    /// Run each tool in a fresh container from `--image`.
+    Container,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Driver {
+    Make,
+    Native,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let cli = Cli::parse();
+    auton_core::logging::init();
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    apply_config(&mut cli, &matches)?;
+    if cli.print_config {
+        return print_config(&cli);
+    }
+    exec::set_backend(backend(&cli)?);
 
     let toolchain = ArchToolchain::for_arch(&cli.arch)?;
     let cc = cli.cc.clone().unwrap_or_else(|| toolchain.cc.clone());
 
+    if cli.emit_compdb && cli.driver == Driver::Make {
+        bail!("--emit-compdb needs --driver native (make does not report its compile commands)");
+    }
+
+    if cli.profile != Profile::Release && cli.driver == Driver::Make {
+        bail!("--profile needs --driver native (the Makefile has a single flag set)");
+    }
+
+    if cli.emit_listings && cli.driver == Driver::Make {
+        bail!("--emit-listings needs --driver native (make keeps its objects to itself)");
+    }
+
+    if (cli.stack_usage || cli.stack_budget.is_some()) && cli.driver == Driver::Make {
+        bail!("--stack-usage needs --driver native (make's compile flags are its own)");
+    }
+
+    if cli.reproducible && cli.driver == Driver::Make {
+        bail!("--reproducible needs --driver native (make's rules are outside our control)");
+    }
+
+    if cli.dry_run {
+        return dry_run(&cli, &cc).await;
+    }
+
+    if let Some(Cmd::Deps {
+        command: DepsCmd::Graph { format },
+    }) = &cli.command
+    {
+        let obj_dir = cli.profile.output_dir(&cli.output).join("obj");
+        let graph = DepGraph::load(&obj_dir)?;
+        match format {
+            GraphFormat::Dot => print!("{}", graph.to_dot()),
+            GraphFormat::Json => println!("{}", serde_json::to_string_pretty(&graph)?),
+        }
+        return Ok(());
+    }
+
+    if let Some(Cmd::Clean { stage, older_than }) = &cli.command {
+        return clean_outputs(&cli, *stage, *older_than).await;
+    }
+
+    if let Some(Cmd::Size(args)) = &cli.command {
+        return size_report(&cli, args);
+    }
+
+    if let Some(Cmd::Mkfs(args)) = &cli.command {
+        return make_filesystem(&cli, args);
+    }
+
+    if let Some(Cmd::Symdiff { old, new }) = &cli.command {
+        let delta = symdiff::compare(old, new)?;
+        if cli.json {
+            println!("{}", serde_json::to_string_pretty(&delta)?);
+        } else {
+            print_symdiff(&delta);
+        }
+        return Ok(());
+    }
+
+    if let Some(Cmd::Objdump(args)) = &cli.command {
+        return objdump(&cli, args).await;
+    }
+
+    if let Some(Cmd::CheckWorkspace) = &cli.command {
+        let report = check::check_workspace(&check_options(&cli)).await;
+        print_check_report(&cli, &report)?;
+        if !report.ok {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     tracing::info!(workspace = %cli.workspace.display(), arch = %cli.arch, cc = %cc, "building kernel");
 
-    let args = make_args(&cli.workspace, &cli.target, cli.clean);
-    let out = Command::new("make")
-        .args(&args)
-        .env("CC", &cc)
+    if cli.watch && cli.command.is_none() {
+        return watch_loop(&cli, &cc).await;
+    }
+
+    let result = {
+        let span = tracing::info_span!("build", arch = %cli.arch, success = tracing::field::Empty);
+        let result = run_once(&cli, &cc).instrument(span.clone()).await;
+        span.record("success", matches!(&result, Ok(outcome) if outcome.success));
+        result
+    };
+    if cli.diagnostics_format == DiagnosticsFormat::Json {
+        let outcome = with_diagnostics(&cli, &cc, result);
+        emit_json_lines(&outcome)?;
+        if !outcome.success {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let outcome = result?;
+    report(&cli, &outcome)?;
+    if !outcome.success {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Fill every flag left at its default from `auton-build.toml`.
+fn apply_config(cli: &mut Cli, matches: &ArgMatches) -> Result<()> {
+    let Some((path, config)) = BuildConfig::find(cli.config.as_deref(), &cli.workspace)? else {
+        return Ok(());
+    };
+    tracing::debug!(config = %path.display(), "loaded build config");
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    if !given("workspace") {
+        cli.workspace = config.workspace.clone().unwrap_or(cli.workspace.clone());
+    }
+    if !given("output") {
+        cli.output = config.output.clone().unwrap_or(cli.output.clone());
+    }
+    if !given("arch") {
+        cli.arch = config.default_arch().unwrap_or(&cli.arch).to_string();
+    }
+    config
+        .check_arch(&cli.arch)
+        .with_context(|| format!("checking {}", path.display()))?;
+    if let (false, Some(driver)) = (given("driver"), &config.driver) {
+        cli.driver = Driver::from_str(driver, true)
+            .map_err(|e| anyhow!("{}: driver: {e}", path.display()))?;
+    }
+    if !given("boot_dir") {
+        cli.boot_dir = config.boot_dir.clone().unwrap_or(cli.boot_dir.clone());
+    }
+    if !given("profile") {
+        cli.profile = config.profile.unwrap_or(cli.profile);
+    }
+    cli.cc = cli.cc.take().or(config.cc.clone());
+    cli.ld = cli.ld.take().or(config.ld.clone());
+    cli.linker_script = cli.linker_script.take().or(config.linker_script.clone());
+    cli.image_format = cli.image_format.or(config.image_format);
+    cli.jobs = cli.jobs.or(config.jobs);
+    cli.build_config = Some((path, config));
+    Ok(())
+}
+
+/// `--print-config`: the settings a build would use, as TOML (or JSON with
+/// `--json`).
+fn print_config(cli: &Cli) -> Result<()> {
+    let file = cli.build_config.as_ref();
+    let from_file = file.map(|(_, c)| c.clone()).unwrap_or_default();
+    let effective = BuildConfig {
+        workspace: Some(cli.workspace.clone()),
+        output: Some(cli.output.clone()),
+        arch: Some(cli.arch.clone()),
+        archs: from_file.archs,
+        driver: Some(driver_name(cli.driver).to_string()),
+        cc: cli.cc.clone(),
+        ld: cli.ld.clone(),
+        boot_dir: Some(cli.boot_dir.clone()),
+        linker_script: cli.linker_script.clone(),
+        image_format: cli.image_format,
+        profile: Some(cli.profile),
+        jobs: Some(cli.jobs.unwrap_or_else(jobs::default_jobs)),
+        sources: from_file.sources,
+        flags: from_file.flags,
+        stack: StackConfig {
+            budget: cli.stack_budget.or(from_file.stack.budget),
+            ..from_file.stack
+        },
+    };
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&effective)?);
+        return Ok(());
+    }
+    if let Some((path, _)) = file {
+        println!("# merged with {}", path.display());
+    }
+    print!(
+        "{}",
+        auton_toml::to_string(&serde_json::to_value(&effective)?)
+    );
+    Ok(())
+}
+
+fn driver_name(driver: Driver) -> &'static str {
+    match driver {
+        Driver::Make => "make",
+        Driver::Native => "native",
+    }
+}
+
+/// The execution backend selected on the command line. Containers mount the
+/// workspace and output directory (the working directory always is).
+fn backend(cli: &Cli) -> Result<exec::Backend> {
+    if cli.backend == BackendKind::Host {
+        return Ok(exec::Backend::Host);
+    }
+    let engine = match &cli.engine {
+        Some(e) => e.clone(),
+        None => exec::default_engine()
+            .context("--backend container needs docker or podman on PATH (or --engine)")?,
+    };
+    if !cli.dry_run {
+        std::fs::create_dir_all(&cli.output)
+            .with_context(|| format!("creating {}", cli.output.display()))?;
+    }
+    Ok(exec::Backend::Container {
+        engine,
+        image: cli.container_image.clone(),
+        mounts: vec![
+            std::path::absolute(&cli.workspace)?,
+            std::path::absolute(&cli.output)?,
+        ],
+    })
+}
+
+/// `--dry-run`: the plan of the build [`run_once`] would do.
+async fn dry_run(cli: &Cli, cc: &str) -> Result<()> {
+    if cli.command.is_some() {
+        bail!("--dry-run plans a build; it does not take a subcommand");
+    }
+    let mut plan = match cli.driver {
+        Driver::Make => {
+            let mut cmd =
+                exec::command(None, "make", &make_args(&cli.workspace, &cli.target, false))?;
+            cmd.env("CC", cc);
+            let mut plan = Plan::default();
+            let what = format!("building {}", cli.workspace.display());
+            plan.run(Planned::of(what, cmd.as_std()));
+            plan.write(cli.output.join("kernel.bin"));
+            plan
+        }
+        Driver::Native => {
+            let opts = native_options(cli);
+            let mut plan = pipeline::plan(&opts).await?;
+            if cli.reproducible {
+                plan.extend(pipeline::plan(&repro::verify_options(&opts)).await?);
+            }
+            plan
+        }
+    };
+    if cli.metrics {
+        plan.write(cli.output.join(metrics::METRICS_NAME));
+    }
+    if let Some(store) = &cli.store {
+        plan.write(store.clone());
+    }
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&plan)?);
+    } else {
+        print!("{}", plan.render());
+    }
+    Ok(())
+}
+
+async fn run_once(cli: &Cli, cc: &str) -> Result<BuildOutcome> {
+    let started = Instant::now();
+    let result = match (&cli.command, cli.driver) {
+        (Some(Cmd::Image { elf }), _) => build_images_only(cli, elf).await,
+        (
+            Some(
+                Cmd::CheckWorkspace
+                | Cmd::Clean { .. }
+                | Cmd::Deps { .. }
+                | Cmd::Mkfs(_)
+                | Cmd::Objdump(_)
+                | Cmd::Size(_)
+                | Cmd::Symdiff { .. },
+            ),
+            _,
+        ) => {
+            unreachable!("handled before building")
+        }
+        (None, Driver::Make) => build_with_make(cli, cc).await,
+        (None, Driver::Native) if cli.reproducible => {
+            repro::build_and_verify(&native_options(cli)).await
+        }
+        (None, Driver::Native) => pipeline::build(&native_options(cli)).await,
+    };
+    if cli.metrics && cli.command.is_none() {
+        record_metrics(cli, result.as_ref().ok(), started.elapsed()).await;
+    }
+    if let (Some(store), None, Ok(outcome)) = (&cli.store, &cli.command, &result) {
+        if outcome.success {
+            store_build(cli, store)?;
+        }
+    }
+    result
+}
+
+fn store_build(cli: &Cli, store: &Path) -> Result<()> {
+    let output = match cli.driver {
+        Driver::Make => cli.output.clone(),
+        Driver::Native => cli.profile.output_dir(&cli.output),
+    };
+    let manifest = ArtifactStore::new(store).add_build(&output)?;
+    tracing::info!(
+        "stored {} artifacts in {}",
+        manifest.artifacts.len(),
+        store.display()
+    );
+    Ok(())
+}
+
+/// Append to the metrics history; a failure to record never fails the build.
+async fn record_metrics(cli: &Cli, outcome: Option<&BuildOutcome>, elapsed: Duration) {
+    let driver = driver_name(cli.driver);
+    let mut record = BuildMetrics::new(outcome, &cli.arch, driver, cli.profile.name(), elapsed);
+    record.git_commit = manifest::git_commit(&cli.workspace).await;
+    let path = cli.output.join(metrics::METRICS_NAME);
+    if let Err(e) = metrics::append(&path, &record) {
+        tracing::warn!("recording metrics: {e:#}");
+    }
+}
+
+/// Fold a build result and everything the tools printed into one outcome;
+/// errors become a failed outcome instead of propagating.
+fn with_diagnostics(cli: &Cli, cc: &str, result: Result<BuildOutcome>) -> BuildOutcome {
+    let mut found = diagnostics::take();
+    let mut outcome = match result {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            found.extend(diagnostics::from_error(&e));
+            BuildOutcome {
+                success: false,
+                arch: cli.arch.clone(),
+                stderr: format!("{e:#}"),
+                ..Default::default()
+            }
+        }
+    };
+    // The make driver only has make's captured stderr; the compiler lines in
+    // it parse the same way.
+    if cli.driver == Driver::Make && !outcome.stderr.is_empty() {
+        found.extend(diagnostics::parse(cc, &outcome.stderr));
+    }
+    outcome.diagnostics = found;
+    outcome
+}
+
+fn emit_json_lines(outcome: &BuildOutcome) -> Result<()> {
+    for d in &outcome.diagnostics {
+        let mut v = serde_json::to_value(d)?;
+        v["type"] = "diagnostic".into();
+        println!("{v}");
+    }
+    let mut v = serde_json::to_value(outcome)?;
+    if let Some(obj) = v.as_object_mut() {
+        obj.remove("diagnostics");
+        obj.insert("type".into(), "outcome".into());
+    }
+    println!("{v}");
+    Ok(())
+}
+
+fn report(cli: &Cli, outcome: &BuildOutcome) -> Result<()> {
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&outcome)?);
+    } else if outcome.success {
+        match &outcome.artifact {
+            Some(artifact) => println!("build ok: {artifact}"),
+            None => println!("build ok: {} objects", outcome.objects.len()),
+        }
+        for img in &outcome.images {
+            println!("image: {img}");
+        }
+        if let Some(c) = &outcome.cache {
+            println!(
+                "cache: {} hits, {} misses, {} rebuilt",
+                c.hits, c.misses, c.rebuilt
+            );
+        }
+        if !outcome.timings.is_empty() {
+            println!("timings: {}", metrics::summary(&outcome.timings));
+        }
+    } else {
+        eprintln!("build failed:\n{}", outcome.stderr);
+    }
+    Ok(())
+}
+
+/// `--watch`: build once, then rebuild on every settled change. Failed
+/// rebuilds are reported and the watcher keeps going.
+async fn watch_loop(cli: &Cli, cc: &str) -> Result<()> {
+    let root = cli
+        .workspace
+        .canonicalize()
+        .with_context(|| format!("watching {}", cli.workspace.display()))?;
+    std::fs::create_dir_all(&cli.output)
+        .with_context(|| format!("creating {}", cli.output.display()))?;
+    let ignore = vec![cli.output.canonicalize()?];
+    let mut last = watch::snapshot(&root, &ignore);
+
+    let result = run_once(cli, cc).await;
+    if cli.diagnostics_format == DiagnosticsFormat::Json {
+        emit_json_lines(&with_diagnostics(cli, cc, result))?;
+        eprintln!("watching {} for changes (Ctrl-C to stop)", root.display());
+    } else {
+        diagnostics::take();
+        match result {
+            Ok(outcome) => report(cli, &outcome)?,
+            Err(e) => eprintln!("build failed: {e:#}"),
+        }
+        println!("watching {} for changes (Ctrl-C to stop)", root.display());
+    }
+
+    for n in 1.. {
+        let changes =
+            watch::wait_for_change(&root, &ignore, &mut last, WatchOptions::default()).await;
+        let started = Instant::now();
+        let result = run_once(cli, cc).await;
+        let secs = started.elapsed().as_secs_f64();
+        if cli.diagnostics_format == DiagnosticsFormat::Json {
+            emit_json_lines(&with_diagnostics(cli, cc, result))?;
+            continue;
+        }
+        diagnostics::take();
+        let what = watch::describe_changes(&changes);
+        match result {
+            Ok(outcome) if outcome.success => {
+                let cache = outcome
+                    .cache
+                    .map(|c| format!(", {} rebuilt, {} cached", c.misses, c.hits))
+                    .unwrap_or_default();
+                println!("rebuild #{n} [{what}]: ok in {secs:.2}s{cache}");
+            }
+            Ok(outcome) => {
+                println!("rebuild #{n} [{what}]: FAILED in {secs:.2}s");
+                eprintln!("{}", outcome.stderr.trim_end());
+            }
+            Err(e) => {
+                println!("rebuild #{n} [{what}]: FAILED in {secs:.2}s");
+                eprintln!("{e:#}");
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn build_with_make(cli: &Cli, cc: &str) -> Result<BuildOutcome> {
+    let mut timings = Timings::default();
+    let args = make_args(&cli.workspace, &cli.target, false);
+    let out = exec::command(None, "make", &args)?
+        .env("CC", cc)
         .output()
         .await
         .context("failed to spawn `make` (is it installed?)")?;
 
     let artifact = artifact_path(&cli.workspace);
-    let success = out.status.success() && artifact.exists();
+    let mut success = out.status.success() && artifact.exists();
+    let mut stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+    if success {
+        if let Err(e) = bootproto::verify_file(&artifact, &cli.arch, cli.boot_protocol) {
+            stderr.push_str(&format!("kernel-builder: {e:#}\n"));
+            success = false;
+        }
+    }
 
     let mut artifact_out = None;
+    let mut stages = Vec::new();
     if success {
         std::fs::create_dir_all(&cli.output)
             .with_context(|| format!("creating {}", cli.output.display()))?;
         let dest = cli.output.join("kernel.bin");
         std::fs::copy(&artifact, &dest).context("copying kernel artifact")?;
+        timings.lap("make");
+        stages = timings.into_stages();
+        write_make_manifest(cli, cc, &dest, stages.clone()).await?;
         artifact_out = Some(dest.display().to_string());
     }
 
-    let outcome = BuildOutcome {
+    Ok(BuildOutcome {
         success,
         arch: cli.arch.clone(),
         artifact: artifact_out,
         stdout: String::from_utf8_lossy(&out.stdout).into_owned(),
-        stderr: String::from_utf8_lossy(&out.stderr).into_owned(),
+        stderr,
+        timings: stages,
+        ..Default::default()
+    })
+}
+
+/// The make driver only knows the final artifact; objects stay inside make.
+async fn write_make_manifest(
+    cli: &Cli,
+    cc: &str,
+    kernel: &std::path::Path,
+    timings: Vec<StageTiming>,
+) -> Result<()> {
+    let toolchain = ArchToolchain::for_arch(&cli.arch)?;
+    // make leaves no map, but kernel.bin is the linked ELF.
+    let symbols = match symbols::export(kernel, &cli.output) {
+        Ok(path) => Some(path.display().to_string()),
+        Err(e) => {
+            tracing::debug!("no symbol table for {}: {e:#}", kernel.display());
+            None
+        }
+    };
+    let manifest = BuildManifest {
+        version: manifest::MANIFEST_VERSION,
+        arch: cli.arch.clone(),
+        profile: Profile::Release.name().to_string(),
+        workspace: cli.workspace.display().to_string(),
+        git_commit: manifest::git_commit(&cli.workspace).await,
+        kernel: kernel.display().to_string(),
+        symbols,
+        images: Vec::new(),
+        boot: None,
+        qemu: toolchain.qemu_defaults(),
+        toolchain: manifest::Toolchain {
+            cc: manifest::probe(cc).await,
+            ..Default::default()
+        },
+        artifacts: vec![Artifact::from_file(kernel, ArtifactKind::Kernel)?],
+        timings,
+        store: None,
     };
+    manifest.write(&cli.output)?;
+    Ok(())
+}
 
+fn check_options(cli: &Cli) -> CheckOptions {
+    CheckOptions {
+        workspace: cli.workspace.clone(),
+        arch: cli.arch.clone(),
+        cc: cli.cc.clone(),
+        ld: cli.ld.clone(),
+        linker_script: cli.linker_script.clone(),
+        boot_dir: cli.boot_dir.clone(),
+        native: cli.driver == Driver::Native,
+    }
+}
+
+fn print_check_report(cli: &Cli, report: &CheckReport) -> Result<()> {
     if cli.json {
-        println!("{}", serde_json::to_string_pretty(&outcome)?);
-    } else if success {
+        println!("{}", serde_json::to_string_pretty(report)?);
+        return Ok(());
+    }
+    for f in &report.findings {
+        let severity = match f.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let location = match (&f.path, f.line) {
+            (Some(path), Some(line)) => format!("{path}:{line}: "),
+            (Some(path), None) => format!("{path}: "),
+            _ => String::new(),
+        };
+        println!("{severity} [{}] {location}{}", f.check.name(), f.message);
+    }
+    let errors = report
+        .findings
+        .iter()
+        .filter(|f| f.severity == Severity::Error)
+        .count();
+    if report.ok {
+        println!("workspace ok: {}", cli.workspace.display());
+    } else {
+        println!("workspace check failed: {errors} error(s)");
+    }
+    Ok(())
+}
+
+fn native_options(cli: &Cli) -> pipeline::NativeOptions {
+    pipeline::NativeOptions {
+        workspace: cli.workspace.clone(),
+        arch: cli.arch.clone(),
+        output: cli.profile.output_dir(&cli.output),
+        cc: cli.cc.clone(),
+        boot_dir: cli.boot_dir.clone(),
+        linker_script: cli.linker_script.clone(),
+        ld: cli.ld.clone(),
+        max_image_size: cli.max_image_size,
+        image: cli.image_format.map(|f| cli.image.options(f)),
+        cache: !cli.no_cache,
+        cache_dir: cli.cache_dir.clone(),
+        jobs: cli.jobs.unwrap_or_else(jobs::default_jobs),
+        emit_compdb: cli.emit_compdb,
+        emit_listings: cli.emit_listings,
+        boot_protocol: cli.boot_protocol,
+        profile: cli.profile,
+        reproducible: cli.reproducible,
+        sources: cli
+            .build_config
+            .as_ref()
+            .map(|(_, c)| c.sources.clone())
+            .unwrap_or_default(),
+        dir_flags: cli
+            .build_config
+            .as_ref()
+            .map(|(_, c)| c.flags.clone())
+            .unwrap_or_default(),
+        stack: stack_config(cli),
+    }
+}
+
+/// `[stack]` with `--stack-budget` applied, when the analysis is on:
+/// `--stack-usage`, a budget flag, or a `[stack] budget`.
+fn stack_config(cli: &Cli) -> Option<StackConfig> {
+    let mut config = cli
+        .build_config
+        .as_ref()
+        .map(|(_, c)| c.stack.clone())
+        .unwrap_or_default();
+    config.budget = cli.stack_budget.or(config.budget);
+    (cli.stack_usage || config.budget.is_some()).then_some(config)
+}
+
+/// `clean` subcommand.
+async fn clean_outputs(cli: &Cli, stage: CleanStage, older_than: Option<Duration>) -> Result<()> {
+    if cli.driver == Driver::Make && stage == CleanStage::All && older_than.is_none() {
+        let out = exec::command(None, "make", &make_args(&cli.workspace, "clean", false))?
+            .output()
+            .await
+            .context("failed to spawn `make` (is it installed?)")?;
+        if !out.status.success() {
+            bail!(
+                "make clean failed:\n{}",
+                String::from_utf8_lossy(&out.stderr)
+            );
+        }
+    }
+    let output = cli.profile.output_dir(&cli.output);
+    let (output, report) = match (&cli.cache_dir, stage) {
+        (Some(dir), CleanStage::Cache) => (dir.clone(), clean::clean_dir(dir, older_than)?),
+        _ => {
+            let report = clean::clean(&output, stage, older_than)?;
+            (output, report)
+        }
+    };
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!(
+            "removed {} files ({} bytes) from {}",
+            report.files,
+            report.bytes,
+            output.display()
+        );
+    }
+    Ok(())
+}
+
+/// `size` subcommand: print the report and delta, enforce the limits, and
+/// advance the baseline when they hold.
+/// `objdump` subcommand.
+async fn objdump(cli: &Cli, args: &ObjdumpArgs) -> Result<()> {
+    let elf = args.elf.clone().unwrap_or_else(|| match cli.driver {
+        Driver::Make => cli.output.join("kernel.bin"),
+        Driver::Native => cli.profile.output_dir(&cli.output).join("kernel.elf"),
+    });
+    if !elf.is_file() {
+        bail!("{} not found (build first or pass --elf)", elf.display());
+    }
+    let program = listing::find_objdump(&ArchToolchain::for_arch(&cli.arch)?)?;
+    let mut objdump_args = vec!["-d".to_string(), "-w".to_string()];
+    if args.source {
+        objdump_args.push("-S".to_string());
+    }
+    if let Some(sym) = &args.symbol {
+        // GNU objdump and llvm-objdump spell this differently.
+        let flag = if program.contains("llvm") {
+            "--disassemble-symbols"
+        } else {
+            "--disassemble"
+        };
+        objdump_args.push(format!("{flag}={sym}"));
+    }
+    objdump_args.extend(args.extra.iter().cloned());
+    objdump_args.push(elf.display().to_string());
+    let status = exec::command(None, &program, &objdump_args)?
+        .status()
+        .await
+        .with_context(|| format!("failed to spawn `{program}` (is it installed?)"))?;
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(1));
+    }
+    Ok(())
+}
+
+fn make_filesystem(cli: &Cli, args: &MkfsArgs) -> Result<()> {
+    let opts = MkfsOptions {
+        fs: args.fs,
+        size: args.size,
+        label: args.label.clone(),
+        epoch: args.epoch.unwrap_or_else(mkfs::default_epoch),
+    };
+    let report = mkfs::build(&args.from, &args.out, &opts)?;
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
         println!(
-            "build ok: {}",
-            outcome.artifact.as_deref().unwrap_or("(unknown)")
+            "{}: {} image, {} bytes, {} files in {} directories, sha256 {}",
+            report.image.display(),
+            report.fs.name(),
+            report.size,
+            report.files,
+            report.directories,
+            report.sha256
         );
+    }
+    Ok(())
+}
+
+fn size_report(cli: &Cli, args: &SizeArgs) -> Result<()> {
+    let out_dir = cli.profile.output_dir(&cli.output);
+    let elf = args
+        .elf
+        .clone()
+        .unwrap_or_else(|| out_dir.join("kernel.elf"));
+    let baseline_path = args
+        .baseline
+        .clone()
+        .unwrap_or_else(|| out_dir.join(size::BASELINE_NAME));
+    let report = size::analyze(&elf)?;
+    let baseline = baseline_path
+        .is_file()
+        .then(|| size::SizeReport::load(&baseline_path))
+        .transpose()?;
+    let delta = baseline.as_ref().map(|b| size::diff(b, &report));
+    let limits = size::SizeLimits {
+        max_text_growth: args.max_text_growth,
+        max_total_growth: args.max_total_growth,
+    };
+    let problems = delta
+        .as_ref()
+        .map(|d| size::check(d, &limits))
+        .unwrap_or_default();
+
+    if cli.json {
+        let v = serde_json::json!({
+            "report": report,
+            "delta": delta,
+            "problems": problems,
+        });
+        println!("{}", serde_json::to_string_pretty(&v)?);
     } else {
-        eprintln!("build failed:\n{}", outcome.stderr);
+        print_size_report(&report, delta.as_ref(), args.top);
+        for p in &problems {
+            eprintln!("size check failed: {p}");
+        }
     }
 
-    if !success {
+    if !problems.is_empty() {
         std::process::exit(1);
     }
+    if !args.no_save {
+        report.save(&baseline_path)?;
+    }
     Ok(())
 }
+
+fn print_size_report(report: &size::SizeReport, delta: Option<&size::SizeDelta>, top: usize) {
+    let fmt_delta = |d: i64| {
+        if d == 0 {
+            String::new()
+        } else {
+            format!(" ({d:+})")
+        }
+    };
+    let (dt, dd, db, dtot) = delta.map_or((0, 0, 0, 0), |d| (d.text, d.data, d.bss, d.total));
+    println!("{}", report.elf);
+    println!("  text  {:>10}{}", report.text, fmt_delta(dt));
+    println!("  data  {:>10}{}", report.data, fmt_delta(dd));
+    println!("  bss   {:>10}{}", report.bss, fmt_delta(db));
+    println!("  total {:>10}{}", report.total, fmt_delta(dtot));
+    println!("sections:");
+    for s in &report.sections {
+        let d = delta
+            .and_then(|d| d.sections.iter().find(|c| c.name == s.name))
+            .map_or(0, |c| c.delta);
+        println!("  {:<24} {:>10}{}", s.name, s.size, fmt_delta(d));
+    }
+    println!("largest symbols:");
+    for s in report.symbols.iter().take(top) {
+        println!("  {:<40} {:>10}  {}", s.name, s.size, s.section);
+    }
+    if let Some(d) = delta.filter(|d| !d.symbols.is_empty()) {
+        println!("largest symbol changes:");
+        for c in d.symbols.iter().take(top) {
+            println!(
+                "  {:<40} {:>10} -> {:<10} ({:+})",
+                c.name, c.before, c.after, c.delta
+            );
+        }
+    }
+}
+
+fn print_symdiff(d: &SymDiff) {
+    println!("{} -> {}", d.old, d.new);
+    if let Some(m) = d.load_address {
+        println!("  load address {:#x} -> {:#x}", m.before, m.after);
+    }
+    if let Some(m) = d.entry {
+        println!("  entry        {:#x} -> {:#x}", m.before, m.after);
+    }
+    let place = |p: Option<symdiff::Placement>| {
+        p.map_or("-".to_string(), |p| format!("{:#x}+{:#x}", p.addr, p.size))
+    };
+    if !d.sections.is_empty() {
+        println!("sections:");
+        for s in &d.sections {
+            println!(
+                "  {:<24} {:>20} -> {}",
+                s.name,
+                place(s.before),
+                place(s.after)
+            );
+        }
+    }
+    let export = |exported: bool| if exported { "  exported" } else { "" };
+    for (title, symbols) in [("added", &d.added), ("removed", &d.removed)] {
+        if !symbols.is_empty() {
+            println!("{title}:");
+        }
+        for s in symbols {
+            println!(
+                "  {:<40} {:>10}  {}{}",
+                s.name,
+                s.size,
+                s.section,
+                export(s.exported)
+            );
+        }
+    }
+    if !d.resized.is_empty() {
+        println!("resized:");
+    }
+    for r in &d.resized {
+        println!(
+            "  {:<40} {:>10} -> {:<10} ({:+}){}",
+            r.name,
+            r.before,
+            r.after,
+            r.delta,
+            export(r.exported)
+        );
+    }
+    if d.is_empty() {
+        println!("no symbol or layout changes");
+    }
+}
+
+/// `image` subcommand: wrap an existing ELF without rebuilding it.
+async fn build_images_only(cli: &Cli, elf: &std::path::Path) -> Result<BuildOutcome> {
+    let opts = cli
+        .image
+        .options(cli.image_format.unwrap_or(ImageFormat::Iso));
+    let images = image::build_images(elf, &cli.workspace, &cli.output, &opts).await?;
+    Ok(BuildOutcome {
+        success: true,
+        arch: cli.arch.clone(),
+        artifact: Some(elf.display().to_string()),
+        images: images.iter().map(|p| p.display().to_string()).collect(),
+        ..Default::default()
+    })
+}
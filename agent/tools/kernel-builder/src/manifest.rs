@@ -0,0 +1,75 @@
+//! `<output>/manifest.json`: what a build produced and how to boot it.
+//!
+//! The model lives in [`auton_core::manifest`] so test-runner,
+//! diff-validator and the orchestrator read the same types kernel-builder
+//! writes; this module adds the parts that need a toolchain: probing tool
+//! versions and the workspace's commit.
+
+use crate::exec;
+use std::path::Path;
+use tokio::process::Command;
+
+pub use auton_core::manifest::*;
+
+/// Resolve `program` on the backend's PATH and ask it for `--version`.
+pub async fn probe(program: &str) -> ToolInfo {
+    ToolInfo {
+        program: program.to_string(),
+        path: exec::which(program).map(|p| p.display().to_string()),
+        version: version_line(program).await,
+    }
+}
+
+/// HEAD of the git checkout containing `workspace`, if any.
+pub async fn git_commit(workspace: &Path) -> Option<String> {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(workspace)
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .await
+        .ok()?;
+    let sha = String::from_utf8_lossy(&out.stdout).trim().to_string();
+    (out.status.success() && !sha.is_empty()).then_some(sha)
+}
+
+/// First non-empty line of `program --version`.
+async fn version_line(program: &str) -> Option<String> {
+    let out = exec::command(None, program, &["--version".to_string()])
+        .ok()?
+        .output()
+        .await
+        .ok()?;
+    let text = String::from_utf8_lossy(&out.stdout);
+    text.lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::Profile;
+    use crate::ArchToolchain;
+
+    #[test]
+    fn records_arch_qemu_machine_for_test_runner() {
+        let manifest = BuildManifest {
+            version: MANIFEST_VERSION,
+            arch: "aarch64".into(),
+            profile: Profile::KasanLite.name().into(),
+            qemu: ArchToolchain::for_arch("aarch64").unwrap().qemu_defaults(),
+            ..Default::default()
+        };
+        let v = serde_json::to_value(manifest).unwrap();
+        assert_eq!(v["qemu"]["binary"], "qemu-system-aarch64");
+        assert_eq!(v["qemu"]["machine"], "virt");
+        assert_eq!(
+            v["profile"],
+            serde_json::to_value(Profile::KasanLite).unwrap()
+        );
+        assert!(v.get("images").is_none());
+        assert!(v.get("git_commit").is_none());
+    }
+}
@@ -0,0 +1,146 @@
+//! `--metrics`: append one JSON line per build to `<output>/metrics.jsonl`.
+//!
+//! Each record carries the stage timings from the outcome plus enough
+//! context (commit, profile, cache hit rate) to chart build time across the
+//! hundreds of iterations an agent session runs. The file is append-only and
+//! lives in the base output directory, so every profile shares one history.
+
+use crate::cache::CacheStats;
+use crate::manifest::StageTiming;
+use crate::BuildOutcome;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const METRICS_NAME: &str = "metrics.jsonl";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildMetrics {
+    /// Seconds since the Unix epoch when the build finished.
+    pub timestamp: u64,
+    pub arch: String,
+    pub driver: String,
+    pub profile: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub git_commit: Option<String>,
+    pub success: bool,
+    /// Wall-clock time of the whole build, stages included.
+    pub total_ms: u64,
+    #[serde(default)]
+    pub stages: Vec<StageTiming>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub cache: Option<CacheStats>,
+}
+
+impl BuildMetrics {
+    /// Record for `outcome`; a build that errored out has no outcome and
+    /// is recorded as a failure without stages.
+    pub fn new(
+        outcome: Option<&BuildOutcome>,
+        arch: &str,
+        driver: &str,
+        profile: &str,
+        elapsed: Duration,
+    ) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            arch: arch.to_string(),
+            driver: driver.to_string(),
+            profile: profile.to_string(),
+            git_commit: None,
+            success: outcome.is_some_and(|o| o.success),
+            total_ms: elapsed.as_millis() as u64,
+            stages: outcome.map(|o| o.timings.clone()).unwrap_or_default(),
+            cache: outcome.and_then(|o| o.cache),
+        }
+    }
+}
+
+/// Append `record` as one line to `path`, creating the file if needed.
+pub fn append(path: &Path, record: &BuildMetrics) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    }
+    let line = serde_json::to_string(record)? + "\n";
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut f| f.write_all(line.as_bytes()))
+        .with_context(|| format!("appending to {}", path.display()))
+}
+
+/// Every record in `path`, oldest first; unparseable lines are skipped.
+pub fn load(path: &Path) -> Result<Vec<BuildMetrics>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    Ok(text
+        .lines()
+        .filter_map(|l| serde_json::from_str(l).ok())
+        .collect())
+}
+
+/// `assemble 3ms, compile 812ms, link 9ms (total 824ms)`.
+pub fn summary(stages: &[StageTiming]) -> String {
+    let total: u64 = stages.iter().map(|s| s.ms).sum();
+    let parts: Vec<String> = stages
+        .iter()
+        .map(|s| format!("{} {}ms", s.stage, s.ms))
+        .collect();
+    format!("{} (total {total}ms)", parts.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stages() -> Vec<StageTiming> {
+        ["assemble", "compile", "link"]
+            .iter()
+            .zip([3, 812, 9])
+            .map(|(s, ms)| StageTiming {
+                stage: s.to_string(),
+                ms,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn appends_and_reloads_history() {
+        let path = std::env::temp_dir()
+            .join(format!("kb-metrics-{}", std::process::id()))
+            .join(METRICS_NAME);
+        let outcome = BuildOutcome {
+            success: true,
+            timings: stages(),
+            ..Default::default()
+        };
+        let first = BuildMetrics::new(
+            Some(&outcome),
+            "x86_64",
+            "native",
+            "release",
+            Duration::from_millis(900),
+        );
+        let failed = BuildMetrics::new(None, "x86_64", "native", "release", Duration::ZERO);
+        append(&path, &first).unwrap();
+        append(&path, &failed).unwrap();
+        let history = load(&path).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(history, [first, failed]);
+        assert!(!history[1].success && history[1].stages.is_empty());
+    }
+
+    #[test]
+    fn summary_lists_stages_in_order() {
+        assert_eq!(
+            summary(&stages()),
+            "assemble 3ms, compile 812ms, link 9ms (total 824ms)"
+        );
+    }
+}
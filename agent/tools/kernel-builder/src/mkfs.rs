@@ -0,0 +1,231 @@
+//! `kernel-builder mkfs`: filesystem test images built from a directory.
+//!
+//! Filesystem-driver tests boot against a disk image; instead of checking
+//! in hand-made binaries, a test keeps its fixture as a plain directory
+//! tree and builds the image with `kernel-builder mkfs --fs fat32|ext2
+//! --from <dir> --out <image>`. The writers are our own ([`crate::fat32`],
+//! [`crate::ext2`]) rather than mkfs.fat/mke2fs, so the same tree always
+//! gives the same bytes on any host: entries are laid out in byte order of
+//! their names, every timestamp is `--epoch` (`SOURCE_DATE_EPOCH`, else
+//! 0), and volume ids are derived from the label and epoch. Host
+//! ownership, permissions other than the executable bit, and timestamps
+//! are not carried over.
+
+use crate::{ext2, fat32};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+    Fat32,
+    Ext2,
+}
+
+impl Filesystem {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Fat32 => "fat32",
+            Self::Ext2 => "ext2",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MkfsOptions {
+    pub fs: Filesystem,
+    /// Image size in bytes.
+    pub size: u64,
+    pub label: Option<String>,
+    /// Seconds since the Unix epoch every timestamp is set to.
+    pub epoch: u64,
+}
+
+/// A host file or directory, as the image will hold it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    File {
+        name: String,
+        data: Vec<u8>,
+        executable: bool,
+    },
+    Dir {
+        name: String,
+        children: Vec<Node>,
+    },
+    Symlink {
+        name: String,
+        target: String,
+    },
+}
+
+impl Node {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::File { name, .. } | Self::Dir { name, .. } | Self::Symlink { name, .. } => name,
+        }
+    }
+}
+
+/// The entries of `dir`, sorted by name at every level.
+pub fn read_tree(dir: &Path) -> Result<Vec<Node>> {
+    let mut nodes = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))? {
+        let entry = entry?;
+        let path = entry.path();
+        let Some(name) = entry.file_name().to_str().map(String::from) else {
+            bail!("{}: file names must be UTF-8", path.display());
+        };
+        let meta = std::fs::symlink_metadata(&path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        nodes.push(if meta.is_symlink() {
+            let target = std::fs::read_link(&path)?;
+            Node::Symlink {
+                name,
+                target: target.to_string_lossy().into_owned(),
+            }
+        } else if meta.is_dir() {
+            Node::Dir {
+                name,
+                children: read_tree(&path)?,
+            }
+        } else if meta.is_file() {
+            Node::File {
+                name,
+                data: std::fs::read(&path)
+                    .with_context(|| format!("reading {}", path.display()))?,
+                executable: meta.permissions().mode() & 0o111 != 0,
+            }
+        } else {
+            bail!(
+                "{}: only files, directories and symlinks fit in an image",
+                path.display()
+            );
+        });
+    }
+    nodes.sort_by(|a, b| a.name().cmp(b.name()));
+    Ok(nodes)
+}
+
+/// What `mkfs` built.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MkfsReport {
+    pub image: std::path::PathBuf,
+    pub fs: Filesystem,
+    pub size: u64,
+    pub files: usize,
+    pub directories: usize,
+    pub epoch: u64,
+    /// SHA-256 of the image, identical for identical inputs.
+    pub sha256: String,
+}
+
+/// The image of `tree` under `opts`.
+pub fn image(tree: &[Node], opts: &MkfsOptions) -> Result<Vec<u8>> {
+    match opts.fs {
+        Filesystem::Fat32 => fat32::build(tree, opts),
+        Filesystem::Ext2 => ext2::build(tree, opts),
+    }
+}
+
+/// Build the image of the directory `from` into `out`.
+pub fn build(from: &Path, out: &Path, opts: &MkfsOptions) -> Result<MkfsReport> {
+    let tree = read_tree(from)?;
+    let bytes = image(&tree, opts)
+        .with_context(|| format!("building a {} image of {}", opts.fs.name(), from.display()))?;
+    if let Some(dir) = out.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    }
+    std::fs::write(out, &bytes).with_context(|| format!("writing {}", out.display()))?;
+    let (files, directories) = count(&tree);
+    Ok(MkfsReport {
+        image: out.to_path_buf(),
+        fs: opts.fs,
+        size: bytes.len() as u64,
+        files,
+        directories,
+        epoch: opts.epoch,
+        sha256: crate::hash::hex(&crate::hash::sha256(&bytes)),
+    })
+}
+
+/// Files (and symlinks) and directories in `tree`.
+fn count(tree: &[Node]) -> (usize, usize) {
+    tree.iter().fold((0, 0), |(files, dirs), node| match node {
+        Node::Dir { children, .. } => {
+            let (f, d) = count(children);
+            (files + f, dirs + d + 1)
+        }
+        _ => (files + 1, dirs),
+    })
+}
+
+/// `SOURCE_DATE_EPOCH`, else 0.
+pub fn default_epoch() -> u64 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// A volume id for `label` and `epoch`, so images differ only by their
+/// inputs.
+pub(crate) fn volume_id(opts: &MkfsOptions) -> [u8; 16] {
+    let seed = format!(
+        "{}:{}:{}",
+        opts.fs.name(),
+        opts.label.as_deref().unwrap_or(""),
+        opts.epoch
+    );
+    crate::hash::sha256(seed.as_bytes())[..16]
+        .try_into()
+        .expect("16 bytes")
+}
+
+/// Calendar date and time of `epoch`, UTC: (year, month, day, hour,
+/// minute, second).
+pub fn civil(epoch: u64) -> (u64, u64, u64, u64, u64, u64) {
+    let days = epoch / 86_400;
+    let secs = epoch % 86_400;
+    // Howard Hinnant's days-to-civil, for days after 1970-01-01.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_epochs_to_dates() {
+        assert_eq!(civil(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(civil(951_782_400), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(civil(1_700_000_000), (2023, 11, 14, 22, 13, 20));
+    }
+
+    #[test]
+    fn reads_trees_in_name_order() {
+        let dir = std::env::temp_dir().join(format!("mkfs-tree-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("b.txt"), "b").unwrap();
+        std::fs::write(dir.join("sub/a"), "a").unwrap();
+        std::os::unix::fs::symlink("b.txt", dir.join("a")).unwrap();
+        let tree = read_tree(&dir).unwrap();
+        let names: Vec<_> = tree.iter().map(Node::name).collect();
+        assert_eq!(names, ["a", "b.txt", "sub"]);
+        assert_eq!(count(&tree), (3, 1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,567 @@
+//! The native (make-free) build pipeline: assemble → compile → rust → link →
+//! image.
+//!
+//! Lives in the library so other tools can drive a build without shelling
+//! out to the `kernel-builder` binary. Stage errors propagate with the failing
+//! tool's stderr attached.
+
+use crate::asm::{AsmJob, AsmTools};
+use crate::bootproto::{self, BootProtocol};
+use crate::cache::{self, BuildCache, CacheStats};
+use crate::config::{self, SourceGlobs};
+use crate::image::{self, ImageOptions, Step};
+use crate::manifest::{self, Artifact, ArtifactKind, BuildManifest, Timings, ToolInfo};
+use crate::profile::Profile;
+use crate::stack::{self, StackConfig};
+use crate::toolchain::{CompileJob, Compiler};
+use crate::{
+    asm, compdb, exec, jobs, link, listing, prelink, repro, rust, symbols, toolchain,
+    ArchToolchain, AsmSyntax, BuildOutcome,
+};
+use anyhow::{bail, Context, Result};
+use auton_core::plan::{Plan, Planned};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tracing::Instrument;
+
+/// Everything a native build needs; `main.rs` fills this from the CLI.
+#[derive(Debug, Clone)]
+pub struct NativeOptions {
+    pub workspace: PathBuf,
+    pub arch: String,
+    pub output: PathBuf,
+    pub cc: Option<String>,
+    /// Assembly source directory, relative to the workspace.
+    pub boot_dir: PathBuf,
+    pub linker_script: Option<PathBuf>,
+    pub ld: Option<String>,
+    pub max_image_size: u64,
+    /// Build bootable images after linking. A missing `stage2` defaults to the
+    /// first `bin`-format assembly output.
+    pub image: Option<ImageOptions>,
+    /// Use the object cache (`--no-cache` turns this off).
+    pub cache: bool,
+    /// Where the object cache is; `None` is `<output>/.cache`.
+    pub cache_dir: Option<PathBuf>,
+    /// Maximum concurrent assembler/compiler processes.
+    pub jobs: usize,
+    /// Write `<output>/compile_commands.json` before compiling.
+    pub emit_compdb: bool,
+    /// Write disassembly and NASM listings to `<output>/listings/`.
+    pub emit_listings: bool,
+    /// Boot protocol header the linked kernel must carry.
+    pub boot_protocol: BootProtocol,
+    /// Optimisation/instrumentation flags; `output` is already namespaced.
+    pub profile: Profile,
+    /// Add path-normalising flags and pin `SOURCE_DATE_EPOCH` (see
+    /// [`crate::repro`]); verification is [`crate::repro::build_and_verify`].
+    pub reproducible: bool,
+    /// `[sources]` from `auton-build.toml`; the default compiles everything
+    /// under `kernel/`.
+    pub sources: SourceGlobs,
+    /// `[flags]` from `auton-build.toml`: directory → extra C flags.
+    pub dir_flags: BTreeMap<String, Vec<String>>,
+    /// Compile with `-fstack-usage` and check the linked kernel's stack
+    /// depth (see [`crate::stack`]), writing `<output>/stack.json`.
+    pub stack: Option<StackConfig>,
+}
+
+/// The toolchain and compile jobs of a build, as [`build`] runs them and
+/// [`plan`] lists them.
+struct Prepared {
+    tc: ArchToolchain,
+    compiler: Compiler,
+    cache: BuildCache,
+    obj_dir: PathBuf,
+    asm_jobs: Vec<AsmJob>,
+    cc_jobs: Vec<CompileJob>,
+    /// `SOURCE_DATE_EPOCH` for a reproducible build.
+    epoch: Option<u64>,
+}
+
+/// `planning` is a dry run, which goes on with [`toolchain::assumed`] when
+/// no compiler is found instead of failing.
+async fn prepare(opts: &NativeOptions, planning: bool) -> Result<Prepared> {
+    let tc = ArchToolchain::for_arch(&opts.arch)?;
+    let compiler = match toolchain::detect(&tc, opts.cc.as_deref()).await {
+        Ok(compiler) => compiler,
+        Err(e) if planning => {
+            tracing::warn!("{e:#}");
+            toolchain::assumed(&tc, opts.cc.as_deref())
+        }
+        Err(e) => return Err(e),
+    };
+    tracing::info!(cc = %compiler.path.display(), version = %compiler.version, "toolchain");
+
+    let cache = match &opts.cache_dir {
+        Some(dir) => BuildCache::at(dir.clone(), opts.cache),
+        None => BuildCache::new(&opts.output, opts.cache),
+    };
+    let obj_dir = opts.output.join("obj");
+
+    let asm_tools = asm_tools(&tc, &compiler.program);
+    let mut asm_jobs = asm::plan(&opts.workspace, &opts.boot_dir, &obj_dir, &asm_tools)?;
+    let mut cc_jobs = if opts.sources.is_default() {
+        toolchain::plan(&opts.workspace, &obj_dir, &compiler, opts.profile)?
+    } else {
+        let sources = opts.sources.select(&opts.workspace)?;
+        toolchain::plan_files(&opts.workspace, &obj_dir, &compiler, opts.profile, sources)?
+    };
+    for job in &mut cc_jobs {
+        let rel = job
+            .source
+            .strip_prefix(&opts.workspace)
+            .unwrap_or(&job.source);
+        job.args.extend(config::flags_for(&opts.dir_flags, rel));
+        if opts.stack.is_some() {
+            job.args.push(stack::STACK_USAGE_FLAG.to_string());
+        }
+    }
+    let mut epoch = None;
+    if opts.reproducible {
+        let pinned = repro::source_date_epoch(&opts.workspace).await;
+        epoch = Some(pinned);
+        let maps = repro::prefix_map_args(
+            &std::env::current_dir()?,
+            &std::path::absolute(&opts.workspace)?,
+        );
+        tracing::info!(source_date_epoch = pinned, "reproducible mode");
+        let c_driven = asm_jobs
+            .iter_mut()
+            .filter(|j| j.program == compiler.program);
+        for args in c_driven
+            .map(|j| &mut j.args)
+            .chain(cc_jobs.iter_mut().map(|j| &mut j.args))
+        {
+            args.extend(maps.iter().cloned());
+        }
+    }
+    Ok(Prepared {
+        tc,
+        compiler,
+        cache,
+        obj_dir,
+        asm_jobs,
+        cc_jobs,
+        epoch,
+    })
+}
+
+/// Run the native pipeline end to end.
+pub async fn build(opts: &NativeOptions) -> Result<BuildOutcome> {
+    let mut timings = Timings::default();
+    let Prepared {
+        tc,
+        compiler,
+        cache,
+        obj_dir,
+        asm_jobs,
+        cc_jobs,
+        epoch,
+    } = prepare(opts, false).await?;
+    if let Some(epoch) = epoch {
+        repro::pin_epoch(epoch);
+    }
+    let mut stats = CacheStats::default();
+
+    if opts.emit_compdb {
+        let cwd = std::env::current_dir()?;
+        let commands = compdb::entries(&cwd, &asm_jobs, &cc_jobs, &compiler.program);
+        let path = compdb::write(&opts.output, &commands)?;
+        tracing::info!(compdb = %path.display(), entries = commands.len(), "wrote compile database");
+    }
+
+    let outcomes = jobs::run_bounded(asm_jobs.clone(), opts.jobs, |job| {
+        let cache = cache.clone();
+        async move { asm::assemble(&job, &cache).await }
+    })
+    .instrument(tracing::info_span!("assemble"))
+    .await?;
+    outcomes.into_iter().for_each(|o| stats.record(o));
+    timings.lap("assemble");
+
+    let outcomes = jobs::run_bounded(cc_jobs.clone(), opts.jobs, |job| {
+        let cache = cache.clone();
+        async move { toolchain::compile(&job, &cache).await }
+    })
+    .instrument(tracing::info_span!("compile"))
+    .await?;
+    outcomes.into_iter().for_each(|o| stats.record(o));
+    timings.lap("compile");
+
+    let mut rust_job = None;
+    if let Some(crate_dir) = rust::detect(&opts.workspace) {
+        let cargo_toml = std::fs::read_to_string(crate_dir.join("Cargo.toml"))?;
+        let job = rust::plan(&crate_dir, &cargo_toml, &tc, &opts.output, opts.profile)?;
+        rust::build(&job)
+            .instrument(tracing::info_span!("rust"))
+            .await?;
+        timings.lap("rust");
+        rust_job = Some(job);
+    }
+
+    // Archives go last so C references resolve against them.
+    let objects: Vec<PathBuf> = asm_jobs
+        .iter()
+        .filter(|j| j.is_link_input())
+        .map(|j| j.output.clone())
+        .chain(cc_jobs.iter().map(|j| j.object.clone()))
+        .chain(rust_job.iter().map(|j| j.staticlib.clone()))
+        .collect();
+
+    let script = link::find_script(&opts.workspace, &opts.arch, opts.linker_script.as_deref())?;
+    let linker = link::find_linker(&tc, opts.ld.as_deref())?;
+    let script_text = std::fs::read_to_string(&script)
+        .with_context(|| format!("reading linker script {}", script.display()))?;
+    if let Some(report) = prelink::check(
+        &objects,
+        &obj_dir,
+        &script_text,
+        &opts.workspace,
+        &opts.output,
+    ) {
+        if !report.is_clean() {
+            bail!("{report}");
+        }
+    }
+    let elf_out = opts.output.join("kernel.elf");
+    let report = link::link(&linker, &script, &objects, &elf_out, opts.max_image_size)
+        .instrument(tracing::info_span!("link"))
+        .await?;
+    tracing::info!(
+        elf = %report.elf,
+        entry = format!("{:#x}", report.entry),
+        loaded_size = report.loaded_size,
+        "linked"
+    );
+    let symbols_path = symbols::export(&elf_out, &opts.output)?;
+    timings.lap("link");
+
+    if let Some(found) = bootproto::verify_file(&elf_out, &opts.arch, opts.boot_protocol)? {
+        tracing::info!(?found, "boot protocol header");
+    }
+
+    if opts.emit_listings {
+        let objdump = listing::find_objdump(&tc)?;
+        let jobs = listing::plan(
+            &objdump,
+            &obj_dir,
+            &opts.output,
+            &objects,
+            &asm_jobs,
+            &elf_out,
+        );
+        let count = jobs.len();
+        jobs::run_bounded(
+            jobs,
+            opts.jobs,
+            |job| async move { listing::run(&job).await },
+        )
+        .await?;
+        tracing::info!(
+            listings = count,
+            dir = %opts.output.join(listing::LISTINGS_DIR).display(),
+            "wrote listings"
+        );
+        timings.lap("listings");
+    }
+
+    if let Some(stack_config) = &opts.stack {
+        let objdump = listing::find_objdump(&tc)?;
+        let su_files = cc_jobs.iter().map(|j| j.object.with_extension("su"));
+        let report = stack::run(&objdump, &elf_out, su_files, stack_config)
+            .instrument(tracing::info_span!("stack"))
+            .await?;
+        let path = report.write(&opts.output)?;
+        let deepest = report.entries.iter().find(|e| e.interrupt);
+        tracing::info!(
+            report = %path.display(),
+            entries = report.entries.len(),
+            deepest_interrupt = deepest.map(|e| e.name.as_str()).unwrap_or("-"),
+            deepest_bytes = deepest.map(|e| e.bytes).unwrap_or(0),
+            "stack usage"
+        );
+        if !report.unresolved_calls.is_empty() || !report.unknown_frames.is_empty() {
+            tracing::warn!(
+                unresolved_calls = report.unresolved_calls.len(),
+                unknown_frames = report.unknown_frames.len(),
+                "stack depths are lower bounds; see [stack] calls and frames"
+            );
+        }
+        report.check()?;
+        timings.lap("stack");
+    }
+
+    let mut images = Vec::new();
+    if let Some(image_opts) = image_options(opts, &asm_jobs) {
+        images = image::build_images(&elf_out, &opts.workspace, &opts.output, &image_opts)
+            .instrument(tracing::info_span!("image"))
+            .await?;
+        timings.lap("image");
+    }
+
+    let mut artifacts = Vec::new();
+    for job in &asm_jobs {
+        let kind = if job.is_link_input() {
+            ArtifactKind::Object
+        } else {
+            ArtifactKind::Binary
+        };
+        artifacts.push(Artifact::from_file(&job.output, kind)?.built_by(
+            &job.source,
+            &job.program,
+            &job.args,
+        ));
+    }
+    for job in &cc_jobs {
+        artifacts.push(
+            Artifact::from_file(&job.object, ArtifactKind::Object)?.built_by(
+                &job.source,
+                &job.program,
+                &job.args,
+            ),
+        );
+    }
+    if let Some(job) = &rust_job {
+        artifacts.push(
+            Artifact::from_file(&job.staticlib, ArtifactKind::StaticLib)?.built_by(
+                &job.crate_dir,
+                "cargo",
+                &job.args,
+            ),
+        );
+    }
+    artifacts.push(Artifact::from_file(&elf_out, ArtifactKind::Kernel)?);
+    for img in &images {
+        artifacts.push(Artifact::from_file(img, ArtifactKind::Image)?);
+    }
+
+    let mut assemblers: Vec<String> = asm_jobs
+        .iter()
+        .map(|j| j.program.clone())
+        .filter(|p| *p != compiler.program)
+        .collect();
+    assemblers.sort();
+    assemblers.dedup();
+    let mut asm_info = Vec::new();
+    for program in &assemblers {
+        asm_info.push(manifest::probe(program).await);
+    }
+
+    let stages = timings.into_stages();
+    let manifest = BuildManifest {
+        version: manifest::MANIFEST_VERSION,
+        arch: opts.arch.clone(),
+        profile: opts.profile.name().to_string(),
+        workspace: opts.workspace.display().to_string(),
+        git_commit: manifest::git_commit(&opts.workspace).await,
+        kernel: report.elf.clone(),
+        symbols: Some(symbols_path.display().to_string()),
+        images: images.iter().map(|p| p.display().to_string()).collect(),
+        boot: opts
+            .image
+            .as_ref()
+            .filter(|_| !images.is_empty())
+            .map(|i| i.boot),
+        qemu: tc.qemu_defaults(),
+        toolchain: manifest::Toolchain {
+            cc: ToolInfo {
+                program: compiler.program.clone(),
+                path: Some(compiler.path.display().to_string()),
+                version: Some(compiler.version.clone()),
+            },
+            linker: Some(manifest::probe(&linker).await),
+            assemblers: asm_info,
+        },
+        artifacts,
+        timings: stages.clone(),
+        store: None,
+    };
+    let manifest_path = manifest.write(&opts.output)?;
+    tracing::info!(manifest = %manifest_path.display(), "wrote build manifest");
+
+    Ok(BuildOutcome {
+        success: true,
+        arch: opts.arch.clone(),
+        artifact: Some(report.elf),
+        objects: objects.iter().map(|p| p.display().to_string()).collect(),
+        images: images.iter().map(|p| p.display().to_string()).collect(),
+        cache: Some(stats),
+        timings: stages,
+        ..Default::default()
+    })
+}
+
+/// What [`build`] would run and write with `opts`, from the same job
+/// planning, running only the compiler probe (`--dry-run`). Jobs whose
+/// inputs a stage before them produces are listed with the paths they will
+/// have; the checks on those outputs (pre-link, boot protocol, stack budget)
+/// are not planned. A tool missing from PATH does not fail the plan: it is
+/// listed under the name a real run would look for first, as unresolved.
+pub async fn plan(opts: &NativeOptions) -> Result<Plan> {
+    let Prepared {
+        tc,
+        compiler: _,
+        cache,
+        obj_dir,
+        asm_jobs,
+        cc_jobs,
+        epoch,
+    } = prepare(opts, true).await?;
+    let mut plan = Plan::default();
+    if opts.emit_compdb {
+        plan.write(opts.output.join(compdb::COMPDB_NAME));
+    }
+
+    let compile_jobs = asm_jobs
+        .iter()
+        .map(|j| ("assembling", &j.source, &j.output, &j.program, &j.args))
+        .chain(
+            cc_jobs
+                .iter()
+                .map(|j| ("compiling", &j.source, &j.object, &j.program, &j.args)),
+        );
+    for (verb, source, output, program, args) in compile_jobs {
+        let what = format!("{verb} {}", source.display());
+        let mut planned = planned(what, None, program, args)?;
+        if let Some(hit) = cache.would_hit(source, program, args)? {
+            plan.count(hit);
+            planned = planned.cached(hit);
+        }
+        plan.run(planned);
+        plan.write(output);
+        cache::side_outputs(output, args)
+            .into_iter()
+            .for_each(|p| plan.write(p));
+    }
+
+    let mut rust_job = None;
+    if let Some(crate_dir) = rust::detect(&opts.workspace) {
+        let cargo_toml = std::fs::read_to_string(crate_dir.join("Cargo.toml"))?;
+        let job = rust::plan(&crate_dir, &cargo_toml, &tc, &opts.output, opts.profile)?;
+        let what = format!("building {}", job.crate_dir.display());
+        plan.run(planned(what, Some(&job.crate_dir), "cargo", &job.args)?);
+        plan.write(&job.staticlib);
+        rust_job = Some(job);
+    }
+
+    let objects: Vec<PathBuf> = asm_jobs
+        .iter()
+        .filter(|j| j.is_link_input())
+        .map(|j| j.output.clone())
+        .chain(cc_jobs.iter().map(|j| j.object.clone()))
+        .chain(rust_job.iter().map(|j| j.staticlib.clone()))
+        .collect();
+    let script = link::find_script(&opts.workspace, &opts.arch, opts.linker_script.as_deref())?;
+    let linker = link::find_linker(&tc, opts.ld.as_deref())
+        .unwrap_or_else(|_| link::linker_candidates(&tc, opts.ld.as_deref()).remove(0));
+    let elf_out = opts.output.join("kernel.elf");
+    let what = format!("linking {}", elf_out.display());
+    let args = link::ld_args(&script, &elf_out, &objects);
+    plan.run(planned(what, None, &linker, &args)?);
+    plan.write(&elf_out);
+    plan.write(symbols::map_for(&elf_out));
+    plan.write(opts.output.join(symbols::SYMBOLS_NAME));
+
+    if opts.emit_listings || opts.stack.is_some() {
+        let objdump = listing::find_objdump(&tc)
+            .unwrap_or_else(|_| listing::objdump_candidates(&tc).remove(0));
+        if opts.emit_listings {
+            let jobs = listing::plan(
+                &objdump,
+                &obj_dir,
+                &opts.output,
+                &objects,
+                &asm_jobs,
+                &elf_out,
+            );
+            for job in jobs {
+                let what = format!("listing {}", job.output.display());
+                plan.run(planned(what, None, &job.program, &job.args)?);
+                plan.write(job.output);
+            }
+        }
+        if opts.stack.is_some() {
+            let what = format!("disassembling {}", elf_out.display());
+            let args = stack::disassemble_args(&elf_out);
+            plan.run(planned(what, None, &objdump, &args)?);
+            plan.write(opts.output.join(stack::REPORT_NAME));
+        }
+    }
+
+    if let Some(image_opts) = image_options(opts, &asm_jobs) {
+        let mut steps = Vec::new();
+        if image_opts.format.wants_iso() {
+            steps.extend(image::plan_iso(&elf_out, &opts.workspace, &opts.output));
+        }
+        if image_opts.format.wants_raw() {
+            steps.extend(image::plan_raw(
+                &elf_out,
+                &opts.output,
+                image_opts.bootloader,
+                image_opts.boot,
+                image_opts.stage2.as_deref(),
+                &image_opts.limine_dir,
+                image_opts.raw_size,
+            )?);
+        }
+        for step in steps {
+            match step {
+                Step::Run { program, args } => {
+                    let what = format!("running {program}");
+                    plan.run(planned(what, None, &program, &args)?);
+                }
+                Step::Copy { to: path, .. }
+                | Step::Write { path, .. }
+                | Step::Allocate { path, .. }
+                | Step::Concat { to: path, .. } => plan.write(path),
+                Step::Mkdir(_) => {}
+            }
+        }
+    }
+    plan.write(opts.output.join(manifest::MANIFEST_NAME));
+
+    // Pinned for the whole process, so every tool inherits it.
+    if let Some(epoch) = epoch {
+        for command in &mut plan.commands {
+            command
+                .env
+                .insert("SOURCE_DATE_EPOCH".to_string(), epoch.to_string());
+        }
+    }
+    Ok(plan)
+}
+
+/// `opts.image`, with `stage2` defaulted to the first `bin`-format
+/// assembly output.
+fn image_options(opts: &NativeOptions, asm_jobs: &[AsmJob]) -> Option<ImageOptions> {
+    let mut image_opts = opts.image.clone()?;
+    if image_opts.stage2.is_none() {
+        image_opts.stage2 = asm_jobs
+            .iter()
+            .find(|j| !j.is_link_input())
+            .map(|j| j.output.clone());
+    }
+    Some(image_opts)
+}
+
+/// Assembler programs for `tc`: GNU `as` is the first prefixed one on PATH
+/// (the host `as` only when it targets the same arch).
+/// `program args…` as the backend would run it, marked unresolved when
+/// `program` is not on the backend's PATH.
+fn planned(what: String, cwd: Option<&Path>, program: &str, args: &[String]) -> Result<Planned> {
+    let cmd = exec::command(cwd, program, args)?;
+    Ok(Planned::of(what, cmd.as_std()).unresolved(exec::which(program).is_none()))
+}
+
+fn asm_tools(tc: &ArchToolchain, cc: &str) -> AsmTools {
+    let mut gas = tc.prefixed("as");
+    if tc.arch == std::env::consts::ARCH {
+        gas.push("as".to_string());
+    }
+    AsmTools {
+        cc: cc.to_string(),
+        gas: toolchain::first_on_path(&gas).unwrap_or_else(|| gas[0].clone()),
+        nasm: tc.assembler == AsmSyntax::Nasm,
+    }
+}
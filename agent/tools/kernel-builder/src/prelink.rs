@@ -0,0 +1,639 @@
+//! Pre-link check: undefined symbols and archive cycles, found before `ld`
+//! runs and reported by the source file that is probably missing.
+//!
+//! Symbol resolution is simulated the way GNU ld does it. Objects come in
+//! link order. An archive contributes the members that define a symbol
+//! still undefined at that point, and it is rescanned until nothing new is
+//! pulled in. Later inputs never satisfy an earlier archive. Symbols the
+//! linker script assigns (`_kernel_end = .;`, `PROVIDE(...)`) count as
+//! defined, and undefined weak references are allowed.
+//!
+//! A symbol that is still undefined is reported one of two ways:
+//!
+//! - an earlier archive has a member that defines it: a circular
+//!   dependency between archives, which ld only resolves inside
+//!   `--start-group`;
+//! - otherwise, grouped under the workspace file that explains it: a
+//!   source that defines it but was not built, else a header that declares
+//!   it but has no implementation, else nothing that mentions it at all.
+//!
+//! Inputs that cannot be read as ELF64 objects or `ar` archives turn the
+//! check off; ld's own errors follow instead.
+
+use crate::elf::{self, STB_GLOBAL, STB_WEAK};
+use anyhow::{ensure, Context, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Symbols GNU ld defines itself, even under a custom script.
+const LINKER_DEFINED: &[&str] = &["_GLOBAL_OFFSET_TABLE_", "__ehdr_start", "_DYNAMIC"];
+
+/// One object's global symbols: what it defines and what it needs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectSymbols {
+    /// `kernel/main.c.o`, or `libkernel.a(core.o)` for archive members.
+    pub name: String,
+    pub defines: BTreeSet<String>,
+    /// Undefined, non-weak references.
+    pub needs: BTreeSet<String>,
+}
+
+impl ObjectSymbols {
+    /// The global symbols of an ELF object.
+    pub fn parse(name: &str, bytes: &[u8]) -> Result<Self> {
+        let image = elf::parse(bytes).with_context(|| format!("parsing {name}"))?;
+        let mut symbols = Self {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        for s in &image.symbols {
+            if s.name.is_empty() || !matches!(s.bind, STB_GLOBAL | STB_WEAK) {
+                continue;
+            }
+            if s.shndx != 0 {
+                symbols.defines.insert(s.name.clone());
+            } else if s.bind == STB_GLOBAL {
+                symbols.needs.insert(s.name.clone());
+            }
+        }
+        Ok(symbols)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkInput {
+    Object(ObjectSymbols),
+    Archive {
+        name: String,
+        members: Vec<ObjectSymbols>,
+    },
+}
+
+impl LinkInput {
+    /// Read `path`, shown as `name` in the report.
+    pub fn read(path: &Path, name: &str) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        if bytes.starts_with(b"!<arch>\n") {
+            let file = path.file_name().unwrap_or_default().to_string_lossy();
+            let mut members = Vec::new();
+            for (member, data) in archive_members(&bytes)
+                .with_context(|| format!("reading archive {}", path.display()))?
+            {
+                // Archives can carry non-object members (bitcode, metadata).
+                if data.starts_with(b"\x7fELF") {
+                    members.push(ObjectSymbols::parse(&format!("{file}({member})"), data)?);
+                }
+            }
+            return Ok(Self::Archive {
+                name: name.to_string(),
+                members,
+            });
+        }
+        Ok(Self::Object(ObjectSymbols::parse(name, &bytes)?))
+    }
+}
+
+/// The members of a System V / GNU `ar` archive, by name.
+fn archive_members(bytes: &[u8]) -> Result<Vec<(String, &[u8])>> {
+    let mut members = Vec::new();
+    let mut long_names: &[u8] = &[];
+    let mut at = 8;
+    while at + 60 <= bytes.len() {
+        let header = &bytes[at..at + 60];
+        ensure!(&header[58..60] == b"`\n", "bad member header at {at:#x}");
+        let field =
+            |r: std::ops::Range<usize>| String::from_utf8_lossy(&header[r]).trim().to_string();
+        let size: usize = field(48..58)
+            .parse()
+            .with_context(|| format!("bad member size at {at:#x}"))?;
+        let start = at + 60;
+        let data = bytes
+            .get(start..start + size)
+            .with_context(|| format!("truncated member at {at:#x}"))?;
+        at = start + size + size % 2;
+        let raw = field(0..16);
+        let name = match raw.as_str() {
+            "/" | "/SYM64/" => continue,
+            "//" => {
+                long_names = data;
+                continue;
+            }
+            _ => match raw.strip_prefix('/') {
+                Some(offset) => {
+                    let offset: usize = offset.parse().context("bad long member name")?;
+                    let tail = long_names.get(offset..).unwrap_or_default();
+                    let end = tail.iter().position(|&c| c == b'\n').unwrap_or(tail.len());
+                    String::from_utf8_lossy(&tail[..end])
+                        .trim_end_matches('/')
+                        .to_string()
+                }
+                None => raw.trim_end_matches('/').to_string(),
+            },
+        };
+        members.push((name, data));
+    }
+    Ok(members)
+}
+
+/// Symbols a linker script assigns: `sym = expr;`, `sym += expr;` and
+/// `PROVIDE(sym = expr)` (so also `PROVIDE_HIDDEN` and `HIDDEN`).
+pub fn script_symbols(script: &str) -> BTreeSet<String> {
+    let mut text = String::with_capacity(script.len());
+    let mut rest = script;
+    while let Some(start) = rest.find("/*") {
+        text.push_str(&rest[..start]);
+        text.push(' ');
+        rest = rest[start..]
+            .find("*/")
+            .map_or("", |end| &rest[start + end + 2..]);
+    }
+    text.push_str(rest);
+
+    let ident = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$');
+    let mut symbols = BTreeSet::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if !ident(c) {
+            continue;
+        }
+        let mut end = start + c.len_utf8();
+        while let Some(&(i, c)) = chars.peek().filter(|(_, c)| ident(*c)) {
+            end = i + c.len_utf8();
+            chars.next();
+        }
+        let after = text[end..].trim_start();
+        let assigns = after.starts_with('=') && !after.starts_with("==")
+            || ["+=", "-=", "*=", "/=", "|=", "&="]
+                .iter()
+                .any(|op| after.starts_with(op));
+        let word = &text[start..end];
+        let is_symbol = word != "." && !word.starts_with(|c: char| c.is_ascii_digit());
+        if assigns && is_symbol {
+            symbols.insert(word.to_string());
+        }
+    }
+    symbols
+}
+
+/// A symbol nothing linked defines.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Undefined {
+    pub symbol: String,
+    /// The objects that reference it, in link order.
+    pub referenced_by: Vec<String>,
+    /// For a circular dependency: the earlier archive member that defines it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub defined_in: Option<String>,
+}
+
+/// Simulate ld over `inputs` (in link order): the undefined references
+/// left at the end, by symbol.
+pub fn resolve(inputs: &[LinkInput], provided: &BTreeSet<String>) -> Vec<Undefined> {
+    let mut defined: BTreeSet<&str> = provided.iter().map(String::as_str).collect();
+    defined.extend(LINKER_DEFINED);
+    let mut needed: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    // Archive members ld left out.
+    let mut unused: Vec<&ObjectSymbols> = Vec::new();
+
+    fn add<'a>(
+        obj: &'a ObjectSymbols,
+        defined: &mut BTreeSet<&'a str>,
+        needed: &mut BTreeMap<&'a str, Vec<&'a str>>,
+    ) {
+        for d in &obj.defines {
+            defined.insert(d);
+            needed.remove(d.as_str());
+        }
+        for n in &obj.needs {
+            if !defined.contains(n.as_str()) {
+                needed.entry(n).or_default().push(&obj.name);
+            }
+        }
+    }
+
+    for input in inputs {
+        match input {
+            LinkInput::Object(obj) => add(obj, &mut defined, &mut needed),
+            LinkInput::Archive { members, .. } => {
+                let mut left: Vec<&ObjectSymbols> = members.iter().collect();
+                loop {
+                    let (pull, keep): (Vec<_>, Vec<_>) = left
+                        .into_iter()
+                        .partition(|m| m.defines.iter().any(|d| needed.contains_key(d.as_str())));
+                    left = keep;
+                    if pull.is_empty() {
+                        break;
+                    }
+                    for member in pull {
+                        add(member, &mut defined, &mut needed);
+                    }
+                }
+                unused.extend(left);
+            }
+        }
+    }
+
+    needed
+        .into_iter()
+        .map(|(symbol, referenced_by)| Undefined {
+            symbol: symbol.to_string(),
+            referenced_by: referenced_by.into_iter().map(str::to_string).collect(),
+            defined_in: unused
+                .iter()
+                .find(|m| m.defines.contains(symbol))
+                .map(|m| m.name.clone()),
+        })
+        .collect()
+}
+
+/// How the workspace explains a group of undefined symbols.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cause {
+    /// A source defines them but is not built (`[sources]` leaves it out).
+    NotBuilt,
+    /// A built source has them, but not as global symbols (`static`, or
+    /// compiled out).
+    NotExported,
+    /// A header declares them and no source defines them.
+    Unimplemented,
+    /// Nothing in the workspace mentions them.
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MissingSource {
+    /// Workspace-relative; none for [`Cause::Unknown`].
+    pub file: Option<String>,
+    pub cause: Cause,
+    pub symbols: Vec<Undefined>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PrelinkReport {
+    /// Most actionable first: sources to build, then to fix, then to write.
+    pub missing: Vec<MissingSource>,
+    /// Defined only by a member of an archive ld has already passed.
+    pub circular: Vec<Undefined>,
+}
+
+impl PrelinkReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.circular.is_empty()
+    }
+
+    /// Group `undefined` by the file in `files` (workspace-relative path and
+    /// text) that explains it; `built` are the sources that were compiled.
+    pub fn explain(
+        undefined: Vec<Undefined>,
+        files: &[(String, String)],
+        built: &BTreeSet<String>,
+    ) -> Self {
+        let mut report = Self::default();
+        let mut groups: BTreeMap<(Cause, Option<String>), Vec<Undefined>> = BTreeMap::new();
+        for u in undefined {
+            if u.defined_in.is_some() {
+                report.circular.push(u);
+                continue;
+            }
+            let defining = files
+                .iter()
+                .find(|(rel, text)| !is_header(rel) && defines(rel, text, &u.symbol));
+            let declaring = files
+                .iter()
+                .find(|(rel, text)| is_header(rel) && text.lines().any(|l| names(l, &u.symbol)));
+            let key = match (defining, declaring) {
+                (Some((rel, _)), _) if built.contains(rel) => {
+                    (Cause::NotExported, Some(rel.clone()))
+                }
+                (Some((rel, _)), _) => (Cause::NotBuilt, Some(rel.clone())),
+                (None, Some((rel, _))) => (Cause::Unimplemented, Some(rel.clone())),
+                (None, None) => (Cause::Unknown, None),
+            };
+            groups.entry(key).or_default().push(u);
+        }
+        report.missing = groups
+            .into_iter()
+            .map(|((cause, file), symbols)| MissingSource {
+                file,
+                cause,
+                symbols,
+            })
+            .collect();
+        report
+    }
+}
+
+impl fmt::Display for PrelinkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count =
+            self.circular.len() + self.missing.iter().map(|g| g.symbols.len()).sum::<usize>();
+        write!(f, "{count} undefined symbol(s) before linking:")?;
+        let line = |u: &Undefined| {
+            format!(
+                "{} (referenced by {})",
+                u.symbol,
+                u.referenced_by.join(", ")
+            )
+        };
+        for group in &self.missing {
+            let file = group.file.as_deref().unwrap_or_default();
+            match group.cause {
+                Cause::NotBuilt => write!(
+                    f,
+                    "\n  {file} defines these but is not built (see [sources]):"
+                )?,
+                Cause::NotExported => write!(
+                    f,
+                    "\n  {file} has these but does not export them (static, or compiled out?):"
+                )?,
+                Cause::Unimplemented => {
+                    write!(f, "\n  {file} declares these but no source defines them:")?
+                }
+                Cause::Unknown => write!(f, "\n  nothing in the workspace declares these:")?,
+            }
+            for u in &group.symbols {
+                write!(f, "\n    {}", line(u))?;
+            }
+        }
+        if !self.circular.is_empty() {
+            write!(
+                f,
+                "\n  circular dependency: these are defined in an archive linked before the one that needs them, which ld only resolves inside --start-group:"
+            )?;
+            for u in &self.circular {
+                write!(
+                    f,
+                    "\n    {}, defined in {}",
+                    line(u),
+                    u.defined_in.as_deref().unwrap_or_default()
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_header(rel: &str) -> bool {
+    rel.ends_with(".h")
+}
+
+fn is_asm(rel: &str) -> bool {
+    [".S", ".s", ".asm"].iter().any(|e| rel.ends_with(e))
+}
+
+/// Whether `text` has `symbol` as a whole identifier.
+fn names(text: &str, symbol: &str) -> bool {
+    ident_end(text, symbol).is_some()
+}
+
+/// Where the first whole-identifier `symbol` in `text` ends.
+fn ident_end(text: &str, symbol: &str) -> Option<usize> {
+    let ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    text.match_indices(symbol)
+        .find(|(at, _)| {
+            !text[..*at].ends_with(ident) && !text[at + symbol.len()..].starts_with(ident)
+        })
+        .map(|(at, _)| at + symbol.len())
+}
+
+/// Whether the source `rel` defines `symbol`: a top-level C function or
+/// variable definition, or an assembly label or `global`/`.globl`.
+fn defines(rel: &str, text: &str, symbol: &str) -> bool {
+    if is_asm(rel) {
+        return text.lines().any(|l| {
+            let l = l.trim();
+            l.strip_prefix(symbol).is_some_and(|r| r.starts_with(':'))
+                || [".globl", ".global", "global"]
+                    .iter()
+                    .any(|d| l.strip_prefix(d).is_some_and(|r| r.trim() == symbol))
+        });
+    }
+    text.lines().any(|l| {
+        if l.starts_with(char::is_whitespace)
+            || ["extern", "#", "//", "/*", "*", "return", "}"]
+                .iter()
+                .any(|p| l.starts_with(p))
+        {
+            return false;
+        }
+        let Some(end) = ident_end(l, symbol) else {
+            return false;
+        };
+        let after = l[end..].trim_start();
+        let line = l.trim_end();
+        if after.starts_with('(') {
+            !line.ends_with(';')
+        } else {
+            after.starts_with(['=', ';', '['])
+        }
+    })
+}
+
+/// Workspace-relative path and text of every C, header and assembly file
+/// under `workspace`, skipping hidden directories and `skip` (the build
+/// output).
+pub fn workspace_files(workspace: &Path, skip: &Path) -> Vec<(String, String)> {
+    fn walk(dir: &Path, skip: &Path, found: &mut Vec<PathBuf>) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for path in entries.flatten().map(|e| e.path()) {
+            let hidden = path
+                .file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with('.'));
+            if path.is_dir() {
+                if !hidden && path != skip {
+                    walk(&path, skip, found);
+                }
+            } else {
+                found.push(path);
+            }
+        }
+    }
+    let mut found = Vec::new();
+    walk(workspace, skip, &mut found);
+    found.sort();
+    found
+        .into_iter()
+        .filter_map(|path| {
+            let rel = path
+                .strip_prefix(workspace)
+                .ok()?
+                .to_string_lossy()
+                .into_owned();
+            if !(rel.ends_with(".c") || is_header(&rel) || is_asm(&rel)) {
+                return None;
+            }
+            Some((rel, std::fs::read_to_string(&path).ok()?))
+        })
+        .collect()
+}
+
+/// How a link input is named in the report: objects under `obj_dir` by
+/// their source (`obj/kernel/main.c.o` → `kernel/main.c`).
+fn input_name(obj_dir: &Path, path: &Path) -> String {
+    match path.strip_prefix(obj_dir) {
+        Ok(rel) => {
+            let rel = rel.to_string_lossy();
+            rel.strip_suffix(".o").unwrap_or(&rel).to_string()
+        }
+        Err(_) => path.display().to_string(),
+    }
+}
+
+/// Check `objects` (in link order) against `script` before linking. `None`
+/// when an input could not be read, so ld has to report.
+pub fn check(
+    objects: &[PathBuf],
+    obj_dir: &Path,
+    script: &str,
+    workspace: &Path,
+    output: &Path,
+) -> Option<PrelinkReport> {
+    let mut inputs = Vec::with_capacity(objects.len());
+    for path in objects {
+        match LinkInput::read(path, &input_name(obj_dir, path)) {
+            Ok(input) => inputs.push(input),
+            Err(e) => {
+                tracing::debug!("skipping the pre-link check: {e:#}");
+                return None;
+            }
+        }
+    }
+    let undefined = resolve(&inputs, &script_symbols(script));
+    if undefined.is_empty() {
+        return Some(PrelinkReport::default());
+    }
+    let built: BTreeSet<String> = inputs
+        .iter()
+        .filter_map(|i| match i {
+            LinkInput::Object(o) => Some(o.name.clone()),
+            LinkInput::Archive { .. } => None,
+        })
+        .collect();
+    let files = workspace_files(workspace, output);
+    Some(PrelinkReport::explain(undefined, &files, &built))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn obj(name: &str, defines: &[&str], needs: &[&str]) -> ObjectSymbols {
+        ObjectSymbols {
+            name: name.into(),
+            defines: defines.iter().map(|s| s.to_string()).collect(),
+            needs: needs.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn script_assignments_define_symbols() {
+        let script =
+            "/* _fake = 1; */\nENTRY(_start)\nSECTIONS {\n  . = 1M;\n  _kernel_start = .;\n  \
+                      .bss : { *(.bss) }\n  PROVIDE(__stack_top = . + 0x4000);\n  \
+                      ASSERT(. <= 16M, \"too big\")\n  _end += 0;\n}\n";
+        let symbols: Vec<_> = script_symbols(script).into_iter().collect();
+        assert_eq!(symbols, ["__stack_top", "_end", "_kernel_start"]);
+    }
+
+    #[test]
+    fn archives_only_satisfy_later_inputs() {
+        let inputs = [
+            LinkInput::Object(obj(
+                "kernel/main.c",
+                &["kmain"],
+                &["pmm_alloc", "rs_init", "_kernel_end"],
+            )),
+            LinkInput::Archive {
+                name: "liba.a".into(),
+                members: vec![
+                    obj("liba.a(pmm.o)", &["pmm_alloc"], &["memset"]),
+                    obj("liba.a(log.o)", &["log_write"], &[]),
+                ],
+            },
+            LinkInput::Archive {
+                name: "libkernel.a".into(),
+                members: vec![
+                    obj("libkernel.a(rs.o)", &["rs_init"], &["log_write"]),
+                    obj("libkernel.a(mem.o)", &["memset"], &[]),
+                ],
+            },
+        ];
+        let provided = BTreeSet::from(["_kernel_end".to_string()]);
+        let undefined = resolve(&inputs, &provided);
+        assert_eq!(
+            undefined,
+            [Undefined {
+                symbol: "log_write".into(),
+                referenced_by: vec!["libkernel.a(rs.o)".into()],
+                defined_in: Some("liba.a(log.o)".into()),
+            }]
+        );
+    }
+
+    #[test]
+    fn groups_undefined_symbols_by_the_file_that_explains_them() {
+        let files = [
+            ("kernel/mm/pmm.c", "#include \"pmm.h\"\nvoid *pmm_alloc(void)\n{\n\treturn 0;\n}\nint pmm_pages = 0;\n"),
+            ("kernel/include/vmm.h", "#pragma once\nvoid vmm_map(unsigned long va);\n"),
+            ("kernel/sched.c", "static void sched_tick(void)\n{\n}\n"),
+            ("kernel/main.c", "extern int pmm_pages;\nvoid kmain(void)\n{\n\tvmm_map(0);\n}\n"),
+            ("boot/entry.S", ".globl boot_stub\nboot_stub:\n\tret\n"),
+        ]
+        .map(|(r, t)| (r.to_string(), t.to_string()));
+        let built = BTreeSet::from(["kernel/main.c".to_string(), "kernel/sched.c".to_string()]);
+        let undefined = [
+            "pmm_alloc",
+            "pmm_pages",
+            "vmm_map",
+            "sched_tick",
+            "boot_stub",
+            "mystery",
+        ]
+        .map(|s| Undefined {
+            symbol: s.into(),
+            referenced_by: vec!["kernel/main.c".into()],
+            defined_in: None,
+        })
+        .to_vec();
+        let report = PrelinkReport::explain(undefined, &files, &built);
+        let groups: Vec<_> = report
+            .missing
+            .iter()
+            .map(|g| {
+                let symbols: Vec<_> = g.symbols.iter().map(|u| u.symbol.as_str()).collect();
+                (g.cause, g.file.as_deref(), symbols)
+            })
+            .collect();
+        assert_eq!(
+            groups,
+            [
+                (Cause::NotBuilt, Some("boot/entry.S"), vec!["boot_stub"]),
+                (
+                    Cause::NotBuilt,
+                    Some("kernel/mm/pmm.c"),
+                    vec!["pmm_alloc", "pmm_pages"]
+                ),
+                (
+                    Cause::NotExported,
+                    Some("kernel/sched.c"),
+                    vec!["sched_tick"]
+                ),
+                (
+                    Cause::Unimplemented,
+                    Some("kernel/include/vmm.h"),
+                    vec!["vmm_map"]
+                ),
+                (Cause::Unknown, None, vec!["mystery"]),
+            ]
+        );
+        let text = report.to_string();
+        assert!(text.starts_with("6 undefined symbol(s) before linking:"));
+        assert!(text.contains("kernel/mm/pmm.c defines these but is not built"));
+        assert!(text.contains("    mystery (referenced by kernel/main.c)"));
+    }
+}
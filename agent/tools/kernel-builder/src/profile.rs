@@ -0,0 +1,92 @@
+//! Build profiles (`--profile`): optimisation, debug info and instrumentation.
+//!
+//! The per-arch flag tables in [`crate::toolchain`] carry only what the target
+//! requires; everything a developer might want to vary lives here. The
+//! sanitizer profiles use trap mode so they need no runtime library: a
+//! failed check executes `ud2`/`brk`/`unimp` and shows up as an invalid-opcode
+//! fault at the offending instruction.
+
+use crate::toolchain::CompilerKind;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// -O0 with full debug info, for stepping through in gdb.
+    Debug,
+    /// -O2 -g, the Makefile's flags (default).
+    #[default]
+    Release,
+    /// UBSan checks that trap instead of calling a runtime.
+    Ubsan,
+    /// Bounds and object-size checks only (trap mode): the part of KASAN
+    /// that needs no shadow memory.
+    KasanLite,
+}
+
+impl Profile {
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::Debug => "debug",
+            Profile::Release => "release",
+            Profile::Ubsan => "ubsan",
+            Profile::KasanLite => "kasan-lite",
+        }
+    }
+
+    /// Flags appended after the arch flags, so `-O` here wins.
+    pub fn cflags(self, kind: CompilerKind) -> Vec<String> {
+        let trap = match kind {
+            CompilerKind::Gcc => "-fsanitize-undefined-trap-on-error",
+            CompilerKind::Clang => "-fsanitize-trap=all",
+        };
+        let flags: Vec<&str> = match self {
+            Profile::Debug => vec!["-O0", "-g3", "-fno-omit-frame-pointer"],
+            Profile::Release => vec!["-O2", "-g"],
+            Profile::Ubsan => vec!["-O1", "-g", "-fsanitize=undefined", trap],
+            Profile::KasanLite => vec![
+                "-O1",
+                "-g",
+                "-fno-omit-frame-pointer",
+                "-fsanitize=bounds,object-size",
+                trap,
+            ],
+        };
+        flags.into_iter().map(str::to_string).collect()
+    }
+
+    /// Where this profile's artifacts go. The default profile keeps the
+    /// plain output directory so existing paths (`build/kernel.elf`) hold;
+    /// every other profile gets its own subdirectory.
+    pub fn output_dir(self, base: &Path) -> PathBuf {
+        match self {
+            Profile::Release => base.to_path_buf(),
+            other => base.join(other.name()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitizers_trap_with_either_compiler() {
+        let gcc = Profile::Ubsan.cflags(CompilerKind::Gcc);
+        assert!(gcc.contains(&"-fsanitize=undefined".to_string()));
+        assert!(gcc.contains(&"-fsanitize-undefined-trap-on-error".to_string()));
+        let clang = Profile::KasanLite.cflags(CompilerKind::Clang);
+        assert!(clang.contains(&"-fsanitize-trap=all".to_string()));
+        assert_eq!(Profile::Release.cflags(CompilerKind::Gcc), ["-O2", "-g"]);
+    }
+
+    #[test]
+    fn non_default_profiles_get_their_own_output_dir() {
+        let base = Path::new("build");
+        assert_eq!(Profile::Release.output_dir(base), base);
+        assert_eq!(Profile::Debug.output_dir(base), base.join("debug"));
+        assert_eq!(Profile::KasanLite.output_dir(base), base.join("kasan-lite"));
+    }
+}
@@ -0,0 +1,206 @@
+//! `--reproducible`: deterministic flags, and a second build to prove them.
+//!
+//! Reproducible mode maps the workspace and working directory out of
+//! `__FILE__` and debug info (`-ffile-prefix-map`) and pins
+//! `SOURCE_DATE_EPOCH`, which gcc/clang use for `__DATE__`/`__TIME__` and
+//! grub-mkrescue for ISO timestamps. [`build_and_verify`] then rebuilds from
+//! scratch into `<output>/repro-check` and compares every artifact's hash, so
+//! nondeterminism introduced by a new build rule fails the build instead of
+//! going unnoticed. On a mismatch the second tree is kept for diffing.
+
+use crate::manifest;
+use crate::pipeline::{self, NativeOptions};
+use crate::BuildOutcome;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// Subdirectory of the output holding the verification build.
+pub const CHECK_DIR: &str = "repro-check";
+
+/// `-ffile-prefix-map` flags rewriting `cwd` and `workspace` to `.`. The
+/// workspace comes last so it wins when it lies inside `cwd`.
+pub fn prefix_map_args(cwd: &Path, workspace: &Path) -> Vec<String> {
+    let mut args = vec![format!("-ffile-prefix-map={}=.", cwd.display())];
+    if workspace != cwd {
+        args.push(format!("-ffile-prefix-map={}=.", workspace.display()));
+    }
+    args
+}
+
+/// The epoch to pin: `SOURCE_DATE_EPOCH` if already set, else the commit
+/// time of the workspace's HEAD, else 0.
+pub async fn source_date_epoch(workspace: &Path) -> u64 {
+    if let Some(epoch) = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+    {
+        return epoch;
+    }
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(workspace)
+        .args(["log", "-1", "--format=%ct"])
+        .output()
+        .await;
+    match out {
+        Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout)
+            .trim()
+            .parse()
+            .unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Export the epoch so every tool the pipeline spawns inherits it.
+pub fn pin_epoch(epoch: u64) {
+    std::env::set_var("SOURCE_DATE_EPOCH", epoch.to_string());
+}
+
+/// An artifact whose bytes differ between the two builds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Path relative to the output directory.
+    pub path: String,
+    pub first: String,
+    pub second: String,
+}
+
+/// Hash of every artifact in `outcome`, keyed by its path under `output`.
+pub fn artifact_hashes(outcome: &BuildOutcome, output: &Path) -> Result<BTreeMap<String, String>> {
+    let mut hashes = BTreeMap::new();
+    let files = outcome
+        .artifact
+        .iter()
+        .chain(&outcome.objects)
+        .chain(&outcome.images);
+    for file in files {
+        let path = Path::new(file);
+        let (_, sha) = manifest::hash_file(path)?;
+        let rel = path.strip_prefix(output).unwrap_or(path);
+        hashes.insert(rel.display().to_string(), sha);
+    }
+    Ok(hashes)
+}
+
+/// Artifacts that differ, or exist in only one build.
+pub fn compare(
+    first: &BTreeMap<String, String>,
+    second: &BTreeMap<String, String>,
+) -> Vec<Mismatch> {
+    let missing = String::from("(missing)");
+    let mut out = Vec::new();
+    for path in first
+        .keys()
+        .chain(second.keys().filter(|k| !first.contains_key(*k)))
+    {
+        let a = first.get(path).unwrap_or(&missing);
+        let b = second.get(path).unwrap_or(&missing);
+        if a != b {
+            out.push(Mismatch {
+                path: path.clone(),
+                first: a.clone(),
+                second: b.clone(),
+            });
+        }
+    }
+    out
+}
+
+/// The verification build of `opts`: uncached, into [`CHECK_DIR`], without
+/// the extra reports.
+pub fn verify_options(opts: &NativeOptions) -> NativeOptions {
+    NativeOptions {
+        output: opts.output.join(CHECK_DIR),
+        cache: false,
+        emit_compdb: false,
+        emit_listings: false,
+        stack: None,
+        ..opts.clone()
+    }
+}
+
+/// Build `opts` (which should have `reproducible` set), then rebuild without
+/// the cache into [`CHECK_DIR`] and compare. A mismatch turns the outcome
+/// into a failure listing the differing artifacts.
+pub async fn build_and_verify(opts: &NativeOptions) -> Result<BuildOutcome> {
+    let mut outcome = pipeline::build(opts).await?;
+    let check_dir: PathBuf = opts.output.join(CHECK_DIR);
+    if check_dir.exists() {
+        std::fs::remove_dir_all(&check_dir)
+            .with_context(|| format!("clearing {}", check_dir.display()))?;
+    }
+    let second_opts = verify_options(opts);
+    let second = pipeline::build(&second_opts)
+        .await
+        .context("verification build")?;
+
+    let mismatches = compare(
+        &artifact_hashes(&outcome, &opts.output)?,
+        &artifact_hashes(&second, &check_dir)?,
+    );
+    if mismatches.is_empty() {
+        std::fs::remove_dir_all(&check_dir)
+            .with_context(|| format!("removing {}", check_dir.display()))?;
+        tracing::info!("reproducible: second build is byte-identical");
+        return Ok(outcome);
+    }
+    let mut msg = format!(
+        "build is not reproducible: {} artifact(s) differ between {} and {}\n",
+        mismatches.len(),
+        opts.output.display(),
+        check_dir.display()
+    );
+    for m in &mismatches {
+        msg.push_str(&format!("  {}: {} != {}\n", m.path, m.first, m.second));
+    }
+    outcome.success = false;
+    outcome.stderr = msg;
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn compare_reports_changed_and_one_sided_artifacts() {
+        let first = map(&[
+            ("kernel.elf", "aa"),
+            ("obj/a.c.o", "11"),
+            ("obj/b.c.o", "22"),
+        ]);
+        let second = map(&[
+            ("kernel.elf", "ab"),
+            ("obj/a.c.o", "11"),
+            ("obj/c.c.o", "33"),
+        ]);
+        let paths: Vec<_> = compare(&first, &second)
+            .into_iter()
+            .map(|m| m.path)
+            .collect();
+        assert_eq!(paths, ["kernel.elf", "obj/b.c.o", "obj/c.c.o"]);
+        assert!(compare(&first, &first).is_empty());
+    }
+
+    #[test]
+    fn prefix_maps_put_the_workspace_last() {
+        let args = prefix_map_args(Path::new("/src"), Path::new("/src/kernels/x86_64"));
+        assert_eq!(
+            args,
+            [
+                "-ffile-prefix-map=/src=.",
+                "-ffile-prefix-map=/src/kernels/x86_64=."
+            ]
+        );
+        assert_eq!(prefix_map_args(Path::new("/k"), Path::new("/k")).len(), 1);
+    }
+}
@@ -0,0 +1,197 @@
+//! Rust stage: build `kernel/rust/` as a `no_std` static library.
+//!
+//! If the workspace has a `kernel/rust/Cargo.toml`, the crate is built with
+//! `cargo build -Zbuild-std=core,alloc` for the workspace's custom target
+//! spec (`kernel/rust/<arch>.json`), or for the arch's built-in bare-metal
+//! target when no spec is shipped. The resulting `lib<name>.a` is appended to
+//! the link inputs after the C objects, so unresolved references from C
+//! pull in only the Rust symbols they use.
+//!
+//! cargo runs inside `kernel/rust/` so the crate's `rust-toolchain.toml` and
+//! `.cargo/config.toml` apply; without a toolchain file `+nightly` is passed,
+//! since `-Zbuild-std` needs a nightly compiler with `rust-src`.
+
+use crate::exec::run_tool_in;
+use crate::profile::Profile;
+use crate::ArchToolchain;
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Workspace-relative crate directory.
+pub const CRATE_DIR: &str = "kernel/rust";
+
+/// A cargo invocation producing one static library.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RustJob {
+    /// Directory cargo runs in (the crate root).
+    pub crate_dir: PathBuf,
+    pub args: Vec<String>,
+    /// Where cargo leaves the archive.
+    pub staticlib: PathBuf,
+}
+
+/// Value of `key = "…"` inside `[table]` of a Cargo.toml. Handles the plain
+/// string and string-array forms Cargo.toml uses for name and crate-type.
+pub fn toml_value(text: &str, table: &str, key: &str) -> Option<String> {
+    let mut current = String::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            current = name.trim().to_string();
+            continue;
+        }
+        if current != table {
+            continue;
+        }
+        let Some((k, v)) = line.split_once('=') else {
+            continue;
+        };
+        if k.trim() == key {
+            return Some(v.trim().to_string());
+        }
+    }
+    None
+}
+
+fn unquote(v: &str) -> String {
+    v.trim().trim_matches('"').to_string()
+}
+
+/// Library name cargo will use for the archive (`[lib] name`, else the
+/// package name with `-` mapped to `_`). Fails if the crate is not a staticlib.
+pub fn staticlib_name(cargo_toml: &str) -> Result<String> {
+    let crate_type = toml_value(cargo_toml, "lib", "crate-type").unwrap_or_default();
+    if !crate_type.contains("\"staticlib\"") {
+        bail!("{CRATE_DIR}/Cargo.toml must set `[lib] crate-type = [\"staticlib\"]` to link into the kernel");
+    }
+    let name = toml_value(cargo_toml, "lib", "name")
+        .or_else(|| toml_value(cargo_toml, "package", "name"))
+        .map(|v| unquote(&v))
+        .context("Cargo.toml has no [package] name")?;
+    Ok(name.replace('-', "_"))
+}
+
+/// The crate directory, if the workspace has Rust sources.
+pub fn detect(workspace: &Path) -> Option<PathBuf> {
+    let dir = workspace.join(CRATE_DIR);
+    dir.join("Cargo.toml").is_file().then_some(dir)
+}
+
+/// Target argument and the name cargo uses for its output subdirectory.
+fn target_for(crate_dir: &Path, tc: &ArchToolchain) -> (String, String) {
+    let spec = crate_dir.join(format!("{}.json", tc.arch));
+    if spec.is_file() {
+        let stem = tc.arch.clone();
+        return (spec.display().to_string(), stem);
+    }
+    (tc.rust_target.clone(), tc.rust_target.clone())
+}
+
+/// Plan the cargo build for `crate_dir`; artifacts go under `<output>/rust`.
+pub fn plan(
+    crate_dir: &Path,
+    cargo_toml: &str,
+    tc: &ArchToolchain,
+    output: &Path,
+    profile: Profile,
+) -> Result<RustJob> {
+    let name = staticlib_name(cargo_toml)?;
+    let (target, target_dir_name) = target_for(crate_dir, tc);
+    let target_dir = std::path::absolute(output.join("rust"))?;
+    let pinned = ["rust-toolchain.toml", "rust-toolchain"]
+        .iter()
+        .any(|f| crate_dir.join(f).is_file());
+
+    let mut args = Vec::new();
+    if !pinned {
+        args.push("+nightly".to_string());
+    }
+    args.extend(
+        [
+            "build",
+            "--target",
+            &target,
+            "-Zbuild-std=core,alloc",
+            "-Zbuild-std-features=compiler-builtins-mem",
+            "--target-dir",
+        ]
+        .map(str::to_string),
+    );
+    args.push(target_dir.display().to_string());
+    let cargo_profile = match profile {
+        Profile::Debug => "debug",
+        _ => {
+            args.push("--release".to_string());
+            "release"
+        }
+    };
+    let staticlib = target_dir
+        .join(target_dir_name)
+        .join(cargo_profile)
+        .join(format!("lib{name}.a"));
+    Ok(RustJob {
+        crate_dir: crate_dir.to_path_buf(),
+        args,
+        staticlib,
+    })
+}
+
+/// Run cargo; returns the static library to link.
+pub async fn build(job: &RustJob) -> Result<PathBuf> {
+    let what = format!("building {}", job.crate_dir.display());
+    run_tool_in(&job.crate_dir, "cargo", &job.args, &what).await?;
+    if !job.staticlib.is_file() {
+        bail!(
+            "cargo succeeded but {} was not produced",
+            job.staticlib.display()
+        );
+    }
+    Ok(job.staticlib.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CARGO_TOML: &str = r#"
+[package]
+name = "auton-kernel-rs"   # crate name
+version = "0.1.0"
+
+[lib]
+crate-type = ["staticlib"]
+"#;
+
+    #[test]
+    fn staticlib_name_maps_dashes_and_requires_staticlib() {
+        assert_eq!(staticlib_name(CARGO_TOML).unwrap(), "auton_kernel_rs");
+        let named = format!("{CARGO_TOML}name = \"kernel_rs\"\n");
+        assert_eq!(staticlib_name(&named).unwrap(), "kernel_rs");
+        let rlib = "[package]\nname = \"x\"\n";
+        assert!(staticlib_name(rlib)
+            .unwrap_err()
+            .to_string()
+            .contains("staticlib"));
+    }
+
+    #[test]
+    fn plan_uses_builtin_target_without_spec() {
+        let tc = ArchToolchain::for_arch("riscv64").unwrap();
+        let job = plan(
+            Path::new("/nonexistent/kernel/rust"),
+            CARGO_TOML,
+            &tc,
+            Path::new("/out"),
+            Profile::Release,
+        )
+        .unwrap();
+        assert_eq!(job.args[0], "+nightly");
+        assert!(job.args.contains(&"-Zbuild-std=core,alloc".to_string()));
+        assert!(job.args.contains(&"riscv64gc-unknown-none-elf".to_string()));
+        assert!(job.args.contains(&"--release".to_string()));
+        assert_eq!(
+            job.staticlib,
+            Path::new("/out/rust/riscv64gc-unknown-none-elf/release/libauton_kernel_rs.a")
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! `kernel-builder size`: section/symbol size report and growth guardrail.
+//!
+//! The report is computed from the linked ELF with [`crate::elf`]. Each run
+//! is compared against the previous report (`<output>/size.json` by default)
+//! and, when no limit is exceeded, replaces it, so the baseline only ever
+//! advances past builds that passed the check.
+
+use crate::elf::{self, Elf, SHF_EXECINSTR, SHT_NOBITS};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+pub const BASELINE_NAME: &str = "size.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SectionSize {
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SymbolSize {
+    pub name: String,
+    pub size: u64,
+    pub section: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SizeReport {
+    pub elf: String,
+    /// Executable allocated sections.
+    pub text: u64,
+    /// Other allocated sections with file contents (.rodata, .data, …).
+    pub data: u64,
+    /// Allocated `NOBITS` sections (.bss).
+    pub bss: u64,
+    pub total: u64,
+    /// Allocated sections, in file order.
+    pub sections: Vec<SectionSize>,
+    /// Sized symbols, largest first.
+    pub symbols: Vec<SymbolSize>,
+}
+
+impl SizeReport {
+    pub fn from_elf(path: &str, image: &Elf) -> Self {
+        let (mut text, mut data, mut bss) = (0, 0, 0);
+        let mut sections = Vec::new();
+        for s in image.sections.iter().filter(|s| s.is_alloc()) {
+            if s.flags & SHF_EXECINSTR != 0 {
+                text += s.size;
+            } else if s.kind == SHT_NOBITS {
+                bss += s.size;
+            } else {
+                data += s.size;
+            }
+            sections.push(SectionSize {
+                name: s.name.clone(),
+                size: s.size,
+            });
+        }
+        let mut symbols: Vec<SymbolSize> = image
+            .symbols
+            .iter()
+            .filter(|s| s.size > 0 && !s.name.is_empty())
+            .map(|s| SymbolSize {
+                name: s.name.clone(),
+                size: s.size,
+                section: image
+                    .sections
+                    .get(s.shndx as usize)
+                    .map(|sec| sec.name.clone())
+                    .unwrap_or_default(),
+            })
+            .collect();
+        symbols.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+        Self {
+            elf: path.to_string(),
+            text,
+            data,
+            bss,
+            total: text + data + bss,
+            sections,
+            symbols,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, text + "\n").with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+/// Analyse the ELF at `path`.
+pub fn analyze(path: &Path) -> Result<SizeReport> {
+    let image = elf::read_file(path)?;
+    Ok(SizeReport::from_elf(&path.display().to_string(), &image))
+}
+
+/// One entry whose size changed between two reports.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Change {
+    pub name: String,
+    pub before: u64,
+    pub after: u64,
+    pub delta: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SizeDelta {
+    pub text: i64,
+    pub data: i64,
+    pub bss: i64,
+    pub total: i64,
+    pub sections: Vec<Change>,
+    /// Changed symbols, largest absolute change first.
+    pub symbols: Vec<Change>,
+}
+
+fn signed(before: u64, after: u64) -> i64 {
+    after as i64 - before as i64
+}
+
+/// Sum sizes per name (a section or symbol name can repeat).
+fn by_name<'a>(items: impl Iterator<Item = (&'a str, u64)>) -> BTreeMap<&'a str, u64> {
+    let mut m = BTreeMap::new();
+    for (name, size) in items {
+        *m.entry(name).or_insert(0) += size;
+    }
+    m
+}
+
+fn changes(before: &BTreeMap<&str, u64>, after: &BTreeMap<&str, u64>) -> Vec<Change> {
+    let names: BTreeSet<&str> = before.keys().chain(after.keys()).copied().collect();
+    let mut out: Vec<Change> = names
+        .into_iter()
+        .filter_map(|name| {
+            let b = before.get(name).copied().unwrap_or(0);
+            let a = after.get(name).copied().unwrap_or(0);
+            (a != b).then(|| Change {
+                name: name.to_string(),
+                before: b,
+                after: a,
+                delta: signed(b, a),
+            })
+        })
+        .collect();
+    out.sort_by(|x, y| {
+        y.delta
+            .unsigned_abs()
+            .cmp(&x.delta.unsigned_abs())
+            .then_with(|| x.name.cmp(&y.name))
+    });
+    out
+}
+
+/// What changed from `before` to `after`.
+pub fn diff(before: &SizeReport, after: &SizeReport) -> SizeDelta {
+    fn sections(r: &SizeReport) -> BTreeMap<&str, u64> {
+        by_name(r.sections.iter().map(|s| (s.name.as_str(), s.size)))
+    }
+    fn symbols(r: &SizeReport) -> BTreeMap<&str, u64> {
+        by_name(r.symbols.iter().map(|s| (s.name.as_str(), s.size)))
+    }
+    SizeDelta {
+        text: signed(before.text, after.text),
+        data: signed(before.data, after.data),
+        bss: signed(before.bss, after.bss),
+        total: signed(before.total, after.total),
+        sections: changes(&sections(before), &sections(after)),
+        symbols: changes(&symbols(before), &symbols(after)),
+    }
+}
+
+/// Growth limits in bytes; `None` disables a check.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeLimits {
+    pub max_text_growth: Option<u64>,
+    pub max_total_growth: Option<u64>,
+}
+
+/// One message per exceeded limit.
+pub fn check(delta: &SizeDelta, limits: &SizeLimits) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Some(max) = limits.max_text_growth {
+        if delta.text > max as i64 {
+            problems.push(format!(".text grew by {} bytes (limit {max})", delta.text));
+        }
+    }
+    if let Some(max) = limits.max_total_growth {
+        if delta.total > max as i64 {
+            problems.push(format!("image grew by {} bytes (limit {max})", delta.total));
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(text: u64, syms: &[(&str, u64)]) -> SizeReport {
+        SizeReport {
+            elf: "kernel.elf".into(),
+            text,
+            data: 100,
+            bss: 0,
+            total: text + 100,
+            sections: vec![SectionSize {
+                name: ".text".into(),
+                size: text,
+            }],
+            symbols: syms
+                .iter()
+                .map(|(n, s)| SymbolSize {
+                    name: n.to_string(),
+                    size: *s,
+                    section: ".text".into(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn diff_reports_grown_new_and_removed_symbols() {
+        let before = report(1000, &[("kmain", 200), ("old", 50)]);
+        let after = report(1600, &[("kmain", 300), ("matmul", 500)]);
+        let d = diff(&before, &after);
+        assert_eq!(d.text, 600);
+        assert_eq!(d.sections[0].delta, 600);
+        let names: Vec<_> = d
+            .symbols
+            .iter()
+            .map(|c| (c.name.as_str(), c.delta))
+            .collect();
+        assert_eq!(names, [("matmul", 500), ("kmain", 100), ("old", -50)]);
+    }
+
+    #[test]
+    fn limits_fail_only_on_growth_past_threshold() {
+        let d = diff(&report(1000, &[]), &report(1600, &[]));
+        let limits = SizeLimits {
+            max_text_growth: Some(512),
+            max_total_growth: Some(4096),
+        };
+        let problems = check(&d, &limits);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with(".text grew by 600"));
+        assert!(check(&diff(&report(1600, &[]), &report(1000, &[])), &limits).is_empty());
+    }
+
+    #[test]
+    fn analyzes_a_real_elf() {
+        let exe = std::env::current_exe().unwrap();
+        let r = analyze(&exe).unwrap();
+        assert!(r.text > 0);
+        assert_eq!(r.total, r.text + r.data + r.bss);
+        assert!(r.symbols.windows(2).all(|w| w[0].size >= w[1].size));
+    }
+}
@@ -0,0 +1,637 @@
+//! `--stack-usage`: worst-case stack depth per entry point, checked against
+//! a budget for interrupt handlers.
+//!
+//! C sources are compiled with `-fstack-usage`, so the compiler writes each
+//! function's frame size beside its object (`main.c.o` → `main.c.su`). The
+//! call graph comes from `objdump -d` of the linked kernel: a call to the
+//! start of a function is an edge, and so is a jump to one (a tail call,
+//! counted as if it were a call, which can only overestimate). A function's
+//! depth is its frame plus its deepest callee's.
+//!
+//! The analysis is only as complete as that graph. Indirect calls cannot be
+//! followed; `[stack] calls` names their targets. Functions with no frame
+//! record, the assembly stubs, count as `[stack] frames` says, else zero.
+//! Either gap marks the entry's depth as a lower bound. A path that recurses
+//! or goes through a dynamically sized frame is unbounded.
+//!
+//! Entry points are functions nothing calls, `[stack] entries`, and every
+//! function matching `[stack] interrupts`. The report goes to
+//! `<output>/stack.json`. With a budget, the build fails when an interrupt
+//! entry can go deeper than it or is unbounded.
+//!
+//! ```toml
+//! [stack]
+//! budget = 4096
+//! interrupts = ["isr_*", "irq*_stub"]
+//! frames = { irq0_stub = 136 }
+//! calls = { vfs_read = ["ramfs_read", "fat_read"] }
+//! ```
+
+use crate::config::glob_match;
+use crate::exec::run_tool;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+pub const REPORT_NAME: &str = "stack.json";
+
+/// The compiler flag that writes `.su` files.
+pub const STACK_USAGE_FLAG: &str = "-fstack-usage";
+
+/// Default `[stack] interrupts`: the names interrupt entry stubs and
+/// handlers usually have.
+pub const DEFAULT_INTERRUPTS: &[&str] = &[
+    "isr_*",
+    "irq_*",
+    "irq*_stub",
+    "*_isr",
+    "*_irq_handler",
+    "exception_handler",
+];
+
+/// `[stack]` in `auton-build.toml`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct StackConfig {
+    /// Bytes an interrupt entry may use; none only reports.
+    pub budget: Option<u64>,
+    /// Name globs for interrupt entries; defaults to [`DEFAULT_INTERRUPTS`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub interrupts: Vec<String>,
+    /// Entry points besides the functions nothing calls.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub entries: Vec<String>,
+    /// Frame sizes for functions the compiler does not report (assembly).
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub frames: BTreeMap<String, u64>,
+    /// Function → the functions its indirect calls reach.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub calls: BTreeMap<String, Vec<String>>,
+}
+
+impl StackConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn is_interrupt(&self, name: &str) -> bool {
+        if self.interrupts.is_empty() {
+            DEFAULT_INTERRUPTS.iter().any(|g| glob_match(g, name))
+        } else {
+            self.interrupts.iter().any(|g| glob_match(g, name))
+        }
+    }
+}
+
+/// One function's frame, from a `.su` line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub bytes: u64,
+    /// `dynamic` without `bounded`: `alloca` or a VLA of unknown size.
+    pub unbounded: bool,
+}
+
+/// Parse `.su` text (`file:line:col:name<TAB>bytes<TAB>qualifiers`) into
+/// `frames`. A name defined in several files (`static` functions) keeps
+/// its largest frame.
+pub fn parse_su(text: &str, frames: &mut BTreeMap<String, Frame>) {
+    for line in text.lines() {
+        let mut fields = line.split('\t');
+        let (Some(location), Some(bytes), Some(qualifiers)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let (Some(name), Ok(bytes)) = (location.rsplit(':').next(), bytes.trim().parse()) else {
+            continue;
+        };
+        let frame = Frame {
+            bytes,
+            unbounded: qualifiers.contains("dynamic") && !qualifiers.contains("bounded"),
+        };
+        frames
+            .entry(name.to_string())
+            .and_modify(|f| {
+                f.bytes = f.bytes.max(frame.bytes);
+                f.unbounded |= frame.unbounded;
+            })
+            .or_insert(frame);
+    }
+}
+
+/// Every frame recorded in `files`; missing files (sources that compiled
+/// to nothing) are skipped.
+pub fn read_frames(files: impl IntoIterator<Item = PathBuf>) -> Result<BTreeMap<String, Frame>> {
+    let mut frames = BTreeMap::new();
+    for path in files {
+        match std::fs::read_to_string(&path) {
+            Ok(text) => parse_su(&text, &mut frames),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+    Ok(frames)
+}
+
+/// Who calls whom in a disassembled image.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CallGraph {
+    /// Every function, with the functions it calls or tail-calls.
+    pub calls: BTreeMap<String, BTreeSet<String>>,
+    /// Functions that make indirect calls.
+    pub indirect: BTreeSet<String>,
+}
+
+impl CallGraph {
+    /// Build the graph from `objdump -d --no-show-raw-insn` output (GNU or
+    /// LLVM; x86_64, aarch64 and riscv64 mnemonics).
+    pub fn parse(disassembly: &str) -> Self {
+        let mut graph = Self::default();
+        let mut current: Option<String> = None;
+        for line in disassembly.lines() {
+            if let Some(name) = function_header(line) {
+                graph.calls.entry(name.to_string()).or_default();
+                current = Some(name.to_string());
+                continue;
+            }
+            let (Some(function), Some((mnemonic, operands))) = (&current, instruction(line)) else {
+                continue;
+            };
+            let call = matches!(mnemonic, "call" | "callq" | "bl" | "jal");
+            if (call && operands.starts_with('*')) || matches!(mnemonic, "blr" | "jalr") {
+                graph.indirect.insert(function.clone());
+                continue;
+            }
+            if !call && !is_branch(mnemonic) {
+                continue;
+            }
+            // A branch to its own start is a loop; a call to it recurses.
+            if let Some(target) = branch_target(operands).filter(|t| call || t != function) {
+                graph
+                    .calls
+                    .get_mut(function)
+                    .expect("current function is in the graph")
+                    .insert(target.to_string());
+            }
+        }
+        graph
+    }
+
+    /// Functions nothing in the graph calls.
+    pub fn roots(&self) -> BTreeSet<&str> {
+        let called: BTreeSet<&str> = self.calls.values().flatten().map(String::as_str).collect();
+        self.calls
+            .keys()
+            .map(String::as_str)
+            .filter(|f| !called.contains(f))
+            .collect()
+    }
+}
+
+/// `0000000000401126 <leaf>:` → `leaf`.
+fn function_header(line: &str) -> Option<&str> {
+    let (addr, rest) = line.split_once(' ')?;
+    if addr.is_empty() || !addr.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    rest.strip_prefix('<')?.strip_suffix(">:")
+}
+
+/// `  401028:\tcall   40100e <mid>` → `("call", "40100e <mid>")`, with
+/// `notrack`/`bnd` prefixes dropped.
+fn instruction(line: &str) -> Option<(&str, &str)> {
+    let (addr, rest) = line.trim_start().split_once(':')?;
+    if addr.is_empty() || !addr.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut rest = rest.trim();
+    loop {
+        let (mnemonic, operands) = rest
+            .split_once(|c: char| c.is_whitespace())
+            .map(|(m, o)| (m, o.trim()))
+            .unwrap_or((rest, ""));
+        if matches!(mnemonic, "notrack" | "bnd") {
+            rest = operands;
+            continue;
+        }
+        return (!mnemonic.is_empty()).then_some((mnemonic, operands));
+    }
+}
+
+/// Direct jumps and conditional branches (x86 `jmp`/`jcc`, aarch64
+/// `b`/`b.cond`/`cbz`/`tbz`, riscv `j`/`beq…`/`tail`).
+fn is_branch(mnemonic: &str) -> bool {
+    mnemonic.starts_with('j')
+        || mnemonic.starts_with('b')
+        || matches!(mnemonic, "cbz" | "cbnz" | "tbz" | "tbnz" | "tail")
+}
+
+/// The function a branch goes to, when its last operand is
+/// `<addr> <name>` for the start of `name` (not `<name+0x10>`).
+fn branch_target(operands: &str) -> Option<&str> {
+    let last = operands.rsplit(',').next()?.trim();
+    let (addr, sym) = last.split_once(' ')?;
+    let addr = addr.strip_prefix("0x").unwrap_or(addr);
+    if addr.is_empty() || !addr.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let name = sym.strip_prefix('<')?.strip_suffix('>')?;
+    let name = name.split('@').next().unwrap_or(name);
+    (!name.is_empty() && !name.contains(['+', '-'])).then_some(name)
+}
+
+/// How deep one entry point's stack can get.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EntryDepth {
+    pub name: String,
+    pub interrupt: bool,
+    /// The deepest path found; with `bounded` false, up to where it
+    /// recursed or hit a dynamic frame.
+    pub bytes: u64,
+    pub bounded: bool,
+    /// False when the path crosses an unresolved indirect call or a
+    /// function without a frame size, so `bytes` is a lower bound.
+    pub complete: bool,
+    /// The deepest call chain, starting at `name`.
+    pub path: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StackReport {
+    pub budget: Option<u64>,
+    /// Unbounded first, then deepest first.
+    pub entries: Vec<EntryDepth>,
+    /// Functions on some entry's path with no frame size.
+    pub unknown_frames: Vec<String>,
+    /// Functions whose indirect calls `[stack] calls` does not resolve.
+    pub unresolved_calls: Vec<String>,
+}
+
+impl StackReport {
+    /// Interrupt entries deeper than the budget, or unbounded.
+    pub fn over_budget(&self) -> Vec<&EntryDepth> {
+        let Some(budget) = self.budget else {
+            return Vec::new();
+        };
+        self.entries
+            .iter()
+            .filter(|e| e.interrupt && (!e.bounded || e.bytes > budget))
+            .collect()
+    }
+
+    /// Write `<output>/stack.json`.
+    pub fn write(&self, output: &Path) -> Result<PathBuf> {
+        let path = output.join(REPORT_NAME);
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("writing {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Fail when an interrupt entry is over budget.
+    pub fn check(&self) -> Result<()> {
+        let over = self.over_budget();
+        if over.is_empty() {
+            return Ok(());
+        }
+        let lines: Vec<String> = over
+            .iter()
+            .map(|e| {
+                let depth = if e.bounded {
+                    format!("{} bytes", e.bytes)
+                } else {
+                    "unbounded".to_string()
+                };
+                format!("  {}: {depth} via {}", e.name, e.path.join(" -> "))
+            })
+            .collect();
+        bail!(
+            "{} interrupt path(s) exceed the {}-byte stack budget:\n{}",
+            over.len(),
+            self.budget.unwrap_or_default(),
+            lines.join("\n")
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Depth {
+    bytes: u64,
+    bounded: bool,
+    complete: bool,
+    path: Vec<String>,
+}
+
+impl Depth {
+    fn deeper_than(&self, other: &Depth) -> bool {
+        match (self.bounded, other.bounded) {
+            (false, true) => true,
+            (true, false) => false,
+            _ => self.bytes > other.bytes,
+        }
+    }
+}
+
+struct Walk<'a> {
+    graph: &'a CallGraph,
+    frames: &'a BTreeMap<String, Frame>,
+    config: &'a StackConfig,
+    memo: BTreeMap<String, Depth>,
+    active: BTreeSet<String>,
+    unknown: BTreeSet<String>,
+    unresolved: BTreeSet<String>,
+}
+
+impl Walk<'_> {
+    /// `name`'s frame: `[stack] frames`, then the `.su` records. gcc
+    /// records clones without their number (`f.constprop.0` is
+    /// `f.constprop`), and a `.cold` part runs in its parent's frame.
+    fn frame(&self, name: &str) -> Option<Frame> {
+        if let Some(&bytes) = self.config.frames.get(name) {
+            return Some(Frame {
+                bytes,
+                unbounded: false,
+            });
+        }
+        if let Some(f) = self.frames.get(name) {
+            return Some(*f);
+        }
+        if name.split('.').any(|part| part == "cold") {
+            return Some(Frame {
+                bytes: 0,
+                unbounded: false,
+            });
+        }
+        let (base, number) = name.rsplit_once('.')?;
+        number.bytes().all(|b| b.is_ascii_digit()).then_some(())?;
+        self.frames.get(base).copied()
+    }
+
+    fn depth(&mut self, name: &str) -> Depth {
+        if let Some(d) = self.memo.get(name) {
+            return d.clone();
+        }
+        if !self.active.insert(name.to_string()) {
+            return Depth {
+                bytes: 0,
+                bounded: false,
+                complete: true,
+                path: vec![name.to_string()],
+            };
+        }
+        let (frame, mut complete) = match self.frame(name) {
+            Some(f) => (f, true),
+            None => {
+                self.unknown.insert(name.to_string());
+                (
+                    Frame {
+                        bytes: 0,
+                        unbounded: false,
+                    },
+                    false,
+                )
+            }
+        };
+        let extra = self.config.calls.get(name);
+        if self.graph.indirect.contains(name) && extra.is_none() {
+            self.unresolved.insert(name.to_string());
+            complete = false;
+        }
+        let callees: BTreeSet<String> = self
+            .graph
+            .calls
+            .get(name)
+            .into_iter()
+            .flatten()
+            .chain(extra.into_iter().flatten())
+            .cloned()
+            .collect();
+
+        let mut deepest: Option<Depth> = None;
+        for callee in &callees {
+            let d = self.depth(callee);
+            complete &= d.complete;
+            if deepest.as_ref().is_none_or(|best| d.deeper_than(best)) {
+                deepest = Some(d);
+            }
+        }
+        let mut path = vec![name.to_string()];
+        let (mut bytes, mut bounded) = (frame.bytes, !frame.unbounded);
+        if let Some(d) = deepest.filter(|_| bounded) {
+            bytes += d.bytes;
+            bounded = d.bounded;
+            path.extend(d.path);
+        }
+        self.active.remove(name);
+        let depth = Depth {
+            bytes,
+            bounded,
+            complete,
+            path,
+        };
+        self.memo.insert(name.to_string(), depth.clone());
+        depth
+    }
+}
+
+/// The worst-case depth of every entry point in `graph`.
+pub fn analyze(
+    graph: &CallGraph,
+    frames: &BTreeMap<String, Frame>,
+    config: &StackConfig,
+) -> StackReport {
+    let mut entries: BTreeSet<&str> = graph.roots();
+    entries.extend(config.entries.iter().map(String::as_str));
+    entries.extend(
+        graph
+            .calls
+            .keys()
+            .map(String::as_str)
+            .filter(|f| config.is_interrupt(f)),
+    );
+
+    let mut walk = Walk {
+        graph,
+        frames,
+        config,
+        memo: BTreeMap::new(),
+        active: BTreeSet::new(),
+        unknown: BTreeSet::new(),
+        unresolved: BTreeSet::new(),
+    };
+    let mut depths: Vec<EntryDepth> = entries
+        .into_iter()
+        .map(|name| {
+            let d = walk.depth(name);
+            EntryDepth {
+                name: name.to_string(),
+                interrupt: config.is_interrupt(name),
+                bytes: d.bytes,
+                bounded: d.bounded,
+                complete: d.complete,
+                path: d.path,
+            }
+        })
+        .collect();
+    depths.sort_by(|a, b| {
+        a.bounded
+            .cmp(&b.bounded)
+            .then_with(|| b.bytes.cmp(&a.bytes))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    StackReport {
+        budget: config.budget,
+        entries: depths,
+        unknown_frames: walk.unknown.into_iter().collect(),
+        unresolved_calls: walk.unresolved.into_iter().collect(),
+    }
+}
+
+/// `objdump` arguments disassembling `elf` for its call graph.
+pub fn disassemble_args(elf: &Path) -> Vec<String> {
+    vec![
+        "-d".to_string(),
+        "--no-show-raw-insn".to_string(),
+        elf.display().to_string(),
+    ]
+}
+
+/// Disassemble `elf` with `objdump`, read the `.su` files and analyse.
+pub async fn run(
+    objdump: &str,
+    elf: &Path,
+    su_files: impl IntoIterator<Item = PathBuf>,
+    config: &StackConfig,
+) -> Result<StackReport> {
+    let frames = read_frames(su_files)?;
+    let args = disassemble_args(elf);
+    let out = run_tool(objdump, &args, &format!("disassembling {}", elf.display())).await?;
+    Ok(analyze(&CallGraph::parse(&out.stdout), &frames, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GNU: &str = "\
+kernel.elf:     file format elf64-x86-64
+
+Disassembly of section .text:
+
+0000000000100000 <irq0_stub>:
+  100000:\tpush   %rax
+  100001:\tcall   100020 <irq_timer>
+  100006:\tiretq
+
+0000000000100020 <irq_timer>:
+  100020:\tsub    $0x18,%rsp
+  100024:\tje     100030 <irq_timer+0x10>
+  100026:\tcall   100040 <sched_tick>
+  10002b:\tlea    0x10(%rip),%rax        # 100040 <sched_tick>
+  100030:\tadd    $0x18,%rsp
+  100034:\tjmp    100050 <pic_eoi>
+
+0000000000100040 <sched_tick>:
+  100040:\tnotrack call *%rax
+  100042:\tret
+
+0000000000100050 <pic_eoi>:
+  100050:\tret
+
+0000000000100060 <walk>:
+  100060:\tcallq  0x100060 <walk>
+  100065:\tcallq  0x100070 <fold>
+  10006a:\tret
+
+0000000000100070 <fold>:
+  100070:\tcall   100060 <walk>
+";
+
+    fn frames(su: &str) -> BTreeMap<String, Frame> {
+        let mut frames = BTreeMap::new();
+        parse_su(su, &mut frames);
+        frames
+    }
+
+    #[test]
+    fn parses_su_lines_keeping_the_largest_frame() {
+        let f = frames(
+            "irq.c:3:6:irq_timer\t32\tstatic\n\
+             a.c:1:13:helper\t16\tstatic\nb.c:9:13:helper\t48\tstatic\n\
+             vla.c:2:5:copy\t64\tdynamic\nva.c:2:5:log\t96\tdynamic,bounded\n",
+        );
+        assert_eq!(f["irq_timer"].bytes, 32);
+        assert_eq!(f["helper"].bytes, 48);
+        assert!(f["copy"].unbounded);
+        assert!(!f["log"].unbounded);
+    }
+
+    #[test]
+    fn builds_the_call_graph_from_disassembly() {
+        let g = CallGraph::parse(GNU);
+        let calls = |f: &str| g.calls[f].iter().cloned().collect::<Vec<_>>();
+        assert_eq!(calls("irq0_stub"), ["irq_timer"]);
+        // The tail call counts; the address load and local branch do not.
+        assert_eq!(calls("irq_timer"), ["pic_eoi", "sched_tick"]);
+        assert_eq!(calls("walk"), ["fold", "walk"]);
+        assert!(g.indirect.contains("sched_tick"));
+        assert_eq!(g.roots().into_iter().collect::<Vec<_>>(), ["irq0_stub"]);
+    }
+
+    #[test]
+    fn clones_and_cold_parts_find_their_frames() {
+        let g = CallGraph::parse(
+            "0000000000100000 <irq_rx>:\n  100000:\tcall   100010 <fmt.constprop.0>\n\n\
+             0000000000100010 <fmt.constprop.0>:\n  100010:\tjmp    100020 <fmt.constprop.0.cold>\n\n\
+             0000000000100020 <fmt.constprop.0.cold>:\n  100020:\tud2\n",
+        );
+        let f = frames("rx.c:1:6:irq_rx\t16\tstatic\nrx.c:9:13:fmt.constprop\t40\tstatic\n");
+        let report = analyze(&g, &f, &StackConfig::default());
+        assert_eq!(
+            (report.entries[0].bytes, report.entries[0].complete),
+            (56, true)
+        );
+        assert!(report.unknown_frames.is_empty());
+    }
+
+    #[test]
+    fn depth_follows_the_deepest_path_and_flags_gaps() {
+        let g = CallGraph::parse(GNU);
+        let f = frames(
+            "irq.c:3:6:irq_timer\t32\tstatic\nsched.c:1:6:sched_tick\t48\tstatic\n\
+             pic.c:1:6:pic_eoi\t8\tstatic\nw.c:1:6:walk\t16\tstatic\nw.c:9:6:fold\t16\tstatic\n",
+        );
+        let mut config = StackConfig {
+            budget: Some(100),
+            entries: vec!["walk".into()],
+            frames: BTreeMap::from([("irq0_stub".to_string(), 24)]),
+            ..Default::default()
+        };
+        let report = analyze(&g, &f, &config);
+        let stub = report
+            .entries
+            .iter()
+            .find(|e| e.name == "irq0_stub")
+            .unwrap();
+        assert_eq!(stub.bytes, 24 + 32 + 48);
+        assert_eq!(stub.path, ["irq0_stub", "irq_timer", "sched_tick"]);
+        assert!(stub.interrupt && stub.bounded && !stub.complete);
+        assert_eq!(report.unresolved_calls, ["sched_tick"]);
+        assert_eq!(report.entries[0].name, "walk");
+        assert!(!report.entries[0].bounded);
+        assert_eq!(report.over_budget().len(), 1);
+        assert!(report.check().is_err());
+
+        config.calls = BTreeMap::from([("sched_tick".to_string(), vec!["pic_eoi".into()])]);
+        config.budget = Some(112);
+        let report = analyze(&g, &f, &config);
+        let stub = report
+            .entries
+            .iter()
+            .find(|e| e.name == "irq0_stub")
+            .unwrap();
+        assert_eq!(stub.bytes, 24 + 32 + 48 + 8);
+        assert!(stub.complete);
+        assert!(report.check().is_ok());
+    }
+}
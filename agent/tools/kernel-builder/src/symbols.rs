@@ -0,0 +1,327 @@
+//! `<output>/symbols.json`: symbol → address table for the linked kernel.
+//!
+//! The link stage asks ld for a map (`-Map=kernel.map`). Symbols come from the
+//! ELF symbol table, which unlike the map also lists `static` functions; the
+//! map contributes the object file each symbol was linked from. test-runner
+//! uses [`SymbolTable::lookup`] to symbolize panic backtraces, and the agent
+//! reads the JSON to reason about crash addresses.
+//!
+//! Both GNU ld and lld map formats are understood. Without a map (the make
+//! driver) the table is built from the ELF alone and `object` is absent.
+
+use crate::elf::{self, Elf};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+pub const SYMBOLS_NAME: &str = "symbols.json";
+
+/// Linker map written next to `elf` (`kernel.elf` → `kernel.map`).
+pub fn map_for(elf: &Path) -> PathBuf {
+    elf.with_extension("map")
+}
+
+/// One input section placed by the linker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputSection {
+    pub section: String,
+    pub address: u64,
+    pub size: u64,
+    pub object: String,
+}
+
+/// What the linker map tells us.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkerMap {
+    pub inputs: Vec<InputSection>,
+    /// Symbols the map lists (globals only), in map order.
+    pub symbols: Vec<(String, u64)>,
+}
+
+impl LinkerMap {
+    /// Object file whose input section contains `addr`.
+    pub fn object_at(&self, addr: u64) -> Option<&str> {
+        self.inputs
+            .iter()
+            .find(|i| i.size > 0 && (i.address..i.address + i.size).contains(&addr))
+            .map(|i| i.object.as_str())
+    }
+}
+
+fn hex(tok: &str) -> Option<u64> {
+    u64::from_str_radix(tok.strip_prefix("0x").unwrap_or(tok), 16).ok()
+}
+
+/// Parse a GNU ld or lld map file.
+pub fn parse_map(text: &str) -> LinkerMap {
+    if text
+        .lines()
+        .next()
+        .is_some_and(|l| l.split_whitespace().take(3).eq(["VMA", "LMA", "Size"]))
+    {
+        return parse_lld_map(text);
+    }
+    let mut map = LinkerMap::default();
+    let mut pending: Option<String> = None;
+    let body = text
+        .split_once("Linker script and memory map")
+        .map_or(text, |(_, b)| b);
+    for line in body.lines() {
+        let toks: Vec<&str> = line.split_whitespace().collect();
+        // ` .text  0xaddr  0xsize  file.o`, possibly wrapped after the name.
+        let input = match (pending.take(), toks.as_slice()) {
+            (Some(name), [addr, size, object @ ..]) if !object.is_empty() => {
+                Some((name, *addr, *size, object.join(" ")))
+            }
+            (None, [name, addr, size, object @ ..])
+                if line.starts_with(' ')
+                    && !line.starts_with("  ")
+                    && (name.starts_with('.') || *name == "COMMON")
+                    && !object.is_empty() =>
+            {
+                Some((name.to_string(), *addr, *size, object.join(" ")))
+            }
+            (None, [name])
+                if line.starts_with(' ') && !line.starts_with("  ") && name.starts_with('.') =>
+            {
+                pending = Some(name.to_string());
+                None
+            }
+            (None, [addr, name]) if line.starts_with("   ") => {
+                if let (Some(a), false) = (hex(addr), name.contains('=')) {
+                    if addr.starts_with("0x") {
+                        map.symbols.push((name.to_string(), a));
+                    }
+                }
+                None
+            }
+            _ => None,
+        };
+        if let Some((section, addr, size, object)) = input {
+            if let (Some(address), Some(size)) = (hex(addr), hex(size)) {
+                map.inputs.push(InputSection {
+                    section,
+                    address,
+                    size,
+                    object,
+                });
+            }
+        }
+    }
+    map
+}
+
+/// `VMA LMA Size Align Out In Symbol` table; the indentation of the last
+/// column says which of the three it is.
+fn parse_lld_map(text: &str) -> LinkerMap {
+    let mut map = LinkerMap::default();
+    for line in text.lines().skip(1) {
+        let toks: Vec<&str> = line.split_whitespace().collect();
+        let [vma, _lma, size, _align, rest @ ..] = toks.as_slice() else {
+            continue;
+        };
+        let (Some(address), Some(size)) = (hex(vma), hex(size)) else {
+            continue;
+        };
+        let rest = rest.join(" ");
+        if let Some((object, section)) = rest.split_once(":(") {
+            map.inputs.push(InputSection {
+                section: section.trim_end_matches(')').to_string(),
+                address,
+                size,
+                object: object.to_string(),
+            });
+        } else if !rest.starts_with('.') && !rest.contains('=') && !rest.is_empty() {
+            map.symbols.push((rest, address));
+        }
+    }
+    map
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SymbolEntry {
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+    /// `func`, `object` or `notype`.
+    pub kind: String,
+    /// Output section, or `*ABS*` for linker-script symbols.
+    pub section: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub object: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SymbolTable {
+    pub elf: String,
+    /// Sorted by address.
+    pub symbols: Vec<SymbolEntry>,
+}
+
+const SHN_ABS: u16 = 0xfff1;
+
+impl SymbolTable {
+    /// Defined function, object and untyped symbols of `image`, annotated
+    /// with their object file when a map is available.
+    pub fn build(elf_path: &str, image: &Elf, map: Option<&LinkerMap>) -> Self {
+        let mut symbols: Vec<SymbolEntry> = image
+            .symbols
+            .iter()
+            .filter(|s| s.shndx != 0 && !s.name.is_empty() && matches!(s.kind, 0..=2))
+            .map(|s| SymbolEntry {
+                name: s.name.clone(),
+                address: s.value,
+                size: s.size,
+                kind: match s.kind {
+                    elf::STT_FUNC => "func",
+                    1 => "object",
+                    _ => "notype",
+                }
+                .to_string(),
+                section: match s.shndx {
+                    SHN_ABS => "*ABS*".to_string(),
+                    i => image
+                        .sections
+                        .get(i as usize)
+                        .map(|sec| sec.name.clone())
+                        .unwrap_or_default(),
+                },
+                object: map
+                    .filter(|_| s.shndx != SHN_ABS)
+                    .and_then(|m| m.object_at(s.value))
+                    .map(str::to_string),
+            })
+            .collect();
+        symbols.sort_by(|a, b| a.address.cmp(&b.address).then_with(|| a.name.cmp(&b.name)));
+        symbols.dedup_by(|a, b| a.name == b.name && a.address == b.address);
+        Self {
+            elf: elf_path.to_string(),
+            symbols,
+        }
+    }
+
+    /// Symbol containing `addr` and the offset into it. Sized symbols must
+    /// cover the address; an unsized one matches only if it is the nearest
+    /// symbol at or below it.
+    pub fn lookup(&self, addr: u64) -> Option<(&SymbolEntry, u64)> {
+        let idx = self.symbols.partition_point(|s| s.address <= addr);
+        let below = &self.symbols[..idx];
+        if let Some(s) = below
+            .iter()
+            .rev()
+            .filter(|s| s.section != "*ABS*")
+            .find(|s| s.size > 0 && addr < s.address + s.size)
+        {
+            return Some((s, addr - s.address));
+        }
+        below
+            .last()
+            .filter(|s| s.size == 0 && s.section != "*ABS*")
+            .map(|s| (s, addr - s.address))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// Write pretty-printed JSON to `<output>/symbols.json`.
+    pub fn write(&self, output: &Path) -> Result<PathBuf> {
+        let path = output.join(SYMBOLS_NAME);
+        let text = serde_json::to_string_pretty(self)?;
+        std::fs::write(&path, text + "\n")
+            .with_context(|| format!("writing {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+/// Build the table for `elf` (using its map if one was written) and save it
+/// under `output`.
+pub fn export(elf: &Path, output: &Path) -> Result<PathBuf> {
+    let image = elf::read_file(elf)?;
+    let map_path = map_for(elf);
+    let map = match std::fs::read_to_string(&map_path) {
+        Ok(text) => Some(parse_map(&text)),
+        Err(_) => None,
+    };
+    SymbolTable::build(&elf.display().to_string(), &image, map.as_ref()).write(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GNU_MAP: &str = "\
+Memory Configuration
+
+Linker script and memory map
+
+                0x0000000000100000                . = 0x100000
+
+.text           0x0000000000101000     0x6173
+ *(.text .text.*)
+ .text          0x0000000000101000       0xed build/obj/boot.S.o
+                0x0000000000101000                _start
+ *fill*         0x00000000001010ed        0x3
+ .text.startup
+                0x00000000001010f0       0x40 build/obj/kernel/main.c.o
+                0x00000000001010f0                kernel_main
+";
+
+    #[test]
+    fn parses_gnu_map_with_wrapped_section_names() {
+        let map = parse_map(GNU_MAP);
+        assert_eq!(map.inputs.len(), 2);
+        assert_eq!(map.inputs[1].section, ".text.startup");
+        assert_eq!(map.inputs[1].object, "build/obj/kernel/main.c.o");
+        assert_eq!(
+            map.symbols,
+            [
+                ("_start".to_string(), 0x101000),
+                ("kernel_main".to_string(), 0x1010f0)
+            ]
+        );
+        assert_eq!(map.object_at(0x101100), Some("build/obj/kernel/main.c.o"));
+        assert_eq!(map.object_at(0x1010ee), None);
+    }
+
+    #[test]
+    fn parses_lld_map() {
+        let text = "             VMA              LMA     Size Align Out     In      Symbol
+          101000           101000       40    16 .text
+          101000           101000       40    16         build/a.c.o:(.text)
+          101000           101000        0     1                 kmain
+";
+        let map = parse_map(text);
+        assert_eq!(map.inputs[0].object, "build/a.c.o");
+        assert_eq!(map.inputs[0].section, ".text");
+        assert_eq!(map.symbols, [("kmain".to_string(), 0x101000)]);
+    }
+
+    #[test]
+    fn lookup_prefers_the_covering_sized_symbol() {
+        let entry = |name: &str, address, size| SymbolEntry {
+            name: name.into(),
+            address,
+            size,
+            kind: "func".into(),
+            section: ".text".into(),
+            object: None,
+        };
+        let table = SymbolTable {
+            elf: "kernel.elf".into(),
+            symbols: vec![
+                entry("_start", 0x1000, 0),
+                entry("kmain", 0x1010, 0x20),
+                entry("local_label", 0x1018, 0),
+                entry("isr_stub", 0x1030, 0),
+            ],
+        };
+        let (s, off) = table.lookup(0x101c).unwrap();
+        assert_eq!((s.name.as_str(), off), ("kmain", 0xc));
+        assert_eq!(table.lookup(0x1004).unwrap().0.name, "_start");
+        assert_eq!(table.lookup(0x1034).unwrap(), (&table.symbols[3], 4));
+        assert!(table.lookup(0x10).is_none());
+    }
+}
@@ -0,0 +1,332 @@
+//! `kernel-builder symdiff`: what changed between two linked kernels.
+//!
+//! Symbols are matched by name and reported when added, removed or
+//! resized; addresses are not compared, since nearly every change moves
+//! what follows it. Allocated sections are compared by address and size,
+//! and the load address (the lowest `PT_LOAD` physical address) and entry
+//! point are reported when they move. Symbols that are global or weak are
+//! the kernel's exports; diff-validator flags a diff that removes one it
+//! does not mean to.
+
+use crate::elf::{self, Elf, Symbol, STB_GLOBAL, STB_WEAK, STT_FILE, STT_SECTION};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SymbolInfo {
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+    pub section: String,
+    /// Global or weak, so visible to other objects.
+    pub exported: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Resized {
+    pub name: String,
+    pub before: u64,
+    pub after: u64,
+    pub delta: i64,
+    pub exported: bool,
+}
+
+/// Where an allocated section sits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Placement {
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// An allocated section that was added (no `before`), removed (no
+/// `after`), moved or resized.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SectionChange {
+    pub name: String,
+    pub before: Option<Placement>,
+    pub after: Option<Placement>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Moved {
+    pub before: u64,
+    pub after: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SymDiff {
+    pub old: String,
+    pub new: String,
+    pub load_address: Option<Moved>,
+    pub entry: Option<Moved>,
+    /// In the new image's section order, then removed sections.
+    pub sections: Vec<SectionChange>,
+    /// By name.
+    pub added: Vec<SymbolInfo>,
+    /// By name.
+    pub removed: Vec<SymbolInfo>,
+    /// Largest absolute change first.
+    pub resized: Vec<Resized>,
+}
+
+impl SymDiff {
+    pub fn is_empty(&self) -> bool {
+        self.load_address.is_none()
+            && self.entry.is_none()
+            && self.sections.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.resized.is_empty()
+    }
+
+    pub fn removed_exports(&self) -> impl Iterator<Item = &SymbolInfo> {
+        self.removed.iter().filter(|s| s.exported)
+    }
+}
+
+fn exported(s: &Symbol) -> bool {
+    s.bind == STB_GLOBAL || s.bind == STB_WEAK
+}
+
+/// Named, defined symbols by name; an exported one wins over `static`s of
+/// the same name.
+fn symbols(image: &Elf) -> BTreeMap<&str, SymbolInfo> {
+    let mut out: BTreeMap<&str, SymbolInfo> = BTreeMap::new();
+    for s in &image.symbols {
+        if s.name.is_empty() || s.shndx == 0 || matches!(s.kind, STT_SECTION | STT_FILE) {
+            continue;
+        }
+        if out
+            .get(s.name.as_str())
+            .is_some_and(|old| old.exported || !exported(s))
+        {
+            continue;
+        }
+        out.insert(
+            &s.name,
+            SymbolInfo {
+                name: s.name.clone(),
+                address: s.value,
+                size: s.size,
+                section: image
+                    .sections
+                    .get(s.shndx as usize)
+                    .map(|sec| sec.name.clone())
+                    .unwrap_or_default(),
+                exported: exported(s),
+            },
+        );
+    }
+    out
+}
+
+fn placements(image: &Elf) -> Vec<(&str, Placement)> {
+    image
+        .sections
+        .iter()
+        .filter(|s| s.is_alloc())
+        .map(|s| {
+            (
+                s.name.as_str(),
+                Placement {
+                    addr: s.addr,
+                    size: s.size,
+                },
+            )
+        })
+        .collect()
+}
+
+fn moved(before: Option<u64>, after: Option<u64>) -> Option<Moved> {
+    match (before, after) {
+        (Some(before), Some(after)) if before != after => Some(Moved { before, after }),
+        _ => None,
+    }
+}
+
+/// What changed from `old` to `new`.
+pub fn diff(old_name: &str, old: &Elf, new_name: &str, new: &Elf) -> SymDiff {
+    let (before, after) = (placements(old), placements(new));
+    let find = |list: &[(&str, Placement)], name: &str| {
+        list.iter().find(|(n, _)| *n == name).map(|(_, p)| *p)
+    };
+    let mut sections: Vec<SectionChange> = after
+        .iter()
+        .filter(|(name, p)| find(&before, name) != Some(*p))
+        .map(|(name, p)| SectionChange {
+            name: name.to_string(),
+            before: find(&before, name),
+            after: Some(*p),
+        })
+        .collect();
+    sections.extend(
+        before
+            .iter()
+            .filter(|(name, _)| find(&after, name).is_none())
+            .map(|(name, p)| SectionChange {
+                name: name.to_string(),
+                before: Some(*p),
+                after: None,
+            }),
+    );
+
+    let (before, after) = (symbols(old), symbols(new));
+    let added = after
+        .iter()
+        .filter(|(name, _)| !before.contains_key(*name))
+        .map(|(_, s)| s.clone())
+        .collect();
+    let removed = before
+        .iter()
+        .filter(|(name, _)| !after.contains_key(*name))
+        .map(|(_, s)| s.clone())
+        .collect();
+    let mut resized: Vec<Resized> = before
+        .iter()
+        .filter_map(|(name, b)| {
+            let a = after.get(name).filter(|a| a.size != b.size)?;
+            Some(Resized {
+                name: name.to_string(),
+                before: b.size,
+                after: a.size,
+                delta: a.size as i64 - b.size as i64,
+                exported: a.exported || b.exported,
+            })
+        })
+        .collect();
+    resized.sort_by(|x, y| {
+        y.delta
+            .unsigned_abs()
+            .cmp(&x.delta.unsigned_abs())
+            .then_with(|| x.name.cmp(&y.name))
+    });
+
+    SymDiff {
+        old: old_name.to_string(),
+        new: new_name.to_string(),
+        load_address: moved(old.load_address(), new.load_address()),
+        entry: moved(Some(old.entry), Some(new.entry)),
+        sections,
+        added,
+        removed,
+        resized,
+    }
+}
+
+/// Compare the ELF files at `old` and `new`.
+pub fn compare(old: &Path, new: &Path) -> Result<SymDiff> {
+    let (before, after) = (elf::read_file(old)?, elf::read_file(new)?);
+    Ok(diff(
+        &old.display().to_string(),
+        &before,
+        &new.display().to_string(),
+        &after,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::{Section, Segment, PT_LOAD, SHF_ALLOC, STT_FUNC, STT_OBJECT};
+
+    fn image(base: u64, text: u64, syms: &[(&str, u64, u8)]) -> Elf {
+        let section = |name: &str, addr, size| Section {
+            name: name.into(),
+            kind: 1,
+            flags: SHF_ALLOC,
+            addr,
+            offset: 0x1000,
+            size,
+        };
+        Elf {
+            machine: 62,
+            entry: base,
+            sections: vec![
+                section("", 0, 0),
+                section(".text", base, text),
+                section(".data", base + 0x10000, 0x100),
+            ],
+            segments: vec![Segment {
+                kind: PT_LOAD,
+                flags: 5,
+                offset: 0x1000,
+                vaddr: base,
+                paddr: base,
+                filesz: text,
+                memsz: text,
+            }],
+            symbols: syms
+                .iter()
+                .map(|&(name, size, bind)| Symbol {
+                    name: name.into(),
+                    value: base,
+                    size,
+                    kind: if name.ends_with("_table") {
+                        STT_OBJECT
+                    } else {
+                        STT_FUNC
+                    },
+                    bind,
+                    shndx: 1,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn reports_added_removed_and_resized_symbols() {
+        let old = image(
+            0x100000,
+            0x4000,
+            &[
+                ("kmain", 0x200, STB_GLOBAL),
+                ("pmm_alloc", 0x80, STB_GLOBAL),
+                ("helper", 0x10, 0),
+            ],
+        );
+        let new = image(
+            0x100000,
+            0x4000,
+            &[
+                ("kmain", 0x300, STB_GLOBAL),
+                ("helper", 0x10, 0),
+                ("irq_table", 0x40, STB_GLOBAL),
+            ],
+        );
+        let d = diff("old.elf", &old, "new.elf", &new);
+        let names = |s: &[SymbolInfo]| s.iter().map(|s| s.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&d.added), ["irq_table"]);
+        assert_eq!(names(&d.removed), ["pmm_alloc"]);
+        assert_eq!(d.removed_exports().count(), 1);
+        assert_eq!(
+            (d.resized[0].name.as_str(), d.resized[0].delta),
+            ("kmain", 0x100)
+        );
+        assert!(d.sections.is_empty() && d.load_address.is_none());
+    }
+
+    #[test]
+    fn reports_moved_sections_and_load_address() {
+        let old = image(0x100000, 0x4000, &[]);
+        let new = image(0x200000, 0x5000, &[]);
+        let d = diff("old.elf", &old, "new.elf", &new);
+        assert_eq!(
+            d.load_address,
+            Some(Moved {
+                before: 0x100000,
+                after: 0x200000
+            })
+        );
+        let names: Vec<_> = d.sections.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, [".text", ".data"]);
+        assert_eq!(d.sections[0].after.unwrap().size, 0x5000);
+    }
+
+    #[test]
+    fn an_image_matches_itself() {
+        let exe = std::env::current_exe().unwrap();
+        assert!(compare(&exe, &exe).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,490 @@
+//! C toolchain detection and the compile stage.
+//!
+//! Detection prefers the bare-metal cross compilers for the arch (each
+//! prefix in [`ArchToolchain::prefixes`], e.g. `aarch64-elf-gcc` then
+//! `aarch64-none-elf-gcc`) and falls back to clang with an explicit
+//! freestanding target. An explicit
+//! `--cc` override is used as-is (still version-checked), which is how the
+//! Docker image's `x86_64-linux-gnu-gcc` gets selected.
+
+use crate::cache::{BuildCache, CacheOutcome};
+use crate::deps;
+use crate::exec::{self, run_tool};
+use crate::profile::Profile;
+use crate::ArchToolchain;
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Oldest compilers known to handle the seed kernel's flags.
+pub const MIN_GCC_MAJOR: u32 = 9;
+pub const MIN_CLANG_MAJOR: u32 = 11;
+
+/// Freestanding, integer-only x86_64 flags (mirrors `CFLAGS` in the arch
+/// `toolchain.mk`; gcc-only options are added per compiler kind, and
+/// optimisation/debug flags come from the [`Profile`]).
+const X86_64_CFLAGS: &[&str] = &[
+    "-ffreestanding",
+    "-fno-stack-protector",
+    "-fno-pic",
+    "-fno-pie",
+    "-mno-red-zone",
+    "-mno-mmx",
+    "-mno-sse",
+    "-mno-sse2",
+    "-mno-80387",
+    "-std=gnu11",
+    "-Wall",
+    "-Wextra",
+];
+
+/// x86_64 SSE-enabled profile for the neural backend's float math (`CFLAGS_SSE`).
+const X86_64_SSE_CFLAGS: &[&str] = &[
+    "-ffreestanding",
+    "-fno-stack-protector",
+    "-fno-pic",
+    "-fno-pie",
+    "-mno-red-zone",
+    "-fno-math-errno",
+    "-std=gnu11",
+    "-Wall",
+    "-Wextra",
+];
+
+/// Translation units the x86_64 Makefile builds with `CFLAGS_SSE`.
+const SSE_UNITS: &[&str] = &["kernel/slm/neural/", "kernel/lib/kmath.c"];
+
+/// AArch64: keep the compiler off the FP/SIMD registers (`arch_registry.py`).
+const AARCH64_CFLAGS: &[&str] = &[
+    "-ffreestanding",
+    "-fno-stack-protector",
+    "-fno-pic",
+    "-fno-pie",
+    "-mgeneral-regs-only",
+    "-std=gnu11",
+    "-Wall",
+    "-Wextra",
+];
+
+/// RISC-V: rv64gc/lp64d as in `arch_registry.py`, medany so the kernel can
+/// live above 2 GiB (QEMU virt RAM starts at 0x80000000).
+const RISCV64_CFLAGS: &[&str] = &[
+    "-ffreestanding",
+    "-fno-stack-protector",
+    "-fno-pic",
+    "-fno-pie",
+    "-march=rv64gc",
+    "-mabi=lp64d",
+    "-mcmodel=medany",
+    "-std=gnu11",
+    "-Wall",
+    "-Wextra",
+];
+
+/// Base flags for one translation unit on `arch`.
+fn base_cflags(arch: &str, rel: &Path) -> &'static [&'static str] {
+    match arch {
+        "aarch64" => AARCH64_CFLAGS,
+        "riscv64" => RISCV64_CFLAGS,
+        _ => {
+            let rel = rel.to_string_lossy();
+            if SSE_UNITS.iter().any(|u| rel.starts_with(u)) {
+                X86_64_SSE_CFLAGS
+            } else {
+                X86_64_CFLAGS
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompilerKind {
+    Gcc,
+    Clang,
+}
+
+impl CompilerKind {
+    /// Best guess from the program name; `--version` output refines it.
+    fn from_program(program: &str) -> Self {
+        if program.contains("clang") {
+            CompilerKind::Clang
+        } else {
+            CompilerKind::Gcc
+        }
+    }
+
+    fn min_major(self) -> u32 {
+        match self {
+            CompilerKind::Gcc => MIN_GCC_MAJOR,
+            CompilerKind::Clang => MIN_CLANG_MAJOR,
+        }
+    }
+}
+
+/// A compiler to look for on PATH.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub program: String,
+    pub kind: CompilerKind,
+    /// Extra args selecting the target (clang's `--target=`).
+    pub target_args: Vec<String>,
+}
+
+/// A located and version-checked compiler.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Compiler {
+    pub arch: String,
+    pub program: String,
+    pub path: PathBuf,
+    pub kind: CompilerKind,
+    pub version: String,
+    pub target_args: Vec<String>,
+}
+
+impl Compiler {
+    /// Full flag set for one translation unit (workspace-relative `rel`).
+    pub fn cflags(&self, workspace: &Path, rel: &Path, profile: Profile) -> Vec<String> {
+        let mut flags = self.target_args.clone();
+        flags.extend(base_cflags(&self.arch, rel).iter().map(|s| s.to_string()));
+        flags.extend(profile.cflags(self.kind));
+        if self.kind == CompilerKind::Gcc {
+            flags.push("-fno-tree-loop-distribute-patterns".to_string());
+        }
+        flags.push(format!("-I{}", workspace.join("kernel/include").display()));
+        flags
+    }
+}
+
+/// Compilers to try for `tc`, in preference order.
+pub fn candidates(tc: &ArchToolchain, cc_override: Option<&str>) -> Vec<Candidate> {
+    if let Some(cc) = cc_override {
+        return vec![Candidate {
+            program: cc.to_string(),
+            kind: CompilerKind::from_program(cc),
+            target_args: Vec::new(),
+        }];
+    }
+    let mut cands: Vec<Candidate> = tc
+        .prefixed("gcc")
+        .into_iter()
+        .map(|program| Candidate {
+            program,
+            kind: CompilerKind::Gcc,
+            target_args: Vec::new(),
+        })
+        .collect();
+    cands.push(Candidate {
+        program: "clang".to_string(),
+        kind: CompilerKind::Clang,
+        target_args: vec![format!("--target={}", tc.clang_target)],
+    });
+    cands
+}
+
+/// First of `programs` found on the backend's PATH.
+pub fn first_on_path(programs: &[String]) -> Option<String> {
+    programs.iter().find(|p| exec::which(p).is_some()).cloned()
+}
+
+/// First candidate that `lookup` resolves to a path.
+pub fn locate(
+    candidates: &[Candidate],
+    lookup: impl Fn(&str) -> Option<PathBuf>,
+) -> Option<(Candidate, PathBuf)> {
+    candidates
+        .iter()
+        .find_map(|c| lookup(&c.program).map(|p| (c.clone(), p)))
+}
+
+/// Actionable error for when no candidate is on PATH.
+pub fn missing_toolchain_message(tc: &ArchToolchain, candidates: &[Candidate]) -> String {
+    let arch = &tc.arch;
+    let searched: Vec<&str> = candidates.iter().map(|c| c.program.as_str()).collect();
+    format!(
+        "no C compiler found for {arch}; searched PATH for: {}\n\
+         install one of:\n  \
+         - a bare-metal cross compiler: build binutils+gcc for {arch}-elf, or `brew install {arch}-elf-gcc`\n  \
+         - clang (`apt install clang` / `brew install llvm`), used with --target={}\n  \
+         - or point --cc at an existing compiler (e.g. --cc {arch}-linux-gnu-gcc)",
+        searched.join(", "),
+        tc.clang_target
+    )
+}
+
+/// Parse `major.minor` from the first line of `<cc> --version`.
+pub fn parse_version(text: &str) -> Option<(u32, u32)> {
+    let first = text.lines().next()?;
+    first.split_whitespace().find_map(|tok| {
+        let mut parts = tok.split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        Some((major, minor))
+    })
+}
+
+/// Locate a compiler for `tc` and validate its version.
+pub async fn detect(tc: &ArchToolchain, cc_override: Option<&str>) -> Result<Compiler> {
+    let cands = candidates(tc, cc_override);
+    let Some((cand, path)) = locate(&cands, exec::which) else {
+        bail!("{}", missing_toolchain_message(tc, &cands));
+    };
+
+    let what = format!("probing {} version", cand.program);
+    let out = run_tool(&cand.program, &["--version".to_string()], &what).await?;
+    let text = &out.stdout;
+    let kind = if text.to_lowercase().contains("clang") {
+        CompilerKind::Clang
+    } else {
+        cand.kind
+    };
+    let (major, minor) = parse_version(text)
+        .with_context(|| format!("unrecognised `{} --version` output", cand.program))?;
+    if major < kind.min_major() {
+        bail!(
+            "{} {major}.{minor} is too old; need {:?} >= {}",
+            cand.program,
+            kind,
+            kind.min_major()
+        );
+    }
+
+    Ok(Compiler {
+        arch: tc.arch.clone(),
+        program: cand.program,
+        path,
+        kind,
+        version: format!("{major}.{minor}"),
+        target_args: cand.target_args,
+    })
+}
+
+/// The compiler a dry run plans with when [`detect`] finds none: the first
+/// candidate, unprobed, so the plan shows what a real run would look for.
+pub fn assumed(tc: &ArchToolchain, cc_override: Option<&str>) -> Compiler {
+    let cand = candidates(tc, cc_override).remove(0);
+    Compiler {
+        arch: tc.arch.clone(),
+        path: PathBuf::from(&cand.program),
+        program: cand.program,
+        kind: cand.kind,
+        version: "unknown".to_string(),
+        target_args: cand.target_args,
+    }
+}
+
+/// One C compiler invocation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CompileJob {
+    pub source: PathBuf,
+    pub object: PathBuf,
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+/// Recursively collect `.c` files under `dir`, sorted for stable output.
+pub fn discover_sources(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    walk(dir, &mut found).with_context(|| format!("scanning {}", dir.display()))?;
+    found.sort();
+    Ok(found)
+}
+
+fn walk(dir: &Path, found: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(&path, found)?;
+        } else if path.extension().and_then(|e| e.to_str()) == Some("c") {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Plan a compile job for every C source under `workspace/kernel/`.
+/// Objects are named like the Makefile's: `kernel/x.c` → `obj_dir/kernel/x.c.o`.
+pub fn plan(
+    workspace: &Path,
+    obj_dir: &Path,
+    compiler: &Compiler,
+    profile: Profile,
+) -> Result<Vec<CompileJob>> {
+    let root = workspace.join("kernel");
+    if !root.is_dir() {
+        bail!("no kernel/ source directory under {}", workspace.display());
+    }
+    plan_files(
+        workspace,
+        obj_dir,
+        compiler,
+        profile,
+        discover_sources(&root)?,
+    )
+}
+
+/// [`plan`] for an explicit source list (`[sources]` globs).
+pub fn plan_files(
+    workspace: &Path,
+    obj_dir: &Path,
+    compiler: &Compiler,
+    profile: Profile,
+    sources: Vec<PathBuf>,
+) -> Result<Vec<CompileJob>> {
+    let jobs = sources
+        .into_iter()
+        .map(|source| {
+            let rel = source
+                .strip_prefix(workspace)
+                .unwrap_or(&source)
+                .to_path_buf();
+            let object = obj_dir.join(format!("{}.o", rel.display()));
+            let mut args = compiler.cflags(workspace, &rel, profile);
+            args.push("-c".to_string());
+            args.push(source.display().to_string());
+            args.push("-o".to_string());
+            args.push(object.display().to_string());
+            args.extend(deps::depfile_args(&object));
+            CompileJob {
+                source,
+                object,
+                program: compiler.program.clone(),
+                args,
+            }
+        })
+        .collect();
+    Ok(jobs)
+}
+
+/// Compile one translation unit (or restore it from `cache`); the compiler's
+/// stderr is the error's root cause.
+pub async fn compile(job: &CompileJob, cache: &BuildCache) -> Result<CacheOutcome> {
+    let what = format!("compiling {}", job.source.display());
+    cache
+        .run(&job.source, &job.object, &job.program, &job.args, &what)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gcc() -> Compiler {
+        Compiler {
+            arch: "x86_64".into(),
+            program: "x86_64-elf-gcc".into(),
+            path: PathBuf::from("/usr/bin/x86_64-elf-gcc"),
+            kind: CompilerKind::Gcc,
+            version: "13.2".into(),
+            target_args: Vec::new(),
+        }
+    }
+
+    fn tc(arch: &str) -> ArchToolchain {
+        ArchToolchain::for_arch(arch).unwrap()
+    }
+
+    #[test]
+    fn prefers_cross_gcc_then_clang() {
+        let cands = candidates(&tc("x86_64"), None);
+        let found = locate(&cands, |p| {
+            (p == "clang").then(|| PathBuf::from("/usr/bin/clang"))
+        });
+        let (cand, _) = found.unwrap();
+        assert_eq!(cand.kind, CompilerKind::Clang);
+        assert_eq!(cand.target_args, vec!["--target=x86_64-unknown-none"]);
+        assert_eq!(cands[0].program, "x86_64-elf-gcc");
+    }
+
+    #[test]
+    fn override_is_the_only_candidate() {
+        let cands = candidates(&tc("x86_64"), Some("x86_64-linux-gnu-gcc"));
+        assert_eq!(cands.len(), 1);
+        assert_eq!(cands[0].program, "x86_64-linux-gnu-gcc");
+    }
+
+    #[test]
+    fn missing_message_lists_searched_and_install_hints() {
+        let cands = candidates(&tc("x86_64"), None);
+        assert!(locate(&cands, |_| None).is_none());
+        let msg = missing_toolchain_message(&tc("x86_64"), &cands);
+        assert!(msg.contains("x86_64-elf-gcc, clang"));
+        assert!(msg.contains("--cc"));
+    }
+
+    #[test]
+    fn parses_gcc_and_clang_versions() {
+        assert_eq!(
+            parse_version("x86_64-elf-gcc (GCC) 13.2.0\nCopyright"),
+            Some((13, 2))
+        );
+        assert_eq!(
+            parse_version("Debian clang version 14.0.6\nTarget: x86_64"),
+            Some((14, 0))
+        );
+        assert_eq!(parse_version("no digits here"), None);
+    }
+
+    #[test]
+    fn cflags_are_freestanding_and_sse_units_differ() {
+        let cc = gcc();
+        let flags = cc.cflags(
+            Path::new("ws"),
+            Path::new("kernel/mm/pmm.c"),
+            Profile::Release,
+        );
+        for f in [
+            "-ffreestanding",
+            "-mno-red-zone",
+            "-fno-stack-protector",
+            "-mno-sse",
+        ] {
+            assert!(flags.iter().any(|a| a == f), "missing {f}");
+        }
+        assert!(flags.contains(&"-Iws/kernel/include".to_string()));
+        assert!(flags.contains(&"-O2".to_string()));
+
+        let sse = cc.cflags(
+            Path::new("ws"),
+            Path::new("kernel/slm/neural/matmul.c"),
+            Profile::Release,
+        );
+        assert!(!sse.iter().any(|a| a == "-mno-sse"));
+    }
+
+    #[test]
+    fn per_arch_prefixes_and_flags() {
+        let cands = candidates(&tc("riscv64"), None);
+        let programs: Vec<&str> = cands.iter().map(|c| c.program.as_str()).collect();
+        assert_eq!(
+            programs,
+            ["riscv64-elf-gcc", "riscv64-unknown-elf-gcc", "clang"]
+        );
+        assert_eq!(cands[2].target_args, ["--target=riscv64-unknown-elf"]);
+
+        let a64 = Compiler {
+            arch: "aarch64".into(),
+            ..gcc()
+        };
+        let flags = a64.cflags(
+            Path::new("ws"),
+            Path::new("kernel/mm/pmm.c"),
+            Profile::Release,
+        );
+        assert!(flags.iter().any(|f| f == "-mgeneral-regs-only"));
+        assert!(!flags.iter().any(|f| f == "-mno-red-zone"));
+    }
+
+    #[test]
+    fn profile_flags_follow_the_arch_flags() {
+        let flags = gcc().cflags(
+            Path::new("ws"),
+            Path::new("kernel/mm/pmm.c"),
+            Profile::Debug,
+        );
+        let red_zone = flags.iter().position(|f| f == "-mno-red-zone").unwrap();
+        let opt = flags.iter().position(|f| f == "-O0").unwrap();
+        assert!(opt > red_zone);
+        assert!(!flags.iter().any(|f| f == "-O2"));
+    }
+}
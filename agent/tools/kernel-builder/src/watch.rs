@@ -0,0 +1,213 @@
+//! `--watch`: rebuild when workspace sources change.
+//!
+//! Change detection polls file metadata (size + mtime) rather than using
+//! inotify, so it behaves the same on every host and inside bind-mounted
+//! containers where inotify events are unreliable. A burst of saves is
+//! debounced into one rebuild: after the first change the watcher waits
+//! until the tree has been quiet for the debounce window.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Extensions that can affect a kernel build.
+const WATCHED_EXTENSIONS: &[&str] = &[
+    "c", "h", "S", "s", "asm", "ld", "json", "cfg", "mk", "rs", "toml",
+];
+
+/// Directory names never descended into.
+const SKIPPED_DIRS: &[&str] = &[".git", ".cache", "build", "target"];
+
+/// What a file looked like at the last poll.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+}
+
+pub type Snapshot = BTreeMap<PathBuf, Stamp>;
+
+/// Whether `path` is a build input worth watching.
+pub fn is_watched(path: &Path) -> bool {
+    if path.file_name().is_some_and(|n| n == "Makefile") {
+        return true;
+    }
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| WATCHED_EXTENSIONS.contains(&e))
+}
+
+/// Stamp every watched file under `root`, skipping `ignore` (typically the
+/// build output directory) and the usual generated directories.
+pub fn snapshot(root: &Path, ignore: &[PathBuf]) -> Snapshot {
+    let mut snap = Snapshot::new();
+    let mut stack = vec![root.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            if meta.is_dir() {
+                let skipped = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| SKIPPED_DIRS.contains(&n));
+                if !skipped && !ignore.iter().any(|i| path.starts_with(i)) {
+                    stack.push(path);
+                }
+            } else if is_watched(&path) {
+                let stamp = Stamp {
+                    len: meta.len(),
+                    modified: meta.modified().ok(),
+                };
+                snap.insert(path, stamp);
+            }
+        }
+    }
+    snap
+}
+
+/// Paths added, removed or modified between two snapshots, sorted.
+pub fn changed(old: &Snapshot, new: &Snapshot) -> Vec<PathBuf> {
+    let mut out: Vec<PathBuf> = new
+        .iter()
+        .filter(|(p, s)| old.get(*p) != Some(*s))
+        .map(|(p, _)| p.clone())
+        .collect();
+    out.extend(old.keys().filter(|p| !new.contains_key(*p)).cloned());
+    out.sort();
+    out
+}
+
+/// Polling and debounce intervals.
+#[derive(Debug, Clone, Copy)]
+pub struct WatchOptions {
+    pub poll: Duration,
+    pub debounce: Duration,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            poll: Duration::from_millis(500),
+            debounce: Duration::from_millis(300),
+        }
+    }
+}
+
+/// Block until something under `root` changes and then stays quiet for the
+/// debounce window; returns the changed paths relative to `root`. `last` is
+/// updated to the settled snapshot.
+pub async fn wait_for_change(
+    root: &Path,
+    ignore: &[PathBuf],
+    last: &mut Snapshot,
+    opts: WatchOptions,
+) -> Vec<PathBuf> {
+    let mut pending: Vec<PathBuf> = Vec::new();
+    loop {
+        let interval = if pending.is_empty() {
+            opts.poll
+        } else {
+            opts.debounce
+        };
+        tokio::time::sleep(interval).await;
+        let now = snapshot(root, ignore);
+        let delta = changed(last, &now);
+        *last = now;
+        if delta.is_empty() {
+            if !pending.is_empty() {
+                break;
+            }
+            continue;
+        }
+        pending.extend(delta);
+    }
+    pending.sort();
+    pending.dedup();
+    pending
+        .into_iter()
+        .map(|p| p.strip_prefix(root).map(Path::to_path_buf).unwrap_or(p))
+        .collect()
+}
+
+/// One-line summary of the changed paths for the rebuild banner.
+pub fn describe_changes(paths: &[PathBuf]) -> String {
+    match paths {
+        [] => "no changes".to_string(),
+        [one] => one.display().to_string(),
+        [first, rest @ ..] => format!("{} (+{} more)", first.display(), rest.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kb-watch-{tag}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("kernel")).unwrap();
+        std::fs::create_dir_all(dir.join("build")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn snapshot_skips_outputs_and_unwatched_files() {
+        let dir = scratch_dir("snap");
+        std::fs::write(dir.join("kernel/a.c"), "int a;").unwrap();
+        std::fs::write(dir.join("kernel/notes.txt"), "x").unwrap();
+        std::fs::write(dir.join("build/a.c"), "int a;").unwrap();
+        std::fs::write(dir.join("Makefile"), "all:").unwrap();
+        let snap = snapshot(&dir, &[]);
+        let names: Vec<_> = snap.keys().map(|p| p.strip_prefix(&dir).unwrap()).collect();
+        assert_eq!(names, vec![Path::new("Makefile"), Path::new("kernel/a.c")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_reports_edits_additions_and_removals() {
+        let stamp = |len| Stamp {
+            len,
+            modified: None,
+        };
+        let old: Snapshot = [("a.c".into(), stamp(1)), ("b.c".into(), stamp(1))].into();
+        let new: Snapshot = [("a.c".into(), stamp(2)), ("c.c".into(), stamp(1))].into();
+        assert_eq!(
+            changed(&old, &new),
+            vec![PathBuf::from("a.c"), "b.c".into(), "c.c".into()]
+        );
+        assert!(changed(&new, &new).is_empty());
+    }
+
+    #[tokio::test]
+    async fn debounces_a_burst_into_one_change_set() {
+        let dir = scratch_dir("burst");
+        std::fs::write(dir.join("kernel/a.c"), "int a;").unwrap();
+        let mut last = snapshot(&dir, &[]);
+        let opts = WatchOptions {
+            poll: Duration::from_millis(10),
+            debounce: Duration::from_millis(150),
+        };
+        let writer = {
+            let dir = dir.clone();
+            tokio::spawn(async move {
+                std::fs::write(dir.join("kernel/a.c"), "int a = 1;").unwrap();
+                tokio::time::sleep(Duration::from_millis(15)).await;
+                std::fs::write(dir.join("kernel/b.h"), "#pragma once").unwrap();
+            })
+        };
+        let changes = wait_for_change(&dir, &[], &mut last, opts).await;
+        writer.await.unwrap();
+        assert_eq!(
+            changes,
+            vec![PathBuf::from("kernel/a.c"), "kernel/b.h".into()]
+        );
+        assert_eq!(describe_changes(&changes), "kernel/a.c (+1 more)");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+//! Integration tests for `--dry-run` planning of the native pipeline: a
+//! one-file workspace planned with the host gcc, which must leave the
+//! output directory untouched (skipped when gcc is missing), and the same
+//! workspace planned with nothing on PATH.
+
+use kernel_builder::bootproto::BootProtocol;
+use kernel_builder::pipeline::{self, NativeOptions};
+use kernel_builder::profile::Profile;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn workspace() -> Option<PathBuf> {
+    which::which("gcc").ok()?;
+    Some(scratch_workspace("kb-dry-run"))
+}
+
+fn scratch_workspace(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("boot")).unwrap();
+    std::fs::create_dir_all(dir.join("kernel")).unwrap();
+    std::fs::write(dir.join("boot/entry.S"), ".globl _start\n_start: hlt\n").unwrap();
+    std::fs::write(dir.join("kernel/main.c"), "void kmain(void) {}\n").unwrap();
+    std::fs::write(dir.join("linker.ld"), "ENTRY(_start)\n").unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn plans_every_stage_without_running_any() {
+    let Some(ws) = workspace() else {
+        eprintln!("skipping: no host gcc");
+        return;
+    };
+    let output = ws.join("build");
+    let opts = NativeOptions {
+        workspace: ws.clone(),
+        arch: "x86_64".into(),
+        output: output.clone(),
+        cc: Some("gcc".into()),
+        boot_dir: "boot".into(),
+        linker_script: None,
+        ld: Some("ld".into()),
+        max_image_size: 1 << 24,
+        image: None,
+        cache: true,
+        cache_dir: None,
+        jobs: 1,
+        emit_compdb: true,
+        emit_listings: false,
+        boot_protocol: BootProtocol::None,
+        profile: Profile::Release,
+        reproducible: true,
+        sources: Default::default(),
+        dir_flags: Default::default(),
+        stack: None,
+    };
+    let plan = pipeline::plan(&opts).await.unwrap();
+
+    let what: Vec<&str> = plan.commands.iter().map(|c| c.what.as_str()).collect();
+    assert!(what[0].starts_with("assembling ") && what[0].ends_with("entry.S"));
+    assert!(what[1].starts_with("compiling ") && what[1].ends_with("main.c"));
+    let link = plan.commands.last().unwrap();
+    assert_eq!(link.program, "ld");
+    assert!(link
+        .args
+        .contains(&output.join("kernel.elf").display().to_string()));
+    assert!(plan
+        .commands
+        .iter()
+        .all(|c| c.env.contains_key("SOURCE_DATE_EPOCH")));
+    assert_eq!(plan.commands[1].cached, Some(false));
+    let cache = plan.cache.unwrap();
+    assert_eq!((cache.hits, cache.misses), (0, 2));
+    for name in ["compile_commands.json", "kernel.elf", "manifest.json"] {
+        assert!(plan.writes.contains(&output.join(name)), "{name}");
+    }
+    assert!(!output.exists(), "a dry run wrote to the output directory");
+    std::fs::remove_dir_all(&ws).unwrap();
+}
+
+#[test]
+fn plans_with_default_tool_names_when_none_are_on_path() {
+    let ws = scratch_workspace("kb-dry-run-no-path");
+    let output = ws.join("build");
+    let run = Command::new(env!("CARGO_BIN_EXE_kernel-builder"))
+        .arg("--workspace")
+        .arg(&ws)
+        .arg("--output")
+        .arg(&output)
+        .args(["--arch", "x86_64", "--driver", "native", "--dry-run"])
+        .env("PATH", "")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&run.stderr);
+    assert!(run.status.success(), "{stderr}");
+    let plan = String::from_utf8_lossy(&run.stdout);
+
+    let compile = plan
+        .lines()
+        .skip_while(|l| !l.starts_with("# compiling "))
+        .take(2)
+        .collect::<Vec<_>>();
+    assert!(compile[0].ends_with("main.c (unresolved)"), "{plan}");
+    assert!(compile[1].starts_with("x86_64-elf-gcc "), "{plan}");
+    let link = plan
+        .lines()
+        .position(|l| l.starts_with("# linking "))
+        .unwrap();
+    assert!(plan.lines().nth(link).unwrap().ends_with(" (unresolved)"));
+    assert!(plan
+        .lines()
+        .nth(link + 1)
+        .unwrap()
+        .starts_with("x86_64-elf-ld "));
+    assert!(!output.exists(), "a dry run wrote to the output directory");
+    std::fs::remove_dir_all(&ws).unwrap();
+}
@@ -0,0 +1,106 @@
+//! Integration tests for `mkfs` images. Where e2fsprogs or dosfstools are
+//! installed, their checkers must accept the images without changes.
+
+use kernel_builder::mkfs::{self, Filesystem, MkfsOptions};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn fixture(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mkfs-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("tree/etc/deep/er")).unwrap();
+    std::fs::write(dir.join("tree/hello.txt"), "hello\n").unwrap();
+    std::fs::write(dir.join("tree/etc/motd"), "welcome").unwrap();
+    std::fs::write(
+        dir.join("tree/etc/deep/er/Long File Name.dat"),
+        vec![7u8; 5000],
+    )
+    .unwrap();
+    let big: Vec<u8> = (0..400_000u32).map(|i| (i % 253) as u8).collect();
+    std::fs::write(dir.join("tree/big.bin"), big).unwrap();
+    dir
+}
+
+fn opts(fs: Filesystem) -> MkfsOptions {
+    MkfsOptions {
+        fs,
+        // FAT32 needs 65525 clusters; ext2 is checked faster small.
+        size: match fs {
+            Filesystem::Fat32 => 34 * 1024 * 1024,
+            Filesystem::Ext2 => 8 * 1024 * 1024,
+        },
+        label: Some("TESTDISK".into()),
+        epoch: 1_700_000_000,
+    }
+}
+
+/// Run `tool` on `image` if it is installed.
+fn check(tool: &str, args: &[&str], image: &Path) -> Option<std::process::Output> {
+    let program = which::which(tool).ok()?;
+    Some(
+        Command::new(program)
+            .args(args)
+            .arg(image)
+            .output()
+            .unwrap(),
+    )
+}
+
+#[test]
+fn ext2_images_pass_e2fsck() {
+    let dir = fixture("ext2");
+    let image = dir.join("disk.img");
+    let report = mkfs::build(&dir.join("tree"), &image, &opts(Filesystem::Ext2)).unwrap();
+    assert_eq!((report.files, report.directories), (4, 3));
+    if let Some(out) = check("e2fsck", &["-fn"], &image) {
+        assert!(
+            out.status.success(),
+            "e2fsck: {}{}",
+            String::from_utf8_lossy(&out.stdout),
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+    if let Some(out) = check("debugfs", &["-R", "cat /etc/motd"], &image) {
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "welcome");
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn fat32_images_pass_fsck() {
+    let dir = fixture("fat32");
+    let image = dir.join("disk.img");
+    mkfs::build(&dir.join("tree"), &image, &opts(Filesystem::Fat32)).unwrap();
+    if let Some(out) = check("fsck.fat", &["-n"], &image) {
+        assert!(
+            out.status.success(),
+            "fsck.fat: {}",
+            String::from_utf8_lossy(&out.stdout)
+        );
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn the_same_tree_gives_the_same_image() {
+    let dir = fixture("repeat");
+    let tree = mkfs::read_tree(&dir.join("tree")).unwrap();
+    for fs in [Filesystem::Fat32, Filesystem::Ext2] {
+        let image = mkfs::image(&tree, &opts(fs)).unwrap();
+        assert!(
+            mkfs::image(&tree, &opts(fs)).unwrap() == image,
+            "{}",
+            fs.name()
+        );
+        let later = MkfsOptions {
+            epoch: 1_800_000_000,
+            ..opts(fs)
+        };
+        assert!(
+            mkfs::image(&tree, &later).unwrap() != image,
+            "{}",
+            fs.name()
+        );
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,98 @@
+//! Integration tests for the pre-link check on objects and an archive
+//! built by the host gcc and ar. Skipped when either is missing.
+
+use kernel_builder::prelink::{self, Cause};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn run(dir: &Path, program: &str, args: &[&str]) -> bool {
+    let status = Command::new(program).args(args).current_dir(dir).status();
+    status.is_ok_and(|s| s.success())
+}
+
+#[test]
+fn reports_missing_sources_before_linking() {
+    if which::which("gcc").is_err() || which::which("ar").is_err() {
+        eprintln!("skipping: no host gcc/ar");
+        return;
+    }
+    let ws = std::env::temp_dir().join(format!("kb-prelink-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&ws);
+    let obj_dir = ws.join("build/obj");
+    std::fs::create_dir_all(ws.join("kernel/mm")).unwrap();
+    std::fs::create_dir_all(ws.join("kernel/include")).unwrap();
+    std::fs::create_dir_all(obj_dir.join("kernel")).unwrap();
+    std::fs::write(
+        ws.join("kernel/main.c"),
+        "#include \"include/vmm.h\"\nvoid *pmm_alloc(void);\nvoid rs_log(void);\n\
+         void kmain(void)\n{\n\tvmm_map(pmm_alloc());\n\trs_log();\n}\n",
+    )
+    .unwrap();
+    std::fs::write(
+        ws.join("kernel/include/vmm.h"),
+        "void vmm_map(void *page);\n",
+    )
+    .unwrap();
+    std::fs::write(
+        ws.join("kernel/mm/pmm.c"),
+        "void *pmm_alloc(void)\n{\n\treturn 0;\n}\n",
+    )
+    .unwrap();
+    // Stands in for the Rust staticlib: one member with a long name.
+    std::fs::write(ws.join("rust_logging_support.c"), "void rs_log(void) {}\n").unwrap();
+
+    let main_o = obj_dir.join("kernel/main.c.o");
+    let built = run(
+        &ws,
+        "gcc",
+        &["-c", "kernel/main.c", "-o", main_o.to_str().unwrap()],
+    ) && run(&ws, "gcc", &["-c", "rust_logging_support.c"])
+        && run(
+            &ws,
+            "ar",
+            &["rcs", "build/libkernel.a", "rust_logging_support.o"],
+        );
+    assert!(built);
+
+    let objects: Vec<PathBuf> = vec![main_o, ws.join("build/libkernel.a")];
+    let report = prelink::check(
+        &objects,
+        &obj_dir,
+        "SECTIONS { . = 1M; _end = .; }",
+        &ws,
+        &ws.join("build"),
+    )
+    .unwrap();
+    let groups: Vec<_> = report
+        .missing
+        .iter()
+        .map(|g| {
+            (
+                g.cause,
+                g.file.clone().unwrap_or_default(),
+                g.symbols[0].symbol.clone(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        groups,
+        [
+            (
+                Cause::NotBuilt,
+                "kernel/mm/pmm.c".to_string(),
+                "pmm_alloc".to_string()
+            ),
+            (
+                Cause::Unimplemented,
+                "kernel/include/vmm.h".to_string(),
+                "vmm_map".to_string()
+            ),
+        ]
+    );
+    assert_eq!(
+        report.missing[0].symbols[0].referenced_by,
+        ["kernel/main.c"]
+    );
+    assert!(report.circular.is_empty());
+    let _ = std::fs::remove_dir_all(&ws);
+}
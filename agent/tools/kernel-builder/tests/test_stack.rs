@@ -0,0 +1,87 @@
+//! Integration tests for stack usage analysis: a small C "kernel" built
+//! with the host gcc and `-fstack-usage`, disassembled by the host objdump.
+//! Skipped when either is missing.
+
+use kernel_builder::stack::{self, StackConfig};
+use std::path::PathBuf;
+use std::process::Command;
+
+const KERNEL: &str = r#"
+__attribute__((noinline)) int leaf(int x) { volatile char buf[200]; buf[0] = x; return buf[0]; }
+__attribute__((noinline)) int sched_tick(int x) { volatile char buf[100]; buf[1] = x; return leaf(x) + buf[1]; }
+__attribute__((noinline)) int walk(int n) { volatile int pad[4]; pad[0] = n; return n ? walk(n - 1) + pad[0] : 0; }
+void irq_timer(void) { sched_tick(1); }
+void kmain(void) { walk(3); leaf(2); }
+"#;
+
+/// Compile and link `KERNEL` in a fresh directory, returning the ELF and
+/// the `.su` file.
+fn build() -> Option<(PathBuf, PathBuf)> {
+    which::which("gcc").ok()?;
+    which::which("objdump").ok()?;
+    let dir = std::env::temp_dir().join(format!("kb-stack-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("kernel.c"), KERNEL).unwrap();
+    let object = dir.join("kernel.c.o");
+    let run = |args: &[&str]| {
+        let status = Command::new("gcc").args(args).current_dir(&dir).status();
+        status.is_ok_and(|s| s.success())
+    };
+    let compiled = run(&[
+        "-O1",
+        "-ffreestanding",
+        "-fno-pic",
+        "-fstack-usage",
+        "-c",
+        "kernel.c",
+        "-o",
+        "kernel.c.o",
+    ]);
+    let linked = compiled
+        && run(&[
+            "-nostdlib",
+            "-static",
+            "-no-pie",
+            "-Wl,-e,kmain",
+            "kernel.c.o",
+            "-o",
+            "kernel.elf",
+        ]);
+    linked.then(|| (dir.join("kernel.elf"), object.with_extension("su")))
+}
+
+#[tokio::test]
+async fn interrupt_paths_are_checked_against_the_budget() {
+    let Some((elf, su)) = build() else {
+        eprintln!("skipping: no host gcc/objdump");
+        return;
+    };
+    let mut config = StackConfig {
+        budget: Some(128),
+        ..Default::default()
+    };
+    let report = stack::run("objdump", &elf, [su.clone()], &config)
+        .await
+        .unwrap();
+
+    let irq = report
+        .entries
+        .iter()
+        .find(|e| e.name == "irq_timer")
+        .unwrap();
+    assert!(irq.interrupt && irq.bounded && irq.complete);
+    assert_eq!(irq.path, ["irq_timer", "sched_tick", "leaf"]);
+    let frames = stack::read_frames([su.clone()]).unwrap();
+    let sum: u64 = irq.path.iter().map(|f| frames[f].bytes).sum();
+    assert_eq!(irq.bytes, sum);
+    let kmain = report.entries.iter().find(|e| e.name == "kmain").unwrap();
+    assert!(!kmain.interrupt && !kmain.bounded);
+    assert_eq!(report.over_budget().len(), 1);
+    assert!(report.check().is_err());
+
+    config.budget = Some(irq.bytes);
+    let report = stack::run("objdump", &elf, [su], &config).await.unwrap();
+    assert!(report.check().is_ok());
+    let _ = std::fs::remove_dir_all(elf.parent().unwrap());
+}
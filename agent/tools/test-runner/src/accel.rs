@@ -0,0 +1,131 @@
+//! Hardware acceleration (`--accel`).
+//!
+//! By default a guest of the host's architecture runs under KVM when
+//! `/dev/kvm` is usable (it opens read-write and answers
+//! `KVM_GET_API_VERSION`), and under TCG otherwise, with a warning. Spec
+//! timeouts are written for KVM, so a TCG run of a guest KVM could have
+//! run multiplies its timeout and idle timeout by `tcg-timeout-factor`;
+//! a cross-architecture guest is always TCG and keeps its timeouts.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Timeout multiplier for TCG runs without `--tcg-timeout-factor`.
+pub const DEFAULT_TCG_TIMEOUT_FACTOR: u32 = 4;
+
+const KVM_DEVICE: &str = "/dev/kvm";
+/// `_IO(KVMIO, 0x00)`.
+const KVM_GET_API_VERSION: libc::c_ulong = 0xae00;
+/// The only API version KVM has ever reported.
+const KVM_API_VERSION: libc::c_int = 12;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Accel {
+    /// KVM when usable for the guest's architecture, else TCG.
+    #[default]
+    Auto,
+    Kvm,
+    Tcg,
+}
+
+impl Accel {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Kvm => "kvm",
+            Self::Tcg => "tcg",
+        }
+    }
+
+    /// Replace `Auto` with KVM or TCG for a guest of `arch`.
+    pub fn resolve(self, arch: &str) -> Self {
+        match self {
+            Self::Auto if host_arch(arch) && kvm_usable() => Self::Kvm,
+            Self::Auto => Self::Tcg,
+            a => a,
+        }
+    }
+
+    /// QEMU flags selecting the resolved accelerator (TCG is QEMU's
+    /// default); left unresolved, QEMU tries KVM and falls back to TCG.
+    pub fn qemu_args(self) -> Vec<String> {
+        match self {
+            Self::Kvm => vec!["-enable-kvm".to_string()],
+            Self::Auto => ["-accel", "kvm", "-accel", "tcg"]
+                .map(String::from)
+                .to_vec(),
+            Self::Tcg => Vec::new(),
+        }
+    }
+
+    /// `timeout` for a guest of `arch` under this (resolved) accelerator:
+    /// scaled by `factor` when TCG stands in for KVM.
+    pub fn scale_timeout(self, arch: &str, timeout: Duration, factor: u32) -> Duration {
+        if self == Self::Tcg && host_arch(arch) {
+            timeout.saturating_mul(factor.max(1))
+        } else {
+            timeout
+        }
+    }
+}
+
+/// Whether a guest of `arch` could run under KVM on this host.
+fn host_arch(arch: &str) -> bool {
+    arch == std::env::consts::ARCH
+}
+
+/// Probes `/dev/kvm` once per process, warning if it is unusable.
+fn kvm_usable() -> bool {
+    static USABLE: OnceLock<bool> = OnceLock::new();
+    *USABLE.get_or_init(|| match probe_kvm() {
+        Ok(()) => true,
+        Err(why) => {
+            tracing::warn!("KVM unavailable ({why}); falling back to TCG with longer timeouts");
+            false
+        }
+    })
+}
+
+/// Whether `/dev/kvm` is usable, and why not if it is not.
+pub fn probe_kvm() -> Result<(), String> {
+    use std::os::fd::AsRawFd;
+    let dev = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(KVM_DEVICE)
+        .map_err(|e| format!("{KVM_DEVICE}: {e}"))?;
+    // SAFETY: KVM_GET_API_VERSION only returns a value; KVM rejects any
+    // argument but 0.
+    let version = unsafe { libc::ioctl(dev.as_raw_fd(), KVM_GET_API_VERSION as _, 0) };
+    match version {
+        KVM_API_VERSION => Ok(()),
+        -1 => Err(format!("{KVM_DEVICE}: {}", std::io::Error::last_os_error())),
+        v => Err(format!("{KVM_DEVICE}: unsupported API version {v}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcg_scales_only_guests_kvm_could_run() {
+        let host = std::env::consts::ARCH;
+        let other = if host == "riscv64" {
+            "aarch64"
+        } else {
+            "riscv64"
+        };
+        let t = Duration::from_secs(30);
+        assert_eq!(Accel::Tcg.scale_timeout(host, t, 4), t * 4);
+        assert_eq!(Accel::Tcg.scale_timeout(other, t, 4), t);
+        assert_eq!(Accel::Kvm.scale_timeout(host, t, 4), t);
+        assert_eq!(Accel::Auto.resolve(other), Accel::Tcg);
+        assert_eq!(Accel::Kvm.resolve(other), Accel::Kvm);
+        assert_eq!(Accel::Kvm.qemu_args(), ["-enable-kvm"]);
+        assert!(Accel::Tcg.qemu_args().is_empty());
+    }
+}
@@ -0,0 +1,129 @@
+//! Host-level admission control for suites.
+//!
+//! `--parallel` caps how many VMs run at once, but sixteen 1 GiB guests
+//! can still OOM a CI runner. Each test is therefore also admitted against
+//! a memory budget (its `memory` plus [`QEMU_OVERHEAD_MB`]; default: 80% of
+//! the host's `MemAvailable`) and a CPU budget (its `smp`; default: the
+//! host's CPUs), and waits until both have room. A VM larger than a whole
+//! budget is admitted alone rather than never.
+
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Host memory QEMU itself uses per VM beyond guest RAM, in MiB.
+pub const QEMU_OVERHEAD_MB: u32 = 64;
+
+/// Share of available host memory the default budget hands out.
+const DEFAULT_MEMORY_SHARE: f64 = 0.8;
+
+/// `MemAvailable` from `/proc/meminfo`, in MiB.
+pub fn host_memory_mb() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    parse_mem_available(&meminfo)
+}
+
+fn parse_mem_available(meminfo: &str) -> Option<u64> {
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib / 1024)
+}
+
+/// A resource pool, in units of MiB or CPUs.
+#[derive(Debug, Clone)]
+struct Budget {
+    pool: Arc<Semaphore>,
+    total: u32,
+}
+
+impl Budget {
+    fn new(total: u32) -> Self {
+        let total = total.clamp(1, Semaphore::MAX_PERMITS as u32);
+        Self {
+            pool: Arc::new(Semaphore::new(total as usize)),
+            total,
+        }
+    }
+
+    async fn take(&self, want: u32) -> OwnedSemaphorePermit {
+        self.pool
+            .clone()
+            .acquire_many_owned(want.clamp(1, self.total))
+            .await
+            .expect("budget semaphore open")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Admission {
+    memory: Budget,
+    cpus: Budget,
+}
+
+/// Resources held by a running test; released on drop.
+pub struct Ticket {
+    _memory: OwnedSemaphorePermit,
+    _cpus: OwnedSemaphorePermit,
+}
+
+impl Admission {
+    /// Budgets of `memory_mb` and `cpus`, defaulting to the host's.
+    pub fn new(memory_mb: Option<u64>, cpus: Option<u32>) -> Self {
+        let memory_mb = memory_mb
+            .or_else(|| host_memory_mb().map(|m| (m as f64 * DEFAULT_MEMORY_SHARE) as u64))
+            .unwrap_or(u64::from(u32::MAX));
+        let cpus = cpus
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get() as u32));
+        tracing::debug!(memory_mb, cpus, "admission budget");
+        Self {
+            memory: Budget::new(memory_mb.min(u64::from(u32::MAX)) as u32),
+            cpus: Budget::new(cpus),
+        }
+    }
+
+    /// Wait for room for a VM with `memory_mb` of RAM and `cpus` vCPUs.
+    pub async fn admit(&self, memory_mb: u32, cpus: u32) -> Ticket {
+        let need = memory_mb.saturating_add(QEMU_OVERHEAD_MB);
+        if need > self.memory.total || cpus > self.cpus.total {
+            tracing::warn!(
+                memory_mb = need,
+                cpus,
+                "VM exceeds the host budget; running it alone"
+            );
+        }
+        // Always memory first, so concurrent admissions cannot deadlock.
+        let memory = self.memory.take(need).await;
+        let cpus = self.cpus.take(cpus).await;
+        Ticket {
+            _memory: memory,
+            _cpus: cpus,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn reads_available_memory() {
+        let meminfo = "MemTotal:       16303284 kB\nMemAvailable:    8151642 kB\n";
+        assert_eq!(parse_mem_available(meminfo), Some(7960));
+        assert_eq!(parse_mem_available("MemTotal: 1 kB\n"), None);
+    }
+
+    #[tokio::test]
+    async fn waits_for_memory_and_admits_oversized_vms_alone() {
+        let admission = Admission::new(Some(1024), Some(8));
+        let first = admission.admit(512, 1).await;
+        // 576 + 576 > 1024: the second VM waits for the first.
+        let second = tokio::time::timeout(Duration::from_millis(50), admission.admit(512, 1));
+        assert!(second.await.is_err());
+        drop(first);
+        let second = admission.admit(512, 1).await;
+        drop(second);
+        // Bigger than the whole budget, yet admitted once the pool is free.
+        let huge = tokio::time::timeout(Duration::from_secs(1), admission.admit(4096, 16));
+        assert!(huge.await.is_ok());
+    }
+}
@@ -0,0 +1,230 @@
+//! Boot-time benchmarking (`test-runner bench`).
+//!
+//! The kernel is booted `runs` times, one VM at a time so boots do not
+//! compete for the host, and each boot is timed from QEMU's start to the
+//! serial line matching the marker. The samples' mean, median and p95 are
+//! compared with a stored baseline: a median more than `threshold` percent
+//! above the baseline's is a regression. Baselines record the accelerator,
+//! since a TCG boot is not comparable with a KVM one.
+
+use crate::accel::Accel;
+use crate::machine::Machine;
+use crate::qemu::{self, ExitReason};
+use crate::spec::TestSpec;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Baseline file used without `--baseline`.
+pub const DEFAULT_BASELINE: &str = "build/boot-baseline.json";
+
+/// Boots measured without `--runs`.
+pub const DEFAULT_RUNS: u32 = 10;
+
+/// Percent over the baseline median that counts as a regression.
+pub const DEFAULT_THRESHOLD_PCT: u32 = 50;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    pub runs: usize,
+    pub mean_ms: f64,
+    pub median_ms: f64,
+    /// Nearest-rank 95th percentile.
+    pub p95_ms: u64,
+    pub min_ms: u64,
+    pub max_ms: u64,
+}
+
+impl Stats {
+    /// `None` without samples.
+    pub fn from_samples(samples: &[u64]) -> Option<Self> {
+        let mut sorted = samples.to_vec();
+        sorted.sort_unstable();
+        let n = sorted.len();
+        if n == 0 {
+            return None;
+        }
+        let median_ms = if n % 2 == 1 {
+            sorted[n / 2] as f64
+        } else {
+            (sorted[n / 2 - 1] + sorted[n / 2]) as f64 / 2.0
+        };
+        Some(Self {
+            runs: n,
+            mean_ms: sorted.iter().sum::<u64>() as f64 / n as f64,
+            median_ms,
+            p95_ms: sorted[(n * 95).div_ceil(100) - 1],
+            min_ms: sorted[0],
+            max_ms: sorted[n - 1],
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "mean {:.1}ms, median {:.1}ms, p95 {}ms (min {}ms, max {}ms)",
+            self.mean_ms, self.median_ms, self.p95_ms, self.min_ms, self.max_ms
+        )
+    }
+}
+
+/// A stored benchmark result.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Baseline {
+    pub marker: String,
+    pub accel: Accel,
+    pub stats: Stats,
+    pub samples_ms: Vec<u64>,
+    /// Unix time, seconds.
+    pub recorded_at: u64,
+}
+
+impl Baseline {
+    pub fn new(marker: &str, accel: Accel, samples_ms: Vec<u64>) -> Result<Self> {
+        let stats = Stats::from_samples(&samples_ms).context("no boots measured")?;
+        let recorded_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Ok(Self {
+            marker: marker.to_string(),
+            accel,
+            stats,
+            samples_ms,
+            recorded_at,
+        })
+    }
+
+    /// The baseline at `path`, or `None` if none was saved yet.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map(Some)
+                .with_context(|| format!("parsing {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Comparison {
+    pub baseline_median_ms: f64,
+    pub median_ms: f64,
+    /// Current median over the baseline's.
+    pub ratio: f64,
+    pub threshold_pct: u32,
+    pub regressed: bool,
+}
+
+impl Comparison {
+    pub fn new(baseline: &Stats, current: &Stats, threshold_pct: u32) -> Self {
+        let ratio = current.median_ms / baseline.median_ms.max(1.0);
+        Self {
+            baseline_median_ms: baseline.median_ms,
+            median_ms: current.median_ms,
+            ratio,
+            threshold_pct,
+            regressed: ratio > 1.0 + f64::from(threshold_pct) / 100.0,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let verdict = if self.regressed { "over" } else { "within" };
+        format!(
+            "median {:.1}ms vs baseline {:.1}ms: {:.2}x ({verdict} the {}% threshold)",
+            self.median_ms, self.baseline_median_ms, self.ratio, self.threshold_pct
+        )
+    }
+}
+
+/// Milliseconds from QEMU's start to `marker` for each of `runs` boots of
+/// `kernel` under `spec`, after `warmup` unmeasured boots; and the
+/// accelerator they ran under.
+pub async fn measure(
+    spec: &TestSpec,
+    kernel: &Path,
+    marker: &str,
+    runs: u32,
+    warmup: u32,
+    transcript_limit: usize,
+) -> Result<(Vec<u64>, Accel)> {
+    let boot = TestSpec {
+        expect: vec![marker.to_string()],
+        expect_any: Vec::new(),
+        step: Vec::new(),
+        wait_exit: None,
+        gdb: None,
+        gdb_script: None,
+        snapshot_at: None,
+        coverage: None,
+        ..spec.clone()
+    };
+    let arch = Machine::resolve(&boot, kernel)?.arch;
+    let accel = boot.resolve_accel(&arch)?;
+    let mut samples = Vec::new();
+    for i in 0..warmup + runs {
+        let cfg = boot.run_config(kernel, transcript_limit)?;
+        let result = qemu::run(&cfg).await?;
+        let Some(m) = result
+            .matched
+            .first()
+            .filter(|_| result.reason == ExitReason::PatternMatched)
+        else {
+            bail!(
+                "boot {} never reached `{marker}`:\n{}",
+                i + 1,
+                result.explain().join("\n")
+            );
+        };
+        if i < warmup {
+            tracing::info!(boot = i + 1, ms = m.elapsed_ms, "warm-up boot");
+        } else {
+            tracing::info!(boot = i + 1 - warmup, ms = m.elapsed_ms, "boot");
+            samples.push(m.elapsed_ms);
+        }
+    }
+    Ok((samples, accel))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_and_regressions() {
+        let s = Stats::from_samples(&[120, 100, 110, 400, 105]).unwrap();
+        assert_eq!(s.median_ms, 110.0);
+        assert_eq!(s.mean_ms, 167.0);
+        assert_eq!((s.p95_ms, s.min_ms, s.max_ms), (400, 100, 400));
+        let even = Stats::from_samples(&[100, 200]).unwrap();
+        assert_eq!(even.median_ms, 150.0);
+        assert!(Stats::from_samples(&[]).is_none());
+
+        let slow = Stats::from_samples(&[220, 220, 230]).unwrap();
+        let c = Comparison::new(&s, &slow, 50);
+        assert!(c.regressed);
+        assert_eq!(
+            c.describe(),
+            "median 220.0ms vs baseline 110.0ms: 2.00x (over the 50% threshold)"
+        );
+        assert!(!Comparison::new(&s, &slow, 150).regressed);
+    }
+
+    #[test]
+    fn baseline_round_trips() {
+        let path = crate::scratch_path("json");
+        assert_eq!(Baseline::load(&path).unwrap(), None);
+        let b = Baseline::new(r"\[BOOT\] OK", Accel::Kvm, vec![10, 12, 11]).unwrap();
+        b.save(&path).unwrap();
+        assert_eq!(Baseline::load(&path).unwrap(), Some(b));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+//! Bounded serial capture.
+//!
+//! A kernel stuck printing in a loop can produce hundreds of megabytes before
+//! the timeout fires, so the transcript is a byte ring: once `capacity` is
+//! reached the oldest bytes are dropped and counted. Pattern matching does
+//! not depend on the ring; it runs on each complete line as it arrives, and
+//! a line with no end in sight is cut every [`MAX_LINE`] bytes.
+
+use std::collections::VecDeque;
+
+/// Default transcript cap (8 MiB).
+pub const DEFAULT_CAPACITY: usize = 8 * 1024 * 1024;
+
+/// Longest line [`LineSplitter`] holds back (64 KiB); a longer one comes
+/// out in pieces of at most this many bytes.
+pub const MAX_LINE: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct SerialRing {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    dropped: u64,
+}
+
+impl SerialRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buf: VecDeque::with_capacity(capacity.min(64 * 1024)),
+            capacity: capacity.max(1),
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        let bytes = if bytes.len() > self.capacity {
+            let skip = bytes.len() - self.capacity;
+            self.dropped += skip as u64;
+            &bytes[skip..]
+        } else {
+            bytes
+        };
+        let overflow = (self.buf.len() + bytes.len()).saturating_sub(self.capacity);
+        if overflow > 0 {
+            self.buf.drain(..overflow);
+            self.dropped += overflow as u64;
+        }
+        self.buf.extend(bytes);
+    }
+
+    /// Bytes discarded from the front so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// The retained transcript (lossy UTF-8).
+    pub fn contents(&self) -> String {
+        let (a, b) = self.buf.as_slices();
+        let mut bytes = Vec::with_capacity(a.len() + b.len());
+        bytes.extend_from_slice(a);
+        bytes.extend_from_slice(b);
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+/// Splits a byte stream into lines, holding back an unterminated tail.
+#[derive(Debug, Default)]
+pub struct LineSplitter {
+    pending: Vec<u8>,
+}
+
+impl LineSplitter {
+    /// Complete lines in `bytes` (without `\n`/`\r\n`).
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for &b in bytes {
+            if b == b'\n' {
+                lines.push(Self::line(&std::mem::take(&mut self.pending)));
+                continue;
+            }
+            self.pending.push(b);
+            if self.pending.len() >= MAX_LINE {
+                // Cut before a char the piece would split, if any.
+                let cut = match std::str::from_utf8(&self.pending) {
+                    Err(e) if e.error_len().is_none() && e.valid_up_to() > 0 => e.valid_up_to(),
+                    _ => self.pending.len(),
+                };
+                let rest = self.pending.split_off(cut);
+                lines.push(Self::line(&std::mem::replace(&mut self.pending, rest)));
+            }
+        }
+        lines
+    }
+
+    /// The unterminated tail so far, without consuming it.
+    pub fn partial(&self) -> Option<String> {
+        (!self.pending.is_empty()).then(|| Self::line(&self.pending))
+    }
+
+    /// The unterminated tail, if any (at EOF).
+    pub fn finish(&mut self) -> Option<String> {
+        (!self.pending.is_empty()).then(|| Self::line(&std::mem::take(&mut self.pending)))
+    }
+
+    fn line(bytes: &[u8]) -> String {
+        let bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_drops_oldest_bytes() {
+        let mut ring = SerialRing::new(8);
+        ring.push(b"abcdef");
+        ring.push(b"ghij");
+        assert_eq!(ring.contents(), "cdefghij");
+        assert_eq!(ring.dropped(), 2);
+        ring.push(b"0123456789");
+        assert_eq!(ring.contents(), "23456789");
+        assert_eq!(ring.dropped(), 12);
+    }
+
+    #[test]
+    fn splitter_holds_partial_lines() {
+        let mut s = LineSplitter::default();
+        assert_eq!(s.push(b"[BOOT] Lo"), Vec::<String>::new());
+        assert_eq!(s.push(b"ng mode\r\n[DRV"), ["[BOOT] Long mode"]);
+        assert_eq!(s.partial().as_deref(), Some("[DRV"));
+        assert_eq!(s.finish().as_deref(), Some("[DRV"));
+        assert_eq!(s.finish(), None);
+    }
+
+    #[test]
+    fn splitter_cuts_endless_lines() {
+        let mut s = LineSplitter::default();
+        let lines = s.push(&vec![b'a'; 2 * MAX_LINE + 5]);
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|l| l.len() == MAX_LINE));
+        assert_eq!(s.finish().map(|l| l.len()), Some(5));
+
+        // A char straddling the cap stays whole in the next piece.
+        let mut bytes = vec![b'a'; MAX_LINE - 1];
+        bytes.extend("é\n".bytes());
+        let lines = s.push(&bytes);
+        assert_eq!(lines, ["a".repeat(MAX_LINE - 1), "é".to_string()]);
+    }
+}
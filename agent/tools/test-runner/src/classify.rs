@@ -0,0 +1,509 @@
+//! Failure classification: turn a failed run's output into a structured
+//! cause.
+//!
+//! Three sources are recognized, most specific first:
+//!
+//! * a QEMU `-d int,cpu_reset` log (`--cpu-log`) ending in `Triple fault`:
+//!   the fault that started the cascade is the last exception raised with
+//!   nothing pending (`check_exception old: 0xffffffff new 0x..`), and its
+//!   interrupt entry carries the vector, error code, RIP, CR2 and a full
+//!   register dump;
+//! * a CPU exception dump on serial (`unhandled exception`, `page fault`,
+//!   `#GP`, ...), with `NAME=value` / `NAME: value` registers on the
+//!   following lines;
+//! * a panic banner (`PANIC`, `panic:`), whose text becomes the message.
+//!
+//! Fields found in several sources are merged, so a kernel that prints
+//! `panic: ...` after an exception dump yields one cause with both.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Serial lines after an exception banner searched for registers.
+const DUMP_LINES: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureKind {
+    Panic,
+    CpuException,
+    TripleFault,
+}
+
+/// Where the fields of a [`Failure`] were read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    Serial,
+    QemuLog,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Exception {
+    pub vector: u8,
+    /// `#PF`, `#GP`, ...
+    pub mnemonic: &'static str,
+    pub name: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub source: Source,
+    /// 1-based line in the source of the banner or fault entry.
+    pub line_no: usize,
+    /// Panic message, or the exception banner line.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// The (first) CPU exception.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exception: Option<Exception>,
+    /// Faulting instruction pointer (RIP; PC/ELR/SEPC on other arches).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rip: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<u64>,
+    /// Page-fault address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cr2: Option<u64>,
+    pub registers: BTreeMap<String, u64>,
+}
+
+impl Failure {
+    fn new(kind: FailureKind, source: Source, line_no: usize) -> Self {
+        Self {
+            kind,
+            source,
+            line_no,
+            message: None,
+            exception: None,
+            rip: None,
+            error_code: None,
+            cr2: None,
+            registers: BTreeMap::new(),
+        }
+    }
+
+    /// One line, e.g. `triple fault after #PF (page fault) at
+    /// RIP=0xffffffff80100123 err=0x2 CR2=0x0`.
+    pub fn describe(&self) -> String {
+        let mut out = match self.kind {
+            FailureKind::Panic => "kernel panic".to_string(),
+            FailureKind::CpuException => "CPU exception".to_string(),
+            FailureKind::TripleFault => "triple fault".to_string(),
+        };
+        if let Some(e) = &self.exception {
+            if self.kind == FailureKind::TripleFault {
+                out.push_str(" after");
+            }
+            out.push_str(&format!(" {} ({})", e.mnemonic, e.name));
+        }
+        if let Some(rip) = self.rip {
+            out.push_str(&format!(" at RIP={rip:#x}"));
+        }
+        if let Some(err) = self.error_code {
+            out.push_str(&format!(" err={err:#x}"));
+        }
+        if let Some(cr2) = self.cr2 {
+            out.push_str(&format!(" CR2={cr2:#x}"));
+        }
+        if let Some(msg) = &self.message {
+            out.push_str(&format!(": {msg}"));
+        }
+        out
+    }
+
+    /// Fill fields this one lacks from `other`.
+    fn absorb(&mut self, other: Failure) {
+        self.message = self.message.take().or(other.message);
+        self.exception = self.exception.take().or(other.exception);
+        self.rip = self.rip.or(other.rip);
+        self.error_code = self.error_code.or(other.error_code);
+        self.cr2 = self.cr2.or(other.cr2);
+        for (k, v) in other.registers {
+            self.registers.entry(k).or_insert(v);
+        }
+    }
+}
+
+/// Classify `serial` and, when captured, the QEMU `-d int,cpu_reset` log.
+pub fn classify(serial: &str, qemu_log: Option<&str>) -> Option<Failure> {
+    let panic = panic_banner(serial);
+    let found = [
+        qemu_log.and_then(triple_fault),
+        exception_dump(serial),
+        panic.clone(),
+    ];
+    let mut found = found.into_iter().flatten();
+    let mut failure = found.next()?;
+    for other in found {
+        failure.absorb(other);
+    }
+    // The kernel's own words beat an exception banner.
+    if let Some(msg) = panic.and_then(|p| p.message) {
+        failure.message = Some(msg);
+    }
+    Some(failure)
+}
+
+fn triple_fault(log: &str) -> Option<Failure> {
+    let lines: Vec<&str> = log.lines().collect();
+    let tf = lines.iter().rposition(|l| l.trim() == "Triple fault")?;
+    let mut failure = Failure::new(FailureKind::TripleFault, Source::QemuLog, tf + 1);
+    // The cascade starts at the last exception raised with none pending.
+    let Some(start) = lines[..tf]
+        .iter()
+        .rposition(|l| l.starts_with("check_exception old: 0xffffffff"))
+    else {
+        return Some(failure);
+    };
+    let Some(entry) = (start + 1..tf).find(|&i| interrupt_entry(lines[i]).is_some()) else {
+        return Some(failure);
+    };
+    let (vector, fields) = interrupt_entry(lines[entry]).expect("checked above");
+    failure.line_no = entry + 1;
+    failure.exception = exception(vector);
+    failure.error_code = fields.get("e").copied();
+    failure.rip = fields.get("pc").copied();
+    failure.cr2 = fields.get("CR2").copied();
+    // The register dump follows until the next entry or check_exception.
+    for line in &lines[entry + 1..tf] {
+        if line.starts_with("check_exception") || interrupt_entry(line).is_some() {
+            break;
+        }
+        registers_into(line, &mut failure.registers);
+    }
+    failure.rip = failure.rip.or(failure.registers.get("RIP").copied());
+    Some(failure)
+}
+
+/// `     0: v=0e e=0002 i=0 cpl=0 IP=0008:ffffffff80100123 pc=... CR2=...`
+pub(crate) fn interrupt_entry(line: &str) -> Option<(u8, BTreeMap<String, u64>)> {
+    let (count, rest) = line.trim_start().split_once(": v=")?;
+    count.parse::<u64>().ok()?;
+    let mut fields = BTreeMap::new();
+    for token in format!("v={rest}").split_whitespace() {
+        let Some((k, v)) = token.split_once('=') else {
+            continue;
+        };
+        // `IP=0008:ffff...` is selector:offset.
+        let v = v.rsplit(':').next().unwrap_or(v);
+        if let Some(v) = parse_hex(v) {
+            fields.insert(k.to_string(), v);
+        }
+    }
+    let vector = u8::try_from(*fields.get("v")?).ok()?;
+    Some((vector, fields))
+}
+
+fn exception_dump(serial: &str) -> Option<Failure> {
+    let lines: Vec<&str> = serial.lines().collect();
+    let (i, vector) = lines
+        .iter()
+        .enumerate()
+        .find_map(|(i, l)| exception_banner(l).map(|v| (i, v)))?;
+    let mut failure = Failure::new(FailureKind::CpuException, Source::Serial, i + 1);
+    failure.message = Some(lines[i].trim().to_string());
+    let mut vector = vector;
+    for line in lines.iter().skip(i).take(DUMP_LINES) {
+        let mut regs = BTreeMap::new();
+        registers_into(line, &mut regs);
+        for (k, v) in regs {
+            match k.as_str() {
+                "ERR" | "ERROR" | "ERRCODE" | "ERROR_CODE" => {
+                    failure.error_code.get_or_insert(v);
+                }
+                "VECTOR" | "VEC" | "INT" => {
+                    vector = vector.or(u8::try_from(v).ok());
+                }
+                _ => {
+                    failure.registers.entry(k).or_insert(v);
+                }
+            }
+        }
+    }
+    failure.exception = vector.and_then(exception);
+    failure.cr2 = failure.registers.get("CR2").copied();
+    failure.rip = ["RIP", "EIP", "PC", "ELR", "ELR_EL1", "SEPC", "MEPC"]
+        .iter()
+        .find_map(|r| failure.registers.get(*r).copied());
+    Some(failure)
+}
+
+/// `Some(vector)` when the line announces a CPU exception; the inner
+/// `None` means the banner did not say which.
+fn exception_banner(line: &str) -> Option<Option<u8>> {
+    let lower = line.to_lowercase();
+    if lower.contains("unhandled exception") || lower.contains("cpu exception") {
+        return Some(
+            EXCEPTIONS
+                .iter()
+                .position(|e| mentions(line, &lower, e))
+                .map(|v| v as u8),
+        );
+    }
+    // Without the keyword only unambiguous names count, so that e.g.
+    // `[TEST] overflow: PASS` is not a #OF.
+    BANNER_VECTORS
+        .iter()
+        .find(|&&v| mentions(line, &lower, &EXCEPTIONS[v as usize]))
+        .map(|&v| Some(v))
+}
+
+fn mentions(line: &str, lower: &str, (mnemonic, name): &(&str, &str)) -> bool {
+    (!mnemonic.is_empty() && line.contains(mnemonic)) || (!name.is_empty() && lower.contains(name))
+}
+
+fn panic_banner(serial: &str) -> Option<Failure> {
+    let (i, text) = serial
+        .lines()
+        .enumerate()
+        .find_map(|(i, l)| panic_text(l).map(|t| (i, t)))?;
+    let mut failure = Failure::new(FailureKind::Panic, Source::Serial, i + 1);
+    failure.message = Some(text);
+    Some(failure)
+}
+
+/// The message of a panic banner line.
+fn panic_text(line: &str) -> Option<String> {
+    let idx = line.find("PANIC").or_else(|| line.find("panic:"))?;
+    let rest = &line[idx..];
+    let rest = rest
+        .split_once(':')
+        .map_or(rest.trim_start_matches("PANIC"), |(_, m)| m);
+    let rest = rest.trim();
+    Some(if rest.is_empty() { line.trim() } else { rest }.to_string())
+}
+
+/// Collect `NAME=hex` and `NAME: hex` register values, upper-casing names
+/// (`R8 =...` as QEMU pads it is accepted too).
+fn registers_into(line: &str, regs: &mut BTreeMap<String, u64>) {
+    let line = line.replace(" =", "=");
+    let tokens: Vec<&str> = line
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|t| !t.is_empty())
+        .collect();
+    let mut i = 0;
+    while i < tokens.len() {
+        let (name, value) = match tokens[i].split_once('=') {
+            Some((n, v)) => (n, v),
+            None if tokens[i].ends_with(':') && i + 1 < tokens.len() => {
+                i += 1;
+                (tokens[i - 1].trim_end_matches(':'), tokens[i])
+            }
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        i += 1;
+        let name = name.to_uppercase();
+        if !is_register(&name) {
+            continue;
+        }
+        if let Some(v) = parse_hex(value) {
+            regs.insert(name, v);
+        }
+    }
+}
+
+fn is_register(name: &str) -> bool {
+    const NAMES: &[&str] = &[
+        "RAX",
+        "RBX",
+        "RCX",
+        "RDX",
+        "RSI",
+        "RDI",
+        "RBP",
+        "RSP",
+        "RIP",
+        "RFL",
+        "RFLAGS",
+        "EAX",
+        "EBX",
+        "ECX",
+        "EDX",
+        "ESI",
+        "EDI",
+        "EBP",
+        "ESP",
+        "EIP",
+        "EFL",
+        "EFLAGS",
+        "CR0",
+        "CR2",
+        "CR3",
+        "CR4",
+        "EFER",
+        "ERR",
+        "ERROR",
+        "ERRCODE",
+        "ERROR_CODE",
+        "VECTOR",
+        "VEC",
+        "INT",
+        "PC",
+        "SP",
+        "LR",
+        "ELR",
+        "ELR_EL1",
+        "ESR",
+        "ESR_EL1",
+        "FAR",
+        "FAR_EL1",
+        "SPSR",
+        "SEPC",
+        "SCAUSE",
+        "STVAL",
+        "MEPC",
+        "MCAUSE",
+        "MTVAL",
+    ];
+    NAMES.contains(&name)
+        || name
+            .strip_prefix('R')
+            .and_then(|n| n.parse::<u8>().ok())
+            .is_some_and(|n| (8..=15).contains(&n))
+}
+
+/// `0x1f`, `1f` or `0000001f`.
+fn parse_hex(s: &str) -> Option<u64> {
+    let s = s.trim_end_matches([',', ')', ']']);
+    let s = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    u64::from_str_radix(s, 16).ok()
+}
+
+pub(crate) fn exception(vector: u8) -> Option<Exception> {
+    let (mnemonic, name) = *EXCEPTIONS.get(vector as usize)?;
+    (!mnemonic.is_empty()).then_some(Exception {
+        vector,
+        mnemonic,
+        name,
+    })
+}
+
+/// Vectors recognized by name or mnemonic alone.
+const BANNER_VECTORS: &[u8] = &[0, 6, 8, 10, 11, 12, 13, 14, 17, 18];
+
+/// x86 exception vectors 0-31 (mnemonic, lower-case name); reserved ones
+/// are empty.
+const EXCEPTIONS: [(&str, &str); 32] = [
+    ("#DE", "divide error"),
+    ("#DB", "debug exception"),
+    ("NMI", "non-maskable interrupt"),
+    ("#BP", "breakpoint"),
+    ("#OF", "overflow"),
+    ("#BR", "bound range exceeded"),
+    ("#UD", "invalid opcode"),
+    ("#NM", "device not available"),
+    ("#DF", "double fault"),
+    ("", ""),
+    ("#TS", "invalid tss"),
+    ("#NP", "segment not present"),
+    ("#SS", "stack-segment fault"),
+    ("#GP", "general protection"),
+    ("#PF", "page fault"),
+    ("", ""),
+    ("#MF", "x87 floating-point exception"),
+    ("#AC", "alignment check"),
+    ("#MC", "machine check"),
+    ("#XM", "simd floating-point exception"),
+    ("#VE", "virtualization exception"),
+    ("#CP", "control protection"),
+    ("", ""),
+    ("", ""),
+    ("", ""),
+    ("", ""),
+    ("", ""),
+    ("", ""),
+    ("#HV", "hypervisor injection"),
+    ("#VC", "vmm communication"),
+    ("#SX", "security exception"),
+    ("", ""),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRIPLE_FAULT_LOG: &str = "\
+CPU Reset (CPU 0)
+RAX=0000000000000000 RBX=0000000000000000 RCX=0000000000000000 RDX=0000000000000663
+check_exception old: 0xffffffff new 0xe
+     3: v=0e e=0002 i=0 cpl=0 IP=0008:ffffffff80100123 pc=ffffffff80100123 SP=0010:ffffffff80200f00 CR2=00000000deadbeef
+RAX=0000000000000001 RBX=0000000000000002 RCX=0000000000000003 RDX=0000000000000004
+R8 =0000000000000008 R9 =0000000000000009 R10=0000000000000010 R11=0000000000000011
+RIP=ffffffff80100123 RFL=00000046 [---Z-P-] CPL=0 II=0 A20=1 SMM=0 HLT=0
+CR0=80000011 CR2=00000000deadbeef CR3=0000000000101000 CR4=00000020
+check_exception old: 0xe new 0xd
+     4: v=08 e=0000 i=0 cpl=0 IP=0008:ffffffff80100200 pc=ffffffff80100200 SP=0010:0000000000000000 env->regs[R_EAX]=0000000000000000
+RAX=00000000000000ff
+check_exception old: 0x8 new 0xd
+Triple fault
+";
+
+    #[test]
+    fn triple_fault_reports_the_first_fault_of_the_cascade() {
+        let f = classify("[BOOT] Long mode\n", Some(TRIPLE_FAULT_LOG)).unwrap();
+        assert_eq!(
+            (f.kind, f.source, f.line_no),
+            (FailureKind::TripleFault, Source::QemuLog, 4)
+        );
+        assert_eq!(f.exception.as_ref().unwrap().mnemonic, "#PF");
+        assert_eq!(f.rip, Some(0xffffffff80100123));
+        assert_eq!(f.error_code, Some(2));
+        assert_eq!(f.cr2, Some(0xdeadbeef));
+        assert_eq!(f.registers["RAX"], 1);
+        assert_eq!(f.registers["R8"], 8);
+        assert_eq!(f.registers["CR3"], 0x101000);
+        assert_eq!(
+            f.describe(),
+            "triple fault after #PF (page fault) at RIP=0xffffffff80100123 err=0x2 CR2=0xdeadbeef"
+        );
+        // A log that never faulted classifies nothing.
+        assert_eq!(classify("[BOOT] OK\n", Some("CPU Reset (CPU 0)\n")), None);
+    }
+
+    #[test]
+    fn serial_exception_dump_with_registers() {
+        let serial = "\
+[MM] pmm ready
+[CPU] unhandled exception - halting
+vector=0x0d err=0x10
+RIP: 0xffffffff80001234 RSP: 0xffffffff80200ff0
+RAX=0x0 CR2=0x0
+kernel PANIC: cannot continue
+";
+        let f = classify(serial, None).unwrap();
+        assert_eq!((f.kind, f.line_no), (FailureKind::CpuException, 2));
+        assert_eq!(f.exception.as_ref().unwrap().mnemonic, "#GP");
+        assert_eq!(f.rip, Some(0xffffffff80001234));
+        assert_eq!(f.error_code, Some(0x10));
+        assert_eq!(f.registers["RSP"], 0xffffffff80200ff0);
+        // The panic message is merged in from the banner.
+        assert_eq!(
+            f.describe(),
+            "CPU exception #GP (general protection) at RIP=0xffffffff80001234 err=0x10 CR2=0x0: \
+             cannot continue"
+        );
+    }
+
+    #[test]
+    fn named_exceptions_and_panics() {
+        let f = classify("Page fault at RIP=ffffffff80001000\n", None).unwrap();
+        assert_eq!(f.exception.unwrap().vector, 14);
+        assert_eq!(f.rip, Some(0xffffffff80001000));
+
+        let f = classify("[BOOT] OK\npanic: vmm: out of frames\n", None).unwrap();
+        assert_eq!((f.kind, f.line_no), (FailureKind::Panic, 2));
+        assert_eq!(f.message.as_deref(), Some("vmm: out of frames"));
+        assert_eq!(classify("[TEST] kmath_overflow: PASS\n", None), None);
+    }
+}
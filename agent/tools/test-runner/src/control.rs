@@ -0,0 +1,503 @@
+//! The control channel to an in-guest test stub (`[control]`).
+//!
+//! Instead of scraping `[TEST]` lines off the console, a kernel can link a
+//! test stub that serves a virtio-serial port named [`PORT_NAME`]:
+//! test-runner asks it for its tests, runs them one at a time and gets
+//! each one's output and verdict back as frames, so one run reports every
+//! in-kernel test case, and a hung case is told apart from the ones
+//! before it. The cases are in [`crate::qemu::RunResult::control`] and in
+//! the run's [`crate::TestSummary`] beside any `[TEST]` lines, so a failed
+//! case fails the run, and the run is only complete once every case is
+//! done.
+//!
+//! ```toml
+//! [control]
+//! tests = ["pmm_alloc", "vmm_map"]  # default: every test the stub lists
+//! test-timeout = 10
+//! ```
+//!
+//! # Wire format
+//!
+//! Both directions carry frames of a 4-byte header and a payload:
+//!
+//! | offset | size | field                                  |
+//! |--------|------|----------------------------------------|
+//! | 0      | 1    | kind                                   |
+//! | 1      | 1    | reserved, 0                            |
+//! | 2      | 2    | payload length, little-endian          |
+//! | 4      | len  | payload, UTF-8                         |
+//!
+//! From test-runner to the stub:
+//!
+//! | kind   | name   | payload | meaning                                |
+//! |--------|--------|---------|----------------------------------------|
+//! | `0x01` | LIST   | empty   | send your tests                        |
+//! | `0x02` | RUN    | name    | run this test                          |
+//!
+//! From the stub to test-runner:
+//!
+//! | kind   | name   | payload          | meaning                       |
+//! |--------|--------|------------------|-------------------------------|
+//! | `0x80` | HELLO  | version, `1`     | the stub is ready, sent first |
+//! | `0x81` | TEST   | name             | one of the tests, for LIST    |
+//! | `0x82` | LISTED | empty            | the end of the list           |
+//! | `0x83` | LOG    | text             | a line of the running test's  |
+//! | `0x84` | PASS   | name             | the test passed               |
+//! | `0x85` | FAIL   | name `\n` reason | the test failed               |
+//! | `0x86` | SKIP   | name `\n` reason | the test did not apply        |
+//!
+//! A stub answers RUN of a test it does not have with FAIL. Frames of
+//! other kinds are ignored, so either side can add to the protocol;
+//! [`VERSION`] changes when a frame's meaning does. test-runner waits for
+//! HELLO, sends LIST unless the spec names its tests, then one RUN at a
+//! time; a test without a verdict within `test-timeout` seconds times out,
+//! and the tests after it are not run.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// The virtio-serial port the stub serves.
+pub const PORT_NAME: &str = "auton.control";
+/// The protocol version HELLO carries.
+pub const VERSION: &str = "1";
+/// Seconds a test may take without `test-timeout`.
+const DEFAULT_TEST_TIMEOUT: u64 = 30;
+/// How long to keep trying the port's socket after QEMU starts.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// LOG lines kept per test.
+const LOG_LIMIT: usize = 200;
+
+/// Frame kinds.
+pub mod kind {
+    pub const LIST: u8 = 0x01;
+    pub const RUN: u8 = 0x02;
+    pub const HELLO: u8 = 0x80;
+    pub const TEST: u8 = 0x81;
+    pub const LISTED: u8 = 0x82;
+    pub const LOG: u8 = 0x83;
+    pub const PASS: u8 = 0x84;
+    pub const FAIL: u8 = 0x85;
+    pub const SKIP: u8 = 0x86;
+}
+
+/// One frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(kind: u8, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            kind,
+            payload: payload.into(),
+        }
+    }
+
+    /// The frame on the wire; payloads are cut at 64 KiB.
+    pub fn encode(&self) -> Vec<u8> {
+        let len = self.payload.len().min(usize::from(u16::MAX));
+        [
+            &[self.kind, 0][..],
+            &(len as u16).to_le_bytes(),
+            &self.payload[..len],
+        ]
+        .concat()
+    }
+
+    pub async fn read(from: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Self> {
+        let mut header = [0u8; 4];
+        from.read_exact(&mut header).await?;
+        let mut payload = vec![0; usize::from(u16::from_le_bytes([header[2], header[3]]))];
+        from.read_exact(&mut payload).await?;
+        Ok(Self {
+            kind: header[0],
+            payload,
+        })
+    }
+
+    pub async fn write(&self, to: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
+        to.write_all(&self.encode()).await
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.payload).into_owned()
+    }
+
+    /// A verdict's test name and reason.
+    fn verdict(&self) -> (String, Option<String>) {
+        let text = self.text();
+        match text.split_once('\n') {
+            Some((name, reason)) => (name.to_string(), Some(reason.to_string())),
+            None => (text, None),
+        }
+    }
+}
+
+/// `[control]` in a spec.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ControlSpec {
+    /// Tests to run, in order [default: every test the stub lists].
+    pub tests: Vec<String>,
+    /// Seconds each test may take.
+    pub test_timeout: Option<u64>,
+}
+
+/// A run's control channel.
+#[derive(Debug, Clone)]
+pub struct Control {
+    /// Socket QEMU serves the port's host end on.
+    pub socket: PathBuf,
+    pub tests: Vec<String>,
+    pub test_timeout: Duration,
+}
+
+impl Control {
+    pub fn new(spec: &ControlSpec) -> Self {
+        Self {
+            socket: crate::scratch_path("control"),
+            tests: spec.tests.clone(),
+            test_timeout: Duration::from_secs(spec.test_timeout.unwrap_or(DEFAULT_TEST_TIMEOUT)),
+        }
+    }
+
+    /// QEMU flags for the port.
+    pub fn qemu_args(&self) -> Vec<String> {
+        vec![
+            "-device".to_string(),
+            "virtio-serial-pci,id=controlser".to_string(),
+            "-chardev".to_string(),
+            format!(
+                "socket,id=control,path={},server=on,wait=off",
+                self.socket.display()
+            ),
+            "-device".to_string(),
+            format!("virtserialport,bus=controlser.0,chardev=control,name={PORT_NAME}"),
+        ]
+    }
+
+    /// Talk to the stub once QEMU is up, for the rest of the run.
+    pub fn start(&self) -> Session {
+        let (tx, progress) = mpsc::unbounded_channel();
+        let task = tokio::spawn(serve(self.clone(), tx));
+        Session {
+            progress,
+            task,
+            cases: Vec::new(),
+            error: None,
+            finished: false,
+        }
+    }
+
+    /// The channel's outcome: `session`'s cases, and the requested tests it
+    /// did not get to.
+    pub fn report(&self, session: Session) -> ControlReport {
+        let finished = session.finished;
+        let mut cases = session.cases.clone();
+        let reason = match cases.last() {
+            Some(last) if last.status == CaseStatus::Timeout => {
+                format!("after {} timed out", last.name)
+            }
+            _ => "the run ended first".to_string(),
+        };
+        for name in &self.tests {
+            if !cases.iter().any(|c| &c.name == name) {
+                cases.push(CaseResult {
+                    name: name.clone(),
+                    status: CaseStatus::NotRun,
+                    reason: Some(reason.clone()),
+                    duration_ms: 0,
+                    log: Vec::new(),
+                });
+            }
+        }
+        let error = session.error.clone().or_else(|| {
+            (!finished).then(|| "the run ended before the in-guest tests were done".to_string())
+        });
+        ControlReport { cases, error }
+    }
+}
+
+/// What the stub said, per test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaseStatus {
+    Pass,
+    Fail,
+    Skip,
+    Timeout,
+    NotRun,
+}
+
+impl CaseStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+            Self::Skip => "skip",
+            Self::Timeout => "timeout",
+            Self::NotRun => "not-run",
+        }
+    }
+}
+
+/// One in-guest test case.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CaseResult {
+    pub name: String,
+    pub status: CaseStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub duration_ms: u64,
+    /// Its LOG lines, the first [`LOG_LIMIT`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub log: Vec<String>,
+}
+
+impl CaseResult {
+    pub fn ok(&self) -> bool {
+        matches!(self.status, CaseStatus::Pass | CaseStatus::Skip)
+    }
+
+    /// As a [`crate::TestCase`].
+    pub fn test_case(&self) -> crate::TestCase {
+        let reason = self.reason.clone().unwrap_or_default();
+        crate::TestCase {
+            name: self.name.clone(),
+            passed: self.ok(),
+            message: match self.status {
+                CaseStatus::Pass | CaseStatus::Fail => reason,
+                status if reason.is_empty() => status.name().to_string(),
+                status => format!("{}: {reason}", status.name()),
+            },
+        }
+    }
+}
+
+/// The channel's outcome.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ControlReport {
+    pub cases: Vec<CaseResult>,
+    /// Why the session ended early.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ControlReport {
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self.error.iter().map(|e| format!("control: {e}")).collect();
+        for case in self.cases.iter().filter(|c| !c.ok()) {
+            let mut line = format!("in-guest test {}: {}", case.name, case.status.name());
+            if let Some(reason) = &case.reason {
+                line = format!("{line} - {reason}");
+            }
+            lines.push(line);
+            lines.extend(case.log.iter().map(|l| format!("  {l}")));
+        }
+        lines
+    }
+}
+
+/// What the session task reports.
+#[derive(Debug)]
+enum Progress {
+    Case(CaseResult),
+    /// The session is over, with why if it ended early.
+    Finished(Option<String>),
+}
+
+/// The channel while a run lasts; dropping it hangs up.
+#[derive(Debug)]
+pub struct Session {
+    progress: mpsc::UnboundedReceiver<Progress>,
+    task: JoinHandle<()>,
+    cases: Vec<CaseResult>,
+    error: Option<String>,
+    finished: bool,
+}
+
+impl Session {
+    /// Wait for the next case, or for the end of the session.
+    pub async fn update(&mut self) {
+        match self.progress.recv().await {
+            Some(Progress::Case(case)) => self.cases.push(case),
+            Some(Progress::Finished(error)) => {
+                self.error = error;
+                self.finished = true;
+            }
+            None => self.finished = true,
+        }
+    }
+
+    /// Every case is done, or the session failed.
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(control: Control, tx: mpsc::UnboundedSender<Progress>) {
+    let error = run_tests(&control, &tx).await.err();
+    let _ = tx.send(Progress::Finished(error.map(|e| format!("{e:#}"))));
+}
+
+async fn run_tests(control: &Control, tx: &mpsc::UnboundedSender<Progress>) -> Result<()> {
+    let mut stream = connect(&control.socket).await?;
+    let hello = loop {
+        let frame = Frame::read(&mut stream)
+            .await
+            .context("waiting for the stub's HELLO")?;
+        if frame.kind == kind::HELLO {
+            break frame.text();
+        }
+    };
+    if hello != VERSION {
+        bail!("the stub speaks protocol {hello:?}, test-runner speaks {VERSION}");
+    }
+    let tests = if control.tests.is_empty() {
+        list(&mut stream).await?
+    } else {
+        control.tests.clone()
+    };
+    for test in tests {
+        let case = run_test(&mut stream, &test, control.test_timeout).await?;
+        let timed_out = case.status == CaseStatus::Timeout;
+        let _ = tx.send(Progress::Case(case));
+        if timed_out {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The tests the stub has.
+async fn list(stream: &mut UnixStream) -> Result<Vec<String>> {
+    Frame::new(kind::LIST, Vec::new()).write(stream).await?;
+    let mut tests = Vec::new();
+    loop {
+        let frame = Frame::read(stream)
+            .await
+            .context("reading the stub's tests")?;
+        match frame.kind {
+            kind::TEST => tests.push(frame.text()),
+            kind::LISTED => return Ok(tests),
+            _ => {}
+        }
+    }
+}
+
+async fn run_test(stream: &mut UnixStream, test: &str, timeout: Duration) -> Result<CaseResult> {
+    Frame::new(kind::RUN, test).write(stream).await?;
+    let start = Instant::now();
+    let mut case = CaseResult {
+        name: test.to_string(),
+        status: CaseStatus::Timeout,
+        reason: Some(format!("no verdict within {timeout:?}")),
+        duration_ms: 0,
+        log: Vec::new(),
+    };
+    loop {
+        let frame = match tokio::time::timeout_at(start + timeout, Frame::read(stream)).await {
+            Err(_) => break,
+            Ok(frame) => frame.with_context(|| format!("running {test}"))?,
+        };
+        let status = match frame.kind {
+            kind::LOG => {
+                if case.log.len() < LOG_LIMIT {
+                    case.log.push(frame.text());
+                }
+                continue;
+            }
+            kind::PASS => CaseStatus::Pass,
+            kind::FAIL => CaseStatus::Fail,
+            kind::SKIP => CaseStatus::Skip,
+            _ => continue,
+        };
+        let (name, reason) = frame.verdict();
+        if name != test {
+            bail!("a verdict for {name} while running {test}");
+        }
+        case.status = status;
+        case.reason = reason;
+        break;
+    }
+    case.duration_ms = start.elapsed().as_millis() as u64;
+    Ok(case)
+}
+
+/// Connect to QEMU's end of the port, which it serves once it is up.
+async fn connect(socket: &Path) -> Result<UnixStream> {
+    let started = Instant::now();
+    loop {
+        match UnixStream::connect(socket).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if started.elapsed() > CONNECT_TIMEOUT => {
+                return Err(e).with_context(|| format!("connecting to {}", socket.display()))
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let frame = Frame::new(kind::FAIL, "vmm_map\nnull page");
+        let bytes = frame.encode();
+        assert_eq!(bytes[..4], [0x85, 0, 17, 0]);
+        assert_eq!(Frame::read(&mut &bytes[..]).await.unwrap(), frame);
+        assert_eq!(
+            frame.verdict(),
+            ("vmm_map".to_string(), Some("null page".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn unfinished_sessions_report_what_was_not_run() {
+        let control = Control::new(&ControlSpec {
+            tests: vec!["a".into(), "b".into()],
+            test_timeout: Some(2),
+        });
+        let mut session = control.start();
+        session.task.abort();
+        session.cases.push(CaseResult {
+            name: "a".into(),
+            status: CaseStatus::Timeout,
+            reason: Some("no verdict within 2s".into()),
+            duration_ms: 2000,
+            log: vec!["halfway".into()],
+        });
+        let report = control.report(session);
+        assert_eq!(report.cases[1].status, CaseStatus::NotRun);
+        assert_eq!(
+            report.lines(),
+            [
+                "control: the run ended before the in-guest tests were done",
+                "in-guest test a: timeout - no verdict within 2s",
+                "  halfway",
+                "in-guest test b: not-run - after a timed out"
+            ]
+        );
+        assert_eq!(
+            report.cases[1].test_case().message,
+            "not-run: after a timed out"
+        );
+    }
+}
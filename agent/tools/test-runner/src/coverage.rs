@@ -0,0 +1,465 @@
+//! Guest code coverage (`--coverage lcov:<path>`, `coverage = true`).
+//!
+//! QEMU's `in_asm` log lists each guest instruction as it is translated,
+//! and TCG translates only code that is about to run, so the logged
+//! addresses are the executed PCs. KVM translates nothing, which is why
+//! coverage runs under TCG. After the runs, every instruction of the kernel
+//! ELF (from `objdump -d`) and every executed PC are mapped to source lines
+//! with addr2line: a line's hit count is the number of runs that executed
+//! any of its instructions, and a function's the number that executed any
+//! instruction in its symbol's range. Runs of different kernels are mapped
+//! separately and merged by source file.
+
+use crate::suite::TestOutcome;
+use crate::symbolize::{self, Symbolizer};
+use anyhow::{bail, Context, Result};
+use auton_core::process;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::process::Command;
+
+/// The QEMU `-d` item logging translated guest code.
+pub const LOG_ITEM: &str = "in_asm";
+
+/// objdump binaries to try, in preference order; LLVM's reads any target.
+const OBJDUMP: &[&str] = &["llvm-objdump", "objdump"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoverageFormat {
+    Lcov,
+    Cobertura,
+}
+
+/// `<format>:<path>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageTarget {
+    pub format: CoverageFormat,
+    pub path: PathBuf,
+}
+
+impl FromStr for CoverageTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let Some((format, path)) = s.split_once(':') else {
+            return Err(format!("`{s}`: expected lcov:<path> or cobertura:<path>"));
+        };
+        let format = match format {
+            "lcov" => CoverageFormat::Lcov,
+            "cobertura" => CoverageFormat::Cobertura,
+            other => {
+                return Err(format!(
+                    "unknown coverage format `{other}` (lcov, cobertura)"
+                ))
+            }
+        };
+        if path.is_empty() {
+            return Err(format!("`{s}`: missing coverage path"));
+        }
+        Ok(Self {
+            format,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+/// What a run records coverage with: the `-D` log holding `in_asm` (the
+/// flags are already in the run's `args`) and the ELF mapping it to source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageConfig {
+    pub elf: PathBuf,
+    pub log: PathBuf,
+}
+
+/// The guest PCs one run executed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Executed {
+    pub elf: PathBuf,
+    pub pcs: BTreeSet<u64>,
+}
+
+impl Executed {
+    pub fn from_log(cfg: &CoverageConfig) -> std::io::Result<Self> {
+        let file = std::fs::File::open(&cfg.log)?;
+        let mut pcs = BTreeSet::new();
+        for line in std::io::BufReader::new(file).split(b'\n') {
+            pcs.extend(parse_pc(&String::from_utf8_lossy(&line?)));
+        }
+        Ok(Self {
+            elf: cfg.elf.clone(),
+            pcs,
+        })
+    }
+}
+
+/// The address of an `in_asm` instruction line (`0x00101000:  cli`).
+fn parse_pc(line: &str) -> Option<u64> {
+    let (addr, _) = line.strip_prefix("0x")?.split_once(':')?;
+    u64::from_str_radix(addr, 16).ok()
+}
+
+/// The instruction addresses in `objdump -d` output.
+fn parse_objdump(text: &str) -> Vec<u64> {
+    text.lines()
+        .filter_map(|line| {
+            let (addr, insn) = line.trim_start().split_once(':')?;
+            let addr = u64::from_str_radix(addr, 16).ok()?;
+            insn.starts_with(['\t', ' ']).then_some(addr)
+        })
+        .collect()
+}
+
+/// `kernel/main.c:42` → (`kernel/main.c`, 42).
+fn split_location(loc: &str) -> Option<(String, u32)> {
+    let (file, line) = loc.rsplit_once(':')?;
+    Some((file.to_string(), line.parse().ok()?))
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Function {
+    line: u32,
+    hits: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct FileCoverage {
+    /// Hits by line number.
+    lines: BTreeMap<u32, u64>,
+    functions: BTreeMap<String, Function>,
+}
+
+impl FileCoverage {
+    fn lines_hit(&self) -> usize {
+        self.lines.values().filter(|&&h| h > 0).count()
+    }
+
+    fn functions_hit(&self) -> usize {
+        self.functions.values().filter(|f| f.hits > 0).count()
+    }
+}
+
+/// Line and function hits by source file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    files: BTreeMap<String, FileCoverage>,
+}
+
+impl Coverage {
+    /// Map the runs' PCs to source, one kernel ELF at a time.
+    pub async fn collect(runs: &[&Executed]) -> Result<Self> {
+        let mut by_elf: BTreeMap<&Path, Vec<&BTreeSet<u64>>> = BTreeMap::new();
+        for run in runs {
+            by_elf.entry(&run.elf).or_default().push(&run.pcs);
+        }
+        let mut coverage = Self::default();
+        for (elf, pcs) in by_elf {
+            coverage.merge(Self::map(elf, &pcs).await?);
+        }
+        Ok(coverage)
+    }
+
+    async fn map(elf: &Path, runs: &[&BTreeSet<u64>]) -> Result<Self> {
+        let addrs = instructions(elf).await?;
+        let locations = symbolize::addr2line(elf, &addrs).await?;
+        let instructions: Vec<(u64, Option<(String, u32)>)> = addrs
+            .into_iter()
+            .zip(locations)
+            .map(|(a, loc)| (a, loc.as_deref().and_then(split_location)))
+            .collect();
+        let symbols = Symbolizer::load(elf)?;
+        let functions: Vec<(&str, u64, u64)> = symbols.functions().collect();
+        Ok(Self::build(&instructions, &functions, runs))
+    }
+
+    /// Coverage of `instructions` (address and source line) and
+    /// `functions` (name, start, size) by each run's executed PCs.
+    fn build(
+        instructions: &[(u64, Option<(String, u32)>)],
+        functions: &[(&str, u64, u64)],
+        runs: &[&BTreeSet<u64>],
+    ) -> Self {
+        let located: BTreeMap<u64, (&str, u32)> = instructions
+            .iter()
+            .filter_map(|(a, loc)| loc.as_ref().map(|(f, l)| (*a, (f.as_str(), *l))))
+            .collect();
+        let mut coverage = Self::default();
+        for &(file, line) in located.values() {
+            coverage.file(file).lines.entry(line).or_insert(0);
+        }
+        for pcs in runs {
+            let hit: BTreeSet<(&str, u32)> = pcs
+                .iter()
+                .filter_map(|pc| located.get(pc).copied())
+                .collect();
+            for (file, line) in hit {
+                *coverage.file(file).lines.entry(line).or_insert(0) += 1;
+            }
+        }
+        for &(name, start, size) in functions {
+            let end = start.saturating_add(size);
+            // Where the function starts, or its first line with DWARF.
+            let Some((_, &(file, line))) = located.range(start..end).next() else {
+                continue;
+            };
+            let hits = runs
+                .iter()
+                .filter(|pcs| pcs.range(start..end).next().is_some())
+                .count() as u64;
+            coverage
+                .file(file)
+                .functions
+                .insert(name.to_string(), Function { line, hits });
+        }
+        coverage
+    }
+
+    fn file(&mut self, name: &str) -> &mut FileCoverage {
+        self.files.entry(name.to_string()).or_default()
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (name, theirs) in other.files {
+            let ours = self.file(&name);
+            for (line, hits) in theirs.lines {
+                *ours.lines.entry(line).or_insert(0) += hits;
+            }
+            for (name, f) in theirs.functions {
+                ours.functions
+                    .entry(name)
+                    .or_insert(Function { hits: 0, ..f })
+                    .hits += f.hits;
+            }
+        }
+    }
+
+    /// `lines: HIT/FOUND (P%), functions: HIT/FOUND`.
+    pub fn summary(&self) -> String {
+        let (mut lines, mut lines_hit, mut functions, mut functions_hit) = (0, 0, 0, 0);
+        for f in self.files.values() {
+            lines += f.lines.len();
+            lines_hit += f.lines_hit();
+            functions += f.functions.len();
+            functions_hit += f.functions_hit();
+        }
+        format!(
+            "lines: {lines_hit}/{lines} ({:.1}%), functions: {functions_hit}/{functions}",
+            100.0 * rate(lines_hit, lines)
+        )
+    }
+
+    /// An lcov tracefile (`genhtml`, Codecov).
+    pub fn lcov(&self) -> String {
+        let mut out = String::from("TN:\n");
+        for (name, f) in &self.files {
+            out += &format!("SF:{name}\n");
+            for (func, info) in &f.functions {
+                out += &format!("FN:{},{func}\n", info.line);
+            }
+            for (func, info) in &f.functions {
+                out += &format!("FNDA:{},{func}\n", info.hits);
+            }
+            out += &format!("FNF:{}\nFNH:{}\n", f.functions.len(), f.functions_hit());
+            for (line, hits) in &f.lines {
+                out += &format!("DA:{line},{hits}\n");
+            }
+            out += &format!("LF:{}\nLH:{}\n", f.lines.len(), f.lines_hit());
+            out += "end_of_record\n";
+        }
+        out
+    }
+
+    /// A Cobertura XML report (Jenkins, GitLab), one package per source
+    /// directory and one class per file.
+    pub fn cobertura(&self) -> String {
+        let escape = crate::report::escape;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut packages: BTreeMap<&str, Vec<(&str, &FileCoverage)>> = BTreeMap::new();
+        for (name, f) in &self.files {
+            let dir = name.rsplit_once('/').map_or(".", |(dir, _)| dir);
+            packages.entry(dir).or_default().push((name, f));
+        }
+        let count = |files: &mut dyn Iterator<Item = &FileCoverage>| {
+            files.fold((0, 0), |(found, hit), f| {
+                (found + f.lines.len(), hit + f.lines_hit())
+            })
+        };
+        let (found, hit) = count(&mut self.files.values());
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        out += &format!(
+            "<coverage line-rate=\"{:.4}\" branch-rate=\"0\" lines-covered=\"{hit}\" \
+             lines-valid=\"{found}\" branches-covered=\"0\" branches-valid=\"0\" \
+             complexity=\"0\" version=\"0\" timestamp=\"{timestamp}\">\n",
+            rate(hit, found)
+        );
+        out += "  <sources>\n    <source>.</source>\n  </sources>\n  <packages>\n";
+        for (dir, files) in packages {
+            let (found, hit) = count(&mut files.iter().map(|(_, f)| *f));
+            out += &format!(
+                "    <package name=\"{}\" line-rate=\"{:.4}\" branch-rate=\"0\" complexity=\"0\">\n      <classes>\n",
+                escape(dir),
+                rate(hit, found)
+            );
+            for (name, f) in files {
+                out += &format!(
+                    "        <class name=\"{}\" filename=\"{}\" line-rate=\"{:.4}\" branch-rate=\"0\" complexity=\"0\">\n          <methods>\n",
+                    escape(name.rsplit('/').next().unwrap_or(name)),
+                    escape(name),
+                    rate(f.lines_hit(), f.lines.len())
+                );
+                for (func, info) in &f.functions {
+                    out += &format!(
+                        "            <method name=\"{}\" signature=\"\" line-rate=\"{}\" branch-rate=\"0\" complexity=\"0\">\n              <lines>\n                <line number=\"{}\" hits=\"{}\" branch=\"false\"/>\n              </lines>\n            </method>\n",
+                        escape(func),
+                        u8::from(info.hits > 0),
+                        info.line,
+                        info.hits
+                    );
+                }
+                out += "          </methods>\n          <lines>\n";
+                for (line, hits) in &f.lines {
+                    out += &format!(
+                        "            <line number=\"{line}\" hits=\"{hits}\" branch=\"false\"/>\n"
+                    );
+                }
+                out += "          </lines>\n        </class>\n";
+            }
+            out += "      </classes>\n    </package>\n";
+        }
+        out += "  </packages>\n</coverage>\n";
+        out
+    }
+}
+
+fn rate(hit: usize, found: usize) -> f64 {
+    if found == 0 {
+        0.0
+    } else {
+        hit as f64 / found as f64
+    }
+}
+
+/// Every instruction address in `elf`'s executable sections.
+async fn instructions(elf: &Path) -> Result<Vec<u64>> {
+    let program = OBJDUMP
+        .iter()
+        .find(|p| which::which(p).is_ok())
+        .context("no objdump on PATH")?;
+    let mut cmd = Command::new(program);
+    cmd.args(["-d", "--no-show-raw-insn"]).arg(elf);
+    let out = process::run(cmd, None)
+        .await?
+        .check(&format!("disassembling {}", elf.display()))?;
+    let addrs = parse_objdump(&out.stdout);
+    if addrs.is_empty() {
+        bail!("{program} found no code in {}", elf.display());
+    }
+    Ok(addrs)
+}
+
+/// Map the coverage the outcomes recorded and write every requested
+/// report, creating parent directories. Returns the summary, or `None` if
+/// no run recorded coverage.
+pub async fn write_all(
+    targets: &[CoverageTarget],
+    outcomes: &[TestOutcome],
+) -> Result<Option<String>> {
+    let runs: Vec<&Executed> = outcomes
+        .iter()
+        .filter_map(|o| o.result.as_ref()?.coverage.as_ref())
+        .collect();
+    if runs.is_empty() {
+        return Ok(None);
+    }
+    let coverage = Coverage::collect(&runs).await?;
+    for target in targets {
+        let text = match target.format {
+            CoverageFormat::Lcov => coverage.lcov(),
+            CoverageFormat::Cobertura => coverage.cobertura(),
+        };
+        if let Some(dir) = target.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        std::fs::write(&target.path, text)
+            .with_context(|| format!("writing {}", target.path.display()))?;
+        tracing::info!(path = %target.path.display(), "wrote coverage");
+    }
+    Ok(Some(coverage.summary()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Coverage {
+        let at = |file: &str, line| Some((file.to_string(), line));
+        let instructions = [
+            (0x1000, at("kernel/main.c", 10)),
+            (0x1004, at("kernel/main.c", 11)),
+            (0x1008, at("kernel/main.c", 11)),
+            (0x100c, None),
+            (0x2000, at("mm/pmm.c", 5)),
+            (0x2002, at("mm/pmm.c", 6)),
+        ];
+        let functions = [("kmain", 0x1000, 0x10), ("pmm_init", 0x2000, 4)];
+        let first: BTreeSet<u64> = [0x1000, 0x1008, 0x100c, 0xfff0].into();
+        let second: BTreeSet<u64> = [0x1000, 0x1004].into();
+        Coverage::build(&instructions, &functions, &[&first, &second])
+    }
+
+    #[test]
+    fn maps_pcs_to_lines_and_functions() {
+        let c = sample();
+        let main = &c.files["kernel/main.c"];
+        assert_eq!(main.lines, [(10, 2), (11, 2)].into());
+        assert_eq!(main.functions["kmain"], Function { line: 10, hits: 2 });
+        let pmm = &c.files["mm/pmm.c"];
+        assert_eq!(pmm.lines, [(5, 0), (6, 0)].into());
+        assert_eq!(pmm.functions["pmm_init"].hits, 0);
+        assert_eq!(c.summary(), "lines: 2/4 (50.0%), functions: 1/2");
+
+        let mut merged = c.clone();
+        merged.merge(c);
+        assert_eq!(merged.files["kernel/main.c"].lines[&10], 4);
+        assert_eq!(merged.files["kernel/main.c"].functions["kmain"].hits, 4);
+    }
+
+    #[test]
+    fn writes_lcov_and_cobertura() {
+        let c = sample();
+        let lcov = c.lcov();
+        assert!(lcov.starts_with("TN:\nSF:kernel/main.c\nFN:10,kmain\nFNDA:2,kmain\nFNF:1\nFNH:1\nDA:10,2\nDA:11,2\nLF:2\nLH:2\nend_of_record\n"));
+        assert!(lcov.ends_with("DA:6,0\nLF:2\nLH:0\nend_of_record\n"));
+        let xml = c.cobertura();
+        assert!(xml.contains("lines-covered=\"2\" lines-valid=\"4\""));
+        assert!(xml.contains("<package name=\"kernel\" line-rate=\"1.0000\""));
+        assert!(xml.contains("<class name=\"pmm.c\" filename=\"mm/pmm.c\" line-rate=\"0.0000\""));
+        assert!(xml.contains("<method name=\"kmain\" signature=\"\" line-rate=\"1\""));
+        assert!(xml.ends_with("</packages>\n</coverage>\n"));
+    }
+
+    #[test]
+    fn parses_logs_and_disassembly() {
+        assert_eq!(parse_pc("0x00101000:  cli"), Some(0x101000));
+        assert_eq!(
+            parse_pc("0xffffffff80001000:  movq %rsp, %rbp"),
+            Some(0xffffffff80001000)
+        );
+        assert_eq!(parse_pc("IN: kmain"), None);
+        let dump = "\nDisassembly of section .text:\n\n0000000000101000 <_start>:\n  101000:      \tcli\n  101001:\tmov    $0x115000,%esp\n";
+        assert_eq!(parse_objdump(dump), [0x101000, 0x101001]);
+        assert_eq!(
+            "lcov:build/lcov.info".parse(),
+            Ok(CoverageTarget {
+                format: CoverageFormat::Lcov,
+                path: "build/lcov.info".into()
+            })
+        );
+        assert!("gcov:x".parse::<CoverageTarget>().is_err());
+        assert!("cobertura:".parse::<CoverageTarget>().is_err());
+    }
+}
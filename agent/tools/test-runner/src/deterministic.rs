@@ -0,0 +1,92 @@
+//! Deterministic runs (`--deterministic`), which replay bit-for-bit.
+//!
+//! QEMU runs under TCG with `-icount`, so guest time advances by executed
+//! instructions rather than with the host clock, and never sleeps; the RTC
+//! starts at [`RTC_BASE`] and follows that virtual clock; and `-seed` fixes
+//! every source of guest randomness QEMU has (RDRAND, virtio-rng, ...). A
+//! run with the same kernel, spec and seed then sees the same interrupts at
+//! the same instructions. The seed is picked from the clock unless
+//! `--deterministic-seed` gives one, and is in
+//! [`crate::qemu::RunResult::deterministic`] and the run's `status.json`,
+//! so a failure can be replayed with it.
+
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Nanoseconds per guest instruction, as a power of two.
+pub const ICOUNT_SHIFT: u32 = 3;
+/// What the guest's RTC reads at power-on.
+pub const RTC_BASE: &str = "2000-01-01T00:00:00";
+
+/// A run's fixed inputs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Deterministic {
+    /// QEMU's `-seed`.
+    pub seed: u64,
+    pub icount_shift: u32,
+    pub rtc_base: &'static str,
+}
+
+impl Deterministic {
+    /// Settings for a run seeded with `seed`, else with one from the clock.
+    pub fn new(seed: Option<u64>) -> Self {
+        Self {
+            seed: seed.unwrap_or_else(clock_seed),
+            icount_shift: ICOUNT_SHIFT,
+            rtc_base: RTC_BASE,
+        }
+    }
+
+    pub fn qemu_args(&self) -> Vec<String> {
+        vec![
+            "-icount".to_string(),
+            format!("shift={},align=off,sleep=off", self.icount_shift),
+            "-rtc".to_string(),
+            format!("base={},clock=vm", self.rtc_base),
+            "-seed".to_string(),
+            self.seed.to_string(),
+        ]
+    }
+
+    /// How to replay the run.
+    pub fn line(&self) -> String {
+        format!(
+            "deterministic: replay with --deterministic-seed {}",
+            self.seed
+        )
+    }
+}
+
+/// A seed that differs between runs.
+fn clock_seed() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_nanos() as u64 ^ (u64::from(std::process::id()) << 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixes_the_clock_and_the_seed() {
+        let d = Deterministic::new(Some(42));
+        assert_eq!(
+            d.qemu_args(),
+            [
+                "-icount",
+                "shift=3,align=off,sleep=off",
+                "-rtc",
+                "base=2000-01-01T00:00:00,clock=vm",
+                "-seed",
+                "42"
+            ]
+        );
+        assert_eq!(
+            d.line(),
+            "deterministic: replay with --deterministic-seed 42"
+        );
+        assert_ne!(Deterministic::new(None).seed, 0);
+    }
+}
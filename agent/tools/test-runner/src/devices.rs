@@ -0,0 +1,209 @@
+//! Virtio disks and NICs for tests that need them (`disk`, `net`).
+//!
+//! A spec's `disk` is attached as a virtio-blk drive, but QEMU never
+//! writes to the image itself: each run gets a fresh qcow2 overlay backed
+//! by it, created with `qemu-img` just before QEMU starts and removed when
+//! the run ends, so tests can neither mutate base images nor see each
+//! other's writes. `net` is a `-netdev` backend such as
+//! `user,hostfwd=tcp::5555-:22` behind a virtio-net NIC. Both devices have
+//! fixed ids, so [`crate::inject`] can act on them, and a disk with
+//! `[[disk-error]]` rules is opened through blkdebug.
+
+use crate::inject::{self, DiskError, DISK_ID, DRIVE_ID, NETDEV_ID, NIC_ID};
+use anyhow::{bail, Context, Result};
+use auton_core::plan::{Plan, Planned};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+/// A base image and the per-run overlay QEMU writes to instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disk {
+    /// Absolute, so the overlay's backing reference resolves anywhere.
+    pub base: PathBuf,
+    pub overlay: PathBuf,
+    /// blkdebug rules the overlay is opened with, if any.
+    pub errors: Vec<DiskError>,
+    /// Where they are written for the run.
+    pub rules: PathBuf,
+}
+
+impl Disk {
+    /// A disk backed by `base`, which must exist.
+    pub fn new(base: &Path) -> Result<Self> {
+        let base = base
+            .canonicalize()
+            .with_context(|| format!("disk image {}", base.display()))?;
+        Ok(Self {
+            base,
+            overlay: crate::scratch_path("disk.qcow2"),
+            errors: Vec::new(),
+            rules: crate::scratch_path("blkdebug.conf"),
+        })
+    }
+
+    /// QEMU reads qcow2 bases as such and anything else as raw.
+    fn backing_format(&self) -> &'static str {
+        match self.base.extension() {
+            Some(e) if e == "qcow2" => "qcow2",
+            _ => "raw",
+        }
+    }
+
+    /// QEMU flags attaching the overlay as a virtio-blk drive.
+    pub fn qemu_args(&self) -> Vec<String> {
+        let file = if self.errors.is_empty() {
+            self.overlay.display().to_string()
+        } else {
+            format!(
+                "blkdebug:{}:{}",
+                self.rules.display(),
+                self.overlay.display()
+            )
+        };
+        vec![
+            "-drive".to_string(),
+            format!("if=none,id={DRIVE_ID},format=qcow2,file={file}"),
+            "-device".to_string(),
+            format!("virtio-blk-pci,drive={DRIVE_ID},id={DISK_ID}"),
+        ]
+    }
+
+    /// `qemu-img` arguments creating the overlay.
+    fn overlay_args(&self) -> Vec<&OsStr> {
+        vec![
+            "create".as_ref(),
+            "-q".as_ref(),
+            "-f".as_ref(),
+            "qcow2".as_ref(),
+            "-F".as_ref(),
+            self.backing_format().as_ref(),
+            "-b".as_ref(),
+            self.base.as_os_str(),
+            self.overlay.as_os_str(),
+        ]
+    }
+
+    /// Add what [`Disk::create_overlay`] runs and writes to `plan`.
+    pub fn plan(&self, plan: &mut Plan) {
+        let args: Vec<String> = self
+            .overlay_args()
+            .iter()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+        let what = format!("creating an overlay of {}", self.base.display());
+        plan.run(Planned::new(what, "qemu-img", &args));
+        plan.write(&self.overlay);
+        if !self.errors.is_empty() {
+            plan.write(&self.rules);
+        }
+    }
+
+    /// Create the overlay (and blkdebug rules); they are removed when the
+    /// returned guard drops.
+    pub async fn create_overlay(&self) -> Result<Overlay> {
+        let mut overlay = Overlay(vec![self.overlay.clone()]);
+        if !self.errors.is_empty() {
+            let rules = inject::blkdebug_rules(&self.errors)?;
+            std::fs::write(&self.rules, rules)
+                .with_context(|| format!("writing {}", self.rules.display()))?;
+            overlay.0.push(self.rules.clone());
+        }
+        qemu_img(&self.overlay_args())
+            .await
+            .with_context(|| format!("creating an overlay of {}", self.base.display()))?;
+        Ok(overlay)
+    }
+}
+
+/// A run's overlay files, removed on drop.
+pub struct Overlay(Vec<PathBuf>);
+
+impl Drop for Overlay {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// QEMU flags for a virtio-net NIC on `backend` (a `-netdev` value without
+/// the `id`, e.g. `user,hostfwd=tcp::5555-:22`).
+pub fn net_args(backend: &str) -> Vec<String> {
+    vec![
+        "-netdev".to_string(),
+        format!("{backend},id={NETDEV_ID}"),
+        "-device".to_string(),
+        format!("virtio-net-pci,netdev={NETDEV_ID},id={NIC_ID}"),
+    ]
+}
+
+/// Run `qemu-img` with `args`, failing with its stderr.
+pub(crate) async fn qemu_img(args: &[&OsStr]) -> Result<()> {
+    let qemu_img = which::which("qemu-img").context("qemu-img not found on PATH")?;
+    let out = tokio::process::Command::new(&qemu_img)
+        .args(args)
+        .output()
+        .await
+        .with_context(|| format!("running {}", qemu_img.display()))?;
+    if !out.status.success() {
+        bail!(
+            "qemu-img {} failed: {}",
+            args[0].to_string_lossy(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disks_write_to_an_overlay() {
+        let base = crate::scratch_path("img");
+        std::fs::write(&base, b"").unwrap();
+        let disk = Disk::new(&base).unwrap();
+        assert!(disk.base.is_absolute());
+        assert_eq!(disk.backing_format(), "raw");
+        assert_eq!(
+            disk.qemu_args(),
+            [
+                "-drive".to_string(),
+                format!(
+                    "if=none,id=disk0,format=qcow2,file={}",
+                    disk.overlay.display()
+                ),
+                "-device".to_string(),
+                "virtio-blk-pci,drive=disk0,id=virtio-disk0".to_string(),
+            ]
+        );
+        let failing = Disk {
+            errors: vec![DiskError {
+                event: "read_aio".into(),
+                ..Default::default()
+            }],
+            ..disk.clone()
+        };
+        assert_eq!(
+            failing.qemu_args()[1],
+            format!(
+                "if=none,id=disk0,format=qcow2,file=blkdebug:{}:{}",
+                disk.rules.display(),
+                disk.overlay.display()
+            )
+        );
+        assert!(Disk::new(Path::new("/nonexistent/fat32.img")).is_err());
+        std::fs::remove_file(&base).unwrap();
+
+        assert_eq!(
+            net_args("user,hostfwd=tcp::5555-:22"),
+            [
+                "-netdev",
+                "user,hostfwd=tcp::5555-:22,id=net0",
+                "-device",
+                "virtio-net-pci,netdev=net0,id=nic0"
+            ]
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Guest-controlled exit devices, so a kernel can end the run with a
+//! pass/fail code instead of relying on serial patterns alone.
+//!
+//! * x86_64: `isa-debug-exit` at port `0xf4`. A guest `outl`/`outb` of `v`
+//!   makes QEMU exit with status `(v << 1) | 1`, so statuses are always odd
+//!   and `0` still means QEMU quit for another reason. The kernel writes
+//!   [`ISA_DEBUG_EXIT_SUCCESS`] (`0x10`, status 33) to pass; anything else
+//!   fails.
+//! * aarch64/riscv64: semihosting `SYS_EXIT`, where the guest's exit code
+//!   becomes QEMU's status directly; `0` passes.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+pub const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+/// Value the guest writes to `isa-debug-exit` to report success.
+pub const ISA_DEBUG_EXIT_SUCCESS: u32 = 0x10;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExitDevice {
+    /// `isa-debug-exit` on x86_64, semihosting on aarch64/riscv64.
+    #[default]
+    Auto,
+    IsaDebugExit,
+    Semihosting,
+    None,
+}
+
+/// Exit code reported by the guest through the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DeviceExit {
+    pub device: ExitDevice,
+    /// The value the guest wrote (isa-debug-exit) or passed to `SYS_EXIT`.
+    pub code: u32,
+    pub passed: bool,
+}
+
+impl ExitDevice {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::IsaDebugExit => "isa-debug-exit",
+            Self::Semihosting => "semihosting",
+            Self::None => "none",
+        }
+    }
+
+    /// Replace `Auto` with the device for `arch`.
+    pub fn resolve(self, arch: &str) -> Self {
+        match (self, arch) {
+            (Self::Auto, "x86_64") => Self::IsaDebugExit,
+            (Self::Auto, "aarch64" | "riscv64") => Self::Semihosting,
+            (Self::Auto, _) => Self::None,
+            (d, _) => d,
+        }
+    }
+
+    /// QEMU flags that attach the (resolved) device.
+    pub fn qemu_args(self) -> Vec<String> {
+        match self {
+            Self::IsaDebugExit => vec![
+                "-device".to_string(),
+                format!("isa-debug-exit,iobase={ISA_DEBUG_EXIT_PORT:#x},iosize=0x04"),
+            ],
+            Self::Semihosting => vec![
+                "-semihosting-config".to_string(),
+                "enable=on,target=native".to_string(),
+            ],
+            Self::Auto | Self::None => Vec::new(),
+        }
+    }
+
+    /// Map QEMU's exit status to a guest exit, if the status can have come
+    /// from the device. `success` is the isa-debug-exit pass value.
+    pub fn interpret(self, status: Option<i32>, success: u32) -> Option<DeviceExit> {
+        let status = status?;
+        let (code, passed) = match self {
+            Self::IsaDebugExit if status & 1 == 1 => {
+                let code = (status as u32) >> 1;
+                (code, code == success)
+            }
+            Self::Semihosting if status >= 0 => (status as u32, status == 0),
+            _ => return None,
+        };
+        Some(DeviceExit {
+            device: self,
+            code,
+            passed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_per_arch_and_builds_flags() {
+        assert_eq!(ExitDevice::Auto.resolve("x86_64"), ExitDevice::IsaDebugExit);
+        assert_eq!(ExitDevice::Auto.resolve("aarch64"), ExitDevice::Semihosting);
+        assert_eq!(ExitDevice::None.resolve("x86_64"), ExitDevice::None);
+        assert_eq!(
+            ExitDevice::IsaDebugExit.qemu_args(),
+            ["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"]
+        );
+        assert!(ExitDevice::None.qemu_args().is_empty());
+    }
+
+    #[test]
+    fn decodes_isa_debug_exit_statuses() {
+        let dev = ExitDevice::IsaDebugExit;
+        let pass = dev.interpret(Some(33), ISA_DEBUG_EXIT_SUCCESS).unwrap();
+        assert_eq!((pass.code, pass.passed), (0x10, true));
+        let fail = dev.interpret(Some(35), ISA_DEBUG_EXIT_SUCCESS).unwrap();
+        assert_eq!((fail.code, fail.passed), (0x11, false));
+        // Even statuses (including a plain `0`) are not from the device.
+        assert_eq!(dev.interpret(Some(0), ISA_DEBUG_EXIT_SUCCESS), None);
+        assert_eq!(dev.interpret(None, ISA_DEBUG_EXIT_SUCCESS), None);
+    }
+
+    #[test]
+    fn semihosting_passes_on_zero() {
+        let dev = ExitDevice::Semihosting;
+        assert!(dev.interpret(Some(0), 0).unwrap().passed);
+        assert_eq!(dev.interpret(Some(3), 0).unwrap().code, 3);
+        assert_eq!(ExitDevice::None.interpret(Some(1), 0), None);
+    }
+}
@@ -0,0 +1,298 @@
+//! Expectations over a serial transcript.
+//!
+//! * `ordered` patterns must match in sequence: each one is only looked for
+//!   after the previous one has matched (the same line may satisfy several).
+//! * `unordered` patterns may match anywhere.
+//! * `forbid` and `panic` patterns fail the run on their first match; the
+//!   report carries the offending line with `context` lines on either side.
+//!
+//! The run is complete once every ordered and unordered pattern has matched.
+
+use crate::regex::Regex;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// Lines of context kept on each side of a forbidden line.
+pub const DEFAULT_CONTEXT: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct Expectations {
+    pub ordered: Vec<Regex>,
+    pub unordered: Vec<Regex>,
+    pub forbid: Vec<Regex>,
+    /// Kernel panic banners; like `forbid`, but reported as a panic.
+    pub panic: Vec<Regex>,
+    pub context: usize,
+}
+
+impl Expectations {
+    pub fn new(
+        ordered: &[String],
+        unordered: &[String],
+        forbid: &[String],
+        panic: &[String],
+        context: usize,
+    ) -> Result<Self> {
+        let compile = |pats: &[String]| -> Result<Vec<Regex>> {
+            pats.iter().map(|p| Regex::new(p)).collect()
+        };
+        Ok(Self {
+            ordered: compile(ordered)?,
+            unordered: compile(unordered)?,
+            forbid: compile(forbid)?,
+            panic: compile(panic)?,
+            context,
+        })
+    }
+
+    /// Whether there is anything to wait for; without it the run lasts
+    /// until QEMU exits or times out.
+    pub fn has_positive(&self) -> bool {
+        !self.ordered.is_empty() || !self.unordered.is_empty()
+    }
+}
+
+/// A pattern and the (1-based) line that satisfied it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Matched {
+    pub pattern: String,
+    pub line_no: usize,
+    pub line: String,
+    /// When the line arrived, in milliseconds since the tracker started.
+    pub elapsed_ms: u64,
+}
+
+/// A forbidden or panic line with its surroundings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Violation {
+    pub pattern: String,
+    pub line_no: usize,
+    pub line: String,
+    /// Line number of `context[0]`.
+    pub context_start: usize,
+    pub context: Vec<String>,
+}
+
+impl Violation {
+    /// `context` rendered with line numbers, `>` marking the offending line.
+    pub fn render(&self) -> String {
+        self.context
+            .iter()
+            .enumerate()
+            .map(|(i, l)| {
+                let n = self.context_start + i;
+                let mark = if n == self.line_no { '>' } else { ' ' };
+                format!("{mark}{n:>6} | {l}\n")
+            })
+            .collect()
+    }
+}
+
+/// What a line did to the run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Nothing decisive.
+    Continue,
+    /// The last outstanding pattern matched.
+    Complete,
+    /// A forbidden (`panic == false`) or panic pattern matched.
+    Violated { panic: bool },
+}
+
+/// Feeds serial lines through [`Expectations`].
+#[derive(Debug)]
+pub struct Tracker<'a> {
+    exp: &'a Expectations,
+    started: Instant,
+    line_no: usize,
+    ordered: Vec<Matched>,
+    unordered: Vec<Option<Matched>>,
+    recent: VecDeque<String>,
+    violation: Option<Violation>,
+    /// Context lines still to collect after the violation.
+    after: usize,
+}
+
+impl<'a> Tracker<'a> {
+    pub fn new(exp: &'a Expectations) -> Self {
+        Self {
+            exp,
+            started: Instant::now(),
+            line_no: 0,
+            ordered: Vec::new(),
+            unordered: vec![None; exp.unordered.len()],
+            recent: VecDeque::new(),
+            violation: None,
+            after: 0,
+        }
+    }
+
+    pub fn line(&mut self, line: &str) -> Event {
+        self.line_no += 1;
+        if let Some(v) = &mut self.violation {
+            if self.after > 0 {
+                v.context.push(line.to_string());
+                self.after -= 1;
+            }
+            return Event::Continue;
+        }
+
+        let hit = |pats: &[Regex]| pats.iter().find(|p| p.is_match(line)).cloned();
+        let violated = hit(&self.exp.panic)
+            .map(|p| (p, true))
+            .or_else(|| hit(&self.exp.forbid).map(|p| (p, false)));
+        if let Some((pattern, panic)) = violated {
+            let mut context: Vec<String> = self.recent.drain(..).collect();
+            let context_start = self.line_no - context.len();
+            context.push(line.to_string());
+            self.violation = Some(Violation {
+                pattern: pattern.to_string(),
+                line_no: self.line_no,
+                line: line.to_string(),
+                context_start,
+                context,
+            });
+            self.after = self.exp.context;
+            return Event::Violated { panic };
+        }
+
+        if self.exp.context > 0 {
+            if self.recent.len() == self.exp.context {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(line.to_string());
+        }
+
+        let record = |p: &Regex, line_no| Matched {
+            pattern: p.to_string(),
+            line_no,
+            line: line.to_string(),
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+        };
+        while let Some(p) = self.exp.ordered.get(self.ordered.len()) {
+            if !p.is_match(line) {
+                break;
+            }
+            self.ordered.push(record(p, self.line_no));
+        }
+        for (slot, p) in self.unordered.iter_mut().zip(&self.exp.unordered) {
+            if slot.is_none() && p.is_match(line) {
+                *slot = Some(record(p, self.line_no));
+            }
+        }
+        if self.exp.has_positive() && self.unmatched().is_empty() {
+            Event::Complete
+        } else {
+            Event::Continue
+        }
+    }
+
+    /// Lines seen so far.
+    pub fn lines(&self) -> usize {
+        self.line_no
+    }
+
+    /// Whether the violation's trailing context is complete.
+    pub fn context_done(&self) -> bool {
+        self.after == 0
+    }
+
+    pub fn violation(&self) -> Option<&Violation> {
+        self.violation.as_ref()
+    }
+
+    pub fn into_violation(self) -> Option<Violation> {
+        self.violation
+    }
+
+    /// Matched patterns: ordered ones first, in order.
+    pub fn matched(&self) -> Vec<Matched> {
+        let mut all = self.ordered.clone();
+        all.extend(self.unordered.iter().flatten().cloned());
+        all
+    }
+
+    /// Patterns that have not matched yet. For ordered ones the first is the
+    /// one being waited for.
+    pub fn unmatched(&self) -> Vec<String> {
+        let mut left: Vec<String> = self.exp.ordered[self.ordered.len()..]
+            .iter()
+            .map(Regex::to_string)
+            .collect();
+        left.extend(
+            self.unordered
+                .iter()
+                .zip(&self.exp.unordered)
+                .filter(|(slot, _)| slot.is_none())
+                .map(|(_, p)| p.to_string()),
+        );
+        left
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn exp(ordered: &[&str], unordered: &[&str], forbid: &[&str]) -> Expectations {
+        Expectations::new(
+            &strings(ordered),
+            &strings(unordered),
+            &strings(forbid),
+            &strings(&["PANIC"]),
+            1,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn ordered_patterns_must_match_in_sequence() {
+        let e = exp(&[r"\[MM\]", r"\[BOOT\] OK"], &[r"\[TEST\] a: PASS"], &[]);
+        let mut t = Tracker::new(&e);
+        // `[BOOT] OK` before `[MM]` does not count.
+        assert_eq!(t.line("[BOOT] OK"), Event::Continue);
+        assert_eq!(t.line("[TEST] a: PASS"), Event::Continue);
+        assert_eq!(t.line("[MM] ready"), Event::Continue);
+        assert_eq!(t.unmatched(), [r"\[BOOT\] OK"]);
+        assert_eq!(t.line("[BOOT] OK"), Event::Complete);
+        let lines: Vec<usize> = t.matched().iter().map(|m| m.line_no).collect();
+        assert_eq!(lines, [3, 4, 2]);
+    }
+
+    #[test]
+    fn forbidden_line_is_reported_with_context() {
+        let e = exp(&[r"\[BOOT\] OK"], &[], &["GPF"]);
+        let mut t = Tracker::new(&e);
+        t.line("[DRV] serial");
+        t.line("[MM] pmm init");
+        assert_eq!(t.line("CPU: GPF err=0"), Event::Violated { panic: false });
+        assert!(!t.context_done());
+        t.line("RIP=0x1000");
+        assert!(t.context_done());
+        // Lines after the violation no longer count as progress.
+        assert_eq!(t.line("[BOOT] OK"), Event::Continue);
+        let v = t.into_violation().unwrap();
+        assert_eq!(
+            (v.pattern.as_str(), v.line_no, v.context_start),
+            ("GPF", 3, 2)
+        );
+        assert_eq!(
+            v.render(),
+            "      2 | [MM] pmm init\n>     3 | CPU: GPF err=0\n      4 | RIP=0x1000\n"
+        );
+    }
+
+    #[test]
+    fn panic_patterns_take_precedence() {
+        let e = exp(&[], &[], &["PANIC"]);
+        let mut t = Tracker::new(&e);
+        assert_eq!(t.line("KERNEL PANIC"), Event::Violated { panic: true });
+        assert!(!e.has_positive());
+    }
+}
@@ -0,0 +1,167 @@
+//! Flaky-test detection (`--retries`, `--detect-flaky`).
+//!
+//! A test that fails is rerun up to `retries` times; one that then passes
+//! is flaky by definition. With `--detect-flaky`, every attempt is also
+//! appended to a per-test history in a local JSON state file, and a test
+//! whose last [`HISTORY_LEN`] recorded attempts include both passes and
+//! failures is flagged even when this invocation saw only one outcome.
+//! Flagging does not change pass/fail; it tells the reader (or the agent)
+//! that a failure may not be a regression.
+//!
+//! The last attempt of each run is also timed, for the timeouts that
+//! `--adaptive-timeout` learns ([`crate::timeouts`]).
+
+use crate::accel::Accel;
+use crate::suite::TestOutcome;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// History file used without `--history`.
+pub const DEFAULT_HISTORY: &str = "build/test-history.json";
+
+/// Attempts kept per test.
+pub const HISTORY_LEN: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attempt {
+    pub passed: bool,
+    /// Unix time, seconds.
+    pub at: u64,
+    /// How long it ran, and under what; only a run's last attempt is timed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accel: Option<Accel>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct History {
+    /// Oldest attempt first.
+    pub tests: BTreeMap<String, Vec<Attempt>>,
+}
+
+impl History {
+    /// The history at `path`; empty if the file does not exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        // Written aside and renamed, so an interrupted run keeps the old file.
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("writing {}", path.display()))
+    }
+
+    /// Append `name`'s attempts, dropping the oldest beyond [`HISTORY_LEN`].
+    pub fn record(&mut self, name: &str, passed: impl IntoIterator<Item = bool>) {
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let runs = self.tests.entry(name.to_string()).or_default();
+        runs.extend(passed.into_iter().map(|passed| Attempt {
+            passed,
+            at,
+            duration_ms: None,
+            accel: None,
+        }));
+        let excess = runs.len().saturating_sub(HISTORY_LEN);
+        runs.drain(..excess);
+    }
+
+    /// Why `name` looks flaky, if its history holds both outcomes.
+    pub fn verdict(&self, name: &str) -> Option<String> {
+        let runs = self.tests.get(name)?;
+        let passes = runs.iter().filter(|r| r.passed).count();
+        let failures = runs.len() - passes;
+        (passes > 0 && failures > 0).then(|| {
+            format!(
+                "{passes} passed and {failures} failed of the last {} attempts",
+                runs.len()
+            )
+        })
+    }
+}
+
+/// Why a run that took `attempts` tries and finally `passed` is flaky.
+pub fn retry_verdict(attempts: u32, passed: bool) -> Option<String> {
+    (passed && attempts > 1).then(|| format!("passed on attempt {attempts} after failing"))
+}
+
+/// Record every outcome that ran into `history`, timing its last attempt.
+pub fn record(history: &mut History, outcomes: &[TestOutcome]) {
+    for o in outcomes {
+        let Some(result) = &o.result else {
+            continue;
+        };
+        let failures = o.attempts.saturating_sub(1) as usize;
+        history.record(
+            &o.name,
+            std::iter::repeat_n(false, failures).chain([o.passed]),
+        );
+        if let Some(last) = history.tests.get_mut(&o.name).and_then(|r| r.last_mut()) {
+            last.duration_ms = Some(result.duration_ms);
+            last.accel = Some(result.accel);
+        }
+    }
+}
+
+/// Flag the outcomes that `history` shows to be flaky.
+pub fn flag(history: &History, outcomes: &mut [TestOutcome]) {
+    for o in outcomes.iter_mut().filter(|o| o.result.is_some()) {
+        if o.flaky.is_none() {
+            o.flaky = history.verdict(&o.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mixed_history_is_flaky_and_bounded() {
+        let mut h = History::default();
+        h.record("vmm", [true, true]);
+        assert_eq!(h.verdict("vmm"), None);
+        h.record("vmm", [false]);
+        assert_eq!(
+            h.verdict("vmm").as_deref(),
+            Some("2 passed and 1 failed of the last 3 attempts")
+        );
+        h.record("vmm", std::iter::repeat_n(false, HISTORY_LEN));
+        assert_eq!(h.tests["vmm"].len(), HISTORY_LEN);
+        // The passes have aged out: consistently failing, not flaky.
+        assert_eq!(h.verdict("vmm"), None);
+        assert_eq!(h.verdict("pmm"), None);
+    }
+
+    #[test]
+    fn history_round_trips_through_its_file() {
+        let path = crate::scratch_path("json");
+        assert_eq!(History::load(&path).unwrap(), History::default());
+        let mut h = History::default();
+        h.record("smoke", [false, true]);
+        h.save(&path).unwrap();
+        assert_eq!(History::load(&path).unwrap(), h);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            retry_verdict(2, true).as_deref(),
+            Some("passed on attempt 2 after failing")
+        );
+        assert_eq!(retry_verdict(3, false), None);
+    }
+}
@@ -0,0 +1,513 @@
+//! Fuzzing kernel input paths (`test-runner fuzz`).
+//!
+//! A spec's `[fuzz]` table says how inputs reach the kernel and how to tell
+//! that it survived one. Each iteration boots the test (from its
+//! `snapshot-at` snapshot when it has one, so an input costs a restore
+//! rather than a boot), delivers a mutated input and waits for `alive`; a
+//! panic, forbidden pattern, hang, timeout or early exit is a crash. The
+//! crashing input is shrunk, by deleting ever smaller chunks while the
+//! crash keeps its kind, and stored with what happened under
+//! `fuzz-corpus/<test>/`. Inputs start from the files in the test's
+//! `seeds/` directory there, or from nothing, and splice in `dictionary`
+//! tokens.
+//!
+//! Inputs go to the serial console (typed after `ready`, and kept to
+//! printable ASCII) or, with `channel = "virtio-serial"`, to a
+//! virtio-serial port named [`PORT_NAME`] as raw bytes.
+//!
+//! ```toml
+//! snapshot-at = 'auton> $'
+//!
+//! [fuzz]
+//! prefix = "echo "
+//! suffix = "\n"
+//! alive = 'auton> $'
+//! dictionary = ["%s", "%n", "\t"]
+//! ```
+
+use crate::qemu::{self, RunResult};
+use crate::snapshot;
+use crate::spec::TestSpec;
+use crate::steps::Step;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+
+/// Corpus directory used without `--corpus`.
+pub const DEFAULT_CORPUS: &str = "fuzz-corpus";
+
+/// Inputs tried without `--iterations`.
+pub const DEFAULT_ITERATIONS: u32 = 100;
+
+/// Runs spent shrinking each crash without `--minimize-runs`.
+pub const DEFAULT_MINIMIZE_RUNS: u32 = 32;
+
+/// Longest input without `max-len`.
+pub const DEFAULT_MAX_LEN: usize = 64;
+
+/// The virtio-serial port inputs are written to (`/dev/vport0p1` or the
+/// kernel's equivalent, named `auton.fuzz`).
+pub const PORT_NAME: &str = "auton.fuzz";
+
+/// How long to keep trying the virtio-serial socket after QEMU starts.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Channel {
+    /// The serial console, QEMU's stdin.
+    #[default]
+    Serial,
+    VirtioSerial,
+}
+
+/// `[fuzz]` in a spec.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FuzzSpec {
+    pub channel: Channel,
+    /// Console pattern to wait for before typing an input.
+    pub ready: Option<String>,
+    /// Serial pattern that shows the kernel survived the input (required).
+    pub alive: Option<String>,
+    /// Sent before and after every input, e.g. a command and its newline.
+    pub prefix: String,
+    pub suffix: String,
+    /// Tokens mutations insert.
+    pub dictionary: Vec<String>,
+    /// Longest input in bytes, without `prefix` and `suffix`.
+    pub max_len: Option<usize>,
+}
+
+/// QEMU flags for the virtio-serial port, served on `socket`.
+pub fn channel_args(socket: &Path) -> Vec<String> {
+    vec![
+        "-device".to_string(),
+        "virtio-serial-pci,id=fuzzser".to_string(),
+        "-chardev".to_string(),
+        format!(
+            "socket,id=fuzz,path={},server=on,wait=off",
+            socket.display()
+        ),
+        "-device".to_string(),
+        format!("virtserialport,bus=fuzzser.0,chardev=fuzz,name={PORT_NAME}"),
+    ]
+}
+
+/// xorshift64*: deterministic for a seed, which is all a fuzzer needs.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform-enough in `0..n`; `n` must not be 0.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// `base` with one to four random edits, at most `max_len` bytes.
+pub fn mutate(rng: &mut Rng, base: &[u8], dictionary: &[String], max_len: usize) -> Vec<u8> {
+    let mut data = base.to_vec();
+    for _ in 0..1 + rng.below(4) {
+        let at = rng.below(data.len() + 1);
+        match rng.below(5) {
+            0 if !data.is_empty() => {
+                let i = rng.below(data.len());
+                data[i] ^= 1 << rng.below(8);
+            }
+            1 if !data.is_empty() => {
+                let i = rng.below(data.len());
+                data[i] = rng.next() as u8;
+            }
+            2 if !data.is_empty() => {
+                let end = (at + 1 + rng.below(8)).min(data.len());
+                data.drain(at..end);
+            }
+            3 if !dictionary.is_empty() => {
+                let token = dictionary[rng.below(dictionary.len())].as_bytes();
+                data.splice(at..at, token.iter().copied());
+            }
+            _ => data.insert(at, rng.next() as u8),
+        }
+    }
+    data.truncate(max_len);
+    data
+}
+
+/// `input` as console-safe text: printable ASCII, tabs and newlines; other
+/// bytes are folded into the printable range.
+pub fn printable(input: &[u8]) -> String {
+    input
+        .iter()
+        .map(|&b| match b & 0x7f {
+            b @ (b'\t' | b'\n' | 0x20..=0x7e) => b as char,
+            b => (0x20 + b % 0x5f) as char,
+        })
+        .collect()
+}
+
+/// Shrinks a crashing input by deleting chunks, halving the chunk size
+/// whenever no chunk of the current size can go.
+#[derive(Debug, Clone)]
+pub struct Shrinker {
+    best: Vec<u8>,
+    chunk: usize,
+    at: usize,
+    shrunk: bool,
+}
+
+impl Shrinker {
+    pub fn new(input: Vec<u8>) -> Self {
+        let chunk = (input.len() / 2).max(1);
+        Self {
+            best: input,
+            chunk,
+            at: 0,
+            shrunk: false,
+        }
+    }
+
+    /// The next smaller input to try, or `None` when done.
+    pub fn candidate(&mut self) -> Option<Vec<u8>> {
+        loop {
+            if self.best.is_empty() {
+                return None;
+            }
+            if self.at >= self.best.len() {
+                if !self.shrunk && self.chunk == 1 {
+                    return None;
+                }
+                if !self.shrunk {
+                    self.chunk /= 2;
+                }
+                self.at = 0;
+                self.shrunk = false;
+                continue;
+            }
+            let end = (self.at + self.chunk).min(self.best.len());
+            return Some([&self.best[..self.at], &self.best[end..]].concat());
+        }
+    }
+
+    /// Whether the last candidate still crashed the same way.
+    pub fn report(&mut self, candidate: Vec<u8>, crashed: bool) {
+        if crashed {
+            self.best = candidate;
+            self.shrunk = true;
+        } else {
+            self.at += self.chunk;
+        }
+    }
+
+    pub fn best(self) -> Vec<u8> {
+        self.best
+    }
+}
+
+/// A crash and where its reproducer went.
+#[derive(Debug, Clone)]
+pub struct Crash {
+    /// The run's exit reason (`panic`, `hang`, ...).
+    pub kind: String,
+    pub input: Vec<u8>,
+    pub path: PathBuf,
+    /// False if the corpus already held this reproducer.
+    pub new: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct FuzzOptions {
+    pub iterations: u32,
+    pub seed: u64,
+    pub corpus: PathBuf,
+    pub minimize_runs: u32,
+}
+
+/// What went wrong in a run, or `None` if the kernel survived it.
+pub fn crash_kind(result: &RunResult) -> Option<&'static str> {
+    let summary = result.summary();
+    (!result.passed(&summary)).then(|| result.reason.name())
+}
+
+/// A test set up to take fuzz inputs.
+pub struct Fuzzer {
+    spec: TestSpec,
+    fuzz: FuzzSpec,
+    kernel: PathBuf,
+    image: Option<PathBuf>,
+    transcript_limit: usize,
+}
+
+impl Fuzzer {
+    /// `spec`'s test, restored from its snapshot (created in
+    /// `snapshot_dir` if need be) when it has `snapshot-at`.
+    pub async fn new(
+        spec: &TestSpec,
+        kernel: &Path,
+        snapshot_dir: &Path,
+        transcript_limit: usize,
+    ) -> Result<Self> {
+        let fuzz = spec
+            .fuzz
+            .clone()
+            .context("the spec has no `[fuzz]` table")?;
+        if fuzz.alive.is_none() {
+            bail!("`[fuzz]` needs `alive`, the pattern of a kernel that survived");
+        }
+        let image = match &spec.snapshot_at {
+            Some(_) => Some(snapshot::ensure(spec, kernel, snapshot_dir, transcript_limit).await?),
+            None => None,
+        };
+        let spec = TestSpec {
+            step: Vec::new(),
+            gdb: None,
+            gdb_script: None,
+            golden: None,
+            bless: None,
+            retries: None,
+            wait_exit: None,
+            ..spec.clone()
+        };
+        Ok(Self {
+            spec,
+            fuzz,
+            kernel: kernel.to_path_buf(),
+            image,
+            transcript_limit,
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.spec.test_name(&self.kernel)
+    }
+
+    /// Run the test on one input.
+    pub async fn run(&self, input: &[u8]) -> Result<RunResult> {
+        let alive = self.fuzz.alive.clone();
+        let mut spec = TestSpec {
+            expect: alive.iter().cloned().collect(),
+            expect_any: Vec::new(),
+            ..self.spec.clone()
+        };
+        let mut payload = self.fuzz.prefix.as_bytes().to_vec();
+        payload.extend(input);
+        payload.extend(self.fuzz.suffix.as_bytes());
+        if self.fuzz.channel == Channel::Serial {
+            // The second step keeps a prompt printed before the input
+            // from counting as the kernel's answer to it.
+            spec.step = vec![
+                Step {
+                    expect: self.fuzz.ready.clone(),
+                    send: Some(printable(&payload)),
+                },
+                Step {
+                    expect: alive,
+                    send: None,
+                },
+            ];
+        }
+        let mut cfg = spec.run_config(&self.kernel, self.transcript_limit)?;
+        let _fork = match &self.image {
+            Some(image) => Some(snapshot::fork(&mut cfg, image)?),
+            None => None,
+        };
+        let Some(socket) = cfg.fuzz_channel.clone() else {
+            return qemu::run(&cfg).await;
+        };
+        let result = tokio::select! {
+            result = qemu::run(&cfg) => result,
+            _ = feed(&socket, &payload) => unreachable!("feed never returns"),
+        };
+        let _ = std::fs::remove_file(&socket);
+        result
+    }
+
+    /// Run a campaign: mutate, run, and shrink and store each crash.
+    pub async fn campaign(&self, opts: &FuzzOptions) -> Result<(u32, Vec<Crash>)> {
+        let dir = opts.corpus.join(self.name());
+        let seeds = load_seeds(&dir.join("seeds"))?;
+        let max_len = self.fuzz.max_len.unwrap_or(DEFAULT_MAX_LEN);
+        let mut rng = Rng::new(opts.seed);
+        let mut crashes = Vec::new();
+        let mut runs = 0;
+        for i in 0..opts.iterations {
+            let base = if seeds.is_empty() {
+                &[][..]
+            } else {
+                &seeds[rng.below(seeds.len())][..]
+            };
+            let input = mutate(&mut rng, base, &self.fuzz.dictionary, max_len);
+            let result = self.run(&input).await?;
+            runs += 1;
+            let Some(kind) = crash_kind(&result) else {
+                continue;
+            };
+            tracing::info!(iteration = i + 1, kind, input = %input.escape_ascii(), "crash");
+            let (input, result, shrink_runs) = self.minimize(input, result, kind, opts).await?;
+            runs += shrink_runs;
+            crashes.push(store(&dir, kind, &input, &result, opts.seed)?);
+        }
+        Ok((runs, crashes))
+    }
+
+    /// The smallest input found that crashes the same way, its run, and
+    /// the runs it took.
+    async fn minimize(
+        &self,
+        input: Vec<u8>,
+        mut result: RunResult,
+        kind: &str,
+        opts: &FuzzOptions,
+    ) -> Result<(Vec<u8>, RunResult, u32)> {
+        let mut shrinker = Shrinker::new(input);
+        let mut runs = 0;
+        while runs < opts.minimize_runs {
+            let Some(candidate) = shrinker.candidate() else {
+                break;
+            };
+            let attempt = self.run(&candidate).await?;
+            runs += 1;
+            let crashed = crash_kind(&attempt) == Some(kind);
+            if crashed {
+                result = attempt;
+            }
+            shrinker.report(candidate, crashed);
+        }
+        Ok((shrinker.best(), result, runs))
+    }
+}
+
+/// Connect to QEMU's end of the virtio-serial port, write `payload` and
+/// hold the connection open; never returns.
+async fn feed(socket: &Path, payload: &[u8]) {
+    let started = tokio::time::Instant::now();
+    let mut stream = loop {
+        match UnixStream::connect(socket).await {
+            Ok(stream) => break Some(stream),
+            Err(e) if started.elapsed() > CONNECT_TIMEOUT => {
+                tracing::warn!(socket = %socket.display(), "no fuzz channel: {e}");
+                break None;
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    if let Some(stream) = stream.as_mut() {
+        if let Err(e) = stream.write_all(payload).await {
+            tracing::warn!("fuzz channel: {e}");
+        }
+    }
+    std::future::pending().await
+}
+
+/// The seed inputs in `dir`, if it exists.
+fn load_seeds(dir: &Path) -> Result<Vec<Vec<u8>>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", dir.display())),
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_file())
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .map(|p| std::fs::read(p).with_context(|| format!("reading {}", p.display())))
+        .collect()
+}
+
+/// Save `input` as `crash-<kind>-<hash>.bin` in `dir`, with a `.txt`
+/// saying what happened.
+fn store(dir: &Path, kind: &str, input: &[u8], result: &RunResult, seed: u64) -> Result<Crash> {
+    std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    let mut h = DefaultHasher::new();
+    input.hash(&mut h);
+    let path = dir.join(format!("crash-{kind}-{:016x}.bin", h.finish()));
+    let new = !path.exists();
+    std::fs::write(&path, input).with_context(|| format!("writing {}", path.display()))?;
+    let mut notes = format!("input: {}\nseed: {seed}\n", input.escape_ascii());
+    for line in result.explain() {
+        notes += &line;
+        notes.push('\n');
+    }
+    notes += "--- serial transcript ---\n";
+    notes += &result.transcript;
+    let txt = path.with_extension("txt");
+    std::fs::write(&txt, notes).with_context(|| format!("writing {}", txt.display()))?;
+    Ok(Crash {
+        kind: kind.to_string(),
+        input: input.to_vec(),
+        path,
+        new,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mutations_are_seeded_and_bounded() {
+        let dictionary = vec!["%n".to_string()];
+        let run = |seed| {
+            let mut rng = Rng::new(seed);
+            (0..50)
+                .map(|_| mutate(&mut rng, b"meminfo", &dictionary, 16))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+        assert!(run(7).iter().all(|m| m.len() <= 16));
+        assert!(run(7).iter().any(|m| m.windows(2).any(|w| w == b"%n")));
+        assert_eq!(printable(b"ls\x00\xff\n"), "ls @\n");
+    }
+
+    #[test]
+    fn shrinks_to_the_bytes_that_matter() {
+        // Crashes whenever both `X` and `Y` are present, in order.
+        let crashes = |input: &[u8]| {
+            let x = input.iter().position(|&b| b == b'X');
+            let y = input.iter().rposition(|&b| b == b'Y');
+            matches!((x, y), (Some(x), Some(y)) if x < y)
+        };
+        let mut shrinker = Shrinker::new(b"aaXbbbbbbYcc".to_vec());
+        let mut tries = 0;
+        while let Some(candidate) = shrinker.candidate() {
+            tries += 1;
+            let crashed = crashes(&candidate);
+            shrinker.report(candidate, crashed);
+        }
+        assert_eq!(shrinker.best(), b"XY");
+        assert!(tries < 40, "{tries} tries");
+    }
+
+    #[test]
+    fn parses_a_fuzz_table() {
+        let spec: TestSpec = auton_toml::from_str(
+            "snapshot-at = 'auton> $'\n[fuzz]\nchannel = \"virtio-serial\"\nalive = 'ok'\nmax-len = 8\n",
+        )
+        .unwrap();
+        let fuzz = spec.fuzz.unwrap();
+        assert_eq!(fuzz.channel, Channel::VirtioSerial);
+        assert_eq!((fuzz.alive.as_deref(), fuzz.max_len), (Some("ok"), Some(8)));
+        assert!(channel_args(Path::new("f.sock"))
+            .contains(&"virtserialport,bus=fuzzser.0,chardev=fuzz,name=auton.fuzz".to_string()));
+    }
+}
@@ -0,0 +1,227 @@
+//! GDB attach mode (`--gdb`, `--gdb-script`).
+//!
+//! QEMU starts paused with its gdbstub listening (`-gdb tcp:...:<port> -S`,
+//! the explicit form of `-s -S`). With a script, `gdb -batch` connects,
+//! runs it against the paused target — it decides when to `continue` — and
+//! its combined output becomes [`crate::qemu::RunResult::gdb_transcript`];
+//! the run itself proceeds as usual on serial. Without a script the run is
+//! interactive: connection instructions are printed and the runner waits
+//! for QEMU to exit (or Ctrl-C); patterns are still tracked for the report,
+//! but neither a match nor a panic ends the session.
+//!
+//! While GDB holds the guest no serial output is expected, so the idle
+//! watchdog is off in both modes, and interactive runs get a day-long
+//! timeout unless one is given.
+
+use anyhow::{Context, Result};
+use auton_core::manifest::BuildManifest;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+
+/// Port for interactive sessions, the one `-s` uses.
+pub const DEFAULT_PORT: u16 = 1234;
+
+/// Timeout for interactive sessions without `--timeout`.
+pub const INTERACTIVE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long GDB may take to exit once QEMU is gone.
+const EXIT_GRACE: Duration = Duration::from_secs(5);
+
+/// Debuggers to try, in preference order.
+const GDB: &[&str] = &["gdb-multiarch", "gdb"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GdbConfig {
+    pub port: u16,
+    /// Batch script; `None` is interactive.
+    pub script: Option<PathBuf>,
+    /// Debugger binary (also named in the interactive instructions).
+    pub program: String,
+    /// Kernel ELF to load symbols from.
+    pub elf: Option<PathBuf>,
+}
+
+impl GdbConfig {
+    /// QEMU flags: gdbstub on localhost, CPU stopped at reset.
+    pub fn qemu_args(&self) -> Vec<String> {
+        vec![
+            "-gdb".to_string(),
+            format!("tcp:127.0.0.1:{}", self.port),
+            "-S".to_string(),
+        ]
+    }
+
+    /// `gdb -batch` arguments that connect and run the script.
+    pub fn gdb_args(&self, script: &Path) -> Vec<String> {
+        let mut args: Vec<String> = ["-batch", "-nx", "-ex", "set pagination off"]
+            .map(String::from)
+            .to_vec();
+        if let Some(elf) = &self.elf {
+            args.extend(["-ex".to_string(), format!("file {}", elf.display())]);
+        }
+        args.extend([
+            "-ex".to_string(),
+            format!("target remote 127.0.0.1:{}", self.port),
+            "-x".to_string(),
+            script.display().to_string(),
+        ]);
+        args
+    }
+
+    /// What to tell the user in interactive mode.
+    pub fn instructions(&self) -> String {
+        let file = self
+            .elf
+            .as_ref()
+            .map_or(String::new(), |e| format!("{} ", e.display()));
+        format!(
+            "QEMU is paused with a gdbstub on 127.0.0.1:{port}; connect with\n  \
+             {gdb} {file}-ex 'target remote 127.0.0.1:{port}'\n\
+             The run ends when QEMU exits (Ctrl-C to stop).",
+            gdb = self.program,
+            port = self.port
+        )
+    }
+}
+
+/// The first debugger on PATH.
+pub fn find_gdb() -> Result<String> {
+    GDB.iter()
+        .find(|g| which::which(g).is_ok())
+        .map(|g| g.to_string())
+        .with_context(|| format!("no gdb found; searched PATH for: {}", GDB.join(", ")))
+}
+
+/// A free localhost port for a scripted session (suites run several, and
+/// so may other sessions at the same time).
+pub fn free_port() -> Result<u16> {
+    auton_core::lock::lease_port()
+}
+
+/// The kernel ELF for `kernel`: `symbols` if it is one, else the one the
+/// build manifest beside the image names, else `kernel` unless it is an ISO.
+pub fn find_elf(kernel: &Path, symbols: Option<&Path>) -> Option<PathBuf> {
+    let is_elf = |p: &Path| {
+        std::fs::read(p)
+            .map(|b| b.starts_with(b"\x7fELF"))
+            .unwrap_or(false)
+    };
+    if let Some(s) = symbols.filter(|s| is_elf(s)) {
+        return Some(s.to_path_buf());
+    }
+    let from_manifest = BuildManifest::beside(kernel)
+        .map(|m| m.kernel)
+        .filter(|k| !k.is_empty())
+        .map(PathBuf::from);
+    from_manifest.or_else(|| Some(kernel.to_path_buf()).filter(|k| is_elf(k)))
+}
+
+/// Start the batch session; QEMU must already be launched.
+pub fn spawn_script(cfg: &GdbConfig, script: &Path) -> JoinHandle<String> {
+    let program = cfg.program.clone();
+    let args = cfg.gdb_args(script);
+    tokio::spawn(async move {
+        run_script(&program, &args)
+            .await
+            .unwrap_or_else(|e| format!("gdb failed: {e:#}\n"))
+    })
+}
+
+/// Wait for a session started by [`spawn_script`], killing it if it
+/// outlives QEMU by more than [`EXIT_GRACE`].
+pub async fn collect(task: JoinHandle<String>) -> String {
+    let abort = task.abort_handle();
+    match tokio::time::timeout(EXIT_GRACE, task).await {
+        Ok(Ok(transcript)) => transcript,
+        Ok(Err(e)) => format!("gdb task failed: {e}\n"),
+        Err(_) => {
+            abort.abort();
+            "gdb did not exit after QEMU stopped and was killed\n".to_string()
+        }
+    }
+}
+
+/// Run the debugger with stdout and stderr interleaved into one transcript.
+async fn run_script(program: &str, args: &[String]) -> Result<String> {
+    let log = crate::scratch_path("gdb.log");
+    let file =
+        std::fs::File::create(&log).with_context(|| format!("creating {}", log.display()))?;
+    let status = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(file.try_clone()?)
+        .stderr(file)
+        .kill_on_drop(true)
+        .status()
+        .await
+        .with_context(|| format!("running {program}"));
+    let mut transcript = std::fs::read_to_string(&log).unwrap_or_default();
+    let _ = std::fs::remove_file(&log);
+    let status = status?;
+    if !status.success() {
+        transcript.push_str(&format!("({program} exited with {status})\n"));
+    }
+    Ok(transcript)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(script: Option<&str>) -> GdbConfig {
+        GdbConfig {
+            port: 4321,
+            script: script.map(PathBuf::from),
+            program: "gdb".into(),
+            elf: Some(PathBuf::from("out/kernel.elf")),
+        }
+    }
+
+    #[test]
+    fn builds_qemu_and_gdb_arguments() {
+        let cfg = config(Some("t/break.gdb"));
+        assert_eq!(cfg.qemu_args(), ["-gdb", "tcp:127.0.0.1:4321", "-S"]);
+        assert_eq!(
+            cfg.gdb_args(Path::new("t/break.gdb")),
+            [
+                "-batch",
+                "-nx",
+                "-ex",
+                "set pagination off",
+                "-ex",
+                "file out/kernel.elf",
+                "-ex",
+                "target remote 127.0.0.1:4321",
+                "-x",
+                "t/break.gdb"
+            ]
+        );
+        assert!(config(None)
+            .instructions()
+            .contains("gdb out/kernel.elf -ex 'target remote 127.0.0.1:4321'"));
+    }
+
+    #[tokio::test]
+    async fn captures_the_session_transcript() {
+        // `echo` stands in for gdb: the transcript is its arguments.
+        let cfg = GdbConfig {
+            program: "echo".into(),
+            elf: None,
+            ..config(None)
+        };
+        let out = collect(spawn_script(&cfg, Path::new("s.gdb"))).await;
+        assert_eq!(
+            out,
+            "-batch -nx -ex set pagination off -ex target remote 127.0.0.1:4321 -x s.gdb\n"
+        );
+        let failed = GdbConfig {
+            program: "false".into(),
+            ..cfg
+        };
+        let out = collect(spawn_script(&failed, Path::new("s.gdb"))).await;
+        assert!(out.contains("(false exited with"));
+    }
+}
@@ -0,0 +1,317 @@
+//! Golden transcripts (`--golden <file>`, `--bless`).
+//!
+//! The serial lines after the first match of `golden-start` (else from the
+//! first line) up to the next match of `golden-end` (else the last line)
+//! are normalized and compared with a checked-in file. Normalizing applies
+//! [`DEFAULT_NORMALIZE`] — long hex addresses and timestamps — then each
+//! `normalize = 'REGEX=REPLACEMENT'` rule in order, and trims trailing
+//! whitespace. A mismatch fails the run with a unified diff; `--bless`
+//! writes the normalized section to the file instead, which is how goldens
+//! are created and updated. Only a run that otherwise passed is blessed.
+
+use crate::regex::Regex;
+use anyhow::{Context, Result};
+use auton_core::diff::unified_diff;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Substitutions applied before any from the spec.
+pub const DEFAULT_NORMALIZE: &[(&str, &str)] = &[
+    (r"0x[0-9a-fA-F]{8,}", "<addr>"),
+    (r"\[ *\d+\.\d+\]", "[<time>]"),
+    (r"\b\d+(\.\d+)? ?(ns|us|ms)\b", "<duration>"),
+];
+
+/// A `REGEX=REPLACEMENT` rule, split at the last `=`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Normalize {
+    pub pattern: Regex,
+    pub replacement: String,
+}
+
+impl FromStr for Normalize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (pattern, replacement) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected REGEX=REPLACEMENT, got `{s}`"))?;
+        Ok(Self {
+            pattern: Regex::new(pattern).map_err(|e| e.to_string())?,
+            replacement: replacement.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for Normalize {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Normalize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.pattern, self.replacement)
+    }
+}
+
+/// What a run's serial output is compared with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Golden {
+    pub file: PathBuf,
+    pub start: Option<Regex>,
+    pub end: Option<Regex>,
+    /// [`DEFAULT_NORMALIZE`] followed by the spec's rules.
+    pub normalize: Vec<Normalize>,
+    /// Rewrite `file` instead of comparing.
+    pub bless: bool,
+}
+
+/// The outcome of a golden comparison.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum GoldenResult {
+    Matched {
+        file: PathBuf,
+    },
+    Blessed {
+        file: PathBuf,
+    },
+    /// Unified diff from the golden file to the run's section.
+    Mismatch {
+        file: PathBuf,
+        diff: String,
+    },
+    /// No comparison was possible (missing file or marker).
+    Error {
+        file: PathBuf,
+        message: String,
+    },
+}
+
+impl GoldenResult {
+    pub fn ok(&self) -> bool {
+        matches!(self, Self::Matched { .. } | Self::Blessed { .. })
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        match self {
+            Self::Matched { .. } => Vec::new(),
+            Self::Blessed { file } => vec![format!("blessed golden {}", file.display())],
+            Self::Mismatch { file, diff } => {
+                let mut lines = vec![format!("serial output differs from {}:", file.display())];
+                lines.extend(diff.lines().map(String::from));
+                lines
+            }
+            Self::Error { file, message } => {
+                vec![format!("golden {}: {message}", file.display())]
+            }
+        }
+    }
+}
+
+impl Golden {
+    /// `file` with the default substitutions ahead of `normalize`.
+    pub fn new(
+        file: PathBuf,
+        start: Option<&str>,
+        end: Option<&str>,
+        normalize: &[Normalize],
+        bless: bool,
+    ) -> Result<Self> {
+        let mut rules = DEFAULT_NORMALIZE
+            .iter()
+            .map(|(pattern, replacement)| {
+                Ok(Normalize {
+                    pattern: Regex::new(pattern)?,
+                    replacement: replacement.to_string(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        rules.extend(normalize.iter().cloned());
+        let marker = |m: Option<&str>| m.map(Regex::new).transpose();
+        Ok(Self {
+            file,
+            start: marker(start).context("golden-start")?,
+            end: marker(end).context("golden-end")?,
+            normalize: rules,
+            bless,
+        })
+    }
+
+    /// Compare `transcript` (of which the ring dropped `dropped` bytes)
+    /// with the file, or bless it when the run `passed`.
+    pub fn check(&self, transcript: &str, dropped: u64, passed: bool) -> GoldenResult {
+        let file = self.file.clone();
+        let section = match self.section(transcript, dropped) {
+            Ok(s) => s,
+            Err(message) => return GoldenResult::Error { file, message },
+        };
+        if self.bless && passed {
+            return match write(&self.file, &section) {
+                Ok(()) => {
+                    tracing::info!(golden = %file.display(), lines = section.len(), "blessed");
+                    GoldenResult::Blessed { file }
+                }
+                Err(e) => GoldenResult::Error {
+                    file,
+                    message: format!("{e:#}"),
+                },
+            };
+        }
+        if self.bless {
+            tracing::warn!(golden = %file.display(), "not blessing a failed run");
+        }
+        let expected = match std::fs::read_to_string(&self.file) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return GoldenResult::Error {
+                    file,
+                    message: "not found (run with --bless to create it)".to_string(),
+                }
+            }
+            Err(e) => {
+                return GoldenResult::Error {
+                    file,
+                    message: e.to_string(),
+                }
+            }
+        };
+        let expected: Vec<&str> = expected.lines().collect();
+        let actual: Vec<&str> = section.iter().map(String::as_str).collect();
+        if expected == actual {
+            return GoldenResult::Matched { file };
+        }
+        let old = self.file.display().to_string();
+        GoldenResult::Mismatch {
+            diff: unified_diff(&expected, &actual, &old, "serial"),
+            file,
+        }
+    }
+
+    /// The normalized lines between the markers.
+    fn section(&self, transcript: &str, dropped: u64) -> Result<Vec<String>, String> {
+        let truncated =
+            || format!("the transcript lost its first {dropped} bytes; raise --max-transcript");
+        let lines: Vec<&str> = transcript
+            .lines()
+            .map(|l| l.strip_suffix('\r').unwrap_or(l))
+            .collect();
+        let begin = match &self.start {
+            Some(re) => match lines.iter().position(|l| re.is_match(l)) {
+                Some(i) => i + 1,
+                None if dropped > 0 => return Err(truncated()),
+                None => return Err(format!("start marker `{re}` never printed")),
+            },
+            None if dropped > 0 => return Err(truncated()),
+            None => 0,
+        };
+        let end = match &self.end {
+            Some(re) => match lines[begin..].iter().position(|l| re.is_match(l)) {
+                Some(i) => begin + i,
+                None => return Err(format!("end marker `{re}` never printed")),
+            },
+            None => lines.len(),
+        };
+        Ok(lines[begin..end]
+            .iter()
+            .map(|l| self.normalize(l))
+            .collect())
+    }
+
+    fn normalize(&self, line: &str) -> String {
+        let line = self.normalize.iter().fold(line.to_string(), |l, rule| {
+            rule.pattern.replace_all(&l, &rule.replacement)
+        });
+        line.trim_end().to_string()
+    }
+}
+
+fn write(path: &Path, lines: &[String]) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    }
+    let mut text = lines.join("\n");
+    if !lines.is_empty() {
+        text.push('\n');
+    }
+    std::fs::write(path, text).with_context(|| format!("writing {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERIAL: &str =
+        "SeaBIOS\r\n[INIT] start\r\n[    0.001234] pmm: 0xffffffff80100000 free\r\n\
+                          tick 42  \r\n[INIT] done\r\nlater\r\n";
+
+    fn golden(file: PathBuf, bless: bool) -> Golden {
+        let tick = "tick \\d+=tick N".parse::<Normalize>().unwrap();
+        Golden::new(
+            file,
+            Some(r"\[INIT\] start"),
+            Some(r"\[INIT\] done"),
+            &[tick],
+            bless,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn blesses_then_matches_normalized_sections() {
+        let file = crate::scratch_path("golden");
+        let g = golden(file.clone(), false);
+        assert!(matches!(
+            g.check(SERIAL, 0, true),
+            GoldenResult::Error { .. }
+        ));
+        assert_eq!(
+            golden(file.clone(), true).check(SERIAL, 0, false),
+            GoldenResult::Error {
+                file: file.clone(),
+                message: "not found (run with --bless to create it)".into()
+            }
+        );
+        let blessed = golden(file.clone(), true).check(SERIAL, 0, true);
+        assert_eq!(blessed, GoldenResult::Blessed { file: file.clone() });
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            "[<time>] pmm: <addr> free\ntick N\n"
+        );
+        let rerun = SERIAL.replace("0.001234", "0.002").replace("42", "7");
+        assert_eq!(
+            g.check(&rerun, 0, true),
+            GoldenResult::Matched { file: file.clone() }
+        );
+        let no_end = Golden {
+            end: Regex::new("never").ok(),
+            ..g.clone()
+        };
+        assert_eq!(
+            no_end.check(SERIAL, 0, true).lines(),
+            [format!(
+                "golden {}: end marker `never` never printed",
+                file.display()
+            )]
+        );
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn normalizes_long_dump_lines() {
+        let g = Golden::new(crate::scratch_path("golden"), None, None, &[], false).unwrap();
+        let hex = format!("dump: 0x{} end", "deadbeef".repeat(12_500));
+        assert_eq!(g.normalize(&hex), "dump: <addr> end");
+        let digits = "0123456789".repeat(10_000);
+        assert_eq!(g.normalize(&format!("{digits} ms")), "<duration>");
+        assert_eq!(g.normalize(&digits), digits);
+        let words = "de ad be ef ".repeat(10_000);
+        assert_eq!(g.normalize(&words), words.trim_end());
+    }
+}
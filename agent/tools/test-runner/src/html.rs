@@ -0,0 +1,539 @@
+//! Static HTML reports (`--report html:<dir>`), for browsing a suite's
+//! results from CI.
+//!
+//! The directory gets `index.html`, the suite summary with a row per test,
+//! and `tests/<name>.html` per test: why it failed ([`RunResult::explain`]),
+//! its patterns and in-kernel tests, the backtrace, links to the files in
+//! its artifact directory (serial log, dumps, screenshots, traces) and its
+//! serial transcript (the last `--report-transcript` bytes), with line
+//! numbers, matched and failing lines highlighted, and a search box. The
+//! trend of each test is drawn from the `--detect-flaky` history
+//! ([`History`]): its recent attempts as a strip of passes and failures.
+//! Pages link to each other and to the artifacts by relative path, and need
+//! nothing but `style.css` beside them, so the directory can be uploaded as
+//! it is.
+
+use crate::classify::Source;
+use crate::flaky::{Attempt, History};
+use crate::qemu::RunResult;
+use crate::report::{escape, truncate};
+use crate::results::{civil_from_days, sanitize};
+use crate::suite::TestOutcome;
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::path::{Component, Path, PathBuf};
+
+const STYLE: &str = "\
+body { font-family: system-ui, sans-serif; margin: 2em; color: #222; }
+a { color: #0645ad; }
+table { border-collapse: collapse; }
+th, td { text-align: left; padding: 0.25em 0.75em; border-bottom: 1px solid #ddd; }
+.pass { color: #1a7f37; }
+.fail { color: #cf222e; font-weight: bold; }
+.flaky { color: #9a6700; }
+.summary span { margin-right: 1.5em; }
+pre { background: #f6f8fa; padding: 0.75em; overflow-x: auto; }
+#transcript .l { display: block; white-space: pre; }
+#transcript .l::before { content: attr(data-n); display: inline-block; width: 5em; color: #999; }
+#transcript .match { background: #dafbe1; }
+#transcript .failure { background: #ffebe9; }
+#transcript .hit { background: #fff8c5; }
+#transcript.only .l:not(.hit) { display: none; }
+.search { position: sticky; top: 0; background: #fff; padding: 0.5em 0; }
+";
+
+/// Filter and step through the transcript's lines as the search changes.
+const SEARCH: &str = "\
+<script>
+const q = document.getElementById('q'), only = document.getElementById('only');
+const pre = document.getElementById('transcript'), count = document.getElementById('count');
+let hits = [], at = -1;
+function search() {
+  const text = q.value.toLowerCase();
+  hits = [];
+  for (const line of pre.children) {
+    const hit = text !== '' && line.textContent.toLowerCase().includes(text);
+    line.classList.toggle('hit', hit);
+    if (hit) hits.push(line);
+  }
+  pre.classList.toggle('only', only.checked && text !== '');
+  count.textContent = text === '' ? '' : hits.length + ' matching lines';
+  at = -1;
+}
+q.addEventListener('input', search);
+only.addEventListener('change', search);
+q.addEventListener('keydown', e => {
+  if (e.key !== 'Enter' || hits.length === 0) return;
+  at = (at + 1) % hits.length;
+  hits[at].scrollIntoView({block: 'center'});
+});
+</script>
+";
+
+/// Write the report for `outcomes` into `dir`.
+pub fn write(dir: &Path, outcomes: &[TestOutcome], limit: usize, history: &History) -> Result<()> {
+    let tests = dir.join("tests");
+    std::fs::create_dir_all(&tests).with_context(|| format!("creating {}", tests.display()))?;
+    let pages = page_names(outcomes);
+    let mut files = vec![
+        (dir.join("style.css"), STYLE.to_string()),
+        (dir.join("index.html"), index(outcomes, &pages, history)),
+    ];
+    for (o, page) in outcomes.iter().zip(&pages) {
+        let path = tests.join(page);
+        let attempts = history.tests.get(&o.name).map_or(&[][..], Vec::as_slice);
+        files.push((path, test_page(o, &tests, limit, attempts)));
+    }
+    for (path, text) in files {
+        std::fs::write(&path, text).with_context(|| format!("writing {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Each test's page: its name as a file name, numbered if two match.
+fn page_names(outcomes: &[TestOutcome]) -> Vec<String> {
+    let mut taken = BTreeSet::new();
+    outcomes
+        .iter()
+        .map(|o| {
+            let base = sanitize(&o.name);
+            let name = (1..)
+                .map(|n| match n {
+                    1 => format!("{base}.html"),
+                    n => format!("{base}-{n}.html"),
+                })
+                .find(|name| !taken.contains(name))
+                .unwrap_or_default();
+            taken.insert(name.clone());
+            name
+        })
+        .collect()
+}
+
+fn index(outcomes: &[TestOutcome], pages: &[String], history: &History) -> String {
+    let passed = outcomes.iter().filter(|o| o.passed).count();
+    let flaky = outcomes.iter().filter(|o| o.flaky.is_some()).count();
+    let total_ms: u64 = outcomes.iter().map(duration_ms).sum();
+    let mut out = head("test-runner report", "style.css");
+    let _ = write!(
+        out,
+        "<h1>test-runner report</h1>\n<p class=\"summary\"><span>{} tests</span>\
+         <span class=\"pass\">{passed} passed</span><span class=\"{}\">{} failed</span>\
+         <span class=\"flaky\">{flaky} flaky</span><span>{}</span></p>\n",
+        outcomes.len(),
+        if passed == outcomes.len() {
+            "pass"
+        } else {
+            "fail"
+        },
+        outcomes.len() - passed,
+        secs(total_ms)
+    );
+    out.push_str(
+        "<table>\n<tr><th>Test</th><th>Result</th><th>Time</th><th>Exit</th>\
+            <th>Attempts</th><th>Trend</th></tr>\n",
+    );
+    for (o, page) in outcomes.iter().zip(pages) {
+        let attempts = history.tests.get(&o.name).map_or(&[][..], Vec::as_slice);
+        let exit = match (&o.result, &o.error) {
+            (Some(r), _) => r.reason.name().to_string(),
+            (None, Some(_)) => "error".to_string(),
+            (None, None) => String::new(),
+        };
+        let _ = writeln!(
+            out,
+            "<tr><td><a href=\"tests/{}\">{}</a></td><td>{}</td><td>{}</td><td>{}</td>\
+             <td>{}</td><td>{}</td></tr>",
+            escape(page),
+            escape(&o.name),
+            verdict(o),
+            secs(duration_ms(o)),
+            escape(&exit),
+            o.attempts,
+            strip(attempts, 8)
+        );
+    }
+    out.push_str("</table>\n</body>\n</html>\n");
+    out
+}
+
+fn test_page(o: &TestOutcome, dir: &Path, limit: usize, attempts: &[Attempt]) -> String {
+    let mut out = head(&o.name, "../style.css");
+    let _ = write!(
+        out,
+        "<p><a href=\"../index.html\">&larr; all tests</a></p>\n<h1>{}</h1>\n<p>{}",
+        escape(&o.name),
+        verdict(o)
+    );
+    if let Some(r) = &o.result {
+        let _ = write!(
+            out,
+            " after {}, {}",
+            secs(r.duration_ms),
+            escape(r.reason.name())
+        );
+    }
+    if o.attempts > 1 {
+        let _ = write!(out, ", {} attempts", o.attempts);
+    }
+    out.push_str("</p>\n<table>\n");
+    row(&mut out, "Spec", &o.spec.display().to_string());
+    if let Some(kernel) = &o.kernel {
+        row(&mut out, "Kernel", &kernel.display().to_string());
+    }
+    if let Some(why) = &o.flaky {
+        row(&mut out, "Flaky", why);
+    }
+    out.push_str("</table>\n");
+    if !attempts.is_empty() {
+        let passes = attempts.iter().filter(|a| a.passed).count();
+        let _ = write!(
+            out,
+            "<h2>Trend</h2>\n<p>{passes} of the last {} attempts passed</p>\n{}\n",
+            attempts.len(),
+            strip(attempts, 16)
+        );
+    }
+    if let Some(error) = &o.error {
+        let _ = write!(out, "<h2>Error</h2>\n<pre>{}</pre>\n", escape(error));
+    }
+    if let Some(r) = &o.result {
+        run_sections(&mut out, o, r, dir, limit);
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn run_sections(out: &mut String, o: &TestOutcome, r: &RunResult, dir: &Path, limit: usize) {
+    if !o.passed {
+        let explained = r.explain();
+        if !explained.is_empty() {
+            let _ = write!(
+                out,
+                "<h2>Failure</h2>\n<pre>{}</pre>\n",
+                escape(&explained.join("\n"))
+            );
+        }
+    }
+    if !r.matched.is_empty() || !r.unmatched.is_empty() {
+        out.push_str("<h2>Patterns</h2>\n<table>\n");
+        for m in &r.matched {
+            let _ = writeln!(
+                out,
+                "<tr><td class=\"pass\">matched</td><td><code>{}</code></td><td>line {}</td></tr>",
+                escape(&m.pattern),
+                m.line_no
+            );
+        }
+        for p in &r.unmatched {
+            let _ = writeln!(
+                out,
+                "<tr><td class=\"fail\">unmatched</td><td><code>{}</code></td><td></td></tr>",
+                escape(p)
+            );
+        }
+        out.push_str("</table>\n");
+    }
+    if let Some(summary) = o.summary.as_ref().filter(|s| !s.tests.is_empty()) {
+        out.push_str("<h2>Kernel tests</h2>\n<table>\n");
+        for t in &summary.tests {
+            let class = if t.passed { "pass" } else { "fail" };
+            let _ = writeln!(
+                out,
+                "<tr><td class=\"{class}\">{}</td><td>{}</td><td>{}</td></tr>",
+                if t.passed { "PASS" } else { "FAIL" },
+                escape(&t.name),
+                escape(&t.message)
+            );
+        }
+        out.push_str("</table>\n");
+    }
+    if !r.backtrace.is_empty() {
+        let frames: Vec<String> = r.backtrace.iter().map(|f| f.to_string()).collect();
+        let _ = write!(
+            out,
+            "<h2>Backtrace</h2>\n<pre>{}</pre>\n",
+            escape(&frames.join("\n"))
+        );
+    }
+    let artifacts = artifacts(o, r);
+    if !artifacts.is_empty() {
+        out.push_str("<h2>Artifacts</h2>\n<ul>\n");
+        for path in artifacts {
+            let name = path.file_name().map_or_else(
+                || path.display().to_string(),
+                |n| n.to_string_lossy().into_owned(),
+            );
+            let _ = writeln!(
+                out,
+                "<li><a href=\"{}\">{}</a></li>",
+                escape(&link(dir, &path)),
+                escape(&name)
+            );
+        }
+        out.push_str("</ul>\n");
+    }
+    if let Some(gdb) = &r.gdb_transcript {
+        let _ = write!(out, "<h2>gdb</h2>\n<pre>{}</pre>\n", escape(gdb));
+    }
+    transcript(out, r, limit);
+}
+
+/// The run's files: its artifact directory's, then dumps kept elsewhere.
+fn artifacts(o: &TestOutcome, r: &RunResult) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = o
+        .artifacts
+        .as_deref()
+        .and_then(|dir| std::fs::read_dir(dir).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    files.sort();
+    let dumps = r
+        .dump
+        .iter()
+        .flat_map(|d| [&d.screenshot, &d.core])
+        .flatten();
+    let traced = r.trace.as_ref().map(|t| &t.log);
+    for path in dumps.chain(traced) {
+        if !files.contains(path) {
+            files.push(path.clone());
+        }
+    }
+    files
+}
+
+/// The serial transcript, one numbered line per element: lines a pattern
+/// matched are marked, and so is the failure's when its number is known.
+fn transcript(out: &mut String, r: &RunResult, limit: usize) {
+    let (text, cut) = truncate(&r.transcript, r.transcript_dropped, limit);
+    out.push_str("<h2>Serial transcript</h2>\n");
+    if cut > 0 {
+        let _ = writeln!(out, "<p>({cut} earlier bytes truncated)</p>");
+    }
+    // Line numbers are the serial stream's unless the capture ring dropped
+    // its start, when nothing says how many lines went with it.
+    let first = match r.transcript_dropped {
+        0 => {
+            1 + r.transcript[..r.transcript.len() - text.len()]
+                .matches('\n')
+                .count()
+        }
+        _ => 0,
+    };
+    let matched: BTreeSet<usize> = r.matched.iter().map(|m| m.line_no).collect();
+    let failed = r
+        .failure
+        .as_ref()
+        .filter(|f| f.source == Source::Serial)
+        .map(|f| f.line_no);
+    out.push_str("<div class=\"search\"><input id=\"q\" type=\"search\" placeholder=\"Search the transcript\"> \
+            <label><input id=\"only\" type=\"checkbox\"> only matching lines</label> \
+            <span id=\"count\"></span></div>\n<pre id=\"transcript\">");
+    for (i, line) in text.lines().enumerate() {
+        let n = if first > 0 { first + i } else { i + 1 };
+        let class = match first > 0 {
+            true if failed == Some(n) => " failure",
+            true if matched.contains(&n) => " match",
+            _ => "",
+        };
+        let _ = write!(
+            out,
+            "<span class=\"l{class}\" data-n=\"{n}\">{}</span>",
+            escape(line)
+        );
+    }
+    out.push_str("</pre>\n");
+    out.push_str(SEARCH);
+}
+
+/// Recent attempts, oldest first, as a row of squares (pass or fail) of
+/// `size` pixels, each titled with its time.
+fn strip(attempts: &[Attempt], size: usize) -> String {
+    if attempts.is_empty() {
+        return String::new();
+    }
+    let mut svg = format!(
+        "<svg width=\"{}\" height=\"{size}\" role=\"img\">",
+        attempts.len() * (size + 2)
+    );
+    for (i, a) in attempts.iter().enumerate() {
+        let (fill, word) = if a.passed {
+            ("#2da44e", "passed")
+        } else {
+            ("#cf222e", "failed")
+        };
+        let _ = write!(
+            svg,
+            "<rect x=\"{}\" width=\"{size}\" height=\"{size}\" fill=\"{fill}\"><title>{word} {}</title></rect>",
+            i * (size + 2),
+            date(a.at)
+        );
+    }
+    svg + "</svg>"
+}
+
+/// `YYYY-MM-DD HH:MM UTC`.
+fn date(at: u64) -> String {
+    let (y, m, d) = civil_from_days((at / 86_400) as i64);
+    let rem = at % 86_400;
+    format!(
+        "{y:04}-{m:02}-{d:02} {:02}:{:02} UTC",
+        rem / 3600,
+        rem % 3600 / 60
+    )
+}
+
+/// `target` as a link from a page in `from`: relative when both resolve,
+/// so the report and the artifacts can move together.
+fn link(from: &Path, target: &Path) -> String {
+    let (Ok(from), Ok(to)) = (from.canonicalize(), target.canonicalize()) else {
+        return target.display().to_string();
+    };
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut rel = PathBuf::new();
+    for _ in common..from.len() {
+        rel.push("..");
+    }
+    rel.extend(&to[common..]);
+    rel.display().to_string()
+}
+
+fn head(title: &str, style: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{}</title>\n<link rel=\"stylesheet\" href=\"{style}\">\n</head>\n<body>\n",
+        escape(title)
+    )
+}
+
+fn row(out: &mut String, name: &str, value: &str) {
+    let _ = writeln!(out, "<tr><th>{name}</th><td>{}</td></tr>", escape(value));
+}
+
+fn verdict(o: &TestOutcome) -> &'static str {
+    match (o.passed, o.flaky.is_some()) {
+        (true, false) => "<span class=\"pass\">passed</span>",
+        (true, true) => "<span class=\"flaky\">passed (flaky)</span>",
+        (false, _) => "<span class=\"fail\">FAILED</span>",
+    }
+}
+
+fn duration_ms(o: &TestOutcome) -> u64 {
+    o.result.as_ref().map_or(0, |r| r.duration_ms)
+}
+
+fn secs(ms: u64) -> String {
+    format!("{:.1}s", ms as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expect::Matched;
+    use crate::qemu::ExitReason;
+
+    fn outcome(name: &str, reason: ExitReason, transcript: &str) -> TestOutcome {
+        let result = RunResult {
+            reason,
+            duration_ms: 2500,
+            accel: crate::accel::Accel::Kvm,
+            matched: Vec::new(),
+            unmatched: Vec::new(),
+            transcript: transcript.into(),
+            transcript_dropped: 0,
+            failure: None,
+            backtrace: Vec::new(),
+            dump: None,
+            gdb_transcript: None,
+            trace: None,
+            golden: None,
+            soak: None,
+            injected: Vec::new(),
+            deterministic: None,
+            screens: Vec::new(),
+            packets: Vec::new(),
+            control: None,
+            replay: None,
+            shutdown: crate::qemu::Shutdown::Exited,
+            coverage: None,
+        };
+        TestOutcome::from_result(
+            name.into(),
+            PathBuf::from(format!("tests/{name}.toml")),
+            PathBuf::from("build/auton.iso"),
+            result,
+        )
+    }
+
+    #[test]
+    fn writes_an_index_and_a_page_per_test() {
+        let dir = crate::scratch_path("html");
+        let artifacts = dir.join("results/20261014T120000.000Z-boot");
+        std::fs::create_dir_all(&artifacts).unwrap();
+        std::fs::write(artifacts.join("serial.log"), "boot\n").unwrap();
+
+        let mut boot = outcome(
+            "boot",
+            ExitReason::PatternMatched,
+            "one\n[BOOT] OK\n<three>\n",
+        );
+        boot.artifacts = Some(artifacts.clone());
+        if let Some(r) = boot.result.as_mut() {
+            r.matched = vec![Matched {
+                pattern: r"\[BOOT\] OK".into(),
+                line_no: 2,
+                line: "[BOOT] OK".into(),
+                elapsed_ms: 10,
+            }];
+        }
+        let hang = outcome("mm/hang", ExitReason::Timeout { timeout_ms: 30_000 }, "");
+        let mut history = History::default();
+        history.record("boot", [false, true]);
+
+        write(&dir.join("report"), &[boot, hang], 1024, &history).unwrap();
+        let index = std::fs::read_to_string(dir.join("report/index.html")).unwrap();
+        assert!(index.contains("<span>2 tests</span><span class=\"pass\">1 passed</span>"));
+        assert!(index.contains("<a href=\"tests/boot.html\">boot</a>"));
+        assert!(index.contains("<a href=\"tests/mm_hang.html\">mm/hang</a>"));
+        assert_eq!(index.matches("<rect ").count(), 2);
+        assert!(dir.join("report/style.css").is_file());
+
+        let page = std::fs::read_to_string(dir.join("report/tests/boot.html")).unwrap();
+        assert!(page.contains("1 of the last 2 attempts passed"));
+        assert!(page.contains("<span class=\"l match\" data-n=\"2\">[BOOT] OK</span>"));
+        assert!(page.contains("<span class=\"l\" data-n=\"3\">&lt;three&gt;</span>"));
+        assert!(page.contains(
+            "<a href=\"../../results/20261014T120000.000Z-boot/serial.log\">serial.log</a>"
+        ));
+        let hang = std::fs::read_to_string(dir.join("report/tests/mm_hang.html")).unwrap();
+        assert!(hang.contains("<h2>Failure</h2>\n<pre>QEMU timed out after 30s"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_transcripts_keep_their_line_numbers() {
+        let mut out = String::new();
+        let o = outcome("boot", ExitReason::PatternMatched, "a\nb\nc\n");
+        transcript(&mut out, o.result.as_ref().unwrap(), 2);
+        assert!(out.contains("(4 earlier bytes truncated)"));
+        assert!(
+            out.contains("<pre id=\"transcript\"><span class=\"l\" data-n=\"3\">c</span></pre>")
+        );
+    }
+
+    #[test]
+    fn page_names_are_unique_file_names() {
+        let outcomes = [
+            outcome("a/b", ExitReason::PatternMatched, ""),
+            outcome("a_b", ExitReason::PatternMatched, ""),
+        ];
+        assert_eq!(page_names(&outcomes), ["a_b.html", "a_b-2.html"]);
+        assert_eq!(date(1_792_000_711), "2026-10-14 17:58 UTC");
+    }
+}
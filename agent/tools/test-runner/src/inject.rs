@@ -0,0 +1,244 @@
+//! Device fault injection (`[[disk-error]]`, `[[inject]]`), so that the
+//! error paths of the kernel's drivers get run.
+//!
+//! `[[disk-error]]` rules make the spec's `disk` fail I/O. The overlay is
+//! opened through QEMU's blkdebug driver, and each rule becomes an
+//! `[inject-error]` section of its rules file: requests of the `event`
+//! (`read_aio`, `write_aio`, `flush_to_disk`, ...) fail with `errno`
+//! (`EIO` by default), optionally only at `sector` or only `once`.
+//!
+//! `[[inject]]` acts on the running VM over QMP, `delay` seconds after the
+//! serial line matching `when`, or after QEMU starts without one:
+//! `link-down` and `link-up` switch the NIC's link (`set_link`), and
+//! `unplug-disk` and `unplug-nic` hot-unplug the virtio device
+//! (`device_del`, which needs a machine with a hot-pluggable slot, such as
+//! `pc`). What was done, and when, is in [`crate::qemu::RunResult::injected`].
+//!
+//! ```toml
+//! disk = "images/fat32.img"
+//! net = "user"
+//!
+//! [[disk-error]]
+//! event = "write_aio"
+//! sector = 2048
+//! once = true
+//!
+//! [[inject]]
+//! when = 'virtio-net: link up'
+//! delay = 2
+//! action = "link-down"
+//! ```
+
+use crate::qmp::Qmp;
+use crate::schedule::{Schedule, Trigger};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::Path;
+use tokio::time::Instant;
+
+/// `-netdev` id of the spec's `net` backend.
+pub const NETDEV_ID: &str = "net0";
+/// `-device` id of the spec's virtio-net NIC.
+pub const NIC_ID: &str = "nic0";
+/// `-drive` id of the spec's disk.
+pub const DRIVE_ID: &str = "disk0";
+/// `-device` id of the spec's virtio-blk disk.
+pub const DISK_ID: &str = "virtio-disk0";
+
+/// errno a `[[disk-error]]` fails requests with by default.
+pub const EIO: i32 = 5;
+
+/// `[[disk-error]]`: a blkdebug rule for the spec's disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DiskError {
+    /// blkdebug event whose requests fail (required).
+    pub event: String,
+    pub errno: Option<i32>,
+    /// Only requests covering this sector fail.
+    pub sector: Option<u64>,
+    /// Fail one request, not every one.
+    pub once: bool,
+}
+
+/// A blkdebug rules file with a section per rule.
+pub fn blkdebug_rules(errors: &[DiskError]) -> Result<String> {
+    let mut out = String::new();
+    for e in errors {
+        let named = !e.event.is_empty()
+            && e.event
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !named {
+            bail!("[[disk-error]] needs a blkdebug `event`, e.g. `read_aio`");
+        }
+        out.push_str("[inject-error]\n");
+        out.push_str(&format!("event = \"{}\"\n", e.event));
+        out.push_str(&format!("errno = \"{}\"\n", e.errno.unwrap_or(EIO)));
+        if let Some(sector) = e.sector {
+            out.push_str(&format!("sector = \"{sector}\"\n"));
+        }
+        if e.once {
+            out.push_str("once = \"on\"\n");
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    LinkDown,
+    LinkUp,
+    UnplugDisk,
+    UnplugNic,
+}
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::LinkDown => "link-down",
+            Self::LinkUp => "link-up",
+            Self::UnplugDisk => "unplug-disk",
+            Self::UnplugNic => "unplug-nic",
+        }
+    }
+
+    /// The QMP command and arguments that carry it out.
+    pub fn command(self) -> (&'static str, Value) {
+        match self {
+            Self::LinkDown => ("set_link", json!({ "name": NETDEV_ID, "up": false })),
+            Self::LinkUp => ("set_link", json!({ "name": NETDEV_ID, "up": true })),
+            Self::UnplugDisk => ("device_del", json!({ "id": DISK_ID })),
+            Self::UnplugNic => ("device_del", json!({ "id": NIC_ID })),
+        }
+    }
+
+    fn needs_disk(self) -> bool {
+        self == Self::UnplugDisk
+    }
+}
+
+/// `[[inject]]` in a spec.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct InjectSpec {
+    /// Required.
+    pub action: Option<Action>,
+    /// Serial pattern to wait for [default: none, count from QEMU's start].
+    pub when: Option<String>,
+    /// Seconds to wait after `when`.
+    pub delay: Option<u64>,
+}
+
+/// A compiled `[[inject]]`.
+#[derive(Debug, Clone)]
+pub struct Injection {
+    pub action: Action,
+    pub trigger: Trigger,
+}
+
+impl Injection {
+    /// `spec`, for a run with a disk if `disk` and a NIC if `net`.
+    pub fn new(spec: &InjectSpec, disk: bool, net: bool) -> Result<Self> {
+        let action = spec.action.context(
+            "[[inject]] needs an `action`: link-down, link-up, unplug-disk or unplug-nic",
+        )?;
+        if action.needs_disk() && !disk {
+            bail!("[[inject]] `{}` needs a `disk`", action.name());
+        }
+        if !action.needs_disk() && !net {
+            bail!(
+                "[[inject]] `{}` needs a NIC (`net` or `[[packet]]`)",
+                action.name()
+            );
+        }
+        let trigger = Trigger::new(spec.when.as_deref(), spec.delay)
+            .with_context(|| format!("[[inject]] `{}`", action.name()))?;
+        Ok(Self { action, trigger })
+    }
+}
+
+/// An action carried out, or tried.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Injected {
+    pub action: Action,
+    /// Milliseconds into the run.
+    pub at_ms: u64,
+    /// Serial lines seen by then.
+    pub after_line: usize,
+    /// Why QEMU refused it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The schedule of a run started at `start`.
+pub fn schedule(injections: &[Injection], start: Instant) -> Schedule<Action> {
+    let items = injections.iter().map(|i| (i.trigger.clone(), i.action));
+    Schedule::new(items, start)
+}
+
+/// Carry out `action` on the VM whose QMP socket is `socket`.
+pub async fn fire(socket: &Path, action: Action) -> Result<()> {
+    let (command, arguments) = action.command();
+    Qmp::connect(socket)
+        .await?
+        .execute(command, arguments)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn disk_errors_become_blkdebug_sections() {
+        let errors = [
+            DiskError {
+                event: "read_aio".into(),
+                ..Default::default()
+            },
+            DiskError {
+                event: "write_aio".into(),
+                errno: Some(28),
+                sector: Some(2048),
+                once: true,
+            },
+        ];
+        assert_eq!(
+            blkdebug_rules(&errors).unwrap(),
+            "[inject-error]\nevent = \"read_aio\"\nerrno = \"5\"\n\n\
+             [inject-error]\nevent = \"write_aio\"\nerrno = \"28\"\nsector = \"2048\"\n\
+             once = \"on\"\n\n"
+        );
+        assert!(blkdebug_rules(&[DiskError::default()]).is_err());
+        let quoted = DiskError {
+            event: "read\"".into(),
+            ..Default::default()
+        };
+        assert!(blkdebug_rules(&[quoted]).is_err());
+    }
+
+    #[test]
+    fn injections_need_their_device() {
+        let spec = |action, when: Option<&str>, delay| InjectSpec {
+            action: Some(action),
+            when: when.map(String::from),
+            delay: Some(delay),
+        };
+        let down = Injection::new(&spec(Action::LinkDown, Some("link up"), 2), false, true);
+        assert_eq!(down.unwrap().trigger.delay, Duration::from_secs(2));
+        assert!(Injection::new(&spec(Action::LinkUp, None, 0), true, false).is_err());
+        let bad = spec(Action::LinkDown, Some("("), 0);
+        assert!(Injection::new(&bad, false, true).is_err());
+
+        let unplug = spec(Action::UnplugDisk, None, 0);
+        assert!(Injection::new(&unplug, false, true).is_err());
+        assert!(Injection::new(&unplug, true, false).is_ok());
+        assert!(Injection::new(&InjectSpec::default(), true, true).is_err());
+    }
+}
@@ -0,0 +1,282 @@
+//! Known failures (`suite --baseline`).
+//!
+//! A baseline file lists the tests known to fail. A suite run with
+//! `--baseline` fails only on regressions: a test failing that is not in
+//! the list, or one that was in it, has passed since and fails again.
+//! Known failures still fail and are still reported, but marked as known,
+//! so the agent can keep working around issues that predate it.
+//!
+//! A known failure that passes is moved to the file's `fixed` list right
+//! away (a ratchet), so a fix cannot quietly regress; a flaky pass does
+//! not count. `--update-baseline` accepts the run's failures as the new
+//! known ones.
+//!
+//! ```json
+//! {
+//!   "failing": {"net": {"reason": "timeout", "since": 1792000000}},
+//!   "fixed": {"vfs": 1792003600}
+//! }
+//! ```
+
+use crate::suite::TestOutcome;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How a test's outcome compares with the baseline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    /// Failed, and not known to.
+    New,
+    /// Failed, as known.
+    Known,
+    /// A known failure that passed.
+    Fixed,
+    /// Failed again after being fixed.
+    Rebroken,
+}
+
+impl Status {
+    /// Whether a failure with this status fails the run.
+    pub fn regressed(self) -> bool {
+        matches!(self, Self::New | Self::Rebroken)
+    }
+}
+
+/// A test known to fail.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Failing {
+    /// How it failed when it was added.
+    pub reason: String,
+    /// Unix time, seconds, it was added.
+    pub since: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Baseline {
+    pub failing: BTreeMap<String, Failing>,
+    /// Former known failures, with the Unix time they first passed.
+    pub fixed: BTreeMap<String, u64>,
+}
+
+impl Baseline {
+    /// The baseline at `path`; empty if the file does not exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        // Written aside and renamed, so an interrupted run keeps the old file.
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("writing {}", path.display()))
+    }
+
+    /// `outcome`'s status; `None` for a pass with nothing to say.
+    pub fn status(&self, outcome: &TestOutcome) -> Option<Status> {
+        let known = self.failing.contains_key(&outcome.name);
+        match (outcome.passed, known) {
+            (true, true) if outcome.flaky.is_some() => Some(Status::Known),
+            (true, true) => Some(Status::Fixed),
+            (true, false) => None,
+            (false, true) => Some(Status::Known),
+            (false, false) if self.fixed.contains_key(&outcome.name) => Some(Status::Rebroken),
+            (false, false) => Some(Status::New),
+        }
+    }
+
+    /// Mark each outcome with its status and move the fixed tests out of
+    /// the known failures; with `accept`, the failing ones become known.
+    /// Whether the baseline changed.
+    pub fn apply(&mut self, outcomes: &mut [TestOutcome], accept: bool) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let before = self.clone();
+        for o in outcomes.iter_mut() {
+            o.baseline = self.status(o);
+            match o.baseline {
+                Some(Status::Fixed) => {
+                    self.failing.remove(&o.name);
+                    self.fixed.insert(o.name.clone(), now);
+                }
+                Some(Status::New | Status::Rebroken) if accept => {
+                    self.fixed.remove(&o.name);
+                    let failing = Failing {
+                        reason: reason(o),
+                        since: now,
+                    };
+                    self.failing.insert(o.name.clone(), failing);
+                }
+                _ => {}
+            }
+        }
+        *self != before
+    }
+}
+
+/// What `outcome` failed with, in a word or a line.
+fn reason(outcome: &TestOutcome) -> String {
+    match (&outcome.error, &outcome.result) {
+        (Some(error), _) => error.lines().next().unwrap_or_default().to_string(),
+        (None, Some(result)) => result.reason.name().to_string(),
+        (None, None) => "failed".to_string(),
+    }
+}
+
+/// Whether `outcome` fails the run: with a baseline, only regressions do.
+pub fn fails_run(outcome: &TestOutcome) -> bool {
+    !outcome.passed && outcome.baseline.is_none_or(Status::regressed)
+}
+
+/// `baseline: 2 known failures; fixed: vfs; new: net`, or `None` when no
+/// outcome was compared.
+pub fn describe(outcomes: &[TestOutcome]) -> Option<String> {
+    let named = |status| {
+        let names: Vec<&str> = outcomes
+            .iter()
+            .filter(|o| o.baseline == Some(status))
+            .map(|o| o.name.as_str())
+            .collect();
+        names.join(", ")
+    };
+    let known = outcomes
+        .iter()
+        .filter(|o| o.baseline == Some(Status::Known))
+        .count();
+    if outcomes.iter().all(|o| o.baseline.is_none()) {
+        return None;
+    }
+    let mut out = match known {
+        1 => "baseline: 1 known failure".to_string(),
+        n => format!("baseline: {n} known failures"),
+    };
+    for (label, status) in [
+        ("fixed", Status::Fixed),
+        ("new", Status::New),
+        ("rebroken", Status::Rebroken),
+    ] {
+        let names = named(status);
+        if !names.is_empty() {
+            out.push_str(&format!("; {label}: {names}"));
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn outcome(name: &str, passed: bool) -> TestOutcome {
+        TestOutcome {
+            name: name.into(),
+            spec: PathBuf::from(format!("tests/{name}.toml")),
+            kernel: None,
+            matrix: None,
+            passed,
+            error: (!passed).then(|| "build failed\nld: undefined symbol".into()),
+            summary: None,
+            result: None,
+            attempts: 1,
+            flaky: None,
+            artifacts: None,
+            baseline: None,
+        }
+    }
+
+    fn statuses(outcomes: &[TestOutcome]) -> Vec<(&str, Option<Status>, bool)> {
+        outcomes
+            .iter()
+            .map(|o| (o.name.as_str(), o.baseline, fails_run(o)))
+            .collect()
+    }
+
+    #[test]
+    fn only_new_and_rebroken_failures_fail_the_run() {
+        let mut baseline = Baseline::default();
+        let mut first = [outcome("net", false), outcome("vfs", false)];
+        assert!(baseline.apply(&mut first, true));
+        assert_eq!(baseline.failing["net"].reason, "build failed");
+        assert_eq!(
+            statuses(&first),
+            [
+                ("net", Some(Status::New), true),
+                ("vfs", Some(Status::New), true)
+            ]
+        );
+
+        let mut second = [
+            outcome("net", false),
+            outcome("vfs", true),
+            outcome("boot", true),
+            outcome("mm", false),
+        ];
+        assert!(baseline.apply(&mut second, false));
+        assert_eq!(
+            statuses(&second),
+            [
+                ("net", Some(Status::Known), false),
+                ("vfs", Some(Status::Fixed), false),
+                ("boot", None, false),
+                ("mm", Some(Status::New), true),
+            ]
+        );
+        assert!(!baseline.failing.contains_key("vfs"));
+        assert!(!baseline.failing.contains_key("mm"));
+
+        let mut third = [outcome("net", false), outcome("vfs", false)];
+        assert!(!baseline.apply(&mut third, false));
+        assert_eq!(
+            statuses(&third),
+            [
+                ("net", Some(Status::Known), false),
+                ("vfs", Some(Status::Rebroken), true),
+            ]
+        );
+        assert_eq!(
+            describe(&third).as_deref(),
+            Some("baseline: 1 known failure; rebroken: vfs")
+        );
+        assert_eq!(describe(&[outcome("boot", true)]), None);
+        assert!(fails_run(&outcome("boot", false)));
+    }
+
+    #[test]
+    fn flaky_passes_do_not_fix_a_known_failure() {
+        let mut baseline = Baseline::default();
+        baseline.apply(&mut [outcome("net", false)], true);
+        let mut flaky = outcome("net", true);
+        flaky.flaky = Some("passed on attempt 2 after failing".into());
+        let mut run = [flaky];
+        assert!(!baseline.apply(&mut run, false));
+        assert_eq!(run[0].baseline, Some(Status::Known));
+        assert!(baseline.failing.contains_key("net"));
+    }
+
+    #[test]
+    fn baseline_round_trips() {
+        let path = crate::scratch_path("json");
+        assert_eq!(Baseline::load(&path).unwrap(), Baseline::default());
+        let mut baseline = Baseline::default();
+        baseline.apply(&mut [outcome("net", false)], true);
+        baseline.save(&path).unwrap();
+        assert_eq!(Baseline::load(&path).unwrap(), baseline);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
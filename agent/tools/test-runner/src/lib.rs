@@ -1,12 +1,90 @@
 //! QEMU test-runner core: serial-output parsing and QEMU command construction.
 //!
-//! Mirrors the marker contract in
+//! - [`accel`]: pick KVM or TCG.
+//! - [`admission`]: host budgets for suite runs.
+//! - [`bench`](mod@bench): time boots against a baseline.
+//! - [`capture`]: the bounded serial transcript.
+//! - [`classify`]: a structured cause for a failed run.
+//! - [`control`]: in-kernel tests over a virtio-serial channel.
+//! - [`coverage`]: executed guest code as lcov/Cobertura reports.
+//! - [`deterministic`]: repeatable runs.
+//! - [`devices`]: virtio disks and NICs.
+//! - [`exitdev`]: guest-controlled exit devices.
+//! - [`expect`]: expect/forbid patterns over the transcript.
+//! - [`flaky`]: reruns and flaky-test history.
+//! - [`fuzz`]: feed the kernel mutated inputs until it crashes.
+//! - [`gdb`]: attach a debugger.
+//! - [`golden`]: diff transcripts against checked-in ones.
+//! - [`html`]: HTML reports.
+//! - [`inject`]: make attached devices fail.
+//! - [`known`]: known failures.
+//! - [`machine`]: per-arch QEMU machines.
+//! - [`packets`]: scripted frames for the guest's network stack.
+//! - [`qemu`]: the launch loop.
+//! - [`qmp`]: QEMU control.
+//! - [`regex`]: the pattern engine behind expectations.
+//! - [`remote`]: QEMU on another host over SSH.
+//! - [`replay`]: record runs to replay under a debugger.
+//! - [`report`]: JUnit/JSON reports.
+//! - [`results`]: per-run artifact directories.
+//! - [`schedule`]: actions at points in a run.
+//! - [`screen`]: compare the guest display with reference images.
+//! - [`snapshot`]: start tests from a saved boot.
+//! - [`soak`]: keep one boot running for hours, watching its health.
+//! - [`spec`]: test spec files.
+//! - [`steps`]: type into the guest console.
+//! - [`suite`]: directory-of-specs runs.
+//! - [`symbolize`]: symbolized backtraces.
+//! - [`timeouts`]: timeouts learned from the history.
+//! - [`trace`]: summaries of QEMU interrupt/MMIO traces.
+//!
+//! Serial parsing mirrors the marker contract in
 //! `orchestrator/validation/test_validator.py`:
 //!   [TEST] name: PASS
 //!   [TEST] name: FAIL - reason
 //!   [BOOT] OK
 
+pub mod accel;
+pub mod admission;
+pub mod bench;
+pub mod capture;
+pub mod classify;
+pub mod control;
+pub mod coverage;
+pub mod deterministic;
+pub mod devices;
+pub mod exitdev;
+pub mod expect;
+pub mod flaky;
+pub mod fuzz;
+pub mod gdb;
+pub mod golden;
+pub mod html;
+pub mod inject;
+pub mod known;
+pub mod machine;
+pub mod packets;
+pub mod qemu;
+pub mod qmp;
+pub mod regex;
+pub mod remote;
+pub mod replay;
+pub mod report;
+pub mod results;
+pub mod schedule;
+pub mod screen;
+pub mod snapshot;
+pub mod soak;
+pub mod spec;
+pub mod steps;
+pub mod suite;
+pub mod symbolize;
+pub mod timeouts;
+pub mod trace;
+
 use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct TestCase {
@@ -25,6 +103,20 @@ pub struct TestSummary {
     pub tests: Vec<TestCase>,
 }
 
+impl TestSummary {
+    /// Count in `case`, e.g. one an in-guest test stub reported.
+    pub fn add(&mut self, case: TestCase) {
+        if case.passed {
+            self.passed += 1;
+        } else {
+            self.failed += 1;
+        }
+        self.total += 1;
+        self.success = self.failed == 0 && self.boot_ok;
+        self.tests.push(case);
+    }
+}
+
 fn parse_test_line(line: &str) -> Option<TestCase> {
     let idx = line.find("[TEST]")?;
     let rest = line[idx + "[TEST]".len()..].trim();
@@ -68,21 +160,22 @@ pub fn parse_serial(output: &str) -> TestSummary {
 
 /// Build the QEMU argument vector for booting a kernel image.
 ///
-/// `.iso` images boot via `-cdrom` (GRUB/Multiboot2 ELF64 path); everything
-/// else boots via `-kernel`. `machine` adds `-machine <m>` for non-x86 arches.
+/// `.iso` images boot via `-cdrom` (GRUB/Multiboot2 ELF64 path), `.img` raw
+/// disk images (BIOS or UEFI) as a raw `-drive`, and everything else via
+/// `-kernel`. `machine` adds `-machine <m>` for non-x86 arches.
 pub fn qemu_args(image: &str, machine: Option<&str>, memory_mb: u32) -> Vec<String> {
     let mut args = Vec::new();
     if let Some(m) = machine {
         args.push("-machine".to_string());
         args.push(m.to_string());
     }
-    let media = if image.ends_with(".iso") {
-        "-cdrom"
+    if image.ends_with(".iso") {
+        args.extend(["-cdrom".to_string(), image.to_string()]);
+    } else if image.ends_with(".img") {
+        args.extend(["-drive".to_string(), format!("format=raw,file={image}")]);
     } else {
-        "-kernel"
-    };
-    args.push(media.to_string());
-    args.push(image.to_string());
+        args.extend(["-kernel".to_string(), image.to_string()]);
+    }
     for a in ["-serial", "stdio", "-display", "none", "-no-reboot", "-m"] {
         args.push(a.to_string());
     }
@@ -90,6 +183,14 @@ pub fn qemu_args(image: &str, machine: Option<&str>, memory_mb: u32) -> Vec<Stri
     args
 }
 
+/// A fresh path under the temp dir for a per-run file (QMP socket, QEMU
+/// log), unique within this process even when suite tests run concurrently.
+pub fn scratch_path(ext: &str) -> PathBuf {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("test-runner-{}-{n}.{ext}", std::process::id()))
+}
+
 /// QEMU binary name for a target architecture.
 pub fn qemu_binary(arch: &str) -> Option<&'static str> {
     match arch {
@@ -145,6 +246,10 @@ mod tests {
         assert!(args
             .windows(2)
             .any(|w| w == ["-kernel", "build/kernel.bin"]));
+        let disk = qemu_args("build/auton-uefi.img", None, 128);
+        assert!(disk
+            .windows(2)
+            .any(|w| w == ["-drive", "format=raw,file=build/auton-uefi.img"]));
     }
 
     #[test]
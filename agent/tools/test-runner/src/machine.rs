@@ -0,0 +1,223 @@
+//! Which QEMU to launch and how: binary, machine type, CPU and firmware.
+//!
+//! A kernel-builder build leaves `manifest.json` beside its images, naming
+//! the target `arch`, the QEMU settings for it and the firmware the images
+//! need (`boot = "uefi"` for OVMF). Those are the defaults for a test that
+//! boots from that directory; spec keys and flags override them, and
+//! without a manifest the per-arch table here applies:
+//!
+//! | arch    | binary              | machine | extra             |
+//! |---------|---------------------|---------|-------------------|
+//! | x86_64  | qemu-system-x86_64  | (pc)    |                   |
+//! | aarch64 | qemu-system-aarch64 | virt    | `-cpu cortex-a53` |
+//! | riscv64 | qemu-system-riscv64 | virt    | `-bios default`   |
+//!
+//! Serial is always `-serial stdio` (the first UART: COM1, the virt PL011
+//! or NS16550); the exit device follows the arch (see [`crate::exitdev`]).
+//! UEFI boots are x86_64 only and load OVMF from `firmware` or the usual
+//! distro locations.
+
+use crate::qemu_binary;
+use crate::spec::{TestSpec, DEFAULT_ARCH};
+use anyhow::{bail, Context, Result};
+use auton_core::manifest::BuildManifest;
+use std::path::{Path, PathBuf};
+
+/// OVMF builds searched for UEFI boots without `firmware`: combined images
+/// first, then split code images.
+pub const OVMF_PATHS: &[&str] = &[
+    "/usr/share/ovmf/OVMF.fd",
+    "/usr/share/qemu/OVMF.fd",
+    "/usr/share/OVMF/OVMF_CODE.fd",
+    "/usr/share/OVMF/OVMF_CODE_4M.fd",
+    "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+    "/usr/share/edk2/x64/OVMF_CODE.fd",
+    "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd",
+];
+
+/// Firmware the guest boots under.
+pub use auton_core::manifest::BootMode as Boot;
+
+/// A resolved machine.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Machine {
+    pub arch: String,
+    pub binary: String,
+    pub machine: Option<String>,
+    pub cpu: Option<String>,
+    /// Arch- or build-specific flags (e.g. `-bios default`).
+    pub extra: Vec<String>,
+    pub boot: Boot,
+    /// OVMF image for UEFI boots.
+    pub firmware: Option<PathBuf>,
+}
+
+impl Machine {
+    /// The defaults for `arch` (see the module docs).
+    pub fn for_arch(arch: &str) -> Result<Self> {
+        let binary =
+            qemu_binary(arch).with_context(|| format!("unsupported architecture: {arch}"))?;
+        let (machine, cpu, extra): (_, _, &[&str]) = match arch {
+            "aarch64" => (Some("virt"), Some("cortex-a53"), &[]),
+            "riscv64" => (Some("virt"), None, &["-bios", "default"]),
+            _ => (None, None, &[]),
+        };
+        Ok(Self {
+            arch: arch.to_string(),
+            binary: binary.to_string(),
+            machine: machine.map(str::to_string),
+            cpu: cpu.map(str::to_string),
+            extra: extra.iter().map(|s| s.to_string()).collect(),
+            boot: Boot::Bios,
+            firmware: None,
+        })
+    }
+
+    /// The machine for `spec` booting `kernel`: the spec's settings over
+    /// the build manifest's over the arch defaults.
+    pub fn resolve(spec: &TestSpec, kernel: &Path) -> Result<Self> {
+        Self::with_manifest(spec, BuildManifest::beside(kernel).unwrap_or_default())
+    }
+
+    fn with_manifest(spec: &TestSpec, manifest: BuildManifest) -> Result<Self> {
+        let arch = spec
+            .arch
+            .clone()
+            .or(manifest.arch().map(str::to_string))
+            .unwrap_or_else(|| DEFAULT_ARCH.to_string());
+        let mut m = Self::for_arch(&arch)?;
+        // A manifest for another arch (overridden by the spec) says nothing
+        // about this machine.
+        let same_arch = manifest.arch().is_none_or(|a| a == arch);
+        if same_arch {
+            let q = manifest.qemu;
+            if !q.binary.is_empty() {
+                m.binary = q.binary;
+            }
+            m.machine = q.machine.or(m.machine);
+            m.cpu = q.cpu.or(m.cpu);
+            if !q.extra.is_empty() {
+                m.extra = q.extra;
+            }
+        }
+        m.machine = spec.machine.clone().or(m.machine);
+        m.boot = spec
+            .boot
+            .or(manifest.boot.filter(|_| same_arch))
+            .unwrap_or_default();
+        if m.boot == Boot::Uefi {
+            if arch != "x86_64" {
+                bail!("UEFI boot is only supported on x86_64, not {arch}");
+            }
+            m.firmware = Some(match &spec.firmware {
+                Some(f) => f.clone(),
+                None => find_ovmf().context(
+                    "UEFI boot needs OVMF: install ovmf (edk2-ovmf) or set `firmware` (--firmware)",
+                )?,
+            });
+        }
+        Ok(m)
+    }
+
+    /// Flags for the CPU, firmware and extras (the machine type goes
+    /// through [`crate::qemu_args`]).
+    pub fn qemu_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(cpu) = &self.cpu {
+            args.extend(["-cpu".to_string(), cpu.clone()]);
+        }
+        if let Some(fw) = &self.firmware {
+            args.extend(firmware_args(fw));
+        }
+        args.extend(self.extra.iter().cloned());
+        args
+    }
+}
+
+/// The first of [`OVMF_PATHS`] installed.
+pub fn find_ovmf() -> Option<PathBuf> {
+    OVMF_PATHS.iter().map(PathBuf::from).find(|p| p.is_file())
+}
+
+/// `-bios` for a combined OVMF image, a read-only code flash for a split
+/// `*CODE*` one; without a vars flash OVMF keeps variables in memory, so
+/// runs cannot leak boot entries into each other.
+fn firmware_args(fw: &Path) -> Vec<String> {
+    let name = fw.file_name().unwrap_or_default().to_string_lossy();
+    if name.contains("CODE") {
+        vec![
+            "-drive".to_string(),
+            format!(
+                "if=pflash,format=raw,unit=0,readonly=on,file={}",
+                fw.display()
+            ),
+        ]
+    } else {
+        vec!["-bios".to_string(), fw.display().to_string()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(json: &str) -> BuildManifest {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn manifest_arch_picks_binary_and_machine() {
+        let m = Machine::with_manifest(
+            &TestSpec::default(),
+            manifest(r#"{"arch": "aarch64", "qemu": {"binary": "qemu-system-aarch64", "machine": "virt", "cpu": "cortex-a72"}, "kernel": "k"}"#),
+        )
+        .unwrap();
+        assert_eq!(m.binary, "qemu-system-aarch64");
+        assert_eq!(m.machine.as_deref(), Some("virt"));
+        assert_eq!(m.qemu_args(), ["-cpu", "cortex-a72"]);
+
+        let riscv =
+            Machine::with_manifest(&TestSpec::default(), manifest(r#"{"arch": "riscv64"}"#))
+                .unwrap();
+        assert_eq!(riscv.qemu_args(), ["-bios", "default"]);
+
+        // The spec wins, and another arch's QEMU settings do not leak in.
+        let spec = TestSpec {
+            arch: Some("x86_64".into()),
+            ..Default::default()
+        };
+        let x86 = Machine::with_manifest(
+            &spec,
+            manifest(r#"{"arch": "aarch64", "boot": "uefi", "qemu": {"machine": "virt"}}"#),
+        )
+        .unwrap();
+        assert_eq!(x86, Machine::for_arch("x86_64").unwrap());
+        assert!(Machine::for_arch("sparc").is_err());
+    }
+
+    #[test]
+    fn uefi_boots_ovmf() {
+        let spec = TestSpec {
+            firmware: Some("/fw/OVMF.fd".into()),
+            ..Default::default()
+        };
+        let m = Machine::with_manifest(&spec, manifest(r#"{"arch": "x86_64", "boot": "uefi"}"#))
+            .unwrap();
+        assert_eq!(m.boot, Boot::Uefi);
+        assert_eq!(m.qemu_args(), ["-bios", "/fw/OVMF.fd"]);
+        let code = firmware_args(Path::new("/nonexistent/OVMF_CODE.fd"));
+        assert_eq!(
+            code,
+            [
+                "-drive",
+                "if=pflash,format=raw,unit=0,readonly=on,file=/nonexistent/OVMF_CODE.fd"
+            ]
+        );
+        let arm = TestSpec {
+            arch: Some("aarch64".into()),
+            boot: Some(Boot::Uefi),
+            ..spec
+        };
+        assert!(Machine::with_manifest(&arm, BuildManifest::default()).is_err());
+    }
+}
@@ -1,91 +1,1162 @@
 //! test-runner: boot a kernel image in QEMU, capture serial, parse results.
 
-use anyhow::{Context, Result};
-use clap::Parser;
-use std::path::PathBuf;
-use std::process::Stdio;
+use anyhow::{bail, Context, Result};
+use auton_core::plan::Plan;
+use auton_core::store::ArtifactStore;
+use clap::{Args, Parser, Subcommand};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
-use test_runner::{parse_serial, qemu_args, qemu_binary};
-use tokio::process::Command;
-use tokio::time::timeout;
+use test_runner::accel::Accel;
+use test_runner::bench::{self, Baseline};
+use test_runner::capture::DEFAULT_CAPACITY;
+use test_runner::coverage::{self, CoverageTarget};
+use test_runner::exitdev::ExitDevice;
+use test_runner::expect::Matched;
+use test_runner::flaky::{self, History};
+use test_runner::fuzz::{self, FuzzOptions, Fuzzer};
+use test_runner::golden::{GoldenResult, Normalize};
+use test_runner::known;
+use test_runner::machine::Boot;
+use test_runner::qemu::{self, ExitReason, RunResult, Shutdown};
+use test_runner::qmp::MemoryRange;
+use test_runner::replay;
+use test_runner::report::{self, ReportTarget};
+use test_runner::results::{self, Store};
+use test_runner::soak::{self, Monitor, SoakReport};
+use test_runner::spec::TestSpec;
+use test_runner::suite::{self, SuiteOptions, SuiteTest, TestOutcome};
+use test_runner::timeouts::{self, Learned, Policy};
+use test_runner::trace::{TraceEvent, TraceSummary};
+use test_runner::TestSummary;
+use test_runner::{snapshot, symbolize};
+use tracing::Instrument;
 
 #[derive(Parser)]
 #[command(name = "test-runner", about = "QEMU-based kernel test execution")]
 struct Cli {
-    /// Path to the kernel image (.iso boots via -cdrom, else -kernel).
-    #[arg(short, long)]
-    kernel: PathBuf,
+    #[command(subcommand)]
+    cmd: Option<Cmd>,
 
-    /// Target architecture (selects the qemu binary).
-    #[arg(short, long, default_value = "x86_64")]
-    arch: String,
+    /// Path to the kernel image (.iso boots via -cdrom, else -kernel); for
+    /// `suite`, the image for specs that name none.
+    #[arg(short, long, global = true)]
+    kernel: Option<PathBuf>,
 
-    /// QEMU machine type (e.g. "virt" for aarch64/riscv64).
+    /// Test spec TOML (keys: the long flag names, e.g. expect, forbid,
+    /// timeout, exit-device); the flags below override or add to it.
     #[arg(long)]
-    machine: Option<String>,
+    spec: Option<PathBuf>,
 
-    /// Memory in MiB.
-    #[arg(long, default_value = "128")]
-    memory: u32,
+    #[command(flatten)]
+    overrides: SpecArgs,
 
-    /// Timeout in seconds (a hang counts as failure).
-    #[arg(short, long, default_value = "60")]
-    timeout: u64,
+    /// Maximum transcript size in bytes; older output is dropped.
+    #[arg(long, global = true, default_value_t = DEFAULT_CAPACITY)]
+    max_transcript: usize,
 
     /// Emit the summary as JSON.
-    #[arg(long)]
+    #[arg(long, global = true)]
     json: bool,
+
+    /// Also write a report: `junit:<path>`, `json:<path>` or an HTML
+    /// report directory, `html:<dir>` (repeatable).
+    #[arg(long, global = true, value_name = "FORMAT:PATH")]
+    report: Vec<ReportTarget>,
+
+    /// Record the guest code each run executes (under TCG) and write a
+    /// coverage report of the kernel's source lines: `lcov:<path>` or
+    /// `cobertura:<path>` (repeatable).
+    #[arg(long, global = true, value_name = "FORMAT:PATH")]
+    coverage: Vec<CoverageTarget>,
+
+    /// Transcript bytes kept per test in report files (the tail is kept).
+    #[arg(long, global = true, default_value_t = report::DEFAULT_TRANSCRIPT_LIMIT)]
+    report_transcript: usize,
+
+    /// Record every attempt in the history file and flag tests that have
+    /// both passed and failed recently as flaky.
+    #[arg(long, global = true)]
+    detect_flaky: bool,
+
+    /// Pass/fail history for --detect-flaky and --adaptive-timeout.
+    #[arg(long, global = true, default_value = flaky::DEFAULT_HISTORY)]
+    history: PathBuf,
+
+    /// Time every run into the history file, and give tests with enough
+    /// timed passes a timeout learned from them in place of their spec's
+    /// (unless --timeout is given).
+    #[arg(long, global = true)]
+    adaptive_timeout: bool,
+
+    /// A learned timeout is this many times the p99 of a test's passing
+    /// durations.
+    #[arg(long, global = true, value_name = "FACTOR", default_value_t = timeouts::DEFAULT_FACTOR)]
+    adaptive_factor: f64,
+
+    /// Shortest learned timeout, in seconds.
+    #[arg(long, global = true, value_name = "SECS", default_value_t = timeouts::DEFAULT_FLOOR_SECS)]
+    adaptive_floor: u64,
+
+    /// Longest learned timeout, in seconds.
+    #[arg(long, global = true, value_name = "SECS", default_value_t = timeouts::DEFAULT_CEILING_SECS)]
+    adaptive_ceiling: u64,
+
+    /// Where each run's serial log, QEMU command line and status are
+    /// stored, one `<timestamp>-<test>` directory per run.
+    #[arg(long, global = true, default_value = results::DEFAULT_DIR)]
+    results_dir: PathBuf,
+
+    /// Also add each run's files to this artifact store, with a `runs` ref
+    /// recording them and the kernel by hash.
+    #[arg(long, global = true, value_name = "DIR")]
+    store: Option<PathBuf>,
+
+    /// Run directories kept per test in --results-dir; 0 stores nothing.
+    #[arg(long, global = true, value_name = "N", default_value_t = results::DEFAULT_KEEP)]
+    keep_last: usize,
+
+    /// Cache directory for `--snapshot-at` images [default:
+    /// $TMPDIR/test-runner-snapshots].
+    #[arg(long, global = true, value_name = "DIR")]
+    snapshot_dir: Option<PathBuf>,
+
+    /// Print every command a run or `suite` would execute (the kernel
+    /// builds, QEMU with its full arguments) and the files it would write,
+    /// and exit without running anything.
+    #[arg(long, global = true)]
+    dry_run: bool,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Run every `*.toml` test spec in a directory and print a summary table.
+    Suite(SuiteArgs),
+    /// Boot the kernel repeatedly, time it to a serial marker, and compare
+    /// with a stored baseline.
+    Bench(BenchArgs),
+    /// Boot the kernel over and over with mutated inputs, as the spec's
+    /// `[fuzz]` table says, and shrink and store the ones that crash it.
+    Fuzz(FuzzArgs),
+    /// Boot the kernel once and keep it running for a long time, failing
+    /// on the health conditions in the spec's `[soak]` table.
+    Soak(SoakArgs),
+    /// Replay a failed run recorded with `--record`, optionally under a
+    /// debugger (`--gdb`, `--gdb-script`) that can also run backwards.
+    Replay(ReplayArgs),
+}
+
+/// Settings shared with spec files; on the command line they override the
+/// spec (patterns add to it).
+#[derive(Args)]
+struct SpecArgs {
+    /// Target architecture (selects the qemu binary) [default: the build
+    /// manifest's, else x86_64].
+    #[arg(short, long, global = true)]
+    arch: Option<String>,
+
+    /// QEMU machine type [default: the build manifest's, else "virt" on
+    /// aarch64/riscv64].
+    #[arg(long, global = true)]
+    machine: Option<String>,
+
+    /// Firmware: bios, or uefi to boot OVMF (x86_64) [default: the build
+    /// manifest's, else bios].
+    #[arg(long, global = true, value_enum)]
+    boot: Option<Boot>,
+
+    /// OVMF image for --boot uefi [default: searched in /usr/share].
+    #[arg(long, global = true, value_name = "PATH")]
+    firmware: Option<PathBuf>,
+
+    /// Memory in MiB [default: 128].
+    #[arg(long, global = true)]
+    memory: Option<u32>,
+
+    /// vCPUs per VM (`-smp`) [default: QEMU's, 1].
+    #[arg(long, global = true)]
+    smp: Option<u32>,
+
+    /// Disk image attached as a virtio-blk drive; writes go to a per-run
+    /// qcow2 overlay, never to IMAGE.
+    #[arg(long, global = true, value_name = "IMAGE")]
+    disk: Option<PathBuf>,
+
+    /// `-netdev` backend for a virtio-net NIC, e.g.
+    /// `user,hostfwd=tcp::5555-:22`.
+    #[arg(long, global = true, value_name = "BACKEND")]
+    net: Option<String>,
+
+    /// Accelerator [default: auto: KVM when /dev/kvm is usable for the
+    /// guest's architecture, else TCG with a warning].
+    #[arg(long, global = true, value_enum)]
+    accel: Option<Accel>,
+
+    /// Multiply timeouts by N when TCG runs a guest KVM could have run
+    /// [default: 4].
+    #[arg(long, global = true, value_name = "N")]
+    tcg_timeout_factor: Option<u32>,
+
+    /// Extra QEMU argument, appended last (repeatable).
+    #[arg(long, global = true, allow_hyphen_values = true)]
+    qemu_arg: Vec<String>,
+
+    /// Timeout in seconds (a hang counts as failure) [default: 60].
+    #[arg(short, long, global = true)]
+    timeout: Option<u64>,
+
+    /// Seconds without any serial output after which the run is a hang
+    /// [default: off].
+    #[arg(long, global = true)]
+    idle_timeout: Option<u64>,
+
+    /// Regex that must match, in order with other `--expect`s (repeatable;
+    /// default `\[BOOT\] OK` if no expect pattern is given).
+    #[arg(long, global = true)]
+    expect: Vec<String>,
+
+    /// Regex that must match at some point, in any order (repeatable).
+    #[arg(long, global = true)]
+    expect_any: Vec<String>,
+
+    /// Regex that fails the run as soon as it matches (repeatable).
+    #[arg(long, global = true)]
+    forbid: Vec<String>,
+
+    /// Regex marking a kernel panic (repeatable; replaces the defaults:
+    /// PANIC, panic:, unhandled exception).
+    #[arg(long, global = true)]
+    panic_pattern: Vec<String>,
+
+    /// Lines of context shown around a forbidden or panic line [default: 3].
+    #[arg(long, global = true)]
+    context: Option<usize>,
+
+    /// Compare the (normalized) serial output with this golden transcript
+    /// and fail with a unified diff on mismatch.
+    #[arg(long, global = true, value_name = "FILE")]
+    golden: Option<PathBuf>,
+
+    /// Regex after whose first match the golden section starts [default:
+    /// the first line].
+    #[arg(long, global = true, value_name = "REGEX")]
+    golden_start: Option<String>,
+
+    /// Regex whose next match ends the golden section [default: the last
+    /// line].
+    #[arg(long, global = true, value_name = "REGEX")]
+    golden_end: Option<String>,
+
+    /// Substitution applied to each golden line after the defaults (long
+    /// hex addresses, timestamps, durations); repeatable.
+    #[arg(long, global = true, value_name = "REGEX=REPLACEMENT")]
+    normalize: Vec<Normalize>,
+
+    /// Write the run's normalized section to the --golden file instead of
+    /// comparing (only if the run otherwise passed).
+    #[arg(long, global = true)]
+    bless: bool,
+
+    /// Exit device the guest can end the run with [default: auto:
+    /// isa-debug-exit on x86_64, semihosting on aarch64/riscv64].
+    #[arg(long, global = true, value_enum)]
+    exit_device: Option<ExitDevice>,
+
+    /// Value the guest writes to isa-debug-exit to pass (decimal or 0x hex)
+    /// [default: 0x10].
+    #[arg(long, global = true, value_parser = parse_u32)]
+    exit_success: Option<u32>,
+
+    /// Keep running after the expected patterns until the guest exits
+    /// through the exit device.
+    #[arg(long, global = true)]
+    wait_exit: bool,
+
+    /// symbols.json or kernel ELF for symbolizing crash addresses
+    /// [default: the build's, via manifest.json beside the image].
+    #[arg(long, global = true)]
+    symbols: Option<PathBuf>,
+
+    /// Start QEMU paused with a gdbstub and wait for a debugger to attach
+    /// (no timeout unless given).
+    #[arg(long, global = true)]
+    gdb: bool,
+
+    /// GDB script to run in batch mode against the paused target; its
+    /// transcript goes into the report.
+    #[arg(long, global = true)]
+    gdb_script: Option<PathBuf>,
+
+    /// gdbstub port [default: 1234, or a free one for --gdb-script].
+    #[arg(long, global = true)]
+    gdb_port: Option<u16>,
+
+    /// Log interrupts and CPU resets (`-d int,cpu_reset`) so a triple fault
+    /// is reported with the faulting RIP and registers. Noisy: every IRQ is
+    /// logged.
+    #[arg(long, global = true)]
+    cpu_log: bool,
+
+    /// Trace QEMU events to a kept log and summarize them after the run:
+    /// int (interrupts and exceptions), mmio (accesses, invalid and
+    /// unimplemented ones), pic (i8259/APIC events); comma-separated.
+    #[arg(long, global = true, value_enum, value_delimiter = ',')]
+    trace: Vec<TraceEvent>,
+
+    /// Run QEMU on this host over SSH: the image and spec files are copied
+    /// there, serial output streams back, and QEMU is reaped on cancel.
+    #[arg(long, global = true, value_name = "[USER@]HOST")]
+    remote: Option<String>,
+
+    /// Run under TCG with `-icount`, a fixed RTC and a fixed RNG seed, so
+    /// the run can be replayed exactly; the seed is reported.
+    #[arg(long, global = true)]
+    deterministic: bool,
+
+    /// Seed for --deterministic (implies it), e.g. a failed run's to
+    /// replay it [default: from the clock].
+    #[arg(long, global = true, value_name = "SEED")]
+    deterministic_seed: Option<u64>,
+
+    /// Record each run under TCG with QEMU record/replay; a failing run's
+    /// recording is kept for `test-runner replay`.
+    #[arg(long, global = true)]
+    record: bool,
+
+    /// Rerun a failing test up to N more times; passing on a rerun marks
+    /// it flaky [default: 0].
+    #[arg(long, global = true, value_name = "N")]
+    retries: Option<u32>,
+
+    /// Boot once until this regex, snapshot the VM, and start the test
+    /// from the snapshot (cached per kernel; patterns then only see later
+    /// output).
+    #[arg(long, global = true, value_name = "REGEX")]
+    snapshot_at: Option<String>,
+
+    /// Guest physical memory to dump on failure, ADDR:LEN (repeatable; at
+    /// most 64 KiB each). CPU registers are always dumped.
+    #[arg(long, global = true, value_name = "ADDR:LEN")]
+    dump_memory: Vec<MemoryRange>,
+
+    /// On failure, write all of guest RAM as an ELF core for
+    /// `gdb <kernel> <core>` (into the run's results directory, if kept).
+    #[arg(long, global = true)]
+    dump_on_failure: bool,
+
+    /// Save a screenshot of the guest display to DIR/<test>.ppm on failure.
+    #[arg(long, global = true, value_name = "DIR")]
+    screenshot_dir: Option<PathBuf>,
+}
+
+impl SpecArgs {
+    fn to_spec(&self) -> TestSpec {
+        TestSpec {
+            arch: self.arch.clone(),
+            machine: self.machine.clone(),
+            boot: self.boot,
+            firmware: self.firmware.clone(),
+            memory: self.memory,
+            smp: self.smp,
+            disk: self.disk.clone(),
+            net: self.net.clone(),
+            accel: self.accel,
+            tcg_timeout_factor: self.tcg_timeout_factor,
+            qemu_args: self.qemu_arg.clone(),
+            timeout: self.timeout,
+            idle_timeout: self.idle_timeout,
+            expect: self.expect.clone(),
+            expect_any: self.expect_any.clone(),
+            forbid: self.forbid.clone(),
+            panic_pattern: self.panic_pattern.clone(),
+            context: self.context,
+            golden: self.golden.clone(),
+            golden_start: self.golden_start.clone(),
+            golden_end: self.golden_end.clone(),
+            normalize: self.normalize.clone(),
+            bless: self.bless.then_some(true),
+            exit_device: self.exit_device,
+            exit_success: self.exit_success,
+            wait_exit: self.wait_exit.then_some(true),
+            cpu_log: self.cpu_log.then_some(true),
+            trace: self.trace.clone(),
+            symbols: self.symbols.clone(),
+            gdb: self.gdb.then_some(true),
+            gdb_script: self.gdb_script.clone(),
+            gdb_port: self.gdb_port,
+            dump_memory: self.dump_memory.clone(),
+            dump_on_failure: self.dump_on_failure.then_some(true),
+            screenshot_dir: self.screenshot_dir.clone(),
+            snapshot_at: self.snapshot_at.clone(),
+            retries: self.retries,
+            remote: self.remote.clone(),
+            deterministic: self.deterministic.then_some(true),
+            deterministic_seed: self.deterministic_seed,
+            record: self.record.then_some(true),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    /// Boots measured.
+    #[arg(short = 'n', long, default_value_t = bench::DEFAULT_RUNS)]
+    runs: u32,
+
+    /// Unmeasured boots first (warm caches).
+    #[arg(long, default_value_t = 0)]
+    warmup: u32,
+
+    /// Regex for the serial line that ends boot.
+    #[arg(long, value_name = "REGEX", default_value = qemu::DEFAULT_EXPECT)]
+    marker: String,
+
+    /// Stored baseline to compare against.
+    #[arg(long, default_value = bench::DEFAULT_BASELINE)]
+    baseline: PathBuf,
+
+    /// Fail when the median boot is more than PCT percent slower than the
+    /// baseline's.
+    #[arg(long, value_name = "PCT", default_value_t = bench::DEFAULT_THRESHOLD_PCT)]
+    threshold: u32,
+
+    /// Store this run's timings as the new baseline.
+    #[arg(long)]
+    save_baseline: bool,
+}
+
+#[derive(Serialize)]
+struct BenchReport<'a> {
+    marker: &'a str,
+    accel: Accel,
+    stats: &'a bench::Stats,
+    samples_ms: &'a [u64],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comparison: Option<&'a bench::Comparison>,
+}
+
+#[derive(Args)]
+struct FuzzArgs {
+    /// Inputs tried.
+    #[arg(short = 'n', long, default_value_t = fuzz::DEFAULT_ITERATIONS)]
+    iterations: u32,
+
+    /// Mutation seed [default: from the clock; printed either way].
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Reproducers go to DIR/<test>/, seed inputs come from
+    /// DIR/<test>/seeds/.
+    #[arg(long, value_name = "DIR", default_value = fuzz::DEFAULT_CORPUS)]
+    corpus: PathBuf,
+
+    /// Reruns spent shrinking each crashing input.
+    #[arg(long, default_value_t = fuzz::DEFAULT_MINIMIZE_RUNS)]
+    minimize_runs: u32,
+
+    /// Run one stored input (a reproducer) instead of fuzzing.
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct FuzzReport<'a> {
+    seed: u64,
+    runs: u32,
+    crashes: Vec<FuzzCrash<'a>>,
+}
+
+#[derive(Serialize)]
+struct FuzzCrash<'a> {
+    kind: &'a str,
+    reproducer: &'a Path,
+    /// The input with non-ASCII bytes escaped.
+    input: String,
+    new: bool,
+}
+
+#[derive(Args)]
+struct SoakArgs {
+    /// How long to run, e.g. `30m` or `2h` [default: the spec's
+    /// `duration`, else 1h].
+    #[arg(long, value_parser = kernel_builder::clean::parse_duration)]
+    duration: Option<Duration>,
+}
+
+#[derive(Args)]
+struct ReplayArgs {
+    /// A failed recorded run's `replay.json`, or its results directory.
+    file: PathBuf,
+}
+
+#[derive(Args)]
+struct SuiteArgs {
+    /// Directory of test specs.
+    #[arg(default_value = "tests")]
+    dir: PathBuf,
+
+    /// Concurrent QEMU instances [default: available CPUs, at most 4].
+    #[arg(short = 'j', long)]
+    parallel: Option<usize>,
+
+    /// Only run tests whose name contains this string.
+    #[arg(long)]
+    filter: Option<String>,
+
+    /// kernel-builder for `[build]` specs [default: next to test-runner,
+    /// else PATH].
+    #[arg(long)]
+    kernel_builder: Option<PathBuf>,
+
+    /// Where `[build]` specs are built (one numbered directory per build).
+    #[arg(long, default_value = "build/suite")]
+    build_dir: PathBuf,
+
+    /// Host memory in MiB the running VMs may use together, guest RAM plus
+    /// QEMU overhead [default: 80% of available memory].
+    #[arg(long, value_name = "MIB")]
+    max_memory: Option<u64>,
+
+    /// vCPUs the running VMs may use together [default: host CPUs].
+    #[arg(long, value_name = "N")]
+    max_cpus: Option<u32>,
+
+    /// Known failures: fail only on tests that newly fail, or fail again
+    /// after passing.
+    #[arg(long, value_name = "FILE")]
+    baseline: Option<PathBuf>,
+
+    /// Make this run's failures the baseline's known ones.
+    #[arg(long, requires = "baseline")]
+    update_baseline: bool,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    #[serde(flatten)]
+    summary: &'a TestSummary,
+    exit_reason: &'a ExitReason,
+    shutdown: Shutdown,
+    duration_ms: u64,
+    matched: &'a [Matched],
+    unmatched: &'a [String],
+    attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flaky: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artifacts: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace: Option<&'a TraceSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    core_dump: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    golden: Option<&'a GoldenResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    soak: Option<&'a SoakReport>,
+    transcript: &'a str,
+    transcript_dropped: u64,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    auton_core::logging::init();
     let cli = Cli::parse();
+    // The root of this run's trace; VMs' spans are children of it.
+    let span = match &cli.cmd {
+        Some(Cmd::Suite(_)) => tracing::info_span!("suite"),
+        Some(Cmd::Bench(_)) => tracing::info_span!("bench"),
+        Some(Cmd::Fuzz(_)) => tracing::info_span!("fuzz"),
+        Some(Cmd::Soak(_)) => tracing::info_span!("soak"),
+        Some(Cmd::Replay(_)) => tracing::info_span!("replay"),
+        None => tracing::info_span!("run"),
+    };
+    async {
+        match &cli.cmd {
+            _ if cli.dry_run => dry_run(&cli).await,
+            Some(Cmd::Suite(args)) => run_suite(&cli, args).await,
+            Some(Cmd::Bench(args)) => run_bench(&cli, args).await,
+            Some(Cmd::Fuzz(args)) => run_fuzz(&cli, args).await,
+            Some(Cmd::Soak(args)) => run_soak(&cli, args).await,
+            Some(Cmd::Replay(args)) => run_replay(&cli, args).await,
+            None => run_single(&cli).await,
+        }
+    }
+    .instrument(span)
+    .await
+}
 
-    let qemu = qemu_binary(&cli.arch)
-        .with_context(|| format!("unsupported architecture: {}", cli.arch))?;
-    let image = cli.kernel.display().to_string();
-    let args = qemu_args(&image, cli.machine.as_deref(), cli.memory);
+/// The spec the flags amount to.
+fn overrides(cli: &Cli) -> TestSpec {
+    let mut spec = cli.overrides.to_spec();
+    if !cli.coverage.is_empty() {
+        spec.coverage = Some(true);
+    }
+    spec
+}
 
-    tracing::info!(qemu, image = %image, "launching QEMU");
+/// The `--spec` file with the flags merged in, and the kernel to boot.
+fn single_spec(cli: &Cli) -> Result<(TestSpec, PathBuf)> {
+    let mut spec = match &cli.spec {
+        Some(path) => TestSpec::load(path)?,
+        None => TestSpec::default(),
+    };
+    spec.merge(overrides(cli));
+    if !spec.cpus.is_empty() && spec.smp.is_none() {
+        bail!("a `cpus` matrix runs under `suite`; pick one count with --smp");
+    }
+    if !spec.mem.is_empty() && spec.memory.is_none() {
+        bail!("a `mem` matrix runs under `suite`; pick one size with --memory");
+    }
+    let Some(kernel) = cli.kernel.clone().or(spec.kernel.clone()) else {
+        bail!("no kernel image: pass --kernel or set `kernel` in the spec");
+    };
+    Ok((spec, kernel))
+}
 
-    let child = Command::new(qemu)
-        .args(&args)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .spawn()
-        .with_context(|| format!("failed to spawn {qemu} (is it installed?)"))?;
+async fn run_single(cli: &Cli) -> Result<()> {
+    let (spec, kernel) = single_spec(cli)?;
+    let image = match &spec.snapshot_at {
+        Some(_) => {
+            let dir = cli
+                .snapshot_dir
+                .clone()
+                .unwrap_or_else(snapshot::default_dir);
+            Some(snapshot::ensure(&spec, &kernel, &dir, cli.max_transcript).await?)
+        }
+        None => None,
+    };
+    // A debugging session is not rerun.
+    let interactive = spec.gdb == Some(true) && spec.gdb_script.is_none();
+    let tries = if interactive {
+        1
+    } else {
+        1 + spec.retries.unwrap_or(0)
+    };
+    let mut attempt = 1;
+    let (result, artifacts) = loop {
+        let (result, artifacts) = run_attempt(cli, &spec, &kernel, image.as_deref(), None).await?;
+        if attempt >= tries || result.passed(&result.summary()) {
+            break (result, artifacts);
+        }
+        eprintln!(
+            "test-runner: attempt {attempt} of {tries} ended with {}; retrying",
+            result.reason.name()
+        );
+        attempt += 1;
+    };
+    finish_single(cli, &spec, &kernel, result, artifacts, attempt).await
+}
 
-    let output = match timeout(Duration::from_secs(cli.timeout), child.wait_with_output()).await {
-        Ok(res) => res.context("waiting for QEMU")?,
-        Err(_) => {
-            // Timed out: the kernel likely hung. Report a clean failure.
-            eprintln!(
-                "test-runner: QEMU timed out after {}s (possible hang)",
-                cli.timeout
-            );
-            std::process::exit(2);
+/// Report a single run's `result` after `attempt` attempts, and exit with
+/// its status.
+async fn finish_single(
+    cli: &Cli,
+    spec: &TestSpec,
+    kernel: &Path,
+    result: RunResult,
+    artifacts: Option<PathBuf>,
+    attempt: u32,
+) -> Result<()> {
+    let summary = result.summary();
+    let success = result.passed(&summary);
+    let name = spec.test_name(kernel);
+    let spec_path = cli.spec.clone().unwrap_or_default();
+    let mut outcome =
+        TestOutcome::from_result(name, spec_path, kernel.to_path_buf(), result.clone());
+    outcome.attempts = attempt;
+    outcome.flaky = flaky::retry_verdict(attempt, success);
+    outcome.artifacts = artifacts;
+    let outcomes = std::slice::from_mut(&mut outcome);
+    record_history(cli, outcomes)?;
+    report::write_all(&cli.report, outcomes, cli.report_transcript, &cli.history)?;
+    write_coverage(cli, outcomes).await?;
+    if cli.json {
+        let report = Report {
+            summary: &summary,
+            exit_reason: &result.reason,
+            shutdown: result.shutdown,
+            duration_ms: result.duration_ms,
+            matched: &result.matched,
+            unmatched: &result.unmatched,
+            attempts: outcome.attempts,
+            flaky: outcome.flaky.as_deref(),
+            artifacts: outcome.artifacts.as_deref(),
+            trace: result.trace.as_ref(),
+            core_dump: result.dump.as_ref().and_then(|d| d.core.as_deref()),
+            golden: result.golden.as_ref(),
+            soak: result.soak.as_ref(),
+            transcript: &result.transcript,
+            transcript_dropped: result.transcript_dropped,
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_human(&summary, &result, success);
+        if let Some(why) = &outcome.flaky {
+            eprintln!("test-runner: flaky: {why}");
+        }
+        if let Some(dir) = outcome.artifacts.as_ref().filter(|_| !success) {
+            eprintln!("test-runner: artifacts: {}", dir.display());
+        }
+    }
+
+    match result.reason {
+        // A hung kernel keeps its own exit code.
+        ExitReason::Timeout { .. } | ExitReason::Hang { .. } => std::process::exit(2),
+        _ if !success => std::process::exit(1),
+        _ => Ok(()),
+    }
+}
+
+/// One boot of `kernel` (from the snapshot `image` if given, as a soak run
+/// with `soak`), symbolized, and the directory its artifacts were stored
+/// in.
+async fn run_attempt(
+    cli: &Cli,
+    spec: &TestSpec,
+    kernel: &Path,
+    image: Option<&Path>,
+    soak: Option<Monitor>,
+) -> Result<(RunResult, Option<PathBuf>)> {
+    let mut cfg = spec.run_config(kernel, cli.max_transcript)?;
+    match soak {
+        Some(monitor) => soak::configure(&mut cfg, monitor),
+        None => {
+            if let Some(learned) = learned_timeouts(cli)? {
+                learned.apply(&spec.test_name(kernel), &mut cfg);
+            }
+        }
+    }
+    let fork = match image {
+        Some(image) => Some(snapshot::fork(&mut cfg, image)?),
+        None => None,
+    };
+
+    let store = results_store(cli);
+    let run_dir = match &store {
+        Some(store) => Some(store.create(&spec.test_name(kernel), &mut cfg)?),
+        None => None,
+    };
+
+    tracing::info!(qemu = %cfg.program, image = %kernel.display(), "launching QEMU");
+    let mut result = launch(&cfg).await?;
+    drop(fork);
+    symbolize::annotate(&mut result, spec.symbols.as_deref(), kernel).await;
+    let artifacts = match (store, run_dir) {
+        (Some(store), Some(dir)) => {
+            store.finish(&dir, kernel, &result)?;
+            Some(dir.path)
+        }
+        _ => None,
+    };
+    Ok((result, artifacts))
+}
+
+/// Run `cfg`, which waits for a debugger to attach if it has an
+/// interactive GDB session.
+async fn launch(cfg: &qemu::RunConfig) -> Result<RunResult> {
+    let Some(gdb) = cfg.gdb.as_ref().filter(|g| g.script.is_none()) else {
+        return qemu::run(cfg).await;
+    };
+    eprintln!("{}", gdb.instructions());
+    // QEMU leads its own process group, so Ctrl-C must kill it here.
+    tokio::select! {
+        result = qemu::run(cfg) => result,
+        _ = tokio::signal::ctrl_c() => std::process::exit(130),
+    }
+}
+
+/// Write the `--coverage` reports and say how much was covered.
+async fn write_coverage(cli: &Cli, outcomes: &[TestOutcome]) -> Result<()> {
+    if let Some(summary) = coverage::write_all(&cli.coverage, outcomes).await? {
+        eprintln!("test-runner: coverage: {summary}");
+    }
+    Ok(())
+}
+
+/// The artifact store, unless `--keep-last 0`.
+fn results_store(cli: &Cli) -> Option<Store> {
+    (cli.keep_last > 0).then(|| Store {
+        root: cli.results_dir.clone(),
+        keep: cli.keep_last,
+        artifacts: cli.store.as_ref().map(ArtifactStore::new),
+    })
+}
+
+/// With `--detect-flaky` or `--adaptive-timeout`, add the outcomes to the
+/// history file; with `--detect-flaky`, flag the tests it shows to be
+/// flaky.
+fn record_history(cli: &Cli, outcomes: &mut [TestOutcome]) -> Result<()> {
+    if !cli.detect_flaky && !cli.adaptive_timeout {
+        return Ok(());
+    }
+    let mut history = History::load(&cli.history)?;
+    flaky::record(&mut history, outcomes);
+    if cli.detect_flaky {
+        flaky::flag(&history, outcomes);
+    }
+    history.save(&cli.history)
+}
+
+/// With `--adaptive-timeout` (and no `--timeout`), the timeouts the
+/// history has learned.
+fn learned_timeouts(cli: &Cli) -> Result<Option<Learned>> {
+    if !cli.adaptive_timeout || cli.overrides.timeout.is_some() {
+        return Ok(None);
+    }
+    let policy = Policy {
+        factor: cli.adaptive_factor,
+        floor: Duration::from_secs(cli.adaptive_floor),
+        ceiling: Duration::from_secs(cli.adaptive_ceiling),
+    };
+    Ok(Some(Learned::new(&History::load(&cli.history)?, policy)))
+}
+
+/// `--dry-run`: the plan of a single run or a suite, with the reports and
+/// state files the run would write.
+async fn dry_run(cli: &Cli) -> Result<()> {
+    let mut plan = match &cli.cmd {
+        None => plan_single(cli)?,
+        Some(Cmd::Suite(args)) => {
+            let tests = suite::discover(&args.dir, args.filter.as_deref())?;
+            let mut plan = suite::plan(tests, &suite_options(cli, args)?).await?;
+            if let Some(path) = &args.baseline {
+                plan.write(path);
+            }
+            plan
+        }
+        Some(_) => bail!("--dry-run plans a single run or a suite"),
+    };
+    plan.writes
+        .extend(cli.report.iter().map(|t| t.path.clone()));
+    plan.writes
+        .extend(cli.coverage.iter().map(|t| t.path.clone()));
+    if cli.detect_flaky || cli.adaptive_timeout {
+        plan.write(&cli.history);
+    }
+    if let Some(store) = &cli.store {
+        plan.write(store);
+    }
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&plan)?);
+    } else {
+        print!("{}", plan.render());
+    }
+    Ok(())
+}
+
+/// What [`run_single`] would run and write (its first attempt).
+fn plan_single(cli: &Cli) -> Result<Plan> {
+    let (spec, kernel) = single_spec(cli)?;
+    let mut cfg = spec.run_config(&kernel, cli.max_transcript)?;
+    if let Some(learned) = learned_timeouts(cli)? {
+        learned.apply(&spec.test_name(&kernel), &mut cfg);
+    }
+    let test = SuiteTest {
+        name: spec.test_name(&kernel),
+        path: cli.spec.clone().unwrap_or_default(),
+        spec,
+        matrix: None,
+    };
+    let snapshots = cli
+        .snapshot_dir
+        .clone()
+        .unwrap_or_else(snapshot::default_dir);
+    let mut plan = Plan::default();
+    let store = results_store(cli);
+    suite::plan_boot(&test, &kernel, cfg, &snapshots, store.as_ref(), &mut plan)?;
+    Ok(plan)
+}
+
+async fn run_suite(cli: &Cli, args: &SuiteArgs) -> Result<()> {
+    let tests = suite::discover(&args.dir, args.filter.as_deref())?;
+    let opts = suite_options(cli, args)?;
+    let mut outcomes = suite::run_suite(tests, &opts).await;
+    record_history(cli, &mut outcomes)?;
+    compare_baseline(args, &mut outcomes)?;
+    report::write_all(&cli.report, &outcomes, cli.report_transcript, &cli.history)?;
+    write_coverage(cli, &outcomes).await?;
+
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&outcomes)?);
+    } else {
+        for o in outcomes.iter().filter(|o| !o.passed) {
+            eprint!("{}", suite::render_failure(o));
         }
+        print!("{}", suite::render_table(&outcomes));
+        print!("{}", suite::render_matrix(&outcomes));
+        if let Some(line) = known::describe(&outcomes) {
+            println!("{line}");
+        }
+    }
+    if !args.update_baseline && outcomes.iter().any(known::fails_run) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// The suite settings the flags amount to.
+fn suite_options(cli: &Cli, args: &SuiteArgs) -> Result<SuiteOptions> {
+    if cli.spec.is_some() {
+        bail!("--spec is for single runs; `suite` reads every spec in its directory");
+    }
+    let parallel = args.parallel.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(4)
+    });
+    Ok(SuiteOptions {
+        parallel,
+        kernel_builder: args
+            .kernel_builder
+            .clone()
+            .unwrap_or_else(suite::find_kernel_builder),
+        build_dir: args.build_dir.clone(),
+        kernel: cli.kernel.clone(),
+        overrides: overrides(cli),
+        transcript_limit: cli.max_transcript,
+        max_memory_mb: args.max_memory,
+        max_cpus: args.max_cpus,
+        results: results_store(cli),
+        timeouts: learned_timeouts(cli)?.map(Arc::new),
+        snapshot_dir: cli
+            .snapshot_dir
+            .clone()
+            .unwrap_or_else(snapshot::default_dir),
+    })
+}
+
+/// With `--baseline`, mark the outcomes against the known failures and
+/// save the fixes (and with `--update-baseline`, the new failures).
+fn compare_baseline(args: &SuiteArgs, outcomes: &mut [TestOutcome]) -> Result<()> {
+    let Some(path) = &args.baseline else {
+        return Ok(());
     };
+    let mut baseline = known::Baseline::load(path)?;
+    if baseline.apply(outcomes, args.update_baseline) {
+        baseline.save(path)?;
+        eprintln!("test-runner: baseline {} updated", path.display());
+    }
+    Ok(())
+}
 
-    let serial = String::from_utf8_lossy(&output.stdout);
-    let summary = parse_serial(&serial);
+async fn run_bench(cli: &Cli, args: &BenchArgs) -> Result<()> {
+    if args.runs == 0 {
+        bail!("--runs must be at least 1");
+    }
+    let (spec, kernel) = single_spec(cli)?;
+    let (samples, accel) = bench::measure(
+        &spec,
+        &kernel,
+        &args.marker,
+        args.runs,
+        args.warmup,
+        cli.max_transcript,
+    )
+    .await?;
+    let current = Baseline::new(&args.marker, accel, samples)?;
+    let baseline = Baseline::load(&args.baseline)?;
+    if let Some(b) = &baseline {
+        if b.marker != args.marker {
+            eprintln!(
+                "test-runner: warning: baseline was recorded to `{}`, not `{}`",
+                b.marker, args.marker
+            );
+        }
+        if b.accel != accel {
+            eprintln!(
+                "test-runner: warning: baseline ran under {}, this run under {}: not comparable",
+                b.accel.name(),
+                accel.name()
+            );
+        }
+    }
+    let comparison = baseline
+        .as_ref()
+        .map(|b| bench::Comparison::new(&b.stats, &current.stats, args.threshold));
 
     if cli.json {
-        println!("{}", serde_json::to_string_pretty(&summary)?);
+        let report = BenchReport {
+            marker: &args.marker,
+            accel,
+            stats: &current.stats,
+            samples_ms: &current.samples_ms,
+            comparison: comparison.as_ref(),
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
     } else {
         println!(
-            "boot_ok={} tests {}/{} passed",
-            summary.boot_ok, summary.passed, summary.total
+            "boot to `{}` over {} runs ({}): {}",
+            args.marker,
+            current.stats.runs,
+            accel.name(),
+            current.stats.describe()
         );
-        for t in &summary.tests {
-            let status = if t.passed { "PASS" } else { "FAIL" };
-            println!("  {status} {} {}", t.name, t.message);
+        match &comparison {
+            Some(c) => println!("{}", c.describe()),
+            None if !args.save_baseline => eprintln!(
+                "test-runner: no baseline at {}; record one with --save-baseline",
+                args.baseline.display()
+            ),
+            None => {}
         }
     }
+    if args.save_baseline {
+        current.save(&args.baseline)?;
+        eprintln!("test-runner: baseline saved to {}", args.baseline.display());
+    }
+    if comparison.is_some_and(|c| c.regressed) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
-    if !summary.success {
+async fn run_fuzz(cli: &Cli, args: &FuzzArgs) -> Result<()> {
+    let (spec, kernel) = single_spec(cli)?;
+    let snapshot_dir = cli
+        .snapshot_dir
+        .clone()
+        .unwrap_or_else(snapshot::default_dir);
+    let fuzzer = Fuzzer::new(&spec, &kernel, &snapshot_dir, cli.max_transcript).await?;
+    if let Some(path) = &args.replay {
+        let input = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        let result = fuzzer.run(&input).await?;
+        let crashed = fuzz::crash_kind(&result).is_some();
+        print_human(&result.summary(), &result, !crashed);
+        if crashed {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let seed = args.seed.unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(1, |d| d.as_nanos() as u64)
+    });
+    eprintln!("test-runner: fuzzing {} with --seed {seed}", fuzzer.name());
+    let opts = FuzzOptions {
+        iterations: args.iterations,
+        seed,
+        corpus: args.corpus.clone(),
+        minimize_runs: args.minimize_runs,
+    };
+    let (runs, crashes) = fuzzer.campaign(&opts).await?;
+    if cli.json {
+        let report = FuzzReport {
+            seed,
+            runs,
+            crashes: crashes
+                .iter()
+                .map(|c| FuzzCrash {
+                    kind: &c.kind,
+                    reproducer: &c.path,
+                    input: c.input.escape_ascii().to_string(),
+                    new: c.new,
+                })
+                .collect(),
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for c in &crashes {
+            let known = if c.new { "" } else { ", already in the corpus" };
+            println!(
+                "{}: {} (input `{}`{known})",
+                c.kind,
+                c.path.display(),
+                c.input.escape_ascii()
+            );
+        }
+        let new = crashes.iter().filter(|c| c.new).count();
+        println!("{runs} runs, {} crashes ({new} new)", crashes.len());
+    }
+    if !crashes.is_empty() {
         std::process::exit(1);
     }
     Ok(())
 }
+
+async fn run_soak(cli: &Cli, args: &SoakArgs) -> Result<()> {
+    let (spec, kernel) = single_spec(cli)?;
+    let table = spec.soak.clone().unwrap_or_default();
+    let duration = args
+        .duration
+        .or(table.duration.map(Duration::from_secs))
+        .unwrap_or(soak::DEFAULT_DURATION);
+    if duration.is_zero() {
+        bail!("--duration must be more than 0s");
+    }
+    let monitor = Monitor::new(&table, duration)?;
+    eprintln!(
+        "test-runner: soaking {} for {}s",
+        spec.test_name(&kernel),
+        duration.as_secs()
+    );
+    let (result, artifacts) = run_attempt(cli, &spec, &kernel, None, Some(monitor)).await?;
+    finish_single(cli, &spec, &kernel, result, artifacts, 1).await
+}
+
+/// `16` or `0x10`.
+async fn run_replay(cli: &Cli, args: &ReplayArgs) -> Result<()> {
+    let info = replay::Info::load(&args.file)?;
+    let debug = TestSpec {
+        gdb: cli.overrides.gdb.then_some(true),
+        gdb_script: cli.overrides.gdb_script.clone(),
+        gdb_port: cli.overrides.gdb_port,
+        symbols: cli.overrides.symbols.clone(),
+        ..Default::default()
+    };
+    let gdb = debug.gdb_config(cli.kernel.as_deref().unwrap_or(Path::new("")))?;
+    let cfg = info.run_config(gdb, cli.max_transcript);
+    let result = launch(&cfg).await?;
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+        return Ok(());
+    }
+    print!("{}", result.transcript);
+    if let Some(transcript) = &result.gdb_transcript {
+        eprintln!("--- gdb transcript ---\n{transcript}");
+    }
+    let ended = match result.reason {
+        ExitReason::Hang { .. } => "the end of the recording".to_string(),
+        ref reason => reason.name().to_string(),
+    };
+    eprintln!(
+        "test-runner: replay reached {ended} after {:.2}s",
+        result.duration_ms as f64 / 1000.0
+    );
+    Ok(())
+}
+
+fn parse_u32(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("`{s}`: {e}"))
+}
+
+fn print_human(summary: &TestSummary, result: &RunResult, success: bool) {
+    println!(
+        "{} after {:.2}s: boot_ok={} tests {}/{} passed",
+        result.reason.name(),
+        result.duration_ms as f64 / 1000.0,
+        summary.boot_ok,
+        summary.passed,
+        summary.total
+    );
+    for t in &summary.tests {
+        let status = if t.passed { "PASS" } else { "FAIL" };
+        println!("  {status} {} {}", t.name, t.message);
+    }
+    for line in result.explain() {
+        eprintln!("test-runner: {line}");
+    }
+    if !success {
+        eprintln!("--- serial transcript ---");
+        if result.transcript_dropped > 0 {
+            eprintln!("({} earlier bytes dropped)", result.transcript_dropped);
+        }
+        eprint!("{}", result.transcript);
+    }
+}
@@ -0,0 +1,744 @@
+//! Scripted exchanges with the guest's NIC (`[[packet]]`), for kernels
+//! with their own network stack.
+//!
+//! A spec with `[[packet]]` exchanges gets a virtio-net NIC at
+//! [`GUEST_MAC`] whose backend is `-netdev socket,udp=`: every datagram on
+//! that localhost socket pair is one Ethernet frame, and test-runner holds
+//! the other end, so it is a host on the guest's link at [`HOST_MAC`] and
+//! [`HOST_IP`]. The guest is expected at `guest-ip` (by default
+//! [`DEFAULT_GUEST_IP`], the address QEMU's user networking hands out).
+//! Each exchange is sent `delay` seconds after the serial line matching
+//! `when` (or after QEMU starts without one), one at a time in the order
+//! they fall due, and waits up to `timeout` seconds for its reply:
+//!
+//! * `send = "arp"`: who-has `guest-ip`; expects the guest's ARP reply.
+//! * `send = "ping"`: an ICMP echo request; expects the echo reply, with
+//!   the same identifier, sequence number and payload.
+//! * `send = "udp"`: `payload` to the guest's `port`, from [`HOST_PORT`];
+//!   with `reply`, expects a datagram back to that port carrying it.
+//!
+//! Throughout, the guest's ARP requests for [`HOST_IP`] are answered and
+//! any other frame is ignored. A run is only complete once its exchanges
+//! are done, and fails unless each got its reply (see
+//! [`crate::qemu::RunResult::packets`]).
+//!
+//! ```toml
+//! guest-ip = "10.0.2.15"
+//!
+//! [[packet]]
+//! when = '\[NET\] up'
+//! send = "ping"
+//!
+//! [[packet]]
+//! send = "udp"
+//! port = 7
+//! payload = "hello"
+//! reply = "hello"
+//! timeout = 2
+//! ```
+
+use crate::inject::{NETDEV_ID, NIC_ID};
+use crate::schedule::Trigger;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// The guest NIC's MAC address.
+pub const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+/// test-runner's MAC address on the link.
+pub const HOST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x35, 0x02];
+pub const HOST_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+pub const DEFAULT_GUEST_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+/// The UDP port exchanges are sent from, and their replies expected at.
+pub const HOST_PORT: u16 = 40000;
+/// Seconds an exchange waits for its reply.
+const DEFAULT_TIMEOUT: u64 = 5;
+
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+/// Identifier of the echo requests the harness sends.
+const PING_ID: u16 = 0x4155;
+const PING_PAYLOAD: &[u8] = b"auton test-runner ping";
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const PROTO_ICMP: u8 = 1;
+const PROTO_UDP: u8 = 17;
+const BROADCAST: [u8; 6] = [0xff; 6];
+
+/// `[[packet]]` in a spec.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PacketSpec {
+    /// Serial pattern to wait for [default: none, count from QEMU's start].
+    pub when: Option<String>,
+    /// Seconds to wait after `when`.
+    pub delay: Option<u64>,
+    /// Required.
+    pub send: Option<Probe>,
+    /// The guest's UDP port (`udp` only, required there).
+    pub port: Option<u16>,
+    /// UDP payload.
+    pub payload: Option<String>,
+    /// UDP payload expected back [default: none, nothing is expected].
+    pub reply: Option<String>,
+    /// Seconds to wait for the reply.
+    pub timeout: Option<u64>,
+}
+
+/// What an exchange sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Probe {
+    Arp,
+    Ping,
+    Udp,
+}
+
+/// A checked `[[packet]]`.
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    pub probe: Probe,
+    pub trigger: Trigger,
+    /// The guest's port, for `udp`.
+    pub port: u16,
+    pub payload: Vec<u8>,
+    pub reply: Option<Vec<u8>>,
+    pub timeout: Duration,
+}
+
+impl Exchange {
+    pub fn new(spec: &PacketSpec) -> Result<Self> {
+        let Some(probe) = spec.send else {
+            bail!("[[packet]] needs `send` (\"arp\", \"ping\" or \"udp\")");
+        };
+        let port = match (probe, spec.port) {
+            (Probe::Udp, Some(port)) => port,
+            (Probe::Udp, None) => bail!("[[packet]] `send = \"udp\"` needs a `port`"),
+            (_, None) => 0,
+            (_, Some(_)) => bail!("[[packet]] `port` is only for `send = \"udp\"`"),
+        };
+        if probe != Probe::Udp && (spec.payload.is_some() || spec.reply.is_some()) {
+            bail!("[[packet]] `payload` and `reply` are only for `send = \"udp\"`");
+        }
+        Ok(Self {
+            probe,
+            trigger: Trigger::new(spec.when.as_deref(), spec.delay).context("[[packet]]")?,
+            port,
+            payload: spec.payload.clone().unwrap_or_default().into_bytes(),
+            reply: spec.reply.clone().map(String::into_bytes),
+            timeout: Duration::from_secs(spec.timeout.unwrap_or(DEFAULT_TIMEOUT)),
+        })
+    }
+
+    /// What is sent, e.g. `udp to port 7`.
+    pub fn describe(&self) -> String {
+        match self.probe {
+            Probe::Arp => "arp".to_string(),
+            Probe::Ping => "ping".to_string(),
+            Probe::Udp => format!("udp to port {}", self.port),
+        }
+    }
+}
+
+/// How an exchange went.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Exchanged {
+    /// Its place among the spec's `[[packet]]`s.
+    #[serde(skip)]
+    pub index: usize,
+    pub exchange: String,
+    /// When it was sent, from QEMU's start; absent if it never was.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub at_ms: Option<u64>,
+    pub ok: bool,
+    /// The reply, or why there was none.
+    pub detail: String,
+}
+
+impl Exchanged {
+    pub fn line(&self) -> String {
+        format!("packet {}: {}", self.exchange, self.detail)
+    }
+}
+
+/// A run's exchanges and the link they go over.
+#[derive(Debug, Clone)]
+pub struct Harness {
+    pub guest_ip: Ipv4Addr,
+    pub exchanges: Vec<Exchange>,
+    /// Localhost UDP port test-runner's end of the link is bound to.
+    pub host_port: u16,
+    /// Localhost UDP port QEMU's end is bound to.
+    pub qemu_port: u16,
+}
+
+impl Harness {
+    pub fn new(specs: &[PacketSpec], guest_ip: Option<Ipv4Addr>) -> Result<Self> {
+        Ok(Self {
+            guest_ip: guest_ip.unwrap_or(DEFAULT_GUEST_IP),
+            exchanges: specs.iter().map(Exchange::new).collect::<Result<_>>()?,
+            host_port: auton_core::lock::lease_port()?,
+            qemu_port: auton_core::lock::lease_port()?,
+        })
+    }
+
+    /// QEMU flags for the NIC on the link.
+    pub fn qemu_args(&self) -> Vec<String> {
+        vec![
+            "-netdev".to_string(),
+            format!(
+                "socket,id={NETDEV_ID},udp=127.0.0.1:{},localaddr=127.0.0.1:{}",
+                self.host_port, self.qemu_port
+            ),
+            "-device".to_string(),
+            format!(
+                "virtio-net-pci,netdev={NETDEV_ID},id={NIC_ID},mac={}",
+                mac(GUEST_MAC)
+            ),
+        ]
+    }
+
+    /// Bind test-runner's end of the link, for a run started at `start`.
+    pub async fn start(&self, start: Instant) -> Result<Link> {
+        let socket = UdpSocket::bind(("127.0.0.1", self.host_port))
+            .await
+            .with_context(|| format!("binding UDP port {} for [[packet]]", self.host_port))?;
+        let qemu = SocketAddr::from((Ipv4Addr::LOCALHOST, self.qemu_port));
+        socket.connect(qemu).await?;
+        let (requests, requested) = mpsc::unbounded_channel();
+        let (done_tx, done) = mpsc::unbounded_channel();
+        let peer = Peer {
+            socket,
+            guest_ip: self.guest_ip,
+            buf: vec![0; 65536],
+        };
+        let task = tokio::spawn(serve(
+            peer,
+            self.exchanges.clone(),
+            start,
+            requested,
+            done_tx,
+        ));
+        Ok(Link {
+            requests,
+            done,
+            task,
+        })
+    }
+
+    /// Every exchange's outcome, in spec order, given those `done` and
+    /// which were `sent` for.
+    pub fn results(&self, mut done: Vec<Exchanged>, sent: &[bool]) -> Vec<Exchanged> {
+        for (index, exchange) in self.exchanges.iter().enumerate() {
+            if done.iter().any(|d| d.index == index) {
+                continue;
+            }
+            done.push(Exchanged {
+                index,
+                exchange: exchange.describe(),
+                at_ms: None,
+                ok: false,
+                detail: if sent.get(index).copied().unwrap_or(false) {
+                    "the run ended before it was done".to_string()
+                } else {
+                    "never sent: its `when` did not match".to_string()
+                },
+            });
+        }
+        done.sort_by_key(|d| d.index);
+        done
+    }
+}
+
+/// test-runner's end of the link while a run lasts; dropping it closes
+/// the link.
+#[derive(Debug)]
+pub struct Link {
+    requests: mpsc::UnboundedSender<usize>,
+    done: mpsc::UnboundedReceiver<Exchanged>,
+    task: JoinHandle<()>,
+}
+
+impl Link {
+    /// Queue exchange `index`.
+    pub fn send(&self, index: usize) {
+        let _ = self.requests.send(index);
+    }
+
+    /// The next exchange done.
+    pub async fn next(&mut self) -> Option<Exchanged> {
+        self.done.recv().await
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// The host on the guest's link.
+struct Peer {
+    socket: UdpSocket,
+    guest_ip: Ipv4Addr,
+    buf: Vec<u8>,
+}
+
+impl Peer {
+    async fn send(&self, packet: &Packet, dst: [u8; 6]) -> std::io::Result<()> {
+        self.socket.send(&packet.frame(HOST_MAC, dst)).await?;
+        Ok(())
+    }
+
+    /// The next frame that is not the guest's ARP request for the host,
+    /// which is answered.
+    async fn recv(&mut self) -> std::io::Result<Option<Packet>> {
+        let n = self.socket.recv(&mut self.buf).await?;
+        let packet = Packet::parse(&self.buf[..n]);
+        if let Some(Packet::Arp {
+            op: ARP_REQUEST,
+            sender_mac,
+            sender_ip,
+            target_ip: HOST_IP,
+        }) = packet
+        {
+            let reply = Packet::Arp {
+                op: ARP_REPLY,
+                sender_mac: HOST_MAC,
+                sender_ip: HOST_IP,
+                target_ip: sender_ip,
+            };
+            self.send(&reply, sender_mac).await?;
+            return Ok(None);
+        }
+        Ok(packet)
+    }
+
+    /// Carry out `exchange`, the `seq`th, returning what answered it.
+    async fn exchange(&mut self, exchange: &Exchange, seq: u16) -> Result<String, String> {
+        let guest = self.guest_ip;
+        let (packet, dst) = match exchange.probe {
+            Probe::Arp => (
+                Packet::Arp {
+                    op: ARP_REQUEST,
+                    sender_mac: HOST_MAC,
+                    sender_ip: HOST_IP,
+                    target_ip: guest,
+                },
+                BROADCAST,
+            ),
+            Probe::Ping => (
+                Packet::Icmp {
+                    src: HOST_IP,
+                    dst: guest,
+                    kind: ICMP_ECHO_REQUEST,
+                    id: PING_ID,
+                    seq,
+                    payload: PING_PAYLOAD.to_vec(),
+                },
+                GUEST_MAC,
+            ),
+            Probe::Udp => (
+                Packet::Udp {
+                    src: HOST_IP,
+                    dst: guest,
+                    src_port: HOST_PORT,
+                    dst_port: exchange.port,
+                    payload: exchange.payload.clone(),
+                },
+                GUEST_MAC,
+            ),
+        };
+        let sent = Instant::now();
+        self.send(&packet, dst)
+            .await
+            .map_err(|e| format!("sending: {e}"))?;
+        if exchange.probe == Probe::Udp && exchange.reply.is_none() {
+            return Ok("sent".to_string());
+        }
+        let until = sent + exchange.timeout;
+        loop {
+            let packet = match tokio::time::timeout_at(until, self.recv()).await {
+                Err(_) => return Err(format!("no reply within {:?}", exchange.timeout)),
+                Ok(Err(e)) => return Err(format!("receiving: {e}")),
+                Ok(Ok(None)) => continue,
+                Ok(Ok(Some(packet))) => packet,
+            };
+            let took = sent.elapsed().as_millis();
+            match (exchange.probe, packet) {
+                (
+                    Probe::Arp,
+                    Packet::Arp {
+                        op: ARP_REPLY,
+                        sender_mac,
+                        sender_ip,
+                        ..
+                    },
+                ) if sender_ip == guest => {
+                    if sender_mac != GUEST_MAC {
+                        return Err(format!(
+                            "{guest} is at {}, not the NIC's {}",
+                            mac(sender_mac),
+                            mac(GUEST_MAC)
+                        ));
+                    }
+                    return Ok(format!("{guest} is at {} ({took}ms)", mac(sender_mac)));
+                }
+                (
+                    Probe::Ping,
+                    Packet::Icmp {
+                        src,
+                        kind: ICMP_ECHO_REPLY,
+                        id: PING_ID,
+                        seq: reply_seq,
+                        payload,
+                        ..
+                    },
+                ) if src == guest && reply_seq == seq => {
+                    if payload != PING_PAYLOAD {
+                        return Err("the echo reply's payload differs".to_string());
+                    }
+                    return Ok(format!("echo reply ({took}ms)"));
+                }
+                (
+                    Probe::Udp,
+                    Packet::Udp {
+                        src,
+                        dst_port: HOST_PORT,
+                        payload,
+                        ..
+                    },
+                ) if src == guest => {
+                    let expected = exchange.reply.as_deref().unwrap_or_default();
+                    if payload != expected {
+                        return Err(format!(
+                            "replied {:?}, expected {:?}",
+                            String::from_utf8_lossy(&payload),
+                            String::from_utf8_lossy(expected)
+                        ));
+                    }
+                    return Ok(format!("replied ({took}ms)"));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Carry out the exchanges `requested`, one at a time, answering ARP
+/// between them.
+async fn serve(
+    mut peer: Peer,
+    exchanges: Vec<Exchange>,
+    start: Instant,
+    mut requested: mpsc::UnboundedReceiver<usize>,
+    done: mpsc::UnboundedSender<Exchanged>,
+) {
+    let mut seq = 0u16;
+    loop {
+        let index = tokio::select! {
+            index = requested.recv() => match index {
+                Some(index) => index,
+                None => return,
+            },
+            received = peer.recv() => {
+                if let Err(e) = received {
+                    tracing::debug!("[[packet]] link: {e}");
+                }
+                continue;
+            }
+        };
+        let Some(exchange) = exchanges.get(index) else {
+            continue;
+        };
+        seq = seq.wrapping_add(1);
+        let at_ms = start.elapsed().as_millis() as u64;
+        let outcome = peer.exchange(exchange, seq).await;
+        let result = Exchanged {
+            index,
+            exchange: exchange.describe(),
+            at_ms: Some(at_ms),
+            ok: outcome.is_ok(),
+            detail: outcome.unwrap_or_else(|e| e),
+        };
+        if done.send(result).is_err() {
+            return;
+        }
+    }
+}
+
+/// What a frame carries, as far as the harness is concerned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    /// Ethernet/IPv4 ARP.
+    Arp {
+        op: u16,
+        sender_mac: [u8; 6],
+        sender_ip: Ipv4Addr,
+        target_ip: Ipv4Addr,
+    },
+    /// ICMP echo request or reply.
+    Icmp {
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        kind: u8,
+        id: u16,
+        seq: u16,
+        payload: Vec<u8>,
+    },
+    Udp {
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        src_port: u16,
+        dst_port: u16,
+        payload: Vec<u8>,
+    },
+}
+
+impl Packet {
+    /// The packet in an Ethernet `frame`, if it is one of these.
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        let payload = frame.get(14..)?;
+        match be16(frame, 12)? {
+            ETHERTYPE_ARP => {
+                // Hardware type Ethernet, protocol IPv4, 6- and 4-byte
+                // addresses.
+                if payload.get(..6)? != [0, 1, 8, 0, 6, 4] {
+                    return None;
+                }
+                Some(Self::Arp {
+                    op: be16(payload, 6)?,
+                    sender_mac: payload.get(8..14)?.try_into().ok()?,
+                    sender_ip: ipv4(payload, 14)?,
+                    target_ip: ipv4(payload, 24)?,
+                })
+            }
+            ETHERTYPE_IPV4 => {
+                let header = usize::from(payload.first()? & 0xf) * 4;
+                let total = usize::from(be16(payload, 2)?);
+                if payload.first()? >> 4 != 4 || header < 20 || total < header {
+                    return None;
+                }
+                let (src, dst) = (ipv4(payload, 12)?, ipv4(payload, 16)?);
+                // Frames can be padded past the datagram.
+                let data = payload.get(header..total)?;
+                match *payload.get(9)? {
+                    PROTO_ICMP => Some(Self::Icmp {
+                        src,
+                        dst,
+                        kind: *data.first()?,
+                        id: be16(data, 4)?,
+                        seq: be16(data, 6)?,
+                        payload: data.get(8..)?.to_vec(),
+                    }),
+                    PROTO_UDP => {
+                        let len = usize::from(be16(data, 4)?);
+                        Some(Self::Udp {
+                            src,
+                            dst,
+                            src_port: be16(data, 0)?,
+                            dst_port: be16(data, 2)?,
+                            payload: data.get(8..len)?.to_vec(),
+                        })
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// An Ethernet frame from `src` to `dst` carrying the packet.
+    pub fn frame(&self, src: [u8; 6], dst: [u8; 6]) -> Vec<u8> {
+        let (ethertype, payload) = match self {
+            Self::Arp {
+                op,
+                sender_mac,
+                sender_ip,
+                target_ip,
+            } => {
+                let target_mac = if *op == ARP_REPLY { dst } else { [0; 6] };
+                let arp = [
+                    &[0, 1, 8, 0, 6, 4][..],
+                    &op.to_be_bytes(),
+                    sender_mac,
+                    &sender_ip.octets(),
+                    &target_mac,
+                    &target_ip.octets(),
+                ]
+                .concat();
+                (ETHERTYPE_ARP, arp)
+            }
+            Self::Icmp {
+                src,
+                dst,
+                kind,
+                id,
+                seq,
+                payload,
+            } => {
+                let mut icmp = [
+                    &[*kind, 0, 0, 0][..],
+                    &id.to_be_bytes(),
+                    &seq.to_be_bytes(),
+                    payload,
+                ]
+                .concat();
+                let sum = checksum(&icmp);
+                icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+                (ETHERTYPE_IPV4, ip_packet(*src, *dst, PROTO_ICMP, &icmp))
+            }
+            Self::Udp {
+                src,
+                dst,
+                src_port,
+                dst_port,
+                payload,
+            } => {
+                // A zero checksum means none, which IPv4 allows.
+                let udp = [
+                    &src_port.to_be_bytes()[..],
+                    &dst_port.to_be_bytes(),
+                    &(8 + payload.len() as u16).to_be_bytes(),
+                    &[0, 0],
+                    payload,
+                ]
+                .concat();
+                (ETHERTYPE_IPV4, ip_packet(*src, *dst, PROTO_UDP, &udp))
+            }
+        };
+        [&dst[..], &src, &ethertype.to_be_bytes(), &payload].concat()
+    }
+}
+
+/// An IPv4 datagram, TTL 64, not fragmented.
+fn ip_packet(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, data: &[u8]) -> Vec<u8> {
+    let total = (20 + data.len()) as u16;
+    let mut header = [
+        &[0x45, 0][..],
+        &total.to_be_bytes(),
+        &[0, 0, 0x40, 0, 64, proto, 0, 0],
+        &src.octets(),
+        &dst.octets(),
+    ]
+    .concat();
+    let sum = checksum(&header);
+    header[10..12].copy_from_slice(&sum.to_be_bytes());
+    [header.as_slice(), data].concat()
+}
+
+/// The Internet checksum (RFC 1071).
+fn checksum(bytes: &[u8]) -> u16 {
+    let mut sum: u32 = bytes
+        .chunks(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], c.get(1).copied().unwrap_or(0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn be16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn ipv4(bytes: &[u8], at: usize) -> Option<Ipv4Addr> {
+    let octets: [u8; 4] = bytes.get(at..at + 4)?.try_into().ok()?;
+    Some(Ipv4Addr::from(octets))
+}
+
+/// `52:54:00:12:34:56`.
+pub fn mac(bytes: [u8; 6]) -> String {
+    bytes.map(|b| format!("{b:02x}")).join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let packets = [
+            Packet::Arp {
+                op: ARP_REPLY,
+                sender_mac: GUEST_MAC,
+                sender_ip: DEFAULT_GUEST_IP,
+                target_ip: HOST_IP,
+            },
+            Packet::Icmp {
+                src: HOST_IP,
+                dst: DEFAULT_GUEST_IP,
+                kind: ICMP_ECHO_REQUEST,
+                id: PING_ID,
+                seq: 3,
+                payload: b"odd".to_vec(),
+            },
+            Packet::Udp {
+                src: DEFAULT_GUEST_IP,
+                dst: HOST_IP,
+                src_port: 7,
+                dst_port: HOST_PORT,
+                payload: b"hello".to_vec(),
+            },
+        ];
+        for packet in packets {
+            let mut frame = packet.frame(GUEST_MAC, HOST_MAC);
+            assert_eq!(frame[..6], HOST_MAC);
+            // NICs pad short frames to 60 bytes.
+            frame.resize(frame.len().max(60), 0);
+            assert_eq!(Packet::parse(&frame), Some(packet));
+        }
+        assert_eq!(Packet::parse(&[0; 60]), None);
+    }
+
+    #[test]
+    fn checksums_verify() {
+        let packet = Packet::Icmp {
+            src: HOST_IP,
+            dst: DEFAULT_GUEST_IP,
+            kind: ICMP_ECHO_REQUEST,
+            id: PING_ID,
+            seq: 1,
+            payload: PING_PAYLOAD.to_vec(),
+        };
+        let frame = packet.frame(HOST_MAC, GUEST_MAC);
+        // A header or message with its checksum in sums to zero.
+        assert_eq!(checksum(&frame[14..34]), 0);
+        assert_eq!(checksum(&frame[34..]), 0);
+        assert_eq!(mac(GUEST_MAC), "52:54:00:12:34:56");
+    }
+
+    #[test]
+    fn exchanges_are_checked() {
+        let spec = |send, port| PacketSpec {
+            send,
+            port,
+            ..Default::default()
+        };
+        let err = |s: PacketSpec| Exchange::new(&s).unwrap_err().to_string();
+        assert_eq!(
+            err(spec(None, None)),
+            "[[packet]] needs `send` (\"arp\", \"ping\" or \"udp\")"
+        );
+        assert_eq!(
+            err(spec(Some(Probe::Udp), None)),
+            "[[packet]] `send = \"udp\"` needs a `port`"
+        );
+        assert_eq!(
+            err(spec(Some(Probe::Ping), Some(7))),
+            "[[packet]] `port` is only for `send = \"udp\"`"
+        );
+        let udp = Exchange::new(&spec(Some(Probe::Udp), Some(7))).unwrap();
+        assert_eq!(udp.describe(), "udp to port 7");
+        assert_eq!(udp.timeout, Duration::from_secs(DEFAULT_TIMEOUT));
+    }
+}
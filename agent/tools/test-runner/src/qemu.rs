@@ -0,0 +1,1291 @@
+//! Launching QEMU and watching its serial console.
+//!
+//! QEMU runs in its own process group with serial on stdout. Output is read
+//! as it arrives: each complete line goes through an [`expect::Tracker`], and
+//! the bytes go into a [`SerialRing`] transcript. The run ends on the first
+//! of:
+//!
+//! * every expected pattern matched (`\[BOOT\] OK` by default) and every
+//!   console step ([`crate::steps`]) run → `pattern-matched`;
+//! * a panic banner or forbidden pattern → `panic`/`forbidden`, once the
+//!   trailing context lines arrived or a short grace period passed;
+//! * the timeout → `timeout`;
+//! * no serial output at all for `idle_timeout` → `hang`;
+//! * the guest exiting through the exit device ([`crate::exitdev`]) →
+//!   `device-exit` with its code;
+//! * QEMU exiting by itself → `qemu-error`, with its status and stderr.
+//!
+//! A soak run ([`crate::soak`]) does not stop at its patterns; a health
+//! condition failing ends it as `unhealthy`, and the timeout, its duration,
+//! as `survived`.
+//!
+//! A recorded run ([`crate::replay`]) keeps its log only if it fails, in
+//! [`RunResult::replay`].
+//!
+//! Device faults ([`crate::inject`]) are carried out over QMP as they fall
+//! due, each recorded in [`RunResult::injected`], and screens
+//! ([`crate::screen`]) are captured the same way and compared once the run
+//! is over, in [`RunResult::screens`]. Network exchanges
+//! ([`crate::packets`]) are sent as they fall due too, over the NIC's link;
+//! the patterns are only complete once they are all done, and their
+//! outcomes are in [`RunResult::packets`]. An in-guest test stub's cases
+//! ([`crate::control`]) are run over their own channel, which the patterns
+//! also wait for; they are in [`RunResult::control`] and in
+//! [`RunResult::summary`].
+//!
+//! Serial lines are also logged at debug level under the `serial` target
+//! (`RUST_LOG=serial=debug`), inside the caller's span.
+//!
+//! With `wait_for_exit` the expected patterns no longer end the run; it
+//! lasts until the guest exits, and patterns still unmatched then are left
+//! in [`RunResult::unmatched`].
+//!
+//! With `save_snapshot`, a run whose patterns matched is paused and saved
+//! with `savevm` instead. If the run failed while QEMU is still up, the
+//! guest is paused and its CPU state, any `dump_memory` ranges, a
+//! screenshot and, with `core_dump`, an ELF core of guest RAM are captured
+//! over QMP ([`crate::qmp`]).
+//!
+//! QEMU is then stopped in escalating steps, each given a grace period:
+//! QMP `quit` (for a timeout or hang, the ACPI power button instead, so
+//! the guest can shut down), SIGTERM to the process group, and SIGKILL.
+//! The step that worked is [`RunResult::shutdown`]; whatever it was, the
+//! whole group is killed before returning, so no QEMU outlives its run. A
+//! run that did not pass is classified ([`crate::classify`]) from its
+//! transcript and, with `cpu_log`, QEMU's interrupt/reset log.
+
+use crate::accel::Accel;
+use crate::capture::{LineSplitter, SerialRing};
+use crate::classify::{self, Failure};
+use crate::control::{Control, ControlReport, Session};
+use crate::coverage::{CoverageConfig, Executed};
+use crate::deterministic::Deterministic;
+use crate::devices::Disk;
+use crate::exitdev::{DeviceExit, ExitDevice};
+use crate::expect::{self, Event, Expectations, Matched, Tracker, Violation};
+use crate::gdb::{self, GdbConfig};
+use crate::golden::{Golden, GoldenResult};
+use crate::inject::{self, Injected, Injection};
+use crate::packets::{Exchanged, Harness, Link};
+use crate::qmp::{FailureDump, MemoryRange};
+use crate::remote::Remote;
+use crate::replay;
+use crate::schedule::Schedule;
+use crate::screen::{self, Screen, ScreenResult};
+use crate::soak::{Monitor, SoakReport, Unhealthy};
+use crate::steps::Steps;
+use crate::symbolize::Frame;
+use crate::trace::TraceSummary;
+use crate::{parse_serial, qmp, TestSummary};
+use anyhow::{Context, Result};
+use auton_core::plan::{Plan, Planned};
+use serde::Serialize;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::time::Instant;
+
+/// Pattern that ends a run successfully when no other is given.
+pub const DEFAULT_EXPECT: &str = r"\[BOOT\] OK";
+
+/// Patterns that mark a kernel panic or fatal CPU exception.
+pub const DEFAULT_PANIC_PATTERNS: &[&str] = &["PANIC", "panic:", "unhandled exception"];
+
+/// How long to wait for context lines after a panic or forbidden line.
+pub const PANIC_GRACE: Duration = Duration::from_millis(500);
+
+/// How long QEMU may take to exit after `quit` before it is killed.
+const QUIT_GRACE: Duration = Duration::from_secs(2);
+
+/// How long a timed-out guest may take to power off after the ACPI power
+/// button before QEMU is signalled.
+const POWERDOWN_GRACE: Duration = Duration::from_secs(3);
+
+/// How long QEMU may take to exit on SIGTERM before SIGKILL.
+const TERM_GRACE: Duration = Duration::from_secs(1);
+
+/// QEMU stderr kept for a `qemu-error` result.
+const STDERR_LIMIT: usize = 64 * 1024;
+
+/// Tail of the `-d int,cpu_reset` log read for classification; the fault
+/// cascade that ends a run is always at the end.
+const CPU_LOG_TAIL: u64 = 4 * 1024 * 1024;
+
+/// QEMU `-d` items that log interrupts and CPU resets.
+pub const CPU_LOG_ITEMS: [&str; 2] = ["int", "cpu_reset"];
+
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    pub program: String,
+    pub args: Vec<String>,
+    /// The accelerator `args` select.
+    pub accel: Accel,
+    pub timeout: Duration,
+    /// Without positive patterns the run lasts until QEMU exits or the
+    /// timeout fires.
+    pub expect: Expectations,
+    /// Console input steps (see [`crate::steps`]); the patterns are only
+    /// complete once these have all run.
+    pub steps: Steps,
+    /// Transcript cap in bytes (see [`crate::capture`]).
+    pub transcript_limit: usize,
+    /// File that receives all serial output, uncapped.
+    pub serial_log: Option<PathBuf>,
+    /// Resolved exit device; its flags are already in `args`.
+    pub exit_device: ExitDevice,
+    /// isa-debug-exit value that means pass.
+    pub exit_success: u32,
+    /// Keep running after the patterns matched until the guest exits.
+    pub wait_for_exit: bool,
+    /// Declare a hang after this long without serial output.
+    pub idle_timeout: Option<Duration>,
+    /// QMP socket QEMU serves (its `-qmp` flag is already in `args`), used
+    /// for the failure dump and a clean `quit`.
+    pub qmp_socket: Option<PathBuf>,
+    /// Physical memory dumped on failure.
+    pub dump_memory: Vec<MemoryRange>,
+    /// Where to save a screenshot on failure.
+    pub screenshot: Option<PathBuf>,
+    /// Where to write an ELF core of guest RAM on failure.
+    pub core_dump: Option<PathBuf>,
+    /// Disk whose overlay is created for the run (its flags are already
+    /// in `args`).
+    pub disk: Option<Disk>,
+    /// Golden transcript the serial output is compared with (or blessed
+    /// into).
+    pub golden: Option<Golden>,
+    /// Save the VM as this internal snapshot when the patterns match (see
+    /// [`crate::snapshot`]); failing to is an error.
+    pub save_snapshot: Option<String>,
+    /// QEMU `-D` log file for `-d int,cpu_reset` (flags already in `args`).
+    pub cpu_log: Option<PathBuf>,
+    /// `-D` log of `--trace` events, kept and summarized after the run; the
+    /// same file as `cpu_log` when both are set.
+    pub trace: Option<PathBuf>,
+    /// Record executed guest code (see [`crate::coverage`]); its log is
+    /// the `-D` file `cpu_log` and `trace` use when they are set.
+    pub coverage: Option<CoverageConfig>,
+    /// Socket of the virtio-serial port `test-runner fuzz` writes inputs
+    /// to (see [`crate::fuzz`]); its flags are already in `args`.
+    pub fuzz_channel: Option<PathBuf>,
+    /// GDB session; its gdbstub flags are already in `args`. A script is
+    /// run against the target while the serial is watched.
+    pub gdb: Option<GdbConfig>,
+    /// Host QEMU runs on instead of this one (see [`crate::remote`]).
+    pub remote: Option<Remote>,
+    /// Health checks of a soak run, which lasts `timeout` (see
+    /// [`crate::soak`]).
+    pub soak: Option<Monitor>,
+    /// Faults to inject over `qmp_socket` (see [`crate::inject`]).
+    pub inject: Vec<Injection>,
+    /// A deterministic run's settings; its flags are already in `args`.
+    pub deterministic: Option<Deterministic>,
+    /// Record/replay log the run records to; its flags are already in
+    /// `args` (see [`crate::replay`]).
+    pub record: Option<PathBuf>,
+    /// Screens captured over `qmp_socket` and compared with references
+    /// (see [`crate::screen`]).
+    pub screens: Vec<Screen>,
+    /// Network exchanges with the guest's NIC, whose flags are already in
+    /// `args` (see [`crate::packets`]).
+    pub packets: Option<Harness>,
+    /// Channel to an in-guest test stub, whose flags are already in `args`
+    /// (see [`crate::control`]).
+    pub control: Option<Control>,
+}
+
+/// A TCG boot of nothing yet: the default arch's QEMU with no arguments,
+/// the spec defaults' timeout and patterns, and every extra off. Callers
+/// set `args` and whatever else they need with `..Default::default()`.
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            program: crate::qemu_binary(crate::spec::DEFAULT_ARCH)
+                .unwrap_or_default()
+                .to_string(),
+            args: Vec::new(),
+            accel: Accel::Tcg,
+            timeout: Duration::from_secs(crate::spec::DEFAULT_TIMEOUT_SECS),
+            expect: default_expectations(),
+            steps: Steps::default(),
+            transcript_limit: crate::capture::DEFAULT_CAPACITY,
+            serial_log: None,
+            exit_device: ExitDevice::None,
+            exit_success: crate::exitdev::ISA_DEBUG_EXIT_SUCCESS,
+            wait_for_exit: false,
+            idle_timeout: None,
+            qmp_socket: None,
+            dump_memory: Vec::new(),
+            screenshot: None,
+            core_dump: None,
+            disk: None,
+            golden: None,
+            save_snapshot: None,
+            cpu_log: None,
+            trace: None,
+            coverage: None,
+            fuzz_channel: None,
+            gdb: None,
+            remote: None,
+            soak: None,
+            inject: Vec::new(),
+            deterministic: None,
+            record: None,
+            screens: Vec::new(),
+            packets: None,
+            control: None,
+        }
+    }
+}
+
+/// Why the run ended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum ExitReason {
+    PatternMatched,
+    Panic(Violation),
+    Forbidden(Violation),
+    Timeout {
+        timeout_ms: u64,
+    },
+    /// Serial went quiet for `idle_secs` after `lines` lines.
+    Hang {
+        idle_secs: u64,
+        lines: usize,
+    },
+    DeviceExit(DeviceExit),
+    QemuError {
+        status: Option<i32>,
+        stderr: String,
+    },
+    /// A soak run's health condition failed.
+    Unhealthy(Unhealthy),
+    /// A soak run lasted its duration.
+    Survived {
+        duration_secs: u64,
+    },
+}
+
+impl ExitReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PatternMatched => "pattern-matched",
+            Self::Panic(_) => "panic",
+            Self::Forbidden(_) => "forbidden",
+            Self::Timeout { .. } => "timeout",
+            Self::Hang { .. } => "hang",
+            Self::DeviceExit(_) => "device-exit",
+            Self::QemuError { .. } => "qemu-error",
+            Self::Unhealthy(_) => "unhealthy",
+            Self::Survived { .. } => "survived",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RunResult {
+    pub reason: ExitReason,
+    pub duration_ms: u64,
+    /// The accelerator the run asked for (`auto` when a remote QEMU
+    /// picked).
+    pub accel: Accel,
+    pub matched: Vec<Matched>,
+    pub unmatched: Vec<String>,
+    /// Serial output, oldest bytes first.
+    pub transcript: String,
+    /// Bytes dropped from the front of `transcript` by the ring cap.
+    pub transcript_dropped: u64,
+    /// Structured cause of a failed run, when one is recognized.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<Failure>,
+    /// Crash addresses resolved against the build's symbols (filled in by
+    /// [`crate::symbolize::annotate`]).
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub backtrace: Vec<Frame>,
+    /// Guest state captured over QMP when the run failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dump: Option<FailureDump>,
+    /// Output of the `--gdb-script` session.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gdb_transcript: Option<String>,
+    /// Summary of the `--trace` log.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace: Option<TraceSummary>,
+    /// Outcome of the `--golden` comparison; a mismatch fails the run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub golden: Option<GoldenResult>,
+    /// A soak run's gauges, faults and heartbeats.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soak: Option<SoakReport>,
+    /// Faults injected, or tried.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub injected: Vec<Injected>,
+    /// What a deterministic run is replayed with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deterministic: Option<Deterministic>,
+    /// Outcomes of the `[[screen]]` comparisons; a mismatch fails the run.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub screens: Vec<ScreenResult>,
+    /// Outcomes of the `[[packet]]` exchanges; one without its reply fails
+    /// the run.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub packets: Vec<Exchanged>,
+    /// The in-guest test stub's cases, which are also in
+    /// [`RunResult::summary`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control: Option<ControlReport>,
+    /// `replay.json` of a failed recorded run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replay: Option<PathBuf>,
+    /// The step QEMU exited after.
+    pub shutdown: Shutdown,
+    /// Guest PCs executed, with `coverage`; reported across runs by
+    /// [`crate::coverage::write_all`].
+    #[serde(skip)]
+    pub coverage: Option<Executed>,
+}
+
+/// How QEMU was stopped once the run was decided, in escalation order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Shutdown {
+    /// QEMU had exited by itself.
+    Exited,
+    /// QMP `quit`.
+    Quit,
+    /// ACPI power button (`system_powerdown`), tried first on a timeout or
+    /// hang.
+    Powerdown,
+    /// SIGTERM to the process group.
+    Sigterm,
+    /// SIGKILL to the process group.
+    Sigkill,
+}
+
+impl Shutdown {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Exited => "exited",
+            Self::Quit => "quit",
+            Self::Powerdown => "powerdown",
+            Self::Sigterm => "sigterm",
+            Self::Sigkill => "sigkill",
+        }
+    }
+}
+
+impl RunResult {
+    /// The `[TEST]` lines in the transcript, and the in-guest cases.
+    pub fn summary(&self) -> TestSummary {
+        let mut summary = parse_serial(&self.transcript);
+        for case in self.control.iter().flat_map(|c| &c.cases) {
+            summary.add(case.test_case());
+        }
+        summary
+    }
+
+    /// The run passed: patterns (or a passing guest exit with every pattern
+    /// matched, or a soak run's whole duration with them), no failed
+    /// `[TEST]` lines or in-guest cases, no golden or screen mismatch and
+    /// every exchange answered.
+    pub fn passed(&self, summary: &TestSummary) -> bool {
+        summary.failed == 0
+            && self.golden.as_ref().is_none_or(GoldenResult::ok)
+            && self.screens.iter().all(ScreenResult::ok)
+            && self.packets.iter().all(|p| p.ok)
+            && self.control.as_ref().is_none_or(|c| c.error.is_none())
+            && match &self.reason {
+                ExitReason::PatternMatched => true,
+                ExitReason::DeviceExit(exit) => {
+                    exit.passed && self.unmatched.is_empty() && self.soak.is_none()
+                }
+                ExitReason::Survived { .. } => self.unmatched.is_empty(),
+                _ => false,
+            }
+    }
+
+    /// Why the run ended and what never matched, one finding per line.
+    pub fn explain(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        match &self.reason {
+            ExitReason::Panic(v) | ExitReason::Forbidden(v) => {
+                let what = if matches!(self.reason, ExitReason::Panic(_)) {
+                    "kernel panic"
+                } else {
+                    "forbidden output"
+                };
+                lines.push(format!(
+                    "{what} at serial line {} (matched `{}`):",
+                    v.line_no, v.pattern
+                ));
+                lines.extend(v.render().lines().map(String::from));
+            }
+            ExitReason::Timeout { timeout_ms } => lines.push(format!(
+                "QEMU timed out after {:?} (possible hang)",
+                Duration::from_millis(*timeout_ms)
+            )),
+            ExitReason::Hang {
+                idle_secs,
+                lines: seen,
+            } => lines.push(format!(
+                "hang: no serial output for {idle_secs}s after line {seen}"
+            )),
+            ExitReason::DeviceExit(exit) => lines.push(format!(
+                "guest exited via {} with code {:#x} ({})",
+                exit.device.name(),
+                exit.code,
+                if exit.passed { "pass" } else { "fail" }
+            )),
+            ExitReason::QemuError { status, stderr } => {
+                let status = status.map_or("a signal".to_string(), |c| format!("status {c}"));
+                lines.push(format!("QEMU exited with {status}"));
+                lines.extend(stderr.lines().map(String::from));
+            }
+            ExitReason::Unhealthy(u) => {
+                lines.push(format!(
+                    "unhealthy after {}s: {}: {}",
+                    u.at_secs, u.check, u.detail
+                ));
+                if let (Some(n), Some(line)) = (u.line_no, &u.line) {
+                    lines.push(format!("at serial line {n}: {line}"));
+                }
+            }
+            ExitReason::Survived { duration_secs } => {
+                lines.push(format!("survived the {duration_secs}s soak"))
+            }
+            ExitReason::PatternMatched => {}
+        }
+        if let Some(f) = &self.failure {
+            lines.push(format!("cause: {}", f.describe()));
+        }
+        if !self.backtrace.is_empty() {
+            lines.push("backtrace:".to_string());
+            lines.extend(
+                self.backtrace
+                    .iter()
+                    .enumerate()
+                    .map(|(i, frame)| format!("  #{i} {frame}")),
+            );
+        }
+        if let Some(dump) = &self.dump {
+            if let Some(state) = &dump.cpu_state {
+                lines.push("CPU state:".to_string());
+                lines.extend(state.lines().map(String::from));
+            }
+            for m in &dump.memory {
+                lines.push(format!("memory at {:#x}:", m.address));
+                lines.extend(m.lines());
+            }
+            if let Some(path) = &dump.screenshot {
+                lines.push(format!("screenshot: {}", path.display()));
+            }
+            if let Some(path) = &dump.core {
+                lines.push(format!("core dump: {}", path.display()));
+            }
+        }
+        lines.extend(self.unmatched.iter().map(|p| format!("never matched: {p}")));
+        if let Some(trace) = &self.trace {
+            lines.extend(trace.lines());
+        }
+        if let Some(golden) = &self.golden {
+            lines.extend(golden.lines());
+        }
+        for screen in &self.screens {
+            lines.extend(screen.lines());
+        }
+        lines.extend(self.packets.iter().filter(|p| !p.ok).map(Exchanged::line));
+        if let Some(control) = &self.control {
+            lines.extend(control.lines());
+        }
+        if let Some(soak) = &self.soak {
+            lines.extend(soak.lines());
+        }
+        for i in &self.injected {
+            let at = format!(
+                "injected {} at {:.2}s",
+                i.action.name(),
+                i.at_ms as f64 / 1000.0
+            );
+            lines.push(match &i.error {
+                Some(e) => format!("{at} failed: {e}"),
+                None => at,
+            });
+        }
+        if let Some(d) = &self.deterministic {
+            lines.push(d.line());
+        }
+        if let Some(replay) = &self.replay {
+            lines.push(format!("recorded: test-runner replay {}", replay.display()));
+        }
+        lines
+    }
+}
+
+impl RunConfig {
+    /// Add this run's commands, and the files it writes, to `plan`
+    /// (`--dry-run`). A remote run's command is the local QEMU one its
+    /// host runs.
+    pub fn plan(&self, what: impl Into<String>, plan: &mut Plan) {
+        if let Some(disk) = &self.disk {
+            disk.plan(plan);
+        }
+        plan.run(Planned::new(what, &self.program, &self.args));
+        let files = [&self.serial_log, &self.trace, &self.record];
+        for path in files.into_iter().flatten() {
+            plan.write(path);
+        }
+        for screen in self.screens.iter().filter(|s| s.keep || s.bless) {
+            plan.write(&screen.capture);
+        }
+    }
+
+    /// Write the `--trace` log to `path` instead (e.g. into a results
+    /// directory), updating the `-D` flag.
+    pub fn move_trace(&mut self, path: PathBuf) {
+        let Some(old) = self.trace.replace(path.clone()) else {
+            return;
+        };
+        let old_arg = old.display().to_string();
+        if let Some(i) = self.args.windows(2).position(|w| w == ["-D", &old_arg]) {
+            self.args[i + 1] = path.display().to_string();
+        }
+        if self.cpu_log.as_ref() == Some(&old) {
+            self.cpu_log = Some(path.clone());
+        }
+        if let Some(c) = self.coverage.as_mut().filter(|c| c.log == old) {
+            c.log = path;
+        }
+    }
+
+    /// Record to `log` instead, updating the record/replay flags.
+    pub fn move_record(&mut self, log: PathBuf) {
+        let Some(old) = self.record.take() else {
+            return;
+        };
+        self.record = Some(log.clone());
+        let moves = [
+            (old.display().to_string(), log.display().to_string()),
+            (
+                replay::snapshots(&old).display().to_string(),
+                replay::snapshots(&log).display().to_string(),
+            ),
+        ];
+        for arg in &mut self.args {
+            for (from, to) in &moves {
+                *arg = arg.replace(from, to);
+            }
+        }
+    }
+}
+
+/// The default expectations: `[BOOT] OK`, no forbidden patterns, and the
+/// default panic banners.
+pub fn default_expectations() -> Expectations {
+    let panic: Vec<String> = DEFAULT_PANIC_PATTERNS
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    Expectations::new(
+        &[DEFAULT_EXPECT.to_string()],
+        &[],
+        &[],
+        &panic,
+        expect::DEFAULT_CONTEXT,
+    )
+    .expect("default patterns compile")
+}
+
+/// Launch `cfg.program` and watch its serial output until one of the exit
+/// conditions in the module docs.
+pub async fn run(cfg: &RunConfig) -> Result<RunResult> {
+    let _overlay = match &cfg.disk {
+        Some(disk) => Some(disk.create_overlay().await?),
+        None => None,
+    };
+    if let Some(log) = &cfg.record {
+        replay::create_snapshots(log).await?;
+    }
+    let mut remote = match &cfg.remote {
+        Some(remote) => Some(remote.launch(cfg).await?),
+        None => None,
+    };
+    let (program, args) = match &remote {
+        Some(session) => (&session.program, &session.args),
+        None => (&cfg.program, &cfg.args),
+    };
+    let start = Instant::now();
+    let mut link = match &cfg.packets {
+        Some(harness) => Some(harness.start(start).await?),
+        None => None,
+    };
+    let mut cmd = Command::new(program);
+    let interactive = !cfg.steps.is_empty();
+    cmd.args(args)
+        .stdin(if interactive {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    #[cfg(unix)]
+    cmd.process_group(0);
+    // ssh goes with test-runner however it ends, and so does the remote
+    // QEMU (see `crate::remote`).
+    #[cfg(target_os = "linux")]
+    if remote.is_some() {
+        // SAFETY: prctl is async-signal-safe.
+        unsafe {
+            cmd.pre_exec(|| {
+                libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+                Ok(())
+            });
+        }
+    }
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("failed to spawn {program} (is it installed?)"))?;
+    // Kills the group even if this future is dropped mid-run (Ctrl-C).
+    let group = ProcessGroup(child.id());
+    let mut stdout = child.stdout.take().context("QEMU stdout not captured")?;
+    let stderr = child.stderr.take().context("QEMU stderr not captured")?;
+    let stderr_task = tokio::spawn(read_bounded(stderr, STDERR_LIMIT));
+    let mut stdin = child.stdin.take();
+    let mut steps = cfg.steps.clone();
+    send(&mut stdin, steps.start()).await;
+    let gdb_task = cfg
+        .gdb
+        .as_ref()
+        .and_then(|g| Some(gdb::spawn_script(g, g.script.as_ref()?)));
+
+    let mut ring = SerialRing::new(cfg.transcript_limit);
+    let mut serial_log = match &cfg.serial_log {
+        Some(path) => Some(
+            std::fs::File::create(path).with_context(|| format!("creating {}", path.display()))?,
+        ),
+        None => None,
+    };
+    let mut lines = LineSplitter::default();
+    let mut tracker = Tracker::new(&cfg.expect);
+    let mut soak = cfg.soak.clone();
+    let mut injector = inject::schedule(&cfg.inject, start);
+    let mut shots = Schedule::new(
+        cfg.screens
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (s.trigger.clone(), i)),
+        start,
+    );
+    let mut captured: Vec<Result<(), String>> = cfg
+        .screens
+        .iter()
+        .map(|_| Err("never captured: its `when` did not match".to_string()))
+        .collect();
+    let mut injected = Vec::new();
+    let exchanges = cfg.packets.as_ref().map_or(&[][..], |h| &h.exchanges);
+    let mut queued = Schedule::new(
+        exchanges
+            .iter()
+            .enumerate()
+            .map(|(i, e)| (e.trigger.clone(), i)),
+        start,
+    );
+    let mut sent = vec![false; exchanges.len()];
+    let mut exchanged = Vec::new();
+    let mut session = cfg.control.as_ref().map(Control::start);
+    let links_done = |exchanged: &[Exchanged], session: &Option<Session>| {
+        exchanged.len() == exchanges.len() && session.as_ref().is_none_or(Session::finished)
+    };
+    let deadline = start + cfg.timeout;
+    let mut last_output = start;
+    // Set once a panic/forbidden line is seen: (is panic, grace deadline).
+    let mut violated: Option<(bool, Instant)> = None;
+    let mut eof = false;
+    let mut exited = false;
+    let mut patterns_done = false;
+    let mut buf = vec![0u8; 4096];
+
+    let violation = |tracker: &Tracker, panic: bool| {
+        let v = tracker.violation().cloned().expect("violation recorded");
+        if panic {
+            ExitReason::Panic(v)
+        } else {
+            ExitReason::Forbidden(v)
+        }
+    };
+    let reason = loop {
+        for action in injector.due(Instant::now()) {
+            let error = match &cfg.qmp_socket {
+                Some(socket) => inject::fire(socket, action)
+                    .await
+                    .err()
+                    .map(|e| format!("{e:#}")),
+                None => Some("no QMP socket".to_string()),
+            };
+            if let Some(e) = &error {
+                tracing::warn!("injecting {}: {e}", action.name());
+            }
+            injected.push(Injected {
+                action,
+                at_ms: start.elapsed().as_millis() as u64,
+                after_line: tracker.lines(),
+                error,
+            });
+        }
+        for i in shots.due(Instant::now()) {
+            let screen = &cfg.screens[i];
+            captured[i] = match &cfg.qmp_socket {
+                Some(socket) => screen::capture(socket, &screen.capture)
+                    .await
+                    .map_err(|e| format!("capturing: {e:#}")),
+                None => Err("no QMP socket".to_string()),
+            };
+        }
+        for i in queued.due(Instant::now()) {
+            if let Some(link) = &link {
+                link.send(i);
+                sent[i] = true;
+            }
+        }
+        let idle_at = cfg.idle_timeout.map(|idle| last_output + idle);
+        let beat_at = soak.as_ref().and_then(Monitor::deadline).map(|d| start + d);
+        let wake = [
+            violated.map(|(_, t)| t),
+            idle_at,
+            beat_at,
+            injector.deadline(),
+            shots.deadline(),
+            queued.deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(deadline, Instant::min);
+        tokio::select! {
+            read = stdout.read(&mut buf), if !eof => {
+                let n = read.context("reading QEMU serial output")?;
+                let mut found = Vec::new();
+                if n == 0 {
+                    eof = true;
+                    found.extend(lines.finish());
+                } else {
+                    last_output = Instant::now();
+                    ring.push(&buf[..n]);
+                    if let Some(log) = &mut serial_log {
+                        if let Err(e) = log.write_all(&buf[..n]) {
+                            tracing::warn!("serial log: {e}");
+                            serial_log = None;
+                        }
+                    }
+                    found = lines.push(&buf[..n]);
+                }
+                let mut complete = false;
+                let mut unhealthy = None;
+                for line in &found {
+                    tracing::debug!(target: "serial", "{line}");
+                    send(&mut stdin, steps.line(line)).await;
+                    injector.line(line, Instant::now());
+                    shots.line(line, Instant::now());
+                    queued.line(line, Instant::now());
+                    if let Some(monitor) = &mut soak {
+                        unhealthy = monitor.line(line, start.elapsed());
+                        if unhealthy.is_some() {
+                            break;
+                        }
+                    }
+                    match tracker.line(line) {
+                        Event::Complete => patterns_done = true,
+                        Event::Violated { panic } => {
+                            violated = Some((panic, Instant::now() + PANIC_GRACE));
+                        }
+                        Event::Continue => {}
+                    }
+                    if patterns_done
+                        && steps.done()
+                        && links_done(&exchanged, &session)
+                        && !cfg.wait_for_exit
+                    {
+                        complete = true;
+                        break;
+                    }
+                }
+                // A prompt need not end its line.
+                if !complete && !steps.done() {
+                    if let Some(partial) = lines.partial() {
+                        send(&mut stdin, steps.partial(&partial)).await;
+                        complete = patterns_done
+                            && steps.done()
+                            && links_done(&exchanged, &session)
+                            && !cfg.wait_for_exit;
+                    }
+                }
+                if let Some(u) = unhealthy {
+                    break ExitReason::Unhealthy(u);
+                }
+                if complete {
+                    break ExitReason::PatternMatched;
+                }
+                if let Some((panic, _)) = violated {
+                    if tracker.context_done() {
+                        break violation(&tracker, panic);
+                    }
+                }
+            }
+            Some(done) = next_exchanged(&mut link), if exchanged.len() < exchanges.len() => {
+                exchanged.push(done);
+                if patterns_done
+                    && steps.done()
+                    && links_done(&exchanged, &session)
+                    && !cfg.wait_for_exit
+                {
+                    break ExitReason::PatternMatched;
+                }
+            }
+            _ = update(&mut session), if session.as_ref().is_some_and(|s| !s.finished()) => {
+                if patterns_done
+                    && steps.done()
+                    && links_done(&exchanged, &session)
+                    && !cfg.wait_for_exit
+                {
+                    break ExitReason::PatternMatched;
+                }
+            }
+            status = child.wait(), if eof => {
+                let status = status.context("waiting for QEMU")?;
+                exited = true;
+                match violated {
+                    Some((panic, _)) => break violation(&tracker, panic),
+                    None => {
+                        if let Some(exit) = cfg.exit_device.interpret(status.code(), cfg.exit_success) {
+                            break ExitReason::DeviceExit(exit);
+                        }
+                        let stderr = stderr_task.await.unwrap_or_default();
+                        break ExitReason::QemuError { status: status.code(), stderr };
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(wake) => {
+                match violated {
+                    Some((panic, _)) => break violation(&tracker, panic),
+                    None if Instant::now() >= deadline => match &mut soak {
+                        Some(monitor) => match monitor.overdue(start.elapsed()) {
+                            Some(u) => break ExitReason::Unhealthy(u),
+                            None => break ExitReason::Survived { duration_secs: cfg.timeout.as_secs() },
+                        },
+                        None => break ExitReason::Timeout { timeout_ms: cfg.timeout.as_millis() as u64 },
+                    },
+                    None => {
+                        if let Some(u) = soak.as_mut().and_then(|m| m.overdue(start.elapsed())) {
+                            break ExitReason::Unhealthy(u);
+                        }
+                        if idle_at.is_some_and(|t| Instant::now() >= t) {
+                            break ExitReason::Hang {
+                                idle_secs: cfg.idle_timeout.unwrap_or_default().as_secs(),
+                                lines: tracker.lines(),
+                            };
+                        }
+                    }
+                }
+            }
+        }
+    };
+    drop(link);
+    let control = match (&cfg.control, session) {
+        (Some(c), Some(session)) => Some(c.report(session)),
+        _ => None,
+    };
+    let failing = match &reason {
+        ExitReason::PatternMatched | ExitReason::Survived { .. } => false,
+        ExitReason::DeviceExit(exit) => !exit.passed,
+        _ => true,
+    };
+    let mut dump = None;
+    let mut saved = Ok(());
+    let mut shutdown = Shutdown::Exited;
+    if !exited {
+        if let Some(socket) = &cfg.qmp_socket {
+            if failing {
+                let (screenshot, core) = match &remote {
+                    Some(session) => (&session.screenshot, &session.core_dump),
+                    None => (&cfg.screenshot, &cfg.core_dump),
+                };
+                dump = dump_failure(cfg, socket, screenshot.as_deref(), core.as_deref()).await;
+            } else if let Some(name) = &cfg.save_snapshot {
+                saved = qmp::save_snapshot(socket, name).await;
+            }
+        }
+        let timed_out = matches!(
+            reason,
+            ExitReason::Timeout { .. } | ExitReason::Hang { .. } | ExitReason::Survived { .. }
+        );
+        shutdown = stop(cfg.qmp_socket.as_deref(), timed_out, &mut child, &group).await;
+        tracing::debug!(step = shutdown.name(), "QEMU stopped");
+        if let Some(socket) = &cfg.qmp_socket {
+            let _ = std::fs::remove_file(socket);
+        }
+    }
+    // Whatever else QEMU's group still holds.
+    group.kill();
+    let _ = child.start_kill();
+    let _ = child.wait().await;
+    if let Some(session) = &mut remote {
+        session.finish(&mut dump).await;
+    }
+    let cpu_log = cfg.cpu_log.as_deref().and_then(read_cpu_log);
+    let coverage = cfg
+        .coverage
+        .as_ref()
+        .and_then(|c| match Executed::from_log(c) {
+            Ok(executed) => Some(executed),
+            Err(e) => {
+                tracing::warn!(log = %c.log.display(), "no coverage: {e}");
+                None
+            }
+        });
+    let logs = [cfg.cpu_log.as_ref(), cfg.coverage.as_ref().map(|c| &c.log)];
+    for log in logs
+        .into_iter()
+        .flatten()
+        .filter(|&l| cfg.trace.as_ref() != Some(l))
+    {
+        let _ = std::fs::remove_file(log);
+    }
+    let trace = cfg
+        .trace
+        .as_deref()
+        .and_then(|log| match TraceSummary::from_file(log) {
+            Ok(summary) => Some(summary),
+            Err(e) => {
+                tracing::warn!(log = %log.display(), "no trace summary: {e}");
+                None
+            }
+        });
+    let gdb_transcript = match gdb_task {
+        Some(task) => Some(gdb::collect(task).await),
+        None => None,
+    };
+    saved?;
+
+    let transcript = ring.contents();
+    let passed = !failing
+        && parse_serial(&transcript).failed == 0
+        && control
+            .as_ref()
+            .is_none_or(|c| c.cases.iter().all(|c| c.ok()));
+    let golden = cfg
+        .golden
+        .as_ref()
+        .map(|g| g.check(&transcript, ring.dropped(), passed));
+    let screens = cfg
+        .screens
+        .iter()
+        .zip(captured)
+        .map(|(s, captured)| s.check(captured, passed))
+        .collect();
+    let packets = cfg
+        .packets
+        .as_ref()
+        .map(|h| h.results(exchanged, &sent))
+        .unwrap_or_default();
+    let mut result = RunResult {
+        failure: failing
+            .then(|| classify::classify(&transcript, cpu_log.as_deref()))
+            .flatten(),
+        reason,
+        duration_ms: start.elapsed().as_millis() as u64,
+        accel: cfg.accel,
+        matched: [tracker.matched(), steps.matched().to_vec()].concat(),
+        unmatched: tracker
+            .unmatched()
+            .into_iter()
+            .chain(steps.waiting())
+            .collect(),
+        transcript,
+        transcript_dropped: ring.dropped(),
+        backtrace: Vec::new(),
+        dump,
+        gdb_transcript,
+        trace,
+        golden,
+        soak: soak.map(|m| m.report()),
+        injected,
+        deterministic: cfg.deterministic.clone(),
+        screens,
+        packets,
+        control,
+        replay: None,
+        shutdown,
+        coverage,
+    };
+    if let Some(log) = &cfg.record {
+        let passed = result.passed(&result.summary());
+        result.replay = replay::finish(cfg, log, !passed)?;
+    }
+    Ok(result)
+}
+
+/// Wait for `session`'s next case, or never without one.
+async fn update(session: &mut Option<Session>) {
+    match session {
+        Some(session) => session.update().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The next exchange `link` has done, or never without one.
+async fn next_exchanged(link: &mut Option<Link>) -> Option<Exchanged> {
+    match link {
+        Some(link) => link.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The last [`CPU_LOG_TAIL`] bytes of QEMU's `-D` log.
+fn read_cpu_log(path: &std::path::Path) -> Option<String> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut file = std::fs::File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(CPU_LOG_TAIL)))
+        .ok()?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).ok()?;
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// The failed guest's state, with the screenshot and core written where
+/// QEMU sees those paths, or `None` (logged) when QMP does not answer or
+/// had nothing to give.
+async fn dump_failure(
+    cfg: &RunConfig,
+    socket: &std::path::Path,
+    screenshot: Option<&std::path::Path>,
+    core: Option<&std::path::Path>,
+) -> Option<FailureDump> {
+    match qmp::dump_failure(socket, &cfg.dump_memory, screenshot, core).await {
+        Ok(dump) => Some(dump).filter(|d| !d.is_empty()),
+        Err(e) => {
+            tracing::warn!("no failure dump: {e:#}");
+            None
+        }
+    }
+}
+
+/// Type `input` into the guest console, if there is any.
+async fn send(stdin: &mut Option<ChildStdin>, input: String) {
+    let Some(pipe) = stdin.as_mut().filter(|_| !input.is_empty()) else {
+        return;
+    };
+    tracing::debug!(target: "serial", input = %input.escape_debug(), "sending");
+    if let Err(e) = pipe.write_all(input.as_bytes()).await {
+        tracing::warn!("serial input: {e}");
+        *stdin = None;
+    }
+}
+
+/// Stop a QEMU that is still running, escalating from QMP (`quit`, or the
+/// ACPI power button for a guest that `timed_out`) to SIGTERM and then
+/// SIGKILL of its process group; returns the step it exited after.
+async fn stop(
+    socket: Option<&std::path::Path>,
+    timed_out: bool,
+    child: &mut Child,
+    group: &ProcessGroup,
+) -> Shutdown {
+    if let Some(socket) = socket {
+        let (step, asked, grace) = if timed_out {
+            (
+                Shutdown::Powerdown,
+                qmp::powerdown(socket).await,
+                POWERDOWN_GRACE,
+            )
+        } else {
+            (Shutdown::Quit, qmp::quit(socket).await, QUIT_GRACE)
+        };
+        match asked {
+            Ok(()) if exits_within(child, grace).await => return step,
+            Ok(()) => tracing::debug!("QEMU still running {grace:?} after {}", step.name()),
+            Err(e) => tracing::debug!("no clean QEMU shutdown: {e:#}"),
+        }
+    }
+    #[cfg(unix)]
+    {
+        group.signal(libc::SIGTERM);
+        if exits_within(child, TERM_GRACE).await {
+            return Shutdown::Sigterm;
+        }
+        tracing::warn!("QEMU ignored SIGTERM; killing its process group");
+    }
+    group.kill();
+    let _ = child.start_kill();
+    let _ = child.wait().await;
+    Shutdown::Sigkill
+}
+
+async fn exits_within(child: &mut Child, grace: Duration) -> bool {
+    matches!(tokio::time::timeout(grace, child.wait()).await, Ok(Ok(_)))
+}
+
+/// QEMU's process group (it leads one, `process_group(0)`); killed on
+/// drop, so nothing it spawned outlives the run.
+struct ProcessGroup(Option<u32>);
+
+impl ProcessGroup {
+    #[cfg(unix)]
+    fn signal(&self, signal: libc::c_int) {
+        if let Some(pid) = self.0 {
+            // SAFETY: plain syscall on a group this run created.
+            unsafe {
+                libc::kill(-(pid as libc::pid_t), signal);
+            }
+        }
+    }
+
+    fn kill(&self) {
+        #[cfg(unix)]
+        self.signal(libc::SIGKILL);
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// Read `reader` to EOF, keeping the first `limit` bytes.
+async fn read_bounded(mut reader: impl AsyncRead + Unpin, limit: usize) -> String {
+    let mut kept = Vec::new();
+    let mut buf = [0u8; 4096];
+    while let Ok(n) = reader.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+        let room = limit.saturating_sub(kept.len());
+        kept.extend_from_slice(&buf[..n.min(room)]);
+    }
+    String::from_utf8_lossy(&kept).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(script: &str) -> RunConfig {
+        RunConfig {
+            program: "sh".into(),
+            args: vec!["-c".into(), script.into()],
+            timeout: Duration::from_secs(5),
+            transcript_limit: 1024,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn guest_exit_code_ends_the_run_when_waiting() {
+        // isa-debug-exit status for a guest write of 0x11 (fail).
+        let cfg = RunConfig {
+            exit_device: ExitDevice::IsaDebugExit,
+            wait_for_exit: true,
+            ..config("echo '[BOOT] OK'; echo '[TEST] late: PASS'; exit 35")
+        };
+        let result = run(&cfg).await.unwrap();
+        let ExitReason::DeviceExit(exit) = result.reason else {
+            panic!("expected a device exit, got {:?}", result.reason);
+        };
+        assert_eq!((exit.code, exit.passed), (0x11, false));
+        assert!(result.transcript.ends_with("[TEST] late: PASS\n"));
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_expected_pattern() {
+        let cfg = config("echo '[BOOT] Long mode'; echo '[BOOT] OK'; sleep 30");
+        let result = run(&cfg).await.unwrap();
+        assert_eq!(result.reason, ExitReason::PatternMatched);
+        assert!(result.duration_ms < 5000);
+        assert_eq!(result.transcript, "[BOOT] Long mode\n[BOOT] OK\n");
+        assert_eq!(result.matched[0].line_no, 2);
+        assert!(result.unmatched.is_empty());
+    }
+
+    #[tokio::test]
+    async fn silent_guest_is_a_hang_before_the_timeout() {
+        let cfg = RunConfig {
+            idle_timeout: Some(Duration::from_millis(300)),
+            ..config("echo '[BOOT] Long mode'; sleep 30")
+        };
+        let result = run(&cfg).await.unwrap();
+        assert_eq!(
+            result.reason,
+            ExitReason::Hang {
+                idle_secs: 0,
+                lines: 1,
+            }
+        );
+        assert!(result.duration_ms < 5000);
+        assert_eq!(
+            result.explain()[0],
+            "hang: no serial output for 0s after line 1"
+        );
+    }
+
+    #[tokio::test]
+    async fn steps_answer_prompts() {
+        let steps =
+            [("shell> $", "echo hi\n"), ("^hi$", "")].map(|(expect, send)| crate::steps::Step {
+                expect: Some(expect.into()),
+                send: Some(send.into()),
+            });
+        let cfg = RunConfig {
+            steps: Steps::new(&steps).unwrap(),
+            ..config("echo '[BOOT] OK'; printf 'shell> '; read cmd; echo; $cmd; sleep 30")
+        };
+        let result = run(&cfg).await.unwrap();
+        assert_eq!(result.reason, ExitReason::PatternMatched);
+        assert_eq!(result.transcript, "[BOOT] OK\nshell> \nhi\n");
+        assert_eq!(result.matched.len(), 3);
+
+        let stuck = RunConfig {
+            timeout: Duration::from_millis(300),
+            ..RunConfig {
+                steps: cfg.steps.clone(),
+                ..config("echo '[BOOT] OK'; sleep 30")
+            }
+        };
+        let result = run(&stuck).await.unwrap();
+        assert_eq!(result.unmatched, ["step 1: shell> $"]);
+    }
+
+    #[tokio::test]
+    async fn timeouts_escalate_to_sigkill() {
+        let cfg = RunConfig {
+            timeout: Duration::from_millis(200),
+            ..config("sleep 30")
+        };
+        let result = run(&cfg).await.unwrap();
+        assert_eq!(result.reason, ExitReason::Timeout { timeout_ms: 200 });
+        assert_eq!(result.shutdown, Shutdown::Sigterm);
+        let stubborn = RunConfig {
+            timeout: Duration::from_millis(200),
+            ..config("trap '' TERM; while :; do sleep 0.1; done")
+        };
+        assert_eq!(run(&stubborn).await.unwrap().shutdown, Shutdown::Sigkill);
+    }
+
+    #[tokio::test]
+    async fn reports_qemu_exit_with_stderr() {
+        let cfg = config("echo 'qemu: could not load kernel' >&2; exit 1");
+        let result = run(&cfg).await.unwrap();
+        assert_eq!(
+            result.reason,
+            ExitReason::QemuError {
+                status: Some(1),
+                stderr: "qemu: could not load kernel\n".into()
+            }
+        );
+        assert_eq!(result.unmatched, [DEFAULT_EXPECT]);
+        assert_eq!(result.shutdown, Shutdown::Exited);
+    }
+}
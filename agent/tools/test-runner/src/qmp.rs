@@ -0,0 +1,572 @@
+//! A minimal QMP (QEMU Machine Protocol) client.
+//!
+//! Every run starts QEMU with `-qmp unix:<socket>,server=on,wait=off`; the
+//! client connects, negotiates capabilities and sends typed commands
+//! ([`Qmp::stop`], [`Qmp::screendump`], [`Qmp::read_memory`], ...), with
+//! `human-monitor-command` for monitor text such as `info registers`. The
+//! runner uses it to pause a failed guest and capture a [`FailureDump`]
+//! before it is killed, and to `quit` QEMU cleanly at the end of a run.
+//!
+//! With `--dump-on-failure` the dump includes all of guest RAM, written by
+//! `dump-guest-memory` as an ELF core: `gdb kernel.elf vmcore.elf` then
+//! shows the registers and memory of the failed guest. The core is built
+//! from the guest's page tables, so kernel virtual addresses resolve; a
+//! guest that has not enabled paging yet is dumped by physical address.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{json, Value};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+
+/// How long a dump may take before it is given up on.
+pub const DUMP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long QEMU may take to answer `quit`.
+pub const QUIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long `savevm` may take (it writes all of guest RAM).
+pub const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long `dump-guest-memory` may take (it writes all of guest RAM).
+pub const CORE_DUMP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest `--dump-memory` range.
+pub const MAX_MEMORY_DUMP: u64 = 64 * 1024;
+
+/// A fresh socket path (see [`crate::scratch_path`]).
+pub fn socket_path() -> PathBuf {
+    crate::scratch_path("qmp")
+}
+
+/// QEMU flags that serve QMP on `socket` without waiting for a client.
+pub fn qemu_args(socket: &Path) -> Vec<String> {
+    vec![
+        "-qmp".to_string(),
+        format!("unix:{},server=on,wait=off", socket.display()),
+    ]
+}
+
+/// `query-status`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Status {
+    pub running: bool,
+    /// Run state: `running`, `paused`, `debug`, `shutdown`, ...
+    pub status: String,
+}
+
+/// A physical memory range, `ADDR:LEN` on the command line (hex with `0x`,
+/// else decimal).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct MemoryRange {
+    pub address: u64,
+    pub len: u64,
+}
+
+impl FromStr for MemoryRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let number = |n: &str| match n.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => n.parse(),
+        };
+        let (address, len) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected ADDR:LEN, got `{s}`"))?;
+        let address = number(address).map_err(|e| format!("bad address `{address}`: {e}"))?;
+        let len = number(len).map_err(|e| format!("bad length `{len}`: {e}"))?;
+        if len == 0 || len > MAX_MEMORY_DUMP {
+            return Err(format!("length must be 1..={MAX_MEMORY_DUMP} bytes"));
+        }
+        Ok(Self { address, len })
+    }
+}
+
+impl TryFrom<String> for MemoryRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for MemoryRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}:{:#x}", self.address, self.len)
+    }
+}
+
+/// Guest memory read by [`Qmp::read_memory`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MemoryDump {
+    pub address: u64,
+    /// Hex string in reports.
+    #[serde(serialize_with = "hex")]
+    pub data: Vec<u8>,
+}
+
+impl MemoryDump {
+    /// `hexdump -C`-style lines: address, 16 bytes, ASCII.
+    pub fn lines(&self) -> Vec<String> {
+        self.data
+            .chunks(16)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let bytes: Vec<String> = chunk.iter().map(|b| format!("{b:02x}")).collect();
+                let ascii: String = chunk
+                    .iter()
+                    .map(|&b| {
+                        if b.is_ascii_graphic() || b == b' ' {
+                            b as char
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect();
+                format!(
+                    "{:016x}  {:<47}  |{ascii}|",
+                    self.address + 16 * i as u64,
+                    bytes.join(" ")
+                )
+            })
+            .collect()
+    }
+}
+
+fn hex<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+    let text: String = data.iter().map(|b| format!("{b:02x}")).collect();
+    s.serialize_str(&text)
+}
+
+/// State captured from a failed guest, paused, before QEMU is killed. Parts
+/// QEMU could not provide are left empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FailureDump {
+    /// `info cpus` and `info registers`, as monitor text.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_state: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub memory: Vec<MemoryDump>,
+    /// Screendump of the guest display.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub screenshot: Option<PathBuf>,
+    /// ELF core of all guest RAM (`--dump-on-failure`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub core: Option<PathBuf>,
+}
+
+impl FailureDump {
+    pub fn is_empty(&self) -> bool {
+        self.cpu_state.is_none()
+            && self.memory.is_empty()
+            && self.screenshot.is_none()
+            && self.core.is_none()
+    }
+}
+
+pub struct Qmp {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Qmp {
+    /// Connect and leave capabilities negotiation mode.
+    pub async fn connect(socket: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(socket)
+            .await
+            .with_context(|| format!("connecting to QMP at {}", socket.display()))?;
+        let (read, writer) = stream.into_split();
+        let mut qmp = Self {
+            lines: BufReader::new(read).lines(),
+            writer,
+        };
+        let greeting = qmp.message().await?;
+        if greeting.get("QMP").is_none() {
+            bail!("unexpected QMP greeting: {greeting}");
+        }
+        qmp.execute("qmp_capabilities", json!({})).await?;
+        Ok(qmp)
+    }
+
+    /// Run a QMP command and return its `return` value.
+    pub async fn execute(&mut self, command: &str, arguments: Value) -> Result<Value> {
+        let mut request = json!({ "execute": command, "arguments": arguments }).to_string();
+        request.push('\n');
+        self.writer.write_all(request.as_bytes()).await?;
+        loop {
+            let mut reply = self.message().await?;
+            if let Some(ret) = reply.get_mut("return") {
+                return Ok(ret.take());
+            }
+            if let Some(err) = reply.get("error") {
+                bail!(
+                    "QMP {command}: {}",
+                    err["desc"].as_str().unwrap_or("unknown error")
+                );
+            }
+            // Asynchronous events (STOP, RESET, ...) are skipped.
+        }
+    }
+
+    /// Run a human monitor command such as `info registers`.
+    pub async fn hmp(&mut self, command_line: &str) -> Result<String> {
+        let out = self
+            .execute(
+                "human-monitor-command",
+                json!({ "command-line": command_line }),
+            )
+            .await?;
+        Ok(out.as_str().unwrap_or_default().replace("\r\n", "\n"))
+    }
+
+    pub async fn status(&mut self) -> Result<Status> {
+        let status = self.execute("query-status", json!({})).await?;
+        serde_json::from_value(status).context("bad query-status reply")
+    }
+
+    /// Pause the guest's CPUs.
+    pub async fn stop(&mut self) -> Result<()> {
+        self.execute("stop", json!({})).await.map(drop)
+    }
+
+    /// Resume a paused guest.
+    pub async fn cont(&mut self) -> Result<()> {
+        self.execute("cont", json!({})).await.map(drop)
+    }
+
+    /// Ask the guest to shut down (ACPI power button).
+    pub async fn system_powerdown(&mut self) -> Result<()> {
+        self.execute("system_powerdown", json!({})).await.map(drop)
+    }
+
+    /// Exit QEMU at once. The connection may close before the reply.
+    pub async fn quit(&mut self) -> Result<()> {
+        match self.execute("quit", json!({})).await {
+            Err(e) if e.to_string() == CLOSED => Ok(()),
+            other => other.map(drop),
+        }
+    }
+
+    /// Save the display to `path`: PNG if it ends in `.png`, else PPM.
+    pub async fn screendump(&mut self, path: &Path) -> Result<()> {
+        let mut args = json!({ "filename": path.display().to_string() });
+        if path.extension().is_some_and(|e| e == "png") {
+            args["format"] = json!("png");
+        }
+        self.execute("screendump", args).await.map(drop)
+    }
+
+    /// Save VM state as internal snapshot `name` in the first writable
+    /// qcow2 drive. HMP reports failure as output, not a QMP error.
+    pub async fn savevm(&mut self, name: &str) -> Result<()> {
+        let out = self.hmp(&format!("savevm {name}")).await?;
+        match out.trim() {
+            "" => Ok(()),
+            err => bail!("savevm {name}: {err}"),
+        }
+    }
+
+    /// Restore internal snapshot `name`.
+    pub async fn loadvm(&mut self, name: &str) -> Result<()> {
+        let out = self.hmp(&format!("loadvm {name}")).await?;
+        match out.trim() {
+            "" => Ok(()),
+            err => bail!("loadvm {name}: {err}"),
+        }
+    }
+
+    /// Write all of guest RAM to `path` as an ELF core, its segments at
+    /// the guest's virtual addresses with `paging`, else physical ones.
+    pub async fn dump_guest_memory(&mut self, path: &Path, paging: bool) -> Result<()> {
+        let args = json!({
+            "paging": paging,
+            "protocol": format!("file:{}", path.display()),
+        });
+        self.execute("dump-guest-memory", args).await.map(drop)
+    }
+
+    /// `info registers` for every CPU.
+    pub async fn registers(&mut self) -> Result<String> {
+        self.hmp("info registers").await
+    }
+
+    /// Read guest physical memory (`pmemsave` through a scratch file).
+    pub async fn read_memory(&mut self, range: MemoryRange) -> Result<MemoryDump> {
+        let file = crate::scratch_path("mem");
+        let args = json!({
+            "val": range.address,
+            "size": range.len,
+            "filename": file.display().to_string(),
+        });
+        let saved = self.execute("pmemsave", args).await;
+        let data = saved.and_then(|_| {
+            std::fs::read(&file).with_context(|| format!("reading {}", file.display()))
+        });
+        let _ = std::fs::remove_file(&file);
+        Ok(MemoryDump {
+            address: range.address,
+            data: data.with_context(|| format!("dumping memory at {range}"))?,
+        })
+    }
+
+    async fn message(&mut self) -> Result<Value> {
+        let line = self.lines.next_line().await?.context(CLOSED)?;
+        serde_json::from_str(&line).with_context(|| format!("bad QMP message: {line}"))
+    }
+}
+
+const CLOSED: &str = "QMP connection closed";
+
+/// Pause the guest and capture its CPU state, the `memory` ranges, and a
+/// screendump to `screenshot`. Only failing to connect is an error; other
+/// parts are logged and left out.
+pub async fn dump_failure(
+    socket: &Path,
+    memory: &[MemoryRange],
+    screenshot: Option<&Path>,
+    core: Option<&Path>,
+) -> Result<FailureDump> {
+    let mut qmp = tokio::time::timeout(DUMP_TIMEOUT, Qmp::connect(socket))
+        .await
+        .context("QMP connect timed out")??;
+    let mut dump = FailureDump::default();
+    let capture = async {
+        if let Err(e) = qmp.stop().await {
+            tracing::warn!("cannot pause the guest for the dump: {e:#}");
+        }
+        let mut state = String::new();
+        for cmd in ["info cpus", "info registers"] {
+            match qmp.hmp(cmd).await {
+                Ok(out) => state.push_str(&format!("(qemu) {cmd}\n{out}")),
+                Err(e) => tracing::warn!("`{cmd}` failed: {e:#}"),
+            }
+        }
+        dump.cpu_state = Some(state).filter(|s| !s.is_empty());
+        for &range in memory {
+            match qmp.read_memory(range).await {
+                Ok(m) => dump.memory.push(m),
+                Err(e) => tracing::warn!("{e:#}"),
+            }
+        }
+        if let Some(path) = screenshot {
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            match qmp.screendump(path).await {
+                Ok(()) => dump.screenshot = Some(path.to_path_buf()),
+                Err(e) => tracing::warn!("no screenshot: {e:#}"),
+            }
+        }
+    };
+    if tokio::time::timeout(DUMP_TIMEOUT, capture).await.is_err() {
+        tracing::warn!("QMP dump timed out; keeping what was captured");
+    }
+    if let Some(path) = core {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        let write = async {
+            if let Err(e) = qmp.dump_guest_memory(path, true).await {
+                tracing::debug!("no paged core ({e:#}); dumping physical memory");
+                qmp.dump_guest_memory(path, false).await?;
+            }
+            anyhow::Ok(())
+        };
+        match tokio::time::timeout(CORE_DUMP_TIMEOUT, write).await {
+            Ok(Ok(())) => dump.core = Some(path.to_path_buf()),
+            Ok(Err(e)) => tracing::warn!("no core dump: {e:#}"),
+            Err(_) => tracing::warn!("core dump timed out"),
+        }
+        if dump.core.is_none() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    Ok(dump)
+}
+
+/// Pause the guest and save snapshot `name`.
+pub async fn save_snapshot(socket: &Path, name: &str) -> Result<()> {
+    let save = async {
+        let mut qmp = Qmp::connect(socket).await?;
+        qmp.stop().await?;
+        qmp.savevm(name).await
+    };
+    tokio::time::timeout(SNAPSHOT_TIMEOUT, save)
+        .await
+        .context("savevm timed out")?
+}
+
+/// Press the ACPI power button, resuming the guest first in case a
+/// failure dump paused it; the caller waits for QEMU to exit.
+pub async fn powerdown(socket: &Path) -> Result<()> {
+    let press = async {
+        let mut qmp = Qmp::connect(socket).await?;
+        qmp.cont().await?;
+        qmp.system_powerdown().await
+    };
+    tokio::time::timeout(QUIT_TIMEOUT, press)
+        .await
+        .context("QMP system_powerdown timed out")?
+}
+
+/// Tell QEMU to quit; the caller still reaps (and if need be kills) it.
+pub async fn quit(socket: &Path) -> Result<()> {
+    let quit = async { Qmp::connect(socket).await?.quit().await };
+    tokio::time::timeout(QUIT_TIMEOUT, quit)
+        .await
+        .context("QMP quit timed out")?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixListener;
+
+    /// Answers like QEMU: a greeting, then a reply per request, with an
+    /// event thrown in before the first monitor reply. `pmemsave` writes
+    /// the address's low bytes, `screendump` an empty file, and
+    /// `dump-guest-memory` an ELF magic, refusing paged dumps.
+    async fn fake_qemu(listener: UnixListener) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        write
+            .write_all(b"{\"QMP\": {\"version\": {}, \"capabilities\": []}}\r\n")
+            .await
+            .unwrap();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let req: Value = serde_json::from_str(&line).unwrap();
+            let args = &req["arguments"];
+            let reply = match req["execute"].as_str().unwrap() {
+                "human-monitor-command" => match args["command-line"].as_str().unwrap() {
+                    "info cpus" => {
+                        write
+                            .write_all(b"{\"event\": \"STOP\", \"data\": {}}\r\n")
+                            .await
+                            .unwrap();
+                        json!({ "return": "* CPU #0: thread_id=1 (halted)\r\n" })
+                    }
+                    "savevm boot" => json!({
+                        "return": "Error: No block device can accept snapshots\r\n"
+                    }),
+                    cmd => json!({ "return": format!("RIP=ffffffff80001000 [{cmd}]\r\n") }),
+                },
+                "query-status" => json!({ "return": { "running": false, "status": "paused" } }),
+                "pmemsave" => {
+                    let val = args["val"].as_u64().unwrap().to_le_bytes();
+                    let size = args["size"].as_u64().unwrap() as usize;
+                    std::fs::write(args["filename"].as_str().unwrap(), &val[..size]).unwrap();
+                    json!({ "return": {} })
+                }
+                "screendump" if args["format"] == "png" => {
+                    json!({ "error": { "class": "GenericError", "desc": "no png support" } })
+                }
+                "screendump" => {
+                    std::fs::write(args["filename"].as_str().unwrap(), b"").unwrap();
+                    json!({ "return": {} })
+                }
+                "dump-guest-memory" if args["paging"] == true => json!({
+                    "error": { "class": "GenericError", "desc": "paging not enabled" }
+                }),
+                "dump-guest-memory" => {
+                    let file = args["protocol"].as_str().unwrap();
+                    std::fs::write(file.strip_prefix("file:").unwrap(), b"\x7fELF").unwrap();
+                    json!({ "return": {} })
+                }
+                _ => json!({ "return": {} }),
+            };
+            write
+                .write_all(format!("{reply}\r\n").as_bytes())
+                .await
+                .unwrap();
+        }
+    }
+
+    fn serve() -> (PathBuf, tokio::task::JoinHandle<()>) {
+        let socket = socket_path();
+        let server = tokio::spawn(fake_qemu(UnixListener::bind(&socket).unwrap()));
+        (socket, server)
+    }
+
+    #[tokio::test]
+    async fn dumps_a_failed_guest() {
+        let (socket, server) = serve();
+        let screen = crate::scratch_path("ppm");
+        let core = crate::scratch_path("core");
+        let range: MemoryRange = "0x4241:2".parse().unwrap();
+        let dump = dump_failure(&socket, &[range], Some(&screen), Some(&core))
+            .await
+            .unwrap();
+        assert_eq!(
+            dump.cpu_state.as_deref(),
+            Some(
+                "(qemu) info cpus\n* CPU #0: thread_id=1 (halted)\n\
+                 (qemu) info registers\nRIP=ffffffff80001000 [info registers]\n"
+            )
+        );
+        assert_eq!(dump.memory[0].data, b"AB");
+        assert_eq!(
+            dump.memory[0].lines(),
+            ["0000000000004241  41 42                                            |AB|"]
+        );
+        assert_eq!(dump.screenshot.as_deref(), Some(screen.as_path()));
+        assert_eq!(dump.core.as_deref(), Some(core.as_path()));
+        assert_eq!(std::fs::read(&core).unwrap(), b"\x7fELF");
+        server.abort();
+        std::fs::remove_file(&screen).unwrap();
+        std::fs::remove_file(&core).unwrap();
+        std::fs::remove_file(&socket).unwrap();
+    }
+
+    #[tokio::test]
+    async fn typed_commands_report_qemu_errors() {
+        let (socket, server) = serve();
+        let mut qmp = Qmp::connect(&socket).await.unwrap();
+        qmp.stop().await.unwrap();
+        assert_eq!(
+            qmp.status().await.unwrap(),
+            Status {
+                running: false,
+                status: "paused".into()
+            }
+        );
+        let err = qmp.screendump(Path::new("s.png")).await.unwrap_err();
+        assert_eq!(err.to_string(), "QMP screendump: no png support");
+        let err = qmp.savevm("boot").await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "savevm boot: Error: No block device can accept snapshots"
+        );
+        qmp.quit().await.unwrap();
+        server.abort();
+        std::fs::remove_file(&socket).unwrap();
+    }
+
+    #[test]
+    fn parses_memory_ranges() {
+        assert_eq!(
+            "0xb8000:4000".parse(),
+            Ok(MemoryRange {
+                address: 0xb8000,
+                len: 4000
+            })
+        );
+        assert!("0xb8000".parse::<MemoryRange>().is_err());
+        assert!("0:0x100000".parse::<MemoryRange>().is_err());
+    }
+
+    #[tokio::test]
+    async fn missing_socket_is_an_error() {
+        let err = dump_failure(Path::new("/nonexistent/qmp.sock"), &[], None, None)
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("connecting to QMP"));
+    }
+}
@@ -0,0 +1,739 @@
+//! A small backtracking regex engine for serial patterns.
+//!
+//! Supports what expect/forbid patterns need: literals, `.`, classes
+//! (`[a-z_]`, `[^0-9]`), `\d \w \s` and their negations, `\b`, anchors `^`
+//! and `$`, groups `(…)`/`(?:…)` with `|`, and the quantifiers `* + ? {n}
+//! {n,} {n,m}` (append `?` for lazy). A leading `(?i)` makes the pattern
+//! case-insensitive. Matching is unanchored and per line.
+//!
+//! Patterns compile to a small program that runs as a Pike VM: every way
+//! the pattern could be going at once, one thread per instruction per char,
+//! with a backtracker's preferences breaking ties. Time is the line length
+//! times the program size, whatever the pattern, and nothing recurses per
+//! char, so no guest line can overflow the stack or stall the runner.
+//! Counted repeats are expanded, and a pattern is rejected past
+//! `MAX_PROGRAM` instructions.
+
+use anyhow::{bail, Result};
+use serde::{Serialize, Serializer};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Char(char),
+    Any,
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Start,
+    End,
+    WordBoundary,
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+        greedy: bool,
+    },
+}
+
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+const SPACE: &[(char, char)] = &[('\t', '\r'), (' ', ' ')];
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Regex {
+    source: String,
+    prog: Vec<Inst>,
+    ignore_case: bool,
+}
+
+impl fmt::Debug for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Regex({:?})", self.source)
+    }
+}
+
+impl fmt::Display for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Serialize for Regex {
+    fn serialize<S: Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.serialize_str(&self.source)
+    }
+}
+
+impl Regex {
+    pub fn new(source: &str) -> Result<Self> {
+        let (ignore_case, body) = match source.strip_prefix("(?i)") {
+            Some(rest) => (true, rest),
+            None => (false, source),
+        };
+        let mut parser = Parser {
+            chars: body.chars().collect(),
+            pos: 0,
+            source,
+        };
+        let root = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            bail!("regex `{source}`: unmatched `)` at offset {}", parser.pos);
+        }
+        let mut compiler = Compiler {
+            prog: Vec::new(),
+            source,
+        };
+        compiler.node(&root)?;
+        compiler.emit(Inst::Match)?;
+        Ok(Self {
+            source: source.to_string(),
+            prog: compiler.prog,
+            ignore_case,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// `text` as the matcher sees it: one char per char, so match offsets
+    /// index `text`'s chars too.
+    fn chars(&self, text: &str) -> Vec<char> {
+        if self.ignore_case {
+            text.chars().map(fold).collect()
+        } else {
+            text.chars().collect()
+        }
+    }
+
+    fn vm<'a>(&'a self, chars: &'a [char]) -> Vm<'a> {
+        Vm {
+            prog: &self.prog,
+            s: chars,
+            ignore_case: self.ignore_case,
+        }
+    }
+
+    /// Whether the pattern matches anywhere in `text`.
+    pub fn is_match(&self, text: &str) -> bool {
+        let chars = self.chars(text);
+        self.vm(&chars).find(0, true).is_some()
+    }
+
+    /// Byte offsets of the leftmost match in `text`.
+    pub fn find(&self, text: &str) -> Option<(usize, usize)> {
+        let offsets: Vec<usize> = text
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain([text.len()])
+            .collect();
+        let chars = self.chars(text);
+        let (start, end) = self.vm(&chars).find(0, false)?;
+        Some((offsets[start], offsets[end]))
+    }
+
+    /// `text` with each leftmost non-overlapping match replaced by `with`
+    /// (taken literally; there are no capture groups).
+    pub fn replace_all(&self, text: &str, with: &str) -> String {
+        let original: Vec<char> = text.chars().collect();
+        let chars = self.chars(text);
+        let vm = self.vm(&chars);
+        let mut out = String::with_capacity(text.len());
+        let mut i = 0;
+        while let Some((start, end)) = (i <= chars.len()).then(|| vm.find(i, false)).flatten() {
+            out.extend(&original[i..start]);
+            out.push_str(with);
+            if end == start {
+                // An empty match: keep the char it stood before.
+                out.extend(original.get(start));
+                i = start + 1;
+            } else {
+                i = end;
+            }
+        }
+        out.extend(original.get(i..).unwrap_or_default());
+        out
+    }
+}
+
+/// The case a `(?i)` pattern and its text are both matched in.
+fn fold(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    source: &'a str,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn err<T>(&self, what: &str) -> Result<T> {
+        bail!("regex `{}`: {what} at offset {}", self.source, self.pos)
+    }
+
+    fn alternation(&mut self) -> Result<Node> {
+        let mut alts = vec![self.concat()?];
+        while self.eat('|') {
+            alts.push(self.concat()?);
+        }
+        Ok(if alts.len() == 1 {
+            alts.pop().unwrap()
+        } else {
+            Node::Alt(alts)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn atom(&mut self) -> Result<Node> {
+        let c = self.peek().unwrap();
+        self.pos += 1;
+        Ok(match c {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '(' => {
+                if self.eat('?') && !self.eat(':') {
+                    return self.err("unsupported group flag");
+                }
+                let inner = self.alternation()?;
+                if !self.eat(')') {
+                    return self.err("unclosed `(`");
+                }
+                inner
+            }
+            '[' => self.class()?,
+            '\\' => self.escape()?,
+            '*' | '+' | '?' => return self.err("quantifier with nothing to repeat"),
+            c => Node::Char(c),
+        })
+    }
+
+    fn escape(&mut self) -> Result<Node> {
+        let Some(c) = self.peek() else {
+            return self.err("trailing `\\`");
+        };
+        self.pos += 1;
+        let class = |ranges: &[(char, char)], negated| Node::Class {
+            ranges: ranges.to_vec(),
+            negated,
+        };
+        Ok(match c {
+            'd' => class(DIGIT, false),
+            'D' => class(DIGIT, true),
+            'w' => class(WORD, false),
+            'W' => class(WORD, true),
+            's' => class(SPACE, false),
+            'S' => class(SPACE, true),
+            'b' => Node::WordBoundary,
+            _ => Node::Char(self.literal_escape(c)?),
+        })
+    }
+
+    fn literal_escape(&self, c: char) -> Result<char> {
+        Ok(match c {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            c if c.is_ascii_alphanumeric() => return self.err(&format!("unknown escape `\\{c}`")),
+            c => c,
+        })
+    }
+
+    fn class(&mut self) -> Result<Node> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let Some(c) = self.peek() else {
+                return self.err("unclosed `[`");
+            };
+            self.pos += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = match c {
+                '\\' => {
+                    let Some(e) = self.peek() else {
+                        return self.err("trailing `\\`");
+                    };
+                    self.pos += 1;
+                    match e {
+                        'd' => {
+                            ranges.extend_from_slice(DIGIT);
+                            continue;
+                        }
+                        'w' => {
+                            ranges.extend_from_slice(WORD);
+                            continue;
+                        }
+                        's' => {
+                            ranges.extend_from_slice(SPACE);
+                            continue;
+                        }
+                        e => self.literal_escape(e)?,
+                    }
+                }
+                c => c,
+            };
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|c| *c != ']') {
+                self.pos += 1;
+                let mut hi = self.chars[self.pos];
+                self.pos += 1;
+                if hi == '\\' {
+                    let Some(e) = self.peek() else {
+                        return self.err("trailing `\\`");
+                    };
+                    self.pos += 1;
+                    hi = self.literal_escape(e)?;
+                }
+                if hi < lo {
+                    return self.err("reversed class range");
+                }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        Ok(Node::Class { ranges, negated })
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => match self.braces() {
+                Some(bounds) => bounds,
+                // Not a valid `{n,m}`: the `{` is a literal.
+                None => return Ok(atom),
+            },
+            _ => return Ok(atom),
+        };
+        // Step past `*`, `+`, `?` or the closing `}`.
+        self.pos += 1;
+        if matches!(atom, Node::Start | Node::End | Node::WordBoundary) {
+            return self.err("quantified anchor");
+        }
+        let greedy = !self.eat('?');
+        if matches!(self.peek(), Some('*' | '+' | '?')) {
+            return self.err("repeated quantifier");
+        }
+        Ok(Node::Repeat {
+            node: Box::new(atom),
+            min,
+            max,
+            greedy,
+        })
+    }
+
+    /// Parse `{n}`, `{n,}` or `{n,m}` at the cursor, leaving it on the `}`.
+    fn braces(&mut self) -> Option<(u32, Option<u32>)> {
+        let rest: String = self.chars[self.pos + 1..].iter().collect();
+        let close = rest.find('}')?;
+        let body = &rest[..close];
+        let number = |s: &str| -> Option<u32> {
+            (!s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()))
+                .then(|| s.parse().ok())
+                .flatten()
+        };
+        let bounds = match body.split_once(',') {
+            None => {
+                let n = number(body)?;
+                (n, Some(n))
+            }
+            Some((lo, "")) => (number(lo)?, None),
+            Some((lo, hi)) => (number(lo)?, Some(number(hi)?)),
+        };
+        if bounds.1.is_some_and(|hi| hi < bounds.0) {
+            return None;
+        }
+        self.pos += 1 + body.chars().count();
+        Some(bounds)
+    }
+}
+
+/// One program instruction. Counted repeats are expanded when compiling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Inst {
+    /// Consume one char matching a `Char`, `Any` or `Class` node.
+    One(Node),
+    /// A zero-width `Start`, `End` or `WordBoundary`.
+    Assert(Node),
+    /// Go on at both, preferring the first.
+    Split(usize, usize),
+    Jmp(usize),
+    Match,
+}
+
+/// Most instructions a pattern may compile to: `(?:x{1000}){1000}` would
+/// otherwise take a million.
+const MAX_PROGRAM: usize = 10_000;
+
+struct Compiler<'a> {
+    prog: Vec<Inst>,
+    source: &'a str,
+}
+
+impl Compiler<'_> {
+    fn emit(&mut self, inst: Inst) -> Result<usize> {
+        if self.prog.len() >= MAX_PROGRAM {
+            bail!(
+                "regex `{}`: over {MAX_PROGRAM} steps once its repeats are expanded",
+                self.source
+            );
+        }
+        self.prog.push(inst);
+        Ok(self.prog.len() - 1)
+    }
+
+    /// The `Split` at `at` for one repeat choice: another iteration at
+    /// `more`, or done at the current end.
+    fn patch_split(&mut self, at: usize, more: usize, greedy: bool) {
+        let done = self.prog.len();
+        self.prog[at] = if greedy {
+            Inst::Split(more, done)
+        } else {
+            Inst::Split(done, more)
+        };
+    }
+
+    fn node(&mut self, node: &Node) -> Result<()> {
+        match node {
+            Node::Char(_) | Node::Any | Node::Class { .. } => {
+                self.emit(Inst::One(node.clone()))?;
+            }
+            Node::Start | Node::End | Node::WordBoundary => {
+                self.emit(Inst::Assert(node.clone()))?;
+            }
+            Node::Concat(nodes) => {
+                for n in nodes {
+                    self.node(n)?;
+                }
+            }
+            Node::Alt(alts) => {
+                let (last, rest) = alts.split_last().expect("an Alt has two or more arms");
+                let mut jumps = Vec::new();
+                for alt in rest {
+                    let split = self.emit(Inst::Split(0, 0))?;
+                    self.node(alt)?;
+                    jumps.push(self.emit(Inst::Jmp(0))?);
+                    self.prog[split] = Inst::Split(split + 1, self.prog.len());
+                }
+                self.node(last)?;
+                let end = self.prog.len();
+                for j in jumps {
+                    self.prog[j] = Inst::Jmp(end);
+                }
+            }
+            Node::Repeat {
+                node,
+                min,
+                max,
+                greedy,
+            } => {
+                for _ in 0..*min {
+                    self.node(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.emit(Inst::Split(0, 0))?;
+                        self.node(node)?;
+                        self.emit(Inst::Jmp(split))?;
+                        self.patch_split(split, split + 1, *greedy);
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.emit(Inst::Split(0, 0))?);
+                            self.node(node)?;
+                        }
+                        for split in splits {
+                            self.patch_split(split, split + 1, *greedy);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A Pike VM run of a program over (the folded chars of) one line.
+struct Vm<'a> {
+    prog: &'a [Inst],
+    s: &'a [char],
+    ignore_case: bool,
+}
+
+/// Buffers [`Vm::add`] reuses: `seen[pc]` is the offset `pc` was last
+/// added at, so each instruction holds at most one thread per offset.
+struct Scratch {
+    seen: Vec<usize>,
+    stack: Vec<usize>,
+}
+
+impl Vm<'_> {
+    /// Char offsets of the leftmost match starting at or after `from`,
+    /// preferring what a backtracker would (earlier alternatives, greedy or
+    /// lazy repeats); with `any`, the first match seen, which is enough to
+    /// tell there is one.
+    fn find(&self, from: usize, any: bool) -> Option<(usize, usize)> {
+        let mut scratch = Scratch {
+            seen: vec![usize::MAX; self.prog.len()],
+            stack: Vec::new(),
+        };
+        let mut now = Vec::new();
+        let mut next = Vec::new();
+        let mut found = None;
+        for i in from..=self.s.len() {
+            if found.is_none() {
+                // A match starting here ranks below every earlier start.
+                self.add(&mut now, &mut scratch, 0, i, i);
+            } else if now.is_empty() {
+                break;
+            }
+            for &(pc, start) in &now {
+                match &self.prog[pc] {
+                    Inst::Match => {
+                        found = Some((start, i));
+                        if any {
+                            return found;
+                        }
+                        // Threads after this one rank below it.
+                        break;
+                    }
+                    Inst::One(node) => {
+                        if self.one(node, i) {
+                            self.add(&mut next, &mut scratch, pc + 1, start, i + 1);
+                        }
+                    }
+                    _ => unreachable!("add() queues only One and Match"),
+                }
+            }
+            std::mem::swap(&mut now, &mut next);
+            next.clear();
+        }
+        found
+    }
+
+    /// Queue on `list` the thread (started at `start`) at `pc`, following
+    /// jumps, splits in order of preference and assertions that hold `at`.
+    fn add(
+        &self,
+        list: &mut Vec<(usize, usize)>,
+        scratch: &mut Scratch,
+        pc: usize,
+        start: usize,
+        at: usize,
+    ) {
+        scratch.stack.push(pc);
+        while let Some(pc) = scratch.stack.pop() {
+            if scratch.seen[pc] == at {
+                continue;
+            }
+            scratch.seen[pc] = at;
+            match &self.prog[pc] {
+                Inst::Jmp(to) => scratch.stack.push(*to),
+                Inst::Split(first, second) => scratch.stack.extend([*second, *first]),
+                Inst::Assert(node) => {
+                    if self.holds(node, at) {
+                        scratch.stack.push(pc + 1);
+                    }
+                }
+                Inst::One(_) | Inst::Match => list.push((pc, start)),
+            }
+        }
+    }
+
+    /// Whether the zero-width `node` holds at offset `i`.
+    fn holds(&self, node: &Node, i: usize) -> bool {
+        let s = self.s;
+        match node {
+            Node::Start => i == 0,
+            Node::End => i == s.len(),
+            Node::WordBoundary => {
+                let word = |c: char| c.is_alphanumeric() || c == '_';
+                let before = i > 0 && word(s[i - 1]);
+                let after = i < s.len() && word(s[i]);
+                before != after
+            }
+            _ => unreachable!("holds() on a node that consumes"),
+        }
+    }
+
+    /// Whether the one-char `node` matches `s[i]`.
+    fn one(&self, node: &Node, i: usize) -> bool {
+        let Some(&c) = self.s.get(i) else {
+            return false;
+        };
+        match node {
+            Node::Char(pat) => self.char_eq(*pat, c),
+            Node::Any => true,
+            Node::Class { ranges, negated } => self.in_class(ranges, c) != *negated,
+            _ => unreachable!("one() on a zero-width node"),
+        }
+    }
+
+    fn char_eq(&self, pat: char, c: char) -> bool {
+        if self.ignore_case {
+            fold(pat) == c
+        } else {
+            pat == c
+        }
+    }
+
+    fn in_class(&self, ranges: &[(char, char)], c: char) -> bool {
+        let hit = |c: char| ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c));
+        // `c` is already lowercased under (?i); also try the upper case so
+        // `[A-Z]` still matches.
+        hit(c) || (self.ignore_case && c.to_uppercase().any(hit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn m(re: &str, text: &str) -> bool {
+        Regex::new(re).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn matches_literals_classes_and_anchors() {
+        assert!(m(r"\[BOOT\] OK", "serial: [BOOT] OK"));
+        assert!(!m(r"^\[BOOT\] OK", "serial: [BOOT] OK"));
+        assert!(m(r"^\[TEST\] \w+: PASS$", "[TEST] pmm_alloc: PASS"));
+        assert!(!m(r"^\[TEST\] \w+: PASS$", "[TEST] pmm alloc: PASS"));
+        assert!(m(r"RIP=0x[0-9a-f]{16}", "RIP=0xffffffff80001234"));
+        assert!(!m(r"RIP=0x[0-9a-f]{16}\b", "RIP=0xffffffff8000123"));
+        assert!(m(r"[^ ]+ fault", "page fault"));
+        assert!(m(r"\bGPF\b", "CPU: GPF at"));
+        assert!(!m(r"\bGPF\b", "NOGPFS"));
+        assert!(m(r"a{2,}b", "xaaab"));
+        assert!(m(r"x{1,2", "x{1,2"));
+    }
+
+    #[test]
+    fn alternation_groups_and_quantifiers() {
+        assert!(m(r"(?:page|double) fault", "double fault"));
+        assert!(m(r"^(ab)+$", "ababab"));
+        assert!(!m(r"^(ab)+$", "ababa"));
+        assert!(m(r"^(a*)*b$", "aaab"));
+        assert!(m(r"^a.*?z$", "abcz"));
+        assert!(m(r"colou?r", "color"));
+        assert!(m(r"(?i)kernel panic", "KERNEL PANIC: oops"));
+        assert!(m(r"(?i)[A-Z]+ ok", "boot ok"));
+    }
+
+    #[test]
+    fn replaces_every_match() {
+        let re = Regex::new(r"0x[0-9a-f]{8,}").unwrap();
+        assert_eq!(
+            re.replace_all("RIP=0xffffffff80001234 CR2=0x10 RSP=0xffff8000", "<addr>"),
+            "RIP=<addr> CR2=0x10 RSP=<addr>"
+        );
+        let re = Regex::new(r"(?i)tick \d+").unwrap();
+        assert_eq!(
+            re.replace_all("TICK 12, tick 3", "tick N"),
+            "tick N, tick N"
+        );
+        assert_eq!(Regex::new("x*").unwrap().replace_all("ab", "-"), "-a-b-");
+    }
+
+    #[test]
+    fn finds_the_leftmost_match() {
+        let re = Regex::new(r"(?i)free: \d+").unwrap();
+        let text = "é FREE: 120 free: 7";
+        let (start, end) = re.find(text).unwrap();
+        assert_eq!(&text[start..end], "FREE: 120");
+        assert_eq!(Regex::new("x").unwrap().find("abc"), None);
+        assert_eq!(Regex::new("$").unwrap().find("ab"), Some((2, 2)));
+    }
+
+    #[test]
+    fn long_lines_neither_overflow_nor_crawl() {
+        let line = "a".repeat(100_000);
+        assert!(!m(r"\w+: PASS", &line));
+        assert!(!m(r"a*b", &line));
+        assert!(m(r"^a+$", &line));
+        assert!(m(r"a{3}$", &line));
+        let re = Regex::new(r"\w+: PASS").unwrap();
+        assert_eq!(re.find(&format!("{line}: PASS")), Some((0, line.len() + 6)));
+        assert_eq!(
+            Regex::new(r"a+b")
+                .unwrap()
+                .replace_all(&format!("{line}b{line}"), "x"),
+            format!("x{line}")
+        );
+        assert_eq!(Regex::new(r"a+").unwrap().replace_all("aab", "x"), "xb");
+        assert!(m(r"^(?:a)*?b", "aab"));
+    }
+
+    #[test]
+    fn repeated_groups_stay_linear() {
+        let digits = "12 ".repeat(3_400);
+        assert!(!m(r"(?:\d+ )+ERROR", &digits));
+        assert!(m(r"(?:\d+ )+ERROR", &format!("{digits}ERROR")));
+        let pairs = "ab".repeat(50_000);
+        assert!(!m(r"(ab)*c", &pairs));
+        assert!(m(r"^(ab)*c$", &format!("{pairs}c")));
+        assert!(!m(r"(a|aa)*b", &"a".repeat(40)));
+        assert!(!m(r"(x+x+)+y", &"x".repeat(100_000)));
+        assert!(!m(r"(\w+\s?)+$", &format!("{}!", "ab ".repeat(33_000))));
+        assert!(!m(r".*a.*b", &"a".repeat(100_000)));
+        let re = Regex::new(r"(?:\d+ )+").unwrap();
+        assert_eq!(re.find(&format!("x {digits}")), Some((2, digits.len() + 2)));
+        // Lazy repeats still stop as early as they can.
+        let re = Regex::new(r"<.+?>").unwrap();
+        assert_eq!(re.replace_all("<a> <b>", "T"), "T T");
+    }
+
+    #[test]
+    fn case_folding_is_the_same_everywhere() {
+        // `İ` lowercases to two chars; each function folds it to one.
+        let text = "İx";
+        let re = Regex::new(r"(?i)^.x$").unwrap();
+        assert!(re.is_match(text));
+        assert_eq!(re.find(text), Some((0, text.len())));
+        assert_eq!(re.replace_all(text, "-"), "-");
+    }
+
+    #[test]
+    fn rejects_malformed_patterns() {
+        for bad in ["(abc", "abc)", "[abc", "*a", r"\q", "a**", "[z-a]", "(?=x)"] {
+            assert!(Regex::new(bad).is_err(), "{bad}");
+        }
+        let err = Regex::new(r"(?:x{200}){100}").unwrap_err();
+        assert!(err.to_string().contains("steps"), "{err}");
+    }
+}
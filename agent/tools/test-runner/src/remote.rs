@@ -0,0 +1,381 @@
+//! Running QEMU on another host over SSH (`--remote user@host`).
+//!
+//! [`Remote::launch`] makes a scratch directory on the host (`mktemp -d`),
+//! copies every local file QEMU's arguments name (the kernel, an ISO,
+//! firmware) into it with `scp`, and rewrites the arguments to match; the
+//! files QEMU writes (the `-D` log, the QMP socket, a screenshot or core
+//! dump) get paths there too. The run then launches `ssh` instead of QEMU:
+//! serial output streams back over its stdout as it arrives, console input
+//! goes down its stdin, and QMP is forwarded to the local socket, so the
+//! launch loop in [`crate::qemu`] works unchanged. Host, user, port and
+//! keys come from the SSH config; `ssh` runs in batch mode, so it never
+//! prompts.
+//!
+//! QEMU's directory on the host is cleaned up by [`Session::finish`], which
+//! first fetches what QEMU wrote back to the local paths. Remote processes
+//! are reaped however the run ends: `finish` kills QEMU by its pid file, a
+//! session dropped unfinished (the run cancelled) does the same from the
+//! background, and a watchdog beside QEMU kills it as soon as the SSH
+//! connection is gone, so not even a killed test-runner leaves one behind.
+//!
+//! Disks, GDB, fuzzing input channels and snapshots use local files or
+//! sockets beyond these and are not supported remotely. With `accel =
+//! "auto"` the host's QEMU picks KVM if it can, else TCG, and timeouts are
+//! not scaled for TCG.
+
+use crate::qemu::RunConfig;
+use crate::qmp::FailureDump;
+use anyhow::{bail, Context, Result};
+use auton_core::plan::shell_quote;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+
+/// Runs QEMU and reaps it when the SSH session that started it ends.
+/// `$PPID` is the session's sshd; stdin is kept on fd 3 because a
+/// background job's would be `/dev/null`.
+const WRAPPER: &str = r#"cd "$1" || exit 125
+shift
+exec 3<&0
+"$@" <&3 3<&- &
+pid=$!
+echo $pid > qemu.pid
+(while kill -0 $PPID; do sleep 1; done; kill -TERM $pid; sleep 2; kill -KILL $pid) >/dev/null 2>&1 </dev/null &
+watchdog=$!
+wait $pid
+status=$?
+kill $watchdog 2>/dev/null
+exit $status"#;
+
+/// Kills what is left of a run and removes its directory.
+const REAP: &str = r#"cd "$1" || exit 0
+pid=$(cat qemu.pid 2>/dev/null)
+if [ -n "$pid" ] && kill -TERM "$pid" 2>/dev/null; then
+    sleep 1
+    kill -KILL "$pid" 2>/dev/null
+fi
+[ -n "$2" ] && cd / && rm -rf "$1"
+exit 0"#;
+
+const SSH_OPTIONS: [&str; 2] = ["-o", "BatchMode=yes"];
+
+/// A host QEMU runs on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Remote {
+    /// `[user@]host`, as `ssh` takes it.
+    pub host: String,
+    pub ssh: String,
+    pub scp: String,
+}
+
+impl Remote {
+    pub fn new(host: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            ssh: "ssh".to_string(),
+            scp: "scp".to_string(),
+        }
+    }
+
+    /// Prepare `cfg`'s run on the host: its directory, inputs and the `ssh`
+    /// command that stands in for QEMU.
+    pub async fn launch(&self, cfg: &RunConfig) -> Result<Session> {
+        if cfg.disk.is_some() {
+            bail!("disks are not supported with --remote");
+        }
+        if cfg.gdb.is_some() {
+            bail!("GDB is not supported with --remote");
+        }
+        if cfg.fuzz_channel.is_some() {
+            bail!("fuzzing over virtio-serial is not supported with --remote");
+        }
+        if cfg.save_snapshot.is_some() || cfg.args.iter().any(|a| a == "-loadvm") {
+            bail!("snapshots are not supported with --remote");
+        }
+        let dir = self
+            .ssh_output(&["mktemp".to_string(), "-d".to_string()])
+            .await
+            .with_context(|| format!("making a directory on {}", self.host))?;
+        let dir = dir.trim().to_string();
+        if dir.is_empty() {
+            bail!("mktemp on {} printed no directory", self.host);
+        }
+        let mut session = Session {
+            remote: self.clone(),
+            dir,
+            program: self.ssh.clone(),
+            args: Vec::new(),
+            screenshot: None,
+            core_dump: None,
+            fetch: Vec::new(),
+            finished: false,
+        };
+
+        // Files QEMU writes, by their local path.
+        let mut outputs = BTreeMap::new();
+        let written = [
+            cfg.cpu_log.as_ref(),
+            cfg.trace.as_ref(),
+            cfg.coverage.as_ref().map(|c| &c.log),
+            cfg.screenshot.as_ref(),
+            cfg.core_dump.as_ref(),
+        ];
+        for local in written.into_iter().flatten() {
+            if !outputs.contains_key(local) {
+                let remote = session.path(&format!("out{}", outputs.len()), local);
+                session.fetch.push((remote.clone(), local.clone()));
+                outputs.insert(local.clone(), remote);
+            }
+        }
+        session.screenshot = cfg.screenshot.as_ref().map(|p| PathBuf::from(&outputs[p]));
+        session.core_dump = cfg.core_dump.as_ref().map(|p| PathBuf::from(&outputs[p]));
+        let qmp = cfg.qmp_socket.as_ref().map(|local| {
+            let remote = format!("{}/qmp.sock", session.dir);
+            outputs.insert(local.clone(), remote.clone());
+            (local, remote)
+        });
+
+        // Files QEMU reads, copied up once each.
+        let mut inputs: BTreeMap<String, String> = BTreeMap::new();
+        let mut args = Vec::new();
+        let mut verbatim = false;
+        for arg in &cfg.args {
+            if std::mem::take(&mut verbatim) {
+                args.push(arg.clone());
+                continue;
+            }
+            verbatim = arg == "-append";
+            let mut uploads = Vec::new();
+            let rewritten = rewrite(arg, |path| {
+                if let Some(remote) = outputs.get(Path::new(path)) {
+                    return Some(remote.clone());
+                }
+                if !Path::new(path).is_file() {
+                    return None;
+                }
+                let n = inputs.len();
+                let remote = inputs
+                    .entry(path.to_string())
+                    .or_insert_with(|| {
+                        uploads.push(path.to_string());
+                        session.path(&format!("in{n}"), Path::new(path))
+                    })
+                    .clone();
+                Some(remote)
+            });
+            for local in uploads {
+                self.scp(&local, &format!("{}:{}", self.host, inputs[&local]))
+                    .await
+                    .with_context(|| format!("copying {local} to {}", self.host))?;
+            }
+            args.push(rewritten);
+        }
+
+        session.args.extend(SSH_OPTIONS.map(String::from));
+        if let Some((local, remote)) = qmp {
+            session.args.extend(
+                [
+                    "-o",
+                    "StreamLocalBindUnlink=yes",
+                    "-o",
+                    "ExitOnForwardFailure=yes",
+                    "-L",
+                ]
+                .map(String::from),
+            );
+            session.args.push(format!("{}:{remote}", local.display()));
+        }
+        let command: Vec<String> = ["sh", "-c", WRAPPER, "sh", &session.dir, &cfg.program]
+            .into_iter()
+            .map(String::from)
+            .chain(args)
+            .collect();
+        session.args.push(self.host.clone());
+        // exec, so the wrapper's parent is sshd, not the login shell.
+        session.args.push(format!("exec {}", quoted(&command)));
+        Ok(session)
+    }
+
+    /// Run `command` on the host; its stdout.
+    async fn ssh_output(&self, command: &[String]) -> Result<String> {
+        let output = Command::new(&self.ssh)
+            .args(SSH_OPTIONS)
+            .arg(&self.host)
+            .arg(quoted(command))
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .with_context(|| format!("failed to spawn {}", self.ssh))?;
+        if !output.status.success() {
+            bail!(
+                "{} {}: {}",
+                self.ssh,
+                self.host,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    async fn scp(&self, from: &str, to: &str) -> Result<()> {
+        let output = Command::new(&self.scp)
+            .arg("-q")
+            .args(SSH_OPTIONS)
+            .args([from, to])
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .with_context(|| format!("failed to spawn {}", self.scp))?;
+        if !output.status.success() {
+            bail!(
+                "{}: {}",
+                self.scp,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The command that reaps the run in `dir`, and with `remove` deletes it.
+fn reap(dir: &str, remove: bool) -> Vec<String> {
+    ["sh", "-c", REAP, "sh", dir, if remove { "rm" } else { "" }]
+        .map(String::from)
+        .to_vec()
+}
+
+/// `command` as one line for the remote shell.
+fn quoted(command: &[String]) -> String {
+    let words: Vec<String> = command.iter().map(|a| shell_quote(a)).collect();
+    words.join(" ")
+}
+
+/// One run on a remote host.
+#[derive(Debug)]
+pub struct Session {
+    remote: Remote,
+    /// The run's directory on the host.
+    pub dir: String,
+    /// What to launch instead of QEMU.
+    pub program: String,
+    pub args: Vec<String>,
+    /// Where QEMU writes a screenshot or core dump, on the host.
+    pub screenshot: Option<PathBuf>,
+    pub core_dump: Option<PathBuf>,
+    /// Files QEMU may have written and their local paths.
+    fetch: Vec<(String, PathBuf)>,
+    finished: bool,
+}
+
+impl Session {
+    /// `<dir>/<tag>-<file name of local>`.
+    fn path(&self, tag: &str, local: &Path) -> String {
+        let name = local.file_name().unwrap_or_default().to_string_lossy();
+        format!("{}/{tag}-{name}", self.dir)
+    }
+
+    /// Reap QEMU, copy what it wrote back to the local paths (pointing
+    /// `dump` at them) and remove the run's directory. Failures are only
+    /// logged: the run's result stands without them.
+    pub async fn finish(&mut self, dump: &mut Option<FailureDump>) {
+        self.finished = true;
+        let remote = &self.remote;
+        if let Err(e) = remote.ssh_output(&reap(&self.dir, false)).await {
+            tracing::warn!("reaping QEMU on {}: {e:#}", remote.host);
+        }
+        let mut fetched = Vec::new();
+        for (from, to) in &self.fetch {
+            match remote
+                .scp(&format!("{}:{from}", remote.host), &arg(to))
+                .await
+            {
+                Ok(()) => fetched.push((PathBuf::from(from), to.clone())),
+                Err(e) => tracing::debug!("not fetching {from}: {e:#}"),
+            }
+        }
+        if let Some(dump) = dump.as_mut() {
+            for path in [&mut dump.screenshot, &mut dump.core] {
+                if let Some(p) = path.as_mut() {
+                    match fetched.iter().find(|(from, _)| from == p) {
+                        Some((_, to)) => *p = to.clone(),
+                        None => *path = None,
+                    }
+                }
+            }
+        }
+        if let Err(e) = remote.ssh_output(&reap(&self.dir, true)).await {
+            tracing::warn!("removing {} on {}: {e:#}", self.dir, remote.host);
+        }
+    }
+}
+
+impl Drop for Session {
+    /// Cancelled: reap and clean up in the background, without waiting.
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let spawned = std::process::Command::new(&self.remote.ssh)
+            .args(SSH_OPTIONS)
+            .arg(&self.remote.host)
+            .arg(quoted(&reap(&self.dir, true)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        if let Err(e) = spawned {
+            tracing::warn!("reaping QEMU on {}: {e}", self.remote.host);
+        }
+    }
+}
+
+fn arg(path: &Path) -> String {
+    path.display().to_string()
+}
+
+/// `arg` with every path in it mapped: the whole argument, or a `,`-separated
+/// part of it, bare, after `key=` or after `unix:`.
+fn rewrite(arg: &str, mut map: impl FnMut(&str) -> Option<String>) -> String {
+    if let Some(new) = map(arg) {
+        return new;
+    }
+    let parts: Vec<String> = arg
+        .split(',')
+        .map(|part| {
+            let (prefix, path) = match part.split_once('=') {
+                Some((key, value)) => (&part[..=key.len()], value),
+                None => match part.strip_prefix("unix:") {
+                    Some(path) => ("unix:", path),
+                    None => ("", part),
+                },
+            };
+            match map(path).filter(|_| !path.is_empty()) {
+                Some(new) => format!("{prefix}{new}"),
+                None => part.to_string(),
+            }
+        })
+        .collect();
+    parts.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_paths_anywhere_in_an_argument() {
+        let map = |p: &str| (p.starts_with("/local/")).then(|| p.replacen("/local", "/r", 1));
+        assert_eq!(rewrite("/local/k.elf", map), "/r/k.elf");
+        assert_eq!(
+            rewrite("if=pflash,readonly=on,file=/local/OVMF.fd", map),
+            "if=pflash,readonly=on,file=/r/OVMF.fd"
+        );
+        assert_eq!(
+            rewrite("unix:/local/q.sock,server=on,wait=off", map),
+            "unix:/r/q.sock,server=on,wait=off"
+        );
+        assert_eq!(rewrite("stdio", map), "stdio");
+        assert_eq!(rewrite("a,,b", map), "a,,b");
+    }
+}
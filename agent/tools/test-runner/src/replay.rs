@@ -0,0 +1,225 @@
+//! Record and replay (`--record`, `test-runner replay`).
+//!
+//! A recorded run is a TCG run under QEMU's record/replay
+//! (`-icount ...,rr=record`): every nondeterministic input the guest sees
+//! (interrupt timing, clock reads, serial input) is logged, so replaying
+//! the log runs the same instructions again, however the host is loaded.
+//! Recording costs little more than `-icount` itself, so `--record` records
+//! every run; a run that passes deletes its log, and a failing one keeps it
+//! (in its results directory, if kept) with `replay.json`, which holds the
+//! command line to replay it with.
+//!
+//! `rrsnapshot` saves the guest at power-on into a scratch qcow2 image
+//! beside the log, which lets a debugger attached to the replay
+//! (`test-runner replay --gdb`) step and continue backwards
+//! (`reverse-stepi`, `reverse-continue`). Devices whose inputs QEMU only
+//! replays through extra drivers (`disk`, `net`, the virtio fuzz channel)
+//! cannot be recorded yet, and neither can remote, snapshot or GDB runs.
+
+use crate::devices::qemu_img;
+use crate::exitdev::ExitDevice;
+use crate::gdb::{self, GdbConfig};
+use crate::qemu::RunConfig;
+use crate::qmp;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The log's name in a results directory.
+pub const LOG_NAME: &str = "replay.bin";
+
+/// Seconds without serial output after which a replay is taken to have
+/// reached the end of its log (QEMU stops there, it does not exit).
+pub const REPLAY_IDLE: Duration = Duration::from_secs(10);
+
+/// Drive holding the power-on snapshot.
+const SNAPSHOT_DRIVE: &str = "rr-snapshots";
+
+/// A fresh scratch path for a run's log.
+pub fn scratch_log() -> PathBuf {
+    crate::scratch_path(LOG_NAME)
+}
+
+/// The snapshot image beside `log`.
+pub fn snapshots(log: &Path) -> PathBuf {
+    log.with_extension("qcow2")
+}
+
+/// The `replay.json` beside `log`.
+pub fn info_path(log: &Path) -> PathBuf {
+    log.with_extension("json")
+}
+
+/// Add the flags recording to `log`, into a deterministic run's `-icount`
+/// if there is one.
+pub fn add_args(args: &mut Vec<String>, log: &Path) {
+    let rr = format!("rr=record,rrfile={},rrsnapshot=init", log.display());
+    match args.iter().position(|a| a == "-icount") {
+        Some(i) if i + 1 < args.len() => args[i + 1] = format!("{},{rr}", args[i + 1]),
+        _ => args.extend(["-icount".to_string(), format!("shift=auto,{rr}")]),
+    }
+    args.extend([
+        "-drive".to_string(),
+        format!(
+            "if=none,id={SNAPSHOT_DRIVE},format=qcow2,file={}",
+            snapshots(log).display()
+        ),
+    ]);
+}
+
+/// Create the empty image the power-on snapshot goes into.
+pub async fn create_snapshots(log: &Path) -> Result<()> {
+    let image = snapshots(log);
+    qemu_img(&[
+        "create".as_ref(),
+        "-q".as_ref(),
+        "-f".as_ref(),
+        "qcow2".as_ref(),
+        image.as_os_str(),
+        "1M".as_ref(),
+    ])
+    .await
+    .context("creating the record/replay snapshot image")
+}
+
+/// Keep a failing run's recording, with its `replay.json`, and delete a
+/// passing one's. The kept `replay.json` is returned.
+pub fn finish(cfg: &RunConfig, log: &Path, failing: bool) -> Result<Option<PathBuf>> {
+    if !failing {
+        let _ = std::fs::remove_file(log);
+        let _ = std::fs::remove_file(snapshots(log));
+        return Ok(None);
+    }
+    let info = Info {
+        program: cfg.program.clone(),
+        args: cfg.args.clone(),
+        timeout_secs: cfg.timeout.as_secs(),
+        exit_device: cfg.exit_device,
+        exit_success: cfg.exit_success,
+    };
+    let path = info_path(log);
+    std::fs::write(&path, serde_json::to_string_pretty(&info)? + "\n")
+        .with_context(|| format!("writing {}", path.display()))?;
+    Ok(Some(path))
+}
+
+/// `replay.json`: how a recorded run was launched.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Info {
+    pub program: String,
+    /// The command line it recorded with.
+    pub args: Vec<String>,
+    pub timeout_secs: u64,
+    pub exit_device: ExitDevice,
+    pub exit_success: u32,
+}
+
+impl Info {
+    /// The `replay.json` at `path`, or in the directory `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let path = if path.is_dir() {
+            info_path(&path.join(LOG_NAME))
+        } else {
+            path.to_path_buf()
+        };
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// The recorded command line, replaying, with QMP on `qmp_socket`.
+    pub fn replay_args(&self, qmp_socket: &Path) -> Vec<String> {
+        let mut args: Vec<String> = self
+            .args
+            .iter()
+            .map(|a| a.replace("rr=record,", "rr=replay,"))
+            .collect();
+        if let Some(i) = args.iter().position(|a| a == "-qmp") {
+            args.splice(i..(i + 2).min(args.len()), qmp::qemu_args(qmp_socket));
+        }
+        args
+    }
+
+    /// A run replaying the recording, watched until the guest exits or the
+    /// log ends, or until QEMU exits under `gdb`.
+    pub fn run_config(&self, gdb: Option<GdbConfig>, transcript_limit: usize) -> RunConfig {
+        let qmp_socket = qmp::socket_path();
+        let mut args = self.replay_args(&qmp_socket);
+        if let Some(g) = &gdb {
+            args.extend(g.qemu_args());
+        }
+        let interactive = gdb.as_ref().is_some_and(|g| g.script.is_none());
+        RunConfig {
+            program: self.program.clone(),
+            args,
+            accel: crate::accel::Accel::Tcg,
+            timeout: if interactive {
+                gdb::INTERACTIVE_TIMEOUT
+            } else {
+                Duration::from_secs(self.timeout_secs) + REPLAY_IDLE
+            },
+            transcript_limit,
+            exit_device: self.exit_device,
+            exit_success: self.exit_success,
+            wait_for_exit: true,
+            idle_timeout: gdb.is_none().then_some(REPLAY_IDLE),
+            qmp_socket: Some(qmp_socket),
+            gdb,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_into_the_icount_flags() {
+        let log = Path::new("/r/replay.bin");
+        let mut args = vec!["-m".to_string(), "128M".to_string()];
+        add_args(&mut args, log);
+        assert_eq!(
+            args[2..],
+            [
+                "-icount",
+                "shift=auto,rr=record,rrfile=/r/replay.bin,rrsnapshot=init",
+                "-drive",
+                "if=none,id=rr-snapshots,format=qcow2,file=/r/replay.qcow2"
+            ]
+        );
+        let mut args = vec!["-icount".to_string(), "shift=3,sleep=off".to_string()];
+        add_args(&mut args, log);
+        assert_eq!(
+            args[1],
+            "shift=3,sleep=off,rr=record,rrfile=/r/replay.bin,rrsnapshot=init"
+        );
+        assert_eq!(info_path(log), Path::new("/r/replay.json"));
+    }
+
+    #[test]
+    fn replays_with_the_recorded_command_line() {
+        let mut args = vec![
+            "-qmp".to_string(),
+            "unix:/old,server=on,wait=off".to_string(),
+        ];
+        add_args(&mut args, Path::new("/r/replay.bin"));
+        let info = Info {
+            program: "qemu-system-x86_64".into(),
+            args,
+            timeout_secs: 30,
+            exit_device: ExitDevice::None,
+            exit_success: 0,
+        };
+        let replay = info.replay_args(Path::new("/new"));
+        assert_eq!(replay[1], "unix:/new,server=on,wait=off");
+        assert_eq!(
+            replay[3],
+            "shift=auto,rr=replay,rrfile=/r/replay.bin,rrsnapshot=init"
+        );
+        let cfg = info.run_config(None, 1024);
+        assert_eq!(cfg.timeout, Duration::from_secs(40));
+        assert!(cfg.wait_for_exit && cfg.idle_timeout == Some(REPLAY_IDLE));
+    }
+}
@@ -0,0 +1,445 @@
+//! Report files for CI and the orchestrator (`--report junit:<path>`,
+//! `--report json:<path>`, and a browsable `--report html:<dir>` from
+//! [`crate::html`]).
+//!
+//! Both files carry, per test, the duration, exit reason, matched and
+//! unmatched patterns, and the tail of the serial transcript (at most
+//! `--report-transcript` bytes; the full transcript stays on stderr and in
+//! `--json`). A `--gdb-script` session's output goes with it (JUnit:
+//! `<system-err>`), as does the failure dump (CPU state, memory, screenshot
+//! and core dump paths; JUnit: the `<failure>` text, and the core as a
+//! property), the `--trace` summary (JUnit: the log path as a property)
+//! and the `--golden` outcome (a mismatch's diff is in the `<failure>`
+//! text). A single run is reported as a one-test
+//! suite.
+
+use crate::flaky::History;
+use crate::suite::TestOutcome;
+use crate::TestCase;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Transcript bytes kept per test in a report.
+pub const DEFAULT_TRANSCRIPT_LIMIT: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Junit,
+    Json,
+    /// A directory of pages.
+    Html,
+}
+
+/// `<format>:<path>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportTarget {
+    pub format: ReportFormat,
+    pub path: PathBuf,
+}
+
+impl FromStr for ReportTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let Some((format, path)) = s.split_once(':') else {
+            return Err(format!(
+                "`{s}`: expected junit:<path>, json:<path> or html:<dir>"
+            ));
+        };
+        let format = match format {
+            "junit" => ReportFormat::Junit,
+            "json" => ReportFormat::Json,
+            "html" => ReportFormat::Html,
+            other => {
+                return Err(format!(
+                    "unknown report format `{other}` (junit, json, html)"
+                ))
+            }
+        };
+        if path.is_empty() {
+            return Err(format!("`{s}`: missing report path"));
+        }
+        Ok(Self {
+            format,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+/// Write every requested report, creating parent directories. HTML
+/// reports draw their trends from the pass/fail history at `history`, if
+/// there is one.
+pub fn write_all(
+    targets: &[ReportTarget],
+    outcomes: &[TestOutcome],
+    limit: usize,
+    history: &Path,
+) -> Result<()> {
+    for target in targets {
+        match target.format {
+            ReportFormat::Junit => write(&target.path, &junit(outcomes, limit))?,
+            ReportFormat::Json => write(&target.path, &json(outcomes, limit)?)?,
+            ReportFormat::Html => {
+                crate::html::write(&target.path, outcomes, limit, &History::load(history)?)?
+            }
+        }
+        tracing::info!(path = %target.path.display(), "wrote report");
+    }
+    Ok(())
+}
+
+fn write(path: &Path, text: &str) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    }
+    std::fs::write(path, text).with_context(|| format!("writing {}", path.display()))
+}
+
+/// The last `limit` bytes of `transcript` (on a char boundary) and how many
+/// bytes were cut, counting those the capture ring already dropped.
+pub fn truncate(transcript: &str, already_dropped: u64, limit: usize) -> (&str, u64) {
+    if transcript.len() <= limit {
+        return (transcript, already_dropped);
+    }
+    let mut start = transcript.len() - limit;
+    while !transcript.is_char_boundary(start) {
+        start += 1;
+    }
+    (&transcript[start..], already_dropped + start as u64)
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    total: usize,
+    passed: usize,
+    failed: usize,
+    duration_ms: u64,
+    tests: Vec<JsonTest<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonTest<'a> {
+    name: &'a str,
+    spec: &'a Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kernel: Option<&'a Path>,
+    passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    duration_ms: u64,
+    attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flaky: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artifacts: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_reason: Option<&'a crate::qemu::ExitReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shutdown: Option<crate::qemu::Shutdown>,
+    matched: &'a [crate::expect::Matched],
+    unmatched: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failure: Option<&'a crate::classify::Failure>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gdb_transcript: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dump: Option<&'a crate::qmp::FailureDump>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace: Option<&'a crate::trace::TraceSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    golden: Option<&'a crate::golden::GoldenResult>,
+    backtrace: &'a [crate::symbolize::Frame],
+    /// In-kernel `[TEST]` results.
+    kernel_tests: &'a [TestCase],
+    transcript: &'a str,
+    transcript_truncated: u64,
+}
+
+pub fn json(outcomes: &[TestOutcome], limit: usize) -> Result<String> {
+    let tests: Vec<JsonTest> = outcomes
+        .iter()
+        .map(|o| {
+            let r = o.result.as_ref();
+            let (transcript, transcript_truncated) = r.map_or(("", 0), |r| {
+                truncate(&r.transcript, r.transcript_dropped, limit)
+            });
+            JsonTest {
+                name: &o.name,
+                spec: &o.spec,
+                kernel: o.kernel.as_deref(),
+                passed: o.passed,
+                error: o.error.as_deref(),
+                duration_ms: r.map_or(0, |r| r.duration_ms),
+                attempts: o.attempts,
+                flaky: o.flaky.as_deref(),
+                artifacts: o.artifacts.as_deref(),
+                exit_reason: r.map(|r| &r.reason),
+                shutdown: r.map(|r| r.shutdown),
+                matched: r.map_or(&[], |r| &r.matched),
+                unmatched: r.map_or(&[], |r| &r.unmatched),
+                failure: r.and_then(|r| r.failure.as_ref()),
+                gdb_transcript: r.and_then(|r| r.gdb_transcript.as_deref()),
+                dump: r.and_then(|r| r.dump.as_ref()),
+                trace: r.and_then(|r| r.trace.as_ref()),
+                golden: r.and_then(|r| r.golden.as_ref()),
+                backtrace: r.map_or(&[], |r| &r.backtrace),
+                kernel_tests: o.summary.as_ref().map_or(&[], |s| &s.tests),
+                transcript,
+                transcript_truncated,
+            }
+        })
+        .collect();
+    let passed = outcomes.iter().filter(|o| o.passed).count();
+    let report = JsonReport {
+        total: outcomes.len(),
+        passed,
+        failed: outcomes.len() - passed,
+        duration_ms: tests.iter().map(|t| t.duration_ms).sum(),
+        tests,
+    };
+    Ok(serde_json::to_string_pretty(&report)? + "\n")
+}
+
+/// JUnit XML: one `<testcase>` per test. A test that could not run is an
+/// `<error>`, one that ran and failed a `<failure>`; the exit reason and
+/// patterns are `<properties>` and the transcript is `<system-out>`.
+pub fn junit(outcomes: &[TestOutcome], limit: usize) -> String {
+    let errors = outcomes.iter().filter(|o| o.error.is_some()).count();
+    let failures = outcomes
+        .iter()
+        .filter(|o| !o.passed && o.error.is_none())
+        .count();
+    let total_ms: u64 = outcomes
+        .iter()
+        .filter_map(|o| o.result.as_ref())
+        .map(|r| r.duration_ms)
+        .sum();
+    let counts = format!(
+        "tests=\"{}\" failures=\"{failures}\" errors=\"{errors}\" time=\"{}\"",
+        outcomes.len(),
+        secs(total_ms)
+    );
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str(&format!("<testsuites name=\"test-runner\" {counts}>\n"));
+    out.push_str(&format!("  <testsuite name=\"test-runner\" {counts}>\n"));
+    for o in outcomes {
+        let r = o.result.as_ref();
+        out.push_str(&format!(
+            "    <testcase name=\"{}\" classname=\"test-runner\" time=\"{}\"",
+            escape(&o.name),
+            secs(r.map_or(0, |r| r.duration_ms))
+        ));
+        let Some(r) = r else {
+            let error = o.error.as_deref().unwrap_or("test did not run");
+            let message = error.lines().next().unwrap_or_default();
+            out.push_str(&format!(
+                ">\n      <error message=\"{}\" type=\"error\">{}</error>\n    </testcase>\n",
+                escape(message),
+                escape(error)
+            ));
+            continue;
+        };
+        out.push_str(">\n      <properties>\n");
+        let mut property = |name: &str, value: &str| {
+            out.push_str(&format!(
+                "        <property name=\"{name}\" value=\"{}\"/>\n",
+                escape(value)
+            ));
+        };
+        property("exit-reason", r.reason.name());
+        if o.attempts > 1 {
+            property("attempts", &o.attempts.to_string());
+        }
+        if let Some(why) = &o.flaky {
+            property("flaky", why);
+        }
+        if let Some(f) = &r.failure {
+            property("failure", &f.describe());
+        }
+        if let Some(kernel) = &o.kernel {
+            property("kernel", &kernel.display().to_string());
+        }
+        if let Some(dir) = &o.artifacts {
+            property("artifacts", &dir.display().to_string());
+        }
+        if let Some(t) = &r.trace {
+            property("trace", &t.log.display().to_string());
+        }
+        if let Some(core) = r.dump.as_ref().and_then(|d| d.core.as_ref()) {
+            property("core-dump", &core.display().to_string());
+        }
+        for m in &r.matched {
+            property("matched", &format!("{} (line {})", m.pattern, m.line_no));
+        }
+        for p in &r.unmatched {
+            property("unmatched", p);
+        }
+        out.push_str("      </properties>\n");
+        if !o.passed {
+            let mut details = r.explain();
+            if let Some(summary) = &o.summary {
+                details.extend(
+                    summary
+                        .tests
+                        .iter()
+                        .filter(|t| !t.passed)
+                        .map(|t| format!("FAIL {} {}", t.name, t.message)),
+                );
+            }
+            let message = details
+                .first()
+                .cloned()
+                .unwrap_or_else(|| format!("run ended with {}", r.reason.name()));
+            out.push_str(&format!(
+                "      <failure message=\"{}\" type=\"{}\">{}</failure>\n",
+                escape(&message),
+                r.reason.name(),
+                escape(&details.join("\n"))
+            ));
+        }
+        let (transcript, truncated) = truncate(&r.transcript, r.transcript_dropped, limit);
+        out.push_str("      <system-out>");
+        if truncated > 0 {
+            out.push_str(&format!("({truncated} earlier bytes truncated)\n"));
+        }
+        out.push_str(&escape(transcript));
+        out.push_str("</system-out>\n");
+        if let Some(gdb) = &r.gdb_transcript {
+            out.push_str(&format!("      <system-err>{}</system-err>\n", escape(gdb)));
+        }
+        out.push_str("    </testcase>\n");
+    }
+    out.push_str("  </testsuite>\n</testsuites>\n");
+    out
+}
+
+fn secs(ms: u64) -> String {
+    format!("{:.3}", ms as f64 / 1000.0)
+}
+
+/// Escape for XML text and attributes, dropping characters XML 1.0 cannot
+/// carry at all (serial output is full of stray control bytes).
+pub(crate) fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if c < ' ' || c == '\u{fffe}' || c == '\u{ffff}' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qemu::{ExitReason, RunResult};
+
+    fn outcome(name: &str, reason: ExitReason, transcript: &str) -> TestOutcome {
+        let result = RunResult {
+            reason,
+            duration_ms: 1500,
+            accel: crate::accel::Accel::Kvm,
+            matched: Vec::new(),
+            unmatched: vec![r"\[BOOT\] OK".into()],
+            transcript: transcript.into(),
+            transcript_dropped: 0,
+            failure: None,
+            backtrace: Vec::new(),
+            dump: None,
+            gdb_transcript: None,
+            trace: None,
+            golden: None,
+            soak: None,
+            injected: Vec::new(),
+            deterministic: None,
+            screens: Vec::new(),
+            packets: Vec::new(),
+            control: None,
+            replay: None,
+            shutdown: crate::qemu::Shutdown::Exited,
+            coverage: None,
+        };
+        TestOutcome::from_result(
+            name.into(),
+            PathBuf::from(format!("tests/{name}.toml")),
+            PathBuf::from("build/auton.iso"),
+            result,
+        )
+    }
+
+    #[test]
+    fn parses_report_targets() {
+        let t: ReportTarget = "junit:out/results.xml".parse().unwrap();
+        assert_eq!(t.format, ReportFormat::Junit);
+        assert_eq!(t.path, Path::new("out/results.xml"));
+        let t: ReportTarget = "html:out/report".parse().unwrap();
+        assert_eq!(t.format, ReportFormat::Html);
+        assert!("xml:a".parse::<ReportTarget>().is_err());
+        assert!("json:".parse::<ReportTarget>().is_err());
+        assert!("results.json".parse::<ReportTarget>().is_err());
+    }
+
+    #[test]
+    fn truncates_transcripts_from_the_front() {
+        assert_eq!(truncate("abcdef", 0, 10), ("abcdef", 0));
+        assert_eq!(truncate("abcdef", 5, 2), ("ef", 9));
+        // Never splits a UTF-8 sequence.
+        assert_eq!(truncate("aé", 0, 1), ("", 3));
+    }
+
+    #[test]
+    fn junit_reports_failures_with_escaped_transcript() {
+        let outcomes = [
+            outcome(
+                "smoke",
+                ExitReason::Timeout { timeout_ms: 30_000 },
+                "[MM] <ok> \x1b[0m\n",
+            ),
+            TestOutcome {
+                error: Some("kernel-builder failed".into()),
+                result: None,
+                summary: None,
+                ..outcome("mm", ExitReason::PatternMatched, "")
+            },
+        ];
+        let xml = junit(&outcomes, DEFAULT_TRANSCRIPT_LIMIT);
+        assert!(xml.contains("tests=\"2\" failures=\"1\" errors=\"1\" time=\"1.500\""));
+        assert!(xml.contains(
+            "<failure message=\"QEMU timed out after 30s (possible hang)\" type=\"timeout\">"
+        ));
+        assert!(xml.contains("<property name=\"unmatched\" value=\"\\[BOOT\\] OK\"/>"));
+        assert!(xml.contains("<system-out>[MM] &lt;ok&gt; [0m\n</system-out>"));
+        assert!(xml.contains("<error message=\"kernel-builder failed\" type=\"error\">"));
+    }
+
+    #[test]
+    fn json_report_counts_and_truncates() {
+        let outcomes = [TestOutcome {
+            attempts: 2,
+            flaky: crate::flaky::retry_verdict(2, true),
+            ..outcome(
+                "smoke",
+                ExitReason::PatternMatched,
+                "[TEST] a: PASS\n[BOOT] OK\n",
+            )
+        }];
+        let v: serde_json::Value = serde_json::from_str(&json(&outcomes, 10).unwrap()).unwrap();
+        assert_eq!(v["total"], 1);
+        assert_eq!(v["tests"][0]["exit_reason"]["kind"], "pattern-matched");
+        assert_eq!(v["tests"][0]["kernel_tests"][0]["name"], "a");
+        assert_eq!(v["tests"][0]["transcript"], "[BOOT] OK\n");
+        assert_eq!(v["tests"][0]["transcript_truncated"], 15);
+        assert_eq!(v["tests"][0]["attempts"], 2);
+        assert_eq!(v["tests"][0]["flaky"], "passed on attempt 2 after failing");
+    }
+}
@@ -0,0 +1,330 @@
+//! Per-run artifact directories (`--results-dir`, `--keep-last`).
+//!
+//! Every QEMU run gets `<results-dir>/<timestamp>-<test>/` holding
+//! `serial.log` (the complete serial output, streamed as it arrives and so
+//! not subject to the transcript cap), `command.txt` (the QEMU command line,
+//! shell-quoted), `status.json` (pass/fail, exit reason, how QEMU was
+//! stopped, duration, a deterministic run's seed) and,
+//! with `--trace`, `trace.log`; with `--dump-on-failure`, a failed run
+//! adds `vmcore.elf`, with `[[screen]]`s, `screen-<name>.ppm` captures
+//! (and `.diff.ppm` images of mismatches), and with `--record`, `replay.bin`, `replay.qcow2` and
+//! `replay.json` (see [`crate::replay`]).
+//! Timestamps are UTC, `20261014T121248.632Z`, so names sort by time; only
+//! the newest `keep-last` directories per test are kept.
+//!
+//! With `--store`, a finished run's files are also added to the artifact
+//! store, and a `runs` ref named for its directory records them and the
+//! kernel by hash, so they outlive pruning until `auton gc` collects them.
+
+use crate::deterministic::Deterministic;
+use crate::qemu::{ExitReason, RunConfig, RunResult, Shutdown};
+use crate::replay;
+use anyhow::{Context, Result};
+use auton_core::manifest::hash_file;
+use auton_core::plan::{shell_quote, Plan};
+use auton_core::store::{ArtifactStore, Stored};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_DIR: &str = "test-results";
+
+/// Runs kept per test without `--keep-last`.
+pub const DEFAULT_KEEP: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct Store {
+    pub root: PathBuf,
+    /// Directories kept per test.
+    pub keep: usize,
+    /// Where finished runs are also stored (`--store`).
+    pub artifacts: Option<ArtifactStore>,
+}
+
+/// One run's directory.
+#[derive(Debug, Clone)]
+pub struct RunDir {
+    pub path: PathBuf,
+    test: String,
+}
+
+#[derive(Serialize)]
+struct Status<'a> {
+    test: &'a str,
+    kernel: &'a Path,
+    passed: bool,
+    exit_reason: &'a ExitReason,
+    /// How QEMU was stopped (see [`crate::qemu::Shutdown`]).
+    shutdown: Shutdown,
+    duration_ms: u64,
+    /// Serial bytes the in-memory transcript dropped (`serial.log` has all).
+    transcript_dropped: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    core_dump: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deterministic: Option<&'a Deterministic>,
+}
+
+/// A run as recorded in the artifact store.
+#[derive(Serialize)]
+struct StoredRun<'a> {
+    test: &'a str,
+    run: &'a str,
+    passed: bool,
+    kernel: &'a Path,
+    kernel_sha256: String,
+    files: BTreeMap<String, Stored>,
+}
+
+impl Store {
+    /// A fresh directory for a run of `test`, with `cfg`'s command line
+    /// written and its serial output (and trace log) directed into it.
+    pub fn create(&self, test: &str, cfg: &mut RunConfig) -> Result<RunDir> {
+        let test = sanitize(test);
+        let path = self.fresh(&test);
+        std::fs::create_dir_all(&path).with_context(|| format!("creating {}", path.display()))?;
+        direct(&path, cfg);
+        let command: Vec<String> = std::iter::once(&cfg.program)
+            .chain(&cfg.args)
+            .map(|a| shell_quote(a))
+            .collect();
+        std::fs::write(path.join("command.txt"), command.join(" ") + "\n")
+            .with_context(|| format!("writing {}", path.display()))?;
+        Ok(RunDir { path, test })
+    }
+
+    /// Direct `cfg` into the directory [`Store::create`] would make for
+    /// `test` now, adding its files to `plan` (`--dry-run`).
+    pub fn plan(&self, test: &str, cfg: &mut RunConfig, plan: &mut Plan) {
+        let path = self.fresh(&sanitize(test));
+        direct(&path, cfg);
+        plan.write(path.join("command.txt"));
+    }
+
+    /// A directory for a run of `test` begun now that does not exist yet.
+    fn fresh(&self, test: &str) -> PathBuf {
+        let stamp = timestamp(SystemTime::now());
+        let mut path = self.root.join(format!("{stamp}-{test}"));
+        // Retries can land in the same millisecond.
+        let mut n = 1;
+        while path.exists() {
+            n += 1;
+            path = self.root.join(format!("{stamp}-{test}.{n}"));
+        }
+        path
+    }
+
+    /// Remove all but the newest [`Store::keep`] directories for `dir`'s
+    /// test.
+    pub fn prune(&self, dir: &RunDir) -> Result<()> {
+        let mut runs: Vec<PathBuf> = std::fs::read_dir(&self.root)
+            .with_context(|| format!("reading {}", self.root.display()))?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.is_dir() && run_test_name(p) == Some(dir.test.as_str()))
+            .collect();
+        runs.sort();
+        let excess = runs.len().saturating_sub(self.keep);
+        for old in &runs[..excess] {
+            std::fs::remove_dir_all(old).with_context(|| format!("removing {}", old.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Write `status.json` for the finished run, add it to the artifact
+    /// store if there is one, then prune its test's older runs.
+    pub fn finish(&self, dir: &RunDir, kernel: &Path, result: &RunResult) -> Result<()> {
+        let status = Status {
+            test: &dir.test,
+            kernel,
+            passed: result.passed(&result.summary()),
+            exit_reason: &result.reason,
+            shutdown: result.shutdown,
+            duration_ms: result.duration_ms,
+            transcript_dropped: result.transcript_dropped,
+            core_dump: result.dump.as_ref().and_then(|d| d.core.as_deref()),
+            deterministic: result.deterministic.as_ref(),
+        };
+        let path = dir.path.join("status.json");
+        std::fs::write(&path, serde_json::to_string_pretty(&status)? + "\n")
+            .with_context(|| format!("writing {}", path.display()))?;
+        if let Some(artifacts) = &self.artifacts {
+            store_run(artifacts, dir, kernel, status.passed)?;
+        }
+        self.prune(dir)
+    }
+}
+
+fn store_run(artifacts: &ArtifactStore, dir: &RunDir, kernel: &Path, passed: bool) -> Result<()> {
+    let mut files = BTreeMap::new();
+    for entry in std::fs::read_dir(&dir.path)? {
+        let path = entry?.path();
+        if path.is_file() {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            files.insert(name.into_owned(), artifacts.put(&path)?);
+        }
+    }
+    let run = dir.path.file_name().unwrap_or_default().to_string_lossy();
+    let record = StoredRun {
+        test: &dir.test,
+        run: &run,
+        passed,
+        kernel,
+        kernel_sha256: hash_file(kernel)?.1,
+        files,
+    };
+    artifacts.add_ref("runs", &run, &record)?;
+    Ok(())
+}
+
+/// The test a run directory belongs to: its name after the timestamp,
+/// without a `.N` collision suffix.
+fn run_test_name(dir: &Path) -> Option<&str> {
+    let name = dir.file_name()?.to_str()?;
+    let (stamp, test) = name.split_once('-')?;
+    if !stamp.ends_with('Z') {
+        return None;
+    }
+    Some(match test.rsplit_once('.') {
+        Some((base, n)) if n.parse::<u32>().is_ok() => base,
+        _ => test,
+    })
+}
+
+/// Test names as path components: anything but `[A-Za-z0-9_.-]` becomes `_`.
+pub(crate) fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "_.-".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Point `cfg`'s serial log, trace, core dump, record/replay log and screen
+/// captures into the run directory `path`.
+fn direct(path: &Path, cfg: &mut RunConfig) {
+    if cfg.trace.is_some() {
+        cfg.move_trace(path.join("trace.log"));
+    }
+    if cfg.core_dump.is_some() {
+        cfg.core_dump = Some(path.join("vmcore.elf"));
+    }
+    cfg.move_record(path.join(replay::LOG_NAME));
+    for screen in &mut cfg.screens {
+        screen.capture = path.join(format!("screen-{}.ppm", screen.name));
+        screen.keep = true;
+    }
+    cfg.serial_log = Some(path.join("serial.log"));
+}
+
+/// `YYYYMMDDTHHMMSS.mmmZ` in UTC.
+fn timestamp(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (y, m, day) = civil_from_days(days as i64);
+    format!(
+        "{y:04}{m:02}{day:02}T{:02}{:02}{:02}.{:03}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        d.subsec_millis()
+    )
+}
+
+/// Gregorian (year, month, day) for days since 1970-01-01.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_utc_timestamps() {
+        let t = UNIX_EPOCH + Duration::from_millis(1_791_979_968_632);
+        assert_eq!(timestamp(t), "20261014T121248.632Z");
+        assert_eq!(timestamp(UNIX_EPOCH), "19700101T000000.000Z");
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    }
+
+    #[test]
+    fn keeps_the_newest_runs_per_test() {
+        let store = Store {
+            root: crate::scratch_path("results"),
+            keep: 2,
+            artifacts: None,
+        };
+        let mut cfg = crate::qemu::RunConfig {
+            program: "qemu-system-x86_64".into(),
+            args: vec!["-append".into(), "console=ttyS0 it's".into()],
+            ..crate::spec::TestSpec {
+                dump_on_failure: Some(true),
+                ..Default::default()
+            }
+            .run_config(Path::new("k.elf"), 16)
+            .unwrap()
+        };
+        let dirs: Vec<RunDir> = (0..3)
+            .map(|_| store.create("smoke/boot", &mut cfg).unwrap())
+            .collect();
+        let other = store.create("smoke", &mut cfg).unwrap();
+        store.prune(&dirs[2]).unwrap();
+        assert!(!dirs[0].path.exists());
+        assert!(dirs[1].path.exists() && dirs[2].path.exists() && other.path.exists());
+        assert_eq!(
+            std::fs::read_to_string(dirs[2].path.join("command.txt")).unwrap(),
+            "qemu-system-x86_64 -append 'console=ttyS0 it'\\''s'\n"
+        );
+        assert_eq!(cfg.serial_log, Some(other.path.join("serial.log")));
+        assert_eq!(cfg.core_dump, Some(other.path.join("vmcore.elf")));
+        assert_eq!(run_test_name(&dirs[1].path), Some("smoke_boot"));
+        std::fs::remove_dir_all(&store.root).unwrap();
+    }
+
+    #[test]
+    fn stored_runs_reference_their_files_and_kernel() {
+        let root = crate::scratch_path("stored-runs");
+        let store = Store {
+            root: root.join("results"),
+            keep: 1,
+            artifacts: Some(ArtifactStore::new(root.join("artifacts"))),
+        };
+        let kernel = root.join("k.elf");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(&kernel, "kernel").unwrap();
+        let mut cfg = crate::spec::TestSpec::default()
+            .run_config(&kernel, 16)
+            .unwrap();
+        let dir = store.create("smoke", &mut cfg).unwrap();
+        std::fs::write(dir.path.join("serial.log"), "ok\n").unwrap();
+        let artifacts = store.artifacts.as_ref().unwrap();
+        store_run(artifacts, &dir, &kernel, true).unwrap();
+
+        let name = dir.path.file_name().unwrap().to_str().unwrap();
+        let path = root.join(format!("artifacts/refs/runs/{name}.json"));
+        let record: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(record["test"], "smoke");
+        assert_eq!(record["kernel_sha256"], hash_file(&kernel).unwrap().1);
+        let serial = record["files"]["serial.log"]["sha256"].as_str().unwrap();
+        assert!(artifacts.contains(serial));
+        assert!(record["files"]["command.txt"].is_object());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
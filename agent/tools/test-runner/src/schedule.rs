@@ -0,0 +1,101 @@
+//! Things done at points in a run: `delay` after the first serial line
+//! matching `when`, or after QEMU starts without one. [`crate::inject`]
+//! faults and [`crate::screen`] captures are scheduled this way.
+
+use crate::regex::Regex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// When something is done.
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    pub when: Option<Regex>,
+    pub delay: Duration,
+}
+
+impl Trigger {
+    /// `when` compiled, `delay` in seconds.
+    pub fn new(when: Option<&str>, delay: Option<u64>) -> anyhow::Result<Self> {
+        Ok(Self {
+            when: when.map(Regex::new).transpose()?,
+            delay: Duration::from_secs(delay.unwrap_or(0)),
+        })
+    }
+}
+
+/// What is due when, for one run.
+#[derive(Debug)]
+pub struct Schedule<T> {
+    waiting: Vec<(Regex, Duration, T)>,
+    scheduled: Vec<(Instant, T)>,
+}
+
+impl<T> Schedule<T> {
+    /// `items` for a run started at `start`.
+    pub fn new(items: impl IntoIterator<Item = (Trigger, T)>, start: Instant) -> Self {
+        let mut schedule = Self {
+            waiting: Vec::new(),
+            scheduled: Vec::new(),
+        };
+        for (trigger, item) in items {
+            match trigger.when {
+                Some(when) => schedule.waiting.push((when, trigger.delay, item)),
+                None => schedule.scheduled.push((start + trigger.delay, item)),
+            }
+        }
+        schedule
+    }
+
+    /// Schedule the items waiting for `line`, seen at `now`.
+    pub fn line(&mut self, line: &str, now: Instant) {
+        let (matched, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.waiting)
+            .into_iter()
+            .partition(|(when, _, _)| when.is_match(line));
+        self.waiting = waiting;
+        self.scheduled.extend(
+            matched
+                .into_iter()
+                .map(|(_, delay, item)| (now + delay, item)),
+        );
+    }
+
+    /// When the next scheduled item is due.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.scheduled.iter().map(|&(at, _)| at).min()
+    }
+
+    /// The items due by `now`, in order, removed from the schedule.
+    pub fn due(&mut self, now: Instant) -> Vec<T> {
+        let (mut due, later): (Vec<_>, Vec<_>) = std::mem::take(&mut self.scheduled)
+            .into_iter()
+            .partition(|&(at, _)| at <= now);
+        self.scheduled = later;
+        due.sort_by_key(|&(at, _)| at);
+        due.into_iter().map(|(_, item)| item).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn items_are_scheduled_from_their_line() {
+        let trigger = |when, delay| Trigger::new(when, Some(delay)).unwrap();
+        let start = Instant::now();
+        let mut schedule = Schedule::new(
+            [
+                (trigger(Some("link up"), 2), "down"),
+                (trigger(None, 5), "up"),
+            ],
+            start,
+        );
+        assert_eq!(schedule.deadline(), Some(start + Duration::from_secs(5)));
+        schedule.line("booting", start);
+        schedule.line("virtio-net: link up", start + Duration::from_secs(1));
+        assert_eq!(schedule.deadline(), Some(start + Duration::from_secs(3)));
+        assert!(schedule.due(start + Duration::from_secs(2)).is_empty());
+        assert_eq!(schedule.due(start + Duration::from_secs(9)), ["down", "up"]);
+        assert_eq!(schedule.deadline(), None);
+    }
+}
@@ -0,0 +1,462 @@
+//! Screen assertions (`[[screen]]`), for milestones a kernel shows on its
+//! display (VGA text mode, a framebuffer) rather than on serial.
+//!
+//! Each `[[screen]]` is a QMP `screendump` taken `delay` seconds after the
+//! serial line matching `when` (or after QEMU starts without one), and
+//! compared with `reference`, a binary PPM (`P6`, what `screendump`
+//! writes) relative to the spec. A pixel differs when one of its channels
+//! is off by more than `tolerance`; the capture matches while at most
+//! `threshold` percent of its pixels differ (0 by default). On a mismatch
+//! the capture and a diff image, the differing pixels red over a dimmed
+//! reference, are kept beside each other (in the run's results directory,
+//! if kept). Like golden transcripts, `--bless` writes a passing run's
+//! captures over the references, which is also how they are created.
+//!
+//! ```toml
+//! [[screen]]
+//! name = "desktop"
+//! when = '\[FB\] ready'
+//! delay = 1
+//! reference = "screens/desktop.ppm"
+//! threshold = 0.5
+//! tolerance = 8
+//! ```
+
+use crate::qmp::Qmp;
+use crate::schedule::Trigger;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// `[[screen]]` in a spec.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ScreenSpec {
+    /// Names the capture's files [default: the reference's file stem].
+    pub name: Option<String>,
+    /// Serial pattern to wait for [default: none, count from QEMU's start].
+    pub when: Option<String>,
+    /// Seconds to wait after `when`.
+    pub delay: Option<u64>,
+    /// Required.
+    pub reference: PathBuf,
+    /// Percentage of pixels that may differ.
+    pub threshold: Option<Threshold>,
+    /// Per-channel difference (0-255) that still counts as the same.
+    pub tolerance: Option<u8>,
+}
+
+/// A percentage, kept in hundredths so specs stay `Eq`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "f64")]
+pub struct Threshold {
+    pub hundredths: u32,
+}
+
+impl TryFrom<f64> for Threshold {
+    type Error = String;
+
+    fn try_from(percent: f64) -> Result<Self, String> {
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(format!("threshold {percent} is not a percentage"));
+        }
+        Ok(Self {
+            hundredths: (percent * 100.0).round() as u32,
+        })
+    }
+}
+
+/// A compiled `[[screen]]`.
+#[derive(Debug, Clone)]
+pub struct Screen {
+    pub name: String,
+    pub trigger: Trigger,
+    pub reference: PathBuf,
+    pub threshold: Threshold,
+    pub tolerance: u8,
+    /// Where the capture is written.
+    pub capture: PathBuf,
+    /// Keep a matching capture (it is in a results directory).
+    pub keep: bool,
+    /// Write the capture over `reference` if the run passes.
+    pub bless: bool,
+}
+
+impl Screen {
+    pub fn new(spec: &ScreenSpec, bless: bool) -> Result<Self> {
+        if spec.reference.as_os_str().is_empty() {
+            bail!("[[screen]] needs a `reference` image");
+        }
+        let name = match &spec.name {
+            Some(name) => name.clone(),
+            None => spec
+                .reference
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+        };
+        let ok = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+        if name.is_empty() || !name.chars().all(ok) {
+            bail!("[[screen]] name `{name}`: use letters, digits, `-`, `_` and `.`");
+        }
+        Ok(Self {
+            trigger: Trigger::new(spec.when.as_deref(), spec.delay)
+                .with_context(|| format!("[[screen]] `{name}`"))?,
+            reference: spec.reference.clone(),
+            threshold: spec.threshold.unwrap_or_default(),
+            tolerance: spec.tolerance.unwrap_or(0),
+            capture: crate::scratch_path(&format!("{name}.ppm")),
+            keep: false,
+            bless,
+            name,
+        })
+    }
+
+    /// The diff image beside the capture.
+    pub fn diff_path(&self) -> PathBuf {
+        self.capture.with_extension("diff.ppm")
+    }
+
+    /// Compare the capture, if `captured`, with the reference, or bless it
+    /// when the run `passed`.
+    pub fn check(&self, captured: Result<(), String>, passed: bool) -> ScreenResult {
+        let name = self.name.clone();
+        if let Err(message) = captured {
+            return ScreenResult::Error { name, message };
+        }
+        let result = self
+            .compare(passed)
+            .unwrap_or_else(|e| ScreenResult::Error {
+                name,
+                message: format!("{e:#}"),
+            });
+        if result.ok() && !self.keep {
+            let _ = std::fs::remove_file(&self.capture);
+        }
+        result
+    }
+
+    fn compare(&self, passed: bool) -> Result<ScreenResult> {
+        let name = self.name.clone();
+        if self.bless && passed {
+            if let Some(dir) = self.reference.parent() {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("creating {}", dir.display()))?;
+            }
+            std::fs::copy(&self.capture, &self.reference)
+                .with_context(|| format!("writing {}", self.reference.display()))?;
+            return Ok(ScreenResult::Blessed {
+                name,
+                reference: self.reference.clone(),
+            });
+        }
+        let capture = Image::load(&self.capture)?;
+        let reference = match std::fs::read(&self.reference) {
+            Ok(bytes) => Image::parse(&bytes)
+                .with_context(|| format!("reading {}", self.reference.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => bail!(
+                "{} not found (run with --bless to create it)",
+                self.reference.display()
+            ),
+            Err(e) => {
+                return Err(e).with_context(|| format!("reading {}", self.reference.display()))
+            }
+        };
+        let mismatch = |message: String, diff: Option<PathBuf>| ScreenResult::Mismatch {
+            name: self.name.clone(),
+            reference: self.reference.clone(),
+            capture: self.capture.clone(),
+            diff,
+            message,
+        };
+        if (capture.width, capture.height) != (reference.width, reference.height) {
+            return Ok(mismatch(
+                format!(
+                    "{}x{} screen, the reference is {}x{}",
+                    capture.width, capture.height, reference.width, reference.height
+                ),
+                None,
+            ));
+        }
+        let (differing, diff) = reference.diff(&capture, self.tolerance);
+        let total = capture.pixels().max(1);
+        if differing * 10_000 <= u64::from(self.threshold.hundredths) * total {
+            return Ok(ScreenResult::Matched { name, differing });
+        }
+        let path = self.diff_path();
+        diff.save(&path)?;
+        let percent = differing as f64 * 100.0 / total as f64;
+        Ok(mismatch(
+            format!(
+                "{differing} of {total} pixels differ ({percent:.2}%, threshold {:.2}%)",
+                f64::from(self.threshold.hundredths) / 100.0
+            ),
+            Some(path),
+        ))
+    }
+}
+
+/// Save the display of the VM whose QMP socket is `socket` to `path`.
+pub async fn capture(socket: &Path, path: &Path) -> Result<()> {
+    Qmp::connect(socket).await?.screendump(path).await
+}
+
+/// How a `[[screen]]` compared.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum ScreenResult {
+    Matched {
+        name: String,
+        /// Pixels that differed, within the threshold.
+        differing: u64,
+    },
+    Blessed {
+        name: String,
+        reference: PathBuf,
+    },
+    Mismatch {
+        name: String,
+        reference: PathBuf,
+        capture: PathBuf,
+        /// Absent when the sizes differ.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        diff: Option<PathBuf>,
+        message: String,
+    },
+    /// Not captured, or no comparison was possible.
+    Error {
+        name: String,
+        message: String,
+    },
+}
+
+impl ScreenResult {
+    pub fn ok(&self) -> bool {
+        matches!(self, Self::Matched { .. } | Self::Blessed { .. })
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        match self {
+            Self::Matched { .. } => Vec::new(),
+            Self::Blessed { name, reference } => {
+                vec![format!("blessed screen {name}: {}", reference.display())]
+            }
+            Self::Mismatch {
+                name,
+                reference,
+                capture,
+                diff,
+                message,
+            } => {
+                let mut lines = vec![
+                    format!(
+                        "screen {name} differs from {}: {message}",
+                        reference.display()
+                    ),
+                    format!("  capture: {}", capture.display()),
+                ];
+                lines.extend(diff.iter().map(|d| format!("  diff: {}", d.display())));
+                lines
+            }
+            Self::Error { name, message } => vec![format!("screen {name}: {message}")],
+        }
+    }
+}
+
+/// An RGB image, 8 bits a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub rgb: Vec<u8>,
+}
+
+impl Image {
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::parse(&bytes).with_context(|| format!("reading {}", path.display()))
+    }
+
+    /// A binary PPM: `P6`, width, height and a maximum of 255, separated by
+    /// whitespace and `#` comments, then the pixels.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut pos = 0;
+        let mut fields = Vec::new();
+        while fields.len() < 4 {
+            match bytes.get(pos) {
+                None => bail!("not a PPM image: truncated header"),
+                Some(b'#') => {
+                    while bytes.get(pos).is_some_and(|&b| b != b'\n') {
+                        pos += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => pos += 1,
+                Some(_) => {
+                    let start = pos;
+                    while bytes.get(pos).is_some_and(|b| !b.is_ascii_whitespace()) {
+                        pos += 1;
+                    }
+                    fields.push(String::from_utf8_lossy(&bytes[start..pos]).into_owned());
+                }
+            }
+        }
+        if fields[0] != "P6" {
+            bail!("not a binary PPM image (`{}`)", fields[0]);
+        }
+        let number = |s: &str| {
+            s.parse::<u32>()
+                .with_context(|| format!("bad PPM header field `{s}`"))
+        };
+        let (width, height, max) = (
+            number(&fields[1])?,
+            number(&fields[2])?,
+            number(&fields[3])?,
+        );
+        if max != 255 {
+            bail!("unsupported PPM maximum {max} (only 255)");
+        }
+        // One whitespace byte ends the header.
+        let pixels = &bytes[(pos + 1).min(bytes.len())..];
+        let len = width as u64 * height as u64 * 3;
+        if pixels.len() as u64 != len {
+            bail!(
+                "{width}x{height} PPM image has {} bytes of pixels, not {len}",
+                pixels.len()
+            );
+        }
+        Ok(Self {
+            width,
+            height,
+            rgb: pixels.to_vec(),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut bytes = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        bytes.extend_from_slice(&self.rgb);
+        std::fs::write(path, bytes).with_context(|| format!("writing {}", path.display()))
+    }
+
+    pub fn pixels(&self) -> u64 {
+        u64::from(self.width) * u64::from(self.height)
+    }
+
+    /// How many pixels of `other` (the same size) differ by more than
+    /// `tolerance`, and an image of them: red where they differ, this
+    /// image's grey at a third of its brightness elsewhere.
+    pub fn diff(&self, other: &Self, tolerance: u8) -> (u64, Self) {
+        let mut differing = 0;
+        let mut rgb = Vec::with_capacity(self.rgb.len());
+        for (a, b) in self.rgb.chunks_exact(3).zip(other.rgb.chunks_exact(3)) {
+            if a.iter().zip(b).any(|(x, y)| x.abs_diff(*y) > tolerance) {
+                differing += 1;
+                rgb.extend([255, 0, 0]);
+            } else {
+                let grey = (a.iter().map(|&c| u32::from(c)).sum::<u32>() / 9) as u8;
+                rgb.extend([grey; 3]);
+            }
+        }
+        let diff = Self {
+            width: self.width,
+            height: self.height,
+            rgb,
+        };
+        (differing, diff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(pixels: &[[u8; 3]]) -> Image {
+        Image {
+            width: pixels.len() as u32,
+            height: 1,
+            rgb: pixels.concat(),
+        }
+    }
+
+    #[test]
+    fn parses_and_writes_ppm() {
+        let img = Image::parse(b"P6\n# QEMU\n2 1\n255\n\x01\x02\x03\x04\x05\x06").unwrap();
+        assert_eq!((img.width, img.height), (2, 1));
+        assert_eq!(img.rgb, [1, 2, 3, 4, 5, 6]);
+        assert!(Image::parse(b"P6 2 1 255\n\x01").is_err());
+        assert!(Image::parse(b"P3 1 1 255\n1 2 3").is_err());
+        assert!(Image::parse(b"P6 1 1 65535\n\x01\x02\x03").is_err());
+
+        let path = crate::scratch_path("ppm");
+        img.save(&path).unwrap();
+        assert_eq!(Image::load(&path).unwrap(), img);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn counts_pixels_beyond_the_tolerance() {
+        let reference = image(&[[90, 90, 90], [0, 0, 0], [10, 10, 10]]);
+        let capture = image(&[[90, 90, 90], [0, 0, 5], [10, 30, 10]]);
+        let (differing, diff) = reference.diff(&capture, 4);
+        assert_eq!(differing, 2);
+        assert_eq!(diff.rgb, [30, 30, 30, 255, 0, 0, 255, 0, 0]);
+        assert_eq!(reference.diff(&capture, 20).0, 0);
+    }
+
+    #[tokio::test]
+    async fn mismatches_keep_a_diff_and_passes_bless() {
+        let dir = crate::scratch_path("screen");
+        std::fs::create_dir_all(&dir).unwrap();
+        let spec = ScreenSpec {
+            when: Some("ready".into()),
+            reference: dir.join("boot.ppm"),
+            threshold: Some(Threshold::try_from(50.0).unwrap()),
+            ..Default::default()
+        };
+        let mut screen = Screen::new(&spec, false).unwrap();
+        assert_eq!(screen.name, "boot");
+        screen.capture = dir.join("screen-boot.ppm");
+        image(&[[0, 0, 0], [0, 0, 0]])
+            .save(&spec.reference)
+            .unwrap();
+
+        image(&[[255, 0, 0], [0, 0, 0]])
+            .save(&screen.capture)
+            .unwrap();
+        let result = screen.check(Ok(()), true);
+        assert_eq!(
+            result,
+            ScreenResult::Matched {
+                name: "boot".into(),
+                differing: 1
+            }
+        );
+        assert!(!screen.capture.exists());
+
+        image(&[[255, 0, 0], [0, 9, 0]])
+            .save(&screen.capture)
+            .unwrap();
+        let result = screen.check(Ok(()), true);
+        assert!(!result.ok());
+        assert!(result.lines()[0].ends_with("2 of 2 pixels differ (100.00%, threshold 50.00%)"));
+        assert!(screen.diff_path().exists() && screen.capture.exists());
+
+        screen.bless = true;
+        assert!(matches!(
+            screen.check(Ok(()), false),
+            ScreenResult::Mismatch { .. }
+        ));
+        assert!(matches!(
+            screen.check(Ok(()), true),
+            ScreenResult::Blessed { .. }
+        ));
+        assert_eq!(
+            Image::load(&spec.reference).unwrap().rgb,
+            [255, 0, 0, 0, 9, 0]
+        );
+
+        let missed = screen.check(Err("never captured".into()), true);
+        assert_eq!(missed.lines(), ["screen boot: never captured"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,277 @@
+//! Snapshot/restore for fast iteration (`snapshot-at`).
+//!
+//! Most of a late-boot test's time goes into booting. A spec with
+//! `snapshot-at = '<regex>'` instead boots once until that pattern, pauses
+//! the guest and `savevm`s it into a small qcow2 image attached as a drive
+//! without a device (`-drive if=none`), which is where QEMU keeps the VM
+//! state. Each test then starts from its own copy of the image with
+//! `-loadvm`; its patterns see only what the kernel prints after the
+//! snapshot point.
+//!
+//! Internal snapshots live inside the image and are invisible through a
+//! backing-file overlay, hence a copy per run (small: only non-zero RAM
+//! pages are stored). Images are cached in the snapshot directory under a
+//! key of everything that shapes the machine — the kernel (path, size,
+//! mtime), the resolved machine (arch, type, CPU, firmware), memory,
+//! vCPUs, accelerator, NIC, exit device, extra QEMU args and the pattern — so
+//! later invocations with an unchanged kernel skip the boot too, and QEMU
+//! never sees a `-loadvm` into a differently built machine.
+
+use crate::devices::qemu_img;
+use crate::machine::Machine;
+use crate::qemu::{self, ExitReason, RunConfig};
+use crate::spec::TestSpec;
+use anyhow::{bail, Context, Result};
+use auton_core::plan::{Plan, Planned};
+use std::ffi::OsStr;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+/// Name of the internal snapshot in each image.
+pub const SNAPSHOT_NAME: &str = "post-init";
+
+/// Virtual size of the snapshot drive; the VM state is stored beside it.
+const DISK_SIZE: &str = "16M";
+
+/// Where images are cached without `--snapshot-dir`.
+pub fn default_dir() -> PathBuf {
+    std::env::temp_dir().join("test-runner-snapshots")
+}
+
+/// Cache key for `spec` booting `kernel` (see the module docs).
+pub fn key(spec: &TestSpec, kernel: &Path) -> Result<String> {
+    if spec.disk.is_some() {
+        // The disk's overlay is per run, so a snapshot could not restore
+        // what the guest had written to it.
+        bail!("`snapshot-at` cannot be combined with a `disk`");
+    }
+    let meta =
+        std::fs::metadata(kernel).with_context(|| format!("reading {}", kernel.display()))?;
+    let mut h = DefaultHasher::new();
+    kernel
+        .canonicalize()
+        .unwrap_or_else(|_| kernel.to_path_buf())
+        .hash(&mut h);
+    (meta.len(), meta.modified().ok()).hash(&mut h);
+    let machine = Machine::resolve(spec, kernel)?;
+    (
+        spec.memory,
+        spec.smp,
+        spec.resolve_accel(&machine.arch)?,
+        &machine,
+        &spec.net,
+        spec.fuzz.as_ref().map(|f| f.channel),
+        &spec.qemu_args,
+    )
+        .hash(&mut h);
+    format!("{:?}", spec.exit_device).hash(&mut h);
+    spec.snapshot_at.hash(&mut h);
+    Ok(format!("{:016x}", h.finish()))
+}
+
+/// The cached image for `spec` and `kernel` in `dir`, booting to the
+/// snapshot point to create it if there is none.
+pub async fn ensure(
+    spec: &TestSpec,
+    kernel: &Path,
+    dir: &Path,
+    transcript_limit: usize,
+) -> Result<PathBuf> {
+    let image = dir.join(format!("{}.qcow2", key(spec, kernel)?));
+    if image.is_file() {
+        tracing::debug!(image = %image.display(), "reusing snapshot");
+        return Ok(image);
+    }
+    std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    // Renamed into place only once saved, so a failed boot leaves no image.
+    let partial = image.with_extension("partial.qcow2");
+    let prepared = prepare(spec, kernel, &partial, transcript_limit).await;
+    if let Err(e) = prepared {
+        let _ = std::fs::remove_file(&partial);
+        return Err(e);
+    }
+    std::fs::rename(&partial, &image)
+        .with_context(|| format!("moving snapshot to {}", image.display()))?;
+    Ok(image)
+}
+
+/// What [`ensure`] would run and write (`--dry-run`), and the image.
+pub fn plan(
+    spec: &TestSpec,
+    kernel: &Path,
+    dir: &Path,
+    transcript_limit: usize,
+    plan: &mut Plan,
+) -> Result<PathBuf> {
+    let image = dir.join(format!("{}.qcow2", key(spec, kernel)?));
+    if !image.is_file() {
+        let args: Vec<String> = disk_args(&image)
+            .iter()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+        plan.run(Planned::new(
+            "creating the snapshot disk",
+            "qemu-img",
+            &args,
+        ));
+        let cfg = boot_config(spec, kernel, &image, transcript_limit)?;
+        cfg.plan(
+            format!("booting {} to the snapshot point", kernel.display()),
+            plan,
+        );
+        plan.write(&image);
+    }
+    Ok(image)
+}
+
+/// The run booting `kernel` until `snapshot-at` and saving it into `image`.
+fn boot_config(
+    spec: &TestSpec,
+    kernel: &Path,
+    image: &Path,
+    transcript_limit: usize,
+) -> Result<RunConfig> {
+    let at = spec
+        .snapshot_at
+        .clone()
+        .context("spec has no `snapshot-at`")?;
+    let boot = TestSpec {
+        expect: vec![at],
+        expect_any: Vec::new(),
+        step: Vec::new(),
+        wait_exit: None,
+        gdb: None,
+        gdb_script: None,
+        ..spec.clone()
+    };
+    let mut cfg = boot.run_config(kernel, transcript_limit)?;
+    cfg.args.extend(drive_args(image));
+    cfg.save_snapshot = Some(SNAPSHOT_NAME.to_string());
+    Ok(cfg)
+}
+
+/// Boot `kernel` until `snapshot-at` and save it into a new `image`.
+async fn prepare(
+    spec: &TestSpec,
+    kernel: &Path,
+    image: &Path,
+    transcript_limit: usize,
+) -> Result<()> {
+    let cfg = boot_config(spec, kernel, image, transcript_limit)?;
+    create_disk(image).await?;
+    tracing::info!(kernel = %kernel.display(), "booting to the snapshot point");
+    let result = qemu::run(&cfg).await?;
+    if result.reason != ExitReason::PatternMatched {
+        bail!(
+            "snapshot boot never reached `{}`:\n{}",
+            spec.snapshot_at.as_deref().unwrap_or_default(),
+            result.explain().join("\n")
+        );
+    }
+    Ok(())
+}
+
+async fn create_disk(image: &Path) -> Result<()> {
+    qemu_img(&disk_args(image)).await
+}
+
+fn disk_args(image: &Path) -> Vec<&OsStr> {
+    let args = ["create", "-q", "-f", "qcow2"].map(OsStr::new);
+    [&args[..], &[image.as_os_str(), OsStr::new(DISK_SIZE)]].concat()
+}
+
+/// QEMU flags attaching `image` as a device-less drive for VM state.
+pub fn drive_args(image: &Path) -> Vec<String> {
+    vec![
+        "-drive".to_string(),
+        format!("if=none,id=snapshot,format=qcow2,file={}", image.display()),
+    ]
+}
+
+/// A per-run copy of a snapshot image, removed on drop.
+pub struct Fork(PathBuf);
+
+impl Fork {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Fork {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Make `cfg` start from `image`'s snapshot instead of booting.
+pub fn fork(cfg: &mut RunConfig, image: &Path) -> Result<Fork> {
+    let copy = Fork(crate::scratch_path("qcow2"));
+    std::fs::copy(image, copy.path())
+        .with_context(|| format!("copying snapshot {}", image.display()))?;
+    cfg.args.extend(restore_args(copy.path()));
+    Ok(copy)
+}
+
+/// QEMU flags starting from the snapshot in `image`.
+pub fn restore_args(image: &Path) -> Vec<String> {
+    let mut args = drive_args(image);
+    args.extend(["-loadvm".to_string(), SNAPSHOT_NAME.to_string()]);
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_follows_the_machine_not_the_patterns() {
+        let kernel = crate::scratch_path("elf");
+        std::fs::write(&kernel, b"\x7fELF").unwrap();
+        let spec = TestSpec {
+            snapshot_at: Some(r"\[INIT\] done".into()),
+            ..Default::default()
+        };
+        let base = key(&spec, &kernel).unwrap();
+        let checks = TestSpec {
+            expect: vec!["vmm: PASS".into()],
+            timeout: Some(5),
+            ..spec.clone()
+        };
+        assert_eq!(key(&checks, &kernel).unwrap(), base);
+        let bigger = TestSpec {
+            memory: Some(512),
+            ..spec.clone()
+        };
+        assert_ne!(key(&bigger, &kernel).unwrap(), base);
+        let disk = TestSpec {
+            disk: Some(kernel.clone()),
+            ..spec.clone()
+        };
+        assert!(key(&disk, &kernel).is_err());
+        std::fs::write(&kernel, b"\x7fELF rebuilt").unwrap();
+        assert_ne!(key(&spec, &kernel).unwrap(), base);
+        std::fs::remove_file(&kernel).unwrap();
+    }
+
+    #[test]
+    fn fork_loads_a_private_copy() {
+        let image = crate::scratch_path("qcow2");
+        std::fs::write(&image, b"QFI\xfb").unwrap();
+        let mut cfg = TestSpec::default()
+            .run_config(Path::new("b/kernel.elf"), 1024)
+            .unwrap();
+        let copy = fork(&mut cfg, &image).unwrap();
+        let path = copy.path().to_path_buf();
+        assert_eq!(std::fs::read(&path).unwrap(), b"QFI\xfb");
+        let drive = format!("if=none,id=snapshot,format=qcow2,file={}", path.display());
+        assert!(cfg.args.ends_with(&[
+            "-drive".to_string(),
+            drive,
+            "-loadvm".to_string(),
+            SNAPSHOT_NAME.to_string()
+        ]));
+        drop(copy);
+        assert!(!path.exists());
+        std::fs::remove_file(&image).unwrap();
+    }
+}
@@ -0,0 +1,550 @@
+//! Long-duration soak runs (`test-runner soak`).
+//!
+//! A kernel can pass a one-minute test and still leak or fault its way to
+//! a crash ten minutes later. A soak run boots it once and keeps it
+//! running for the duration, the spec's patterns having to match along the
+//! way, while a [`Monitor`] checks every serial line against the health
+//! conditions in the spec's `[soak]` table:
+//!
+//! - gauges: numbers the kernel prints periodically, read as the first
+//!   decimal or `0x` number at or after the gauge's pattern. A gauge fails
+//!   outside its `min`/`max`, or once it has grown `max-growth` percent
+//!   above (or dropped `max-drop` percent below) its first reading, which
+//!   is how a leak shows;
+//! - faults: lines matching a `faults` pattern are recoverable faults,
+//!   counted, and more than `max-faults` of them fail the run;
+//! - heartbeat: with `heartbeat-timeout`, a `heartbeat` line has to arrive
+//!   at least that often, which catches a kernel that is still printing
+//!   but no longer scheduling.
+//!
+//! The first condition that fails ends the run as `unhealthy`. A run that
+//! reaches the duration with all of them holding ends as `survived`, and
+//! passes. Panics, forbidden lines and hangs end it as they would any
+//! other run. Either way the result carries a [`SoakReport`] with each
+//! gauge's readings and trend.
+//!
+//! ```toml
+//! [soak]
+//! duration = 7200
+//! heartbeat = '\[TICK\]'
+//! heartbeat-timeout = 30
+//! faults = ['\[FAULT\] recovered']
+//! max-faults = 10
+//!
+//! [[soak.gauge]]
+//! name = "heap"
+//! pattern = 'heap used: '
+//! max-growth = 50
+//!
+//! [[soak.gauge]]
+//! name = "free-pages"
+//! pattern = 'free pages: '
+//! min = 1024
+//! ```
+
+use crate::qemu::RunConfig;
+use crate::regex::Regex;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How long a soak runs without `--duration` or `duration` in the spec.
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(3600);
+
+/// How often progress is logged while a soak runs.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// `[soak]` in a spec.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SoakSpec {
+    /// Seconds to run.
+    pub duration: Option<u64>,
+    /// Serial pattern the kernel prints periodically while it is healthy.
+    pub heartbeat: Option<String>,
+    /// Seconds allowed between heartbeats (and before the first one).
+    pub heartbeat_timeout: Option<u64>,
+    /// Patterns for recoverable faults.
+    pub faults: Vec<String>,
+    /// Faults tolerated before the run fails [default: any number].
+    pub max_faults: Option<u64>,
+    pub gauge: Vec<GaugeSpec>,
+}
+
+/// `[[soak.gauge]]`: a number the kernel reports, and its limits.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GaugeSpec {
+    pub name: String,
+    /// The value is the first number at or after the match.
+    pub pattern: String,
+    pub max: Option<u64>,
+    pub min: Option<u64>,
+    /// Percent over the first reading that fails the run.
+    pub max_growth: Option<u32>,
+    /// Percent under the first reading that fails the run.
+    pub max_drop: Option<u32>,
+}
+
+/// The health condition that ended a soak run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Unhealthy {
+    /// `gauge <name>`, `faults` or `heartbeat`.
+    pub check: String,
+    pub detail: String,
+    /// Seconds into the run.
+    pub at_secs: u64,
+    /// The serial line that broke the condition, if one did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_no: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SoakReport {
+    pub duration_secs: u64,
+    pub elapsed_secs: u64,
+    pub faults: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_fault: Option<String>,
+    pub heartbeats: u64,
+    /// Longest wait for a heartbeat, the wait for the first included.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longest_heartbeat_gap_secs: Option<u64>,
+    pub gauges: Vec<GaugeReport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GaugeReport {
+    pub name: String,
+    pub samples: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<u64>,
+    /// Least-squares slope of the readings, per hour of the run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trend_per_hour: Option<f64>,
+}
+
+impl SoakReport {
+    /// One finding per line, for [`crate::qemu::RunResult::explain`].
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "soak: {}s of {}s, {} faults, {} heartbeats",
+            self.elapsed_secs, self.duration_secs, self.faults, self.heartbeats
+        )];
+        if let Some(gap) = self.longest_heartbeat_gap_secs {
+            lines.push(format!("soak: longest heartbeat gap {gap}s"));
+        }
+        if let Some(fault) = &self.last_fault {
+            lines.push(format!("soak: last fault: {fault}"));
+        }
+        for g in &self.gauges {
+            lines.push(match (g.first, g.last, g.min, g.max) {
+                (Some(first), Some(last), Some(min), Some(max)) => {
+                    let trend = g
+                        .trend_per_hour
+                        .map_or(String::new(), |t| format!(", trend {t:+.1}/h"));
+                    format!(
+                        "soak: {}: {first} -> {last} (min {min}, max {max}) over {} samples{trend}",
+                        g.name, g.samples
+                    )
+                }
+                _ => format!("soak: {}: never reported", g.name),
+            });
+        }
+        lines
+    }
+}
+
+/// A gauge's limits and readings so far; the readings are kept as sums
+/// for the trend, so a long run does not hold every sample.
+#[derive(Debug, Clone)]
+struct Gauge {
+    spec: GaugeSpec,
+    pattern: Regex,
+    samples: u64,
+    first: u64,
+    last: u64,
+    min: u64,
+    max: u64,
+    sum_t: f64,
+    sum_v: f64,
+    sum_tt: f64,
+    sum_tv: f64,
+}
+
+impl Gauge {
+    fn new(spec: &GaugeSpec) -> Result<Self> {
+        if spec.name.is_empty() {
+            bail!("[[soak.gauge]] needs a `name`");
+        }
+        let pattern =
+            Regex::new(&spec.pattern).with_context(|| format!("soak gauge `{}`", spec.name))?;
+        Ok(Self {
+            spec: spec.clone(),
+            pattern,
+            samples: 0,
+            first: 0,
+            last: 0,
+            min: u64::MAX,
+            max: 0,
+            sum_t: 0.0,
+            sum_v: 0.0,
+            sum_tt: 0.0,
+            sum_tv: 0.0,
+        })
+    }
+
+    /// Record the reading in `line`, if it has one, and say what limit it
+    /// broke.
+    fn read(&mut self, line: &str, at: Duration) -> Option<String> {
+        let (start, _) = self.pattern.find(line)?;
+        let value = number(&line[start..])?;
+        if self.samples == 0 {
+            self.first = value;
+        }
+        self.samples += 1;
+        self.last = value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        let (t, v) = (at.as_secs_f64() / 3600.0, value as f64);
+        self.sum_t += t;
+        self.sum_v += v;
+        self.sum_tt += t * t;
+        self.sum_tv += t * v;
+
+        let percent = |pct: u32| self.first as f64 * pct as f64 / 100.0;
+        if let Some(max) = self.spec.max.filter(|&max| value > max) {
+            return Some(format!("{value} is over the maximum of {max}"));
+        }
+        if let Some(min) = self.spec.min.filter(|&min| value < min) {
+            return Some(format!("{value} is under the minimum of {min}"));
+        }
+        if let Some(pct) = self.spec.max_growth {
+            if value as f64 > self.first as f64 + percent(pct) {
+                return Some(format!(
+                    "{value} has grown more than {pct}% from {}",
+                    self.first
+                ));
+            }
+        }
+        if let Some(pct) = self.spec.max_drop {
+            if (value as f64) < self.first as f64 - percent(pct) {
+                return Some(format!(
+                    "{value} has dropped more than {pct}% from {}",
+                    self.first
+                ));
+            }
+        }
+        None
+    }
+
+    fn report(&self) -> GaugeReport {
+        let n = self.samples as f64;
+        let spread = n * self.sum_tt - self.sum_t * self.sum_t;
+        let seen = (self.samples > 0).then_some(());
+        GaugeReport {
+            name: self.spec.name.clone(),
+            samples: self.samples,
+            first: seen.map(|_| self.first),
+            last: seen.map(|_| self.last),
+            min: seen.map(|_| self.min),
+            max: seen.map(|_| self.max),
+            trend_per_hour: (self.samples > 1 && spread > 0.0)
+                .then(|| (n * self.sum_tv - self.sum_t * self.sum_v) / spread),
+        }
+    }
+}
+
+/// The first number in `text`: decimal, or hex after `0x`.
+fn number(text: &str) -> Option<u64> {
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let rest = &text[start..];
+    if let Some(hex) = rest.strip_prefix("0x").or_else(|| rest.strip_prefix("0X")) {
+        let end = hex
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .unwrap_or(hex.len());
+        if end > 0 {
+            return u64::from_str_radix(&hex[..end], 16).ok();
+        }
+    }
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+/// Make `cfg` a soak run watched by `monitor`: it lasts the monitor's
+/// duration, whatever its patterns do.
+pub fn configure(cfg: &mut RunConfig, monitor: Monitor) {
+    cfg.timeout = monitor.duration;
+    cfg.wait_for_exit = true;
+    cfg.soak = Some(monitor);
+}
+
+/// Watches a soak run's serial lines for the `[soak]` conditions.
+#[derive(Debug, Clone)]
+pub struct Monitor {
+    duration: Duration,
+    heartbeat: Option<(Regex, Duration)>,
+    faults: Vec<Regex>,
+    max_faults: Option<u64>,
+    gauges: Vec<Gauge>,
+    lines: usize,
+    fault_count: u64,
+    last_fault: Option<String>,
+    beats: u64,
+    last_beat: Duration,
+    longest_gap: Duration,
+    elapsed: Duration,
+    logged: Duration,
+}
+
+impl Monitor {
+    /// A monitor for a run of `duration` under `spec`.
+    pub fn new(spec: &SoakSpec, duration: Duration) -> Result<Self> {
+        let heartbeat = match (&spec.heartbeat, spec.heartbeat_timeout) {
+            (Some(pattern), Some(secs)) => Some((
+                Regex::new(pattern).context("soak heartbeat")?,
+                Duration::from_secs(secs),
+            )),
+            (Some(_), None) => bail!("[soak] `heartbeat` needs a `heartbeat-timeout`"),
+            (None, Some(_)) => bail!("[soak] `heartbeat-timeout` needs a `heartbeat` pattern"),
+            (None, None) => None,
+        };
+        let faults = spec
+            .faults
+            .iter()
+            .map(|p| Regex::new(p).context("soak faults"))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            duration,
+            heartbeat,
+            faults,
+            max_faults: spec.max_faults,
+            gauges: spec.gauge.iter().map(Gauge::new).collect::<Result<_>>()?,
+            lines: 0,
+            fault_count: 0,
+            last_fault: None,
+            beats: 0,
+            last_beat: Duration::ZERO,
+            longest_gap: Duration::ZERO,
+            elapsed: Duration::ZERO,
+            logged: Duration::ZERO,
+        })
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Take in the serial line seen `at` into the run; the condition it
+    /// broke, if any.
+    pub fn line(&mut self, line: &str, at: Duration) -> Option<Unhealthy> {
+        self.lines += 1;
+        self.elapsed = at;
+        if let Some(overdue) = self.overdue(at) {
+            return Some(overdue);
+        }
+        let failed = |check: String, detail: String| Unhealthy {
+            check,
+            detail,
+            at_secs: at.as_secs(),
+            line_no: Some(self.lines),
+            line: Some(line.to_string()),
+        };
+        if let Some((pattern, _)) = &self.heartbeat {
+            if pattern.is_match(line) {
+                self.beats += 1;
+                self.longest_gap = self.longest_gap.max(at.saturating_sub(self.last_beat));
+                self.last_beat = at;
+            }
+        }
+        if self.faults.iter().any(|f| f.is_match(line)) {
+            self.fault_count += 1;
+            self.last_fault = Some(line.to_string());
+            if let Some(max) = self.max_faults.filter(|&max| self.fault_count > max) {
+                let detail = format!("{} faults, more than the {max} allowed", self.fault_count);
+                return Some(failed("faults".to_string(), detail));
+            }
+        }
+        for gauge in &mut self.gauges {
+            if let Some(detail) = gauge.read(line, at) {
+                let check = format!("gauge {}", gauge.spec.name);
+                return Some(failed(check, detail));
+            }
+        }
+        if at >= self.logged + PROGRESS_INTERVAL {
+            self.logged = at;
+            tracing::info!(target: "soak", "{}", self.progress());
+        }
+        None
+    }
+
+    /// When the next heartbeat is due, as time into the run.
+    pub fn deadline(&self) -> Option<Duration> {
+        self.heartbeat
+            .as_ref()
+            .map(|(_, timeout)| self.last_beat + *timeout)
+    }
+
+    /// The heartbeat condition, if it is broken `at` into the run.
+    pub fn overdue(&mut self, at: Duration) -> Option<Unhealthy> {
+        self.elapsed = self.elapsed.max(at);
+        let (_, timeout) = self.heartbeat.as_ref()?;
+        let gap = at.saturating_sub(self.last_beat);
+        if gap < *timeout {
+            return None;
+        }
+        self.longest_gap = self.longest_gap.max(gap);
+        let since = match self.beats {
+            0 => "since boot".to_string(),
+            _ => format!("after {} heartbeats", self.beats),
+        };
+        Some(Unhealthy {
+            check: "heartbeat".to_string(),
+            detail: format!("no heartbeat for {}s {since}", timeout.as_secs()),
+            at_secs: at.as_secs(),
+            line_no: None,
+            line: None,
+        })
+    }
+
+    /// `12m of 2h: 3 faults, heap 4096`, as logged every
+    /// [`PROGRESS_INTERVAL`].
+    fn progress(&self) -> String {
+        let mut text = format!(
+            "soak {}m of {}m: {} faults",
+            self.elapsed.as_secs() / 60,
+            self.duration.as_secs() / 60,
+            self.fault_count
+        );
+        for g in self.gauges.iter().filter(|g| g.samples > 0) {
+            text.push_str(&format!(", {} {}", g.spec.name, g.last));
+        }
+        text
+    }
+
+    pub fn report(&self) -> SoakReport {
+        SoakReport {
+            duration_secs: self.duration.as_secs(),
+            elapsed_secs: self.elapsed.as_secs(),
+            faults: self.fault_count,
+            last_fault: self.last_fault.clone(),
+            heartbeats: self.beats,
+            longest_heartbeat_gap_secs: self.heartbeat.as_ref().map(|_| self.longest_gap.as_secs()),
+            gauges: self.gauges.iter().map(Gauge::report).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    fn gauge(name: &str, pattern: &str) -> GaugeSpec {
+        GaugeSpec {
+            name: name.into(),
+            pattern: pattern.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reads_the_number_after_the_pattern() {
+        assert_eq!(number(": 4096 pages"), Some(4096));
+        assert_eq!(number("=0x1f00, next"), Some(0x1f00));
+        assert_eq!(number(" 0 used"), Some(0));
+        assert_eq!(number("none"), None);
+    }
+
+    #[test]
+    fn a_growing_gauge_fails_and_reports_its_trend() {
+        let spec = SoakSpec {
+            gauge: vec![GaugeSpec {
+                max_growth: Some(50),
+                ..gauge("heap", r"heap used: ")
+            }],
+            ..Default::default()
+        };
+        let mut monitor = Monitor::new(&spec, secs(7200)).unwrap();
+        for (i, used) in [1000, 1100, 1200, 1300, 1400, 1500].into_iter().enumerate() {
+            let line = format!("[MM] heap used: {used} bytes");
+            assert_eq!(monitor.line(&line, secs(i as u64 * 600)), None);
+        }
+        let broke = monitor.line("[MM] heap used: 1600 bytes", secs(3600));
+        let broke = broke.unwrap();
+        assert_eq!(broke.check, "gauge heap");
+        assert_eq!(broke.detail, "1600 has grown more than 50% from 1000");
+        assert_eq!(broke.line_no, Some(7));
+
+        let report = monitor.report();
+        let heap = &report.gauges[0];
+        assert_eq!(
+            (heap.first, heap.last, heap.min, heap.max, heap.samples),
+            (Some(1000), Some(1600), Some(1000), Some(1600), 7)
+        );
+        // 100 bytes every ten minutes.
+        let trend = heap.trend_per_hour.unwrap();
+        assert!((trend - 600.0).abs() < 1.0, "{trend}");
+    }
+
+    #[test]
+    fn faults_are_counted_up_to_the_limit() {
+        let spec = SoakSpec {
+            faults: vec![r"\[FAULT\] recovered".into()],
+            max_faults: Some(2),
+            gauge: vec![GaugeSpec {
+                min: Some(100),
+                ..gauge("free", "free pages")
+            }],
+            ..Default::default()
+        };
+        let mut monitor = Monitor::new(&spec, secs(60)).unwrap();
+        assert_eq!(monitor.line("[FAULT] recovered #PF", secs(1)), None);
+        assert_eq!(monitor.line("[FAULT] recovered #GP", secs(2)), None);
+        assert_eq!(monitor.line("free pages 120", secs(3)), None);
+        let broke = monitor.line("[FAULT] recovered #PF", secs(4)).unwrap();
+        assert_eq!(broke.detail, "3 faults, more than the 2 allowed");
+        assert_eq!(monitor.report().faults, 3);
+
+        let mut monitor = Monitor::new(&spec, secs(60)).unwrap();
+        let broke = monitor.line("free pages 99", secs(5)).unwrap();
+        assert_eq!(broke.detail, "99 is under the minimum of 100");
+    }
+
+    #[test]
+    fn a_missed_heartbeat_is_unhealthy() {
+        let spec = SoakSpec {
+            heartbeat: Some(r"\[TICK\]".into()),
+            heartbeat_timeout: Some(10),
+            ..Default::default()
+        };
+        let mut monitor = Monitor::new(&spec, secs(60)).unwrap();
+        assert_eq!(monitor.deadline(), Some(secs(10)));
+        assert_eq!(monitor.line("[TICK] 1", secs(4)), None);
+        assert_eq!(monitor.line("[TICK] 2", secs(12)), None);
+        assert_eq!(monitor.deadline(), Some(secs(22)));
+        assert_eq!(monitor.overdue(secs(21)), None);
+        let broke = monitor.line("still printing", secs(23)).unwrap();
+        assert_eq!(broke.detail, "no heartbeat for 10s after 2 heartbeats");
+        assert_eq!(monitor.report().longest_heartbeat_gap_secs, Some(11));
+
+        let without_timeout = SoakSpec {
+            heartbeat_timeout: None,
+            ..spec
+        };
+        assert!(Monitor::new(&without_timeout, secs(60)).is_err());
+    }
+}
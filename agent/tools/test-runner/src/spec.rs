@@ -0,0 +1,843 @@
+//! Test spec files (`--spec <file.toml>`, or every `*.toml` for `suite`).
+//!
+//! Keys are the long flag names and mean the same thing; patterns given on
+//! the command line are added to the file's. `kernel`, `symbols`,
+//! `gdb-script`, `screenshot-dir`, `firmware`, `disk`, `golden`, screen
+//! references and `build.workspace` are relative to the spec file; with
+//! `remote`, QEMU runs on that host over SSH (see [`crate::remote`]).
+//! `arch`, `machine` and `boot` default to the build's, via `manifest.json`
+//! beside the image (see [`crate::machine`]).
+//! Instead of `smp` and `memory`, `cpus` and `mem` give a matrix: `suite`
+//! runs the test once per vCPU count and memory size (see
+//! [`crate::suite`]).
+//! Instead of naming an image, a spec can ask for a `[build]`: `test-runner
+//! suite` runs kernel-builder once per distinct build and boots the
+//! resulting image.
+//!
+//! ```toml
+//! kernel = "../build/auton.iso"
+//! timeout = 30
+//! memory = 256
+//! smp = 2
+//! disk = "images/fat32.img"
+//! net = "user,hostfwd=tcp::5555-:22"
+//! boot = "uefi"
+//! accel = "auto"
+//! deterministic = true
+//! record = true
+//! idle-timeout = 10
+//! expect = ['\[MM\] pmm ready', '\[BOOT\] OK']
+//! expect-any = ['\[TEST\] vmm_map: PASS']
+//! forbid = ['GPF', 'double fault']
+//! golden = "boot.golden"
+//! golden-start = '\[INIT\] start'
+//! normalize = ['tick \d+=tick N']
+//! exit-device = "isa-debug-exit"
+//! wait-exit = true
+//! qemu-args = ["-smp", "2"]
+//! dump-memory = ["0xb8000:4000"]
+//! dump-on-failure = true
+//!
+//! [[disk-error]]
+//! event = "read_aio"
+//!
+//! [[inject]]
+//! when = 'virtio-net: link up'
+//! action = "link-down"
+//!
+//! [[screen]]
+//! when = '\[FB\] ready'
+//! reference = "screens/boot.ppm"
+//!
+//! [[packet]]
+//! when = '\[NET\] up'
+//! send = "ping"
+//!
+//! [[step]]
+//! expect = 'auton> $'
+//! send = "meminfo\n"
+//!
+//! [control]
+//! tests = ["pmm_alloc", "vmm_map"]
+//!
+//! # or, instead of `kernel`:
+//! [build]
+//! workspace = ".."
+//! image-format = "iso"
+//! args = ["--driver", "native"]
+//! ```
+
+use crate::accel::{Accel, DEFAULT_TCG_TIMEOUT_FACTOR};
+use crate::control::{Control, ControlSpec};
+use crate::coverage::{self, CoverageConfig};
+use crate::deterministic::Deterministic;
+use crate::devices::{self, Disk};
+use crate::exitdev::{ExitDevice, ISA_DEBUG_EXIT_SUCCESS};
+use crate::expect::{self, Expectations};
+use crate::fuzz::{self, Channel, FuzzSpec};
+use crate::gdb::{self, GdbConfig};
+use crate::golden::{Golden, Normalize};
+use crate::inject::{DiskError, InjectSpec, Injection};
+use crate::machine::{Boot, Machine};
+use crate::packets::{Harness, PacketSpec};
+use crate::qemu::{RunConfig, CPU_LOG_ITEMS, DEFAULT_EXPECT, DEFAULT_PANIC_PATTERNS};
+use crate::qemu_args;
+use crate::qmp::{self, MemoryRange};
+use crate::remote::Remote;
+use crate::replay;
+use crate::screen::{Screen, ScreenSpec};
+use crate::soak::SoakSpec;
+use crate::steps::{Step, Steps};
+use crate::trace::{self, TraceEvent};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const DEFAULT_ARCH: &str = "x86_64";
+pub const DEFAULT_MEMORY_MB: u32 = 128;
+pub const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TestSpec {
+    /// Test name in suite reports; defaults to the file stem.
+    pub name: Option<String>,
+    /// Kernel image (.iso boots via -cdrom, else -kernel).
+    pub kernel: Option<PathBuf>,
+    /// Build the kernel instead of naming an image.
+    pub build: Option<BuildTarget>,
+    /// How `test-runner fuzz` feeds this test inputs (see [`crate::fuzz`]).
+    pub fuzz: Option<FuzzSpec>,
+    /// In-kernel tests run over a virtio-serial channel (see
+    /// [`crate::control`]).
+    pub control: Option<ControlSpec>,
+    /// What `test-runner soak` checks (see [`crate::soak`]).
+    pub soak: Option<SoakSpec>,
+    /// Defaults to the build manifest's, else x86_64.
+    pub arch: Option<String>,
+    pub machine: Option<String>,
+    /// BIOS or UEFI firmware; defaults to the build manifest's.
+    pub boot: Option<Boot>,
+    /// OVMF image for UEFI boots [default: searched for].
+    pub firmware: Option<PathBuf>,
+    /// Memory in MiB.
+    pub memory: Option<u32>,
+    /// Memory sizes (`64M`, `4G`) `suite` runs the test with, one run each.
+    pub mem: Vec<MemorySize>,
+    /// vCPUs (`-smp`).
+    pub smp: Option<u32>,
+    /// vCPU counts `suite` runs the test with, one run each.
+    pub cpus: Vec<u32>,
+    /// Image attached through a per-run overlay (see [`crate::devices`]).
+    pub disk: Option<PathBuf>,
+    /// `-netdev` backend for a virtio-net NIC.
+    pub net: Option<String>,
+    /// I/O errors the disk returns (see [`crate::inject`]).
+    pub disk_error: Vec<DiskError>,
+    /// Actions on the running VM's devices (see [`crate::inject`]).
+    pub inject: Vec<InjectSpec>,
+    /// Display captures compared with reference images (see
+    /// [`crate::screen`]).
+    pub screen: Vec<ScreenSpec>,
+    /// Exchanges with the guest's NIC (see [`crate::packets`]).
+    pub packet: Vec<PacketSpec>,
+    /// The guest's address for `[[packet]]` exchanges.
+    pub guest_ip: Option<Ipv4Addr>,
+    /// KVM or TCG (see [`crate::accel`]).
+    pub accel: Option<Accel>,
+    /// Timeout multiplier when TCG runs a guest KVM could have run.
+    pub tcg_timeout_factor: Option<u32>,
+    /// Extra QEMU arguments, appended after the generated ones.
+    pub qemu_args: Vec<String>,
+    /// Ordered patterns.
+    pub expect: Vec<String>,
+    /// Unordered patterns.
+    pub expect_any: Vec<String>,
+    /// Console input, each after an optional pattern (see
+    /// [`crate::steps`]).
+    pub step: Vec<Step>,
+    pub forbid: Vec<String>,
+    /// Replaces the default panic banners.
+    pub panic_pattern: Vec<String>,
+    pub timeout: Option<u64>,
+    /// Seconds without serial output that count as a hang.
+    pub idle_timeout: Option<u64>,
+    /// Context lines around a forbidden line.
+    pub context: Option<usize>,
+    /// Golden transcript the serial output must match (see
+    /// [`crate::golden`]).
+    pub golden: Option<PathBuf>,
+    pub golden_start: Option<String>,
+    pub golden_end: Option<String>,
+    /// `REGEX=REPLACEMENT` rules for golden lines.
+    pub normalize: Vec<Normalize>,
+    /// Rewrite the golden file from this run.
+    pub bless: Option<bool>,
+    pub exit_device: Option<ExitDevice>,
+    /// isa-debug-exit value that means pass.
+    pub exit_success: Option<u32>,
+    /// Run until the guest exits instead of stopping at the patterns.
+    pub wait_exit: Option<bool>,
+    /// `symbols.json` or kernel ELF for symbolizing crash addresses;
+    /// defaults to the build's, found via `manifest.json` beside the image.
+    pub symbols: Option<PathBuf>,
+    /// Start QEMU paused for GDB (interactive unless `gdb-script` is set).
+    pub gdb: Option<bool>,
+    /// GDB batch script run against the paused target.
+    pub gdb_script: Option<PathBuf>,
+    /// gdbstub port [default: 1234 interactive, a free one for scripts].
+    pub gdb_port: Option<u16>,
+    /// Log interrupts and resets (`-d int,cpu_reset`) to classify triple
+    /// faults.
+    pub cpu_log: Option<bool>,
+    /// QEMU event classes traced to a kept log and summarized (see
+    /// [`crate::trace`]).
+    pub trace: Vec<TraceEvent>,
+    /// Record executed guest code for `--coverage` reports (see
+    /// [`crate::coverage`]); runs under TCG.
+    pub coverage: Option<bool>,
+    /// Physical memory ranges (`ADDR:LEN`) dumped on failure.
+    pub dump_memory: Vec<MemoryRange>,
+    /// Write an ELF core of guest RAM on failure (see [`crate::qmp`]).
+    pub dump_on_failure: Option<bool>,
+    /// Directory for a `<name>.ppm` screenshot on failure.
+    pub screenshot_dir: Option<PathBuf>,
+    /// Boot once to this pattern, snapshot, and start the test from there
+    /// (see [`crate::snapshot`]).
+    pub snapshot_at: Option<String>,
+    /// Reruns of a failing test (see [`crate::flaky`]).
+    pub retries: Option<u32>,
+    /// `[user@]host` to run QEMU on over SSH (see [`crate::remote`]).
+    pub remote: Option<String>,
+    /// Run replayably under TCG (see [`crate::deterministic`]).
+    pub deterministic: Option<bool>,
+    /// Seed of a deterministic run; implies `deterministic`.
+    pub deterministic_seed: Option<u64>,
+    /// Record each run, keeping failures' logs to replay (see
+    /// [`crate::replay`]).
+    pub record: Option<bool>,
+}
+
+/// `[build]`: a kernel-builder invocation whose image the test boots.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct BuildTarget {
+    pub workspace: PathBuf,
+    /// Defaults to the spec's `arch`.
+    pub arch: Option<String>,
+    /// `--image-format`; without it the test boots the linked ELF.
+    pub image_format: Option<String>,
+    /// Extra kernel-builder arguments (before the subcommand position).
+    pub args: Vec<String>,
+}
+
+/// A guest memory size: `64M`, `4G`, or plain MiB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct MemorySize {
+    pub mb: u32,
+}
+
+impl FromStr for MemorySize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (digits, scale) = match s.strip_suffix(['G', 'g']) {
+            Some(digits) => (digits, 1024),
+            None => (s.strip_suffix(['M', 'm']).unwrap_or(s), 1),
+        };
+        let n: u32 = digits
+            .parse()
+            .map_err(|e| format!("bad memory size `{s}`: {e}"))?;
+        match n.checked_mul(scale) {
+            Some(mb) if mb > 0 => Ok(Self { mb }),
+            _ => Err(format!("memory size `{s}` is out of range")),
+        }
+    }
+}
+
+impl TryFrom<String> for MemorySize {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for MemorySize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.mb.is_multiple_of(1024) {
+            write!(f, "{}G", self.mb / 1024)
+        } else {
+            write!(f, "{}M", self.mb)
+        }
+    }
+}
+
+impl TestSpec {
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut spec: Self = auton_toml::from_file(path)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        if let Some(kernel) = &mut spec.kernel {
+            *kernel = dir.join(&*kernel);
+        }
+        for path in [
+            &mut spec.symbols,
+            &mut spec.gdb_script,
+            &mut spec.screenshot_dir,
+            &mut spec.firmware,
+            &mut spec.disk,
+            &mut spec.golden,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = dir.join(&*path);
+        }
+        for screen in &mut spec.screen {
+            screen.reference = dir.join(&screen.reference);
+        }
+        if let Some(build) = &mut spec.build {
+            build.workspace = dir.join(&build.workspace);
+        }
+        if spec.kernel.is_some() && spec.build.is_some() {
+            bail!(
+                "{}: set either `kernel` or `[build]`, not both",
+                path.display()
+            );
+        }
+        if !spec.cpus.is_empty() && spec.smp.is_some() {
+            bail!(
+                "{}: set either `smp` or a `cpus` matrix, not both",
+                path.display()
+            );
+        }
+        let mut counts = spec.cpus.clone();
+        counts.sort_unstable();
+        counts.dedup();
+        if counts.len() < spec.cpus.len() || counts.first() == Some(&0) {
+            bail!(
+                "{}: `cpus` needs distinct counts of at least 1",
+                path.display()
+            );
+        }
+        if !spec.mem.is_empty() && spec.memory.is_some() {
+            bail!(
+                "{}: set either `memory` or a `mem` matrix, not both",
+                path.display()
+            );
+        }
+        let mut sizes = spec.mem.clone();
+        sizes.sort_unstable();
+        sizes.dedup();
+        if sizes.len() < spec.mem.len() {
+            bail!("{}: `mem` needs distinct sizes", path.display());
+        }
+        Ok(spec)
+    }
+
+    pub fn arch(&self) -> &str {
+        self.arch.as_deref().unwrap_or(DEFAULT_ARCH)
+    }
+
+    /// Append `other`'s patterns and let its scalars override.
+    pub fn merge(&mut self, other: TestSpec) {
+        self.name = other.name.or(self.name.take());
+        self.kernel = other.kernel.or(self.kernel.take());
+        self.build = other.build.or(self.build.take());
+        self.fuzz = other.fuzz.or(self.fuzz.take());
+        self.control = other.control.or(self.control.take());
+        self.soak = other.soak.or(self.soak.take());
+        self.arch = other.arch.or(self.arch.take());
+        self.machine = other.machine.or(self.machine.take());
+        self.boot = other.boot.or(self.boot);
+        self.firmware = other.firmware.or(self.firmware.take());
+        self.memory = other.memory.or(self.memory);
+        if !other.mem.is_empty() {
+            self.mem = other.mem;
+        }
+        self.smp = other.smp.or(self.smp);
+        if !other.cpus.is_empty() {
+            self.cpus = other.cpus;
+        }
+        self.disk = other.disk.or(self.disk.take());
+        self.net = other.net.or(self.net.take());
+        self.disk_error.extend(other.disk_error);
+        self.inject.extend(other.inject);
+        self.screen.extend(other.screen);
+        self.packet.extend(other.packet);
+        self.guest_ip = other.guest_ip.or(self.guest_ip);
+        self.accel = other.accel.or(self.accel);
+        self.tcg_timeout_factor = other.tcg_timeout_factor.or(self.tcg_timeout_factor);
+        self.qemu_args.extend(other.qemu_args);
+        self.expect.extend(other.expect);
+        self.expect_any.extend(other.expect_any);
+        self.step.extend(other.step);
+        self.forbid.extend(other.forbid);
+        self.panic_pattern.extend(other.panic_pattern);
+        self.timeout = other.timeout.or(self.timeout);
+        self.idle_timeout = other.idle_timeout.or(self.idle_timeout);
+        self.context = other.context.or(self.context);
+        self.golden = other.golden.or(self.golden.take());
+        self.golden_start = other.golden_start.or(self.golden_start.take());
+        self.golden_end = other.golden_end.or(self.golden_end.take());
+        self.normalize.extend(other.normalize);
+        self.bless = other.bless.or(self.bless);
+        self.exit_device = other.exit_device.or(self.exit_device);
+        self.exit_success = other.exit_success.or(self.exit_success);
+        self.wait_exit = other.wait_exit.or(self.wait_exit);
+        self.cpu_log = other.cpu_log.or(self.cpu_log);
+        self.trace.extend(other.trace);
+        self.coverage = other.coverage.or(self.coverage);
+        self.symbols = other.symbols.or(self.symbols.take());
+        self.gdb = other.gdb.or(self.gdb);
+        self.gdb_script = other.gdb_script.or(self.gdb_script.take());
+        self.gdb_port = other.gdb_port.or(self.gdb_port);
+        self.dump_memory.extend(other.dump_memory);
+        self.dump_on_failure = other.dump_on_failure.or(self.dump_on_failure);
+        self.screenshot_dir = other.screenshot_dir.or(self.screenshot_dir.take());
+        self.snapshot_at = other.snapshot_at.or(self.snapshot_at.take());
+        self.retries = other.retries.or(self.retries);
+        self.remote = other.remote.or(self.remote.take());
+        self.deterministic = other.deterministic.or(self.deterministic);
+        self.deterministic_seed = other.deterministic_seed.or(self.deterministic_seed);
+        self.record = other.record.or(self.record);
+    }
+
+    /// Compile the patterns, falling back to `default_expect` when no
+    /// positive pattern is given and to `default_panic` for the banners.
+    pub fn expectations(
+        &self,
+        default_expect: &str,
+        default_panic: &[&str],
+    ) -> Result<Expectations> {
+        let expect = if self.expect.is_empty() && self.expect_any.is_empty() {
+            vec![default_expect.to_string()]
+        } else {
+            self.expect.clone()
+        };
+        let panic: Vec<String> = if self.panic_pattern.is_empty() {
+            default_panic.iter().map(|s| s.to_string()).collect()
+        } else {
+            self.panic_pattern.clone()
+        };
+        Expectations::new(
+            &expect,
+            &self.expect_any,
+            &self.forbid,
+            &panic,
+            self.context.unwrap_or(expect::DEFAULT_CONTEXT),
+        )
+    }
+
+    /// The QEMU run for booting `kernel` under this spec.
+    pub fn run_config(&self, kernel: &Path, transcript_limit: usize) -> Result<RunConfig> {
+        let machine = Machine::resolve(self, kernel)?;
+        let arch = machine.arch.as_str();
+        let mut args = qemu_args(
+            &kernel.display().to_string(),
+            machine.machine.as_deref(),
+            self.memory.unwrap_or(DEFAULT_MEMORY_MB),
+        );
+        args.extend(machine.qemu_args());
+        if let Some(n) = self.smp {
+            args.extend(["-smp".to_string(), n.to_string()]);
+        }
+        let mut disk = self.disk.as_deref().map(Disk::new).transpose()?;
+        if let Some(d) = &mut disk {
+            d.errors = self.disk_error.clone();
+            args.extend(d.qemu_args());
+        } else if !self.disk_error.is_empty() {
+            bail!("`[[disk-error]]` needs a `disk`");
+        }
+        let inject = self
+            .inject
+            .iter()
+            .map(|i| Injection::new(i, disk.is_some(), self.has_nic()))
+            .collect::<Result<Vec<_>>>()?;
+        if !self.screen.is_empty() && self.remote.is_some() {
+            bail!("`[[screen]]` cannot be combined with `remote`");
+        }
+        let screens = self
+            .screen
+            .iter()
+            .map(|s| Screen::new(s, self.bless.unwrap_or(false)))
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(backend) = &self.net {
+            args.extend(devices::net_args(backend));
+        }
+        let packets = self.harness()?;
+        if self.control.is_some() && self.remote.is_some() {
+            bail!("`[control]` cannot be combined with `remote`");
+        }
+        let control = self.control.as_ref().map(Control::new);
+        if let Some(c) = &control {
+            args.extend(c.qemu_args());
+        }
+        if let Some(harness) = &packets {
+            args.extend(harness.qemu_args());
+        }
+        let fuzz_channel = self
+            .fuzz
+            .as_ref()
+            .filter(|f| f.channel == Channel::VirtioSerial)
+            .map(|_| crate::scratch_path("fuzz"));
+        if let Some(socket) = &fuzz_channel {
+            args.extend(fuzz::channel_args(socket));
+        }
+        let accel = self.resolve_accel(arch)?;
+        args.extend(accel.qemu_args());
+        let deterministic = self
+            .is_deterministic()
+            .then(|| Deterministic::new(self.deterministic_seed));
+        if let Some(d) = &deterministic {
+            args.extend(d.qemu_args());
+        }
+        let record = self.is_recorded()?.then(replay::scratch_log);
+        if let Some(log) = &record {
+            replay::add_args(&mut args, log);
+        }
+        let exit_device = self.exit_device.unwrap_or_default().resolve(arch);
+        args.extend(exit_device.qemu_args());
+        let gdb = self.gdb_config(kernel)?;
+        if let Some(g) = &gdb {
+            args.extend(g.qemu_args());
+        }
+        // A guest held by the debugger is silent, so no watchdog under GDB.
+        let idle_timeout = self.idle_timeout.filter(|_| gdb.is_none());
+        let factor = self
+            .tcg_timeout_factor
+            .unwrap_or(DEFAULT_TCG_TIMEOUT_FACTOR);
+        let scale = |t: Duration| accel.scale_timeout(arch, t, factor);
+        let qmp_socket = qmp::socket_path();
+        args.extend(qmp::qemu_args(&qmp_socket));
+        // QEMU has one `-D` log, so the CPU log, the trace and coverage
+        // share it.
+        let cpu_logged = self.cpu_log.unwrap_or(false);
+        let covered = self.coverage.unwrap_or(false);
+        let mut log_items = Vec::new();
+        if cpu_logged {
+            log_items.extend(CPU_LOG_ITEMS);
+        }
+        if covered {
+            log_items.push(coverage::LOG_ITEM);
+        }
+        let log = match (self.trace.is_empty(), log_items.is_empty()) {
+            (false, _) => Some(crate::scratch_path("trace")),
+            (true, false) => Some(crate::scratch_path("log")),
+            (true, true) => None,
+        };
+        if let Some(log) = &log {
+            args.extend(trace::qemu_args(log, &self.trace, &log_items));
+        }
+        let cpu_log = log.clone().filter(|_| cpu_logged);
+        let trace_log = log.clone().filter(|_| !self.trace.is_empty());
+        let coverage = match log.filter(|_| covered) {
+            Some(log) => Some(CoverageConfig {
+                elf: gdb::find_elf(kernel, self.symbols.as_deref())
+                    .context("coverage needs the kernel ELF; pass --symbols")?,
+                log,
+            }),
+            None => None,
+        };
+        args.extend(self.qemu_args.iter().cloned());
+        let timeout = match (self.timeout, &gdb) {
+            (Some(secs), _) => scale(Duration::from_secs(secs)),
+            (None, Some(g)) if g.script.is_none() => gdb::INTERACTIVE_TIMEOUT,
+            (None, _) => scale(Duration::from_secs(DEFAULT_TIMEOUT_SECS)),
+        };
+        tracing::debug!(accel = accel.name(), ?timeout, "accelerator");
+        let interactive = gdb.as_ref().is_some_and(|g| g.script.is_none());
+        let mut expect = self.expectations(DEFAULT_EXPECT, DEFAULT_PANIC_PATTERNS)?;
+        if interactive {
+            // Only QEMU exiting ends a debugging session.
+            expect.forbid.clear();
+            expect.panic.clear();
+        }
+        Ok(RunConfig {
+            program: machine.binary.clone(),
+            args,
+            accel,
+            timeout,
+            expect,
+            steps: Steps::new(&self.step)?,
+            transcript_limit,
+            exit_device,
+            exit_success: self.exit_success.unwrap_or(ISA_DEBUG_EXIT_SUCCESS),
+            wait_for_exit: interactive || self.wait_exit.unwrap_or(false),
+            idle_timeout: idle_timeout.map(|secs| scale(Duration::from_secs(secs))),
+            qmp_socket: Some(qmp_socket),
+            dump_memory: self.dump_memory.clone(),
+            screenshot: self
+                .screenshot_dir
+                .as_ref()
+                .map(|dir| dir.join(format!("{}.ppm", self.test_name(kernel)))),
+            disk,
+            golden: self
+                .golden
+                .clone()
+                .map(|file| {
+                    Golden::new(
+                        file,
+                        self.golden_start.as_deref(),
+                        self.golden_end.as_deref(),
+                        &self.normalize,
+                        self.bless.unwrap_or(false),
+                    )
+                })
+                .transpose()?,
+            core_dump: self
+                .dump_on_failure
+                .unwrap_or(false)
+                .then(|| crate::scratch_path("vmcore")),
+            cpu_log,
+            trace: trace_log,
+            coverage,
+            fuzz_channel,
+            gdb,
+            remote: self.remote.as_deref().map(Remote::new),
+            inject,
+            deterministic,
+            record,
+            screens,
+            packets,
+            control,
+            // Set by the caller: the results store, snapshots and soak.
+            ..Default::default()
+        })
+    }
+
+    /// The accelerator, resolved for `arch`; coverage and deterministic
+    /// runs need TCG. `auto` is left to a remote host's QEMU, since this
+    /// host's KVM says nothing about it.
+    pub fn resolve_accel(&self, arch: &str) -> Result<Accel> {
+        let accel = self.accel.unwrap_or_default();
+        let needs_tcg = if self.coverage.unwrap_or(false) {
+            "coverage"
+        } else if self.is_deterministic() {
+            "a deterministic run"
+        } else if self.record.unwrap_or(false) {
+            "recording"
+        } else if self.remote.is_some() {
+            return Ok(accel);
+        } else {
+            return Ok(accel.resolve(arch));
+        };
+        if accel == Accel::Kvm {
+            bail!("{needs_tcg} needs TCG, which `accel = \"kvm\"` rules out");
+        }
+        Ok(Accel::Tcg)
+    }
+
+    /// Whether runs are recorded; QEMU cannot replay every device.
+    fn is_recorded(&self) -> Result<bool> {
+        if !self.record.unwrap_or(false) {
+            return Ok(false);
+        }
+        let virtio_channel = self
+            .fuzz
+            .as_ref()
+            .is_some_and(|f| f.channel == Channel::VirtioSerial);
+        let unsupported = [
+            ("`disk`", self.disk.is_some()),
+            ("`net`", self.net.is_some()),
+            ("`[[packet]]`", !self.packet.is_empty()),
+            ("`remote`", self.remote.is_some()),
+            ("`snapshot-at`", self.snapshot_at.is_some()),
+            ("`gdb`", self.gdb == Some(true) || self.gdb_script.is_some()),
+            ("a virtio-serial fuzz channel", virtio_channel),
+            ("`[control]`", self.control.is_some()),
+        ];
+        if let Some((what, _)) = unsupported.iter().find(|(_, set)| *set) {
+            bail!("`record` cannot be combined with {what}");
+        }
+        Ok(true)
+    }
+
+    /// Whether the guest has a NIC, for `net` or `[[packet]]`.
+    fn has_nic(&self) -> bool {
+        self.net.is_some() || !self.packet.is_empty()
+    }
+
+    /// The `[[packet]]` exchanges, which are the NIC's only backend.
+    fn harness(&self) -> Result<Option<Harness>> {
+        if self.packet.is_empty() {
+            if self.guest_ip.is_some() {
+                bail!("`guest-ip` needs a `[[packet]]`");
+            }
+            return Ok(None);
+        }
+        if self.net.is_some() {
+            bail!(
+                "`[[packet]]` cannot be combined with `net`: the exchanges are the NIC's backend"
+            );
+        }
+        if self.remote.is_some() {
+            bail!("`[[packet]]` cannot be combined with `remote`");
+        }
+        Harness::new(&self.packet, self.guest_ip).map(Some)
+    }
+
+    /// Whether runs are deterministic (see [`crate::deterministic`]).
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic.unwrap_or(false) || self.deterministic_seed.is_some()
+    }
+
+    /// `name`, else the kernel image's file stem.
+    pub fn test_name(&self, kernel: &Path) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            let stem = kernel.file_stem().unwrap_or_default();
+            stem.to_string_lossy().into_owned()
+        })
+    }
+
+    pub fn gdb_config(&self, kernel: &Path) -> Result<Option<GdbConfig>> {
+        if !self.gdb.unwrap_or(false) && self.gdb_script.is_none() {
+            return Ok(None);
+        }
+        let (port, program) = match &self.gdb_script {
+            Some(_) => (
+                self.gdb_port.map_or_else(gdb::free_port, Ok)?,
+                gdb::find_gdb()?,
+            ),
+            None => (
+                self.gdb_port.unwrap_or(gdb::DEFAULT_PORT),
+                gdb::find_gdb().unwrap_or_else(|_| "gdb".to_string()),
+            ),
+        };
+        Ok(Some(GdbConfig {
+            port,
+            script: self.gdb_script.clone(),
+            program,
+            elf: gdb::find_elf(kernel, self.symbols.as_deref()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_merges_cli_patterns() {
+        let mut spec: TestSpec = auton_toml::from_str(
+            "timeout = 30\nexpect = ['\\[MM\\]', '\\[BOOT\\] OK']\nforbid = ['GPF']\n",
+        )
+        .unwrap();
+        spec.merge(TestSpec {
+            forbid: vec!["double fault".into()],
+            timeout: Some(5),
+            ..Default::default()
+        });
+        assert_eq!(spec.timeout, Some(5));
+        let exp = spec.expectations("unused", &["PANIC"]).unwrap();
+        assert_eq!(exp.ordered.len(), 2);
+        assert_eq!(exp.forbid.len(), 2);
+        assert_eq!(exp.panic[0].as_str(), "PANIC");
+
+        let exp = TestSpec::default()
+            .expectations(r"\[BOOT\] OK", &[])
+            .unwrap();
+        assert_eq!(exp.ordered[0].as_str(), r"\[BOOT\] OK");
+        assert!(auton_toml::from_str::<TestSpec>("expct = []\n").is_err());
+    }
+
+    #[test]
+    fn parses_memory_sizes() {
+        let spec: TestSpec = auton_toml::from_str("mem = [\"64M\", \"4G\", \"512\"]\n").unwrap();
+        let sizes: Vec<u32> = spec.mem.iter().map(|m| m.mb).collect();
+        assert_eq!(sizes, [64, 4096, 512]);
+        assert_eq!(spec.mem[1].to_string(), "4G");
+        assert_eq!(spec.mem[2].to_string(), "512M");
+        for bad in ["0M", "4T", "G", "9999999G"] {
+            assert!(bad.parse::<MemorySize>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn run_config_adds_exit_device_and_extra_args() {
+        let spec = TestSpec {
+            memory: Some(256),
+            qemu_args: vec!["-smp".into(), "2".into()],
+            accel: Some(Accel::Kvm),
+            ..Default::default()
+        };
+        let cfg = spec.run_config(Path::new("b/auton.iso"), 1024).unwrap();
+        assert_eq!(cfg.program, "qemu-system-x86_64");
+        assert!(cfg.args.windows(2).any(|w| w == ["-cdrom", "b/auton.iso"]));
+        assert!(cfg.args.contains(&"256M".to_string()));
+        assert!(cfg.args.iter().any(|a| a.starts_with("isa-debug-exit")));
+        assert!(cfg.args.contains(&"-enable-kvm".to_string()));
+        assert!(cfg.args.ends_with(&["-smp".to_string(), "2".to_string()]));
+        assert_eq!(cfg.timeout, Duration::from_secs(DEFAULT_TIMEOUT_SECS));
+    }
+
+    #[test]
+    fn deterministic_runs_are_seeded_tcg_runs() {
+        let mut spec = TestSpec {
+            arch: Some("x86_64".into()),
+            deterministic_seed: Some(7),
+            ..Default::default()
+        };
+        let cfg = spec.run_config(Path::new("auton.iso"), 1024).unwrap();
+        assert_eq!(cfg.accel, Accel::Tcg);
+        assert_eq!(cfg.deterministic.unwrap().seed, 7);
+        assert!(cfg.args.windows(2).any(|w| w == ["-seed", "7"]));
+        assert!(cfg.args.contains(&"-icount".to_string()));
+        spec.accel = Some(Accel::Kvm);
+        let err = spec.run_config(Path::new("auton.iso"), 1024).unwrap_err();
+        assert!(err.to_string().starts_with("a deterministic run needs TCG"));
+    }
+
+    #[test]
+    fn recorded_runs_record_under_tcg() {
+        let mut spec = TestSpec {
+            arch: Some("x86_64".into()),
+            record: Some(true),
+            ..Default::default()
+        };
+        let cfg = spec.run_config(Path::new("auton.iso"), 1024).unwrap();
+        assert_eq!(cfg.accel, Accel::Tcg);
+        let log = cfg.record.unwrap().display().to_string();
+        assert!(cfg
+            .args
+            .iter()
+            .any(|a| a.contains(&format!("rr=record,rrfile={log}"))));
+        spec.net = Some("user".into());
+        let err = spec.run_config(Path::new("auton.iso"), 1024).unwrap_err();
+        assert_eq!(err.to_string(), "`record` cannot be combined with `net`");
+    }
+
+    #[test]
+    fn packet_exchanges_own_the_nic() {
+        let mut spec: TestSpec = auton_toml::from_str(
+            r#"
+            arch = "x86_64"
+            guest-ip = "192.168.7.2"
+
+            [[packet]]
+            send = "udp"
+            port = 7
+            reply = "pong"
+            "#,
+        )
+        .unwrap();
+        let cfg = spec.run_config(Path::new("auton.iso"), 1024).unwrap();
+        let harness = cfg.packets.unwrap();
+        assert_eq!(harness.guest_ip, Ipv4Addr::new(192, 168, 7, 2));
+        assert_eq!(harness.exchanges[0].reply.as_deref(), Some(&b"pong"[..]));
+        assert!(cfg.args.contains(&format!(
+            "socket,id=net0,udp=127.0.0.1:{},localaddr=127.0.0.1:{}",
+            harness.host_port, harness.qemu_port
+        )));
+        spec.net = Some("user".into());
+        let err = spec.run_config(Path::new("auton.iso"), 1024).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("`[[packet]]` cannot be combined with `net`"));
+    }
+}
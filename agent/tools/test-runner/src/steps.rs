@@ -0,0 +1,190 @@
+//! Interactive serial steps (`[[step]]` in a spec).
+//!
+//! Steps drive the kernel's serial shell. They run in order: each waits
+//! for its `expect` regex, if it has one, then writes its `send` text to
+//! the guest console (QEMU's stdin, which `-serial stdio` connects to the
+//! first UART). Prompts rarely end in a newline, so a step's pattern is
+//! also tried against the line still being printed; a line satisfies at
+//! most one step, so a prompt followed by the echoed command is not taken
+//! for the next prompt. A run only completes once every step has run, and
+//! a step still waiting when it ends is reported as unmatched.
+//!
+//! ```toml
+//! [[step]]
+//! expect = 'auton> $'
+//! send = "meminfo\n"
+//!
+//! [[step]]
+//! expect = 'free: \d+ KiB'
+//! ```
+
+use crate::expect::Matched;
+use crate::regex::Regex;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Step {
+    /// Wait for this pattern first.
+    pub expect: Option<String>,
+    /// Then type this (TOML escapes such as `\n` apply).
+    pub send: Option<String>,
+}
+
+/// The state of a run's steps.
+#[derive(Debug, Clone)]
+pub struct Steps {
+    steps: Vec<(Option<Regex>, String)>,
+    /// The step being waited for.
+    next: usize,
+    /// Complete lines seen.
+    line_no: usize,
+    /// The line that satisfied the last step, which cannot satisfy another.
+    consumed: Option<usize>,
+    matched: Vec<Matched>,
+    started: Instant,
+}
+
+impl Default for Steps {
+    fn default() -> Self {
+        Self {
+            steps: Vec::new(),
+            next: 0,
+            line_no: 0,
+            consumed: None,
+            matched: Vec::new(),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Steps {
+    pub fn new(steps: &[Step]) -> Result<Self> {
+        let steps = steps
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let expect = s.expect.as_deref().map(Regex::new).transpose();
+                let expect = expect.with_context(|| format!("step {}", i + 1))?;
+                Ok((expect, s.send.clone().unwrap_or_default()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            steps,
+            ..Default::default()
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Input due before any output: the leading steps without `expect`.
+    pub fn start(&mut self) -> String {
+        self.started = Instant::now();
+        self.fire(false)
+    }
+
+    /// Input due after a complete serial line.
+    pub fn line(&mut self, line: &str) -> String {
+        self.line_no += 1;
+        self.offer(line, self.line_no)
+    }
+
+    /// Input due given the line printed so far, not yet terminated.
+    pub fn partial(&mut self, text: &str) -> String {
+        self.offer(text, self.line_no + 1)
+    }
+
+    pub fn done(&self) -> bool {
+        self.next == self.steps.len()
+    }
+
+    /// Steps whose patterns matched, in order.
+    pub fn matched(&self) -> &[Matched] {
+        &self.matched
+    }
+
+    /// The step still waiting, as `step N: PATTERN`.
+    pub fn waiting(&self) -> Option<String> {
+        let (expect, _) = self.steps.get(self.next)?;
+        let pattern = expect.as_ref().map_or("", Regex::as_str);
+        Some(format!("step {}: {pattern}", self.next + 1))
+    }
+
+    fn offer(&mut self, text: &str, line_no: usize) -> String {
+        if self.consumed == Some(line_no) {
+            return String::new();
+        }
+        let Some((Some(re), _)) = self.steps.get(self.next) else {
+            return String::new();
+        };
+        if !re.is_match(text) {
+            return String::new();
+        }
+        self.matched.push(Matched {
+            pattern: re.to_string(),
+            line_no,
+            line: text.to_string(),
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+        });
+        self.consumed = Some(line_no);
+        self.fire(true)
+    }
+
+    /// Sends of the current step (its pattern just `matched`) and of each
+    /// following step that needs no output.
+    fn fire(&mut self, matched: bool) -> String {
+        let mut input = String::new();
+        let mut go = matched;
+        while let Some((expect, send)) = self.steps.get(self.next) {
+            if !go && expect.is_some() {
+                break;
+            }
+            input.push_str(send);
+            self.next += 1;
+            go = false;
+        }
+        input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(expect: Option<&str>, send: &str) -> Step {
+        Step {
+            expect: expect.map(String::from),
+            send: Some(send.into()),
+        }
+    }
+
+    #[test]
+    fn prompts_match_once_and_send_in_order() {
+        let mut steps = Steps::new(&[
+            step(None, "\n"),
+            step(Some("auton> $"), "help\n"),
+            step(Some("auton> $"), "meminfo\n"),
+            step(Some(r"free: \d+"), ""),
+        ])
+        .unwrap();
+        assert_eq!(steps.start(), "\n");
+        assert_eq!(steps.line("[BOOT] OK"), "");
+        assert_eq!(steps.partial("auton> "), "help\n");
+        // The echoed command completes the prompt's line, which is spent.
+        assert_eq!(steps.line("auton> help"), "");
+        assert_eq!(steps.line("commands: help meminfo"), "");
+        assert_eq!(steps.partial("auton> "), "meminfo\n");
+        assert_eq!(steps.waiting().as_deref(), Some(r"step 4: free: \d+"));
+        assert!(!steps.done());
+        assert_eq!(steps.line("auton> meminfo"), "");
+        assert_eq!(steps.line("free: 1024 KiB"), "");
+        assert!(steps.done() && steps.waiting().is_none());
+        let lines: Vec<usize> = steps.matched().iter().map(|m| m.line_no).collect();
+        assert_eq!(lines, [2, 4, 5]);
+        assert!(Steps::new(&[step(Some("(bad"), "")]).is_err());
+    }
+}
@@ -0,0 +1,979 @@
+//! `test-runner suite <dir>`: run every `*.toml` spec in a directory.
+//!
+//! Specs that ask for a `[build]` are built first, once per distinct
+//! target, with kernel-builder writing to `<build-dir>/<n>/`; the image is
+//! taken from that build's `manifest.json`. Tests then run concurrently, at
+//! most `parallel` QEMU instances at a time and within the host's memory
+//! and CPU budget ([`crate::admission`]), and outcomes are reported in
+//! file-name order whatever order they finished in. Log lines from a test
+//! carry a `vm{test=<name>}` prefix. Specs with
+//! `snapshot-at` get their snapshot images ([`crate::snapshot`]) after the
+//! builds, once per distinct machine.
+//!
+//! A spec with a `cpus` or `mem` matrix becomes one test per vCPU count
+//! and memory size (every combination when it has both), named
+//! `<name>@smp2-mem64M`, whose configuration holds whatever `--smp` and
+//! `--memory` say. A matrix test's timeout is multiplied by its memory in
+//! GiB, rounded up, since a large guest takes longer to set up.
+//! Besides a row per configuration, [`render_matrix`] points out the tests
+//! that pass in some and fail in others, as locking bugs do with more CPUs
+//! and allocator bugs at the memory extremes.
+
+use crate::admission::Admission;
+use crate::known::Status;
+use crate::qemu::{self, RunConfig, RunResult};
+use crate::results::Store;
+use crate::spec::{BuildTarget, MemorySize, TestSpec, DEFAULT_MEMORY_MB, DEFAULT_TIMEOUT_SECS};
+use crate::timeouts::Learned;
+use crate::TestSummary;
+use crate::{flaky, snapshot, symbolize};
+use anyhow::{bail, Context, Result};
+use auton_core::manifest::{BuildManifest, MANIFEST_NAME};
+use auton_core::plan::{Plan, Planned};
+use auton_core::process;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::Instrument;
+
+/// stderr lines kept from a failed build.
+const BUILD_LOG_TAIL: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct SuiteTest {
+    pub name: String,
+    pub path: PathBuf,
+    pub spec: TestSpec,
+    /// Its place in the spec's `cpus`/`mem` matrix.
+    pub matrix: Option<MatrixEntry>,
+}
+
+/// One configuration of a spec's `cpus` × `mem` matrix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MatrixEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smp: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_mb: Option<u32>,
+}
+
+impl MatrixEntry {
+    /// Every configuration `spec` asks for; empty without a matrix.
+    pub fn all(spec: &TestSpec) -> Vec<Self> {
+        if spec.cpus.is_empty() && spec.mem.is_empty() {
+            return Vec::new();
+        }
+        let mut cpus: Vec<Option<u32>> = spec.cpus.iter().copied().map(Some).collect();
+        let mut mem: Vec<Option<u32>> = spec.mem.iter().map(|m| Some(m.mb)).collect();
+        for axis in [&mut cpus, &mut mem] {
+            if axis.is_empty() {
+                axis.push(None);
+            }
+        }
+        cpus.iter()
+            .flat_map(|&smp| mem.iter().map(move |&memory_mb| Self { smp, memory_mb }))
+            .collect()
+    }
+
+    /// `smp2-mem64M`.
+    pub fn label(&self) -> String {
+        let smp = self.smp.map(|n| format!("smp{n}"));
+        let mem = self.memory_mb.map(|mb| format!("mem{}", MemorySize { mb }));
+        [smp, mem]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    /// `base`'s name in this configuration.
+    pub fn name(&self, base: &str) -> String {
+        format!("{base}@{}", self.label())
+    }
+
+    /// Run `spec` (with the command line merged in) in this
+    /// configuration, with a timeout for its memory.
+    pub fn apply(&self, spec: &mut TestSpec) {
+        spec.cpus.clear();
+        spec.mem.clear();
+        if self.smp.is_some() {
+            spec.smp = self.smp;
+        }
+        if let Some(mb) = self.memory_mb {
+            spec.memory = Some(mb);
+            let timeout = spec.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS);
+            spec.timeout = Some(timeout * u64::from(mb.div_ceil(1024)));
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SuiteOptions {
+    /// Concurrent QEMU instances.
+    pub parallel: usize,
+    pub kernel_builder: PathBuf,
+    pub build_dir: PathBuf,
+    /// Image for specs that name neither `kernel` nor `[build]`.
+    pub kernel: Option<PathBuf>,
+    /// Command-line settings, merged over every spec.
+    pub overrides: TestSpec,
+    pub transcript_limit: usize,
+    /// Memory budget in MiB [default: from the host].
+    pub max_memory_mb: Option<u64>,
+    /// vCPU budget [default: host CPUs].
+    pub max_cpus: Option<u32>,
+    /// Cache for `snapshot-at` images.
+    pub snapshot_dir: PathBuf,
+    /// Where each run's artifacts go, if anywhere.
+    pub results: Option<Store>,
+    /// Timeouts learned from the history, with `--adaptive-timeout`.
+    pub timeouts: Option<Arc<Learned>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TestOutcome {
+    pub name: String,
+    pub spec: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<PathBuf>,
+    /// The configuration of a matrix run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<MatrixEntry>,
+    pub passed: bool,
+    /// Why the test could not run at all (bad spec, failed build, no QEMU).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<TestSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<RunResult>,
+    /// Runs it took; the last one is `result`.
+    pub attempts: u32,
+    /// Why the test looks nondeterministic (see [`crate::flaky`]).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flaky: Option<String>,
+    /// The last run's artifact directory ([`crate::results`]).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifacts: Option<PathBuf>,
+    /// How it compares with the `--baseline` (see [`crate::known`]).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<Status>,
+}
+
+impl TestOutcome {
+    /// The outcome of a run that reached QEMU.
+    pub fn from_result(name: String, spec: PathBuf, kernel: PathBuf, result: RunResult) -> Self {
+        let summary = result.summary();
+        Self {
+            name,
+            spec,
+            kernel: Some(kernel),
+            matrix: None,
+            passed: result.passed(&summary),
+            error: None,
+            summary: Some(summary),
+            result: Some(result),
+            attempts: 1,
+            flaky: None,
+            artifacts: None,
+            baseline: None,
+        }
+    }
+
+    fn failed(test: &SuiteTest, kernel: Option<PathBuf>, error: String) -> Self {
+        Self {
+            name: test.name.clone(),
+            spec: test.path.clone(),
+            kernel,
+            matrix: test.matrix,
+            passed: false,
+            error: Some(error),
+            summary: None,
+            result: None,
+            attempts: 0,
+            flaky: None,
+            artifacts: None,
+            baseline: None,
+        }
+    }
+}
+
+/// The tests the `*.toml` specs in `dir` make (one per matrix entry),
+/// sorted by file name, optionally only those whose name contains
+/// `filter`.
+pub fn discover(dir: &Path, filter: Option<&str>) -> Result<Vec<SuiteTest>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .with_context(|| format!("reading {}", dir.display()))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_file() && p.extension().is_some_and(|e| e == "toml"))
+        .collect();
+    paths.sort();
+    let mut tests = Vec::new();
+    let mut seen = HashMap::new();
+    for path in paths {
+        let spec = TestSpec::load(&path)?;
+        let base = spec.test_name(&path);
+        let mut matrix: Vec<Option<MatrixEntry>> =
+            MatrixEntry::all(&spec).into_iter().map(Some).collect();
+        if matrix.is_empty() {
+            matrix.push(None);
+        }
+        for entry in matrix {
+            let name = entry.map_or_else(|| base.clone(), |e| e.name(&base));
+            if filter.is_some_and(|f| !name.contains(f)) {
+                continue;
+            }
+            if let Some(other) = seen.insert(name.clone(), path.clone()) {
+                bail!(
+                    "test name `{name}` is used by both {} and {}",
+                    other.display(),
+                    path.display()
+                );
+            }
+            let spec = TestSpec {
+                // Named artifacts (screenshots) follow the test, not the
+                // image.
+                name: Some(name.clone()),
+                ..spec.clone()
+            };
+            let path = path.clone();
+            tests.push(SuiteTest {
+                name,
+                path,
+                spec,
+                matrix: entry,
+            });
+        }
+    }
+    if tests.is_empty() {
+        bail!("no test specs (*.toml) in {}", dir.display());
+    }
+    Ok(tests)
+}
+
+/// kernel-builder next to this executable (same cargo target dir), else
+/// the one on PATH.
+pub fn find_kernel_builder() -> PathBuf {
+    process::sibling("kernel-builder")
+}
+
+/// kernel-builder arguments for `target`, writing to `out`.
+pub fn build_args(target: &BuildTarget, arch: &str, out: &Path) -> Vec<String> {
+    let mut args = vec![
+        "-w".to_string(),
+        target.workspace.display().to_string(),
+        "-a".to_string(),
+        target.arch.clone().unwrap_or_else(|| arch.to_string()),
+        "-o".to_string(),
+        out.display().to_string(),
+    ];
+    if let Some(format) = &target.image_format {
+        args.push("--image-format".to_string());
+        args.push(format.clone());
+    }
+    args.extend(target.args.iter().cloned());
+    args
+}
+
+/// The image a build's `manifest.json` offers for booting: an ISO if one
+/// was built, else the first image, else the linked kernel.
+pub fn image_from_manifest(text: &str) -> Result<PathBuf> {
+    let manifest: BuildManifest = serde_json::from_str(text).context("parsing manifest")?;
+    manifest
+        .boot_image()
+        .context("manifest names no kernel or image")
+}
+
+type Builds = HashMap<(BuildTarget, String), Result<PathBuf, String>>;
+
+/// Run each distinct build once, in order of first use.
+async fn build_all(tests: &[SuiteTest], opts: &SuiteOptions) -> Builds {
+    let mut builds = Builds::new();
+    for test in tests {
+        let Some(target) = &test.spec.build else {
+            continue;
+        };
+        let key = (target.clone(), test.spec.arch().to_string());
+        if builds.contains_key(&key) {
+            continue;
+        }
+        let out = opts.build_dir.join(builds.len().to_string());
+        let result = build_one(&opts.kernel_builder, target, &key.1, &out)
+            .await
+            .map_err(|e| format!("{e:#}"));
+        builds.insert(key, result);
+    }
+    builds
+}
+
+async fn build_one(
+    kernel_builder: &Path,
+    target: &BuildTarget,
+    arch: &str,
+    out: &Path,
+) -> Result<PathBuf> {
+    let args = build_args(target, arch, out);
+    tracing::info!(workspace = %target.workspace.display(), out = %out.display(), "building");
+    let mut cmd = tokio::process::Command::new(kernel_builder);
+    cmd.args(&args);
+    let output = process::run(cmd, None).await?;
+    if !output.success() {
+        bail!(
+            "build of {} failed ({}):\n{}",
+            target.workspace.display(),
+            output.status(),
+            output.stderr_tail(BUILD_LOG_TAIL)
+        );
+    }
+    let manifest = out.join(MANIFEST_NAME);
+    BuildManifest::read(&manifest)?
+        .boot_image()
+        .with_context(|| format!("{} names no kernel or image", manifest.display()))
+}
+
+/// Merge the command line into each test's spec and pick its matrix
+/// configuration.
+fn configure(tests: &mut [SuiteTest], opts: &SuiteOptions) {
+    for test in tests {
+        test.spec.merge(opts.overrides.clone());
+        if let Some(entry) = test.matrix {
+            entry.apply(&mut test.spec);
+        }
+    }
+}
+
+/// What [`run_suite`] would run and write (`--dry-run`): each distinct
+/// build, followed by the steps kernel-builder's own dry run plans for it,
+/// then each test's boot (its first attempt). A kernel not built yet is
+/// named as `<out>/kernel.elf`, where the native driver links it, and its
+/// snapshots are not planned, since they are keyed by the built file.
+pub async fn plan(mut tests: Vec<SuiteTest>, opts: &SuiteOptions) -> Result<Plan> {
+    configure(&mut tests, opts);
+    let mut plan = Plan::default();
+    let mut builds: HashMap<(BuildTarget, String), PathBuf> = HashMap::new();
+    for test in &tests {
+        let Some(target) = &test.spec.build else {
+            continue;
+        };
+        let key = (target.clone(), test.spec.arch().to_string());
+        if builds.contains_key(&key) {
+            continue;
+        }
+        let out = opts.build_dir.join(builds.len().to_string());
+        plan_build(&opts.kernel_builder, target, &key.1, &out, &mut plan).await;
+        let built = std::fs::read_to_string(out.join(MANIFEST_NAME))
+            .ok()
+            .and_then(|text| image_from_manifest(&text).ok());
+        builds.insert(key, built.unwrap_or_else(|| out.join("kernel.elf")));
+    }
+    for test in &tests {
+        let kernel = match &test.spec.build {
+            Some(target) => builds[&(target.clone(), test.spec.arch().to_string())].clone(),
+            None => test
+                .spec
+                .kernel
+                .clone()
+                .or_else(|| opts.kernel.clone())
+                .with_context(|| {
+                    format!(
+                        "{}: spec names no `kernel` or `[build]` and no --kernel was given",
+                        test.name
+                    )
+                })?,
+        };
+        let planned = test
+            .spec
+            .run_config(&kernel, opts.transcript_limit)
+            .and_then(|mut cfg| {
+                if let Some(timeouts) = &opts.timeouts {
+                    timeouts.apply(&test.name, &mut cfg);
+                }
+                let (snapshots, results) = (&opts.snapshot_dir, opts.results.as_ref());
+                plan_boot(test, &kernel, cfg, snapshots, results, &mut plan)
+            });
+        planned.with_context(|| test.name.clone())?;
+    }
+    Ok(plan)
+}
+
+/// Add `test`'s boot of `kernel` with `cfg` to `plan`: from its snapshot
+/// (made in `snapshots` first if need be) if it has one, and into a run
+/// directory in `results`.
+pub fn plan_boot(
+    test: &SuiteTest,
+    kernel: &Path,
+    mut cfg: RunConfig,
+    snapshots: &Path,
+    results: Option<&Store>,
+    plan: &mut Plan,
+) -> Result<()> {
+    let mut what = format!("running {}", test.name);
+    if test.spec.snapshot_at.is_some() && kernel.is_file() {
+        let limit = cfg.transcript_limit;
+        let image = snapshot::plan(&test.spec, kernel, snapshots, limit, plan)?;
+        what.push_str(&format!(" from a copy of {}", image.display()));
+        cfg.args.extend(snapshot::restore_args(&image));
+    }
+    if let Some(store) = results {
+        store.plan(&test.name, &mut cfg, plan);
+    }
+    cfg.plan(what, plan);
+    Ok(())
+}
+
+/// Add kernel-builder's command for `target` to `plan`, then what its own
+/// `--dry-run` says it would run and write; a build it cannot plan (no
+/// compiler yet) is only warned about.
+async fn plan_build(
+    kernel_builder: &Path,
+    target: &BuildTarget,
+    arch: &str,
+    out: &Path,
+    plan: &mut Plan,
+) {
+    let args = build_args(target, arch, out);
+    let program = kernel_builder.display().to_string();
+    let what = format!("building {}", target.workspace.display());
+    plan.run(Planned::new(what, &program, &args));
+    let mut cmd = tokio::process::Command::new(kernel_builder);
+    cmd.args(&args).args(["--dry-run", "--json"]);
+    let planned = process::run(cmd, None).await.and_then(|output| {
+        if !output.success() {
+            bail!("{}", output.stderr_tail(BUILD_LOG_TAIL));
+        }
+        Ok(serde_json::from_str::<Plan>(&output.stdout)?)
+    });
+    match planned {
+        Ok(build) => plan.extend(build),
+        Err(e) => tracing::warn!(
+            workspace = %target.workspace.display(),
+            "kernel-builder could not plan the build: {e:#}"
+        ),
+    }
+}
+
+/// Build what is needed, then run every test; outcomes come back in the
+/// order of `tests`.
+pub async fn run_suite(mut tests: Vec<SuiteTest>, opts: &SuiteOptions) -> Vec<TestOutcome> {
+    configure(&mut tests, opts);
+    let builds = build_all(&tests, opts).await;
+    let kernels: Vec<Result<PathBuf, String>> = tests
+        .iter()
+        .map(|test| match &test.spec.build {
+            Some(target) => builds[&(target.clone(), test.spec.arch().to_string())].clone(),
+            None => test
+                .spec
+                .kernel
+                .clone()
+                .or_else(|| opts.kernel.clone())
+                .ok_or_else(|| {
+                    "spec names no `kernel` or `[build]` and no --kernel was given".into()
+                }),
+        })
+        .collect();
+    let snapshots = snapshot_all(&tests, &kernels, opts).await;
+    let slots = Arc::new(Semaphore::new(opts.parallel.max(1)));
+    let admission = Admission::new(opts.max_memory_mb, opts.max_cpus);
+    let mut handles = Vec::new();
+    for ((test, kernel), snapshot) in tests.into_iter().zip(kernels).zip(snapshots) {
+        let slots = slots.clone();
+        let admission = admission.clone();
+        let transcript_limit = opts.transcript_limit;
+        let results = opts.results.clone();
+        let timeouts = opts.timeouts.clone();
+        let span = tracing::info_span!("vm", test = %test.name);
+        let task = async move {
+            let kernel = match kernel {
+                Ok(k) => k,
+                Err(e) => return TestOutcome::failed(&test, None, e),
+            };
+            let snapshot = match snapshot.transpose() {
+                Ok(s) => s,
+                Err(e) => return TestOutcome::failed(&test, Some(kernel), e),
+            };
+            let _slot = slots.acquire_owned().await.expect("semaphore open");
+            let memory = test.spec.memory.unwrap_or(DEFAULT_MEMORY_MB);
+            let _ticket = admission.admit(memory, test.spec.smp.unwrap_or(1)).await;
+            run_test(
+                &test,
+                kernel,
+                snapshot,
+                transcript_limit,
+                results.as_ref(),
+                timeouts.as_deref(),
+            )
+            .await
+        };
+        handles.push(tokio::spawn(task.instrument(span)));
+    }
+    let mut outcomes = Vec::with_capacity(handles.len());
+    for handle in handles {
+        outcomes.push(handle.await.expect("test task panicked"));
+    }
+    outcomes
+}
+
+/// The snapshot image for each test that wants one, creating each distinct
+/// one once; `None` for the rest.
+async fn snapshot_all(
+    tests: &[SuiteTest],
+    kernels: &[Result<PathBuf, String>],
+    opts: &SuiteOptions,
+) -> Vec<Option<Result<PathBuf, String>>> {
+    let mut images: HashMap<String, Result<PathBuf, String>> = HashMap::new();
+    let mut out = Vec::with_capacity(tests.len());
+    for (test, kernel) in tests.iter().zip(kernels) {
+        let (Some(_), Ok(kernel)) = (&test.spec.snapshot_at, kernel) else {
+            out.push(None);
+            continue;
+        };
+        let key = match snapshot::key(&test.spec, kernel) {
+            Ok(key) => key,
+            Err(e) => {
+                out.push(Some(Err(format!("{e:#}"))));
+                continue;
+            }
+        };
+        if !images.contains_key(&key) {
+            let image = snapshot::ensure(
+                &test.spec,
+                kernel,
+                &opts.snapshot_dir,
+                opts.transcript_limit,
+            )
+            .await
+            .map_err(|e| format!("{e:#}"));
+            images.insert(key.clone(), image);
+        }
+        out.push(Some(images[&key].clone()));
+    }
+    out
+}
+
+/// Run `test`, rerunning it while it fails, up to its `retries`.
+async fn run_test(
+    test: &SuiteTest,
+    kernel: PathBuf,
+    snapshot: Option<PathBuf>,
+    transcript_limit: usize,
+    results: Option<&Store>,
+    timeouts: Option<&Learned>,
+) -> TestOutcome {
+    let tries = 1 + test.spec.retries.unwrap_or(0);
+    let mut attempt = 1;
+    loop {
+        let mut outcome = run_once(
+            test,
+            &kernel,
+            snapshot.as_deref(),
+            transcript_limit,
+            results,
+            timeouts,
+        )
+        .await;
+        // Tests that could not run are not retried.
+        if outcome.passed || outcome.result.is_none() || attempt >= tries {
+            outcome.attempts = attempt;
+            outcome.flaky = flaky::retry_verdict(attempt, outcome.passed);
+            return outcome;
+        }
+        tracing::warn!(test = %test.name, attempt, "failed; retrying");
+        attempt += 1;
+    }
+}
+
+async fn run_once(
+    test: &SuiteTest,
+    kernel: &Path,
+    snapshot: Option<&Path>,
+    transcript_limit: usize,
+    results: Option<&Store>,
+    timeouts: Option<&Learned>,
+) -> TestOutcome {
+    let kernel = kernel.to_path_buf();
+    tracing::info!(test = %test.name, kernel = %kernel.display(), "running");
+    let mut cfg = match test.spec.run_config(&kernel, transcript_limit) {
+        Ok(cfg) if cfg.gdb.as_ref().is_some_and(|g| g.script.is_none()) => {
+            let error = "interactive `gdb` needs a single run; give a `gdb-script`";
+            return TestOutcome::failed(test, Some(kernel), error.to_string());
+        }
+        Ok(cfg) => cfg,
+        Err(e) => return TestOutcome::failed(test, Some(kernel), format!("{e:#}")),
+    };
+    if let Some(timeouts) = timeouts {
+        timeouts.apply(&test.name, &mut cfg);
+    }
+    let _fork = match snapshot {
+        Some(image) => match snapshot::fork(&mut cfg, image) {
+            Ok(fork) => Some(fork),
+            Err(e) => return TestOutcome::failed(test, Some(kernel), format!("{e:#}")),
+        },
+        None => None,
+    };
+    let run_dir = results.and_then(|store| {
+        let dir = store.create(&test.name, &mut cfg);
+        dir.map_err(|e| tracing::warn!("not storing results: {e:#}"))
+            .ok()
+    });
+    match qemu::run(&cfg).await {
+        Ok(mut result) => {
+            symbolize::annotate(&mut result, test.spec.symbols.as_deref(), &kernel).await;
+            let artifacts = match (results, run_dir) {
+                (Some(store), Some(dir)) => {
+                    if let Err(e) = store.finish(&dir, &kernel, &result) {
+                        tracing::warn!("storing results: {e:#}");
+                    }
+                    Some(dir.path)
+                }
+                _ => None,
+            };
+            TestOutcome {
+                artifacts,
+                matrix: test.matrix,
+                ..TestOutcome::from_result(test.name.clone(), test.path.clone(), kernel, result)
+            }
+        }
+        Err(e) => TestOutcome::failed(test, Some(kernel), format!("{e:#}")),
+    }
+}
+
+/// One row per test plus a totals line.
+pub fn render_table(outcomes: &[TestOutcome]) -> String {
+    let width = outcomes
+        .iter()
+        .map(|o| o.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("TEST".len());
+    let mut out = format!(
+        "{:<width$}  RESULT  {:<15}  {:>8}  TESTS\n",
+        "TEST", "REASON", "TIME"
+    );
+    for o in outcomes {
+        let (reason, time) = match &o.result {
+            Some(r) => (
+                r.reason.name(),
+                format!("{:.2}s", r.duration_ms as f64 / 1000.0),
+            ),
+            None => ("error", "-".to_string()),
+        };
+        let tests = o
+            .summary
+            .as_ref()
+            .map_or("-".to_string(), |s| format!("{}/{}", s.passed, s.total));
+        let result = match (o.passed, &o.flaky, o.baseline) {
+            (true, _, Some(Status::Fixed)) => "FIXED",
+            (true, None, _) => "PASS",
+            (true, Some(_), _) => "FLAKY",
+            (false, _, Some(Status::Known)) => "KNOWN",
+            (false, _, _) => "FAIL",
+        };
+        out.push_str(&format!(
+            "{:<width$}  {result:<6}  {reason:<15}  {time:>8}  {tests}\n",
+            o.name
+        ));
+    }
+    let passed = outcomes.iter().filter(|o| o.passed).count();
+    out.push_str(&format!(
+        "{passed}/{} passed, {} failed",
+        outcomes.len(),
+        outcomes.len() - passed
+    ));
+    match outcomes.iter().filter(|o| o.flaky.is_some()).count() {
+        0 => out.push('\n'),
+        n => out.push_str(&format!(" ({n} flaky)\n")),
+    }
+    out
+}
+
+/// A line per matrix whose configurations disagree, e.g. `locks: passes
+/// with smp1, fails with smp2, smp4`; empty when none do.
+pub fn render_matrix(outcomes: &[TestOutcome]) -> String {
+    let mut matrices: Vec<(&Path, Vec<(&TestOutcome, MatrixEntry)>)> = Vec::new();
+    for o in outcomes {
+        let Some(entry) = o.matrix else {
+            continue;
+        };
+        match matrices.iter_mut().find(|(spec, _)| *spec == o.spec) {
+            Some((_, runs)) => runs.push((o, entry)),
+            None => matrices.push((&o.spec, vec![(o, entry)])),
+        }
+    }
+    let labels = |runs: &[(&TestOutcome, MatrixEntry)], passed: bool| {
+        let labels: Vec<String> = runs
+            .iter()
+            .filter(|(o, _)| o.passed == passed)
+            .map(|(_, entry)| entry.label())
+            .collect();
+        labels.join(", ")
+    };
+    let mut out = String::new();
+    for (_, runs) in matrices {
+        if runs.iter().all(|(o, _)| o.passed) || runs.iter().all(|(o, _)| !o.passed) {
+            continue;
+        }
+        let (first, entry) = runs[0];
+        let suffix = format!("@{}", entry.label());
+        let base = first.name.strip_suffix(&suffix).unwrap_or(&first.name);
+        out.push_str(&format!(
+            "{base}: passes with {}, fails with {}\n",
+            labels(&runs, true),
+            labels(&runs, false)
+        ));
+    }
+    out
+}
+
+/// What went wrong in a failed test, followed by its serial transcript.
+pub fn render_failure(o: &TestOutcome) -> String {
+    let mut out = format!("=== {} ({}) ===\n", o.name, o.spec.display());
+    if o.attempts > 1 {
+        out.push_str(&format!("failed all {} attempts\n", o.attempts));
+    }
+    if let Some(why) = &o.flaky {
+        out.push_str(&format!("flaky: {why}\n"));
+    }
+    if let Some(dir) = &o.artifacts {
+        out.push_str(&format!("artifacts: {}\n", dir.display()));
+    }
+    if let Some(e) = &o.error {
+        out.push_str(e);
+        out.push('\n');
+    }
+    if let Some(summary) = &o.summary {
+        for t in summary.tests.iter().filter(|t| !t.passed) {
+            out.push_str(&format!("FAIL {} {}\n", t.name, t.message));
+        }
+    }
+    if let Some(r) = &o.result {
+        for line in r.explain() {
+            out.push_str(&line);
+            out.push('\n');
+        }
+        out.push_str("--- serial transcript ---\n");
+        if r.transcript_dropped > 0 {
+            out.push_str(&format!(
+                "({} earlier bytes dropped)\n",
+                r.transcript_dropped
+            ));
+        }
+        out.push_str(&r.transcript);
+        if !r.transcript.is_empty() && !r.transcript.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovers_sorted_specs_relative_to_their_directory() {
+        let dir = crate::scratch_path("suite");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("b_smoke.toml"), "kernel = \"auton.iso\"\n").unwrap();
+        std::fs::write(
+            dir.join("a_mm.toml"),
+            "name = \"mm\"\n[build]\nworkspace = \"..\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a spec").unwrap();
+
+        let tests = discover(&dir, None).unwrap();
+        let names: Vec<&str> = tests.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["mm", "b_smoke"]);
+        assert_eq!(
+            tests[0].spec.build.as_ref().unwrap().workspace,
+            dir.join("..")
+        );
+        assert_eq!(
+            tests[1].spec.kernel.as_deref(),
+            Some(dir.join("auton.iso").as_path())
+        );
+        assert_eq!(discover(&dir, Some("smoke")).unwrap().len(), 1);
+        assert!(discover(&dir, Some("nothing")).is_err());
+
+        std::fs::write(dir.join("d_locks.toml"), "cpus = [1, 4]\nsmp = 2\n").unwrap();
+        assert!(discover(&dir, None).is_err());
+        std::fs::write(dir.join("d_locks.toml"), "cpus = [1, 4]\n").unwrap();
+        let tests = discover(&dir, Some("locks")).unwrap();
+        let names: Vec<&str> = tests.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["d_locks@smp1", "d_locks@smp4"]);
+        let entry = tests[1].matrix.unwrap();
+        assert_eq!((entry.smp, entry.label().as_str()), (Some(4), "smp4"));
+        assert_eq!(discover(&dir, Some("@smp4")).unwrap().len(), 1);
+        std::fs::remove_file(dir.join("d_locks.toml")).unwrap();
+
+        std::fs::write(dir.join("c.toml"), "name = \"mm\"\n").unwrap();
+        assert!(discover(&dir, None)
+            .unwrap_err()
+            .to_string()
+            .contains("`mm`"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn points_out_matrices_that_pass_in_some_configurations() {
+        let run = |spec: &str, smp: Option<u32>, memory_mb: Option<u32>, passed: bool| {
+            let entry = MatrixEntry { smp, memory_mb };
+            TestOutcome {
+                name: entry.name(spec),
+                spec: PathBuf::from(format!("tests/{spec}.toml")),
+                kernel: None,
+                matrix: Some(entry),
+                passed,
+                error: None,
+                summary: None,
+                result: None,
+                attempts: 1,
+                flaky: None,
+                artifacts: None,
+                baseline: None,
+            }
+        };
+        let outcomes = [
+            run("locks", Some(1), None, true),
+            run("locks", Some(4), None, false),
+            run("alloc", Some(2), Some(64), false),
+            run("alloc", Some(2), Some(4096), true),
+            run("boot", Some(1), None, true),
+        ];
+        assert_eq!(outcomes[2].name, "alloc@smp2-mem64M");
+        assert_eq!(
+            render_matrix(&outcomes),
+            "locks: passes with smp1, fails with smp4\n\
+             alloc: passes with smp2-mem4G, fails with smp2-mem64M\n"
+        );
+        assert_eq!(render_matrix(&outcomes[4..]), "");
+    }
+
+    #[test]
+    fn matrices_cross_their_axes_and_scale_timeouts() {
+        let spec: TestSpec =
+            auton_toml::from_str("timeout = 10\ncpus = [1, 2]\nmem = [\"64M\", \"2G\"]\n").unwrap();
+        let entries = MatrixEntry::all(&spec);
+        let labels: Vec<String> = entries.iter().map(MatrixEntry::label).collect();
+        assert_eq!(
+            labels,
+            ["smp1-mem64M", "smp1-mem2G", "smp2-mem64M", "smp2-mem2G"]
+        );
+        let mut big = spec.clone();
+        entries[3].apply(&mut big);
+        assert_eq!(
+            (big.smp, big.memory, big.timeout),
+            (Some(2), Some(2048), Some(20))
+        );
+        assert!(big.cpus.is_empty() && big.mem.is_empty());
+        let mut small = spec.clone();
+        entries[0].apply(&mut small);
+        assert_eq!(small.timeout, Some(10));
+        assert!(MatrixEntry::all(&TestSpec::default()).is_empty());
+    }
+
+    #[test]
+    fn picks_iso_then_image_then_kernel_from_manifest() {
+        let both = r#"{"kernel": "b/kernel.elf", "images": ["b/auton.img", "b/auton.iso"]}"#;
+        assert_eq!(image_from_manifest(both).unwrap(), Path::new("b/auton.iso"));
+        let raw = r#"{"kernel": "b/kernel.elf", "images": ["b/auton.img"]}"#;
+        assert_eq!(image_from_manifest(raw).unwrap(), Path::new("b/auton.img"));
+        let elf = r#"{"kernel": "b/kernel.elf"}"#;
+        assert_eq!(image_from_manifest(elf).unwrap(), Path::new("b/kernel.elf"));
+        assert!(image_from_manifest("{}").is_err());
+    }
+
+    #[tokio::test]
+    async fn plan_lists_builds_snapshots_and_boots() {
+        let kernel = crate::scratch_path("elf");
+        std::fs::write(&kernel, b"\x7fELF").unwrap();
+        let snapshots = crate::scratch_path("d");
+        let test = |name: &str, spec: TestSpec| SuiteTest {
+            name: name.into(),
+            path: PathBuf::from(format!("{name}.toml")),
+            spec,
+            matrix: None,
+        };
+        let built = TestSpec {
+            build: Some(BuildTarget {
+                workspace: PathBuf::from("kernels/x86_64"),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let snapshotted = TestSpec {
+            kernel: Some(kernel.clone()),
+            snapshot_at: Some("ready".into()),
+            ..Default::default()
+        };
+        let opts = SuiteOptions {
+            parallel: 1,
+            // Cannot plan its own stages; only its command is listed.
+            kernel_builder: PathBuf::from("/nonexistent/kernel-builder"),
+            build_dir: PathBuf::from("out"),
+            kernel: None,
+            overrides: TestSpec::default(),
+            transcript_limit: 1024,
+            max_memory_mb: None,
+            max_cpus: None,
+            snapshot_dir: snapshots.clone(),
+            results: Some(Store {
+                root: PathBuf::from("results"),
+                keep: 1,
+                artifacts: None,
+            }),
+            timeouts: None,
+        };
+        let tests = vec![
+            test("mm", built.clone()),
+            test("vfs", built),
+            test("net", snapshotted),
+        ];
+        let plan = plan(tests, &opts).await.unwrap();
+
+        let what: Vec<&str> = plan.commands.iter().map(|c| c.what.as_str()).collect();
+        assert_eq!(what[0], "building kernels/x86_64");
+        assert_eq!(&what[1..3], ["running mm", "running vfs"]);
+        assert!(plan.commands[1]
+            .args
+            .contains(&"out/0/kernel.elf".to_string()));
+        assert_eq!(what[3], "creating the snapshot disk");
+        assert!(what[4].ends_with("to the snapshot point"));
+        assert!(what[5].starts_with("running net from a copy of "));
+        assert!(plan.commands[5].args.contains(&"-loadvm".to_string()));
+        assert!(plan
+            .writes
+            .iter()
+            .any(|p| p.starts_with("results") && p.ends_with("serial.log")));
+        assert!(plan.writes.iter().any(|p| p.starts_with(&snapshots)));
+        assert!(!snapshots.exists());
+        std::fs::remove_file(&kernel).unwrap();
+    }
+
+    #[test]
+    fn build_args_default_the_arch_from_the_spec() {
+        let target = BuildTarget {
+            workspace: PathBuf::from("kernels/x86_64"),
+            image_format: Some("iso".into()),
+            args: vec!["--driver".into(), "native".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            build_args(&target, "x86_64", Path::new("out/0")),
+            [
+                "-w",
+                "kernels/x86_64",
+                "-a",
+                "x86_64",
+                "-o",
+                "out/0",
+                "--image-format",
+                "iso",
+                "--driver",
+                "native"
+            ]
+        );
+    }
+}
@@ -0,0 +1,306 @@
+//! Symbolizing crash addresses with the kernel's build symbols.
+//!
+//! Symbols come from `--symbols` (a kernel-builder `symbols.json`, or the
+//! kernel ELF itself) or, failing that, from the `manifest.json` in the
+//! kernel image's directory. Addresses are the classified fault RIP plus
+//! any code addresses on the serial lines around the panic or fault; each
+//! becomes `function+offset`, with `(file:line)` from the ELF's DWARF via
+//! `addr2line` when both are available.
+
+use crate::classify::Source;
+use crate::qemu::{ExitReason, RunResult};
+use anyhow::{Context, Result};
+use kernel_builder::symbols::{self, SymbolTable};
+use serde::Serialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Serial lines from the fault banner on searched for addresses.
+const BACKTRACE_LINES: usize = 32;
+
+/// An address this far past an unsized symbol (an asm label) is assumed
+/// not to belong to it.
+const MAX_UNSIZED_OFFSET: u64 = 0x1000;
+
+/// addr2line binaries to try, in preference order.
+const ADDR2LINE: &[&str] = &["llvm-addr2line", "addr2line"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Frame {
+    pub address: u64,
+    pub function: String,
+    pub offset: u64,
+    /// `file:line`, when DWARF knows it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#x} {}+{:#x}",
+            self.address, self.function, self.offset
+        )?;
+        if let Some(loc) = &self.location {
+            write!(f, " ({loc})")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Symbolizer {
+    table: SymbolTable,
+    /// ELF with DWARF for addr2line.
+    elf: Option<PathBuf>,
+}
+
+impl Symbolizer {
+    /// Load `symbols.json`, or build the table from an ELF.
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        if bytes.starts_with(b"\x7fELF") {
+            let image = kernel_builder::elf::read_file(path)?;
+            let map = std::fs::read_to_string(symbols::map_for(path))
+                .ok()
+                .map(|text| symbols::parse_map(&text));
+            let table = SymbolTable::build(&path.display().to_string(), &image, map.as_ref());
+            return Ok(Self {
+                table,
+                elf: Some(path.to_path_buf()),
+            });
+        }
+        let table = SymbolTable::load(path)?;
+        let elf = Some(PathBuf::from(&table.elf)).filter(|p| p.is_file());
+        Ok(Self { table, elf })
+    }
+
+    /// The symbols a kernel-builder build left next to `kernel`.
+    pub fn discover(kernel: &Path) -> Option<Self> {
+        let dir = kernel.parent()?;
+        let text = std::fs::read_to_string(dir.join("manifest.json")).ok()?;
+        let manifest: serde_json::Value = serde_json::from_str(&text).ok()?;
+        let path = manifest["symbols"]
+            .as_str()
+            .or(manifest["kernel"].as_str())?;
+        Self::load(Path::new(path)).ok()
+    }
+
+    /// `function+offset` for `addr`, if it falls in code.
+    pub fn lookup(&self, addr: u64) -> Option<Frame> {
+        let (sym, offset) = self.table.lookup(addr)?;
+        let code = sym.kind == "func" || sym.section.starts_with(".text");
+        (code && (sym.size > 0 || offset < MAX_UNSIZED_OFFSET)).then(|| Frame {
+            address: addr,
+            function: sym.name.clone(),
+            offset,
+            location: None,
+        })
+    }
+
+    /// Sized functions as (name, start, size).
+    pub fn functions(&self) -> impl Iterator<Item = (&str, u64, u64)> {
+        self.table
+            .symbols
+            .iter()
+            .filter(|s| s.kind == "func" && s.size > 0)
+            .map(|s| (s.name.as_str(), s.address, s.size))
+    }
+
+    /// Frames for the addresses that resolve, in order, with DWARF
+    /// locations filled in where addr2line can.
+    pub async fn frames(&self, addrs: &[u64]) -> Vec<Frame> {
+        let mut frames: Vec<Frame> = addrs.iter().filter_map(|&a| self.lookup(a)).collect();
+        if let (Some(elf), false) = (&self.elf, frames.is_empty()) {
+            let addrs: Vec<u64> = frames.iter().map(|f| f.address).collect();
+            match addr2line(elf, &addrs).await {
+                Ok(locations) => {
+                    for (frame, loc) in frames.iter_mut().zip(locations) {
+                        frame.location = loc;
+                    }
+                }
+                Err(e) => tracing::debug!("no DWARF locations: {e:#}"),
+            }
+        }
+        frames
+    }
+}
+
+/// Symbolize a failed run's crash addresses into `result.backtrace`, using
+/// `symbols` or whatever a build left next to `kernel`. Problems are logged,
+/// never fatal.
+pub async fn annotate(result: &mut RunResult, symbols: Option<&Path>, kernel: &Path) {
+    let addrs = crash_addresses(result);
+    if addrs.is_empty() {
+        return;
+    }
+    let symbolizer = match symbols {
+        Some(path) => match Symbolizer::load(path) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::warn!("cannot symbolize: {e:#}");
+                return;
+            }
+        },
+        None => match Symbolizer::discover(kernel) {
+            Some(s) => s,
+            None => return,
+        },
+    };
+    result.backtrace = symbolizer.frames(&addrs).await;
+}
+
+/// The fault RIP, then addresses on the serial lines of the fault or panic,
+/// without duplicates.
+pub fn crash_addresses(result: &RunResult) -> Vec<u64> {
+    let mut addrs = Vec::new();
+    let mut lines: Vec<&str> = Vec::new();
+    if let Some(f) = &result.failure {
+        addrs.extend(f.rip);
+        if f.source == Source::Serial {
+            lines.extend(
+                result
+                    .transcript
+                    .lines()
+                    .skip(f.line_no.saturating_sub(1))
+                    .take(BACKTRACE_LINES),
+            );
+        }
+    }
+    if let ExitReason::Panic(v) | ExitReason::Forbidden(v) = &result.reason {
+        lines.extend(v.context.iter().map(String::as_str));
+    }
+    for line in lines {
+        addrs.extend(addresses_in(line));
+    }
+    let mut seen = std::collections::HashSet::new();
+    addrs.retain(|a| seen.insert(*a));
+    addrs
+}
+
+/// `0x`-prefixed hex numbers of 4+ digits, and bare 16-digit ones (QEMU's
+/// register format).
+fn addresses_in(line: &str) -> Vec<u64> {
+    line.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter_map(|tok| {
+            let (digits, prefixed) = match tok.strip_prefix("0x") {
+                Some(d) => (d, true),
+                None => (tok, false),
+            };
+            let ok = digits.len() <= 16
+                && (if prefixed {
+                    digits.len() >= 4
+                } else {
+                    digits.len() == 16
+                });
+            ok.then(|| u64::from_str_radix(digits, 16).ok()).flatten()
+        })
+        .collect()
+}
+
+/// One `file:line` per address (`None` where DWARF has nothing). The
+/// addresses go in on stdin, as there may be far too many for arguments.
+pub(crate) async fn addr2line(elf: &Path, addrs: &[u64]) -> Result<Vec<Option<String>>> {
+    let program = ADDR2LINE
+        .iter()
+        .find(|p| which::which(p).is_ok())
+        .context("no addr2line on PATH")?;
+    let mut child = Command::new(program)
+        .arg("-e")
+        .arg(elf)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("running {program}"))?;
+    let mut stdin = child.stdin.take().context("addr2line stdin")?;
+    let input: String = addrs.iter().map(|a| format!("{a:#x}\n")).collect();
+    let feed = tokio::spawn(async move { stdin.write_all(input.as_bytes()).await });
+    let out = child
+        .wait_with_output()
+        .await
+        .with_context(|| format!("running {program}"))?;
+    feed.await?
+        .with_context(|| format!("writing to {program}"))?;
+    if !out.status.success() {
+        anyhow::bail!(
+            "{program} failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(parse_location)
+        .collect())
+}
+
+/// `kernel/main.c:42 (discriminator 1)` → `kernel/main.c:42`; `??:0` and
+/// `??:?` → `None`.
+fn parse_location(line: &str) -> Option<String> {
+    let loc = line.split(" (").next().unwrap_or(line).trim();
+    (!loc.starts_with("??") && !loc.ends_with(":0") && !loc.ends_with(":?"))
+        .then(|| loc.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel_builder::symbols::SymbolEntry;
+
+    fn symbolizer() -> Symbolizer {
+        let entry = |name: &str, address, size, kind: &str, section: &str| SymbolEntry {
+            name: name.into(),
+            address,
+            size,
+            kind: kind.into(),
+            section: section.into(),
+            object: None,
+        };
+        Symbolizer {
+            table: SymbolTable {
+                elf: "kernel.elf".into(),
+                symbols: vec![
+                    entry("_start", 0x100000, 0, "notype", ".text"),
+                    entry("kmain", 0x100100, 0x80, "func", ".text"),
+                    entry("g_ticks", 0x200000, 8, "object", ".bss"),
+                ],
+            },
+            elf: None,
+        }
+    }
+
+    #[test]
+    fn resolves_code_addresses_only() {
+        let s = symbolizer();
+        assert_eq!(
+            s.lookup(0x100110).unwrap().to_string(),
+            "0x100110 kmain+0x10"
+        );
+        assert_eq!(s.lookup(0x100004).unwrap().function, "_start");
+        // Data, and far past an unsized label, are not frames.
+        assert_eq!(s.lookup(0x200000), None);
+        assert_eq!(s.lookup(0x180000), None);
+    }
+
+    #[test]
+    fn finds_addresses_in_dump_lines() {
+        assert_eq!(
+            addresses_in("RIP: 0xffffffff80001234 RSP=ffffffff80200ff0 err=0x2 0x1000"),
+            [0xffffffff80001234, 0xffffffff80200ff0, 0x1000]
+        );
+        assert_eq!(addresses_in("[TEST] ok: PASS 12345"), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn parses_addr2line_output() {
+        assert_eq!(
+            parse_location("/src/kernel/main.c:42 (discriminator 3)").as_deref(),
+            Some("/src/kernel/main.c:42")
+        );
+        assert_eq!(parse_location("??:0"), None);
+        assert_eq!(parse_location("boot.S:?"), None);
+    }
+}
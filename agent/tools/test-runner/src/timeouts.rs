@@ -0,0 +1,162 @@
+//! Timeouts learned from a test's history (`--adaptive-timeout`).
+//!
+//! The history file ([`crate::flaky`]) keeps how long each test's last
+//! attempts ran and under which accelerator. A test with at least
+//! [`MIN_SAMPLES`] timed passes under this run's accelerator gets the 99th
+//! percentile of them times a factor, clamped to a floor and a ceiling, in
+//! place of its spec's timeout; one with fewer keeps its spec's. Failures
+//! do not count: a hang runs until the timeout, so learning from it would
+//! only ever push the timeout up.
+
+use crate::accel::Accel;
+use crate::flaky::History;
+use crate::qemu::RunConfig;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Timed passes a test needs before its timeout is learned.
+pub const MIN_SAMPLES: usize = 3;
+
+/// Multiple of the p99 duration a learned timeout allows.
+pub const DEFAULT_FACTOR: f64 = 3.0;
+
+/// Bounds on a learned timeout, in seconds.
+pub const DEFAULT_FLOOR_SECS: u64 = 10;
+pub const DEFAULT_CEILING_SECS: u64 = 900;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Policy {
+    pub factor: f64,
+    pub floor: Duration,
+    pub ceiling: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            factor: DEFAULT_FACTOR,
+            floor: Duration::from_secs(DEFAULT_FLOOR_SECS),
+            ceiling: Duration::from_secs(DEFAULT_CEILING_SECS),
+        }
+    }
+}
+
+/// The passing durations in a history, by test and accelerator.
+#[derive(Debug, Clone, Default)]
+pub struct Learned {
+    policy: Policy,
+    samples: HashMap<(String, Accel), Vec<u64>>,
+}
+
+impl Learned {
+    pub fn new(history: &History, policy: Policy) -> Self {
+        let mut samples: HashMap<(String, Accel), Vec<u64>> = HashMap::new();
+        for (name, attempts) in &history.tests {
+            for a in attempts.iter().filter(|a| a.passed) {
+                if let (Some(ms), Some(accel)) = (a.duration_ms, a.accel) {
+                    samples.entry((name.clone(), accel)).or_default().push(ms);
+                }
+            }
+        }
+        Self { policy, samples }
+    }
+
+    /// `test`'s timeout under `accel`, if its history has enough passes.
+    pub fn timeout(&self, test: &str, accel: Accel) -> Option<Duration> {
+        let durations = self.samples.get(&(test.to_string(), accel))?;
+        if durations.len() < MIN_SAMPLES {
+            return None;
+        }
+        let p99 = percentile(durations, 99);
+        let learned = Duration::from_secs_f64(p99 as f64 / 1000.0 * self.policy.factor.max(1.0));
+        Some(learned.clamp(
+            self.policy.floor,
+            self.policy.ceiling.max(self.policy.floor),
+        ))
+    }
+
+    /// Give `cfg` the timeout learned for `test`; an interactive debugging
+    /// session keeps its own.
+    pub fn apply(&self, test: &str, cfg: &mut RunConfig) {
+        if cfg.gdb.as_ref().is_some_and(|g| g.script.is_none()) {
+            return;
+        }
+        if let Some(timeout) = self.timeout(test, cfg.accel) {
+            tracing::debug!(test, ?timeout, spec = ?cfg.timeout, "learned timeout");
+            cfg.timeout = timeout;
+        }
+    }
+}
+
+/// The nearest-rank `pct`th percentile of `values`, which is not empty.
+fn percentile(values: &[u64], pct: usize) -> u64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    let rank = (pct * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flaky::Attempt;
+
+    fn timed(passed: bool, ms: u64, accel: Accel) -> Attempt {
+        Attempt {
+            passed,
+            at: 0,
+            duration_ms: Some(ms),
+            accel: Some(accel),
+        }
+    }
+
+    #[test]
+    fn learns_from_passes_under_the_same_accelerator() {
+        let mut history = History::default();
+        history.tests.insert(
+            "boot".into(),
+            vec![
+                timed(true, 4_000, Accel::Kvm),
+                timed(true, 5_000, Accel::Kvm),
+                timed(false, 60_000, Accel::Kvm),
+                timed(true, 30_000, Accel::Tcg),
+            ],
+        );
+        let learned = Learned::new(&history, Policy::default());
+        // Two passes are not enough, and the timeout does not count.
+        assert_eq!(learned.timeout("boot", Accel::Kvm), None);
+        history
+            .tests
+            .get_mut("boot")
+            .unwrap()
+            .push(timed(true, 6_000, Accel::Kvm));
+        let learned = Learned::new(&history, Policy::default());
+        assert_eq!(
+            learned.timeout("boot", Accel::Kvm),
+            Some(Duration::from_secs(18))
+        );
+        assert_eq!(learned.timeout("boot", Accel::Tcg), None);
+        assert_eq!(learned.timeout("net", Accel::Kvm), None);
+    }
+
+    #[test]
+    fn learned_timeouts_are_clamped() {
+        let mut history = History::default();
+        let fast = vec![timed(true, 100, Accel::Kvm); 3];
+        let slow = vec![timed(true, 400_000, Accel::Kvm); 3];
+        history.tests.insert("fast".into(), fast);
+        history.tests.insert("slow".into(), slow);
+        let learned = Learned::new(&history, Policy::default());
+        assert_eq!(
+            learned.timeout("fast", Accel::Kvm),
+            Some(Duration::from_secs(DEFAULT_FLOOR_SECS))
+        );
+        assert_eq!(
+            learned.timeout("slow", Accel::Kvm),
+            Some(Duration::from_secs(DEFAULT_CEILING_SECS))
+        );
+        let values: Vec<u64> = (1..=200).collect();
+        assert_eq!(percentile(&values, 99), 198);
+        assert_eq!(percentile(&[7], 99), 7);
+    }
+}
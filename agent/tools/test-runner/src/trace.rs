@@ -0,0 +1,378 @@
+//! Interrupt/MMIO trace capture (`--trace int,mmio,pic`).
+//!
+//! Each event class turns on QEMU `-d` items logged to their own file
+//! (`trace.log` in the run's results directory, else a temp file) that is
+//! kept after the run:
+//!
+//! * `int`: every interrupt and exception taken (`-d int`);
+//! * `mmio`: accesses QEMU rejects or routes to unimplemented devices
+//!   (`-d guest_errors,unimp`) and every MMIO read/write
+//!   (`trace:memory_region_ops_*`);
+//! * `pic`: the i8259, local APIC and I/O APIC trace events.
+//!
+//! The log is then summarized: exceptions by vector, hardware and software
+//! interrupts, spurious interrupts (the conventional APIC spurious vector
+//! `0xff`, and PIC IRQ 7/15 acknowledged without being raised), IRQ lines
+//! raised, MMIO accesses by region, and the unexpected MMIO accesses.
+//! Interrupt entries are parsed in QEMU's x86 `-d int` format.
+
+use crate::classify;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+/// Distinct unexpected MMIO messages kept in a summary.
+const MAX_UNEXPECTED: usize = 20;
+
+/// APIC spurious-interrupt vector most kernels program.
+const APIC_SPURIOUS_VECTOR: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TraceEvent {
+    Int,
+    Mmio,
+    Pic,
+}
+
+impl TraceEvent {
+    fn log_items(self) -> &'static [&'static str] {
+        match self {
+            Self::Int => &["int"],
+            Self::Mmio => &[
+                "guest_errors",
+                "unimp",
+                "trace:memory_region_ops_read",
+                "trace:memory_region_ops_write",
+            ],
+            Self::Pic => &["trace:pic_*", "trace:apic_*", "trace:ioapic_*"],
+        }
+    }
+}
+
+/// QEMU flags logging `also` (the `--cpu-log` or coverage items) and
+/// `events` to `log`. QEMU honours only the last `-d`, so all share it.
+pub fn qemu_args(log: &Path, events: &[TraceEvent], also: &[&str]) -> Vec<String> {
+    let mut items: Vec<&str> = also.to_vec();
+    for e in events {
+        items.extend(e.log_items());
+    }
+    let mut seen = BTreeSet::new();
+    items.retain(|i| seen.insert(*i));
+    vec![
+        "-d".to_string(),
+        items.join(","),
+        "-D".to_string(),
+        log.display().to_string(),
+    ]
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RegionAccesses {
+    pub reads: u64,
+    pub writes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Unexpected {
+    pub message: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TraceSummary {
+    /// The kept trace log.
+    pub log: PathBuf,
+    /// CPU exceptions (vectors below 32) by vector.
+    pub exceptions: BTreeMap<u8, u64>,
+    /// Hardware interrupts by vector.
+    pub interrupts: BTreeMap<u8, u64>,
+    /// `int n` instructions by vector.
+    pub software_interrupts: BTreeMap<u8, u64>,
+    pub spurious: u64,
+    /// PIC IRQ lines raised, by IRQ (0-15).
+    pub irqs_raised: BTreeMap<u8, u64>,
+    /// MMIO reads and writes by memory region.
+    pub mmio: BTreeMap<String, RegionAccesses>,
+    /// Invalid or unimplemented-device accesses, most frequent first.
+    pub unexpected_mmio: Vec<Unexpected>,
+    /// Every trace event seen, by name.
+    pub events: BTreeMap<String, u64>,
+}
+
+impl TraceSummary {
+    /// Summarize the trace log at `path`, streaming it (interrupt logs
+    /// grow quickly).
+    pub fn from_file(path: &Path) -> std::io::Result<Self> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let mut s = Summarizer::default();
+        for line in file.split(b'\n') {
+            s.line(&String::from_utf8_lossy(&line?));
+        }
+        Ok(s.finish(path))
+    }
+
+    /// The summary, one finding per line.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!("trace: {}", self.log.display())];
+        let vectors = |m: &BTreeMap<u8, u64>, name: fn(u8) -> String| {
+            m.iter()
+                .map(|(v, n)| format!("{} x{n}", name(*v)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let hex = |v: u8| format!("{v:#04x}");
+        if !self.exceptions.is_empty() {
+            let named = |v: u8| match classify::exception(v) {
+                Some(e) => format!("{} ({v:#04x})", e.mnemonic),
+                None => format!("{v:#04x}"),
+            };
+            lines.push(format!(
+                "  exceptions: {}",
+                vectors(&self.exceptions, named)
+            ));
+        }
+        if !self.interrupts.is_empty() {
+            lines.push(format!("  interrupts: {}", vectors(&self.interrupts, hex)));
+        }
+        if !self.software_interrupts.is_empty() {
+            lines.push(format!(
+                "  software interrupts: {}",
+                vectors(&self.software_interrupts, hex)
+            ));
+        }
+        if self.spurious > 0 {
+            lines.push(format!("  spurious interrupts: {}", self.spurious));
+        }
+        if !self.irqs_raised.is_empty() {
+            let irq = |v: u8| format!("IRQ{v}");
+            lines.push(format!(
+                "  IRQs raised: {}",
+                vectors(&self.irqs_raised, irq)
+            ));
+        }
+        if !self.mmio.is_empty() {
+            let regions: Vec<String> = self
+                .mmio
+                .iter()
+                .map(|(r, a)| format!("{r} {}r/{}w", a.reads, a.writes))
+                .collect();
+            lines.push(format!("  MMIO: {}", regions.join(", ")));
+        }
+        if !self.unexpected_mmio.is_empty() {
+            lines.push("  unexpected MMIO:".to_string());
+            lines.extend(
+                self.unexpected_mmio
+                    .iter()
+                    .map(|u| format!("    {} x{}", u.message, u.count)),
+            );
+        }
+        lines
+    }
+}
+
+#[derive(Default)]
+struct Summarizer {
+    summary: TraceSummary,
+    /// PIC IRQs raised and not yet acknowledged.
+    pending: BTreeSet<u8>,
+    /// Unexpected-access messages and counts, in first-seen order.
+    unexpected: Vec<Unexpected>,
+}
+
+impl Summarizer {
+    fn line(&mut self, line: &str) {
+        let s = &mut self.summary;
+        if let Some((vector, fields)) = classify::interrupt_entry(line) {
+            let software = fields.get("i").is_some_and(|&i| i != 0);
+            let by_vector = if software {
+                &mut s.software_interrupts
+            } else if vector < 32 {
+                &mut s.exceptions
+            } else {
+                &mut s.interrupts
+            };
+            *by_vector.entry(vector).or_default() += 1;
+            if !software && vector == APIC_SPURIOUS_VECTOR {
+                s.spurious += 1;
+            }
+            return;
+        }
+        if line.contains("Invalid access at addr")
+            || line.contains("Invalid read at addr")
+            || line.contains("Invalid write at addr")
+            || line.contains("unimplemented device")
+        {
+            self.unexpected_mmio(line.trim());
+            return;
+        }
+        let event = strip_timestamp(line);
+        let Some((name, args)) = event.split_once(' ') else {
+            return;
+        };
+        if !is_event_name(name) {
+            return;
+        }
+        *s.events.entry(name.to_string()).or_default() += 1;
+        let field = |key: &str| {
+            let mut tokens = args.split_whitespace();
+            tokens.find(|t| *t == key)?;
+            tokens.next()
+        };
+        match name {
+            "memory_region_ops_read" | "memory_region_ops_write" => {
+                let region = match args.split_once("name ") {
+                    Some((_, n)) => n.trim().trim_matches('\'').to_string(),
+                    // Older QEMUs log no region name: group by page.
+                    None => field("addr")
+                        .and_then(parse_u64)
+                        .map_or("?".to_string(), |a| format!("{:#x}", a & !0xfff)),
+                };
+                let acc = s.mmio.entry(region).or_default();
+                if name.ends_with("read") {
+                    acc.reads += 1;
+                } else {
+                    acc.writes += 1;
+                }
+            }
+            // `pic_set_irq master 1 irq 0 level 1`: irq is per chip.
+            "pic_set_irq" => {
+                let master = field("master") == Some("1");
+                let irq = field("irq").and_then(|v| v.parse::<u8>().ok());
+                if let (Some(irq), Some("1")) = (irq, field("level")) {
+                    let irq = if master { irq } else { irq + 8 };
+                    *s.irqs_raised.entry(irq).or_default() += 1;
+                    self.pending.insert(irq);
+                }
+            }
+            // `pic_interrupt irq 7 intno 39`: the acknowledge, irq 0-15.
+            "pic_interrupt" => {
+                if let Some(irq) = field("irq").and_then(|v| v.parse::<u8>().ok()) {
+                    if !self.pending.remove(&irq) && (irq == 7 || irq == 15) {
+                        s.spurious += 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn unexpected_mmio(&mut self, message: &str) {
+        match self.unexpected.iter_mut().find(|u| u.message == message) {
+            Some(u) => u.count += 1,
+            None => self.unexpected.push(Unexpected {
+                message: message.to_string(),
+                count: 1,
+            }),
+        }
+    }
+
+    fn finish(mut self, log: &Path) -> TraceSummary {
+        // Stable: equally frequent messages stay in first-seen order.
+        self.unexpected.sort_by_key(|u| std::cmp::Reverse(u.count));
+        self.unexpected.truncate(MAX_UNEXPECTED);
+        TraceSummary {
+            log: log.to_path_buf(),
+            unexpected_mmio: self.unexpected,
+            ..self.summary
+        }
+    }
+}
+
+/// The event part of a `-d trace:` line, without the `pid@secs.usecs:`
+/// prefix QEMU adds.
+fn strip_timestamp(line: &str) -> &str {
+    match line.split_once(':') {
+        Some((prefix, rest))
+            if prefix.contains('@')
+                && prefix
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || "@.".contains(c)) =>
+        {
+            rest
+        }
+        _ => line,
+    }
+}
+
+fn is_event_name(name: &str) -> bool {
+    name.contains('_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn parse_u64(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = "\
+     0: v=20 e=0000 i=0 cpl=0 IP=0008:ffffffff80100000 pc=ffffffff80100000 SP=0010:ffffffff80200f00 env->regs[R_EAX]=0
+RAX=0000000000000000 RBX=0000000000000000
+     1: v=0e e=0002 i=0 cpl=0 IP=0008:ffffffff80100123 pc=ffffffff80100123 SP=0010:ffffffff80200f00 CR2=00000000deadbeef
+     2: v=80 e=0000 i=1 cpl=3 IP=001b:0000000000401000 pc=0000000000401000 SP=0023:00007ffffffff000 env->regs[R_EAX]=1
+     3: v=ff e=0000 i=0 cpl=0 IP=0008:ffffffff80100000 pc=ffffffff80100000 SP=0010:ffffffff80200f00 env->regs[R_EAX]=0
+12@1700000000.000001:pic_set_irq master 1 irq 0 level 1
+12@1700000000.000002:pic_interrupt irq 0 intno 32
+pic_interrupt irq 7 intno 39
+memory_region_ops_read cpu 0 mr 0x5555 addr 0xfee00030 value 0x50014 size 4 name 'apic-msi'
+memory_region_ops_write cpu 0 mr 0x5555 addr 0xfee000b0 value 0x0 size 4 name 'apic-msi'
+memory_region_ops_write cpu 0 mr 0x5556 addr 0xfec00000 value 0x1 size 4
+Invalid access at addr 0xFED40000, size 4, region '(null)', reason: rejected
+Invalid access at addr 0xFED40000, size 4, region '(null)', reason: rejected
+";
+
+    #[test]
+    fn summarizes_vectors_irqs_and_mmio() {
+        let mut s = Summarizer::default();
+        LOG.lines().for_each(|l| s.line(l));
+        let t = s.finish(Path::new("trace.log"));
+        assert_eq!(t.exceptions, BTreeMap::from([(0x0e, 1)]));
+        assert_eq!(t.interrupts, BTreeMap::from([(0x20, 1), (0xff, 1)]));
+        assert_eq!(t.software_interrupts, BTreeMap::from([(0x80, 1)]));
+        // The spurious vector, and IRQ 7 acknowledged without being raised.
+        assert_eq!(t.spurious, 2);
+        assert_eq!(t.irqs_raised, BTreeMap::from([(0, 1)]));
+        assert_eq!(
+            t.mmio["apic-msi"],
+            RegionAccesses {
+                reads: 1,
+                writes: 1
+            }
+        );
+        assert_eq!(t.mmio["0xfec00000"].writes, 1);
+        assert_eq!(t.unexpected_mmio.len(), 1);
+        assert_eq!(t.unexpected_mmio[0].count, 2);
+        assert_eq!(t.events["pic_interrupt"], 2);
+        let lines = t.lines();
+        assert_eq!(lines[1], "  exceptions: #PF (0x0e) x1");
+        assert!(lines.contains(&"  MMIO: 0xfec00000 0r/1w, apic-msi 1r/1w".to_string()));
+    }
+
+    #[test]
+    fn shares_one_log_with_the_cpu_log() {
+        let args = qemu_args(
+            Path::new("t.log"),
+            &[TraceEvent::Int, TraceEvent::Pic],
+            &crate::qemu::CPU_LOG_ITEMS,
+        );
+        assert_eq!(
+            args,
+            [
+                "-d",
+                "int,cpu_reset,trace:pic_*,trace:apic_*,trace:ioapic_*",
+                "-D",
+                "t.log"
+            ]
+        );
+    }
+}
@@ -0,0 +1,28 @@
+//! Helpers shared by the integration tests.
+
+// Each test file uses some of these.
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use std::time::Duration;
+use test_runner::qemu::RunConfig;
+
+/// An empty scratch directory for the test `name`.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("test-runner-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A run of `script` under `sh` in place of QEMU, with the default
+/// patterns and a 30 s timeout.
+pub fn fake_qemu(script: &str) -> RunConfig {
+    RunConfig {
+        program: "sh".into(),
+        args: vec!["-c".into(), script.into()],
+        timeout: Duration::from_secs(30),
+        transcript_limit: 4096,
+        ..Default::default()
+    }
+}
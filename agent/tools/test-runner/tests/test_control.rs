@@ -0,0 +1,100 @@
+//! Integration tests for the `[control]` channel. A shell script stands in
+//! for the kernel's console and a Unix socket for QEMU's end of the
+//! virtio-serial port, behind which a fake test stub speaks the protocol.
+
+mod common;
+
+use common::fake_qemu;
+use std::path::Path;
+use std::time::Duration;
+use test_runner::control::{kind, CaseStatus, Control, ControlSpec, Frame, VERSION};
+use test_runner::qemu::{run, ExitReason};
+use tokio::net::UnixListener;
+
+/// A stub at `socket` with tests `alloc` (passes, logging), `map` (fails),
+/// `disk` (skipped) and `hang` (never answers).
+fn fake_stub(socket: &Path) {
+    let listener = UnixListener::bind(socket).unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        Frame::new(kind::HELLO, VERSION)
+            .write(&mut stream)
+            .await
+            .unwrap();
+        while let Ok(frame) = Frame::read(&mut stream).await {
+            let replies = match (frame.kind, frame.text().as_str()) {
+                (kind::LIST, _) => vec![
+                    Frame::new(kind::TEST, "alloc"),
+                    Frame::new(kind::TEST, "map"),
+                    Frame::new(kind::TEST, "disk"),
+                    Frame::new(kind::LISTED, Vec::new()),
+                ],
+                (kind::RUN, "alloc") => vec![
+                    Frame::new(kind::LOG, "4 frames"),
+                    Frame::new(kind::PASS, "alloc"),
+                ],
+                (kind::RUN, "map") => vec![Frame::new(kind::FAIL, "map\nnull page")],
+                (kind::RUN, "disk") => vec![Frame::new(kind::SKIP, "disk\nno disk")],
+                (kind::RUN, "hang") => Vec::new(),
+                (kind::RUN, name) => vec![Frame::new(kind::FAIL, format!("{name}\nno such test"))],
+                _ => Vec::new(),
+            };
+            for reply in replies {
+                reply.write(&mut stream).await.unwrap();
+            }
+        }
+    });
+}
+
+const KERNEL: &str = "echo '[BOOT] OK'; sleep 10";
+
+#[tokio::test]
+async fn listed_tests_are_run_and_reported() {
+    let control = Control::new(&ControlSpec::default());
+    fake_stub(&control.socket);
+    let socket = control.socket.clone();
+    let mut cfg = fake_qemu(KERNEL);
+    cfg.control = Some(control);
+    let result = run(&cfg).await.unwrap();
+    let _ = std::fs::remove_file(socket);
+    assert_eq!(result.reason, ExitReason::PatternMatched);
+    let cases = &result.control.as_ref().unwrap().cases;
+    let statuses: Vec<_> = cases.iter().map(|c| (c.name.as_str(), c.status)).collect();
+    assert_eq!(
+        statuses,
+        [
+            ("alloc", CaseStatus::Pass),
+            ("map", CaseStatus::Fail),
+            ("disk", CaseStatus::Skip)
+        ]
+    );
+    assert_eq!(cases[0].log, ["4 frames"]);
+    let summary = result.summary();
+    assert_eq!((summary.total, summary.passed, summary.failed), (3, 2, 1));
+    assert_eq!(summary.tests[1].message, "null page");
+    assert!(!result.passed(&summary));
+}
+
+#[tokio::test]
+async fn a_hung_test_times_out_and_stops_the_rest() {
+    let mut control = Control::new(&ControlSpec {
+        tests: vec!["alloc".into(), "hang".into(), "disk".into()],
+        test_timeout: None,
+    });
+    control.test_timeout = Duration::from_millis(300);
+    fake_stub(&control.socket);
+    let socket = control.socket.clone();
+    let mut cfg = fake_qemu(KERNEL);
+    cfg.control = Some(control);
+    let result = run(&cfg).await.unwrap();
+    let _ = std::fs::remove_file(socket);
+    assert_eq!(result.reason, ExitReason::PatternMatched);
+    assert!(!result.passed(&result.summary()));
+    assert_eq!(
+        result.explain(),
+        [
+            "in-guest test hang: timeout - no verdict within 300ms",
+            "in-guest test disk: not-run - after hang timed out"
+        ]
+    );
+}
@@ -0,0 +1,88 @@
+//! Integration tests for fault injection. A shell script stands in for the
+//! kernel and a Unix socket for QEMU's QMP monitor, which records what it
+//! is asked to do.
+
+mod common;
+
+use common::{fake_qemu, scratch_dir};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use test_runner::inject::{Action, InjectSpec, Injection};
+use test_runner::qemu::{run, ExitReason};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixListener;
+
+/// A QMP monitor at a fresh socket that accepts every command, and the
+/// commands it was sent.
+fn fake_qmp(name: &str) -> (PathBuf, Arc<Mutex<Vec<Value>>>) {
+    let path = scratch_dir(name).join("qmp.sock");
+    let listener = UnixListener::bind(&path).unwrap();
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    let seen = commands.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let seen = seen.clone();
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let greeting = json!({ "QMP": { "version": {} } }).to_string() + "\n";
+                write.write_all(greeting.as_bytes()).await.unwrap();
+                let mut lines = BufReader::new(read).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    seen.lock()
+                        .unwrap()
+                        .push(serde_json::from_str(&line).unwrap());
+                    if write.write_all(b"{\"return\": {}}\n").await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    (path, commands)
+}
+
+fn link_down_on(when: &str) -> Injection {
+    let spec = InjectSpec {
+        action: Some(Action::LinkDown),
+        when: Some(when.into()),
+        delay: None,
+    };
+    Injection::new(&spec, false, true).unwrap()
+}
+
+#[tokio::test]
+async fn faults_are_injected_after_their_line() {
+    let (socket, commands) = fake_qmp("link");
+    let mut cfg = fake_qemu("echo booting; echo 'virtio-net: link up'; sleep 1; echo '[BOOT] OK'");
+    cfg.qmp_socket = Some(socket.clone());
+    cfg.inject = vec![link_down_on("link up")];
+    let result = run(&cfg).await.unwrap();
+    assert_eq!(result.reason, ExitReason::PatternMatched);
+    let [injected] = &result.injected[..] else {
+        panic!("expected one injection, got {:?}", result.injected);
+    };
+    assert_eq!(injected.action, Action::LinkDown);
+    assert_eq!((injected.after_line, injected.error.as_deref()), (2, None));
+    assert!(injected.at_ms < 1000, "{injected:?}");
+    let sent = commands.lock().unwrap().clone();
+    let set_link = sent.iter().find(|c| c["execute"] == "set_link").unwrap();
+    assert_eq!(
+        set_link["arguments"],
+        json!({ "name": "net0", "up": false })
+    );
+    std::fs::remove_dir_all(socket.parent().unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn faults_without_a_monitor_are_reported() {
+    let mut cfg = fake_qemu("echo 'virtio-net: link up'; sleep 0.5; echo '[BOOT] OK'");
+    cfg.inject = vec![link_down_on("link up"), link_down_on("never printed")];
+    let result = run(&cfg).await.unwrap();
+    assert_eq!(result.injected.len(), 1);
+    assert_eq!(result.injected[0].error.as_deref(), Some("no QMP socket"));
+    assert!(result
+        .explain()
+        .iter()
+        .any(|l| l.starts_with("injected link-down at ") && l.ends_with("failed: no QMP socket")));
+}
@@ -0,0 +1,181 @@
+//! Integration tests for `[[packet]]` exchanges. A shell script stands in
+//! for the kernel's serial output and a UDP socket for QEMU's end of the
+//! link, answering frames the way a guest network stack would.
+
+mod common;
+
+use common::fake_qemu;
+use std::time::Duration;
+use test_runner::packets::{
+    Harness, Packet, PacketSpec, Probe, ARP_REPLY, ARP_REQUEST, DEFAULT_GUEST_IP, GUEST_MAC,
+    HOST_IP, HOST_MAC, ICMP_ECHO_REPLY, ICMP_ECHO_REQUEST,
+};
+use test_runner::parse_serial;
+use test_runner::qemu::{run, ExitReason};
+use tokio::net::UdpSocket;
+
+/// A guest stack on `harness`'s link that answers ARP and pings, and
+/// echoes UDP to port 7 after resolving the host's address itself.
+async fn fake_guest(harness: &Harness) -> tokio::task::JoinHandle<()> {
+    let socket = UdpSocket::bind(("127.0.0.1", harness.qemu_port))
+        .await
+        .unwrap();
+    socket
+        .connect(("127.0.0.1", harness.host_port))
+        .await
+        .unwrap();
+    let guest = harness.guest_ip;
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 2048];
+        let mut host_mac = None;
+        let mut pending = None;
+        loop {
+            let n = socket.recv(&mut buf).await.unwrap();
+            let reply = match Packet::parse(&buf[..n]) {
+                Some(Packet::Arp {
+                    op: ARP_REQUEST,
+                    sender_mac,
+                    sender_ip,
+                    target_ip,
+                }) if target_ip == guest => Some((
+                    Packet::Arp {
+                        op: ARP_REPLY,
+                        sender_mac: GUEST_MAC,
+                        sender_ip: guest,
+                        target_ip: sender_ip,
+                    },
+                    sender_mac,
+                )),
+                Some(Packet::Arp {
+                    op: ARP_REPLY,
+                    sender_mac,
+                    ..
+                }) => {
+                    host_mac = Some(sender_mac);
+                    pending.take().map(|p| (p, sender_mac))
+                }
+                Some(Packet::Icmp {
+                    src,
+                    kind: ICMP_ECHO_REQUEST,
+                    id,
+                    seq,
+                    payload,
+                    ..
+                }) => Some((
+                    Packet::Icmp {
+                        src: guest,
+                        dst: src,
+                        kind: ICMP_ECHO_REPLY,
+                        id,
+                        seq,
+                        payload,
+                    },
+                    HOST_MAC,
+                )),
+                Some(Packet::Udp {
+                    src,
+                    src_port,
+                    dst_port: 7,
+                    payload,
+                    ..
+                }) => {
+                    let echo = Packet::Udp {
+                        src: guest,
+                        dst: src,
+                        src_port: 7,
+                        dst_port: src_port,
+                        payload,
+                    };
+                    match host_mac {
+                        Some(mac) => Some((echo, mac)),
+                        None => {
+                            pending = Some(echo);
+                            Some((
+                                Packet::Arp {
+                                    op: ARP_REQUEST,
+                                    sender_mac: GUEST_MAC,
+                                    sender_ip: guest,
+                                    target_ip: HOST_IP,
+                                },
+                                [0xff; 6],
+                            ))
+                        }
+                    }
+                }
+                _ => None,
+            };
+            if let Some((packet, dst)) = reply {
+                let _ = socket.send(&packet.frame(GUEST_MAC, dst)).await;
+            }
+        }
+    })
+}
+
+fn packet(send: Probe, port: Option<u16>, reply: Option<&str>) -> PacketSpec {
+    PacketSpec {
+        when: Some(r"\[NET\] up".into()),
+        send: Some(send),
+        port,
+        payload: port.map(|_| "hello".into()),
+        reply: reply.map(String::from),
+        timeout: Some(2),
+        ..Default::default()
+    }
+}
+
+const KERNEL: &str = "echo '[NET] up'; echo '[BOOT] OK'; sleep 10";
+
+#[tokio::test]
+async fn exchanges_are_answered_before_the_run_completes() {
+    let specs = [
+        packet(Probe::Arp, None, None),
+        packet(Probe::Ping, None, None),
+        packet(Probe::Udp, Some(7), Some("hello")),
+    ];
+    let harness = Harness::new(&specs, None).unwrap();
+    let guest = fake_guest(&harness).await;
+    let mut cfg = fake_qemu(KERNEL);
+    cfg.packets = Some(harness);
+    let result = run(&cfg).await.unwrap();
+    guest.abort();
+    assert_eq!(result.reason, ExitReason::PatternMatched);
+    let outcomes: Vec<_> = result
+        .packets
+        .iter()
+        .map(|p| (p.exchange.as_str(), p.ok))
+        .collect();
+    assert_eq!(
+        outcomes,
+        [("arp", true), ("ping", true), ("udp to port 7", true)]
+    );
+    assert!(result.packets[0]
+        .detail
+        .starts_with(&format!("{DEFAULT_GUEST_IP} is at 52:54:00:12:34:56")));
+    assert!(result.passed(&parse_serial(&result.transcript)));
+}
+
+#[tokio::test]
+async fn a_missing_reply_fails_the_run() {
+    let specs = [
+        packet(Probe::Udp, Some(9), Some("hello")),
+        PacketSpec {
+            when: Some("never printed".into()),
+            ..packet(Probe::Ping, None, None)
+        },
+    ];
+    let mut harness = Harness::new(&specs, None).unwrap();
+    harness.exchanges[0].timeout = Duration::from_millis(500);
+    let guest = fake_guest(&harness).await;
+    let mut cfg = fake_qemu("echo '[NET] up'; echo '[BOOT] OK'; sleep 2");
+    cfg.packets = Some(harness);
+    let result = run(&cfg).await.unwrap();
+    guest.abort();
+    assert!(!result.passed(&parse_serial(&result.transcript)));
+    assert_eq!(
+        result.explain()[1..],
+        [
+            "packet udp to port 9: no reply within 500ms",
+            "packet ping: never sent: its `when` did not match"
+        ]
+    );
+}
@@ -0,0 +1,148 @@
+//! Integration tests for running QEMU over SSH. Stand-in `ssh` and `scp`
+//! scripts run the "remote" side locally, and a shell script stands in for
+//! QEMU.
+
+mod common;
+
+use common::scratch_dir;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use test_runner::accel::Accel;
+use test_runner::qemu::{run, ExitReason, RunConfig};
+use test_runner::remote::Remote;
+
+/// Runs the command in its own session, as sshd would, so killing this
+/// script leaves it running.
+const SSH: &str = r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -o|-L) shift ;;
+        -*) ;;
+        *) break ;;
+    esac
+    shift
+done
+shift
+setsid sh -c "$*" &
+wait $!
+"#;
+
+const SCP: &str = r#"#!/bin/sh
+while [ $# -gt 2 ]; do shift; done
+cp "${1#*:}" "${2#*:}"
+"#;
+
+/// Prints where it runs and its kernel, writes its `-D` log, and either
+/// boots or (with `--hang PIDFILE`) hangs.
+const QEMU: &str = r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -kernel) kernel=$2; shift ;;
+        -D) log=$2; shift ;;
+        --hang) hang=$2; shift ;;
+    esac
+    shift
+done
+echo "cwd $(pwd)"
+echo "kernel $kernel"
+cat "$kernel"
+echo traced > "$log"
+if [ -n "$hang" ]; then
+    echo $$ > "$hang"
+    exec sleep 60
+fi
+echo '[BOOT] OK'
+"#;
+
+fn script(path: &Path, text: &str) {
+    std::fs::write(path, text).unwrap();
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// A scratch directory with the stand-ins and a kernel, and a config that
+/// boots it remotely.
+fn setup(name: &str) -> (PathBuf, RunConfig) {
+    let dir = scratch_dir(name);
+    script(&dir.join("ssh"), SSH);
+    script(&dir.join("scp"), SCP);
+    script(&dir.join("qemu"), QEMU);
+    std::fs::write(dir.join("k.elf"), "KERNEL BYTES\n").unwrap();
+    let trace = dir.join("trace.log");
+    let args = vec![
+        "-kernel".to_string(),
+        dir.join("k.elf").display().to_string(),
+        "-D".to_string(),
+        trace.display().to_string(),
+    ];
+    let cfg = RunConfig {
+        program: dir.join("qemu").display().to_string(),
+        args,
+        accel: Accel::Auto,
+        timeout: Duration::from_secs(30),
+        transcript_limit: 4096,
+        trace: Some(trace),
+        remote: Some(Remote {
+            host: "kvm-host".into(),
+            ssh: dir.join("ssh").display().to_string(),
+            scp: dir.join("scp").display().to_string(),
+        }),
+        ..Default::default()
+    };
+    (dir, cfg)
+}
+
+fn running(pid: &str) -> bool {
+    std::process::Command::new("kill")
+        .args(["-0", pid])
+        .stderr(std::process::Stdio::null())
+        .status()
+        .unwrap()
+        .success()
+}
+
+#[tokio::test]
+async fn boots_remotely_and_fetches_what_qemu_wrote() {
+    let (dir, cfg) = setup("remote");
+    let result = run(&cfg).await.unwrap();
+    assert_eq!(result.reason, ExitReason::PatternMatched);
+
+    let cwd = result
+        .transcript
+        .lines()
+        .find_map(|l| l.strip_prefix("cwd "))
+        .unwrap();
+    // The kernel was copied into QEMU's directory and streamed back.
+    assert!(result
+        .transcript
+        .contains(&format!("kernel {cwd}/in0-k.elf")));
+    assert!(result.transcript.contains("KERNEL BYTES"));
+    assert_eq!(
+        std::fs::read_to_string(dir.join("trace.log")).unwrap(),
+        "traced\n"
+    );
+    assert!(!Path::new(cwd).exists(), "{cwd} left behind");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_cancelled_run_reaps_remote_qemu() {
+    let (dir, mut cfg) = setup("remote-cancel");
+    let pidfile = dir.join("qemu.pid");
+    cfg.args
+        .extend(["--hang".to_string(), pidfile.display().to_string()]);
+    let cancelled = tokio::time::timeout(Duration::from_secs(2), run(&cfg)).await;
+    assert!(cancelled.is_err(), "the run ended by itself");
+
+    let pid = std::fs::read_to_string(&pidfile).unwrap();
+    let pid = pid.trim();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while running(pid) {
+        assert!(
+            Instant::now() < deadline,
+            "remote QEMU {pid} outlived its run"
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,115 @@
+//! Integration tests for screen assertions. A shell script stands in for
+//! the kernel and a Unix socket for QEMU's QMP monitor, whose `screendump`
+//! writes a fixed image.
+
+mod common;
+
+use common::{fake_qemu, scratch_dir};
+use std::path::{Path, PathBuf};
+use test_runner::parse_serial;
+use test_runner::qemu::{run, ExitReason};
+use test_runner::screen::{Image, Screen, ScreenResult, ScreenSpec};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixListener;
+
+/// A 2x1 image.
+fn image(left: [u8; 3], right: [u8; 3]) -> Image {
+    Image {
+        width: 2,
+        height: 1,
+        rgb: [left, right].concat(),
+    }
+}
+
+/// A QMP monitor at a fresh socket whose display shows `screen`.
+fn fake_qmp(dir: &Path, screen: Image) -> PathBuf {
+    let path = dir.join("qmp");
+    let listener = UnixListener::bind(&path).unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let screen = screen.clone();
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                write
+                    .write_all(b"{\"QMP\": {\"version\": {}}}\n")
+                    .await
+                    .unwrap();
+                let mut lines = BufReader::new(read).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let req: serde_json::Value = serde_json::from_str(&line).unwrap();
+                    if req["execute"] == "screendump" {
+                        let file = req["arguments"]["filename"].as_str().unwrap();
+                        screen.save(Path::new(file)).unwrap();
+                    }
+                    if write.write_all(b"{\"return\": {}}\n").await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    path
+}
+
+fn screen(dir: &Path, when: &str) -> Screen {
+    let spec = ScreenSpec {
+        when: Some(when.into()),
+        reference: dir.join("boot.ppm"),
+        ..Default::default()
+    };
+    let mut screen = Screen::new(&spec, false).unwrap();
+    screen.capture = dir.join("screen-boot.ppm");
+    screen
+}
+
+const KERNEL: &str = "echo '[FB] ready'; sleep 0.5; echo '[BOOT] OK'";
+
+#[tokio::test]
+async fn a_matching_screen_passes() {
+    let dir = scratch_dir("screen-match");
+    image([0, 0, 0], [9, 9, 9])
+        .save(&dir.join("boot.ppm"))
+        .unwrap();
+    let mut cfg = fake_qemu(KERNEL);
+    cfg.qmp_socket = Some(fake_qmp(&dir, image([0, 0, 0], [9, 9, 9])));
+    cfg.screens = vec![screen(&dir, r"\[FB\] ready")];
+    let result = run(&cfg).await.unwrap();
+    assert_eq!(result.reason, ExitReason::PatternMatched);
+    assert_eq!(
+        result.screens,
+        [ScreenResult::Matched {
+            name: "boot".into(),
+            differing: 0
+        }]
+    );
+    assert!(result.passed(&parse_serial(&result.transcript)));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_different_screen_fails_with_a_diff_image() {
+    let dir = scratch_dir("screen-mismatch");
+    image([0, 0, 0], [9, 9, 9])
+        .save(&dir.join("boot.ppm"))
+        .unwrap();
+    let mut cfg = fake_qemu(KERNEL);
+    cfg.qmp_socket = Some(fake_qmp(&dir, image([0, 0, 0], [200, 9, 9])));
+    cfg.screens = vec![screen(&dir, r"\[FB\] ready"), {
+        let mut late = screen(&dir, "never printed");
+        late.name = "late".into();
+        late
+    }];
+    let result = run(&cfg).await.unwrap();
+    assert!(!result.passed(&parse_serial(&result.transcript)));
+    let ScreenResult::Mismatch { diff, message, .. } = &result.screens[0] else {
+        panic!("expected a mismatch, got {:?}", result.screens);
+    };
+    assert_eq!(message, "1 of 2 pixels differ (50.00%, threshold 0.00%)");
+    let diff = Image::load(diff.as_ref().unwrap()).unwrap();
+    assert_eq!(diff.rgb, [0, 0, 0, 255, 0, 0]);
+    assert!(result
+        .explain()
+        .iter()
+        .any(|l| l.starts_with("screen late: never captured")));
+    std::fs::remove_dir_all(dir).unwrap();
+}
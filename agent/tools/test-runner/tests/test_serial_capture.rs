@@ -1,5 +1,7 @@
 //! Integration tests for serial-output parsing.
 
+mod common;
+
 use test_runner::parse_serial;
 
 #[test]
@@ -33,3 +35,39 @@ fn a_single_failure_fails_the_run() {
     assert!(!s.success);
     assert_eq!(s.failed, 1);
 }
+
+#[tokio::test]
+async fn forbidden_pattern_fails_with_context() {
+    use std::time::Duration;
+    use test_runner::expect::Expectations;
+    use test_runner::qemu::{run, ExitReason, RunConfig};
+
+    let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let expect = Expectations::new(
+        &strings(&[r"\[BOOT\] OK"]),
+        &strings(&[r"\[TEST\] \w+: PASS"]),
+        &strings(&[r"\bGPF\b"]),
+        &[],
+        1,
+    )
+    .unwrap();
+    let script = "echo '[TEST] pmm: PASS'; echo '[IDT] loaded'; echo 'CPU: GPF err=0x10'; \
+                  echo 'RIP=0x1000'; echo '[BOOT] OK'; sleep 30";
+    let result = run(&RunConfig {
+        timeout: Duration::from_secs(10),
+        expect,
+        ..common::fake_qemu(script)
+    })
+    .await
+    .unwrap();
+    let ExitReason::Forbidden(v) = &result.reason else {
+        panic!("expected forbidden output, got {:?}", result.reason);
+    };
+    assert_eq!((v.line_no, v.context_start), (3, 2));
+    assert_eq!(
+        v.context,
+        ["[IDT] loaded", "CPU: GPF err=0x10", "RIP=0x1000"]
+    );
+    assert_eq!(result.unmatched, [r"\[BOOT\] OK"]);
+    assert_eq!(result.matched.len(), 1);
+}
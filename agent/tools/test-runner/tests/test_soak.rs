@@ -0,0 +1,88 @@
+//! Integration tests for soak runs. A shell script stands in for a kernel
+//! that keeps reporting its heap, so the launch loop watches it as it would
+//! a long-running QEMU.
+
+mod common;
+
+use common::fake_qemu;
+use std::time::Duration;
+use test_runner::parse_serial;
+use test_runner::qemu::{run, ExitReason};
+use test_runner::soak::{self, GaugeSpec, Monitor, SoakSpec};
+
+fn soak_spec() -> SoakSpec {
+    SoakSpec {
+        heartbeat: Some(r"\[TICK\]".into()),
+        heartbeat_timeout: Some(5),
+        faults: vec![r"\[FAULT\] recovered".into()],
+        max_faults: Some(3),
+        gauge: vec![GaugeSpec {
+            name: "heap".into(),
+            pattern: "heap used: ".into(),
+            max_growth: Some(50),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+/// Boots, then prints a tick and the heap every 0.1s, the heap growing by
+/// `leak` each time.
+fn kernel(leak: u32) -> String {
+    format!(
+        "echo '[BOOT] OK'; heap=1000; while :; do echo '[TICK]'; \
+         echo \"[MM] heap used: $heap\"; heap=$((heap + {leak})); sleep 0.1; done"
+    )
+}
+
+#[tokio::test]
+async fn a_steady_kernel_survives_the_duration() {
+    let mut cfg = fake_qemu(&kernel(0));
+    let monitor = Monitor::new(&soak_spec(), Duration::from_millis(1500)).unwrap();
+    soak::configure(&mut cfg, monitor);
+    let result = run(&cfg).await.unwrap();
+    assert_eq!(result.reason, ExitReason::Survived { duration_secs: 1 });
+    assert!(result.passed(&parse_serial(&result.transcript)));
+    let report = result.soak.as_ref().unwrap();
+    assert!(report.heartbeats >= 5, "{report:?}");
+    let heap = &report.gauges[0];
+    assert_eq!((heap.first, heap.max), (Some(1000), Some(1000)));
+    assert!(result.duration_ms >= 1500);
+}
+
+#[tokio::test]
+async fn a_leaking_kernel_is_unhealthy_before_the_duration() {
+    let mut cfg = fake_qemu(&kernel(100));
+    let monitor = Monitor::new(&soak_spec(), Duration::from_secs(20)).unwrap();
+    soak::configure(&mut cfg, monitor);
+    let result = run(&cfg).await.unwrap();
+    let ExitReason::Unhealthy(u) = &result.reason else {
+        panic!("expected unhealthy, got {:?}", result.reason);
+    };
+    assert_eq!(u.check, "gauge heap");
+    assert_eq!(u.detail, "1600 has grown more than 50% from 1000");
+    assert_eq!(u.line.as_deref(), Some("[MM] heap used: 1600"));
+    assert!(!result.passed(&parse_serial(&result.transcript)));
+    assert!(result.duration_ms < 10_000);
+    assert!(result.explain()[0].contains("gauge heap: 1600 has grown"));
+}
+
+#[tokio::test]
+async fn a_kernel_that_stops_ticking_is_unhealthy() {
+    let mut cfg =
+        fake_qemu("echo '[BOOT] OK'; echo '[TICK]'; while :; do echo busy; sleep 0.1; done");
+    let spec = SoakSpec {
+        heartbeat_timeout: Some(1),
+        ..soak_spec()
+    };
+    soak::configure(
+        &mut cfg,
+        Monitor::new(&spec, Duration::from_secs(20)).unwrap(),
+    );
+    let result = run(&cfg).await.unwrap();
+    let ExitReason::Unhealthy(u) = &result.reason else {
+        panic!("expected unhealthy, got {:?}", result.reason);
+    };
+    assert_eq!(u.detail, "no heartbeat for 1s after 1 heartbeats");
+    assert!(result.duration_ms < 10_000);
+}
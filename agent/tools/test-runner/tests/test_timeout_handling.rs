@@ -1,8 +1,14 @@
-//! Timeout-handling behavior is exercised at the binary level (a hung kernel
-//! exits with code 2). Here we assert the parsing invariant the timeout path
-//! relies on: partial/garbage serial with no [BOOT] OK is not a success.
+//! Integration tests for timeout and panic handling. A shell script stands
+//! in for QEMU so the launch loop runs without an emulator; a hung kernel
+//! exits the binary with code 2.
 
+mod common;
+
+use common::fake_qemu;
+use std::time::Duration;
+use test_runner::classify::FailureKind;
 use test_runner::parse_serial;
+use test_runner::qemu::{run, ExitReason, RunConfig};
 
 #[test]
 fn partial_output_without_boot_ok_is_not_success() {
@@ -19,3 +25,49 @@ fn empty_output_is_not_success() {
     assert!(!s.success);
     assert_eq!(s.total, 0);
 }
+
+#[tokio::test]
+async fn hung_kernel_times_out_and_keeps_partial_transcript() {
+    let cfg = RunConfig {
+        timeout: Duration::from_millis(300),
+        ..fake_qemu("echo '[BOOT] Long mode enabled'; sleep 30 & sleep 30")
+    };
+    let result = run(&cfg).await.unwrap();
+    assert_eq!(result.reason, ExitReason::Timeout { timeout_ms: 300 });
+    assert_eq!(
+        result.explain()[0],
+        "QEMU timed out after 300ms (possible hang)"
+    );
+    assert!(result.duration_ms < 5000);
+    assert_eq!(result.transcript, "[BOOT] Long mode enabled\n");
+}
+
+#[tokio::test]
+async fn panic_is_reported_with_the_rest_of_the_dump() {
+    let cfg = RunConfig {
+        timeout: Duration::from_secs(10),
+        ..fake_qemu(
+            "echo '[MM] init'; echo 'KERNEL PANIC: page fault'; echo 'RIP=0xffffffff80001234'; sleep 30",
+        )
+    };
+    let result = run(&cfg).await.unwrap();
+    let ExitReason::Panic(v) = &result.reason else {
+        panic!("expected a panic, got {:?}", result.reason);
+    };
+    assert_eq!(v.line, "KERNEL PANIC: page fault");
+    assert_eq!(
+        v.context,
+        [
+            "[MM] init",
+            "KERNEL PANIC: page fault",
+            "RIP=0xffffffff80001234"
+        ]
+    );
+    assert!(result.duration_ms < 5000);
+
+    // The dump is also classified into structured fields.
+    let failure = result.failure.as_ref().expect("classified");
+    assert_eq!(failure.kind, FailureKind::CpuException);
+    assert_eq!(failure.exception.as_ref().unwrap().mnemonic, "#PF");
+    assert_eq!(failure.rip, Some(0xffffffff80001234));
+}